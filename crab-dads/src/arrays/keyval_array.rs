@@ -3,6 +3,10 @@ use core::{marker::PhantomData, slice};
 use crate::Error;
 
 /// An array of variable-size key-value pairs that grows upward in memory.
+///
+/// Keys and values can be zero-sized, in which case they're returned as empty
+/// slices pointing at the boundary between pairs, which may be the very end of
+/// the array.
 #[derive(Clone, Debug)]
 pub struct KeyValArray<'a> {
     // These pointers are ordered from lowest memory point to highest.
@@ -75,9 +79,38 @@ impl<'a> KeyValArray<'a> {
         Ok(ret)
     }
 
+    /// Like [`next_pair`][Self::next_pair], but only returns the key, stepping
+    /// over the value without making a slice for it.
+    pub fn next_key(&mut self, key_size: usize, val_size: usize) -> Result<&'a [u8], Error> {
+        let new_front = self.front.wrapping_add(key_size).wrapping_add(val_size);
+        if new_front > self.back {
+            return Err(Error::DataCorruption("advanced past end of lower data region"));
+        }
+
+        let key = unsafe { slice::from_raw_parts(self.front, key_size) };
+        self.front = new_front;
+        Ok(key)
+    }
+
+    /// Like [`next_pair_back`][Self::next_pair_back], but only returns the
+    /// key, stepping over the value without making a slice for it.
+    pub fn next_key_back(&mut self, key_size: usize, val_size: usize) -> Result<&'a [u8], Error> {
+        let new_back = self.back.wrapping_sub(val_size).wrapping_sub(key_size);
+        if new_back < self.front {
+            return Err(Error::DataCorruption("advanced below start of lower data region"));
+        }
+
+        let key = unsafe { slice::from_raw_parts(new_back, key_size) };
+        self.back = new_back;
+        Ok(key)
+    }
+
     /// Update the internal pointers to mimic the outcome of getting a `None`
     /// result from iterating. This returns an error if our iterator isn't
     /// actually exhausted.
+    ///
+    /// This works for either direction: the front and back close in on each
+    /// other, so they meet once every pair is read, whichever end it came from.
     pub fn next_none(&mut self) -> Result<(), Error> {
         if self.back != self.front {
             return Err(Error::DataCorruption("lower data region has unexpected extra bytes"));
@@ -161,6 +194,9 @@ impl<'a> KeyValArrayMut<'a> {
     /// Update the internal pointers to mimic the outcome of getting a `None`
     /// result from iterating. This returns an error if our iterator isn't
     /// actually exhausted.
+    ///
+    /// This works for either direction: the front and back close in on each
+    /// other, so they meet once every pair is read, whichever end it came from.
     pub fn next_none(&mut self) -> Result<(), Error> {
         if self.back != self.front {
             return Err(Error::DataCorruption("lower data region has unexpected extra bytes"));
@@ -176,6 +212,9 @@ impl<'a> KeyValArrayMut<'a> {
 #[derive(Clone, Debug)]
 pub struct KeyValArrayMutResize<'a> {
     // These pointers are ordered from lowest memory point to highest.
+    start: *mut u8,
+    prev_front_key: *mut u8,
+    front_val: *mut u8,
     front: *mut u8,
     back: *mut u8,
     back_val: *mut u8,
@@ -189,6 +228,9 @@ impl<'a> KeyValArrayMutResize<'a> {
     pub fn new(data: &mut [u8]) -> Self {
         let range = data.as_mut_ptr_range();
         Self {
+            start: range.start,
+            prev_front_key: range.start,
+            front_val: range.start,
             front: range.start,
             back: range.end,
             end: range.end,
@@ -203,6 +245,41 @@ impl<'a> KeyValArrayMutResize<'a> {
         unsafe { self.back.offset_from(self.front) as usize }
     }
 
+    /// Try to increment the front to the next key-value pair, failing if the
+    /// result pushes us past the end pointer. This returns the pair's key on
+    /// success.
+    pub fn next_pair(&mut self, key_size: usize, val_size: usize) -> Result<&[u8], Error> {
+        let val_ptr = self.front.wrapping_add(key_size);
+        let new_front = val_ptr.wrapping_add(val_size);
+        if new_front > self.back {
+            return Err(Error::DataCorruption("advanced past end of lower data region"));
+        }
+
+        self.prev_front_key = self.front;
+        self.front_val = val_ptr;
+        self.front = new_front;
+        unsafe { Ok(slice::from_raw_parts(self.prev_front_key, key_size)) }
+    }
+
+    /// Switch from reading with [`next_pair`](#method.next_pair) to reading
+    /// with [`next_pair_back`](#method.next_pair_back). Afterwards, this struct
+    /// points to the pair `next_pair` last read, exactly as if `next_pair_back`
+    /// had been called until it read that pair. If `next_pair` never read
+    /// anything, it's as if [`next_pair_back_none`](#method.next_pair_back_none)
+    /// had been called instead.
+    pub fn front_to_back(&mut self) {
+        if self.front == self.start {
+            self.back = self.start;
+            self.back_val = self.start;
+            self.prev_back_key = self.start;
+        } else {
+            self.back = self.prev_front_key;
+            self.back_val = self.front_val;
+            self.prev_back_key = self.front;
+            self.front = self.start;
+        }
+    }
+
     /// Try to decrement the end to the next key-value, failing if the result
     /// pushes us past the start pointer.
     pub fn next_pair_back(&mut self, key_size: usize, val_size: usize) -> Result<(), Error> {
@@ -144,6 +144,8 @@ impl<'a, T: CheckedBitPattern> DoubleEndedIterator for RevSizedArrayMut<'a, T> {
 /// downward in memory.
 #[derive(Clone, Debug)]
 pub struct RevSizedArrayMutResize<'a, T: CheckedBitPattern> {
+    // One past the first element, where `front` starts out.
+    start: *mut T,
     front: *mut T,
     back: *mut T,
     end: *mut T,
@@ -156,6 +158,7 @@ impl<'a, T: CheckedBitPattern> RevSizedArrayMutResize<'a, T> {
     pub fn new(data: &mut [T]) -> Self {
         let range = data.as_mut_ptr_range();
         Self {
+            start: range.end,
             front: range.end,
             back: range.start,
             end: range.start,
@@ -247,4 +250,35 @@ impl<'a, T: CheckedBitPattern> RevSizedArrayMutResize<'a, T> {
             Some(Ok(ret))
         }
     }
+
+    /// Get the next item in the array, from the front.
+    pub fn next_front(&mut self) -> Option<Result<&T, Error>> {
+        if self.front == self.back {
+            return None;
+        }
+        unsafe {
+            let next = self.front.sub(1);
+            if !T::is_valid_bit_pattern(&*(next as *const T::Bits)) {
+                return Some(Err(Error::DataCorruption("Invalid bit pattern for sized upper array")));
+            }
+            self.front = next;
+            Some(Ok(&*next))
+        }
+    }
+
+    /// Switch from reading with [`next_front`](#method.next_front) to reading
+    /// with [`next_back`](#method.next_back). Afterwards, the array is in the
+    /// same state as if `next_back` had been called until it returned the
+    /// element that `next_front` last returned, or until it returned `None` if
+    /// `next_front` never returned anything.
+    pub fn front_to_back(&mut self) {
+        if self.front == self.start {
+            self.back = self.start;
+            self.prev_back = self.start;
+        } else {
+            self.prev_back = self.front;
+            self.back = unsafe { self.front.add(1) };
+            self.front = self.start;
+        }
+    }
 }
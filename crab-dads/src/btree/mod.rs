@@ -4,10 +4,46 @@ mod writer;
 pub use reader::*;
 pub use writer::*;
 
-use crate::{StorageError, PAGE_4K};
+use crate::{
+    page::{self, PageLayout},
+    StorageError, PAGE_4K,
+};
+
+/// The most children a branch page with layout `B` can have. For layouts with
+/// variable-length keys, this is with the shortest possible keys, and
+/// [`page::min_pairs`] gives the fewest with the longest.
+///
+/// A tree's height is its number of levels, counting the leaves. A tree of
+/// height `h` holds at most `max_pairs::<L>() * branch_fanout::<B>().pow(h - 1)`
+/// entries. Pages are often only partly full, so a tree reaches a given height
+/// with fewer entries than that.
+///
+/// ```
+/// use crab_dads::btree::branch_fanout;
+/// use crab_dads::page::{max_pairs, LayoutU64U64};
+///
+/// let fanout = branch_fanout::<LayoutU64U64>();
+/// let per_leaf = max_pairs::<LayoutU64U64>();
+/// assert_eq!((fanout, per_leaf), (255, 255));
+///
+/// // A tree that's a single leaf, then with one and two levels of branches
+/// // above the leaves
+/// assert_eq!(per_leaf, 255);
+/// assert_eq!(per_leaf * fanout, 65_025);
+/// assert_eq!(per_leaf * fanout.pow(2), 16_581_375);
+/// ```
+pub const fn branch_fanout<B: PageLayout<Value = u64>>() -> usize {
+    page::max_pairs::<B>()
+}
 
 /// Access to a backing reader.
 ///
+/// Every `page: u64` in this trait and in [`RawWrite`] is a page number in
+/// whatever unit the implementation picks. crab-dads never does arithmetic on
+/// them, only storing them in branch pages and handing them back, so they just
+/// need to mean the same thing everywhere. crab-db uses the page's byte offset
+/// in its backing file, not a count of 4 kiB pages.
+///
 /// # Safety
 ///
 /// It's complicated. This is really meant for the `crab-db` approach to page
@@ -34,6 +70,16 @@ pub unsafe trait RawRead {
     /// root database page may be loaded with this function.
     unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError>;
 
+    /// The generation (transaction ID) of the snapshot this reader sees.
+    ///
+    /// Page numbers are only meaningful within a single generation. Callers
+    /// that cache root page numbers across commits can check them against this
+    /// with [`BTreeRead::load_with_generation`]. Defaults to 0 for readers
+    /// that don't track generations.
+    fn generation(&self) -> u64 {
+        0
+    }
+
     /// Load a 4 kiB page.
     ///
     /// # Safety
@@ -111,6 +157,26 @@ pub unsafe trait RawWrite: RawRead {
     /// only be called with them once.
     unsafe fn deallocate(&self, page: u64, num_pages: usize) -> Result<(), StorageError>;
 
+    /// Deallocate a batch of regions, each given as a `(page, num_pages)` pair.
+    ///
+    /// The default implementation calls [`deallocate`][Self::deallocate] for
+    /// each region in turn. Allocators that can do better - sorting the regions
+    /// and coalescing adjacent ones before updating their free lists in a
+    /// single pass - should override this.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`deallocate`][Self::deallocate], for every region in the batch.
+    unsafe fn deallocate_batch(
+        &self,
+        pages: &mut dyn Iterator<Item = (u64, usize)>,
+    ) -> Result<(), StorageError> {
+        for (page, num_pages) in pages {
+            unsafe { self.deallocate(page, num_pages)? };
+        }
+        Ok(())
+    }
+
     /// Load a page for writing. If the range that's been requested is not
     /// available for writing, it should return the
     /// [`Clean`][LoadMutPage::Clean] result with a newly allocated page to
@@ -160,351 +226,112 @@ pub unsafe trait RawWrite: RawRead {
     }
 }
 
-#[cfg(test)]
-#[allow(dead_code)]
-mod test {
-    extern crate std;
-    use core::cell::UnsafeCell;
-    use std::prelude::rust_2021::*;
-    use std::sync::RwLock;
-
-    use std::vec;
-    use std::dbg;
-
-    use alloc::{collections::{btree_map::BTreeMap, vec_deque::VecDeque}, sync::Arc};
-
-    use crate::{
-        page::{LayoutU64U64, LayoutU64Var, PageMapMut},
-        Error,
-    };
-
-    use super::*;
-
-    #[derive(Clone)]
-    struct BasicDbInner {
-        root: u64,
-        memory: BTreeMap<u64, Box<[u8]>>,
-        checkouts: Vec<(u64, u64)>,
-        commit: u64,
-    }
-
-    struct CheckoutFmt<'a>(&'a [(u64,u64)]);
-    impl<'a> std::fmt::Debug for CheckoutFmt<'a> {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            f.write_str("[ ")?;
-            for (commit, count) in self.0 {
-                write!(f, "{}:{}, ", commit, count)?;
-            }
-            f.write_str("]")
-        }
-    }
-
-    struct MemoryFmt<'a>(&'a BTreeMap<u64, Box<[u8]>>);
-    impl<'a> std::fmt::Debug for MemoryFmt<'a> {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            for page in self.0.iter() {
-                writeln!(f, "Page {}", page.0)?;
-                f.write_str("    ")?;
-                for (idx, byte) in page.1.iter().enumerate() {
-                    write!(f, "{:02x}", byte)?;
-                    if (idx & 0x3) == 3 { f.write_str(" ")?; }
-                    if (idx & 0x1F) == 0x1F { 
-                        writeln!(f)?;
-                        f.write_str("    ")?;
-                    }
-                }
-                writeln!(f)?;
-            }
-            Ok(())
-        }
-    }
-
-    impl std::fmt::Debug for BasicDbInner {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            f.debug_struct("BasicDbInner")
-            .field("root", &self.root)
-            .field("commit", &self.commit)
-            .field("checkouts", &CheckoutFmt(self.checkouts.as_slice()))
-            .field("memory", &MemoryFmt(&self.memory))
-            .finish()
-        }
-    }
-
-    #[derive(Debug)]
-    struct BasicDbRead {
-        inner: Arc<RwLock<BasicDbInner>>,
-        root: u64,
-        commit: u64,
+// Shared references forward everything, so storage can be lent to something
+// that wraps a reader or writer by value, like the counting wrappers.
+unsafe impl<R: RawRead + ?Sized> RawRead for &R {
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        unsafe { (**self).load(page, num_pages) }
     }
 
-    impl Clone for BasicDbRead {
-        fn clone(&self) -> Self {
-            let mut inner = self.inner.write().unwrap();
-
-            let commit = inner.commit;
-            let co = inner.checkouts.iter_mut().rev().find(|(c, _)| c == &commit);
-            if let Some(co) = co {
-                co.1 += 1;
-            } else {
-                inner.checkouts.push((commit, 1));
-            }
-            let root = inner.root;
-
-            Self {
-                inner: self.inner.clone(),
-                root,
-                commit,
-            }
-        }
+    fn generation(&self) -> u64 {
+        (**self).generation()
     }
 
-    impl BasicDbRead {
-        pub fn reload(self) -> Self {
-            let new = self.clone();
-            drop(self);
-            new
-        }
-
-        pub fn tree(&self) -> Result<BTreeRead<'_, LayoutU64U64, LayoutU64Var, Self>, Error> {
-            unsafe { BTreeRead::load(self, self.root) }
-        }
+    unsafe fn load_page(&self, page: u64) -> Result<&[u8; PAGE_4K], StorageError> {
+        unsafe { (**self).load_page(page) }
     }
+}
 
-    unsafe impl RawRead for BasicDbRead {
-        unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
-            let inner = self.inner.read().unwrap();
-            let mem = inner
-                .memory
-                .get(&page)
-                .ok_or(StorageError::OutOfRange(page))?;
-            if mem.len() != (num_pages * PAGE_4K) {
-                return Err(StorageError::Corruption(
-                    "Incorrect size for the requested page",
-                ));
-            }
-            // We pinky-promised that we won't drop this memory until this
-            // reader's checkout advances (or it is dropped)
-            unsafe { Ok(core::slice::from_raw_parts(mem.as_ptr(), mem.len())) }
-        }
+unsafe impl<W: RawWrite + ?Sized> RawWrite for &W {
+    unsafe fn load_mut(&self, page: u64, num_pages: usize) -> Result<LoadMut<'_>, StorageError> {
+        unsafe { (**self).load_mut(page, num_pages) }
     }
 
-    impl Drop for BasicDbRead {
-        fn drop(&mut self) {
-            let mut inner = self.inner.write().unwrap();
-
-            let old_co = inner
-                .checkouts
-                .iter_mut()
-                .position(|(c, _)| c == &self.commit)
-                .unwrap();
-            inner.checkouts[old_co].1 -= 1;
-            if inner.checkouts[old_co].1 == 0 {
-                inner.checkouts.remove(old_co);
-            }
-        }
+    fn allocate(&self, num_pages: usize) -> Result<(&mut [u8], u64), StorageError> {
+        (**self).allocate(num_pages)
     }
 
-    fn alloc_paged(pages: usize) -> Box<[u8]> {
-        unsafe {
-            let len = PAGE_4K * pages;
-            let ptr =
-                std::alloc::alloc(core::alloc::Layout::from_size_align_unchecked(len, PAGE_4K));
-            Box::from_raw(core::ptr::slice_from_raw_parts_mut(ptr, len))
-        }
+    unsafe fn deallocate(&self, page: u64, num_pages: usize) -> Result<(), StorageError> {
+        unsafe { (**self).deallocate(page, num_pages) }
     }
 
-    fn new_db() -> (BasicDbRead, BasicDbWrite) {
-        let mut memory = BTreeMap::new();
-        let mut mem = alloc_paged(1);
-        PageMapMut::<LayoutU64Var>::new(unsafe { &mut *(mem.as_mut_ptr() as *mut [u8; 4096]) }, 1);
-        memory.insert(0, mem);
-
-        let inner = Arc::new(RwLock::new(BasicDbInner {
-            root: 0,
-            memory,
-            checkouts: vec![(0, 1)],
-            commit: 0,
-        }));
-        let read = BasicDbRead {
-            inner: inner.clone(),
-            root: 0,
-            commit: 0,
-        };
-
-        let write = BasicDbWrite {
-            inner,
-            cell: UnsafeCell::new(BasicDbWriteCell {
-                dirty: BTreeMap::new(),
-                to_drop: VecDeque::new(),
-                page_num: 1,
-                root: 0,
-            }),
-            commit: 0,
-            starting_page_num: 1,
-            starting_root: 0,
-        };
-        (read, write)
+    unsafe fn deallocate_batch(
+        &self,
+        pages: &mut dyn Iterator<Item = (u64, usize)>,
+    ) -> Result<(), StorageError> {
+        unsafe { (**self).deallocate_batch(pages) }
     }
 
-    #[derive(Debug)]
-    struct BasicDbWrite {
-        inner: Arc<RwLock<BasicDbInner>>,
-        cell: UnsafeCell<BasicDbWriteCell>,
-        commit: u64,
-        starting_page_num: u64,
-        starting_root: u64,
+    unsafe fn load_mut_page(&self, page: u64) -> Result<LoadMutPage<'_>, StorageError> {
+        unsafe { (**self).load_mut_page(page) }
     }
 
-    struct BasicDbWriteCell {
-        page_num: u64,
-        dirty: BTreeMap<u64, Box<[u8]>>,
-        to_drop: VecDeque<(u64, Vec<u64>)>,
-        root: u64,
+    fn allocate_page(&self) -> Result<(&mut [u8; PAGE_4K], u64), StorageError> {
+        (**self).allocate_page()
     }
 
-    impl std::fmt::Debug for BasicDbWriteCell {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            f.debug_struct("BasicDbWriteCell")
-            .field("root", &self.root)
-            .field("page_num", &self.page_num)
-            .field("dirty", &MemoryFmt(&self.dirty))
-            .field("to_drop", &self.to_drop)
-            .finish_non_exhaustive()
-        }
+    unsafe fn deallocate_page(&self, page: u64) -> Result<(), StorageError> {
+        unsafe { (**self).deallocate_page(page) }
     }
+}
 
-    impl BasicDbWrite {
-
-        fn page_count(&self) -> usize {
-            let mem_len = self.inner.read().unwrap().memory.len();
-            let dirty_len = unsafe { (*self.cell.get()).dirty.len() };
-            mem_len + dirty_len
-        }
-
-        fn commit(&mut self) {
-            let mut inner = self.inner.write().unwrap();
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::prelude::rust_2021::*;
 
-            // Move the dirty pages into the full tree map.
-            let dirty = &mut self.cell.get_mut().dirty;
-            while let Some(d) = dirty.pop_last() {
-                inner.memory.insert(d.0, d.1);
-            }
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        dbg, format,
+        ops::Bound,
+    };
 
-            // Update the rest of our state.
-            self.starting_page_num = self.cell.get_mut().page_num;
-            self.starting_root = self.cell.get_mut().root;
-            self.commit += 1;
-            inner.root = self.cell.get_mut().root;
-            inner.commit += 1;
+    use crate::{
+        counting::{CallCounts, CountingReader, CountingWriter},
+        format,
+        page::{
+            LayoutU64U64, LayoutU64Var, LayoutVarU64, PageLayout, PageMap, CONTENT_SIZE,
+            MAX_VAR_SIZE,
+        },
+        rng::Rng,
+        sim::{SimAllocator, SimReader},
+        Error, NULL_PAGE,
+    };
 
-            // Ditch any unused pages
-            let oldest_co = inner.checkouts.first().map(|(c,_)| *c).unwrap_or(u64::MAX);
-            while let Some(d) = self.cell.get_mut().to_drop.pop_front() {
-                if d.0 >= oldest_co {
-                    self.cell.get_mut().to_drop.push_front(d);
-                    break;
-                }
-                for d in d.1 {
-                    inner.memory.remove(&d);
-                }
-            }
-        }
+    use super::*;
 
-        fn reset(&mut self) {
-            let cell = self.cell.get_mut();
-            if let Some((co, _)) = cell.to_drop.back() {
-                if *co == (self.commit+1) {
-                    cell.to_drop.pop_back();
-                }
-            }
-            cell.dirty.clear();
-            cell.page_num = self.starting_page_num;
-            cell.root = self.starting_root;
-        }
-
-        fn tree(&mut self) -> Result<BTreeWrite<'_, LayoutU64U64, LayoutU64Var, Self>, Error> {
-            unsafe {
-                let (tree, root) = BTreeWrite::load(self, (*self.cell.get()).root)?;
-                if let Some(root) = root {
-                    (*self.cell.get()).root = root;
-                }
-                Ok(tree)
-            }
-        }
+    type Tree<'a> = BTreeWrite<'a, LayoutU64U64, LayoutU64Var, SimAllocator>;
+    type ReadTree<'a> = BTreeRead<'a, LayoutU64U64, LayoutU64Var, SimReader>;
 
+    fn new_db() -> (SimReader, SimAllocator) {
+        let writer = SimAllocator::new();
+        let reader = writer.reader().unwrap();
+        (reader, writer)
     }
 
-    unsafe impl RawRead for BasicDbWrite {
-        unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
-            let inner = self.inner.read().unwrap();
-            let cell: &BasicDbWriteCell = unsafe { &*self.cell.get() };
-            let mem = inner
-                .memory
-                .get(&page)
-                .or_else(|| cell.dirty.get(&page))
-                .ok_or(StorageError::OutOfRange(page))?;
-            if mem.len() != (num_pages * PAGE_4K) {
-                return Err(StorageError::Corruption(
-                    "Incorrect size for the requested page",
-                ));
-            }
-            // We pinky-promised that we won't drop this memory until this
-            // reader's checkout advances (or it is dropped)
-            unsafe { Ok(core::slice::from_raw_parts(mem.as_ptr(), mem.len())) }
-        }
+    /// Open the tree through a counting reader, run `scan` over it, and
+    /// return what it loaded.
+    fn scan<F>(reader: &SimReader, scan: F) -> CallCounts
+    where
+        F: FnOnce(BTreeRead<'_, LayoutU64U64, LayoutU64Var, CountingReader<&SimReader>>),
+    {
+        let counting = CountingReader::new(reader);
+        scan(unsafe { BTreeRead::load(&counting, reader.root()).unwrap() });
+        let counts = counting.snapshot();
+        assert_eq!(
+            counts.touched_dropped, 0,
+            "scan loaded too many pages to trace"
+        );
+        counts
     }
 
-    unsafe impl RawWrite for BasicDbWrite {
-        fn allocate(&self, num_pages: usize) -> Result<(&mut [u8], u64), StorageError> {
-            unsafe {
-                let page_num = (*self.cell.get()).page_num;
-                (*self.cell.get()).page_num += 1;
-
-                let mut mem = alloc_paged(num_pages);
-                let raw = core::slice::from_raw_parts_mut(mem.as_mut_ptr(), mem.len());
-                (*self.cell.get()).dirty.insert(page_num, mem);
-                Ok((raw, page_num))
-            }
-        }
-
-        unsafe fn deallocate(&self, page: u64, _num_pages: usize) -> Result<(), StorageError> {
-            unsafe {
-                if (*self.cell.get()).dirty.remove(&page).is_some() {
-                    return Ok(());
-                }
-                let to_drop = &mut (*self.cell.get()).to_drop;
-                if let Some(td) = to_drop.back_mut() {
-                    if td.0 == (self.commit+1) {
-                        td.1.push(page);
-                    }
-                    else {
-                        to_drop.push_back((self.commit+1, vec![page]));
-                    }
-                }
-                else {
-                    to_drop.push_back((self.commit+1, vec![page]));
-                }
-                Ok(())
-            }
-        }
-
-        unsafe fn load_mut(&self, page: u64, num_pages: usize) -> Result<LoadMut, StorageError> {
-            unsafe {
-                if let Some(p) = (*self.cell.get()).dirty.get_mut(&page) {
-                    return Ok(LoadMut::Dirty(core::slice::from_raw_parts_mut(
-                        p.as_mut_ptr(),
-                        p.len(),
-                    )));
-                }
-                let read = self.load(page, num_pages)?;
-                let (write, write_page) = self.allocate(num_pages)?;
-                Ok(LoadMut::Clean {
-                    write,
-                    write_page,
-                    read,
-                })
-            }
+    /// How many times each page was loaded.
+    fn loads_per_page(counts: &CallCounts) -> BTreeMap<u64, usize> {
+        let mut loads = BTreeMap::new();
+        for page in &counts.touched {
+            *loads.entry(*page).or_default() += 1;
         }
+        loads
     }
 
     #[test]
@@ -514,7 +341,7 @@ mod test {
         let p_num = p.1;
         let (p, _) = p.0.split_at_mut(4);
         p.copy_from_slice(&[5,6,7,8]);
-        writer.commit();
+        writer.commit().unwrap();
         unsafe {
             let p = reader.load(p_num, 1).unwrap();
             let (p, _) = p.split_at(4);
@@ -525,7 +352,7 @@ mod test {
     #[test]
     fn sequential_insert_forward() {
         let (reader, mut writer) = new_db();
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         let i_len = 100000;
 
         // Insertion
@@ -537,12 +364,12 @@ mod test {
                 }
             }
         }
-        writer.commit();
+        writer.commit().unwrap();
         println!("Writing complete, {} pages used", writer.page_count());
 
         // Post-insert check
-        let reader = reader.reload();
-        let tree = reader.tree().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in 0..i_len {
             let val = tree.get(&i).unwrap().unwrap();
             assert_eq!(val, i.to_le_bytes().as_slice());
@@ -564,7 +391,7 @@ mod test {
         println!("Reading complete");
 
         // Deletion
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         for i in 0..i_len {
             match tree.entry(&i).unwrap() {
                 Entry::Vacant(_) => panic!("All entries should be occupied"),
@@ -573,24 +400,604 @@ mod test {
                 }
             }
         }
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
         println!("Deletion complete, {} pages used", writer.page_count());
 
         // Post-delete check
-        let tree = reader.tree().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in 0..i_len {
             assert!(tree.get(&i).unwrap().is_none());
         }
     }
 
+    #[test]
+    fn clear_batch_deallocates() {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        let i_len = 100000;
+        for i in 0..i_len {
+            match tree.entry(&i).unwrap() {
+                Entry::Occupied(_) => panic!("All entries should be empty right now"),
+                Entry::Vacant(v) => {
+                    v.insert(i.to_le_bytes().as_slice()).unwrap();
+                }
+            }
+        }
+        writer.commit().unwrap();
+        let used = writer.page_count();
+
+        // Clearing frees every page but the root in a single coalesced batch,
+        // without allocating anything.
+        let counting = CountingWriter::new(&writer);
+        let (mut tree, root) =
+            unsafe { BTreeWrite::<LayoutU64U64, LayoutU64Var, _>::load(&counting, writer.root()) }
+                .unwrap();
+        tree.clear().unwrap();
+        drop(tree);
+        let counts = counting.snapshot();
+        drop(counting);
+        if let Some(root) = root {
+            writer.set_root(root);
+        }
+        let runs = writer.last_batch_runs().to_vec();
+        let freed: usize = runs.iter().map(|(_, n)| n).sum();
+        assert!(freed > 0, "clear should have gone through deallocate_batch");
+        // The old root was freed too, when it got copied on write
+        assert!(root.is_some());
+        assert_eq!(counts.deallocates.total() as usize, freed + 1);
+        assert_eq!(counts.deallocates.sized(1), counts.deallocates.total());
+        assert_eq!(counts.allocates.total(), 0);
+        assert!(runs.len() < freed, "adjacent pages should be coalesced into runs");
+        for w in runs.windows(2) {
+            assert!(w[0].0 + (w[0].1 as u64) < w[1].0, "runs should be sorted and disjoint");
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
+        assert!(writer.page_count() < used);
+
+        // The tree is empty, and still usable.
+        let tree: ReadTree = reader.tree().unwrap();
+        assert!(tree.get(&0).unwrap().is_none());
+        assert!(tree.range(..).unwrap().next().is_none());
+        let mut tree: Tree = writer.tree().unwrap();
+        match tree.entry(&5).unwrap() {
+            Entry::Occupied(_) => panic!("Cleared tree shouldn't have any entries"),
+            Entry::Vacant(v) => {
+                v.insert(b"five").unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.get(&5).unwrap().unwrap(), b"five");
+    }
+
+    #[test]
+    fn stale_generation() {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 0..10000u64 {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+
+        // Cache the root page number along with the generation it came from.
+        let reader = reader.reload().unwrap();
+        let generation = reader.generation();
+        let root = reader.root();
+        unsafe {
+            let tree: BTreeRead<LayoutU64U64, LayoutU64Var, _> =
+                BTreeRead::load_with_generation(&reader, root, generation).unwrap();
+            assert!(tree.get(&5).unwrap().is_some());
+        }
+
+        // After another commit, the cached root is rejected instead of read.
+        let mut tree: Tree = writer.tree().unwrap();
+        if let Entry::Occupied(o) = tree.entry(&5).unwrap() {
+            o.delete().unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        assert_ne!(reader.generation(), generation);
+        unsafe {
+            let res: Result<BTreeRead<LayoutU64U64, LayoutU64Var, _>, _> =
+                BTreeRead::load_with_generation(&reader, root, generation);
+            assert_eq!(
+                res.err(),
+                Some(Error::StaleGeneration {
+                    expected: generation,
+                    found: reader.generation(),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn null_page() {
+        let (reader, mut writer) = new_db();
+
+        // The null page is refused as a root outright
+        unsafe {
+            let res: Result<ReadTree, _> = BTreeRead::load(&reader, NULL_PAGE);
+            assert_eq!(res.err(), Some(Error::NullPage));
+            let res: Result<(Tree, _), _> = BTreeWrite::load(&writer, NULL_PAGE);
+            assert_eq!(res.err(), Some(Error::NullPage));
+        }
+
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 0..10000u64 {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+
+        // Zero out the first branch entry, as if some bug had written it
+        let bad_root = unsafe {
+            let LoadMutPage::Clean {
+                write,
+                write_page,
+                read,
+            } = writer.load_mut_page(writer.root()).unwrap()
+            else {
+                panic!("committed pages should never be writable in place");
+            };
+            let mut branch = PageMap::<LayoutU64U64>::from_page(read)
+                .unwrap()
+                .copy_to(write);
+            let (_, child) = branch.iter_mut().next().unwrap().unwrap();
+            *child = NULL_PAGE;
+            write_page
+        };
+
+        // Following it is caught by both readers and writers
+        unsafe {
+            let tree: BTreeRead<LayoutU64U64, LayoutU64Var, _> =
+                BTreeRead::load(&writer, bad_root).unwrap();
+            assert_eq!(tree.get(&0).err(), Some(Error::NullPage));
+        }
+        writer.set_root(bad_root);
+        let mut tree: Tree = writer.tree().unwrap();
+        assert!(matches!(tree.entry(&0), Err(Error::NullPage)));
+        writer.reset();
+    }
+
+    #[test]
+    fn layout_mismatch() {
+        let (reader, mut writer) = new_db();
+
+        // The fresh root page is legacy, so any layout can claim it
+        let tree: BTreeRead<LayoutVarU64, LayoutVarU64, _> = reader.tree().unwrap();
+        assert_eq!(tree.get(b"a".as_slice()).unwrap(), None);
+
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 0..10000u64 {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.get(&5).unwrap().unwrap(), 5u64.to_le_bytes());
+
+        // Opening it with the wrong branch or leaf layout is caught up front
+        unsafe {
+            let res: Result<BTreeRead<LayoutVarU64, LayoutVarU64, _>, _> =
+                BTreeRead::load(&reader, reader.root());
+            assert_eq!(
+                res.err(),
+                Some(Error::LayoutMismatch {
+                    expected: LayoutVarU64::LAYOUT_ID,
+                    found: LayoutU64U64::LAYOUT_ID,
+                })
+            );
+        }
+        let tree: BTreeRead<LayoutU64U64, LayoutU64U64, _> = reader.tree().unwrap();
+        assert_eq!(
+            tree.get(&5).err(),
+            Some(Error::LayoutMismatch {
+                expected: LayoutU64U64::LAYOUT_ID,
+                found: LayoutU64Var::LAYOUT_ID,
+            })
+        );
+        let mut tree: BTreeWrite<LayoutU64U64, LayoutU64U64, _> = writer.tree().unwrap();
+        assert!(matches!(tree.entry(&5), Err(Error::LayoutMismatch { .. })));
+        writer.reset();
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 0..10000u64 {
+            assert_eq!(tree.insert(&i, &i.to_le_bytes()).unwrap(), None);
+        }
+
+        // Overwriting hands back the old value, even when the new one forces a split
+        for i in (0..10000u64).step_by(3) {
+            let old = tree.insert(&i, &[7; 100]).unwrap();
+            assert_eq!(old.as_deref(), Some(i.to_le_bytes().as_slice()));
+        }
+        assert_eq!(tree.insert(&3, b"").unwrap(), Some(vec![7; 100]));
+
+        // Removing hands back the value, and missing keys just get nothing
+        for i in (1..10000u64).step_by(3) {
+            assert_eq!(tree.remove(&i).unwrap(), Some(i.to_le_bytes().to_vec()));
+            assert_eq!(tree.remove(&i).unwrap(), None);
+        }
+        assert_eq!(tree.remove(&20000).unwrap(), None);
+        writer.commit().unwrap();
+
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.get(&0).unwrap(), Some([7; 100].as_slice()));
+        assert_eq!(tree.get(&1).unwrap(), None);
+        assert_eq!(tree.get(&2).unwrap(), Some(2u64.to_le_bytes().as_slice()));
+        assert_eq!(tree.get(&3).unwrap(), Some(b"".as_slice()));
+        assert_eq!(tree.range(..).unwrap().count(), 6667);
+
+        // Fixed-size values come back as plain numbers
+        let (_, mut writer) = new_db();
+        let mut tree: BTreeWrite<LayoutU64U64, LayoutU64U64, _> = writer.tree().unwrap();
+        assert_eq!(tree.insert(&1, &10).unwrap(), None);
+        assert_eq!(tree.insert(&1, &11).unwrap(), Some(10));
+        assert_eq!(tree.remove(&1).unwrap(), Some(11));
+        assert_eq!(tree.remove(&1).unwrap(), None);
+    }
+
+    #[test]
+    fn scans_load_each_page_once() {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        let i_len = 20000u64;
+        for i in 0..i_len {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+
+        // A full forward scan loads every page in the tree exactly once, one
+        // page at a time
+        let counts = scan(&reader, |tree| {
+            let keys: Vec<u64> = tree.range(..).unwrap().map(|r| *r.unwrap().0).collect();
+            assert_eq!(keys, (0..i_len).collect::<Vec<_>>());
+        });
+        assert_eq!(counts.loads.sized(1), counts.loads.total());
+        let forward = loads_per_page(&counts);
+        assert!(forward.len() > 100, "tree should span many pages");
+        assert!(forward.values().all(|&n| n == 1), "{forward:?}");
+
+        // As does a full reverse scan, which reaches the very same pages
+        let reverse = scan(&reader, |tree| {
+            let keys: Vec<u64> = tree
+                .range(..)
+                .unwrap()
+                .rev()
+                .map(|r| *r.unwrap().0)
+                .collect();
+            assert_eq!(keys, (0..i_len).rev().collect::<Vec<_>>());
+        });
+        assert_eq!(loads_per_page(&reverse), forward);
+
+        // Alternating between the ends, the two meet without reloading anything
+        let alternating = scan(&reader, |tree| {
+            let mut iter = tree.range(..).unwrap();
+            for i in 0..(i_len / 2) {
+                assert_eq!(*iter.next().unwrap().unwrap().0, i);
+                assert_eq!(*iter.next_back().unwrap().unwrap().0, i_len - 1 - i);
+            }
+            assert!(iter.next().is_none());
+            assert!(iter.next_back().is_none());
+        });
+        assert_eq!(loads_per_page(&alternating), forward);
+
+        // A reverse scan ending partway through leaves only loads the pages
+        // it needs, each once
+        let partial = scan(&reader, |tree| {
+            let keys: Vec<u64> = tree
+                .range(1001..=15003)
+                .unwrap()
+                .rev()
+                .map(|r| *r.unwrap().0)
+                .collect();
+            assert_eq!(keys, (1001..=15003).rev().collect::<Vec<_>>());
+        });
+        let partial = loads_per_page(&partial);
+        assert!(partial.len() < forward.len());
+        assert!(partial.values().all(|&n| n == 1), "{partial:?}");
+    }
+
+    #[test]
+    fn peek() {
+        let (reader, mut writer) = new_db();
+
+        // A lone leaf, empty and then not
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut iter = tree.range(..).unwrap();
+        assert!(iter.peek().is_none());
+        assert!(iter.peek_back().is_none());
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 0..5u64 {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut iter = tree.range(1..4).unwrap();
+        assert_eq!(*iter.peek().unwrap().unwrap().0, 1);
+        assert_eq!(*iter.peek_back().unwrap().unwrap().0, 3);
+        assert_eq!(*iter.next_back().unwrap().unwrap().0, 3);
+        assert_eq!(*iter.next_back().unwrap().unwrap().0, 2);
+        assert_eq!(*iter.peek().unwrap().unwrap().0, 1);
+        assert_eq!(*iter.peek_back().unwrap().unwrap().0, 1);
+        assert_eq!(*iter.next().unwrap().unwrap().0, 1);
+        assert!(iter.peek().is_none());
+        assert!(iter.peek_back().is_none());
+
+        // Many pages deep, peeking always matches what comes next, and never
+        // moves either end
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 5..20000u64 {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut iter = tree.range(100..19000).unwrap();
+        let (mut lo, mut hi) = (100u64, 18999u64);
+        while lo <= hi {
+            let front = iter.peek().unwrap().unwrap();
+            assert_eq!(iter.peek().unwrap().unwrap(), front);
+            let back = iter.peek_back().unwrap().unwrap();
+            assert_eq!((*front.0, *back.0), (lo, hi));
+            if lo % 3 == 0 {
+                assert_eq!(iter.next_back().unwrap().unwrap(), back);
+                hi -= 1;
+            } else {
+                assert_eq!(iter.next().unwrap().unwrap(), front);
+                lo += 1;
+            }
+        }
+        assert!(iter.peek().is_none());
+        assert!(iter.peek_back().is_none());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn split_fill_and_append() {
+        // Insert sequentially, returning the pages used and pages visited.
+        let i_len = 100000;
+        let ingest = |config: BTreeConfig| {
+            let (reader, mut writer) = new_db();
+            let counting = CountingWriter::new(&writer);
+            let (mut tree, root) = unsafe {
+                BTreeWrite::<LayoutU64U64, LayoutU64Var, _>::load_with_config(
+                    &counting,
+                    writer.root(),
+                    config,
+                )
+            }
+            .unwrap();
+            for i in 0..i_len {
+                match tree.entry(&i).unwrap() {
+                    Entry::Occupied(_) => panic!("All entries should be empty right now"),
+                    Entry::Vacant(v) => {
+                        v.insert(i.to_le_bytes().as_slice()).unwrap();
+                    }
+                }
+            }
+            drop(tree);
+            let descents = counting.snapshot().load_muts.total();
+            drop(counting);
+            if let Some(root) = root {
+                writer.set_root(root);
+            }
+            writer.commit().unwrap();
+
+            // Everything is still there, in order
+            let reader = reader.reload().unwrap();
+            let tree: ReadTree = reader.tree().unwrap();
+            let mut iter = tree.range(..).unwrap();
+            for i in 0..i_len {
+                let (k, v) = iter.next().expect("should've gotten a pair").unwrap();
+                assert_eq!(*k, i);
+                assert_eq!(v, i.to_le_bytes().as_slice());
+            }
+            assert!(iter.next().is_none());
+            (writer.page_count(), descents)
+        };
+
+        let (even_pages, _) = ingest(BTreeConfig::default());
+        let (full_pages, full_descents) = ingest(BTreeConfig {
+            split_fill: 0.9,
+            append_optimized: false,
+            scratch_pages: 0,
+        });
+        let (append_pages, append_descents) = ingest(BTreeConfig {
+            split_fill: 0.9,
+            append_optimized: true,
+            scratch_pages: 0,
+        });
+
+        // Packing the lower pages fuller takes far fewer pages for sequential inserts, and
+        // appending never takes more
+        assert!(append_pages <= full_pages && full_pages <= even_pages);
+        assert!(full_pages * 3 < even_pages * 2);
+        // Appending doesn't change the layout, but skips the branches entirely, only ever
+        // visiting the one leaf being appended to.
+        assert_eq!(append_pages, full_pages);
+        assert!(full_descents > i_len);
+        assert!(append_descents <= i_len);
+    }
+
+    #[test]
+    fn append_then_modify() {
+        // Appending, then deleting and inserting out of order, leaves a consistent tree.
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer
+            .tree_with_config(BTreeConfig {
+                split_fill: 1.0,
+                append_optimized: true,
+                scratch_pages: 0,
+            })
+            .unwrap();
+        for i in (0..20000u64).step_by(2) {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        for i in (0..20000u64).step_by(10) {
+            if let Entry::Occupied(o) = tree.entry(&i).unwrap() {
+                o.delete().unwrap();
+            }
+        }
+        for i in (1..30000u64).step_by(2) {
+            if let Entry::Vacant(v) = tree.entry(&i).unwrap() {
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+        }
+        writer.commit().unwrap();
+
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let expected = (0..30000u64).filter(|i| (i % 2 == 1) || (i < &20000 && i % 10 != 0));
+        let mut iter = tree.range(..).unwrap();
+        for i in expected {
+            let (k, v) = iter.next().expect("should've gotten a pair").unwrap();
+            assert_eq!(*k, i);
+            assert_eq!(v, i.to_le_bytes().as_slice());
+        }
+        assert!(iter.next().is_none());
+    }
+
+    /// Records the keys held by every page, in walk order.
+    #[derive(Default)]
+    struct KeysPerPage(Vec<(usize, Vec<u64>)>);
+
+    impl TreeVisitor<LayoutU64U64, LayoutU64Var> for KeysPerPage {
+        fn branch(
+            &mut self,
+            depth: usize,
+            _: u64,
+            map: &PageMap<LayoutU64U64>,
+        ) -> Result<WalkControl, Error> {
+            let keys = map.iter().map(|res| res.map(|(k, _)| *k));
+            self.0.push((depth, keys.collect::<Result<_, _>>()?));
+            Ok(WalkControl::Continue)
+        }
+
+        fn leaf(
+            &mut self,
+            depth: usize,
+            _: u64,
+            map: &PageMap<LayoutU64Var>,
+        ) -> Result<WalkControl, Error> {
+            let keys = map.iter().map(|res| res.map(|(k, _)| *k));
+            self.0.push((depth, keys.collect::<Result<_, _>>()?));
+            Ok(WalkControl::Continue)
+        }
+    }
+
+    #[test]
+    fn scratch_pages() {
+        // Growing values splits pages, then deleting and reinserting them small merges them back.
+        // Holding onto the merged pages lets the next round's splits reuse them.
+        let churn = |scratch_pages| {
+            let (reader, mut writer) = new_db();
+            let mut tree: Tree = writer.tree().unwrap();
+            for i in 0..2000u64 {
+                let Entry::Vacant(v) = tree.entry(&i).unwrap() else {
+                    panic!("All entries should be empty right now");
+                };
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+            writer.commit().unwrap();
+
+            let counting = CountingWriter::new(&writer);
+            let config = BTreeConfig {
+                scratch_pages,
+                ..BTreeConfig::default()
+            };
+            let (mut tree, root) = unsafe {
+                BTreeWrite::<LayoutU64U64, LayoutU64Var, _>::load_with_config(
+                    &counting,
+                    writer.root(),
+                    config,
+                )
+            }
+            .unwrap();
+            let big = [7u8; 200];
+            for _ in 0..10 {
+                for i in 0..2000u64 {
+                    let Entry::Occupied(o) = tree.entry(&i).unwrap() else {
+                        panic!("Key {i} should be in the tree");
+                    };
+                    o.replace(&big).unwrap();
+                }
+                for i in 0..2000u64 {
+                    let Entry::Occupied(o) = tree.entry(&i).unwrap() else {
+                        panic!("Key {i} should be in the tree");
+                    };
+                    o.delete().unwrap();
+                    let Entry::Vacant(v) = tree.entry(&i).unwrap() else {
+                        panic!("Key {i} was just deleted");
+                    };
+                    v.insert(i.to_le_bytes().as_slice()).unwrap();
+                }
+            }
+            let mut shape = KeysPerPage::default();
+            tree.as_read().walk(&mut shape).unwrap();
+            tree.release_scratch().unwrap();
+            drop(tree);
+            let counts = counting.snapshot();
+            drop(counting);
+            if let Some(root) = root {
+                writer.set_root(root);
+            }
+            writer.commit().unwrap();
+
+            let reader = reader.reload().unwrap();
+            let tree: ReadTree = reader.tree().unwrap();
+            let mut iter = tree.range(..).unwrap();
+            for i in 0..2000u64 {
+                let (k, v) = iter.next().expect("should've gotten a pair").unwrap();
+                assert_eq!(*k, i);
+                assert_eq!(v, i.to_le_bytes().as_slice());
+            }
+            assert!(iter.next().is_none());
+            (counts, shape.0)
+        };
+
+        let (plain, plain_shape) = churn(0);
+        let (pooled, pooled_shape) = churn(256);
+        let plain_churn = plain.allocates.total() + plain.deallocates.total();
+        let pooled_churn = pooled.allocates.total() + pooled.deallocates.total();
+
+        // Reusing pages doesn't change how the tree gets split up, only where the pages are.
+        assert_eq!(plain_shape, pooled_shape);
+        assert!(pooled_churn * 4 < plain_churn);
+    }
+
     #[test]
     fn sequential_insert_rev() {
         let (reader, mut writer) = new_db();
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         let i_len = 100000;
 
         // Insertion
@@ -603,12 +1010,12 @@ mod test {
                 }
             }
         }
-        writer.commit();
+        writer.commit().unwrap();
         println!("Writing complete, {} pages used", writer.page_count());
 
         // Post-insert check
-        let reader = reader.reload();
-        let tree = reader.tree().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in (0..i_len).rev() {
             let Some(val) = tree.get(&i).expect("no error") else {
                 panic!("expected to get a value for {}", i);
@@ -640,7 +1047,7 @@ mod test {
         println!("Reading complete");
 
         // Deletion
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         for i in (0..i_len).rev() {
             match tree.entry(&i).unwrap() {
                 Entry::Vacant(_) => panic!("All entries should be occupied, but {i} is unoccupied"),
@@ -649,16 +1056,16 @@ mod test {
                 }
             }
         }
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
         println!("Deletion complete, {} pages used", writer.page_count());
 
         // Post-delete check
-        let reader = reader.reload();
-        let tree = reader.tree().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in (0..i_len).rev() {
             assert!(tree.get(&i).unwrap().is_none());
         }
@@ -667,7 +1074,7 @@ mod test {
     #[test]
     fn sequential_var_insert_forward() {
         let (reader, mut writer) = new_db();
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         let i_len = 100000;
 
         fn idx_to_data(i: u64) -> &'static [u8] {
@@ -685,12 +1092,12 @@ mod test {
                 }
             }
         }
-        writer.commit();
+        writer.commit().unwrap();
         println!("Writing complete, {} pages used", writer.page_count());
 
         // Post-insert check
-        let reader = reader.reload();
-        let tree = reader.tree().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in 0..i_len {
             let val = tree.get(&i).unwrap().unwrap();
             assert_eq!(val, idx_to_data(i));
@@ -712,7 +1119,7 @@ mod test {
         println!("Reading complete");
 
         // Deletion
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         for i in 0..i_len {
             match tree.entry(&i).unwrap() {
                 Entry::Vacant(_) => panic!("All entries should be occupied"),
@@ -721,15 +1128,15 @@ mod test {
                 }
             }
         }
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
         println!("Deletion complete, {} pages used", writer.page_count());
 
         // Post-delete check
-        let tree = reader.tree().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in 0..i_len {
             assert!(tree.get(&i).unwrap().is_none());
         }
@@ -738,7 +1145,7 @@ mod test {
     #[test]
     fn sequential_var_insert_rev() {
         let (reader, mut writer) = new_db();
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         let i_len = 100000;
 
         // Insertion
@@ -751,12 +1158,12 @@ mod test {
                 }
             }
         }
-        writer.commit();
+        writer.commit().unwrap();
         println!("Writing complete, {} pages used", writer.page_count());
 
         // Post-insert check
-        let reader = reader.reload();
-        let tree = reader.tree().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in (0..i_len).rev() {
             let Some(val) = tree.get(&i).expect("no error") else {
                 panic!("expected to get a value for {}", i);
@@ -788,7 +1195,7 @@ mod test {
         println!("Reading complete");
 
         // Deletion
-        let mut tree = writer.tree().unwrap();
+        let mut tree: Tree = writer.tree().unwrap();
         for i in (0..i_len).rev() {
             match tree.entry(&i).unwrap() {
                 Entry::Vacant(_) => panic!("All entries should be occupied, but {i} is unoccupied"),
@@ -797,18 +1204,1158 @@ mod test {
                 }
             }
         }
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
-        let reader = reader.reload();
-        writer.commit();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        writer.commit().unwrap();
         println!("Deletion complete, {} pages used", writer.page_count());
 
         // Post-delete check
-        let reader = reader.reload();
-        let tree = reader.tree().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
         for i in (0..i_len).rev() {
             assert!(tree.get(&i).unwrap().is_none());
         }
     }
+
+    /// Counts up pages and pairs, and checks every leaf is at the same depth.
+    #[derive(Default)]
+    struct PageCounter {
+        branches: usize,
+        leaves: Vec<u64>,
+        pairs: usize,
+        leaf_depth: Option<usize>,
+    }
+
+    impl TreeVisitor<LayoutU64U64, LayoutU64Var> for PageCounter {
+        fn branch(
+            &mut self,
+            _: usize,
+            _: u64,
+            _: &PageMap<LayoutU64U64>,
+        ) -> Result<WalkControl, Error> {
+            self.branches += 1;
+            Ok(WalkControl::Continue)
+        }
+
+        fn leaf(
+            &mut self,
+            depth: usize,
+            page: u64,
+            map: &PageMap<LayoutU64Var>,
+        ) -> Result<WalkControl, Error> {
+            assert_eq!(*self.leaf_depth.get_or_insert(depth), depth);
+            self.leaves.push(page);
+            self.pairs += map.iter().count();
+            Ok(WalkControl::Continue)
+        }
+    }
+
+    #[test]
+    fn walk_counts_pages() {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.as_read().walk(&mut counter).unwrap();
+        assert_eq!(
+            (counter.branches, counter.leaves.len(), counter.pairs),
+            (0, 1, 0)
+        );
+
+        for i in 0..100000u64 {
+            let Entry::Vacant(v) = tree.entry(&i).unwrap() else {
+                panic!("All entries should be empty right now");
+            };
+            v.insert(i.to_le_bytes().as_slice()).unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+        assert_eq!(counter.pairs, 100000);
+        assert_eq!(counter.leaf_depth, Some(2));
+
+        // A full scan loads exactly the same leaves, and every branch once on
+        // the way. The range finds both of its ends up front, so the last leaf
+        // comes second, and the rest follow in the same order as the walk.
+        let counts = scan(&reader, |tree| {
+            assert_eq!(tree.range(..).unwrap().count(), 100000);
+        });
+        let leaves: BTreeSet<u64> = counter.leaves.iter().copied().collect();
+        assert_eq!(leaves.len(), counter.leaves.len());
+        let mut scanned: Vec<u64> = counts
+            .touched
+            .iter()
+            .copied()
+            .filter(|page| leaves.contains(page))
+            .collect();
+        let (last, rest) = counter.leaves.split_last().unwrap();
+        assert_eq!(scanned.remove(1), *last);
+        assert_eq!(scanned, rest);
+        assert_eq!(
+            counts.loads.total() as usize,
+            counter.branches + counter.leaves.len()
+        );
+        assert_eq!(loads_per_page(&counts).len(), counts.touched.len());
+    }
+
+    /// Skips the children of branches at one depth, and stops after some number of leaves.
+    struct Limited {
+        skip_depth: usize,
+        stop_after: usize,
+        visited: Vec<(usize, u64)>,
+        first_keys: Vec<u64>,
+    }
+
+    impl TreeVisitor<LayoutU64U64, LayoutU64Var> for Limited {
+        fn branch(
+            &mut self,
+            depth: usize,
+            page: u64,
+            _: &PageMap<LayoutU64U64>,
+        ) -> Result<WalkControl, Error> {
+            self.visited.push((depth, page));
+            Ok(if depth == self.skip_depth {
+                WalkControl::SkipChildren
+            } else {
+                WalkControl::Continue
+            })
+        }
+
+        fn leaf(
+            &mut self,
+            depth: usize,
+            page: u64,
+            map: &PageMap<LayoutU64Var>,
+        ) -> Result<WalkControl, Error> {
+            self.visited.push((depth, page));
+            self.first_keys.push(*map.iter().next().unwrap()?.0);
+            Ok(if self.first_keys.len() == self.stop_after {
+                WalkControl::Stop
+            } else {
+                WalkControl::Continue
+            })
+        }
+    }
+
+    #[test]
+    fn walk_control() {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        for i in 0..100000u64 {
+            let Entry::Vacant(v) = tree.entry(&i).unwrap() else {
+                panic!("All entries should be empty right now");
+            };
+            v.insert(i.to_le_bytes().as_slice()).unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+
+        // Skipping the root's children visits nothing else
+        let mut limited = Limited {
+            skip_depth: 0,
+            stop_after: usize::MAX,
+            visited: Vec::new(),
+            first_keys: Vec::new(),
+        };
+        tree.walk(&mut limited).unwrap();
+        assert_eq!(limited.visited, [(0, reader.root())]);
+
+        // Skipping the children of the branches below it visits only the branches
+        limited.skip_depth = 1;
+        limited.visited.clear();
+        tree.walk(&mut limited).unwrap();
+        assert_eq!(limited.visited.len(), counter.branches);
+        assert!(limited.visited.iter().all(|(depth, _)| *depth < 2));
+        assert!(limited.first_keys.is_empty());
+
+        // Stopping ends the walk right away, after the first leaves in key order
+        limited.skip_depth = usize::MAX;
+        limited.stop_after = 3;
+        limited.visited.clear();
+        tree.walk(&mut limited).unwrap();
+        assert_eq!(limited.first_keys.len(), 3);
+        assert_eq!(limited.first_keys[0], 0);
+        assert!(limited.first_keys.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            limited.visited.last().map(|(depth, _)| *depth),
+            Some(2),
+            "nothing should be visited after stopping"
+        );
+        assert_eq!(limited.visited.len(), 2 + 3);
+
+        // Errors from the visitor end the walk too
+        struct Fails;
+        impl TreeVisitor<LayoutU64U64, LayoutU64Var> for Fails {
+            fn leaf(
+                &mut self,
+                _: usize,
+                _: u64,
+                _: &PageMap<LayoutU64Var>,
+            ) -> Result<WalkControl, Error> {
+                Err(Error::InvalidState("visitor failed"))
+            }
+        }
+        assert_eq!(
+            tree.walk(&mut Fails),
+            Err(Error::InvalidState("visitor failed"))
+        );
+    }
+
+    fn delete(tree: &mut Tree, key: u64) -> Result<(), Error> {
+        match tree.entry(&key)? {
+            Entry::Vacant(_) => panic!("{key} should still be in the tree"),
+            Entry::Occupied(o) => o.delete(),
+        }
+    }
+
+    /// Delete `keys` in order from a committed tree, with faults injected by `inject` after every
+    /// possible number of calls, until the deletes all go through. Pages are only freed once an
+    /// operation succeeds, so whatever point it fails at, the tree never points to a freed page,
+    /// even if the transaction's committed anyway. Returns how many failures happened after the
+    /// failing delete had already removed its key, i.e. while balancing.
+    fn failed_deletes<I>(keys: I, inject: fn(&mut SimAllocator, Option<usize>)) -> usize
+    where
+        I: Iterator<Item = u64> + Clone,
+    {
+        let mut balances_failed = 0;
+        for limit in 1.. {
+            let (reader, mut writer) = new_db();
+            let mut tree: Tree = writer.tree().unwrap();
+            for i in 0..1000u64 {
+                let Entry::Vacant(v) = tree.entry(&i).unwrap() else {
+                    panic!("All entries should be empty right now");
+                };
+                v.insert(i.to_le_bytes().as_slice()).unwrap();
+            }
+            writer.commit().unwrap();
+            drop(reader);
+
+            inject(&mut writer, Some(limit));
+            let mut tree: Tree = writer.tree().unwrap();
+            let Some(failed) = keys.clone().position(|i| delete(&mut tree, i).is_err()) else {
+                return balances_failed;
+            };
+            writer.clear_faults().unwrap();
+            writer.commit().unwrap();
+            writer.commit().unwrap();
+
+            // The failed delete may or may not have removed its key before failing
+            let reader = writer.reader().unwrap();
+            let tree: ReadTree = reader.tree().unwrap();
+            let found: Vec<u64> = tree.range(..).unwrap().map(|res| *res.unwrap().0).collect();
+            let mut deleted: Vec<u64> = keys.clone().take(failed + 1).collect();
+            let failed = deleted.pop().unwrap();
+            if !found.contains(&failed) {
+                balances_failed += 1;
+                deleted.push(failed);
+            }
+            let expected: Vec<u64> = (0..1000).filter(|k| !deleted.contains(k)).collect();
+            assert_eq!(found, expected, "faults injected after {limit} calls");
+            for k in &expected {
+                assert_eq!(tree.get(k).unwrap().unwrap(), k.to_le_bytes().as_slice());
+            }
+
+            // And the tree's still usable afterwards
+            let mut tree: Tree = writer.tree().unwrap();
+            for k in expected {
+                delete(&mut tree, k).unwrap();
+            }
+            writer.commit().unwrap();
+            let reader = writer.reader().unwrap();
+            let tree: ReadTree = reader.tree().unwrap();
+            assert!(tree.range(..).unwrap().next().is_none());
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn failed_balance_allocate() {
+        let failed = failed_deletes(250..750, SimAllocator::fail_allocations_after);
+        assert!(failed > 0, "no delete failed while balancing");
+    }
+
+    #[test]
+    fn failed_balance_load() {
+        // Deleting from the end balances each leaf with its lower neighbor, which gets copied
+        // before the leaf itself fails to load.
+        let failed = failed_deletes((750..1000).rev(), SimAllocator::fail_load_muts_after);
+        assert!(failed > 0, "no delete failed while balancing");
+    }
+
+    /// Collects the first and last key of every leaf, and every key in every branch.
+    #[derive(Default)]
+    struct Boundaries {
+        leaves: Vec<(u64, u64)>,
+        separators: Vec<u64>,
+        leaf_depth: usize,
+    }
+
+    impl TreeVisitor<LayoutU64U64, LayoutU64Var> for Boundaries {
+        fn branch(
+            &mut self,
+            _: usize,
+            _: u64,
+            map: &PageMap<LayoutU64U64>,
+        ) -> Result<WalkControl, Error> {
+            for pair in map.iter() {
+                self.separators.push(*pair?.0);
+            }
+            Ok(WalkControl::Continue)
+        }
+
+        fn leaf(
+            &mut self,
+            depth: usize,
+            _: u64,
+            map: &PageMap<LayoutU64Var>,
+        ) -> Result<WalkControl, Error> {
+            let first = *map.iter().next().unwrap()?.0;
+            let last = *map.iter().next_back().unwrap()?.0;
+            self.leaves.push((first, last));
+            self.leaf_depth = depth;
+            Ok(WalkControl::Continue)
+        }
+    }
+
+    type Model = BTreeMap<u64, Vec<u8>>;
+
+    /// Insert `(key, value length)` pairs in the order given, commit, and return the committed
+    /// tree's reader along with the model of what should be in it.
+    fn build_shape<I>(pairs: I) -> (SimReader, SimAllocator, Model)
+    where
+        I: Iterator<Item = (u64, usize)>,
+    {
+        let (reader, mut writer) = new_db();
+        let mut tree: Tree = writer.tree().unwrap();
+        let mut model = Model::new();
+        for (k, len) in pairs {
+            let value: Vec<u8> = (0..len).map(|i| (k as usize + i) as u8).collect();
+            let Entry::Vacant(v) = tree.entry(&k).unwrap() else {
+                panic!("{k} was inserted twice");
+            };
+            v.insert(&value).unwrap();
+            model.insert(k, value);
+        }
+        writer.commit().unwrap();
+        (reader.reload().unwrap(), writer, model)
+    }
+
+    /// Check a range yields exactly what the model does, iterating from either end.
+    fn check_range(tree: &ReadTree, model: &Model, range: (Bound<u64>, Bound<u64>)) {
+        let expected: Vec<(u64, &[u8])> = model
+            .range(range)
+            .map(|(k, v)| (*k, v.as_slice()))
+            .collect();
+        let forward: Vec<(u64, &[u8])> = tree
+            .range(range)
+            .unwrap()
+            .map(|res| res.map(|(k, v)| (*k, v)).unwrap())
+            .collect();
+        assert_eq!(forward, expected, "forward over {range:?}");
+        let mut backward: Vec<(u64, &[u8])> = tree
+            .range(range)
+            .unwrap()
+            .rev()
+            .map(|res| res.map(|(k, v)| (*k, v)).unwrap())
+            .collect();
+        backward.reverse();
+        assert_eq!(backward, expected, "backward over {range:?}");
+    }
+
+    /// Run ranges starting and ending on every leaf and branch boundary in the tree, and check
+    /// each comes out in the same order as the model. Returns the depth of the tree's leaves.
+    fn check_ordering(reader: &SimReader, model: &Model) -> usize {
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut bounds = Boundaries::default();
+        tree.walk(&mut bounds).unwrap();
+
+        // First and last key of each leaf, the gap between leaves, and every separator
+        let mut probes = bounds.separators.clone();
+        for (first, last) in &bounds.leaves {
+            probes.extend([*first, *last, last + 1]);
+        }
+        probes.sort_unstable();
+        probes.dedup();
+
+        for (i, start) in probes.iter().enumerate() {
+            let end = probes[(i + 7).min(probes.len() - 1)];
+            if end <= *start {
+                continue;
+            }
+            for start in [Bound::Included(*start), Bound::Excluded(*start)] {
+                for end in [Bound::Included(end), Bound::Excluded(end)] {
+                    check_range(&tree, model, (start, end));
+                }
+            }
+            if i % 64 == 0 {
+                check_range(&tree, model, (Bound::Included(*start), Bound::Unbounded));
+                check_range(&tree, model, (Bound::Excluded(*start), Bound::Unbounded));
+                check_range(&tree, model, (Bound::Unbounded, Bound::Included(*start)));
+                check_range(&tree, model, (Bound::Unbounded, Bound::Excluded(*start)));
+            }
+        }
+        bounds.leaf_depth
+    }
+
+    #[test]
+    fn range_order_uniform() {
+        // Every third key, inserted in a scattered order
+        let n = 60000u64;
+        let (reader, _writer, model) = build_shape((0..n).map(|i| (i * 7919 % n * 3, 8)));
+        assert!(check_ordering(&reader, &model) >= 2);
+    }
+
+    #[test]
+    fn range_order_skewed() {
+        // Every tenth value is as large as it can be, so leaf sizes vary wildly
+        let (reader, _writer, model) = build_shape((0..40000u64).map(|i| {
+            let len = if i % 10 == 0 { MAX_VAR_SIZE } else { 4 };
+            (i * 2, len)
+        }));
+        assert!(check_ordering(&reader, &model) >= 2);
+    }
+
+    #[test]
+    fn range_order_sequential() {
+        let (reader, _writer, model) = build_shape((0..100000u64).map(|i| (i, 8)));
+        assert!(check_ordering(&reader, &model) >= 2);
+    }
+
+    #[test]
+    fn range_starts_on_separator() {
+        let (reader, _writer, _) = build_shape((0..100000u64).map(|i| (i, 8)));
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut bounds = Boundaries::default();
+        tree.walk(&mut bounds).unwrap();
+        assert!(bounds.leaf_depth >= 2, "tree should have at least 3 levels");
+
+        let first = |range: (Bound<u64>, Bound<u64>)| -> Option<u64> {
+            tree.range(range).unwrap().next().map(|res| *res.unwrap().0)
+        };
+        let last = |range: (Bound<u64>, Bound<u64>)| -> Option<u64> {
+            tree.range(range)
+                .unwrap()
+                .next_back()
+                .map(|res| *res.unwrap().0)
+        };
+        for s in bounds.separators.into_iter().filter(|s| *s > 0) {
+            assert_eq!(first((Bound::Included(s), Bound::Unbounded)), Some(s));
+            assert_eq!(first((Bound::Excluded(s), Bound::Unbounded)), Some(s + 1));
+            assert_eq!(first((Bound::Excluded(s - 1), Bound::Unbounded)), Some(s));
+            assert_eq!(last((Bound::Unbounded, Bound::Included(s))), Some(s));
+            assert_eq!(last((Bound::Unbounded, Bound::Excluded(s))), Some(s - 1));
+            let single: Vec<u64> = tree
+                .range(s..=s)
+                .unwrap()
+                .map(|res| *res.unwrap().0)
+                .collect();
+            assert_eq!(single, [s]);
+        }
+    }
+
+    #[test]
+    fn update_range_grows_values() {
+        let (reader, mut writer, mut model) = build_shape((0..5000u64).map(|i| (i, 8)));
+        drop(reader);
+        let mut tree: Tree = writer.tree().unwrap();
+
+        // Append to every other value in the range, twice over, until the leaves holding them
+        // have split several times
+        for pass in 0..2u8 {
+            let mut seen = Vec::new();
+            let replaced = tree
+                .update_range(1000..4000, |k, v| {
+                    seen.push(*k);
+                    (k % 2 == 0).then(|| {
+                        let mut v = v.to_vec();
+                        v.extend(core::iter::repeat_n(pass, 100));
+                        v
+                    })
+                })
+                .unwrap();
+            assert_eq!(replaced, 1500);
+            assert_eq!(seen, (1000..4000).collect::<Vec<u64>>(), "pass {pass}");
+            for (_, v) in model.range_mut(1000..4000).filter(|(k, _)| *k % 2 == 0) {
+                v.extend(core::iter::repeat_n(pass, 100));
+            }
+        }
+        writer.commit().unwrap();
+
+        let reader = writer.reader().unwrap();
+        check_ordering(&reader, &model);
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+        assert_eq!(counter.pairs, 5000);
+
+        // An empty range doesn't call the closure at all
+        let mut tree: Tree = writer.tree().unwrap();
+        let replaced = tree
+            .update_range(6000.., |_, _| panic!("nothing should be in range"))
+            .unwrap();
+        assert_eq!(replaced, 0);
+    }
+
+    #[test]
+    fn remove_range() {
+        // Even keys only, so some ranges can fall between them
+        let (reader, mut writer, mut model) = build_shape((0..100000u64).map(|i| (i * 2, 8)));
+        drop(reader);
+        let ranges = [
+            (Bound::Included(40000), Bound::Excluded(120000)),
+            (Bound::Unbounded, Bound::Included(2000)),
+            (Bound::Excluded(180000), Bound::Unbounded),
+            (Bound::Included(150001), Bound::Included(150001)),
+            (Bound::Excluded(150000), Bound::Excluded(150002)),
+            (Bound::Included(120000), Bound::Included(120000)),
+            (Bound::Included(130001), Bound::Excluded(170000)),
+            (Bound::Excluded(2000), Bound::Excluded(2010)),
+        ];
+        for range in ranges {
+            let mut tree: Tree = writer.tree().unwrap();
+            let removed = tree.remove_range(range).unwrap();
+            let expected: Vec<u64> = model.range(range).map(|(k, _)| *k).collect();
+            assert_eq!(removed, expected.len() as u64, "removing {range:?}");
+            for k in expected {
+                model.remove(&k);
+            }
+            writer.commit().unwrap();
+
+            let reader = writer.reader().unwrap();
+            assert!(check_ordering(&reader, &model) >= 1);
+            let tree: ReadTree = reader.tree().unwrap();
+            let mut counter = PageCounter::default();
+            tree.walk(&mut counter).unwrap();
+            assert_eq!(counter.pairs, model.len());
+        }
+
+        // Every page that got dropped was freed
+        writer.commit().unwrap();
+        let mut counter = PageCounter::default();
+        {
+            let reader = writer.reader().unwrap();
+            let tree: ReadTree = reader.tree().unwrap();
+            tree.walk(&mut counter).unwrap();
+        }
+        writer.commit().unwrap();
+        assert_eq!(writer.page_count(), counter.branches + counter.leaves.len());
+
+        // Taking out everything leaves an empty root leaf, ready for more
+        let mut tree: Tree = writer.tree().unwrap();
+        assert_eq!(tree.remove_range(..).unwrap(), model.len() as u64);
+        assert_eq!(tree.remove_range(..).unwrap(), 0);
+        assert_eq!(tree.insert(&5, b"five").unwrap(), None);
+        writer.commit().unwrap();
+        let reader = writer.reader().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.range(..).unwrap().count(), 1);
+        assert_eq!(tree.get(&5).unwrap(), Some(b"five".as_slice()));
+        drop(reader);
+        writer.commit().unwrap();
+        assert_eq!(writer.page_count(), 1);
+    }
+
+    #[test]
+    fn count_range() {
+        let (_, mut writer) = new_db();
+        let mut model = Model::new();
+        let mut rng = Rng::new(0xc0_47);
+
+        // Grow the tree with mostly inserts, then shrink it with mostly deletes. Values of all
+        // sizes keep the pages splitting and merging the whole way through.
+        for round in 0..6 {
+            let mut tree: Tree = writer.tree().unwrap();
+            let inserts = if round < 3 { 3 } else { 1 };
+            for _ in 0..12000 {
+                let k = rng.below(30000);
+                if rng.below(4) < inserts {
+                    let value = vec![k as u8; rng.below(300) as usize];
+                    tree.insert(&k, &value).unwrap();
+                    model.insert(k, value);
+                } else {
+                    assert_eq!(tree.remove(&k).unwrap(), model.remove(&k));
+                }
+            }
+            writer.commit().unwrap();
+
+            let reader = writer.reader().unwrap();
+            let tree: ReadTree = reader.tree().unwrap();
+            assert_eq!(tree.len().unwrap(), model.len() as u64, "round {round}");
+            assert!(!tree.is_empty().unwrap());
+
+            // Random ranges, plus ones starting and ending right on every separator
+            let mut bounds = Boundaries::default();
+            tree.walk(&mut bounds).unwrap();
+            assert!(bounds.leaf_depth >= 1);
+            let check = tree.verify().unwrap();
+            assert!(check.is_ok(), "round {round}: {:?}", check.problems);
+            assert_eq!(check.depth, bounds.leaf_depth);
+            assert_eq!(check.entries, model.len() as u64);
+            let mut ranges = Vec::new();
+            for s in bounds.separators.into_iter().step_by(8) {
+                ranges.push((Bound::Included(s), Bound::Unbounded));
+                ranges.push((Bound::Excluded(s), Bound::Unbounded));
+                ranges.push((Bound::Unbounded, Bound::Included(s)));
+                ranges.push((Bound::Unbounded, Bound::Excluded(s)));
+            }
+            for _ in 0..200 {
+                let a = rng.below(31000);
+                let b = a + rng.below(10000) + 1;
+                let start = [Bound::Included(a), Bound::Excluded(a), Bound::Unbounded];
+                let end = [Bound::Included(b), Bound::Excluded(b), Bound::Unbounded];
+                let pick = rng.next_u64() as usize;
+                ranges.push((start[pick % 3], end[pick / 3 % 3]));
+            }
+            for range in ranges {
+                let expected = model.range(range).count() as u64;
+                assert_eq!(tree.count_range(range).unwrap(), expected, "{range:?}");
+            }
+        }
+
+        let mut tree: Tree = writer.tree().unwrap();
+        tree.remove_range(..).unwrap();
+        writer.commit().unwrap();
+        let reader = writer.reader().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.len().unwrap(), 0);
+        assert!(tree.is_empty().unwrap());
+        assert_eq!(tree.count_range(5..10).unwrap(), 0);
+    }
+
+    #[test]
+    fn first_last_and_pop() {
+        let (reader, mut writer) = new_db();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.first_key_value().unwrap(), None);
+        assert_eq!(tree.last_key_value().unwrap(), None);
+        let mut tree: Tree = writer.tree().unwrap();
+        assert_eq!(tree.pop_first().unwrap(), None);
+        assert_eq!(tree.pop_last().unwrap(), None);
+
+        let mut model = Model::new();
+        for i in 0..20000u64 {
+            let k = i * 7919 % 20000;
+            let value = vec![k as u8; (k % 50) as usize];
+            tree.insert(&k, &value).unwrap();
+            model.insert(k, value);
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.first_key_value().unwrap(), Some((&0, &[][..])));
+        let last = tree.last_key_value().unwrap();
+        assert_eq!(last, Some((&19999, &[31; 49][..])));
+
+        // Pop from both ends until the tree is empty, merging pages all the way down
+        let mut tree: Tree = writer.tree().unwrap();
+        while let Some(first) = model.pop_first() {
+            assert_eq!(tree.pop_first().unwrap(), Some(first));
+            assert_eq!(tree.pop_last().unwrap(), model.pop_last());
+            if model.len() % 1000 == 0 {
+                let read = tree.as_read();
+                let first = read.first_key_value().unwrap();
+                assert_eq!(first, model.first_key_value().map(|(k, v)| (k, &v[..])));
+                let last = read.last_key_value().unwrap();
+                assert_eq!(last, model.last_key_value().map(|(k, v)| (k, &v[..])));
+            }
+        }
+        assert_eq!(tree.pop_first().unwrap(), None);
+        assert_eq!(tree.pop_last().unwrap(), None);
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert_eq!(tree.first_key_value().unwrap(), None);
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+        assert_eq!((counter.branches, counter.leaves.len()), (0, 1));
+    }
+
+    #[test]
+    fn remove_long_keys() {
+        // Keys of up to a kilobyte, so only a few fit on a page. Removing empties out whole
+        // leaves, and putting in a branch's new first key can split it.
+        let key = |i: u32| {
+            let mut key = i.to_be_bytes().to_vec();
+            key.resize(8 + (i as usize * 37 % 1000), i as u8);
+            key
+        };
+        let n = 3000u32;
+        let orders: [(&str, Vec<u32>); 3] = [
+            ("ascending", (0..n).collect()),
+            ("descending", (0..n).rev().collect()),
+            ("scattered", (0..n).map(|i| i * 1009 % n).collect()),
+        ];
+        for (order, removals) in orders {
+            let (_, mut writer) = new_db();
+            let mut tree: BTreeWrite<LayoutVarU64, LayoutVarU64, _> = writer.tree().unwrap();
+            for i in (0..n).map(|i| i * 7 % n) {
+                tree.insert(&key(i), &(i as u64)).unwrap();
+            }
+            for (done, i) in removals.into_iter().enumerate() {
+                let removed = tree.remove(&key(i)).unwrap();
+                assert_eq!(removed, Some(i as u64), "{order} removal {done}");
+                if done % 10 == 0 {
+                    let check = tree.as_read().verify().unwrap();
+                    assert!(check.is_ok(), "{order} removal {done}: {check:?}");
+                    assert_eq!(check.entries, (n as usize - done - 1) as u64);
+                }
+            }
+            assert!(tree.as_read().is_empty().unwrap());
+        }
+    }
+
+    #[test]
+    fn get_pair_and_contains_key() {
+        let (reader, mut writer) = new_db();
+        let mut tree: BTreeWrite<LayoutVarU64, LayoutVarU64, _> = writer.tree().unwrap();
+        for i in 0..5000u64 {
+            let key = format!("key-{:05}", i * 3);
+            tree.insert(key.as_bytes(), &i).unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: BTreeRead<LayoutVarU64, LayoutVarU64, _> = reader.tree().unwrap();
+        for i in 0..15000u64 {
+            let key = format!("key-{i:05}");
+            let pair = tree.get_pair(key.as_bytes()).unwrap();
+            if i % 3 == 0 {
+                assert_eq!(pair, Some((key.as_bytes(), &(i / 3))));
+                assert!(tree.contains_key(key.as_bytes()).unwrap());
+            } else {
+                assert_eq!(pair, None);
+                assert!(!tree.contains_key(key.as_bytes()).unwrap());
+            }
+            assert_eq!(tree.get(key.as_bytes()).unwrap(), pair.map(|(_, v)| v));
+        }
+        assert!(!tree.contains_key(&b"zzz"[..]).unwrap());
+        assert_eq!(tree.get_pair(&b""[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn keys_and_values() {
+        let (reader, _writer, model) =
+            build_shape((0..20000u64).map(|i| (i * 3, (i % 50) as usize)));
+        let tree: ReadTree = reader.tree().unwrap();
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(1000), Bound::Excluded(40001)),
+        ];
+        for range in ranges {
+            let keys: Vec<u64> = model.range(range).map(|(k, _)| *k).collect();
+            let values: Vec<&[u8]> = model.range(range).map(|(_, v)| v.as_slice()).collect();
+            let forward: Vec<u64> = tree.keys(range).unwrap().map(|k| *k.unwrap()).collect();
+            assert_eq!(forward, keys, "keys over {range:?}");
+            let mut backward: Vec<u64> = tree
+                .keys(range)
+                .unwrap()
+                .rev()
+                .map(|k| *k.unwrap())
+                .collect();
+            backward.reverse();
+            assert_eq!(backward, keys, "keys backward over {range:?}");
+            let forward: Vec<&[u8]> = tree.values(range).unwrap().map(|v| v.unwrap()).collect();
+            assert_eq!(forward, values, "values over {range:?}");
+            let mut backward: Vec<&[u8]> = tree
+                .values(range)
+                .unwrap()
+                .rev()
+                .map(|v| v.unwrap())
+                .collect();
+            backward.reverse();
+            assert_eq!(backward, values, "values backward over {range:?}");
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        // Short keys full of 0x00 and 0xFF bytes, and plenty of them so there are several leaves
+        let mut keys: BTreeSet<Vec<u8>> = BTreeSet::new();
+        let bytes = [0x00, 0x01, b'a', 0xfe, 0xff];
+        for len in 1..=4 {
+            for i in 0..bytes.len().pow(len) {
+                let mut i = i;
+                let key = (0..len).map(|_| {
+                    let b = bytes[i % bytes.len()];
+                    i /= bytes.len();
+                    b
+                });
+                keys.insert(key.collect());
+            }
+        }
+        for i in 0..3000u32 {
+            keys.insert(format!("key-{i}").into_bytes());
+        }
+
+        let (reader, mut writer) = new_db();
+        let mut tree: BTreeWrite<LayoutVarU64, LayoutVarU64, _> = writer.tree().unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, &(i as u64)).unwrap();
+        }
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        let tree: BTreeRead<LayoutVarU64, LayoutVarU64, _> = reader.tree().unwrap();
+
+        // Every stored key, and every prefix of one, including the empty one
+        let mut prefixes: BTreeSet<&[u8]> = BTreeSet::new();
+        for key in &keys {
+            prefixes.extend((0..=key.len()).map(|len| &key[..len]));
+        }
+        prefixes.insert(b"key-3\xff");
+        prefixes.insert(b"\xff\xff\xff\xff\xff");
+        prefixes.insert(b"zzz");
+        for prefix in prefixes {
+            let expected: Vec<&[u8]> = keys
+                .iter()
+                .filter(|k| k.starts_with(prefix))
+                .map(|k| &k[..])
+                .collect();
+            let forward: Vec<&[u8]> = tree.prefix(prefix).unwrap().map(|r| r.unwrap().0).collect();
+            assert_eq!(forward, expected, "{prefix:x?}");
+            let mut backward: Vec<&[u8]> = tree
+                .prefix(prefix)
+                .unwrap()
+                .rev()
+                .map(|r| r.unwrap().0)
+                .collect();
+            backward.reverse();
+            assert_eq!(backward, expected, "{prefix:x?}");
+        }
+        assert_eq!(tree.prefix(b"").unwrap().count(), keys.len());
+        assert_eq!(tree.prefix(b"\xff\xff").unwrap().count(), 1 + 5 + 25);
+    }
+
+    #[test]
+    fn range_mut() {
+        let (reader, mut writer, mut model) = build_shape((0..20000u64).map(|i| (i * 2, 8)));
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+        assert!(counter.leaves.len() > 20);
+
+        // Walking the whole tree copies every page exactly once, the root included, freeing each
+        // original once the copy is in place
+        let counting = CountingWriter::new(&writer);
+        let (mut tree, root) =
+            unsafe { BTreeWrite::<LayoutU64U64, LayoutU64Var, _>::load(&counting, writer.root()) }
+                .unwrap();
+        let mut seen = 0;
+        for pair in tree.range_mut(..) {
+            let (k, v) = pair.unwrap();
+            assert_eq!(*k, seen * 2);
+            v[0] = v[0].wrapping_add(1);
+            seen += 1;
+        }
+        assert_eq!(seen, 20000);
+        drop(tree);
+        let counts = counting.snapshot();
+        drop(counting);
+        writer.set_root(root.unwrap());
+        let pages = counter.branches + counter.leaves.len();
+        assert_eq!(counts.deallocates.total() as usize, pages);
+        for v in model.values_mut() {
+            v[0] = v[0].wrapping_add(1);
+        }
+
+        // Bump a counter in every value across a span of leaves, starting and ending between keys
+        let mut tree: Tree = writer.tree().unwrap();
+        for pair in tree.range_mut(1001..=30001) {
+            let (_, v) = pair.unwrap();
+            v[1..].fill(0xaa);
+        }
+        for v in model.range_mut(1001..=30001).map(|(_, v)| v) {
+            v[1..].fill(0xaa);
+        }
+        assert_eq!(tree.range_mut(5001..5002).count(), 0);
+        assert_eq!(tree.range_mut(40000..).count(), 0);
+        let last: Vec<u64> = tree.range_mut(39990..).map(|p| *p.unwrap().0).collect();
+        assert_eq!(last, [39990, 39992, 39994, 39996, 39998]);
+        writer.commit().unwrap();
+
+        let reader = reader.reload().unwrap();
+        check_ordering(&reader, &model);
+    }
+
+    #[test]
+    fn cursor_append() {
+        // Append sorted keys from past the last pair. Each one goes into the leaf the cursor is
+        // already holding, so the branches are only visited again after a split.
+        let n = 100000u64;
+        let (reader, mut writer) = new_db();
+        let counting = CountingWriter::new(&writer);
+        let (mut tree, root) =
+            unsafe { BTreeWrite::<LayoutU64U64, LayoutU64Var, _>::load(&counting, writer.root()) }
+                .unwrap();
+        {
+            let mut cursor = tree.cursor();
+            for k in 0..n {
+                cursor.insert_before(&k, &k.to_le_bytes()).unwrap();
+            }
+            assert_eq!(cursor.key(), None);
+            assert_eq!(cursor.value().unwrap(), None);
+            assert_eq!(
+                cursor.insert_before(&(n - 1), &[]),
+                Err(Error::IncorrectOperation)
+            );
+            assert!(cursor.prev().unwrap());
+            assert_eq!(cursor.key(), Some(&(n - 1)));
+            assert_eq!(cursor.value().unwrap(), Some(&(n - 1).to_le_bytes()[..]));
+        }
+        drop(tree);
+        let loads = counting.snapshot().load_muts.total() as usize;
+        drop(counting);
+        if let Some(root) = root {
+            writer.set_root(root);
+        }
+        writer.commit().unwrap();
+
+        let reader = reader.reload().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+        let depth = counter.leaf_depth.unwrap();
+        assert!(depth >= 2);
+        assert!(loads < n as usize + counter.leaves.len() * (depth + 2) * 2);
+        let model = (0..n).map(|k| (k, k.to_le_bytes().to_vec())).collect();
+        check_ordering(&reader, &model);
+    }
+
+    #[test]
+    fn cursor_edits() {
+        // Keys start out every 16 apart, leaving room to insert between them
+        let (_, mut writer, mut model) = build_shape((0..4000u64).map(|i| (i * 16, 40)));
+        let mut rng = Rng::new(0xc5_50);
+        let mut tree: Tree = writer.tree().unwrap();
+
+        // Wander around with the cursor, inserting and deleting as it goes, with values big
+        // enough that leaves keep splitting and merging under it. Rounds alternate between
+        // growing and shrinking the tree, though never so far that it loses its branches.
+        for round in 0..8 {
+            let mut cursor = tree.cursor();
+            let mut at: Option<u64> = None;
+            for _ in 0..6000 {
+                let grow = round % 2 == 0 || model.len() < 1000;
+                match rng.below(16) {
+                    0 => {
+                        let k = rng.below(70000);
+                        at = model.range(k..).next().map(|(k, _)| *k);
+                        assert_eq!(cursor.seek(&k).unwrap(), at.is_some());
+                    }
+                    1 => {
+                        at = model.keys().next().copied();
+                        assert_eq!(cursor.seek_first().unwrap(), at.is_some());
+                    }
+                    2..=5 => {
+                        let moved = at.map(|a| model.range(a + 1..).next().map(|(k, _)| *k));
+                        assert_eq!(cursor.next().unwrap(), moved.is_some_and(|m| m.is_some()));
+                        at = moved.flatten();
+                    }
+                    6..=8 => {
+                        let prev = match at {
+                            Some(a) => model.range(..a).next_back(),
+                            None => model.iter().next_back(),
+                        };
+                        let prev = prev.map(|(k, _)| *k);
+                        assert_eq!(cursor.prev().unwrap(), prev.is_some());
+                        at = prev.or(at);
+                    }
+                    9..=12 if grow => {
+                        // Mostly keys that fit right before the cursor, and some that don't
+                        let prev = match at {
+                            Some(a) => model.range(..a).next_back(),
+                            None => model.iter().next_back(),
+                        };
+                        let low = prev.map_or(0, |(k, _)| k + 1);
+                        let high = at.unwrap_or(low + 16);
+                        let k = if high > low && rng.below(8) != 0 {
+                            low + rng.below(high - low)
+                        } else {
+                            rng.below(70000)
+                        };
+                        let value = vec![k as u8; rng.below(200) as usize];
+                        let res = cursor.insert_before(&k, &value);
+                        if k >= low && at.map_or(true, |a| k < a) {
+                            res.unwrap();
+                            model.insert(k, value);
+                        } else {
+                            assert_eq!(res, Err(Error::IncorrectOperation), "{k} at {at:?}");
+                        }
+                    }
+                    _ => {
+                        let deleted = cursor.delete_current().unwrap();
+                        assert_eq!(deleted, at.is_some());
+                        if let Some(a) = at {
+                            model.remove(&a);
+                            at = model.range(a..).next().map(|(k, _)| *k);
+                        }
+                    }
+                }
+                assert_eq!(cursor.key(), at.as_ref());
+                let value = cursor.value().unwrap();
+                assert_eq!(value, at.map(|a| &model[&a][..]));
+            }
+
+            let read = tree.as_read();
+            let all = read.range::<u64, _>(..).unwrap().map(|r| *r.unwrap().0);
+            assert!(all.eq(model.keys().copied()), "round {round}");
+            let check = read.verify().unwrap();
+            assert!(check.is_ok(), "round {round}: {:?}", check.problems);
+            assert_eq!(check.entries, model.len() as u64, "round {round}");
+        }
+        writer.commit().unwrap();
+
+        let reader = writer.reader().unwrap();
+        check_ordering(&reader, &model);
+        let tree: ReadTree = reader.tree().unwrap();
+        let mut counter = PageCounter::default();
+        tree.walk(&mut counter).unwrap();
+        assert_eq!(writer.page_count(), counter.branches + counter.leaves.len());
+    }
+
+    /// Keys held by a leaf page.
+    fn leaf_keys(reader: &SimReader, page: u64) -> Vec<u64> {
+        let map = unsafe { PageMap::<LayoutU64Var>::from_page(reader.load_page(page).unwrap()) };
+        map.unwrap().iter().map(|r| *r.unwrap().0).collect()
+    }
+
+    #[test]
+    fn skip_corrupt() {
+        let (reader, mut writer, model) = build_shape((0..20000u64).map(|i| (i, 8)));
+        let mut counter = PageCounter::default();
+        let tree: ReadTree = reader.tree().unwrap();
+        tree.walk(&mut counter).unwrap();
+        let leaves = counter.leaves;
+        assert!(leaves.len() > 20);
+
+        // Wreck the trailer of one leaf so it can't be loaded at all, and the size of the last
+        // entry in another so it fails partway through
+        let (unloadable, malformed) = (leaves[leaves.len() / 3], leaves[2 * leaves.len() / 3]);
+        let lost = leaf_keys(&reader, unloadable);
+        let kept = leaf_keys(&reader, malformed);
+        drop(reader);
+        unsafe {
+            writer
+                .corrupt_page(unloadable, |page| {
+                    page[format::TRAILER_LOWER_LEN..][..2].copy_from_slice(&[0xff, 0xff])
+                })
+                .unwrap();
+            writer
+                .corrupt_page(malformed, |page| {
+                    let len = u16::from_le_bytes([
+                        page[format::TRAILER_UPPER_LEN],
+                        page[format::TRAILER_UPPER_LEN + 1],
+                    ]) as usize;
+                    let last = CONTENT_SIZE - len * format::U64_VAR_INFO_SIZE;
+                    page[last + format::U64_VAR_INFO_VALUE_LEN..][..2]
+                        .copy_from_slice(&[0xff, 0x0f]);
+                })
+                .unwrap();
+        }
+        let reader = writer.reader().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+
+        // A plain scan stops at the first bad leaf
+        let strict: Result<Vec<_>, Error> = tree.range(..).unwrap().collect();
+        assert!(matches!(strict, Err(Error::DataCorruption(_))));
+
+        // Skipping gets everything else, with one error for each bad leaf
+        let mut iter = tree.range(..).unwrap().skip_corrupt();
+        let mut found = Vec::new();
+        let mut skipped = Vec::new();
+        for res in iter.by_ref() {
+            match res {
+                Ok((k, v)) => {
+                    assert_eq!(model[k], v);
+                    found.push(*k);
+                }
+                Err(e) => skipped.push(e.page),
+            }
+        }
+        assert_eq!(skipped, [unloadable, malformed]);
+        assert_eq!(iter.skipped(), 2);
+        let from_malformed: Vec<u64> = found.iter().copied().filter(|k| kept.contains(k)).collect();
+        assert!(from_malformed.len() < kept.len());
+        assert!(kept.starts_with(&from_malformed));
+        let expected: Vec<u64> = model
+            .keys()
+            .copied()
+            .filter(|k| !lost.contains(k) && (!kept.contains(k) || from_malformed.contains(k)))
+            .collect();
+        assert_eq!(found, expected);
+
+        // Point lookups still fail on the bad leaf
+        assert!(tree.get(&lost[0]).is_err());
+        assert!(tree.get(&(lost[0] - 1)).unwrap().is_some());
+    }
+
+    #[test]
+    fn verify() {
+        let (reader, mut writer, model) = build_shape((0..20000u64).map(|i| (i, 8)));
+        let mut counter = PageCounter::default();
+        let tree: ReadTree = reader.tree().unwrap();
+        tree.walk(&mut counter).unwrap();
+        let check = tree.verify().unwrap();
+        assert_eq!(
+            check,
+            TreeCheck {
+                pages: (counter.branches + counter.leaves.len()) as u64,
+                depth: 1,
+                entries: model.len() as u64,
+                problems: Vec::new(),
+            }
+        );
+
+        // Bump the root's second key so it no longer matches its child, point its fourth entry at
+        // the third child as well, and wreck the trailer of the sixth child
+        let root = writer.root();
+        let leaves = counter.leaves;
+        let lost = leaf_keys(&reader, leaves[3]).len() + leaf_keys(&reader, leaves[5]).len();
+        drop(reader);
+        unsafe {
+            writer
+                .corrupt_page(root, |page| {
+                    let key = format::TRAILER_OFFSET - 2 * format::U64_U64_INFO_SIZE
+                        + format::U64_U64_INFO_KEY;
+                    let bumped = u64::from_le_bytes(page[key..][..8].try_into().unwrap()) + 1;
+                    page[key..][..8].copy_from_slice(&bumped.to_le_bytes());
+                    page[24..32].copy_from_slice(&leaves[2].to_le_bytes());
+                })
+                .unwrap();
+            writer
+                .corrupt_page(leaves[5], |page| {
+                    page[format::TRAILER_LOWER_LEN..][..2].copy_from_slice(&[0xff, 0xff])
+                })
+                .unwrap();
+        }
+        let reader = writer.reader().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        let check = tree.verify().unwrap();
+        let pages: Vec<u64> = check.problems.iter().map(|p| p.page).collect();
+        assert_eq!(pages, [leaves[1], leaves[2], leaves[5]]);
+        assert!(check
+            .problems
+            .iter()
+            .all(|p| matches!(p.error, Error::DataCorruption(_))));
+        let pages = counter.branches + leaves.len() - 2;
+        assert_eq!(check.pages, pages as u64);
+        assert_eq!(check.entries, (model.len() - lost) as u64);
+
+        // A branch pointing back up at itself can't be walked at all
+        drop(reader);
+        unsafe {
+            writer
+                .corrupt_page(root, |page| {
+                    page[8..16].copy_from_slice(&root.to_le_bytes())
+                })
+                .unwrap();
+        }
+        let reader = writer.reader().unwrap();
+        let tree: ReadTree = reader.tree().unwrap();
+        assert!(matches!(tree.verify(), Err(Error::DataCorruption(_))));
+    }
 }
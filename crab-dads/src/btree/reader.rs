@@ -1,4 +1,7 @@
-use alloc::collections::VecDeque;
+use alloc::{
+    collections::{BTreeSet, VecDeque},
+    vec::Vec,
+};
 use core::{
     borrow::Borrow,
     cmp::Ordering,
@@ -6,8 +9,9 @@ use core::{
 };
 
 use crate::{
-    page::{self, PageIter, PageLayout, PageMap},
-    Error,
+    format::PAGE_TYPE_LEAF,
+    page::{self, PageIter, PageLayout, PageMap, CONTENT_SIZE},
+    Error, NULL_PAGE,
 };
 
 use super::RawRead;
@@ -52,7 +56,7 @@ where
                 }
             }
             Bound::Included(b) => {
-                if k.borrow() >= b {
+                if k.borrow() <= b {
                     break;
                 }
             }
@@ -86,6 +90,9 @@ where
                 match k_borrow.cmp(b) {
                     Ordering::Less => (),
                     Ordering::Equal => {
+                        // `peek` is still sitting just before this entry, so the
+                        // page starting on the bound stays in the iterator, and
+                        // only the pages entirely below it get dropped.
                         *iter = peek;
                         break;
                     }
@@ -119,6 +126,37 @@ where
     Ok(())
 }
 
+/// Whether `range` holds every key from `lower` up to `upper`, or every key from `lower` on if
+/// there's no `upper`. Like a branch entry's span, `upper` itself is left out.
+pub(crate) fn covers<K, Q, R>(range: &R, lower: &K, upper: Option<&K>) -> bool
+where
+    K: Borrow<Q> + ?Sized,
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    let lower = lower.borrow();
+    let start = match range.start_bound() {
+        Bound::Included(s) => s <= lower,
+        Bound::Excluded(s) => s < lower,
+        Bound::Unbounded => true,
+    };
+    let end = match (range.end_bound(), upper) {
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(e) | Bound::Excluded(e), Some(u)) => u.borrow() <= e,
+        (_, None) => false,
+    };
+    start && end
+}
+
+/// The first byte string after every string starting with `prefix`, or `None` if there isn't one
+/// because the prefix is nothing but 0xFF bytes.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|b| *b != 0xff)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
 pub struct BTreeRead<'a, B, L, R>
 where
     B: PageLayout<Value = u64>,
@@ -126,9 +164,45 @@ where
     R: RawRead,
 {
     reader: &'a R,
+    root_page: u64,
     root: ReadPage<'a, B, L>,
 }
 
+/// What [`BTreeRead::walk`] should do after visiting a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalkControl {
+    /// Carry on, descending into this page's children if it's a branch.
+    Continue,
+    /// Carry on, but don't descend into this page's children. The same as
+    /// `Continue` for a leaf.
+    SkipChildren,
+    /// End the walk right away.
+    Stop,
+}
+
+/// Visits pages during a [`BTreeRead::walk`].
+///
+/// Each page gets borrowed to the visitor as it's reached, along with its page
+/// number and depth, where the root is at depth 0. Both methods default to
+/// doing nothing and carrying on.
+pub trait TreeVisitor<B, L>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+{
+    /// Visit a branch page, before any of its children.
+    fn branch(&mut self, depth: usize, page: u64, map: &PageMap<B>) -> Result<WalkControl, Error> {
+        let _ = (depth, page, map);
+        Ok(WalkControl::Continue)
+    }
+
+    /// Visit a leaf page.
+    fn leaf(&mut self, depth: usize, page: u64, map: &PageMap<L>) -> Result<WalkControl, Error> {
+        let _ = (depth, page, map);
+        Ok(WalkControl::Continue)
+    }
+}
+
 #[derive(Clone)]
 pub(crate) enum ReadPage<'a, B, L>
 where
@@ -145,9 +219,12 @@ where
     L: PageLayout<Key = B::Key>,
 {
     pub unsafe fn try_load<R: RawRead>(reader: &'a R, page: u64) -> Result<Self, Error> {
+        if page == NULL_PAGE {
+            return Err(Error::NullPage);
+        }
         unsafe {
             let page_ptr = reader.load_page(page)?;
-            if (page::page_type(page_ptr) & 1) == 1 {
+            if (page::page_type(page_ptr) & PAGE_TYPE_LEAF) != 0 {
                 Ok(ReadPage::Leaf(PageMap::from_page(page_ptr)?))
             } else {
                 Ok(ReadPage::Branch(PageMap::from_page(page_ptr)?))
@@ -162,7 +239,8 @@ where
     L: PageLayout<Key = B::Key>,
     R: RawRead,
 {
-    /// Load in the root page of a tree.
+    /// Load in the root page of a tree. Fails with [`Error::LayoutMismatch`]
+    /// if it was written with different layouts than `B` and `L`.
     ///
     /// # Safety
     ///
@@ -171,16 +249,110 @@ where
     pub unsafe fn load(reader: &'a R, page: u64) -> Result<Self, Error> {
         unsafe {
             let root = ReadPage::try_load(reader, page)?;
-            Ok(Self { reader, root })
+            Ok(Self {
+                reader,
+                root_page: page,
+                root,
+            })
         }
     }
 
-    pub(crate) unsafe fn from_parts(reader: &'a R, root: ReadPage<'a, B, L>) -> Self {
-        Self { reader, root }
+    /// Load in the root page of a tree, first checking that the reader is at
+    /// the generation the root page number was taken from. Fails with
+    /// [`Error::StaleGeneration`] if it isn't.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`load`][Self::load].
+    pub unsafe fn load_with_generation(
+        reader: &'a R,
+        page: u64,
+        expected_gen: u64,
+    ) -> Result<Self, Error> {
+        let found = reader.generation();
+        if found != expected_gen {
+            return Err(Error::StaleGeneration {
+                expected: expected_gen,
+                found,
+            });
+        }
+        unsafe { Self::load(reader, page) }
+    }
+
+    pub(crate) unsafe fn from_parts(
+        reader: &'a R,
+        root_page: u64,
+        root: ReadPage<'a, B, L>,
+    ) -> Self {
+        Self {
+            reader,
+            root_page,
+            root,
+        }
     }
 
     /// Fetch the value for a key.
     pub fn get<Q>(&self, key: &Q) -> Result<Option<&L::Value>, Error>
+    where
+        L::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(self.find(key)?.map(|(_, v)| v))
+    }
+
+    /// Fetch the pair for a key, with the key as it's stored in the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn get_pair<Q>(&self, key: &Q) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error>
+    where
+        L::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.find(key)
+    }
+
+    /// Check if the tree holds a key. Only the keys are read on the leaf, never the values.
+    pub fn contains_key<Q>(&self, key: &Q) -> Result<bool, Error>
+    where
+        L::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(leaf) = self.leaf_for(key)? else {
+            return Ok(false);
+        };
+        for result in leaf.iter().keys() {
+            match result?.borrow().cmp(key) {
+                Ordering::Equal => return Ok(true),
+                Ordering::Less => continue,
+                Ordering::Greater => return Ok(false),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Descend to the leaf that would hold a key, and find its pair there.
+    #[allow(clippy::type_complexity)]
+    fn find<Q>(&self, key: &Q) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error>
+    where
+        L::Key: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let Some(leaf) = self.leaf_for(key)? else {
+            return Ok(None);
+        };
+        for result in leaf.iter() {
+            let (k, v) = result?;
+            match k.borrow().cmp(key) {
+                Ordering::Equal => return Ok(Some((k, v))),
+                Ordering::Less => continue,
+                Ordering::Greater => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Descend to the leaf that would hold a key. `None` if the key is below everything in the
+    /// tree, so no leaf could hold it.
+    fn leaf_for<Q>(&self, key: &Q) -> Result<Option<PageMap<'a, L>>, Error>
     where
         L::Key: Borrow<Q>,
         Q: Ord + ?Sized,
@@ -198,25 +370,7 @@ where
                     }
                     return Ok(None);
                 }
-                ReadPage::Leaf(l) => {
-                    for result in l.iter() {
-                        let (k, v) = result?;
-                        let k: &Q = k.borrow();
-                        match k.cmp(key) {
-                            Ordering::Equal => {
-                                // SAFETY: We know the page holding v is valid
-                                // and immutable as long as we have the reader,
-                                // so we can extract the object directly and
-                                // give it a new lifetime.
-                                let v = unsafe { &*(v as *const L::Value) };
-                                return Ok(Some(v));
-                            }
-                            Ordering::Less => continue,
-                            Ordering::Greater => return Ok(None),
-                        }
-                    }
-                    return Ok(None);
-                }
+                ReadPage::Leaf(l) => return Ok(Some(l)),
             }
         }
         Err(Error::DataCorruption(
@@ -224,6 +378,42 @@ where
         ))
     }
 
+    /// The pair with the lowest key, found by going straight down the left edge of the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn first_key_value(&self) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
+        self.edge(true)
+    }
+
+    /// The pair with the highest key, found by going straight down the right edge of the tree.
+    #[allow(clippy::type_complexity)]
+    pub fn last_key_value(&self) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
+        self.edge(false)
+    }
+
+    /// Descend down the left or right edge of the tree to the pair at the end of it.
+    #[allow(clippy::type_complexity)]
+    fn edge(&self, first: bool) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
+        let mut page = self.root.clone();
+        for _ in 0..64 {
+            let branch = match page {
+                ReadPage::Branch(b) => b,
+                ReadPage::Leaf(l) => {
+                    let mut iter = l.iter();
+                    let pair = if first { iter.next() } else { iter.next_back() };
+                    return pair.transpose();
+                }
+            };
+            let mut iter = branch.iter();
+            let child = if first { iter.next() } else { iter.next_back() };
+            let (_, child) =
+                child.ok_or(Error::DataCorruption("A branch page was somehow empty"))??;
+            page = unsafe { ReadPage::try_load(self.reader, *child)? };
+        }
+        Err(Error::DataCorruption(
+            "B-Tree depth for an edge descent is unreasonably large",
+        ))
+    }
+
     pub fn range<T, RANGE>(&self, range: RANGE) -> Result<BTreeIter<'a, B, L, R>, Error>
     where
         T: Ord + ?Sized,
@@ -235,10 +425,7 @@ where
             ReadPage::Leaf(l) => {
                 let mut iter = l.iter();
                 trim_leaf(&mut iter, &range)?;
-                return Ok(BTreeIter {
-                    reader: self.reader,
-                    state: BTreeIterState::Leaf(iter),
-                });
+                return Ok(BTreeIter::new(self.reader, Some((self.root_page, iter))));
             }
             ReadPage::Branch(b) => b,
         };
@@ -248,7 +435,7 @@ where
         trim_branch(&mut base, &range)?;
 
         let mut left = VecDeque::with_capacity(8);
-        left.push_back(base);
+        left.push_back((self.root_page, base));
 
         // Delve down the left-hand side of the tree, pushing branches onto the
         // queue as we descend. Because it's possible to hit a page and find
@@ -265,11 +452,8 @@ where
 
             // Fetch the next page address
             let page_addr = loop {
-                let Some(iter) = left.back_mut() else {
-                    return Ok(BTreeIter {
-                        reader: self.reader,
-                        state: BTreeIterState::Empty,
-                    });
+                let Some((_, iter)) = left.back_mut() else {
+                    return Ok(BTreeIter::new(self.reader, None));
                 };
                 let Some(page) = iter.next() else {
                     left.pop_back();
@@ -283,19 +467,19 @@ where
                 ReadPage::Branch(b) => {
                     let mut iter = b.iter();
                     trim_branch(&mut iter, &range)?;
-                    left.push_back(iter);
+                    left.push_back((page_addr, iter));
                 }
                 ReadPage::Leaf(l) => {
                     let mut iter = l.iter();
                     trim_leaf(&mut iter, &range)?;
-                    break iter;
+                    break (page_addr, iter);
                 }
             }
         };
 
         // Descend on the right side this time, zippering up the left-hand side
         // as we go.
-        let mut right: VecDeque<PageIter<'a, B>> = VecDeque::with_capacity(8);
+        let mut right: VecDeque<(u64, PageIter<'a, B>)> = VecDeque::with_capacity(8);
         let right_leaf = loop {
             if right.len() > 64 {
                 return Err(Error::DataCorruption(
@@ -304,19 +488,16 @@ where
             }
 
             let page = loop {
-                if let Some(iter) = right.back_mut() {
+                if let Some((_, iter)) = right.back_mut() {
                     if let Some(page) = iter.next_back() {
                         break page;
                     }
                     right.pop_back();
                 } else {
-                    let Some(iter) = left.front_mut() else {
+                    let Some((_, iter)) = left.front_mut() else {
                         // Single page case - we ended up descending on the
                         // exact same path as the left-hand side.
-                        return Ok(BTreeIter {
-                            reader: self.reader,
-                            state: BTreeIterState::Leaf(left_leaf),
-                        });
+                        return Ok(BTreeIter::new(self.reader, Some(left_leaf)));
                     };
                     if let Some(page) = iter.next_back() {
                         break page;
@@ -331,156 +512,532 @@ where
                 ReadPage::Branch(b) => {
                     let mut iter = b.iter();
                     trim_branch(&mut iter, &range)?;
-                    right.push_back(iter);
+                    right.push_back((page_addr, iter));
                 }
                 ReadPage::Leaf(l) => {
                     let mut iter = l.iter();
                     trim_leaf(&mut iter, &range)?;
-                    break iter;
+                    break (page_addr, iter);
                 }
             }
         };
 
         Ok(BTreeIter {
             reader: self.reader,
-            state: BTreeIterState::Full(BTreeIterFull {
-                left,
-                right,
-                left_leaf,
-                right_leaf,
-            }),
+            left,
+            right,
+            front: Some(left_leaf),
+            back: Some(right_leaf),
+            fault: None,
         })
     }
 
-    pub fn debug_dump(&self) -> Result<(), Error> {
-        let base = match &self.root {
+    /// Iterate over just the keys in `range`. See [`range`][Self::range].
+    pub fn keys<T, RANGE>(&self, range: RANGE) -> Result<BTreeKeys<'a, B, L, R>, Error>
+    where
+        T: Ord + ?Sized,
+        L::Key: Borrow<T> + Ord,
+        RANGE: RangeBounds<T>,
+    {
+        Ok(BTreeKeys(self.range(range)?))
+    }
+
+    /// Iterate over just the values in `range`, in key order. See [`range`][Self::range].
+    pub fn values<T, RANGE>(&self, range: RANGE) -> Result<BTreeValues<'a, B, L, R>, Error>
+    where
+        T: Ord + ?Sized,
+        L::Key: Borrow<T> + Ord,
+        RANGE: RangeBounds<T>,
+    {
+        Ok(BTreeValues(self.range(range)?))
+    }
+
+    /// Count the pairs in `range`.
+    ///
+    /// Only the leaves at the edges of the range get their keys compared. Any page between them
+    /// is entirely inside the range, so each leaf below it is counted straight off its trailer.
+    /// That still loads every page in the range, but never reads through its pairs.
+    pub fn count_range<T, RANGE>(&self, range: RANGE) -> Result<u64, Error>
+    where
+        T: Ord + ?Sized,
+        L::Key: Borrow<T> + Ord,
+        RANGE: RangeBounds<T>,
+    {
+        self.count_page(&self.root, &range, None, 0)
+    }
+
+    /// Count every pair in the tree. See [`count_range`][Self::count_range].
+    pub fn len(&self) -> Result<u64, Error> {
+        self.count_range::<L::Key, _>(..)
+    }
+
+    /// Check if the tree has no pairs in it, without counting them.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.range::<L::Key, _>(..)?.next().transpose()?.is_none())
+    }
+
+    /// Count the pairs in `range` held below `page`, whose keys all come before `upper`.
+    fn count_page<T, RANGE>(
+        &self,
+        page: &ReadPage<'a, B, L>,
+        range: &RANGE,
+        upper: Option<&'a L::Key>,
+        depth: usize,
+    ) -> Result<u64, Error>
+    where
+        T: Ord + ?Sized,
+        L::Key: Borrow<T> + Ord,
+        RANGE: RangeBounds<T>,
+    {
+        let branch = match page {
             ReadPage::Leaf(l) => {
-                eprintln!("Root Leaf:");
-                eprintln!("{:#?}", l);
-                l.verify().unwrap();
-                return Ok(());
-            }
-            ReadPage::Branch(b) => {
-                eprintln!("Root Branch:");
-                eprintln!("{:#?}", b);
-                b.verify().unwrap();
-                b
+                let mut iter = l.iter();
+                trim_leaf(&mut iter, range)?;
+                return iter.try_fold(0, |n, res| res.map(|_| n + 1));
             }
+            ReadPage::Branch(b) => b,
         };
+        if depth >= 64 {
+            return Err(Error::DataCorruption(
+                "B-Tree depth for `count_range` is unreasonably large",
+            ));
+        }
 
-        let branch = base.iter();
-        let mut stack = Vec::with_capacity(8);
-        stack.push(branch);
-        loop {
-            let Some(branch) = stack.last_mut() else {
-                return Ok(());
-            };
-            let Some(page) = branch.next() else {
-                stack.pop();
-                continue;
-            };
-            let page_addr = *(page?.1);
-
-            let new_page = unsafe { ReadPage::<B, L>::try_load(self.reader, page_addr)? };
+        let mut count = 0;
+        let mut iter = branch.iter();
+        let mut entry = iter.next().transpose()?;
+        while let Some((lower, child)) = entry {
+            entry = iter.next().transpose()?;
+            let next = entry.map(|(k, _)| k).or(upper);
 
-            match new_page {
-                ReadPage::Branch(b) => {
-                    eprintln!("Branch ({page_addr}):");
-                    eprintln!("{:#?}", b);
-                    b.verify().unwrap();
-                    let iter = b.iter();
-                    stack.push(iter);
-                }
-                ReadPage::Leaf(l) => {
-                    eprintln!("Leaf ({page_addr}):");
-                    eprintln!("{:#?}", l);
-                    l.verify().unwrap();
+            // Skip pages below the range, and stop at the first one above it
+            if let Some(next) = next {
+                let below = match range.start_bound() {
+                    Bound::Included(s) | Bound::Excluded(s) => next.borrow() <= s,
+                    Bound::Unbounded => false,
+                };
+                if below {
+                    continue;
                 }
             }
-
-            if stack.len() > 64 {
-                return Err(Error::DataCorruption(
-                    "debug B-Tree depth is unreasonably large",
-                ));
+            let above = match range.end_bound() {
+                Bound::Included(e) => lower.borrow() > e,
+                Bound::Excluded(e) => lower.borrow() >= e,
+                Bound::Unbounded => false,
+            };
+            if above {
+                break;
             }
+
+            let child_page = unsafe { ReadPage::try_load(self.reader, *child)? };
+            count += if covers(range, lower, next) {
+                let mut pairs = PairCount(0);
+                unsafe { Self::from_parts(self.reader, *child, child_page) }.walk(&mut pairs)?;
+                pairs.0
+            } else {
+                self.count_page(&child_page, range, next, depth + 1)?
+            };
         }
+        Ok(count)
     }
 
-    pub fn debug_dump_branches(&self) -> Result<(), Error> {
-        let base = match &self.root {
-            ReadPage::Leaf(_) => {
+    /// Visit every page in the tree, depth-first and in key order, with each
+    /// branch visited before its children. Stops early if the visitor returns
+    /// an error or [`WalkControl::Stop`].
+    pub fn walk<V: TreeVisitor<B, L>>(&self, visitor: &mut V) -> Result<(), Error> {
+        let root = match &self.root {
+            ReadPage::Leaf(l) => {
+                visitor.leaf(0, self.root_page, l)?;
                 return Ok(());
             }
             ReadPage::Branch(b) => {
-                eprintln!("Root Branch:");
-                eprintln!("{:#?}", b);
+                if visitor.branch(0, self.root_page, b)? != WalkControl::Continue {
+                    return Ok(());
+                }
                 b
             }
         };
 
-        let branch = base.iter();
         let mut stack = Vec::with_capacity(8);
-        stack.push(branch);
-        loop {
-            let Some(branch) = stack.last_mut() else {
-                return Ok(());
-            };
-            let Some(page) = branch.next() else {
+        stack.push(root.iter());
+        while let Some(branch) = stack.last_mut() {
+            let Some(res) = branch.next() else {
                 stack.pop();
                 continue;
             };
-            let page_addr = *(page?.1);
-
-            let new_page = unsafe { ReadPage::<B, L>::try_load(self.reader, page_addr)? };
+            let page = *(res?.1);
+            let depth = stack.len();
 
-            match new_page {
+            let control = match unsafe { ReadPage::<B, L>::try_load(self.reader, page)? } {
                 ReadPage::Branch(b) => {
-                    eprintln!("Branch ({page_addr}):");
-                    eprintln!("{:#?}", b);
-                    let iter = b.iter();
-                    stack.push(iter);
+                    let control = visitor.branch(depth, page, &b)?;
+                    if control == WalkControl::Continue {
+                        if depth >= 64 {
+                            return Err(Error::DataCorruption(
+                                "B-Tree depth for `walk` is unreasonably large",
+                            ));
+                        }
+                        stack.push(b.iter());
+                    }
+                    control
                 }
-                ReadPage::Leaf(_) => {}
+                ReadPage::Leaf(l) => visitor.leaf(depth, page, &l)?,
+            };
+            if control == WalkControl::Stop {
+                break;
             }
+        }
+        Ok(())
+    }
 
-            if stack.len() > 64 {
-                return Err(Error::DataCorruption(
-                    "debug B-Tree depth is unreasonably large",
-                ));
+    /// Check the structure of the whole tree, collecting every problem found rather than stopping
+    /// at the first one.
+    ///
+    /// On top of the key ordering [`PageMap::verify`] checks within each page, every branch key
+    /// has to match the first key of its child, with everything below it coming before the next
+    /// branch key along. Leaves all have to be at the same depth with only branches above them,
+    /// no page can be referenced twice, and the only page allowed to be empty is the root of an
+    /// empty tree. A page that can't be loaded or fails its own checks is reported, and nothing
+    /// below it gets looked at.
+    ///
+    /// Fails outright if a page refers back to one of the branches above it, as there's no
+    /// getting through a cycle.
+    pub fn verify(&self) -> Result<TreeCheck, Error>
+    where
+        L::Key: Ord,
+    {
+        let mut verifier = Verifier {
+            check: TreeCheck::default(),
+            visited: BTreeSet::new(),
+            leaf_depth: None,
+        };
+        verifier.visited.insert(self.root_page);
+        let mut stack = Vec::with_capacity(8);
+        if let Some(iter) = verifier.visit(0, self.root_page, &self.root, None, None) {
+            stack.push((self.root_page, iter, None));
+        }
+
+        while let Some((branch, iter, branch_upper)) = stack.last_mut() {
+            let branch = *branch;
+            let (lower, page) = match iter.next() {
+                None => {
+                    stack.pop();
+                    continue;
+                }
+                Some(Err(e)) => {
+                    verifier.problem(branch, e);
+                    stack.pop();
+                    continue;
+                }
+                Some(Ok((lower, page))) => (lower, *page),
+            };
+            // A bad next entry gets reported on the next time around, so fall back on the bound
+            // from further up until then
+            let upper = match iter.clone().next() {
+                Some(Ok((next, _))) => Some(next),
+                _ => *branch_upper,
+            };
+            let depth = stack.len();
+
+            if !verifier.visited.insert(page) {
+                if stack.iter().any(|(p, _, _)| *p == page) {
+                    return Err(Error::DataCorruption(
+                        "B-Tree page refers back to a branch above it",
+                    ));
+                }
+                verifier.problem(
+                    page,
+                    Error::DataCorruption("Page is referenced more than once"),
+                );
+                continue;
+            }
+            let child = match unsafe { ReadPage::<B, L>::try_load(self.reader, page) } {
+                Ok(child) => child,
+                Err(e) => {
+                    verifier.problem(page, e);
+                    continue;
+                }
+            };
+            if let Some(iter) = verifier.visit(depth, page, &child, Some(lower), upper) {
+                stack.push((page, iter, upper));
             }
         }
+        Ok(verifier.check)
+    }
+
+    pub fn debug_dump(&self) -> Result<(), Error> {
+        self.walk(&mut DebugDump { leaves: true })
+    }
+
+    pub fn debug_dump_branches(&self) -> Result<(), Error> {
+        self.walk(&mut DebugDump { leaves: false })
     }
 }
 
-pub struct BTreeIter<'a, B, L, R>
+impl<'a, B, L, R> BTreeRead<'a, B, L, R>
+where
+    B: PageLayout<Key = [u8], Value = u64>,
+    L: PageLayout<Key = [u8]>,
+    R: RawRead,
+{
+    /// Iterate over every pair whose key starts with `prefix`, in key order. An empty prefix
+    /// covers the whole tree.
+    pub fn prefix(&self, prefix: &[u8]) -> Result<BTreeIter<'a, B, L, R>, Error> {
+        let end = prefix_end(prefix);
+        let end = match &end {
+            Some(end) => Bound::Excluded(&end[..]),
+            None => Bound::Unbounded,
+        };
+        self.range::<[u8], _>((Bound::Included(prefix), end))
+    }
+}
+
+/// Counts the pairs in every leaf, without reading through them.
+struct PairCount(u64);
+
+impl<B, L> TreeVisitor<B, L> for PairCount
 where
     B: PageLayout<Value = u64>,
     L: PageLayout<Key = B::Key>,
-    R: RawRead,
 {
-    reader: &'a R,
-    state: BTreeIterState<'a, B, L>,
+    fn leaf(&mut self, _: usize, _: u64, map: &PageMap<L>) -> Result<WalkControl, Error> {
+        self.0 += map.page_trailer().lengths::<u8, L>(CONTENT_SIZE)?.upper as u64;
+        Ok(WalkControl::Continue)
+    }
+}
+
+/// Prints every page it visits, checking the key ordering of each.
+struct DebugDump {
+    /// Print leaves too, not just branches
+    leaves: bool,
 }
 
-enum BTreeIterState<'a, B, L>
+impl<B, L> TreeVisitor<B, L> for DebugDump
 where
     B: PageLayout<Value = u64>,
     L: PageLayout<Key = B::Key>,
 {
-    Empty,
-    Leaf(PageIter<'a, L>),
-    Full(BTreeIterFull<'a, B, L>),
+    fn branch(&mut self, depth: usize, page: u64, map: &PageMap<B>) -> Result<WalkControl, Error> {
+        if depth == 0 {
+            eprintln!("Root Branch:");
+        } else {
+            eprintln!("Branch ({page}):");
+        }
+        eprintln!("{:#?}", map);
+        if self.leaves {
+            map.verify()?;
+        }
+        Ok(WalkControl::Continue)
+    }
+
+    fn leaf(&mut self, depth: usize, page: u64, map: &PageMap<L>) -> Result<WalkControl, Error> {
+        if !self.leaves {
+            return Ok(WalkControl::Continue);
+        }
+        if depth == 0 {
+            eprintln!("Root Leaf:");
+        } else {
+            eprintln!("Leaf ({page}):");
+        }
+        eprintln!("{:#?}", map);
+        map.verify()?;
+        Ok(WalkControl::Continue)
+    }
+}
+
+/// What [`BTreeRead::verify`] found.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TreeCheck {
+    /// Number of pages checked, which leaves out any that couldn't be loaded
+    pub pages: u64,
+    /// Depth of the deepest page checked, where the root is at depth 0. All the leaves are at
+    /// this depth in a sound tree.
+    pub depth: usize,
+    /// Number of pairs in the leaves checked
+    pub entries: u64,
+    /// Every problem found, in the order the pages were reached
+    pub problems: Vec<TreeProblem>,
 }
 
-struct BTreeIterFull<'a, B, L>
+impl TreeCheck {
+    /// Check if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A structural problem found by [`BTreeRead::verify`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct TreeProblem {
+    /// Page number of the page with the problem
+    pub page: u64,
+    /// What's wrong with it
+    pub error: Error,
+}
+
+/// Running state for [`BTreeRead::verify`].
+struct Verifier {
+    check: TreeCheck,
+    /// Every page referenced so far, whether or not it could be loaded
+    visited: BTreeSet<u64>,
+    /// Depth of the first leaf reached
+    leaf_depth: Option<usize>,
+}
+
+impl Verifier {
+    fn problem(&mut self, page: u64, error: Error) {
+        self.check.problems.push(TreeProblem { page, error });
+    }
+
+    /// Check a page reached through a branch entry with the key `lower`, whose keys all have to
+    /// come before `upper`. Hands back the page's entries if it's a branch that can be descended
+    /// into.
+    fn visit<'a, B, L>(
+        &mut self,
+        depth: usize,
+        page: u64,
+        map: &ReadPage<'a, B, L>,
+        lower: Option<&B::Key>,
+        upper: Option<&B::Key>,
+    ) -> Option<PageIter<'a, B>>
+    where
+        B: PageLayout<Value = u64>,
+        L: PageLayout<Key = B::Key>,
+        B::Key: Ord,
+    {
+        self.check.pages += 1;
+        self.check.depth = self.check.depth.max(depth);
+        match self.check_page(depth, page, map, lower, upper) {
+            Ok(iter) => iter,
+            Err(e) => {
+                self.problem(page, e);
+                None
+            }
+        }
+    }
+
+    /// Does the work for [`visit`][Self::visit], failing on any problem that means the page's
+    /// children can't be checked.
+    fn check_page<'a, B, L>(
+        &mut self,
+        depth: usize,
+        page: u64,
+        map: &ReadPage<'a, B, L>,
+        lower: Option<&B::Key>,
+        upper: Option<&B::Key>,
+    ) -> Result<Option<PageIter<'a, B>>, Error>
+    where
+        B: PageLayout<Value = u64>,
+        L: PageLayout<Key = B::Key>,
+        B::Key: Ord,
+    {
+        let (edges, iter) = match map {
+            ReadPage::Leaf(l) => {
+                l.verify()?;
+                self.check.entries += l.page_trailer().lengths::<u8, L>(CONTENT_SIZE)?.upper as u64;
+                match self.leaf_depth {
+                    None => self.leaf_depth = Some(depth),
+                    Some(d) if d != depth => self.problem(
+                        page,
+                        Error::DataCorruption("Leaf is at a different depth than the first leaf"),
+                    ),
+                    Some(_) => (),
+                }
+                (edges(l)?, None)
+            }
+            ReadPage::Branch(b) => {
+                b.verify()?;
+                if self.leaf_depth.is_some_and(|d| depth >= d) {
+                    return Err(Error::DataCorruption("Branch is as deep as the leaves"));
+                }
+                if depth >= 64 {
+                    return Err(Error::DataCorruption(
+                        "B-Tree depth for `verify` is unreasonably large",
+                    ));
+                }
+                (edges(b)?, Some(b.iter()))
+            }
+        };
+
+        let Some((first, last)) = edges else {
+            if depth > 0 || iter.is_some() {
+                return Err(Error::DataCorruption(
+                    "Page is empty, but isn't an empty root leaf",
+                ));
+            }
+            return Ok(None);
+        };
+        if lower.is_some_and(|lower| lower != first) {
+            self.problem(
+                page,
+                Error::DataCorruption("Branch key doesn't match the first key of its child"),
+            );
+        }
+        if upper.is_some_and(|upper| last >= upper) {
+            self.problem(
+                page,
+                Error::DataCorruption("Page holds keys past the next key in its parent"),
+            );
+        }
+        Ok(iter)
+    }
+}
+
+/// The first and last keys in a page, or `None` if it's empty.
+#[allow(clippy::type_complexity)]
+fn edges<'a, T: PageLayout>(
+    map: &PageMap<'a, T>,
+) -> Result<Option<(&'a T::Key, &'a T::Key)>, Error> {
+    let mut keys = map.iter().keys();
+    let Some(first) = keys.next().transpose()? else {
+        return Ok(None);
+    };
+    let last = keys.next_back().transpose()?.unwrap_or(first);
+    Ok(Some((first, last)))
+}
+
+/// Iterator over a range of a B-tree, from either end.
+///
+/// Each end keeps the leaf it's currently in, along with the stack of branches
+/// above it. Every page within the range is loaded at most once, whichever
+/// ends it gets consumed from: once everything between the two ends is used
+/// up, an end that runs out of its own leaf carries on into the other end's
+/// leaf instead of loading it again.
+pub struct BTreeIter<'a, B, L, R>
 where
     B: PageLayout<Value = u64>,
     L: PageLayout<Key = B::Key>,
+    R: RawRead,
 {
-    left: VecDeque<PageIter<'a, B>>,
-    right: VecDeque<PageIter<'a, B>>,
-    left_leaf: PageIter<'a, L>,
-    right_leaf: PageIter<'a, L>,
+    reader: &'a R,
+    /// Branches down the front edge, deepest at the back. Once `right` is used
+    /// up, the front of this is where the back edge continues from.
+    left: VecDeque<(u64, PageIter<'a, B>)>,
+    /// Branches down the back edge, deepest at the back. The front of this is
+    /// where the front edge continues from once `left` is used up.
+    right: VecDeque<(u64, PageIter<'a, B>)>,
+    /// Leaf the front end is in, if it's not used up.
+    front: Option<(u64, PageIter<'a, L>)>,
+    /// Leaf the back end is in, if it's not used up or shared with the front.
+    back: Option<(u64, PageIter<'a, L>)>,
+    /// Where the last error from the front end came from.
+    fault: Option<Fault>,
+}
+
+/// A page the front end of a [`BTreeIter`] failed on, and what to drop to get
+/// past it.
+#[derive(Clone, Copy, Debug)]
+enum Fault {
+    /// The page couldn't be loaded. It's already been taken out of its parent.
+    Load(u64),
+    /// The deepest branch in `left` failed partway through.
+    LeftBranch(u64),
+    /// The shallowest branch in `right` failed partway through.
+    RightBranch(u64),
+    /// The front end's leaf failed partway through.
+    FrontLeaf(u64),
+    /// The back end's leaf failed partway through.
+    BackLeaf(u64),
 }
 
 impl<'a, B, L, R> BTreeIter<'a, B, L, R>
@@ -489,93 +1046,212 @@ where
     L: PageLayout<Key = B::Key> + 'a,
     R: RawRead,
 {
+    /// An iterator over no more than a single leaf.
+    fn new(reader: &'a R, leaf: Option<(u64, PageIter<'a, L>)>) -> Self {
+        Self {
+            reader,
+            left: VecDeque::new(),
+            right: VecDeque::new(),
+            front: leaf,
+            back: None,
+            fault: None,
+        }
+    }
+
+    /// Keep going past damaged pages instead of failing on them. See
+    /// [`RobustIter`].
+    pub fn skip_corrupt(self) -> RobustIter<'a, B, L, R> {
+        RobustIter {
+            iter: self,
+            skipped: 0,
+        }
+    }
+
+    /// Look at the next pair from the front without consuming it.
     #[allow(clippy::type_complexity)]
-    fn next_internal(&mut self) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
-        let full = match &mut self.state {
-            BTreeIterState::Empty => return Ok(None),
-            BTreeIterState::Leaf(l) => return l.next().transpose(),
-            BTreeIterState::Full(f) => f,
-        };
+    pub fn peek(&mut self) -> Option<Result<(&'a L::Key, &'a L::Value), Error>> {
+        self.step_front(false).transpose()
+    }
+
+    /// Look at the next pair from the back without consuming it.
+    #[allow(clippy::type_complexity)]
+    pub fn peek_back(&mut self) -> Option<Result<(&'a L::Key, &'a L::Value), Error>> {
+        self.step_back(false).transpose()
+    }
+
+    /// Take the rest of the leaf at the front end out of the range, moving on
+    /// to the next leaf along first if the front one is used up. Returns
+    /// `None` once the range is empty.
+    pub(crate) fn next_leaf(&mut self) -> Result<Option<PageIter<'a, L>>, Error> {
+        if self.step_front(false)?.is_none() {
+            return Ok(None);
+        }
+        // If the front end had nothing left, the next pair came out of the
+        // back end's leaf.
+        Ok(self
+            .front
+            .take()
+            .or_else(|| self.back.take())
+            .map(|(_, l)| l))
+    }
 
-        if let Some(v) = full.left_leaf.next().transpose()? {
-            return Ok(Some(v));
+    /// Get the next pair from the front, taking it out of the range if
+    /// `advance` is set.
+    #[allow(clippy::type_complexity)]
+    fn step_front(&mut self, advance: bool) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
+        loop {
+            if let Some((page, leaf)) = &mut self.front {
+                let pair = if advance {
+                    leaf.next()
+                } else {
+                    leaf.clone().next()
+                };
+                let page = *page;
+                let pair = pair
+                    .transpose()
+                    .inspect_err(|_| self.fault = Some(Fault::FrontLeaf(page)));
+                if let Some(pair) = pair? {
+                    return Ok(Some(pair));
+                }
+                self.front = None;
+            }
+            if !self.load_front()? {
+                // Nothing is left between the two ends, so the rest is in the
+                // back end's leaf.
+                let Some((page, leaf)) = &mut self.back else {
+                    return Ok(None);
+                };
+                let pair = if advance {
+                    leaf.next()
+                } else {
+                    leaf.clone().next()
+                };
+                let page = *page;
+                return pair
+                    .transpose()
+                    .inspect_err(|_| self.fault = Some(Fault::BackLeaf(page)));
+            }
         }
+    }
 
+    /// Get the next pair from the back, taking it out of the range if
+    /// `advance` is set.
+    #[allow(clippy::type_complexity)]
+    fn step_back(&mut self, advance: bool) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
         loop {
-            let page = loop {
-                if let Some(iter) = full.left.back_mut() {
+            if let Some((_, leaf)) = &mut self.back {
+                let pair = if advance {
+                    leaf.next_back()
+                } else {
+                    leaf.clone().next_back()
+                };
+                if let Some(pair) = pair.transpose()? {
+                    return Ok(Some(pair));
+                }
+                self.back = None;
+            }
+            if !self.load_back()? {
+                // Nothing is left between the two ends, so the rest is in the
+                // front end's leaf.
+                let Some((_, leaf)) = &mut self.front else {
+                    return Ok(None);
+                };
+                return if advance {
+                    leaf.next_back().transpose()
+                } else {
+                    leaf.clone().next_back().transpose()
+                };
+            }
+        }
+    }
+
+    /// Load the next leaf along from the front end. Returns false if there
+    /// are no pages left between the two ends.
+    fn load_front(&mut self) -> Result<bool, Error> {
+        loop {
+            let (page, parent) = loop {
+                if let Some((parent, iter)) = self.left.back_mut() {
                     if let Some(page) = iter.next() {
-                        break page;
+                        break (page, Fault::LeftBranch(*parent));
                     }
-                    full.left.pop_back();
+                    self.left.pop_back();
                 } else {
-                    let Some(iter) = full.right.front_mut() else {
-                        let ret = full.right_leaf.next().transpose()?;
-                        self.state = BTreeIterState::Leaf(full.right_leaf.clone());
-                        return Ok(ret);
+                    let Some((parent, iter)) = self.right.front_mut() else {
+                        return Ok(false);
                     };
                     if let Some(page) = iter.next() {
-                        break page;
+                        break (page, Fault::RightBranch(*parent));
                     }
-                    full.right.pop_front();
+                    self.right.pop_front();
                 }
             };
-            let page_addr = *(page?.1);
+            let page_addr = *(page.inspect_err(|_| self.fault = Some(parent))?.1);
 
-            let new_page = unsafe { ReadPage::try_load(self.reader, page_addr)? };
+            let new_page = unsafe { ReadPage::try_load(self.reader, page_addr) }
+                .inspect_err(|_| self.fault = Some(Fault::Load(page_addr)))?;
             match new_page {
-                ReadPage::Branch(b) => full.left.push_back(b.iter()),
+                ReadPage::Branch(b) => self.left.push_back((page_addr, b.iter())),
                 ReadPage::Leaf(l) => {
-                    let mut iter = l.iter();
-                    let ret = iter.next().transpose();
-                    full.left_leaf = iter;
-                    return ret;
+                    self.front = Some((page_addr, l.iter()));
+                    return Ok(true);
                 }
             }
         }
     }
 
-    #[allow(clippy::type_complexity)]
-    fn next_back_internal(&mut self) -> Result<Option<(&'a L::Key, &'a L::Value)>, Error> {
-        let full = match &mut self.state {
-            BTreeIterState::Empty => return Ok(None),
-            BTreeIterState::Leaf(l) => return l.next_back().transpose(),
-            BTreeIterState::Full(f) => f,
-        };
-
-        if let Some(v) = full.right_leaf.next_back().transpose()? {
-            return Ok(Some(v));
-        }
+    /// Drop whatever the front end last failed on, so it can carry on from
+    /// the next page along. Returns the page that failed, or `None` if no
+    /// failure was recorded.
+    fn skip_fault(&mut self) -> Option<u64> {
+        Some(match self.fault.take()? {
+            Fault::Load(page) => page,
+            Fault::LeftBranch(page) => {
+                self.left.pop_back();
+                page
+            }
+            Fault::RightBranch(page) => {
+                self.right.pop_front();
+                page
+            }
+            Fault::FrontLeaf(page) => {
+                self.front = None;
+                page
+            }
+            Fault::BackLeaf(page) => {
+                self.back = None;
+                page
+            }
+        })
+    }
 
+    /// Load the next leaf along from the back end. Returns false if there
+    /// are no pages left between the two ends.
+    fn load_back(&mut self) -> Result<bool, Error> {
         loop {
             let page = loop {
-                if let Some(iter) = full.right.back_mut() {
+                if let Some((_, iter)) = self.right.back_mut() {
                     if let Some(page) = iter.next_back() {
                         break page;
                     }
-                    full.right.pop_back();
+                    self.right.pop_back();
                 } else {
-                    let Some(iter) = full.left.front_mut() else {
-                        let ret = full.left_leaf.next_back().transpose()?;
-                        self.state = BTreeIterState::Leaf(full.left_leaf.clone());
-                        return Ok(ret);
+                    let Some((_, iter)) = self.left.front_mut() else {
+                        return Ok(false);
                     };
                     if let Some(page) = iter.next_back() {
                         break page;
                     }
-                    full.left.pop_front();
+                    self.left.pop_front();
                 }
             };
             let page_addr = *(page?.1);
 
             let new_page = unsafe { ReadPage::try_load(self.reader, page_addr)? };
             match new_page {
-                ReadPage::Branch(b) => full.right.push_back(b.iter()),
+                ReadPage::Branch(b) => self.right.push_back((page_addr, b.iter())),
                 ReadPage::Leaf(l) => {
-                    let mut iter = l.iter();
-                    let ret = iter.next_back().transpose();
-                    full.right_leaf = iter;
-                    return ret;
+                    self.back = Some((page_addr, l.iter()));
+                    return Ok(true);
                 }
             }
         }
@@ -591,7 +1267,7 @@ where
     type Item = Result<(&'a L::Key, &'a L::Value), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_internal().transpose()
+        self.step_front(true).transpose()
     }
 }
 
@@ -602,6 +1278,139 @@ where
     R: RawRead,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.next_back_internal().transpose()
+        self.step_back(true).transpose()
+    }
+}
+
+/// Iterator over the keys in a range of a [`BTreeRead`]. Made by [`BTreeRead::keys`].
+pub struct BTreeKeys<'a, B, L, R>(BTreeIter<'a, B, L, R>)
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    R: RawRead;
+
+impl<'a, B, L, R> Iterator for BTreeKeys<'a, B, L, R>
+where
+    B: PageLayout<Value = u64> + 'a,
+    L: PageLayout<Key = B::Key> + 'a,
+    R: RawRead,
+{
+    type Item = Result<&'a L::Key, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.0.next()?.map(|(k, _)| k))
+    }
+}
+
+impl<'a, B, L, R> DoubleEndedIterator for BTreeKeys<'a, B, L, R>
+where
+    B: PageLayout<Value = u64> + 'a,
+    L: PageLayout<Key = B::Key> + 'a,
+    R: RawRead,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Some(self.0.next_back()?.map(|(k, _)| k))
+    }
+}
+
+/// Iterator over the values in a range of a [`BTreeRead`], in key order. Made by
+/// [`BTreeRead::values`].
+pub struct BTreeValues<'a, B, L, R>(BTreeIter<'a, B, L, R>)
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    R: RawRead;
+
+impl<'a, B, L, R> Iterator for BTreeValues<'a, B, L, R>
+where
+    B: PageLayout<Value = u64> + 'a,
+    L: PageLayout<Key = B::Key> + 'a,
+    R: RawRead,
+{
+    type Item = Result<&'a L::Value, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.0.next()?.map(|(_, v)| v))
+    }
+}
+
+impl<'a, B, L, R> DoubleEndedIterator for BTreeValues<'a, B, L, R>
+where
+    B: PageLayout<Value = u64> + 'a,
+    L: PageLayout<Key = B::Key> + 'a,
+    R: RawRead,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        Some(self.0.next_back()?.map(|(_, v)| v))
+    }
+}
+
+/// A page a [`RobustIter`] had to skip over.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SkippedPage {
+    /// Page number of the page that failed. For a branch, everything below it
+    /// that hadn't been reached yet was skipped along with it.
+    pub page: u64,
+    /// What went wrong with it
+    pub error: Error,
+}
+
+/// Iterator over a range of a B-tree that carries on past damaged pages, made
+/// with [`BTreeIter::skip_corrupt`].
+///
+/// When a page fails to load, or its entries turn out to be malformed partway
+/// through, the failure is yielded as a [`SkippedPage`] and iteration picks
+/// back up at the next page along. Everything on that page that wasn't already
+/// yielded is skipped, along with everything below it if it was a branch. The
+/// pages down to either end of the range were already loaded when the range
+/// was made, so damage there fails [`BTreeRead::range`] itself instead.
+///
+/// Only iterates from the front.
+pub struct RobustIter<'a, B, L, R>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    R: RawRead,
+{
+    iter: BTreeIter<'a, B, L, R>,
+    skipped: usize,
+}
+
+impl<'a, B, L, R> RobustIter<'a, B, L, R>
+where
+    B: PageLayout<Value = u64> + 'a,
+    L: PageLayout<Key = B::Key> + 'a,
+    R: RawRead,
+{
+    /// Number of pages skipped so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl<'a, B, L, R> Iterator for RobustIter<'a, B, L, R>
+where
+    B: PageLayout<Value = u64> + 'a,
+    L: PageLayout<Key = B::Key> + 'a,
+    R: RawRead,
+{
+    type Item = Result<(&'a L::Key, &'a L::Value), SkippedPage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let error = match self.iter.step_front(true) {
+            Ok(pair) => return pair.map(Ok),
+            Err(error) => error,
+        };
+        self.skipped += 1;
+        let page = match self.iter.skip_fault() {
+            Some(page) => page,
+            None => {
+                // No telling where the failure came from, so there's nowhere
+                // safe to carry on from
+                self.iter = BTreeIter::new(self.iter.reader, None);
+                NULL_PAGE
+            }
+        };
+        Some(Err(SkippedPage { page, error }))
     }
 }
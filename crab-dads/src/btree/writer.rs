@@ -1,11 +1,46 @@
-use alloc::vec::Vec;
+use alloc::{borrow::ToOwned, vec::Vec};
+use core::{
+    borrow::Borrow,
+    ops::{Bound, RangeBounds},
+};
 
 use crate::{
-    page::{self, Balance, PageLayout, PageLayoutVectored, PageMap, PageMapMut},
-    Error, PAGE_4K,
+    format::PAGE_TYPE_LEAF,
+    page::{self, Balance, PageLayout, PageLayoutVectored, PageMap, PageMapMut, CONTENT_SIZE},
+    Error, NULL_PAGE, PAGE_4K,
+};
+
+use super::{
+    reader::{covers, ReadPage},
+    BTreeRead, LoadMutPage, RawWrite, TreeVisitor, WalkControl,
 };
 
-use super::{reader::ReadPage, BTreeRead, LoadMutPage, RawWrite};
+/// Tuning for how a [`BTreeWrite`] splits pages and finds where to insert.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BTreeConfig {
+    /// How full to leave the lower page when a page is split, from 0.0 to 1.0. The default of 0.5
+    /// splits pages evenly. Workloads that mostly insert in ascending key order should raise
+    /// this, as the lower page will never be inserted into again.
+    pub split_fill: f32,
+    /// Remember the rightmost leaf of the tree, and put any key greater than every key already in
+    /// the tree straight into it, without descending through the branch pages.
+    pub append_optimized: bool,
+    /// How many pages emptied by merges to hold onto for reuse by later splits, instead of
+    /// freeing them. Workloads that repeatedly grow and shrink values churn through pages this
+    /// way. Held pages must be handed back with [`BTreeWrite::release_scratch`] before the
+    /// transaction commits, or they leak. The default of 0 frees every page right away.
+    pub scratch_pages: usize,
+}
+
+impl Default for BTreeConfig {
+    fn default() -> Self {
+        Self {
+            split_fill: 0.5,
+            append_optimized: false,
+            scratch_pages: 0,
+        }
+    }
+}
 
 pub struct BTreeWrite<'a, B, L, W>
 where
@@ -17,8 +52,101 @@ where
     branches: Vec<(PageMapMut<'a, B>, u64)>,
     leaf: Option<(PageMapMut<'a, L>, u64)>,
     root: u64,
+    config: BTreeConfig,
+    /// The rightmost leaf page, if `branches` currently holds the path down to it
+    rightmost: Option<u64>,
+    /// Pages the operation in progress has replaced or emptied. They're only freed once the
+    /// operation succeeds, as the tree may still point to them if it fails partway through.
+    freed: Vec<u64>,
+    /// Pages the operation in progress emptied by merging, to join `scratch` once it succeeds.
+    released: Vec<u64>,
+    /// Pages allocated this transaction that nothing points to, kept for the next split.
+    scratch: Vec<u64>,
 }
 
+/// Collects the page number of every page but the root.
+struct PagesBelowRoot(Vec<u64>);
+
+impl<B, L> TreeVisitor<B, L> for PagesBelowRoot
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+{
+    fn branch(&mut self, depth: usize, page: u64, _: &PageMap<B>) -> Result<WalkControl, Error> {
+        if depth > 0 {
+            self.0.push(page);
+        }
+        Ok(WalkControl::Continue)
+    }
+
+    fn leaf(&mut self, depth: usize, page: u64, _: &PageMap<L>) -> Result<WalkControl, Error> {
+        if depth > 0 {
+            self.0.push(page);
+        }
+        Ok(WalkControl::Continue)
+    }
+}
+
+/// Collects the page number of every page in a subtree, its root included, and counts the pairs
+/// in its leaves.
+#[derive(Default)]
+struct Subtree {
+    pages: Vec<u64>,
+    pairs: u64,
+}
+
+impl<B, L> TreeVisitor<B, L> for Subtree
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+{
+    fn branch(&mut self, _: usize, page: u64, _: &PageMap<B>) -> Result<WalkControl, Error> {
+        self.pages.push(page);
+        Ok(WalkControl::Continue)
+    }
+
+    fn leaf(&mut self, _: usize, page: u64, map: &PageMap<L>) -> Result<WalkControl, Error> {
+        self.pages.push(page);
+        self.pairs += map.page_trailer().lengths::<u8, L>(CONTENT_SIZE)?.upper as u64;
+        Ok(WalkControl::Continue)
+    }
+}
+
+/// Remove every pair in `range` from a leaf, returning how many there were.
+fn trim<L, R>(leaf: &mut PageMapMut<L>, range: &R) -> Result<u64, Error>
+where
+    L: PageLayout,
+    R: RangeBounds<L::Key>,
+{
+    let mut removed = 0;
+    leaf.retain(|k, _| {
+        let inside = range.contains(k);
+        removed += inside as u64;
+        !inside
+    })?;
+    Ok(removed)
+}
+
+/// Which leaf a descent heads for.
+enum Seek<'k, K: ?Sized> {
+    /// The one that holds, or would hold, a key
+    Key(&'k K),
+    /// The one before the leaf that a key would start, which holds the keys just below it
+    Before(&'k K),
+    /// The leftmost one
+    First,
+    /// The rightmost one
+    Last,
+}
+
+impl<K: ?Sized> Clone for Seek<'_, K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: ?Sized> Copy for Seek<'_, K> {}
+
 pub(crate) enum WritePage<'a, B, L>
 where
     B: PageLayout<Value = u64>,
@@ -33,7 +161,12 @@ where
     B: PageLayout<Value = u64>,
     L: PageLayout<Key = B::Key>,
 {
+    /// Load a page for writing. If it had to be copied first, the copy's page number is returned
+    /// too. The original page is left alone, for the caller to free once nothing points to it.
     fn try_load<W: RawWrite>(writer: &'a W, page: u64) -> Result<(Self, Option<u64>), Error> {
+        if page == NULL_PAGE {
+            return Err(Error::NullPage);
+        }
         unsafe {
             match writer.load_mut_page(page)? {
                 LoadMutPage::Clean {
@@ -41,20 +174,18 @@ where
                     write_page,
                     read,
                 } => {
-                    if (page::page_type(read) & 1) == 1 {
+                    if (page::page_type(read) & PAGE_TYPE_LEAF) != 0 {
                         let read: PageMap<'a, L> = PageMap::from_page(read)?;
                         let write = read.copy_to(write);
-                        writer.deallocate_page(page)?;
                         Ok((WritePage::Leaf(write), Some(write_page)))
                     } else {
                         let read: PageMap<'a, B> = PageMap::from_page(read)?;
                         let write = read.copy_to(write);
-                        writer.deallocate_page(page)?;
                         Ok((WritePage::Branch(write), Some(write_page)))
                     }
                 }
                 LoadMutPage::Dirty(d) => {
-                    if (page::page_type(d) & 1) == 1 {
+                    if (page::page_type(d) & PAGE_TYPE_LEAF) != 0 {
                         Ok((WritePage::Leaf(PageMapMut::from_page(d)?), None))
                     } else {
                         Ok((WritePage::Branch(PageMapMut::from_page(d)?), None))
@@ -78,6 +209,19 @@ where
     /// The provided page (and any child pages it may later navigate to) must
     /// all not be used mutably elsewhere in the program.
     pub unsafe fn load(writer: &'a W, page: u64) -> Result<(Self, Option<u64>), Error> {
+        unsafe { Self::load_with_config(writer, page, BTreeConfig::default()) }
+    }
+
+    /// Load in the root page of a tree, using the given configuration for all modifications.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`load`][Self::load].
+    pub unsafe fn load_with_config(
+        writer: &'a W,
+        page: u64,
+        config: BTreeConfig,
+    ) -> Result<(Self, Option<u64>), Error> {
         let (root, new_page) = WritePage::<B, L>::try_load(writer, page)?;
         let root_page_num = new_page.unwrap_or(page);
         let mut s = Self {
@@ -85,7 +229,16 @@ where
             branches: Vec::new(),
             leaf: None,
             root: root_page_num,
+            config,
+            rightmost: None,
+            freed: Vec::new(),
+            released: Vec::new(),
+            scratch: Vec::new(),
         };
+        if new_page.is_some() {
+            s.freed.push(page);
+            s.free_staged()?;
+        }
         match root {
             WritePage::Branch(b) => s.branches.push((b, root_page_num)),
             WritePage::Leaf(l) => s.leaf = Some((l, root_page_num)),
@@ -97,6 +250,7 @@ where
     pub fn as_read(&mut self) -> BTreeRead<'_, B, L, W> {
         // Clear out any descent into the tree that we'd previously done
         self.branches.truncate(1);
+        self.rightmost = None;
 
         // Loan out the root page
         let root = if let Some(l) = &self.leaf {
@@ -108,13 +262,491 @@ where
                 ReadPage::try_load(self.writer, self.root).expect("root page should be valid")
             }
         };
-        unsafe { BTreeRead::from_parts(self.writer, root) }
+        unsafe { BTreeRead::from_parts(self.writer, self.root, root) }
     }
 
     pub fn entry<'b, 'k>(
         &'b mut self,
         key: &'k L::Key,
     ) -> Result<Entry<'a, 'b, 'k, B, L, W>, Error> {
+        // Keys past the end of the tree go straight into the rightmost leaf, if we still have the
+        // path down to it.
+        if let Some(leaf_num) = self.rightmost.take() {
+            let (WritePage::Leaf(leaf), None) =
+                WritePage::<B, L>::try_load(self.writer, leaf_num)?
+            else {
+                return Err(Error::InvalidState(
+                    "Cached rightmost page should be a leaf that's already been written to",
+                ));
+            };
+            let past_end = match leaf.as_const().iter().next_back() {
+                Some(res) => res?.0 < key,
+                None => false,
+            };
+            if past_end {
+                let page::Entry::Vacant(entry) = leaf.entry(key)? else {
+                    return Err(Error::InvalidState(
+                        "Key past the end of the tree already had an entry",
+                    ));
+                };
+                self.rightmost = Some(leaf_num);
+                return Ok(Entry::Vacant(VacantEntry {
+                    tree: self,
+                    key,
+                    entry,
+                    entry_page_num: leaf_num,
+                }));
+            }
+        }
+
+        let res = self.descend(key);
+        let (leaf, page_num) = self.finish(res)?;
+        match leaf.entry(key)? {
+            page::Entry::Occupied(e) => {
+                // Safety: This only works because we're mutably borrowing
+                // from this B-Tree until modification is done, and
+                // PageMapMut doesn't have any internal state that we need
+                // to maintain.
+                Ok(Entry::Occupied(OccupiedEntry {
+                    tree: self,
+                    key,
+                    entry: e,
+                    entry_page_num: page_num,
+                }))
+            }
+            page::Entry::Vacant(e) => Ok(Entry::Vacant(VacantEntry {
+                tree: self,
+                key,
+                entry: e,
+                entry_page_num: page_num,
+            })),
+        }
+    }
+
+    /// Insert `value` under `key`, returning a copy of the value it replaced, if there was one.
+    pub fn insert(
+        &mut self,
+        key: &L::Key,
+        value: &L::Value,
+    ) -> Result<Option<<L::Value as ToOwned>::Owned>, Error>
+    where
+        L::Value: ToOwned,
+    {
+        match self.entry(key)? {
+            Entry::Occupied(o) => {
+                let old = o.get().to_owned();
+                o.replace(value)?;
+                Ok(Some(old))
+            }
+            Entry::Vacant(v) => {
+                v.insert(value)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Remove `key` from the tree, returning a copy of its value, or `None` if it wasn't there.
+    pub fn remove(&mut self, key: &L::Key) -> Result<Option<<L::Value as ToOwned>::Owned>, Error>
+    where
+        L::Value: ToOwned,
+    {
+        match self.entry(key)? {
+            Entry::Occupied(o) => o.remove().map(Some),
+            Entry::Vacant(_) => Ok(None),
+        }
+    }
+
+    /// Run `f` over every pair in `range`, in key order, replacing the value of each pair it
+    /// returns a new value for. Unlike [`OccupiedEntry::get_mut`], new values can be any length,
+    /// splitting pages as needed. Returns how many values were replaced.
+    ///
+    /// Replacements are gathered one leaf at a time, then applied through the entry API once
+    /// nothing is borrowing that leaf any more. Applying them never adds or removes keys, so the
+    /// scan picks up again right after the leaf's last key, wherever the splits left it. If a
+    /// replacement fails, the ones before it stay applied.
+    pub fn update_range<R, F>(&mut self, range: R, mut f: F) -> Result<usize, Error>
+    where
+        L::Key: ToOwned,
+        L::Value: ToOwned,
+        R: RangeBounds<L::Key>,
+        F: FnMut(&L::Key, &L::Value) -> Option<<L::Value as ToOwned>::Owned>,
+    {
+        let end = range.end_bound();
+        let mut start = range.start_bound().map(ToOwned::to_owned);
+        let mut scratch = Vec::new();
+        let mut replaced = 0;
+        loop {
+            let last = {
+                let read = self.as_read();
+                let start = start.as_ref().map(Borrow::borrow);
+                let Some(leaf) = read.range::<L::Key, _>((start, end))?.next_leaf()? else {
+                    return Ok(replaced);
+                };
+                let mut last = None;
+                for pair in leaf {
+                    let (k, v) = pair?;
+                    if let Some(new) = f(k, v) {
+                        scratch.push((k.to_owned(), new));
+                    }
+                    last = Some(k);
+                }
+                let Some(last) = last else {
+                    return Err(Error::InvalidState(
+                        "Range handed back a leaf with nothing in it",
+                    ));
+                };
+                last.to_owned()
+            };
+
+            for (k, v) in scratch.drain(..) {
+                let Entry::Occupied(entry) = self.entry(k.borrow())? else {
+                    return Err(Error::InvalidState(
+                        "Key being updated went missing from the tree",
+                    ));
+                };
+                entry.replace(v.borrow())?;
+                replaced += 1;
+            }
+            start = Bound::Excluded(last);
+        }
+    }
+
+    /// Iterate over every pair in `range`, in key order, with mutable access to the values.
+    ///
+    /// The tree is descended once per leaf rather than once per key, and each leaf is loaded for
+    /// writing as the iterator reaches it, so it's copied at most once. Values are changed in
+    /// place and keep their length, which leaves the tree's structure alone while iterating. Use
+    /// [`update_range`][Self::update_range] to give values a new length.
+    pub fn range_mut<R>(&mut self, range: R) -> BTreeIterMut<'a, '_, B, L, W, R>
+    where
+        L::Key: ToOwned,
+        R: RangeBounds<L::Key>,
+    {
+        BTreeIterMut {
+            tree: self,
+            range,
+            leaf: None,
+            next: Some(None),
+        }
+    }
+
+    /// A cursor for stepping through the tree and changing it as it goes, starting past the last
+    /// pair, where it's ready to append to the tree.
+    pub fn cursor(&mut self) -> BTreeCursor<'a, '_, B, L, W>
+    where
+        L::Key: ToOwned,
+    {
+        self.rightmost = None;
+        BTreeCursor {
+            tree: self,
+            key: None,
+            leaf: None,
+        }
+    }
+
+    /// The first key after the leaf a descent to `key`, or to the leftmost leaf if there's no
+    /// key, just ended on. Only valid straight after the descent.
+    fn fence(&self, key: Option<&L::Key>) -> Result<Option<<L::Key as ToOwned>::Owned>, Error>
+    where
+        L::Key: ToOwned,
+    {
+        for (branch, _) in self.branches.iter().rev() {
+            // The descent always takes the first entry or one after it, so skip it
+            let mut iter = branch.as_const().iter();
+            iter.next().transpose()?;
+            for res in iter {
+                let (k, _) = res?;
+                if key.map_or(true, |key| k > key) {
+                    return Ok(Some(k.to_owned()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remove every pair in `range`, returning how many were removed.
+    ///
+    /// Each pass descends to the lowest key left in the range. Every subtree hanging off the way
+    /// down that lies entirely inside the range is dropped whole, without ever loading it for
+    /// writing, and the leaf at the bottom is trimmed if it couldn't be. The pages on either side
+    /// of the gap are then rebalanced as they would be after any other delete. That leaves only
+    /// the edges of the range for later passes, so it takes a few passes per level of the tree,
+    /// not one per key.
+    pub fn remove_range<R>(&mut self, range: R) -> Result<u64, Error>
+    where
+        L::Key: ToOwned,
+        R: RangeBounds<L::Key>,
+    {
+        // Rebalancing can move pages around, so drop the cached rightmost leaf.
+        self.rightmost = None;
+
+        let mut removed = 0;
+        loop {
+            let first = {
+                let read = self.as_read();
+                let bounds = (range.start_bound(), range.end_bound());
+                let Some(pair) = read.range::<L::Key, _>(bounds)?.next() else {
+                    break;
+                };
+                pair?.0.to_owned()
+            };
+            let res = self.remove_range_pass(first.borrow(), &range);
+            removed += self.finish(res)?;
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        // Dropping subtrees can leave a root with a single child, possibly several levels over.
+        let res = loop {
+            let staged = self.freed.len();
+            if let Err(e) = self.reduce_depth() {
+                break Err(e);
+            }
+            if self.freed.len() == staged {
+                break Ok(removed);
+            }
+        };
+        self.rightmost = None;
+        self.finish(res)
+    }
+
+    /// One pass of [`remove_range`][Self::remove_range], descending to `first`, the lowest key
+    /// still in the range. Returns how many pairs it removed.
+    fn remove_range_pass<R>(&mut self, first: &L::Key, range: &R) -> Result<u64, Error>
+    where
+        L::Key: ToOwned,
+        R: RangeBounds<L::Key>,
+    {
+        let (mut leaf, leaf_num) = self.descend(first)?;
+        if self.branches.is_empty() {
+            let removed = trim(&mut leaf, range)?;
+            self.leaf = Some((leaf, leaf_num));
+            return Ok(removed);
+        }
+        let mut removed = 0;
+
+        // Copy out each branch on the way down, along with which of its entries the descent took
+        let mut branches = core::mem::take(&mut self.branches);
+        let mut levels = Vec::with_capacity(branches.len());
+        for (branch, _) in branches.iter() {
+            let mut entries = Vec::new();
+            for res in branch.as_const().iter() {
+                let (k, v) = res?;
+                entries.push((k.to_owned(), *v));
+            }
+            let path = entries
+                .iter()
+                .rposition(|(k, _)| k.borrow() <= first)
+                .unwrap_or(0);
+            levels.push((entries, path));
+        }
+
+        // Find the entries to drop at each level: the ones right after the descent's that are
+        // inside the range, and the descent's own at the first level where it's inside too. Below
+        // that, everything is being dropped along with it.
+        let mut upper: Option<&L::Key> = None;
+        let mut cut = None;
+        let mut drops = Vec::with_capacity(levels.len());
+        for (i, (entries, path)) in levels.iter().enumerate() {
+            let span = |j: usize| {
+                let lower: &L::Key = entries[j].0.borrow();
+                let next = entries.get(j + 1).map(|(k, _)| k.borrow()).or(upper);
+                (lower, next)
+            };
+            let inside = |j: usize| {
+                let (lower, next) = span(j);
+                covers(range, lower, next)
+            };
+            let from = if inside(*path) { *path } else { *path + 1 };
+            let to = (from..entries.len())
+                .find(|&j| !inside(j))
+                .unwrap_or(entries.len());
+            drops.push(from..to);
+            if from == *path {
+                cut = Some(i);
+                break;
+            }
+            upper = span(*path).1;
+        }
+
+        for ((branch, _), (drop, (entries, _))) in
+            branches.iter_mut().zip(drops.iter().zip(&levels))
+        {
+            for (_, page) in &entries[drop.clone()] {
+                let mut subtree = Subtree::default();
+                unsafe { BTreeRead::<B, L, W>::load(self.writer, *page)? }.walk(&mut subtree)?;
+                self.freed.append(&mut subtree.pages);
+                removed += subtree.pairs;
+            }
+            let mut j = 0;
+            branch.retain(|_, _| {
+                j += 1;
+                !drop.contains(&(j - 1))
+            })?;
+        }
+
+        let cut = match cut {
+            Some(cut) => cut,
+            None => {
+                let (old_first, _) = leaf.as_const().iter().next().ok_or(
+                    Error::DataCorruption("A leaf page below a branch was empty"),
+                )??;
+                let old_first = old_first.to_owned();
+                removed += trim(&mut leaf, range)?;
+                match leaf.as_const().iter().next() {
+                    Some(res) => {
+                        // Trimmed, but not emptied: fix it up like after any other delete
+                        let new_first = res?.0.to_owned();
+                        let sparse = leaf.free_space() > (PAGE_4K * 3 / 4);
+                        self.branches = branches;
+                        if new_first.borrow() != old_first.borrow() {
+                            self.replace_branch_first(old_first.borrow(), new_first.borrow())?;
+                        }
+                        if sparse {
+                            self.rebalance(new_first.borrow())?;
+                        }
+                        return Ok(removed);
+                    }
+                    None => {
+                        // Emptied, so it goes like any other page in the range
+                        self.freed.push(leaf_num);
+                        let last = branches.len() - 1;
+                        let path = levels[last].1;
+                        let mut j = 0;
+                        branches[last].0.retain(|_, _| {
+                            j += 1;
+                            j - 1 != path
+                        })?;
+                        last
+                    }
+                }
+            }
+        };
+
+        // The branch at `cut` lost the entry the descent took, and may be empty now. Empty
+        // branches go too, taking their entry in the branch above with them.
+        branches.truncate(cut + 1);
+        let mut cut = cut;
+        while branches[cut].0.as_const().iter().next().is_none() {
+            let (branch, page) = branches.pop().expect("cut is always within the branches");
+            if cut == 0 {
+                // Nothing left at all, so the root becomes an empty leaf.
+                let page_type = branch.page_trailer().page_type | PAGE_TYPE_LEAF;
+                self.leaf = Some((PageMapMut::new(branch.to_page(), page_type), self.root));
+                return Ok(removed);
+            }
+            self.freed.push(page);
+            cut -= 1;
+            let path = levels[cut].1;
+            let mut j = 0;
+            branches[cut].0.retain(|_, _| {
+                j += 1;
+                j - 1 != path
+            })?;
+        }
+
+        // If that was the branch's first entry, the branch above needs its new first key.
+        let sparse = branches[cut].0.free_space() > (PAGE_4K * 3 / 4);
+        if cut > 0 && levels[cut].1 == 0 {
+            let mut iter = branches[cut].0.as_const().iter();
+            let (new_first, _) = iter.next().ok_or(Error::InvalidState(
+                "Branch was just checked to not be empty",
+            ))??;
+            let new_first = new_first.to_owned();
+            let (parent, path) = &levels[cut - 1];
+            branches.truncate(cut);
+            self.branches = branches;
+            self.replace_branch_first(parent[*path].0.borrow(), new_first.borrow())?;
+        } else {
+            self.branches = branches;
+        }
+
+        // Then balance the branch against its neighbours. Descend again for a path down to it,
+        // as replacing the first key may have split pages on the way.
+        if cut > 0 && sparse {
+            let depth = levels.len();
+            self.descend(first)?;
+            let cut = (cut + self.branches.len()).saturating_sub(depth);
+            self.branches.truncate(cut);
+            self.rebalance(first)?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove the pair with the lowest key, returning copies of it, or `None` if the tree is
+    /// empty.
+    #[allow(clippy::type_complexity)]
+    pub fn pop_first(
+        &mut self,
+    ) -> Result<Option<(<L::Key as ToOwned>::Owned, <L::Value as ToOwned>::Owned)>, Error>
+    where
+        L::Key: ToOwned,
+        L::Value: ToOwned,
+    {
+        self.pop(Seek::First)
+    }
+
+    /// Remove the pair with the highest key, returning copies of it, or `None` if the tree is
+    /// empty.
+    #[allow(clippy::type_complexity)]
+    pub fn pop_last(
+        &mut self,
+    ) -> Result<Option<(<L::Key as ToOwned>::Owned, <L::Value as ToOwned>::Owned)>, Error>
+    where
+        L::Key: ToOwned,
+        L::Value: ToOwned,
+    {
+        self.pop(Seek::Last)
+    }
+
+    /// Descend down one edge of the tree, and delete the pair at the end of it.
+    #[allow(clippy::type_complexity)]
+    fn pop(
+        &mut self,
+        seek: Seek<L::Key>,
+    ) -> Result<Option<(<L::Key as ToOwned>::Owned, <L::Value as ToOwned>::Owned)>, Error>
+    where
+        L::Key: ToOwned,
+        L::Value: ToOwned,
+    {
+        let res = self.descend_to(seek);
+        let (leaf, page_num) = self.finish(res)?;
+        let mut iter = leaf.as_const().iter();
+        let pair = match seek {
+            Seek::Last => iter.next_back(),
+            _ => iter.next(),
+        };
+        let Some(pair) = pair else {
+            return Ok(None);
+        };
+        let (k, v) = pair?;
+        let (key, value) = (k.to_owned(), v.to_owned());
+
+        // The pair is found again by key to delete it, but only within the leaf
+        let page::Entry::Occupied(entry) = leaf.entry(key.borrow())? else {
+            return Err(Error::InvalidState(
+                "Pair at the edge of a leaf went missing",
+            ));
+        };
+        let entry = OccupiedEntry {
+            tree: self,
+            key: key.borrow(),
+            entry,
+            entry_page_num: page_num,
+        };
+        entry.delete()?;
+        Ok(Some((key, value)))
+    }
+
+    /// Walk down to the leaf that holds, or would hold, the given key, loading every page on the
+    /// way for writing.
+    fn descend(&mut self, key: &L::Key) -> Result<(PageMapMut<'a, L>, u64), Error> {
+        self.descend_to(Seek::Key(key))
+    }
+
+    /// Walk down to the leaf `seek` picks out, loading every page on the way for writing.
+    fn descend_to(&mut self, seek: Seek<L::Key>) -> Result<(PageMapMut<'a, L>, u64), Error> {
         // Clear out any descent into the tree that we'd previously done
         self.branches.truncate(1);
 
@@ -124,50 +756,44 @@ where
         } else if let Some(b) = self.branches.pop() {
             (WritePage::Branch(b.0), b.1)
         } else {
-            (WritePage::try_load(self.writer, self.root)?.0, self.root)
+            (self.load_page(self.root)?.0, self.root)
         };
 
         let mut depth = 0;
+        let mut rightmost = true;
         loop {
             let mut branch_page = match page {
-                WritePage::Leaf(l) => match l.entry(key)? {
-                    page::Entry::Occupied(e) => {
-                        // Safety: This only works because we're mutably borrowing
-                        // from this B-Tree until modification is done, and
-                        // PageMapMut doesn't have any internal state that we need
-                        // to maintain.
-                        return Ok(Entry::Occupied(OccupiedEntry {
-                            tree: self,
-                            key,
-                            entry: e,
-                            entry_page_num: page_num,
-                        }));
-                    }
-                    page::Entry::Vacant(e) => {
-                        return Ok(Entry::Vacant(VacantEntry {
-                            tree: self,
-                            key,
-                            entry: e,
-                            entry_page_num: page_num,
-                        }));
+                WritePage::Leaf(l) => {
+                    if self.config.append_optimized && rightmost && depth > 0 {
+                        self.rightmost = Some(page_num);
                     }
-                },
+                    return Ok((l, page_num));
+                }
                 WritePage::Branch(b) => b,
             };
 
             // Seek the appropriate sub-page in the branch.
             let mut val = None;
+            let mut last = true;
             for res in branch_page.iter_mut().rev() {
                 let (k, v) = res?;
                 val = Some(v);
-                if k <= key {
+                let found = match seek {
+                    Seek::Key(key) => k <= key,
+                    Seek::Before(key) => k < key,
+                    Seek::First => false,
+                    Seek::Last => true,
+                };
+                if found {
                     break;
                 }
+                last = false;
             }
+            rightmost &= last;
             let val = val.ok_or(Error::DataCorruption("A branch page was somehow empty"))?;
 
             // Load the next page
-            let (write_page, write_page_num) = WritePage::<B, L>::try_load(self.writer, *val)?;
+            let (write_page, write_page_num) = self.load_page(*val)?;
             page = write_page;
             if let Some(write_page_num) = write_page_num {
                 *val = write_page_num;
@@ -187,6 +813,120 @@ where
         }
     }
 
+    /// Load a page for writing. If it had to be copied, the original is staged to be freed once
+    /// the operation in progress succeeds.
+    fn load_page(&mut self, page: u64) -> Result<(WritePage<'a, B, L>, Option<u64>), Error> {
+        let (write, new_page) = WritePage::try_load(self.writer, page)?;
+        if new_page.is_some() {
+            self.freed.push(page);
+        }
+        Ok((write, new_page))
+    }
+
+    /// Free every staged page, handing them to the writer in one
+    /// [`deallocate_batch`][RawWrite::deallocate_batch] call.
+    fn free_staged(&mut self) -> Result<(), Error> {
+        if self.freed.is_empty() {
+            return Ok(());
+        }
+        unsafe {
+            self.writer
+                .deallocate_batch(&mut self.freed.drain(..).map(|page| (page, 1)))?;
+        }
+        Ok(())
+    }
+
+    /// End an operation, freeing its staged pages if it succeeded. If it failed, they're dropped
+    /// instead: a page leaked until the transaction is thrown away is better than one freed while
+    /// the tree still points to it.
+    fn finish<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        match res {
+            Ok(t) => {
+                let keep = self.config.scratch_pages.saturating_sub(self.scratch.len());
+                let keep = keep.min(self.released.len());
+                self.scratch.extend(self.released.drain(..keep));
+                self.freed.append(&mut self.released);
+                self.free_staged()?;
+                Ok(t)
+            }
+            Err(e) => {
+                self.freed.clear();
+                self.released.clear();
+                Err(e)
+            }
+        }
+    }
+
+    /// Get a page to split into, reusing a scratch page if there is one.
+    fn scratch_page(&mut self) -> Result<(&'a mut [u8; PAGE_4K], u64), Error> {
+        let Some(page) = self.scratch.pop() else {
+            return Ok(self.writer.allocate_page()?);
+        };
+        match unsafe { self.writer.load_mut_page(page)? } {
+            LoadMutPage::Dirty(d) => Ok((d, page)),
+            LoadMutPage::Clean { .. } => Err(Error::InvalidState(
+                "Scratch page should have been allocated in this transaction",
+            )),
+        }
+    }
+
+    /// Free every page held for reuse by [`BTreeConfig::scratch_pages`]. This must be called
+    /// before the transaction commits, as nothing else will free them.
+    pub fn release_scratch(&mut self) -> Result<(), Error> {
+        self.freed.append(&mut self.scratch);
+        self.free_staged()
+    }
+
+    /// Rebalance the pages around a key after its page was left mostly empty, possibly pulling up
+    /// the root, and free whatever pages that emptied.
+    fn rebalance(&mut self, key: &L::Key) -> Result<(), Error> {
+        let res = match self.balance(key) {
+            // Balancing may have eliminated the top of the tree. Check that now.
+            Ok(true) => self.reduce_depth(),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.finish(res)
+    }
+
+    /// Remove every entry from the tree, freeing all pages except the root.
+    ///
+    /// The freed pages are handed to the writer in a single
+    /// [`deallocate_batch`][RawWrite::deallocate_batch] call.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        // Gather up every page below the root.
+        let mut below_root = PagesBelowRoot(Vec::new());
+        self.as_read().walk(&mut below_root)?;
+
+        // Extract our root page
+        let root = if let Some(l) = self.leaf.take() {
+            WritePage::Leaf(l.0)
+        } else if let Some(b) = self.branches.pop() {
+            WritePage::Branch(b.0)
+        } else {
+            WritePage::<B, L>::try_load(self.writer, self.root)?.0
+        };
+
+        let branch = match root {
+            WritePage::Leaf(l) => {
+                let page_type = l.page_trailer().page_type;
+                self.leaf = Some((PageMapMut::new(l.to_page(), page_type), self.root));
+                return Ok(());
+            }
+            WritePage::Branch(b) => b,
+        };
+
+        unsafe {
+            self.writer
+                .deallocate_batch(&mut below_root.0.iter().map(|page| (*page, 1)))?;
+        }
+
+        // The root becomes an empty leaf.
+        let page_type = branch.page_trailer().page_type | PAGE_TYPE_LEAF;
+        self.leaf = Some((PageMapMut::new(branch.to_page(), page_type), self.root));
+        Ok(())
+    }
+
     fn branch_insert(
         &mut self,
         branch: (PageMapMut<'a, B>, u64),
@@ -206,9 +946,14 @@ where
 
         // Branch is out of space, time to split it up
 
-        let new_branch = self.writer.allocate_page()?;
+        let new_branch = self.scratch_page()?;
         let mut old_branch = (vacant.to_page(), branch.1);
-        let new_branch = (old_branch.0.split_to(new_branch.0)?, new_branch.1);
+        let new_branch = (
+            old_branch
+                .0
+                .split_to_with_fill(new_branch.0, self.config.split_fill)?,
+            new_branch.1,
+        );
         let (k2, _) = new_branch
             .0
             .as_const()
@@ -222,7 +967,7 @@ where
                 // Root page. To keep the root page at the same page
                 // number, we've got to copy it over to a new page, then
                 // put both that new page and the higher page in.
-                let copy_branch = self.writer.allocate_page()?;
+                let copy_branch = self.scratch_page()?;
                 let copy_branch = (
                     old_branch.0.as_const().copy_to(copy_branch.0),
                     copy_branch.1,
@@ -245,6 +990,9 @@ where
                         .ok_or(Error::DataCorruption(
                             "Sub-page for new branch has no entries",
                         ))??;
+                // A key going in ahead of everything on the page becomes its new first key, as
+                // when replacing a branch's first key, so file the page under that instead.
+                let k = if insert.0 < k { insert.0 } else { k };
                 root_branch.0 = match root_branch.0.entry(k)? {
                     page::Entry::Occupied(_) => {
                         return Err(Error::DataCorruption("brand new branch had occupied entry"))
@@ -285,8 +1033,12 @@ where
         key: &L::Key,
     ) -> Result<(PageMapMut<'a, L>, u64), Error> {
         // We need to split the page
-        let new_leaf = self.writer.allocate_page()?;
-        let new_leaf = (leaf.0.split_to(new_leaf.0)?, new_leaf.1);
+        let new_leaf = self.scratch_page()?;
+        let new_leaf = (
+            leaf.0
+                .split_to_with_fill(new_leaf.0, self.config.split_fill)?,
+            new_leaf.1,
+        );
         let (k2, _) = new_leaf
             .0
             .as_const()
@@ -299,9 +1051,9 @@ where
                 // Root page. To keep the root page at the same page
                 // number, we've got to copy it over to a new page, then
                 // put both that new page and the higher page in.
-                let copy_leaf = self.writer.allocate_page()?;
+                let copy_leaf = self.scratch_page()?;
                 let copy_leaf = (leaf.0.as_const().copy_to(copy_leaf.0), copy_leaf.1);
-                let page_type = leaf.0.page_trailer().page_type & 0xFE;
+                let page_type = leaf.0.page_trailer().page_type & !PAGE_TYPE_LEAF;
 
                 // Create the branch
                 let mut branch = (PageMapMut::new(leaf.0.to_page(), page_type), leaf.1);
@@ -353,7 +1105,7 @@ where
         };
         let first = e.first();
         let page = *e.get();
-        let branch = (e.delete(), branch.1);
+        let branch = (e.delete()?, branch.1);
 
         // Calling branch_insert will automatically handle expanding and
         // splitting branch pages as needed.
@@ -369,6 +1121,36 @@ where
         Ok(())
     }
 
+    /// Fix up the tree after `key` was deleted from `leaf`, which was the leaf's first key if
+    /// `first` is set. Returns true if that changed any branch page, in which case the path down
+    /// to the leaf can't be trusted any more.
+    fn deleted(
+        &mut self,
+        key: &L::Key,
+        first: bool,
+        mut leaf: PageMapMut<'a, L>,
+    ) -> Result<bool, Error> {
+        // Rebalancing can move pages around, so drop the cached rightmost leaf.
+        self.rightmost = None;
+
+        // If that was the page's first entry, the branches above need its new first key.
+        let mut changed = false;
+        if first {
+            if let Some(new) = leaf.iter_mut().next() {
+                let (new_key, _) = new?;
+                self.replace_branch_first(key, new_key)?;
+                changed = true;
+            }
+        }
+
+        // Check if we have a page that's a good candidate for rebalancing.
+        if leaf.free_space() > (PAGE_4K * 3 / 4) {
+            self.rebalance(key)?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
     /// Check if we can push down the root of the tree by one level or not.
     fn reduce_depth(&mut self) -> Result<(), Error> {
         self.branches.truncate(1);
@@ -396,28 +1178,54 @@ where
             return Ok(());
         }
 
-        // Only page left? Time to pull that page up into the current page instead.
-        let (sub_page, _) = WritePage::<B, L>::try_load(self.writer, first)?;
-        match sub_page {
-            WritePage::Branch(b) => {
-                let root = b.as_const().copy_to(page.to_page());
-                unsafe {
-                    self.writer.deallocate_page(first)?;
-                }
+        // Only page left? Time to pull that page up into the current page instead. It's only
+        // read from, so there's no need to load it for writing.
+        match unsafe { ReadPage::<B, L>::try_load(self.writer, first)? } {
+            ReadPage::Branch(b) => {
+                let root = b.copy_to(page.to_page());
                 self.branches.push((root, self.root));
             }
-            WritePage::Leaf(l) => {
-                let root = l.as_const().copy_to(page.to_page());
-                unsafe {
-                    self.writer.deallocate_page(first)?;
-                }
+            ReadPage::Leaf(l) => {
+                let root = l.copy_to(page.to_page());
                 self.leaf = Some((root, self.root));
             }
         }
+        self.freed.push(first);
 
         Ok(())
     }
 
+    /// Drop the page `emptied` from `branch` after a deletion left it empty, then carry on
+    /// balancing from the branch. The page is still filed under the deleted key, which no page
+    /// holds any more, so it can't be merged like any other. If it was the branch's first page,
+    /// `next` is the first key of the page after it, which replaces it further up.
+    fn drop_emptied(
+        &mut self,
+        mut branch: (PageMapMut<'a, B>, u64),
+        key: &L::Key,
+        emptied: u64,
+        next: Option<&L::Key>,
+    ) -> Result<bool, Error> {
+        let page::Entry::Occupied(e) = branch.0.entry(key)? else {
+            return Err(Error::DataCorruption(
+                "Branch holding an emptied page should still have its key",
+            ));
+        };
+        let first = e.first();
+        branch.0 = e.delete()?;
+        self.released.push(emptied);
+        if let (true, Some(next)) = (first, next) {
+            self.replace_branch_first(key, next)?;
+        }
+
+        // This may make this branch relevant for a balancing. Repeat the process
+        if branch.0.free_space() > (PAGE_4K * 3 / 4) {
+            self.balance(key)
+        } else {
+            Ok(true)
+        }
+    }
+
     /// Try to rebalance the pages around the given key. This should unwind the
     /// tree in the process.
     fn balance(&mut self, key: &L::Key) -> Result<bool, Error> {
@@ -442,12 +1250,22 @@ where
             return Ok(true);
         };
         let Some(v1) = v1 else {
+            // An only child can't be balanced against anything, but if it was emptied, this branch
+            // goes with it. The root is left for reduce_depth to pull the child up into instead.
+            let only = *v0.1;
+            let empty = match unsafe { ReadPage::<B, L>::try_load(self.writer, only)? } {
+                ReadPage::Branch(b) => b.iter().next().is_none(),
+                ReadPage::Leaf(l) => l.iter().next().is_none(),
+            };
+            if empty && !self.branches.is_empty() {
+                return self.drop_emptied(branch, key, only, None);
+            }
             return Ok(true);
         };
 
         // Load the pages, replacing the page addresses in the process if needed.
-        let page0 = WritePage::<B, L>::try_load(self.writer, *v0.1)?;
-        let page1 = WritePage::<B, L>::try_load(self.writer, *v1.1)?;
+        let page0 = self.load_page(*v0.1)?;
+        let page1 = self.load_page(*v1.1)?;
         if let Some(new_page0) = page0.1 {
             *v0.1 = new_page0;
         }
@@ -464,6 +1282,15 @@ where
 
         match (page0.0, page1.0) {
             (WritePage::Branch(b0), WritePage::Branch(b1)) => {
+                let (page0, page1) = (*v0.1, *v1.1);
+                if b0.as_const().iter().next().is_none() {
+                    let next = b1.as_const().iter().next().transpose()?.map(|(k, _)| k);
+                    return self.drop_emptied(branch, key, page0, next);
+                }
+                if b1.as_const().iter().next().is_none() {
+                    return self.drop_emptied(branch, key, page1, None);
+                }
+
                 match unsafe { b0.balance(b1)? } {
                     Balance::Balanced { lower, higher } => {
                         let lower = lower.as_const();
@@ -487,7 +1314,7 @@ where
 
                         // Do the replacement
                         let higher_page_num = *e.get();
-                        branch.0 = e.delete();
+                        branch.0 = e.delete()?;
                         self.branch_insert(branch, (new_key, higher_page_num))?;
                         Ok(false)
                     }
@@ -507,10 +1334,8 @@ where
                             ));
                         };
 
-                        branch.0 = e.delete();
-                        unsafe {
-                            self.writer.deallocate_page(freed_page)?;
-                        }
+                        branch.0 = e.delete()?;
+                        self.released.push(freed_page);
 
                         // This may make this branch relevant for a balancing. Repeat the process
                         if branch.0.free_space() > (PAGE_4K * 3 / 4) {
@@ -524,6 +1349,15 @@ where
                 }
             }
             (WritePage::Leaf(l0), WritePage::Leaf(l1)) => {
+                let (page0, page1) = (*v0.1, *v1.1);
+                if l0.as_const().iter().next().is_none() {
+                    let next = l1.as_const().iter().next().transpose()?.map(|(k, _)| k);
+                    return self.drop_emptied(branch, key, page0, next);
+                }
+                if l1.as_const().iter().next().is_none() {
+                    return self.drop_emptied(branch, key, page1, None);
+                }
+
                 match unsafe { l0.balance(l1)? } {
                     Balance::Balanced { lower, higher } => {
                         let lower = lower.as_const();
@@ -543,7 +1377,7 @@ where
 
                         // Do the replacement
                         let higher_page_num = *e.get();
-                        branch.0 = e.delete();
+                        branch.0 = e.delete()?;
                         self.branch_insert(branch, (new_key, higher_page_num))?;
                         Ok(false)
                     }
@@ -561,10 +1395,8 @@ where
                             return Err(Error::DataCorruption("Branch holding merged pages should still have old key of the upper page"));
                         };
 
-                        branch.0 = e.delete();
-                        unsafe {
-                            self.writer.deallocate_page(freed_page)?;
-                        }
+                        branch.0 = e.delete()?;
+                        self.released.push(freed_page);
 
                         // This may make this branch relevant for a balancing. Repeat the process
                         if branch.0.free_space() > (PAGE_4K * 3 / 4) {
@@ -626,28 +1458,23 @@ where
     }
 
     pub fn delete(self) -> Result<(), Error> {
-        // Delete the entry, and fix up the tree if it was the first entry in
-        // the page.
         let first = self.entry.first();
-        let mut page = self.entry.delete();
-        if first {
-            if let Some(new) = page.iter_mut().next() {
-                let (new_key, _) = new?;
-                self.tree.replace_branch_first(self.key, new_key)?;
-            }
-        }
-
-        // Check if we have a page that's a good candidate for rebalancing.
-        #[allow(clippy::collapsible_if)]
-        if page.free_space() > (PAGE_4K * 3 / 4) {
-            if self.tree.balance(self.key)? {
-                // Balancing may have eliminated the top of the tree. Check that now.
-                self.tree.reduce_depth()?;
-            }
-        }
+        let page = self.entry.delete()?;
+        self.tree.deleted(self.key, first, page)?;
         Ok(())
     }
 
+    /// Delete the entry, returning a copy of its value. The copy is taken before the page
+    /// changes, as deleting can rebalance the value's page away.
+    pub fn remove(self) -> Result<<L::Value as ToOwned>::Owned, Error>
+    where
+        L::Value: ToOwned,
+    {
+        let old = self.get().to_owned();
+        self.delete()?;
+        Ok(old)
+    }
+
     pub fn replace(mut self, new_value: &L::Value) -> Result<(), Error> {
         // Try and replace normally first
         match self.entry.replace(new_value) {
@@ -657,6 +1484,7 @@ where
         }
 
         // We need to split the page.
+        self.tree.rightmost = None;
         let leaf = self
             .tree
             .split_leaf((self.entry.to_page(), self.entry_page_num), self.key)?;
@@ -668,12 +1496,8 @@ where
         entry.replace(new_value)?;
 
         let page = entry.to_page();
-        #[allow(clippy::collapsible_if)]
         if page.free_space() > (PAGE_4K * 3 / 4) {
-            if self.tree.balance(self.key)? {
-                // Balancing may have eliminated the top of the tree. Check that now.
-                self.tree.reduce_depth()?;
-            }
+            self.tree.rebalance(self.key)?;
         }
 
         Ok(())
@@ -695,6 +1519,7 @@ where
         }
 
         // We need to split the page.
+        self.tree.rightmost = None;
         let leaf = self
             .tree
             .split_leaf((self.entry.to_page(), self.entry_page_num), self.key)?;
@@ -706,18 +1531,463 @@ where
         entry.replace_vectored(new_value)?;
 
         let page = entry.to_page();
-        #[allow(clippy::collapsible_if)]
         if page.free_space() > (PAGE_4K * 3 / 4) {
-            if self.tree.balance(self.key)? {
-                // Balancing may have eliminated the top of the tree. Check that now.
-                self.tree.reduce_depth()?;
-            }
+            self.tree.rebalance(self.key)?;
         }
 
         Ok(())
     }
 }
 
+/// Iterator over a range of a [`BTreeWrite`], with mutable access to the values. Made by
+/// [`BTreeWrite::range_mut`].
+pub struct BTreeIterMut<'a, 't, B, L, W, R>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    L::Key: ToOwned,
+    W: RawWrite,
+{
+    tree: &'t mut BTreeWrite<'a, B, L, W>,
+    range: R,
+    leaf: Option<page::PageIterMut<'a, L>>,
+    /// Where the next leaf starts: `Some(None)` before the first one is loaded, and `None` once
+    /// there are no more.
+    next: Option<Option<<L::Key as ToOwned>::Owned>>,
+}
+
+impl<'a, B, L, W, R> BTreeIterMut<'a, '_, B, L, W, R>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    L::Key: ToOwned,
+    W: RawWrite,
+    R: RangeBounds<L::Key>,
+{
+    fn before_start(&self, key: &L::Key) -> bool {
+        match self.range.start_bound() {
+            Bound::Included(s) => key < s,
+            Bound::Excluded(s) => key <= s,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn past_end(&self, key: &L::Key) -> bool {
+        match self.range.end_bound() {
+            Bound::Included(e) => key > e,
+            Bound::Excluded(e) => key >= e,
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Load the next leaf in the range for writing. Returns false if there isn't one.
+    fn load_leaf(&mut self) -> Result<bool, Error> {
+        let Some(next) = self.next.take() else {
+            return Ok(false);
+        };
+        let key = match &next {
+            Some(next) => {
+                if self.past_end(next.borrow()) {
+                    return Ok(false);
+                }
+                Some(next.borrow())
+            }
+            None => match self.range.start_bound() {
+                Bound::Included(s) | Bound::Excluded(s) => Some(s),
+                Bound::Unbounded => None,
+            },
+        };
+        let res = match key {
+            Some(key) => self.tree.descend(key),
+            None => self.tree.descend_to(Seek::First),
+        };
+        let (leaf, _) = self.tree.finish(res)?;
+        if let Some(fence) = self.tree.fence(key)? {
+            self.next = Some(Some(fence));
+        }
+        self.leaf = Some(leaf.into_iter_mut());
+        Ok(true)
+    }
+}
+
+impl<'a, 't, B, L, W, R> Iterator for BTreeIterMut<'a, 't, B, L, W, R>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    L::Key: ToOwned,
+    W: RawWrite,
+    R: RangeBounds<L::Key>,
+{
+    type Item = Result<(&'t L::Key, &'t mut L::Value), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(leaf) = &mut self.leaf {
+                match leaf.next() {
+                    Some(Ok((k, v))) => {
+                        if self.before_start(k) {
+                            continue;
+                        }
+                        if self.past_end(k) {
+                            self.leaf = None;
+                            self.next = None;
+                            return None;
+                        }
+                        return Some(Ok((k, v)));
+                    }
+                    Some(Err(e)) => {
+                        self.leaf = None;
+                        self.next = None;
+                        return Some(Err(e));
+                    }
+                    None => self.leaf = None,
+                }
+            }
+            match self.load_leaf() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => {
+                    self.next = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// The last key in a leaf that's below `key`, or the leaf's last key if there's no `key`.
+fn last_below<L>(
+    leaf: &PageMapMut<L>,
+    key: Option<&L::Key>,
+) -> Result<Option<<L::Key as ToOwned>::Owned>, Error>
+where
+    L: PageLayout,
+    L::Key: ToOwned,
+{
+    let mut iter = leaf.as_const().iter();
+    let Some(key) = key else {
+        return Ok(iter.next_back().transpose()?.map(|(k, _)| k.to_owned()));
+    };
+    let mut last = None;
+    for res in iter {
+        let (k, _) = res?;
+        if k >= key {
+            break;
+        }
+        last = Some(k);
+    }
+    Ok(last.map(ToOwned::to_owned))
+}
+
+/// A cursor over a [`BTreeWrite`] that can insert and delete pairs where it stands. Made by
+/// [`BTreeWrite::cursor`].
+///
+/// The cursor sits at a pair, or past the last one. It holds on to the leaf that pair is in,
+/// along with the path of branches down to it, so moving within the leaf or changing it only
+/// touches that one page. The tree is only descended again to cross into another leaf, or after
+/// a split or merge has changed the branches above it. Appending sorted keys with
+/// [`insert_before`][Self::insert_before] from past the last pair takes one descent per leaf
+/// filled, instead of one per key.
+pub struct BTreeCursor<'a, 't, B, L, W>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    L::Key: ToOwned,
+    W: RawWrite,
+{
+    tree: &'t mut BTreeWrite<'a, B, L, W>,
+    /// Key of the pair the cursor is at, or `None` once it's past the last pair
+    key: Option<<L::Key as ToOwned>::Owned>,
+    /// The leaf holding that pair, or the rightmost leaf when past the last pair, as long as the
+    /// tree's branches still hold the path down to it
+    leaf: Option<u64>,
+}
+
+impl<'a, B, L, W> BTreeCursor<'a, '_, B, L, W>
+where
+    B: PageLayout<Value = u64>,
+    L: PageLayout<Key = B::Key>,
+    L::Key: ToOwned,
+    W: RawWrite,
+{
+    /// The key of the pair the cursor is at, or `None` if it's past the last pair.
+    pub fn key(&self) -> Option<&L::Key> {
+        self.key.as_ref().map(Borrow::borrow)
+    }
+
+    /// The value of the pair the cursor is at, or `None` if it's past the last pair.
+    pub fn value(&mut self) -> Result<Option<&L::Value>, Error> {
+        let res = self.load();
+        let leaf = self.release_on_err(res)?;
+        let Some(key) = &self.key else {
+            return Ok(None);
+        };
+        for res in leaf.into_iter_mut() {
+            let (k, v) = res?;
+            if k == key.borrow() {
+                return Ok(Some(v));
+            }
+        }
+        Err(Error::InvalidState(
+            "Cursor's pair went missing from its leaf",
+        ))
+    }
+
+    /// Move to the first pair at or after `key`. Returns false if there isn't one, leaving the
+    /// cursor past the last pair.
+    pub fn seek(&mut self, key: &L::Key) -> Result<bool, Error> {
+        let res = self.seek_to(Seek::Key(key), Bound::Included(key.to_owned()));
+        self.release_on_err(res)
+    }
+
+    /// Move to the first pair in the tree. Returns false if the tree is empty.
+    pub fn seek_first(&mut self) -> Result<bool, Error> {
+        let res = self.seek_to(Seek::First, Bound::Unbounded);
+        self.release_on_err(res)
+    }
+
+    /// Move to the next pair. Returns false if there isn't one, leaving the cursor past the last
+    /// pair.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool, Error> {
+        let res = self.step_next();
+        self.release_on_err(res)
+    }
+
+    /// Move to the previous pair. Returns false if there isn't one, leaving the cursor where it
+    /// was.
+    pub fn prev(&mut self) -> Result<bool, Error> {
+        let res = self.step_prev();
+        self.release_on_err(res)
+    }
+
+    /// Insert a pair just before the cursor, which stays at the pair it was at. The key must sort
+    /// between the pair before the cursor and the one at it, or this fails with
+    /// [`Error::IncorrectOperation`].
+    ///
+    /// If the pair before the cursor is in the cursor's leaf, the new pair goes straight into that
+    /// leaf. Otherwise the tree is searched for the pair before, and the new pair is inserted as
+    /// through [`BTreeWrite::entry`].
+    pub fn insert_before(&mut self, key: &L::Key, value: &L::Value) -> Result<(), Error> {
+        let res = self.insert(key, value);
+        self.release_on_err(res)
+    }
+
+    /// Delete the pair the cursor is at, moving on to the pair after it. Returns false if the
+    /// cursor was past the last pair, so there was nothing to delete.
+    pub fn delete_current(&mut self) -> Result<bool, Error> {
+        let res = self.delete();
+        self.release_on_err(res)
+    }
+
+    /// Let go of the cursor's leaf if an operation failed partway, as the branches may not lead
+    /// to it any more.
+    fn release_on_err<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        if res.is_err() {
+            self.leaf = None;
+        }
+        res
+    }
+
+    /// Load the cursor's leaf for writing, descending to it first if it isn't held already.
+    fn load(&mut self) -> Result<PageMapMut<'a, L>, Error> {
+        if let Some(page) = self.leaf {
+            let (WritePage::Leaf(leaf), None) =
+                WritePage::<B, L>::try_load(self.tree.writer, page)?
+            else {
+                return Err(Error::InvalidState(
+                    "Cursor's leaf should be one that's already been written to",
+                ));
+            };
+            return Ok(leaf);
+        }
+        let res = match &self.key {
+            Some(key) => self.tree.descend(key.borrow()),
+            None => self.tree.descend_to(Seek::Last),
+        };
+        let (leaf, page) = self.tree.finish(res)?;
+        self.leaf = Some(page);
+        Ok(leaf)
+    }
+
+    fn seek_to(
+        &mut self,
+        seek: Seek<L::Key>,
+        from: Bound<<L::Key as ToOwned>::Owned>,
+    ) -> Result<bool, Error> {
+        self.leaf = None;
+        let res = self.tree.descend_to(seek);
+        let (leaf, page) = self.tree.finish(res)?;
+        self.leaf = Some(page);
+        self.forward(leaf, from)
+    }
+
+    /// Move to the first pair within `from`, looking through `leaf` and then the leaves after it.
+    /// Returns false if there isn't one, leaving the cursor past the last pair.
+    fn forward(
+        &mut self,
+        mut leaf: PageMapMut<'a, L>,
+        mut from: Bound<<L::Key as ToOwned>::Owned>,
+    ) -> Result<bool, Error> {
+        loop {
+            let mut found = None;
+            for res in leaf.as_const().iter() {
+                let (k, _) = res?;
+                let within = match &from {
+                    Bound::Included(f) => k >= f.borrow(),
+                    Bound::Excluded(f) => k > f.borrow(),
+                    Bound::Unbounded => true,
+                };
+                if within {
+                    found = Some(k.to_owned());
+                    break;
+                }
+            }
+            if found.is_some() {
+                self.key = found;
+                return Ok(true);
+            }
+
+            // Nothing left in this leaf, so go on to the next one, if there is one
+            let key = match &from {
+                Bound::Included(f) | Bound::Excluded(f) => Some(f.borrow()),
+                Bound::Unbounded => None,
+            };
+            let Some(fence) = self.tree.fence(key)? else {
+                self.key = None;
+                return Ok(false);
+            };
+            let res = self.tree.descend(fence.borrow());
+            let (next, page) = self.tree.finish(res)?;
+            self.leaf = Some(page);
+            leaf = next;
+            from = Bound::Included(fence);
+        }
+    }
+
+    fn step_next(&mut self) -> Result<bool, Error> {
+        let Some(key) = self.key.as_ref().map(|k| k.borrow().to_owned()) else {
+            return Ok(false);
+        };
+        let leaf = self.load()?;
+        self.forward(leaf, Bound::Excluded(key))
+    }
+
+    fn step_prev(&mut self) -> Result<bool, Error> {
+        let leaf = self.load()?;
+        if let Some(prev) = last_below(&leaf, self.key.as_ref().map(Borrow::borrow))? {
+            self.key = Some(prev);
+            return Ok(true);
+        }
+        let Some(key) = &self.key else {
+            return Ok(false);
+        };
+
+        // The cursor is at the start of its leaf, so look in the leaf before it
+        let res = self.tree.descend_to(Seek::Before(key.borrow()));
+        let (leaf, page) = self.tree.finish(res)?;
+        match last_below(&leaf, Some(key.borrow()))? {
+            Some(prev) => {
+                self.key = Some(prev);
+                self.leaf = Some(page);
+                Ok(true)
+            }
+            None => {
+                self.leaf = None;
+                Ok(false)
+            }
+        }
+    }
+
+    fn insert(&mut self, key: &L::Key, value: &L::Value) -> Result<(), Error> {
+        let leaf = self.load()?;
+        let current = self.key.as_ref().map(Borrow::borrow);
+        if current.is_some_and(|c| key >= c) {
+            return Err(Error::IncorrectOperation);
+        }
+
+        let Some(prev) = last_below(&leaf, current)? else {
+            // The pair before, if there is one, is in an earlier leaf
+            self.leaf = None;
+            self.tree.rightmost = None;
+            let out_of_order = {
+                let read = self.tree.as_read();
+                let upper = current.map_or(Bound::Unbounded, Bound::Excluded);
+                let mut iter = read.range::<L::Key, _>((Bound::Unbounded, upper))?;
+                match iter.next_back() {
+                    Some(res) => res?.0 >= key,
+                    None => false,
+                }
+            };
+            if out_of_order {
+                return Err(Error::IncorrectOperation);
+            }
+            let Entry::Vacant(entry) = self.tree.entry(key)? else {
+                return Err(Error::InvalidState(
+                    "Key between two neighbouring pairs was already in the tree",
+                ));
+            };
+            entry.insert(value)?;
+            return Ok(());
+        };
+        if prev.borrow() >= key {
+            return Err(Error::IncorrectOperation);
+        }
+
+        let page = self
+            .leaf
+            .ok_or(Error::InvalidState("Cursor's leaf was just loaded"))?;
+        let page::Entry::Vacant(entry) = leaf.entry(key)? else {
+            return Err(Error::InvalidState(
+                "Key between two neighbouring pairs was already in their leaf",
+            ));
+        };
+        match entry.insert(value) {
+            Ok(_) => Ok(()),
+            Err((entry, Error::OutofSpace(_))) => {
+                // Splitting the leaf changes the branches above it
+                self.leaf = None;
+                self.tree.rightmost = None;
+                let entry = VacantEntry {
+                    tree: &mut *self.tree,
+                    key,
+                    entry,
+                    entry_page_num: page,
+                };
+                entry.insert(value)?;
+                Ok(())
+            }
+            Err((_, e)) => Err(e),
+        }
+    }
+
+    fn delete(&mut self) -> Result<bool, Error> {
+        let Some(key) = self.key.as_ref().map(|k| k.borrow().to_owned()) else {
+            return Ok(false);
+        };
+        let leaf = self.load()?;
+        let page::Entry::Occupied(entry) = leaf.entry(key.borrow())? else {
+            return Err(Error::InvalidState(
+                "Cursor's pair went missing from its leaf",
+            ));
+        };
+        let first = entry.first();
+        let leaf = entry.delete()?;
+        let leaf = if self.tree.deleted(key.borrow(), first, leaf)? {
+            // The branches changed, so descend again to where the pair was
+            self.leaf = None;
+            let res = self.tree.descend(key.borrow());
+            let (leaf, page) = self.tree.finish(res)?;
+            self.leaf = Some(page);
+            leaf
+        } else {
+            self.load()?
+        };
+        self.forward(leaf, Bound::Excluded(key))?;
+        Ok(true)
+    }
+}
+
 pub struct VacantEntry<'a, 't, 'k, B, L, W>
 where
     B: PageLayout<Value = u64>,
@@ -773,10 +2043,14 @@ where
             Err((_, e)) => return Err(e),
         };
 
-        // We need to split the page.
+        // We need to split the page. If this was the rightmost leaf, the new upper page is the
+        // rightmost one now, and it's where an appended key ends up.
         let leaf = self
             .tree
             .split_leaf((entry.to_page(), self.entry_page_num), self.key)?;
+        if self.tree.rightmost.is_some() {
+            self.tree.rightmost = (leaf.1 != self.entry_page_num).then_some(leaf.1);
+        }
         let page::Entry::Vacant(entry) = leaf.0.entry(self.key)? else {
             return Err(Error::InvalidState(
                 "Split a page but we couldn't re-locate the vacant entry inside it",
@@ -855,10 +2129,14 @@ where
             Err((_, e)) => return Err(e),
         };
 
-        // We need to split the page.
+        // We need to split the page. If this was the rightmost leaf, the new upper page is the
+        // rightmost one now, and it's where an appended key ends up.
         let leaf = self
             .tree
             .split_leaf((entry.to_page(), self.entry_page_num), self.key)?;
+        if self.tree.rightmost.is_some() {
+            self.tree.rightmost = (leaf.1 != self.entry_page_num).then_some(leaf.1);
+        }
         let page::Entry::Vacant(entry) = leaf.0.entry(self.key)? else {
             return Err(Error::InvalidState(
                 "Split a page but we couldn't re-locate the vacant entry inside it",
@@ -0,0 +1,382 @@
+//! Wrappers around a [`RawRead`] or [`RawWrite`] that count every call made through them, for tests
+//! that need to assert how much work an operation did.
+//!
+//! [`CountingReader`] and [`CountingWriter`] forward everything to the wrapped storage unchanged.
+//! Along the way they count loads, writable loads, allocations, and deallocations, split up by how
+//! many pages each call was for, and keep the most recent page numbers touched in order. All of it
+//! is plain atomics, so wrapping storage doesn't change what it's doing beyond a handful of relaxed
+//! increments per call.
+//!
+//! Only calls made through the wrapper are seen. If the wrapped writer allocates while handling a
+//! [`load_mut`][RawWrite::load_mut], that shows up as the `load_mut` alone.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    btree::{LoadMut, LoadMutPage, RawRead, RawWrite},
+    StorageError, PAGE_4K,
+};
+
+/// Number of size buckets calls are counted in. See [`size_bucket`].
+pub const SIZE_BUCKETS: usize = 8;
+
+/// How many touched page numbers are kept by default.
+pub const DEFAULT_TRACE_LEN: usize = 4096;
+
+/// The bucket a call for `num_pages` pages is counted in. Bucket 0 is single pages, and each one
+/// after covers twice as many as the one before: 2 pages, then 3 to 4, then 5 to 8, and so on. The
+/// last bucket also takes everything bigger.
+pub const fn size_bucket(num_pages: usize) -> usize {
+    if num_pages <= 1 {
+        return 0;
+    }
+    let bucket = (usize::BITS - (num_pages - 1).leading_zeros()) as usize;
+    if bucket < SIZE_BUCKETS {
+        bucket
+    } else {
+        SIZE_BUCKETS - 1
+    }
+}
+
+/// Number of calls of one kind, split up by [`size_bucket`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeCounts(pub [u64; SIZE_BUCKETS]);
+
+impl SizeCounts {
+    /// Calls of every size.
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    /// Calls in the same bucket as a call for `num_pages` pages.
+    pub fn sized(&self, num_pages: usize) -> u64 {
+        self.0[size_bucket(num_pages)]
+    }
+}
+
+/// Everything counted by a [`CountingReader`] or [`CountingWriter`] since it was created or last
+/// reset.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallCounts {
+    /// Calls to [`load`][RawRead::load] and [`load_page`][RawRead::load_page]
+    pub loads: SizeCounts,
+    /// Calls to [`load_mut`][RawWrite::load_mut] and [`load_mut_page`][RawWrite::load_mut_page]
+    pub load_muts: SizeCounts,
+    /// Calls to [`allocate`][RawWrite::allocate] and [`allocate_page`][RawWrite::allocate_page]
+    pub allocates: SizeCounts,
+    /// Regions deallocated, whether one at a time or in a batch
+    pub deallocates: SizeCounts,
+    /// The most recent page numbers touched by any of the above, oldest first. Allocations record
+    /// the page they handed out.
+    pub touched: Vec<u64>,
+    /// How many older page numbers no longer fit in `touched`
+    pub touched_dropped: usize,
+}
+
+/// The counters shared by both wrappers.
+#[derive(Debug)]
+struct Counters {
+    loads: [AtomicU64; SIZE_BUCKETS],
+    load_muts: [AtomicU64; SIZE_BUCKETS],
+    allocates: [AtomicU64; SIZE_BUCKETS],
+    deallocates: [AtomicU64; SIZE_BUCKETS],
+    /// Ring buffer of touched page numbers
+    touched: Box<[AtomicU64]>,
+    /// Total page numbers ever recorded, wrapping around `touched`
+    next_touch: AtomicUsize,
+}
+
+impl Counters {
+    fn new(trace_len: usize) -> Self {
+        Self {
+            loads: Default::default(),
+            load_muts: Default::default(),
+            allocates: Default::default(),
+            deallocates: Default::default(),
+            touched: (0..trace_len).map(|_| AtomicU64::new(0)).collect(),
+            next_touch: AtomicUsize::new(0),
+        }
+    }
+
+    fn record(&self, kind: &[AtomicU64; SIZE_BUCKETS], page: u64, num_pages: usize) {
+        kind[size_bucket(num_pages)].fetch_add(1, Ordering::Relaxed);
+        if self.touched.is_empty() {
+            return;
+        }
+        let idx = self.next_touch.fetch_add(1, Ordering::Relaxed);
+        self.touched[idx % self.touched.len()].store(page, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CallCounts {
+        let counts = |kind: &[AtomicU64; SIZE_BUCKETS]| {
+            SizeCounts(core::array::from_fn(|i| kind[i].load(Ordering::Relaxed)))
+        };
+        let end = self.next_touch.load(Ordering::Relaxed);
+        let start = end.saturating_sub(self.touched.len());
+        CallCounts {
+            loads: counts(&self.loads),
+            load_muts: counts(&self.load_muts),
+            allocates: counts(&self.allocates),
+            deallocates: counts(&self.deallocates),
+            touched: (start..end)
+                .map(|i| self.touched[i % self.touched.len()].load(Ordering::Relaxed))
+                .collect(),
+            touched_dropped: start,
+        }
+    }
+
+    fn reset(&self) {
+        let kinds = [
+            &self.loads,
+            &self.load_muts,
+            &self.allocates,
+            &self.deallocates,
+        ];
+        for count in kinds.into_iter().flatten() {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.next_touch.store(0, Ordering::Relaxed);
+    }
+}
+
+/// A [`RawRead`] that counts every load made through it. See the [module docs][self].
+#[derive(Debug)]
+pub struct CountingReader<R: RawRead> {
+    inner: R,
+    counts: Counters,
+}
+
+impl<R: RawRead> CountingReader<R> {
+    /// Wrap a reader, keeping the last [`DEFAULT_TRACE_LEN`] page numbers touched.
+    pub fn new(inner: R) -> Self {
+        Self::with_trace_len(inner, DEFAULT_TRACE_LEN)
+    }
+
+    /// Wrap a reader, keeping the last `trace_len` page numbers touched.
+    pub fn with_trace_len(inner: R, trace_len: usize) -> Self {
+        Self {
+            inner,
+            counts: Counters::new(trace_len),
+        }
+    }
+
+    /// The wrapped reader.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwrap the reader, discarding the counts.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Everything counted so far.
+    pub fn snapshot(&self) -> CallCounts {
+        self.counts.snapshot()
+    }
+
+    /// Start counting again from zero.
+    pub fn reset(&self) {
+        self.counts.reset()
+    }
+}
+
+unsafe impl<R: RawRead> RawRead for CountingReader<R> {
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        self.counts.record(&self.counts.loads, page, num_pages);
+        unsafe { self.inner.load(page, num_pages) }
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+
+    unsafe fn load_page(&self, page: u64) -> Result<&[u8; PAGE_4K], StorageError> {
+        self.counts.record(&self.counts.loads, page, 1);
+        unsafe { self.inner.load_page(page) }
+    }
+}
+
+/// A [`RawWrite`] that counts every call made through it. See the [module docs][self].
+#[derive(Debug)]
+pub struct CountingWriter<W: RawWrite> {
+    inner: W,
+    counts: Counters,
+}
+
+impl<W: RawWrite> CountingWriter<W> {
+    /// Wrap a writer, keeping the last [`DEFAULT_TRACE_LEN`] page numbers touched.
+    pub fn new(inner: W) -> Self {
+        Self::with_trace_len(inner, DEFAULT_TRACE_LEN)
+    }
+
+    /// Wrap a writer, keeping the last `trace_len` page numbers touched.
+    pub fn with_trace_len(inner: W, trace_len: usize) -> Self {
+        Self {
+            inner,
+            counts: Counters::new(trace_len),
+        }
+    }
+
+    /// The wrapped writer.
+    pub fn inner(&self) -> &W {
+        &self.inner
+    }
+
+    /// The wrapped writer, mutably, eg. to commit through it.
+    pub fn inner_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwrap the writer, discarding the counts.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Everything counted so far.
+    pub fn snapshot(&self) -> CallCounts {
+        self.counts.snapshot()
+    }
+
+    /// Start counting again from zero.
+    pub fn reset(&self) {
+        self.counts.reset()
+    }
+}
+
+unsafe impl<W: RawWrite> RawRead for CountingWriter<W> {
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        self.counts.record(&self.counts.loads, page, num_pages);
+        unsafe { self.inner.load(page, num_pages) }
+    }
+
+    fn generation(&self) -> u64 {
+        self.inner.generation()
+    }
+
+    unsafe fn load_page(&self, page: u64) -> Result<&[u8; PAGE_4K], StorageError> {
+        self.counts.record(&self.counts.loads, page, 1);
+        unsafe { self.inner.load_page(page) }
+    }
+}
+
+unsafe impl<W: RawWrite> RawWrite for CountingWriter<W> {
+    unsafe fn load_mut(&self, page: u64, num_pages: usize) -> Result<LoadMut<'_>, StorageError> {
+        self.counts.record(&self.counts.load_muts, page, num_pages);
+        unsafe { self.inner.load_mut(page, num_pages) }
+    }
+
+    fn allocate(&self, num_pages: usize) -> Result<(&mut [u8], u64), StorageError> {
+        let (mem, page) = self.inner.allocate(num_pages)?;
+        self.counts.record(&self.counts.allocates, page, num_pages);
+        Ok((mem, page))
+    }
+
+    unsafe fn deallocate(&self, page: u64, num_pages: usize) -> Result<(), StorageError> {
+        self.counts
+            .record(&self.counts.deallocates, page, num_pages);
+        unsafe { self.inner.deallocate(page, num_pages) }
+    }
+
+    unsafe fn deallocate_batch(
+        &self,
+        pages: &mut dyn Iterator<Item = (u64, usize)>,
+    ) -> Result<(), StorageError> {
+        let mut counted = pages.inspect(|&(page, num_pages)| {
+            self.counts
+                .record(&self.counts.deallocates, page, num_pages)
+        });
+        unsafe { self.inner.deallocate_batch(&mut counted) }
+    }
+
+    unsafe fn load_mut_page(&self, page: u64) -> Result<LoadMutPage<'_>, StorageError> {
+        self.counts.record(&self.counts.load_muts, page, 1);
+        unsafe { self.inner.load_mut_page(page) }
+    }
+
+    fn allocate_page(&self) -> Result<(&mut [u8; PAGE_4K], u64), StorageError> {
+        let (mem, page) = self.inner.allocate_page()?;
+        self.counts.record(&self.counts.allocates, page, 1);
+        Ok((mem, page))
+    }
+
+    unsafe fn deallocate_page(&self, page: u64) -> Result<(), StorageError> {
+        self.counts.record(&self.counts.deallocates, page, 1);
+        unsafe { self.inner.deallocate_page(page) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        btree::{BTreeRead, Entry},
+        page::{LayoutU64U64, LayoutU64Var},
+        sim::{SimAllocator, SIM_ROOT_PAGE},
+    };
+
+    #[test]
+    fn buckets() {
+        let sizes = [0, 1, 2, 3, 4, 5, 8, 9, 64, 65, 128, 1 << 20];
+        let buckets = sizes.map(size_bucket);
+        assert_eq!(buckets, [0, 0, 1, 2, 2, 3, 3, 4, 6, 7, 7, 7]);
+    }
+
+    #[test]
+    fn counts_and_trace() {
+        let writer = CountingWriter::with_trace_len(SimAllocator::new(), 4);
+        let (_, one) = writer.allocate(1).unwrap();
+        let (_, three) = writer.allocate(3).unwrap();
+        let (_, page) = writer.allocate_page().unwrap();
+        unsafe {
+            writer.load(three, 3).unwrap();
+            writer.load_mut(one, 1).unwrap();
+            let mut batch = [(one, 1), (three, 3)].into_iter();
+            writer.deallocate_batch(&mut batch).unwrap();
+        }
+
+        let counts = writer.snapshot();
+        assert_eq!(counts.allocates.total(), 3);
+        assert_eq!(counts.allocates.sized(1), 2);
+        assert_eq!(counts.allocates.sized(4), 1);
+        assert_eq!((counts.loads.total(), counts.loads.sized(3)), (1, 1));
+        assert_eq!(counts.load_muts.sized(1), 1);
+        assert_eq!(counts.deallocates.0[..3], [1, 0, 1]);
+        // Only the last four pages touched are kept
+        assert_eq!(counts.touched, [three, one, one, three]);
+        assert_eq!(counts.touched_dropped, 3);
+        assert!(page > three);
+
+        // Reads through a wrapped reader count the same way, and resetting starts from scratch
+        let reader = CountingReader::new(writer.into_inner().reader().unwrap());
+        unsafe { reader.load_page(SIM_ROOT_PAGE).unwrap() };
+        assert_eq!(reader.snapshot().touched, [SIM_ROOT_PAGE]);
+        reader.reset();
+        assert_eq!(reader.snapshot(), CallCounts::default());
+    }
+    #[test]
+    fn tree_loads() {
+        let mut writer = SimAllocator::new();
+        let mut tree = writer.tree::<LayoutU64U64, LayoutU64Var>().unwrap();
+        for i in 0..2000u64 {
+            let Entry::Vacant(v) = tree.entry(&i).unwrap() else {
+                panic!("Key {i} was already in the tree");
+            };
+            v.insert(&i.to_le_bytes()).unwrap();
+        }
+        drop(tree);
+        writer.commit().unwrap();
+
+        // Every page on the way down counts once
+        let reader = writer.reader().unwrap();
+        let counting = CountingReader::new(&reader);
+        let tree =
+            unsafe { BTreeRead::<LayoutU64U64, LayoutU64Var, _>::load(&counting, reader.root()) }
+                .unwrap();
+        let loaded = counting.snapshot().loads.total();
+        assert!(loaded >= 1);
+        assert!(tree.get(&1234).unwrap().is_some());
+        assert_eq!(counting.snapshot().loads.total(), loaded + 1);
+    }
+}
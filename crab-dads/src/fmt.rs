@@ -0,0 +1,141 @@
+//! Rendering raw page bytes for humans, for use when debugging corruption.
+//!
+//! Every `Debug` impl in this crate that shows page bytes goes through [`HexDump`], so dumps from
+//! different places can be lined up and diffed against each other.
+
+use core::fmt;
+
+/// Number of bytes shown on each row of a [`HexDump`].
+pub const ROW_LEN: usize = 16;
+
+/// A hex dump of some bytes: an offset column, [`ROW_LEN`] bytes per row split into two groups of
+/// eight, and an ASCII gutter showing the printable ones.
+///
+/// ```text
+/// 00000ff0  63 72 61 62 2d 64 61 64  73 00 01 02 03 04 05 06  |crab-dads.......|
+/// 00001000  ff 7f                                             |..|
+/// ```
+///
+/// `Display` writes the rows with a newline between each. `Debug` writes the same, but starts on
+/// a new line so the dump lines up when it's a field of a larger struct. The format is meant to
+/// stay stable, so dumps in old logs can be diffed against new ones.
+#[derive(Clone, Copy)]
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    base: u64,
+    ascii: bool,
+}
+
+impl<'a> HexDump<'a> {
+    /// Dump `bytes`, with offsets starting from 0 and the ASCII gutter shown.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            base: 0,
+            ascii: true,
+        }
+    }
+
+    /// Start the offset column at `base` instead of 0, such as the file offset the bytes were
+    /// mapped from.
+    pub fn with_base_offset(mut self, base: u64) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Show or hide the ASCII gutter. Without it, rows end right after their last byte.
+    pub fn with_ascii(mut self, ascii: bool) -> Self {
+        self.ascii = ascii;
+        self
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, row) in self.bytes.chunks(ROW_LEN).enumerate() {
+            if idx > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:08x}", self.base.wrapping_add((idx * ROW_LEN) as u64))?;
+            // Short rows only need padding if there's a gutter to line up
+            let cols = if self.ascii { ROW_LEN } else { row.len() };
+            for col in 0..cols {
+                if col % 8 == 0 {
+                    f.write_str(" ")?;
+                }
+                match row.get(col) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            if self.ascii {
+                f.write_str("  |")?;
+                for &byte in row {
+                    let c = match byte {
+                        b' '..=b'~' => byte as char,
+                        _ => '.',
+                    };
+                    write!(f, "{}", c)?;
+                }
+                f.write_str("|")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.bytes.is_empty() {
+            f.write_str("\n")?;
+        }
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn rows() {
+        let mut bytes = b"crab-dads".to_vec();
+        bytes.extend(0..=6);
+        bytes.extend([0xff, 0x7f]);
+        let dump = HexDump::new(&bytes);
+        assert_eq!(
+            dump.to_string(),
+            "00000000  63 72 61 62 2d 64 61 64  73 00 01 02 03 04 05 06  |crab-dads.......|\n\
+             00000010  ff 7f                                             |..|"
+        );
+
+        // Offsets can start anywhere, and the gutter is optional
+        assert_eq!(
+            dump.with_base_offset(0xff0).with_ascii(false).to_string(),
+            "00000ff0  63 72 61 62 2d 64 61 64  73 00 01 02 03 04 05 06\n\
+             00001000  ff 7f"
+        );
+        let wide = HexDump::new(b" ~").with_base_offset(0x1_0000_0000);
+        assert_eq!(
+            wide.to_string(),
+            "100000000  20 7e                                             | ~|"
+        );
+
+        // A row of exactly eight bytes doesn't start a second group
+        let eight: Vec<u8> = (0x41..0x49).collect();
+        assert_eq!(
+            HexDump::new(&eight).with_ascii(false).to_string(),
+            "00000000  41 42 43 44 45 46 47 48"
+        );
+    }
+
+    #[test]
+    fn debug_starts_on_a_new_line() {
+        let padded = format!("\n00000000  01{}  |.|", " ".repeat(46));
+        assert_eq!(format!("{:?}", HexDump::new(&[1])), padded);
+        assert_eq!(format!("{:?}", HexDump::new(&[])), "");
+        assert_eq!(HexDump::new(&[]).to_string(), "");
+    }
+}
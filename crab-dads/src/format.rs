@@ -0,0 +1,184 @@
+//! Where every byte crab-dads writes to disk goes, as constants derived from the structs that
+//! actually get written.
+//!
+//! A page is [`PAGE_SIZE`] bytes, ending in a trailer. Pairs are stored as two arrays:
+//!
+//! - The lower array starts at byte 0 and grows up. It holds each pair's key bytes followed by its
+//!   value bytes, in key order. Variable-length keys and values are padded out to a multiple of
+//!   [`VAR_ALIGN`] bytes, with whatever the padding bytes happened to hold before, and fixed `u64`s
+//!   are stored little-endian.
+//! - The upper array ends at [`TRAILER_OFFSET`] and grows down. It holds one info entry per pair,
+//!   also in key order, so the first pair's entry sits right below the trailer. Each layout has its
+//!   own info entry, whose size and fields are below.
+//!
+//! All multi-byte integers are little-endian. The golden-file tests in this module pin the whole
+//! encoding, so changing any of it means regenerating the fixtures on purpose.
+
+use core::mem::{offset_of, size_of};
+
+use crate::{
+    page::{LayoutU64U64, LayoutU64Var, LayoutVarU64},
+    TwoArrayTrailer,
+};
+
+/// Size of a page.
+pub const PAGE_SIZE: usize = crate::PAGE_4K;
+
+/// Size of the trailer at the end of every page.
+pub const TRAILER_SIZE: usize = size_of::<TwoArrayTrailer>();
+
+/// Offset of the trailer within a page. Everything before it belongs to the two arrays.
+pub const TRAILER_OFFSET: usize = PAGE_SIZE - TRAILER_SIZE;
+
+/// Offset of the lower array's length in bytes, as a `u16`.
+pub const TRAILER_LOWER_LEN: usize = TRAILER_OFFSET + offset_of!(TwoArrayTrailer, lower_len);
+
+/// Offset of the upper array's length in info entries, as a `u16`.
+pub const TRAILER_UPPER_LEN: usize = TRAILER_OFFSET + offset_of!(TwoArrayTrailer, upper_len);
+
+/// Offset of the 2 reserved trailer bytes, which are always written as zero.
+pub const TRAILER_RESERVED: usize = TRAILER_OFFSET + offset_of!(TwoArrayTrailer, unused0);
+
+/// Offset of the layout id byte. See [`PageLayout::LAYOUT_ID`][crate::page::PageLayout::LAYOUT_ID].
+pub const TRAILER_LAYOUT_ID: usize = TRAILER_OFFSET + offset_of!(TwoArrayTrailer, layout_id);
+
+/// Offset of the page type byte.
+pub const TRAILER_PAGE_TYPE: usize = TRAILER_OFFSET + offset_of!(TwoArrayTrailer, page_type);
+
+/// Bit of the page type that's set on B-tree leaf pages, and clear on branch pages.
+pub const PAGE_TYPE_LEAF: u8 = 0x01;
+
+/// Variable-length keys and values are padded out to a multiple of this many bytes.
+pub const VAR_ALIGN: usize = 8;
+
+/// Size of a [`LayoutU64U64`] info entry.
+pub const U64_U64_INFO_SIZE: usize = size_of::<LayoutU64U64>();
+
+/// Offset of the key within a [`LayoutU64U64`] info entry, as a `u64`. Keys aren't stored in the
+/// lower array at all, which only holds the 8-byte values.
+pub const U64_U64_INFO_KEY: usize = offset_of!(LayoutU64U64, key);
+
+/// Size of a [`LayoutU64Var`] info entry.
+pub const U64_VAR_INFO_SIZE: usize = size_of::<LayoutU64Var>();
+
+/// Offset of the unpadded value length within a [`LayoutU64Var`] info entry, as a `u16`.
+pub const U64_VAR_INFO_VALUE_LEN: usize = offset_of!(LayoutU64Var, len);
+
+/// Size of a [`LayoutVarU64`] info entry.
+pub const VAR_U64_INFO_SIZE: usize = size_of::<LayoutVarU64>();
+
+/// Offset of the unpadded key length within a [`LayoutVarU64`] info entry, as a `u16`.
+pub const VAR_U64_INFO_KEY_LEN: usize = offset_of!(LayoutVarU64, len);
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, vec::Vec};
+
+    use super::*;
+    use crate::page::{Entry, PageLayout, PageMapMut};
+
+    #[repr(align(4096))]
+    struct Page([u8; PAGE_SIZE]);
+
+    /// Compare against a checked-in fixture, or rewrite it if `UPDATE_FIXTURES` is set.
+    fn check_fixture(name: &str, bytes: &[u8]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name);
+        if std::env::var_os("UPDATE_FIXTURES").is_some() {
+            std::fs::write(&path, bytes).unwrap();
+            return;
+        }
+        let expected = std::fs::read(&path)
+            .unwrap_or_else(|e| panic!("couldn't read fixture {}: {e}", path.display()));
+        if let Some(at) =
+            (0..bytes.len().max(expected.len())).find(|i| bytes.get(*i) != expected.get(*i))
+        {
+            panic!(
+                "{name} no longer matches its fixture, starting at byte {at}. If the format change \
+                 is intentional, rerun with UPDATE_FIXTURES=1 and commit the new fixture."
+            );
+        }
+    }
+
+    fn fill<T: PageLayout>(page: &mut Page, page_type: u8, pairs: &[(&T::Key, &T::Value)]) {
+        let mut map = PageMapMut::<T>::new(&mut page.0, page_type);
+        for (k, v) in pairs {
+            let Entry::Vacant(entry) = map.entry(k).unwrap() else {
+                panic!("fixture keys should be unique");
+            };
+            map = entry.insert(v).map_err(|(_, e)| e).unwrap().to_page();
+        }
+    }
+
+    fn u16_at(page: &Page, offset: usize) -> u16 {
+        u16::from_le_bytes([page.0[offset], page.0[offset + 1]])
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(TRAILER_SIZE, 8);
+        assert_eq!(TRAILER_OFFSET, 4088);
+        assert_eq!(TRAILER_LOWER_LEN, 4088);
+        assert_eq!(TRAILER_UPPER_LEN, 4090);
+        assert_eq!(TRAILER_RESERVED, 4092);
+        assert_eq!(TRAILER_LAYOUT_ID, 4094);
+        assert_eq!(TRAILER_PAGE_TYPE, 4095);
+        assert_eq!((U64_U64_INFO_SIZE, U64_U64_INFO_KEY), (8, 0));
+        assert_eq!((U64_VAR_INFO_SIZE, U64_VAR_INFO_VALUE_LEN), (2, 0));
+        assert_eq!((VAR_U64_INFO_SIZE, VAR_U64_INFO_KEY_LEN), (2, 0));
+        assert_eq!(crate::page::CONTENT_SIZE, TRAILER_OFFSET);
+    }
+
+    #[test]
+    fn golden_u64_u64() {
+        let mut page = Page([0; PAGE_SIZE]);
+        let keys: Vec<u64> = (0..16).map(|i| i * 0x0101_0101).collect();
+        let values: Vec<u64> = keys.iter().map(|k| !k).collect();
+        let pairs: Vec<_> = keys.iter().zip(values.iter()).collect();
+        fill::<LayoutU64U64>(&mut page, 0x21, &pairs);
+
+        assert_eq!(u16_at(&page, TRAILER_LOWER_LEN), 16 * 8);
+        assert_eq!(u16_at(&page, TRAILER_UPPER_LEN), 16);
+        assert_eq!(page.0[TRAILER_LAYOUT_ID], LayoutU64U64::LAYOUT_ID);
+        assert_eq!(page.0[TRAILER_PAGE_TYPE], 0x21);
+        let first = TRAILER_OFFSET - U64_U64_INFO_SIZE + U64_U64_INFO_KEY;
+        assert_eq!(page.0[first..first + 8], keys[0].to_le_bytes());
+        check_fixture("page_u64_u64.bin", &page.0);
+    }
+
+    #[test]
+    fn golden_u64_var() {
+        let mut page = Page([0; PAGE_SIZE]);
+        let values: [&[u8]; 5] = [b"", b"a", b"exactly8", b"nine byte", &[0xA5; 40]];
+        let keys: Vec<u64> = (0..5).map(|i| 0x1000 + i).collect();
+        let pairs: Vec<_> = keys.iter().zip(values).collect();
+        fill::<LayoutU64Var>(&mut page, 0x31, &pairs);
+
+        assert_eq!(u16_at(&page, TRAILER_LOWER_LEN), 5 * 8 + (8 + 8 + 16 + 40));
+        assert_eq!(u16_at(&page, TRAILER_UPPER_LEN), 5);
+        assert_eq!(page.0[TRAILER_LAYOUT_ID], LayoutU64Var::LAYOUT_ID);
+        let last = TRAILER_OFFSET - 5 * U64_VAR_INFO_SIZE + U64_VAR_INFO_VALUE_LEN;
+        assert_eq!(u16_at(&page, last), 40);
+        check_fixture("page_u64_var.bin", &page.0);
+    }
+
+    #[test]
+    fn golden_var_u64() {
+        let mut page = Page([0; PAGE_SIZE]);
+        let keys: [&[u8]; 5] = [b"\x00", b"a", b"apple", b"exactly8", b"nine byte"];
+        let values: Vec<u64> = (0..5).map(|i| u64::MAX - i).collect();
+        let pairs: Vec<_> = keys.into_iter().zip(values.iter()).collect();
+        fill::<LayoutVarU64>(&mut page, 0x40, &pairs);
+
+        assert_eq!(
+            u16_at(&page, TRAILER_LOWER_LEN),
+            5 * 8 + (8 + 8 + 8 + 8 + 16)
+        );
+        assert_eq!(u16_at(&page, TRAILER_UPPER_LEN), 5);
+        assert_eq!(page.0[TRAILER_LAYOUT_ID], LayoutVarU64::LAYOUT_ID);
+        let first = TRAILER_OFFSET - VAR_U64_INFO_SIZE + VAR_U64_INFO_KEY_LEN;
+        assert_eq!(u16_at(&page, first), 1);
+        check_fixture("page_var_u64.bin", &page.0);
+    }
+}
@@ -7,7 +7,15 @@ pub mod arrays;
 mod trailer;
 pub use trailer::*;
 pub mod btree;
+pub mod fmt;
+pub mod format;
 pub mod page;
+#[cfg(any(test, feature = "testing"))]
+pub mod counting;
+#[cfg(any(test, feature = "testing"))]
+pub mod rng;
+#[cfg(any(test, feature = "testing"))]
+pub mod sim;
 
 #[derive(Debug, PartialEq, Eq)]
 #[non_exhaustive]
@@ -64,6 +72,31 @@ pub enum Error {
     IncorrectOperation,
     /// Database structure entered into an invalid state.
     InvalidState(&'static str),
+    /// A tree was loaded from a different snapshot generation than the one
+    /// the caller expected, meaning the root page it was given is stale.
+    StaleGeneration {
+        /// The generation the caller expected
+        expected: u64,
+        /// The generation of the reader that was actually used
+        found: u64,
+    },
+    /// A page was left half-modified by a panic, and was marked as poisoned
+    /// rather than risk its contents being misread.
+    Poisoned,
+    /// A tree pointed at [`NULL_PAGE`], which can never hold tree data.
+    NullPage,
+    /// Tried to write a zero-length variable-length key. Values may be empty,
+    /// but keys must be at least one byte long.
+    EmptyKey,
+    /// A page was written with a different [`PageLayout`][page::PageLayout]
+    /// than the one it was loaded as, usually because a tree was opened with
+    /// the wrong layouts.
+    LayoutMismatch {
+        /// Layout id of the layout the page was loaded as
+        expected: u8,
+        /// Layout id stored in the page
+        found: u8,
+    },
 }
 
 impl core::error::Error for Error {
@@ -90,6 +123,20 @@ impl core::fmt::Display for Error {
                 f.write_str("attempted to perform a nonsensical operation on the database")
             }
             Self::InvalidState(s) => write!(f, "Invalid database system state: {}", s),
+            Self::StaleGeneration { expected, found } => write!(
+                f,
+                "Tree handle is stale: expected generation {}, reader is at generation {}",
+                expected, found
+            ),
+            Self::Poisoned => {
+                f.write_str("page was left half-modified by a panic and can't be used")
+            }
+            Self::NullPage => f.write_str("Data Corruption: tree points to the null page"),
+            Self::EmptyKey => f.write_str("Variable-length keys can't be empty"),
+            Self::LayoutMismatch { expected, found } => write!(
+                f,
+                "Data Corruption: page has layout id {found}, but was loaded as layout id {expected}"
+            ),
         }
     }
 }
@@ -98,23 +145,6 @@ impl core::fmt::Display for Error {
 /// non-Apple ARM.
 const PAGE_4K: usize = 1 << 12;
 
-
-pub(crate) struct ByteFormatter<'a>(&'a [u8]);
-
-impl<'a> ByteFormatter<'a> { 
-    pub fn new(bytes: &'a[u8]) -> Self {
-        Self(bytes)
-    }
-}
-
-impl<'a> core::fmt::Debug for ByteFormatter<'a> {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        writeln!(f)?;
-        for (idx, byte) in self.0.iter().enumerate() {
-            write!(f, "{:02X}", byte)?;
-            if (idx & 0x1f) == 0x1f { writeln!(f)?; continue; }
-            if (idx & 3) == 3 { f.write_str(" ")?; }
-        }
-        writeln!(f)
-    }
-}
\ No newline at end of file
+/// Page number that's never valid for tree data. For `crab-db`, this is where
+/// the root pages live, so a tree that points here is always corrupted.
+pub const NULL_PAGE: u64 = 0;
@@ -11,22 +11,57 @@ pub use var_u64::*;
 
 use core::{cmp::Ordering, marker::PhantomData, slice};
 
-const CONTENT_SIZE: usize = PAGE_4K - core::mem::size_of::<TwoArrayTrailer>();
+/// Bytes of a page available for pairs, after the trailer.
+pub const CONTENT_SIZE: usize = PAGE_4K - core::mem::size_of::<TwoArrayTrailer>();
 
 /// The maximum allowed variable-length size, assuming either [`LayoutU64Var`]
-/// or [`LayoutVarU64`].
+/// or [`LayoutVarU64`]. Prefer [`PageLayout::MAX_KEY_LEN`] and
+/// [`PageLayout::MAX_VALUE_LEN`], which give the limit for any layout.
 pub const MAX_VAR_SIZE: usize = 1008;
 
+/// The most pairs that can fit in a single page with layout `T`, when every
+/// pair is as small as possible.
+pub const fn max_pairs<T: PageLayout>() -> usize {
+    CONTENT_SIZE / T::MIN_PAIR_SIZE
+}
+
+/// The fewest pairs a page with layout `T` can be guaranteed to hold, when
+/// every pair is as large as possible.
+pub const fn min_pairs<T: PageLayout>() -> usize {
+    CONTENT_SIZE / T::MAX_PAIR_SIZE
+}
+
 use crate::{
     arrays::{KeyValArrayMut, KeyValArrayMutResize, RevSizedArray, RevSizedArrayMutResize},
-    ByteFormatter, Error, TwoArrayTrailer, PAGE_4K,
+    fmt::HexDump,
+    Error, TwoArrayTrailer, PAGE_4K,
 };
 
+/// [`PageLayout::closer_to_first`] for `u64` keys.
+pub(crate) fn u64_closer_to_first(key: u64, first: u64, last: u64) -> bool {
+    key.wrapping_sub(first) < last.wrapping_sub(key)
+}
+
+/// [`PageLayout::closer_to_first`] for byte string keys. Anything between
+/// `first` and `last` shares whatever prefix they have in common, so this
+/// compares the 8 bytes after it as big-endian numbers.
+pub(crate) fn bytes_closer_to_first(key: &[u8], first: &[u8], last: &[u8]) -> bool {
+    let prefix = first.iter().zip(last).take_while(|(a, b)| a == b).count();
+    let word = |k: &[u8]| {
+        let rest = k.get(prefix..).unwrap_or_default();
+        let rest = &rest[..rest.len().min(8)];
+        let mut buf = [0; 8];
+        buf[..rest.len()].copy_from_slice(rest);
+        u64::from_be_bytes(buf)
+    };
+    u64_closer_to_first(word(key), word(first), word(last))
+}
+
 #[derive(Clone, Debug)]
 struct Cutpoint {
-    /// Number of bytes to take off the end of the lower byte region
+    /// Number of bytes to take off the cut end of the lower byte region
     lower_len: usize,
-    /// Number of bytes to take off the end of the upper byte region
+    /// Number of bytes to take off the cut end of the upper byte region
     upper_bytes: usize,
 }
 
@@ -40,8 +75,63 @@ pub enum Balance<'a, 'b, T: PageLayout> {
 
 /// Get a page's type byte.
 pub fn page_type(page: &[u8; PAGE_4K]) -> u8 {
-    let trailer = unsafe { &*(page.as_ptr().byte_add(CONTENT_SIZE) as *const TwoArrayTrailer) };
-    trailer.page_type
+    page[crate::format::TRAILER_PAGE_TYPE]
+}
+
+/// Page type given to a page that a panic interrupted partway through
+/// modifying. `from_page` refuses these with [`Error::Poisoned`], so the
+/// half-moved bytes are never misread as entries. This type is reserved and
+/// shouldn't be used for anything else.
+pub const POISONED_PAGE_TYPE: u8 = 0xFF;
+
+/// Layout id of pages written before layout ids existed, which used to be an
+/// unused byte in the trailer. These pages are accepted as any layout, and get
+/// stamped with the real id the first time they're opened for writing.
+pub const LEGACY_LAYOUT_ID: u8 = 0;
+
+/// Check that a page was written with layout `T`, or predates layout ids.
+fn check_layout<T: PageLayout>(trailer: &TwoArrayTrailer) -> Result<(), Error> {
+    let found = trailer.layout_id;
+    if found != LEGACY_LAYOUT_ID && found != T::LAYOUT_ID {
+        return Err(Error::LayoutMismatch {
+            expected: T::LAYOUT_ID,
+            found,
+        });
+    }
+    Ok(())
+}
+
+/// Poisons a page if dropped before being disarmed. Held across any update
+/// that shifts bytes around in place, where the page is inconsistent until
+/// the trailer is rewritten at the end, so that unwinding out of the middle of
+/// it leaves the page cleanly rejected instead of corrupt.
+///
+/// Nothing between creating the guard and disarming it should return early.
+struct PoisonGuard {
+    trailer: *mut TwoArrayTrailer,
+}
+
+impl PoisonGuard {
+    /// # Safety
+    ///
+    /// `page` must point to a full page, and stay valid for as long as the
+    /// guard does.
+    unsafe fn new(page: *mut u8) -> Self {
+        Self {
+            trailer: unsafe { page.byte_add(CONTENT_SIZE) as *mut TwoArrayTrailer },
+        }
+    }
+
+    /// The update completed, so leave the page alone.
+    fn disarm(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for PoisonGuard {
+    fn drop(&mut self) {
+        unsafe { (*self.trailer).page_type = POISONED_PAGE_TYPE };
+    }
 }
 
 #[repr(transparent)]
@@ -50,20 +140,26 @@ pub struct PageMapMut<'a, T: PageLayout> {
     page: *mut u8,
 }
 
+// Safety: this is an exclusive borrow of the page, like a `&'a mut [u8; 4096]`. `T` only describes
+// how the bytes are laid out.
+unsafe impl<T: PageLayout + Send> Send for PageMapMut<'_, T> {}
+unsafe impl<T: PageLayout + Sync> Sync for PageMapMut<'_, T> {}
+
 impl<'a, T: PageLayout> core::fmt::Debug for PageMapMut<'a, T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        let (lower, upper) = unsafe {
+        let (lower, upper, upper_start) = unsafe {
             let lengths = self.page_trailer().lengths_unchecked();
             let upper_bytes = lengths.upper_bytes::<T>();
+            let upper_start = CONTENT_SIZE - upper_bytes;
             let lower = slice::from_raw_parts(self.page, lengths.lower_bytes::<u8>());
-            let upper =
-                slice::from_raw_parts(self.page.add(CONTENT_SIZE - upper_bytes), upper_bytes);
-            (lower, upper)
+            let upper = slice::from_raw_parts(self.page.add(upper_start), upper_bytes);
+            (lower, upper, upper_start)
         };
+        let upper = HexDump::new(upper).with_base_offset(upper_start as u64);
         f.debug_struct(core::any::type_name::<Self>())
             .field("trailer", self.page_trailer())
-            .field("lower_bytes", &ByteFormatter::new(lower))
-            .field("upper_bytes", &ByteFormatter::new(upper))
+            .field("lower_bytes", &HexDump::new(lower))
+            .field("upper_bytes", &upper)
             .finish()
     }
 }
@@ -77,6 +173,7 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
         };
         let trailer = ret.page_trailer_mut();
         trailer.page_type = page_type;
+        trailer.layout_id = T::LAYOUT_ID;
         trailer.set_lower_len(0);
         trailer.set_upper_len(0);
         ret
@@ -86,14 +183,20 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
         unsafe { &mut *(self.page as *mut [u8; 4096]) }
     }
 
-    /// Convert a page into a `PageMapMut`.
+    /// Convert a page into a `PageMapMut`. A legacy page is stamped with this
+    /// layout's id.
     pub fn from_page(page: &'a mut [u8; 4096]) -> Result<Self, Error> {
-        let ret = Self {
+        let mut ret = Self {
             page: page.as_mut_ptr(),
             layout: PhantomData,
         };
         let trailer = ret.page_trailer();
+        if trailer.page_type == POISONED_PAGE_TYPE {
+            return Err(Error::Poisoned);
+        }
+        check_layout::<T>(trailer)?;
         trailer.lengths::<u8, T>(CONTENT_SIZE)?;
+        ret.page_trailer_mut().layout_id = T::LAYOUT_ID;
         Ok(ret)
     }
 
@@ -103,7 +206,16 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
         unsafe { &*(self as *const PageMapMut<T> as *const PageMap<T>) }
     }
 
-    fn find_cutpoint(&self, target: usize, max: usize) -> Result<Cutpoint, Error> {
+    /// Find where to cut this page so that about `target` bytes move off the top of it, or off the
+    /// bottom if `top` isn't set. If `keep_both` is set, at least one pair will move and at least
+    /// one will stay, no matter the target.
+    fn find_cutpoint(
+        &self,
+        target: usize,
+        max: usize,
+        keep_both: bool,
+        top: bool,
+    ) -> Result<Cutpoint, Error> {
         unsafe {
             let lengths = self.as_const().page_trailer().lengths_unchecked();
             let info = slice::from_raw_parts(
@@ -115,8 +227,9 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
             let mut move_amount = 0;
             let mut taken_lower = 0;
 
-            // Iterate until we're at the approximate split point.
-            while let Some(pair_info) = info.next_back() {
+            // Iterate until we're at the approximate split point. The info array's back end is
+            // the top of the page.
+            while let Some(pair_info) = if top { info.next_back() } else { info.next() } {
                 let pair_info = pair_info?;
 
                 // Check if we're on the final pair or if we have more to go.
@@ -127,9 +240,14 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                         lengths.upper_bytes::<T>() - info.remaining_bytes();
                     // Determine if we actually take this final key-value pair or
                     // not. Choose whatever gets us closer to an even split.
-                    if ((add_len + move_amount - target) > (target - move_amount))
-                        || ((move_amount + add_len) > max)
-                    {
+                    let first = move_amount == 0;
+                    let last = info.remaining_bytes() == 0;
+                    let skip = if keep_both && (first || last) {
+                        last
+                    } else {
+                        (add_len + move_amount - target) > (target - move_amount)
+                    };
+                    if skip || ((move_amount + add_len) > max) {
                         // We don't want to take it.
                         new_upper_len_bytes -= core::mem::size_of::<T>();
                     } else {
@@ -155,6 +273,19 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
     ///
     /// This moves the upper half into a new page and returns that page.
     pub fn split_to<'b>(&mut self, page: &'b mut [u8; 4096]) -> Result<PageMapMut<'b, T>, Error> {
+        self.split_to_with_fill(page, 0.5)
+    }
+
+    /// Split this page, leaving it approximately `fill` full (from 0.0 to 1.0) and moving the rest
+    /// into a new page, which is returned.
+    ///
+    /// Both pages always end up with at least one entry, so a `fill` of 1.0 moves just the
+    /// highest entry.
+    pub fn split_to_with_fill<'b>(
+        &mut self,
+        page: &'b mut [u8; 4096],
+        fill: f32,
+    ) -> Result<PageMapMut<'b, T>, Error> {
         let trailer = self.page_trailer();
         let page_type = trailer.page_type;
 
@@ -165,12 +296,16 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
 
             // Find the point at which we'll split the page
             let total_len = lengths.total::<u8, T>();
-            let cutpoint = self.find_cutpoint(total_len / 2, total_len)?;
+            let target = (total_len as f32 * (1.0 - fill.clamp(0.0, 1.0))) as usize;
+            let target = target.min(total_len.saturating_sub(1));
+            let cutpoint = self.find_cutpoint(target, total_len, true, true)?;
 
             // Copy the data over
             let split_lower_len = lengths.lower_bytes::<u8>() - cutpoint.lower_len;
             let upper_len_bytes = lengths.upper_bytes::<T>();
-            let split_upper_len_bytes = upper_len_bytes - cutpoint.upper_bytes;
+            let cut_upper_len = cutpoint.upper_bytes / core::mem::size_of::<T>();
+            let split_lengths =
+                lengths.offset(-(cutpoint.lower_len as isize), -(cut_upper_len as isize))?;
             core::ptr::copy_nonoverlapping(
                 self.page.add(split_lower_len),
                 new_page.page,
@@ -182,13 +317,12 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                 cutpoint.upper_bytes,
             );
 
-            // Update both trailers
-            let trailer = self.page_trailer_mut();
-            trailer.set_lower_len(split_lower_len as u16);
-            trailer.set_upper_len((split_upper_len_bytes / core::mem::size_of::<T>()) as u16);
+            // Update both trailers. Nothing in this page has been touched up
+            // to this point, so it's only truncated in one step, at the very end.
             let new_trailer = new_page.page_trailer_mut();
-            new_trailer.set_lower_len(cutpoint.lower_len as u16);
-            new_trailer.set_upper_len((cutpoint.upper_bytes / core::mem::size_of::<T>()) as u16);
+            new_trailer.set_lengths(cutpoint.lower_len as u16, cut_upper_len as u16);
+            let trailer = self.page_trailer_mut();
+            trailer.set_lengths(split_lengths.lower as u16, split_lengths.upper as u16);
 
             debug_assert!(
                 self.as_const().verify().is_ok(),
@@ -237,6 +371,8 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                 // Copy the data
                 let self_len = self.page_trailer().lengths_unchecked();
                 let higher_len = higher.page_trailer().lengths_unchecked();
+                let merged_len =
+                    self_len.offset(higher_len.lower as isize, higher_len.upper as isize)?;
                 core::ptr::copy_nonoverlapping(
                     higher.page,
                     self.page.add(self_len.lower_bytes::<u8>()),
@@ -250,10 +386,10 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                     upper_copy_len,
                 );
 
-                // Update the lengths
+                // Update the lengths. The data went into this page's free
+                // space, so it only becomes visible here, all at once.
                 let trailer = self.page_trailer_mut();
-                trailer.add_to_lower_len(higher_len.lower as isize);
-                trailer.add_to_upper_len(higher_len.upper as isize);
+                trailer.set_lengths(merged_len.lower as u16, merged_len.upper as u16);
                 debug_assert!(trailer.lengths::<u8,T>(CONTENT_SIZE).is_ok());
 
                 // Verify after changing
@@ -263,11 +399,26 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
             } else if self_len > higher_len {
                 // Move from self to the higher page
 
-                let cutpoint =
-                    self.find_cutpoint((self_len - higher_len) / 2, higher.free_space())?;
+                let cutpoint = self.find_cutpoint(
+                    (self_len - higher_len) / 2,
+                    higher.free_space(),
+                    false,
+                    true,
+                )?;
                 let self_len = self.page_trailer().lengths_unchecked();
                 let higher_len = higher.page_trailer().lengths_unchecked();
 
+                // Calculate the changes to the lengths
+                let lower_delta = cutpoint.lower_len as isize;
+                let upper_delta = (cutpoint.upper_bytes / core::mem::size_of::<T>()) as isize;
+                let self_new = self_len.offset(-lower_delta, -upper_delta)?;
+                let higher_new = higher_len.offset(lower_delta, upper_delta)?;
+
+                // The higher page gets shifted around in place, and isn't
+                // consistent again until both trailers are updated.
+                let self_guard = PoisonGuard::new(self.page);
+                let higher_guard = PoisonGuard::new(higher.page);
+
                 // Make room and then copy the lower data
                 core::ptr::copy(
                     higher.page,
@@ -297,25 +448,21 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                     cutpoint.upper_bytes,
                 );
 
-                // Calculate the changes to the lengths
-                let lower_delta = cutpoint.lower_len as isize;
-                let upper_delta = (cutpoint.upper_bytes / core::mem::size_of::<T>()) as isize;
-
                 // Update the lower page's lengths
                 let trailer = self.page_trailer_mut();
-                trailer.add_to_lower_len(-lower_delta);
-                trailer.add_to_upper_len(-upper_delta);
+                trailer.set_lengths(self_new.lower as u16, self_new.upper as u16);
                 debug_assert!(trailer.lengths::<u8,T>(CONTENT_SIZE).is_ok());
 
                 // Update the higher page's lengths
                 let trailer = higher.page_trailer_mut();
-                trailer.add_to_lower_len(lower_delta);
-                trailer.add_to_upper_len(upper_delta);
+                trailer.set_lengths(higher_new.lower as u16, higher_new.upper as u16);
                 debug_assert!(trailer.lengths::<u8,T>(CONTENT_SIZE).is_ok());
 
                 // Verify after changing
                 debug_assert!(self.as_const().verify().is_ok(), "balanced lower page should still be valid");
                 debug_assert!(higher.as_const().verify().is_ok(), "balanced higher page should still be valid");
+                self_guard.disarm();
+                higher_guard.disarm();
 
                 Ok(Balance::Balanced {
                     lower: self,
@@ -324,11 +471,26 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
             } else {
                 // Move from the higher page to self
 
-                let cutpoint =
-                    higher.find_cutpoint((higher_len - self_len) / 2, self.free_space())?;
+                let cutpoint = higher.find_cutpoint(
+                    (higher_len - self_len) / 2,
+                    self.free_space(),
+                    false,
+                    false,
+                )?;
                 let self_len = self.page_trailer().lengths_unchecked();
                 let higher_len = higher.page_trailer().lengths_unchecked();
 
+                // Calculate the changes to the lengths
+                let lower_delta = cutpoint.lower_len as isize;
+                let upper_delta = (cutpoint.upper_bytes / core::mem::size_of::<T>()) as isize;
+                let self_new = self_len.offset(lower_delta, upper_delta)?;
+                let higher_new = higher_len.offset(-lower_delta, -upper_delta)?;
+
+                // The higher page gets shifted around in place, and isn't
+                // consistent again until both trailers are updated.
+                let self_guard = PoisonGuard::new(self.page);
+                let higher_guard = PoisonGuard::new(higher.page);
+
                 // Copy the lower data, then delete it from the higher page
                 core::ptr::copy_nonoverlapping(
                     higher.page,
@@ -358,25 +520,21 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                     higher_upper_bytes - cutpoint.upper_bytes,
                 );
 
-                // Calculate the changes to the lengths
-                let lower_delta = cutpoint.lower_len as isize;
-                let upper_delta = (cutpoint.upper_bytes / core::mem::size_of::<T>()) as isize;
-
                 // Update this page's lengths
                 let trailer = self.page_trailer_mut();
-                trailer.add_to_lower_len(lower_delta);
-                trailer.add_to_upper_len(upper_delta);
+                trailer.set_lengths(self_new.lower as u16, self_new.upper as u16);
                 debug_assert!(trailer.lengths::<u8,T>(CONTENT_SIZE).is_ok());
 
                 // Update the higher page's lengths
                 let trailer = higher.page_trailer_mut();
-                trailer.add_to_lower_len(-lower_delta);
-                trailer.add_to_upper_len(-upper_delta);
+                trailer.set_lengths(higher_new.lower as u16, higher_new.upper as u16);
                 debug_assert!(trailer.lengths::<u8,T>(CONTENT_SIZE).is_ok());
 
                 // Verify after changing
                 debug_assert!(self.as_const().verify().is_ok(), "balanced lower page should still be valid");
                 debug_assert!(higher.as_const().verify().is_ok(), "balanced higher page should still be valid");
+                self_guard.disarm();
+                higher_guard.disarm();
 
                 Ok(Balance::Balanced {
                     lower: self,
@@ -386,6 +544,60 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
         }
     }
 
+    /// Keep only the entries for which `f` returns true, compacting the page
+    /// in place.
+    ///
+    /// The page is checked over before anything is moved, so any error is
+    /// returned with the page untouched. If `f` panics, the page may be
+    /// partway through compaction, so it's poisoned and will be refused by
+    /// `from_page` from then on.
+    pub fn retain<F>(&mut self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&T::Key, &T::Value) -> bool,
+    {
+        for res in self.as_const().iter() {
+            res?;
+        }
+
+        unsafe {
+            let lengths = self.page_trailer().lengths_unchecked();
+            let size = core::mem::size_of::<T>();
+            let guard = PoisonGuard::new(self.page);
+
+            // Walk the pairs in order, sliding each one we keep down over the
+            // gaps left by the ones we've dropped.
+            let mut read = 0;
+            let mut write = 0;
+            let mut kept = 0;
+            for i in 0..lengths.upper {
+                let info = *(self.page.add(CONTENT_SIZE - (i + 1) * size) as *const T);
+                let key_len = info.key_len();
+                let pair_len = key_len + info.value_len();
+                let key = info.read_key(slice::from_raw_parts(self.page.add(read), key_len));
+                let value = info.read_value(slice::from_raw_parts(
+                    self.page.add(read + key_len),
+                    info.value_len(),
+                ));
+                if f(key, value) {
+                    if write != read {
+                        core::ptr::copy(self.page.add(read), self.page.add(write), pair_len);
+                    }
+                    if kept != i {
+                        (self.page.add(CONTENT_SIZE - (kept + 1) * size) as *mut T).write(info);
+                    }
+                    write += pair_len;
+                    kept += 1;
+                }
+                read += pair_len;
+            }
+
+            self.page_trailer_mut()
+                .set_lengths(write as u16, kept as u16);
+            guard.disarm();
+        }
+        Ok(())
+    }
+
     /// Borrow the trailer.
     pub fn page_trailer(&self) -> &TwoArrayTrailer {
         unsafe {
@@ -420,6 +632,21 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
 
     /// Iterate over the data within the map, with mutable access to the values.
     pub fn iter_mut(&mut self) -> PageIterMut<'_, T> {
+        unsafe { self.iter_unbound() }
+    }
+
+    /// Turn into an iterator with mutable access to the values, for as long as the page itself
+    /// is borrowed.
+    pub fn into_iter_mut(self) -> PageIterMut<'a, T> {
+        unsafe { self.iter_unbound() }
+    }
+
+    /// Iterate with mutable access to the values, for any lifetime.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may touch the page while the iterator is in use.
+    unsafe fn iter_unbound<'b>(&self) -> PageIterMut<'b, T> {
         unsafe {
             let lengths = self.page_trailer().lengths_unchecked();
             let data = KeyValArrayMut::new(slice::from_raw_parts_mut(self.page, lengths.lower));
@@ -432,17 +659,58 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
     }
 
     /// Get an entry in the page.
+    ///
+    /// The page is searched from whichever end the key looks closer to: keys
+    /// past either end are found right away, and anything in between is left
+    /// up to [`PageLayout::closer_to_first`].
     pub fn entry<'k>(self, key: &'k T::Key) -> Result<Entry<'a, 'k, T>, Error> {
+        let from_front = self.search_from_front(key)?;
+        self.entry_from(key, from_front)
+    }
+
+    /// Get an entry in the page, always searching from the back. Only here to benchmark
+    /// [`entry`][Self::entry] against.
+    #[cfg(feature = "testing")]
+    #[doc(hidden)]
+    pub fn entry_from_back<'k>(self, key: &'k T::Key) -> Result<Entry<'a, 'k, T>, Error> {
+        self.entry_from(key, false)
+    }
+
+    /// Pick which end of the page to search for `key` from.
+    fn search_from_front(&self, key: &T::Key) -> Result<bool, Error> {
+        // The iterator trusts the lengths, so check them first
+        self.page_trailer().lengths::<u8, T>(CONTENT_SIZE)?;
+        let mut iter = self.as_const().iter();
+        let Some(first) = iter.next() else {
+            return Ok(false);
+        };
+        let (first, _) = first?;
+        if key <= first {
+            return Ok(true);
+        }
+        let Some(last) = iter.next_back() else {
+            return Ok(false);
+        };
+        let (last, _) = last?;
+        if key >= last {
+            return Ok(false);
+        }
+        Ok(T::closer_to_first(key, first, last))
+    }
+
+    /// Get an entry in the page, searching from the front or back. Either way
+    /// ends up with the exact same entry.
+    fn entry_from<'k>(self, key: &'k T::Key, from_front: bool) -> Result<Entry<'a, 'k, T>, Error> {
         unsafe {
             // Extract the trailer and info inside it
             let trailer = &mut *(self
                 .page
                 .byte_add(PAGE_4K - core::mem::size_of::<TwoArrayTrailer>())
                 as *mut TwoArrayTrailer);
-            let lengths = trailer.lengths_unchecked();
+            let lengths = trailer.lengths::<u8, T>(CONTENT_SIZE)?;
 
             // Construct the two array iterators
-            let mut kv = crate::arrays::KeyValArrayMutResize::new(slice::from_raw_parts_mut(
+            let kv = crate::arrays::KeyValArrayMutResize::new(slice::from_raw_parts_mut(
                 self.page,
                 lengths.lower,
             ));
@@ -450,44 +718,110 @@ impl<'a, T: PageLayout> PageMapMut<'a, T> {
                 self.page.add(CONTENT_SIZE - lengths.upper_bytes::<T>()) as *mut T,
                 lengths.upper,
             );
-            let mut info = crate::arrays::RevSizedArrayMutResize::new(info);
-
-            while let Some(i) = info.next_back() {
-                let i = i?;
-                kv.next_pair_back(i.key_len(), i.value_len())?;
-                match i.read_key(kv.key()).cmp(key) {
-                    Ordering::Equal => {
-                        return Ok(Entry::Occupied(OccupiedEntry {
-                            page: self.page,
-                            first: info.remaining_bytes() == 0,
-                            trailer,
-                            kv,
-                            info,
-                        }))
-                    }
-                    Ordering::Less => {
-                        return Ok(Entry::Vacant(VacantEntry {
-                            page: self.page,
-                            first: false,
-                            trailer,
-                            kv,
-                            info,
-                            key,
-                        }))
-                    }
-                    Ordering::Greater => (),
+            let info = crate::arrays::RevSizedArrayMutResize::new(info);
+
+            if from_front {
+                self.search_front(trailer, kv, info, key)
+            } else {
+                self.search_back(trailer, kv, info, key)
+            }
+        }
+    }
+
+    /// Find the entry for `key` by walking back from the highest key.
+    fn search_back<'k>(
+        self,
+        trailer: &'a mut TwoArrayTrailer,
+        mut kv: KeyValArrayMutResize<'a>,
+        mut info: RevSizedArrayMutResize<'a, T>,
+        key: &'k T::Key,
+    ) -> Result<Entry<'a, 'k, T>, Error> {
+        while let Some(i) = info.next_back() {
+            let i = i?;
+            kv.next_pair_back(i.key_len(), i.value_len())?;
+            match unsafe { i.read_key(kv.key()) }.cmp(key) {
+                Ordering::Equal => {
+                    return Ok(Entry::Occupied(OccupiedEntry {
+                        page: self.page,
+                        first: info.remaining_bytes() == 0,
+                        trailer,
+                        kv,
+                        info,
+                    }))
                 }
+                Ordering::Less => {
+                    return Ok(Entry::Vacant(VacantEntry {
+                        page: self.page,
+                        first: false,
+                        trailer,
+                        kv,
+                        info,
+                        key,
+                    }))
+                }
+                Ordering::Greater => (),
             }
-            kv.next_pair_back_none()?;
-            Ok(Entry::Vacant(VacantEntry {
-                page: self.page,
-                first: true,
-                info,
-                trailer,
-                kv,
-                key,
-            }))
         }
+        kv.next_pair_back_none()?;
+        Ok(Entry::Vacant(VacantEntry {
+            page: self.page,
+            first: true,
+            info,
+            trailer,
+            kv,
+            key,
+        }))
+    }
+
+    /// Find the entry for `key` by walking forward from the lowest key, then
+    /// turning the arrays around so the entry is left exactly as
+    /// [`search_back`][Self::search_back] would have left it.
+    fn search_front<'k>(
+        self,
+        trailer: &'a mut TwoArrayTrailer,
+        mut kv: KeyValArrayMutResize<'a>,
+        mut info: RevSizedArrayMutResize<'a, T>,
+        key: &'k T::Key,
+    ) -> Result<Entry<'a, 'k, T>, Error> {
+        let mut first = true;
+        loop {
+            // A vacancy goes after the pair before the first greater key, so
+            // hang on to where the arrays were before reading each pair.
+            let (prev_kv, prev_info) = (kv.clone(), info.clone());
+            let Some(i) = info.next_front() else {
+                break;
+            };
+            let i = i?;
+            let raw_key = kv.next_pair(i.key_len(), i.value_len())?;
+            match unsafe { i.read_key(raw_key) }.cmp(key) {
+                Ordering::Equal => {
+                    kv.front_to_back();
+                    info.front_to_back();
+                    return Ok(Entry::Occupied(OccupiedEntry {
+                        page: self.page,
+                        first: info.remaining_bytes() == 0,
+                        trailer,
+                        kv,
+                        info,
+                    }));
+                }
+                Ordering::Greater => {
+                    (kv, info) = (prev_kv, prev_info);
+                    break;
+                }
+                Ordering::Less => first = false,
+            }
+        }
+        kv.front_to_back();
+        info.front_to_back();
+        Ok(Entry::Vacant(VacantEntry {
+            page: self.page,
+            first,
+            trailer,
+            kv,
+            info,
+            key,
+        }))
     }
 }
 
@@ -565,6 +899,11 @@ impl<'a, T: PageLayout> DoubleEndedIterator for PageIterMut<'a, T> {
     }
 }
 
+/// An entry in a page, found by [`PageMapMut::entry`].
+///
+/// Entries are neither `Send` nor `Sync`: they hold the page in the middle of an update, and are
+/// meant to be resolved right where they were looked up. Go back to a [`PageMapMut`] to hand the
+/// page to another thread.
 pub enum Entry<'a, 'k, T: PageLayout> {
     Occupied(OccupiedEntry<'a, T>),
     Vacant(VacantEntry<'a, 'k, T>),
@@ -610,19 +949,24 @@ impl<'a, T: PageLayout> OccupiedEntry<'a, T> {
     }
 
     /// Delete the entire entry.
-    pub fn delete(mut self) -> PageMapMut<'a, T> {
+    pub fn delete(mut self) -> Result<PageMapMut<'a, T>, Error> {
         // Delete the values from both arrays, then update the trailer lengths.
         unsafe {
+            let pair_len = self.kv.key().len() + self.kv.val().len();
+            let lengths = self.trailer.lengths_unchecked();
+            let lengths = lengths.offset(-(pair_len as isize), -1)?;
+            let guard = PoisonGuard::new(self.page);
             self.info.back_delete();
-            let delta = self.kv.delete();
-            self.trailer.add_to_lower_len(-delta);
-            self.trailer.add_to_upper_len(-1);
+            self.kv.delete();
+            self.trailer
+                .set_lengths(lengths.lower as u16, lengths.upper as u16);
+            guard.disarm();
         }
 
-        PageMapMut {
+        Ok(PageMapMut {
             layout: PhantomData,
             page: self.page,
-        }
+        })
     }
 
     /// Replace the value with a new value.
@@ -631,17 +975,22 @@ impl<'a, T: PageLayout> OccupiedEntry<'a, T> {
         let delta = (new_len as isize) - (self.kv.val().len() as isize);
         unsafe {
             // Check for the right size before resizing
-            let free = CONTENT_SIZE - self.trailer.lengths_unchecked().total::<u8, T>();
+            let lengths = self.trailer.lengths::<u8, T>(CONTENT_SIZE)?;
+            let free = CONTENT_SIZE - lengths.total::<u8, T>();
             if (free as isize) < delta {
                 return Err(Error::OutofSpace(delta as usize));
             }
+            // The value gets written in place after the bytes past it have
+            // been shifted, so the page isn't consistent until it's done.
+            self.trailer.add_to_lower_len(delta)?;
+            let guard = PoisonGuard::new(self.page);
             self.kv.resize(delta);
-            self.trailer.add_to_lower_len(delta);
 
             // Update the value
             self.info
                 .get_mut()
                 .write_value(new_value, self.kv.val_mut());
+            guard.disarm();
             Ok(())
         }
     }
@@ -664,17 +1013,22 @@ where
         let delta = (new_len as isize) - (self.kv.val().len() as isize);
         unsafe {
             // Check for the right size before resizing
-            let free = CONTENT_SIZE - self.trailer.lengths_unchecked().total::<u8, T>();
+            let lengths = self.trailer.lengths::<u8, T>(CONTENT_SIZE)?;
+            let free = CONTENT_SIZE - lengths.total::<u8, T>();
             if (free as isize) < delta {
                 return Err(Error::OutofSpace(delta as usize));
             }
+            // The value gets written in place after the bytes past it have
+            // been shifted, so the page isn't consistent until it's done.
+            self.trailer.add_to_lower_len(delta)?;
+            let guard = PoisonGuard::new(self.page);
             self.kv.resize(delta);
-            self.trailer.add_to_lower_len(delta);
 
             // Update the value
             self.info
                 .get_mut()
                 .write_value_vectored(new_value, self.kv.val_mut());
+            guard.disarm();
             Ok(())
         }
     }
@@ -698,10 +1052,11 @@ impl<'a, 'k, T: PageLayout> VacantEntry<'a, 'k, T> {
 
     /// Get the key for this vacant entry.
     pub fn key(&self) -> &T::Key {
-        unsafe { self.info.get().read_key(self.kv.key()) }
+        self.key
     }
 
     /// Insert a value into this entry, transforming into an occupied entry.
+    #[allow(clippy::result_large_err)]
     pub fn insert(mut self, value: &T::Value) -> Result<OccupiedEntry<'a, T>, (Self, Error)> {
         // Length calculations and checking
         let key_len = match T::determine_key_len(self.key) {
@@ -712,24 +1067,36 @@ impl<'a, 'k, T: PageLayout> VacantEntry<'a, 'k, T> {
             Ok(len) => len,
             Err(e) => return Err((self, e)),
         };
-        let total_len = unsafe { self.trailer.lengths_unchecked().total::<u8, T>() };
-        let free = CONTENT_SIZE - total_len;
+        let lengths = match self.trailer.lengths::<u8, T>(CONTENT_SIZE) {
+            Ok(lengths) => lengths,
+            Err(e) => return Err((self, e)),
+        };
+        let free = CONTENT_SIZE - lengths.total::<u8, T>();
         let needed = key_len + val_len + core::mem::size_of::<T>();
         if needed > free {
             return Err((self, Error::OutofSpace(needed)));
         }
+        let new_lengths = match lengths.offset((key_len + val_len) as isize, 1) {
+            Ok(lengths) => lengths,
+            Err(e) => return Err((self, e)),
+        };
 
         unsafe {
+            // Both arrays get shifted to make room, and the page can't be
+            // read correctly until the new pair is fully written out.
+            let guard = PoisonGuard::new(self.page);
+
             // Create the key-value allocation and initialize the info.
             self.kv.back_insert(key_len, val_len);
-            self.trailer.add_to_lower_len((key_len + val_len) as isize);
-            self.trailer.add_to_upper_len(1);
             self.info.back_insert(T::default());
+            self.trailer
+                .set_lengths(new_lengths.lower as u16, new_lengths.upper as u16);
 
             // Write out our key and value.
             let info = self.info.get_mut();
             info.write_key(self.key, self.kv.key_mut());
             info.write_value(value, self.kv.val_mut());
+            guard.disarm();
         }
 
         Ok(OccupiedEntry {
@@ -754,6 +1121,7 @@ impl<'a, 'k, T> VacantEntry<'a, 'k, T>
 where
     T: PageLayoutVectored,
 {
+    #[allow(clippy::result_large_err)]
     pub fn insert_vectored(
         mut self,
         value: &[&T::Value],
@@ -767,24 +1135,36 @@ where
             Ok(len) => len,
             Err(e) => return Err((self, e)),
         };
-        let total_len = unsafe { self.trailer.lengths_unchecked().total::<u8, T>() };
-        let free = CONTENT_SIZE - total_len;
+        let lengths = match self.trailer.lengths::<u8, T>(CONTENT_SIZE) {
+            Ok(lengths) => lengths,
+            Err(e) => return Err((self, e)),
+        };
+        let free = CONTENT_SIZE - lengths.total::<u8, T>();
         let needed = key_len + val_len + core::mem::size_of::<T>();
         if needed < free {
             return Err((self, Error::OutofSpace(needed)));
         }
+        let new_lengths = match lengths.offset((key_len + val_len) as isize, 1) {
+            Ok(lengths) => lengths,
+            Err(e) => return Err((self, e)),
+        };
 
         unsafe {
+            // Both arrays get shifted to make room, and the page can't be
+            // read correctly until the new pair is fully written out.
+            let guard = PoisonGuard::new(self.page);
+
             // Create the key-value allocation and initialize the info.
             self.kv.back_insert(key_len, val_len);
-            self.trailer.add_to_lower_len((key_len + val_len) as isize);
-            self.trailer.add_to_upper_len(1);
             self.info.back_insert(T::default());
+            self.trailer
+                .set_lengths(new_lengths.lower as u16, new_lengths.upper as u16);
 
             // Write out our key and value.
             let info = self.info.get_mut();
             info.write_key(self.key, self.kv.key_mut());
             info.write_value_vectored(value, self.kv.val_mut());
+            guard.disarm();
         }
 
         Ok(OccupiedEntry {
@@ -796,3 +1176,678 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::vec::Vec;
+
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+
+    use super::*;
+    use crate::rng::Rng;
+
+    #[repr(align(4096))]
+    struct Page([u8; PAGE_4K]);
+
+    // Maps can move between threads like the page references they are, but entries stay put
+    assert_impl_all!(PageMap<'static, LayoutU64Var>: Send, Sync);
+    assert_impl_all!(PageMapMut<'static, LayoutU64Var>: Send, Sync);
+    assert_not_impl_any!(Entry<'static, 'static, LayoutU64Var>: Send, Sync);
+    assert_not_impl_any!(OccupiedEntry<'static, LayoutU64Var>: Send, Sync);
+    assert_not_impl_any!(VacantEntry<'static, 'static, LayoutU64Var>: Send, Sync);
+
+    fn filled_page(page: &mut Page, len: u64) -> PageMapMut<'_, LayoutU64Var> {
+        let mut map = PageMapMut::new(&mut page.0, 1);
+        for i in 0..len {
+            let Entry::Vacant(v) = map.entry(&i).unwrap() else {
+                panic!("entries should start out empty");
+            };
+            map = v
+                .insert(&i.to_le_bytes()[..])
+                .map_err(|(_, e)| e)
+                .unwrap()
+                .to_page();
+        }
+        map
+    }
+
+    fn keys(page: &[u8; PAGE_4K]) -> Result<Vec<u64>, Error> {
+        let map = PageMap::<LayoutU64Var>::from_page(page)?;
+        map.iter().map(|res| res.map(|(k, _)| *k)).collect()
+    }
+
+    #[test]
+    fn retain() {
+        let mut page = Page([0; PAGE_4K]);
+        let mut map = filled_page(&mut page, 100);
+        map.retain(|k, v| {
+            assert_eq!(v, k.to_le_bytes());
+            k % 3 != 0
+        })
+        .unwrap();
+        assert!(map.as_const().verify().is_ok());
+        let expected: Vec<u64> = (0..100).filter(|k| k % 3 != 0).collect();
+        assert_eq!(keys(&page.0).unwrap(), expected);
+    }
+
+    #[test]
+    fn retain_panic_poisons() {
+        // A predicate that panics partway through leaves the page rejected,
+        // never half-compacted.
+        for panic_at in [0, 1, 40, 99] {
+            let mut page = Page([0; PAGE_4K]);
+            let mut map = filled_page(&mut page, 100);
+            let res = catch_unwind(AssertUnwindSafe(|| {
+                map.retain(|k, _| {
+                    if *k == panic_at {
+                        panic!("predicate panicked");
+                    }
+                    k % 2 == 0
+                })
+            }));
+            assert!(res.is_err());
+            assert_eq!(keys(&page.0), Err(Error::Poisoned));
+            assert!(matches!(
+                PageMapMut::<LayoutU64Var>::from_page(&mut page.0),
+                Err(Error::Poisoned)
+            ));
+        }
+
+        // Other pages are unaffected: a panic after fully completing leaves
+        // the new contents in place.
+        let mut page = Page([0; PAGE_4K]);
+        let mut map = filled_page(&mut page, 100);
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            map.retain(|k, _| k % 2 == 0).unwrap();
+            panic!("caller panicked afterward");
+        }));
+        assert!(res.is_err());
+        let expected: Vec<u64> = (0..100).filter(|k| k % 2 == 0).collect();
+        assert_eq!(keys(&page.0).unwrap(), expected);
+    }
+
+    #[test]
+    fn trailer_length_overflow() {
+        let mut page = Page([0; PAGE_4K]);
+        let mut map = PageMapMut::<LayoutU64Var>::new(&mut page.0, 1);
+        let trailer = map.page_trailer_mut();
+        trailer.set_lengths(CONTENT_SIZE as u16, 0);
+        for res in [
+            trailer.add_to_lower_len(1),
+            trailer.add_to_upper_len(-1),
+            trailer.add_to_upper_len(isize::MAX),
+        ] {
+            assert!(matches!(res, Err(Error::DataCorruption(_))));
+        }
+        let lengths = unsafe { trailer.lengths_unchecked() };
+        assert_eq!((lengths.lower, lengths.upper), (CONTENT_SIZE, 0));
+        trailer.add_to_lower_len(-8).unwrap();
+        trailer.add_to_upper_len(1).unwrap();
+        let lengths = unsafe { trailer.lengths_unchecked() };
+        assert_eq!((lengths.lower, lengths.upper), (CONTENT_SIZE - 8, 1));
+    }
+
+    #[test]
+    fn corrupt_lengths_on_insert() {
+        // Replay a page whose lengths got corrupted after it was checked: an
+        // insert has to reject it cleanly instead of wrapping the lengths.
+        let mut page = Page([0; PAGE_4K]);
+        let map = filled_page(&mut page, 100);
+        let Entry::Vacant(v) = map.entry(&1000).unwrap() else {
+            panic!("entry should be empty");
+        };
+        let max = CONTENT_SIZE as u16;
+        v.trailer.set_lengths(max, max);
+        let Err((v, e)) = v.insert(&[1, 2, 3][..]) else {
+            panic!("insert into a corrupted page should fail");
+        };
+        assert!(matches!(e, Error::DataCorruption(_)));
+        let lengths = unsafe { v.trailer.lengths_unchecked() };
+        assert_eq!((lengths.lower, lengths.upper), (CONTENT_SIZE, CONTENT_SIZE));
+
+        // And the page is rejected from then on, rather than read as garbage
+        assert!(matches!(
+            v.to_page().entry(&0),
+            Err(Error::DataCorruption(_))
+        ));
+        assert!(matches!(keys(&page.0), Err(Error::DataCorruption(_))));
+    }
+
+    #[test]
+    fn split_and_balance_stay_consistent() {
+        // Both pages are fully valid after each multi-step operation, with
+        // every key accounted for exactly once.
+        let mut page = Page([0; PAGE_4K]);
+        let mut other = Page([0; PAGE_4K]);
+        let mut map = filled_page(&mut page, 150);
+        let mut upper = map.split_to_with_fill(&mut other.0, 0.8).unwrap();
+        assert!(map.as_const().verify().is_ok());
+        assert!(upper.as_const().verify().is_ok());
+        let upper_start = *upper.as_const().iter().next().unwrap().unwrap().0;
+        upper.retain(|k, _| k % 2 == 0).unwrap();
+        let map = match unsafe { map.balance(upper).unwrap() } {
+            Balance::Merged(map) => map,
+            Balance::Balanced { .. } => panic!("pages should have fit into one"),
+        };
+        assert!(map.as_const().verify().is_ok());
+        let expected: Vec<u64> = (0..150)
+            .filter(|k| *k < upper_start || k % 2 == 0)
+            .collect();
+        assert_eq!(keys(&page.0).unwrap(), expected);
+    }
+
+    #[test]
+    fn balance_from_higher_with_varied_sizes() {
+        // Pairs at the bottom of the higher page are much bigger than the ones at its top, so the
+        // cutpoint has to be found from the bottom for what's moved to line up.
+        let mut page = Page([0; PAGE_4K]);
+        let mut other = Page([0; PAGE_4K]);
+        let value = |k: u64| match k {
+            0..100 => vec![k as u8; 200],
+            100..103 => vec![k as u8; 600],
+            _ => k.to_le_bytes().to_vec(),
+        };
+        let mut expected = Vec::new();
+        for (page, keys) in [(&mut page, 0..6), (&mut other, 100..160)] {
+            let mut map = PageMapMut::<LayoutU64Var>::new(&mut page.0, 1);
+            for k in keys {
+                let Entry::Vacant(v) = map.entry(&k).unwrap() else {
+                    panic!("entries should start out empty");
+                };
+                map = v.insert(&value(k)).map_err(|(_, e)| e).unwrap().to_page();
+                expected.push((k, value(k)));
+            }
+        }
+
+        let lower = PageMapMut::<LayoutU64Var>::from_page(&mut page.0).unwrap();
+        let higher = PageMapMut::<LayoutU64Var>::from_page(&mut other.0).unwrap();
+        assert!(lower.data_len() < higher.data_len());
+        let Balance::Balanced { lower, higher } = (unsafe { lower.balance(higher).unwrap() })
+        else {
+            panic!("pages shouldn't fit into one");
+        };
+        assert!(lower.data_len().abs_diff(higher.data_len()) < 1000);
+        let mut found = pairs(&page.0);
+        assert!(found.len() > 6, "nothing moved down into the lower page");
+        found.extend(pairs(&other.0));
+        assert_eq!(found, expected);
+    }
+
+    /// Collect a page's pairs as `(key, value)`, going both directions.
+    fn pairs(page: &[u8; PAGE_4K]) -> Vec<(u64, Vec<u8>)> {
+        let map = PageMap::<LayoutU64Var>::from_page(page).unwrap();
+        map.verify().unwrap();
+        let pairs: Vec<(u64, Vec<u8>)> = map
+            .iter()
+            .map(|res| res.map(|(k, v)| (*k, v.to_vec())).unwrap())
+            .collect();
+        let mut rev: Vec<(u64, Vec<u8>)> = map
+            .iter()
+            .rev()
+            .map(|res| res.map(|(k, v)| (*k, v.to_vec())).unwrap())
+            .collect();
+        rev.reverse();
+        assert_eq!(pairs, rev);
+        pairs
+    }
+
+    #[test]
+    fn empty_values() {
+        let mut page = Page([0; PAGE_4K]);
+        let mut map = PageMapMut::<LayoutU64Var>::new(&mut page.0, 1);
+        let value = |i: u64| if i % 2 == 0 { Vec::new() } else { i.to_le_bytes().to_vec() };
+        for i in 0..10 {
+            let Entry::Vacant(v) = map.entry(&i).unwrap() else {
+                panic!("entries should start out empty");
+            };
+            let o = v.insert(&value(i)).map_err(|(_, e)| e).unwrap();
+            assert_eq!(o.get(), value(i));
+            map = o.to_page();
+        }
+        let mut expected: Vec<(u64, Vec<u8>)> = (0..10).map(|i| (i, value(i))).collect();
+        assert_eq!(map.data_len(), 5 * 16 + 5 * 8 + 10 * 2);
+        assert_eq!(pairs(&page.0), expected);
+
+        // Replacing with and from empty values, then deleting them
+        let mut map = PageMapMut::<LayoutU64Var>::from_page(&mut page.0).unwrap();
+        for (i, new) in [(0, &b"no longer empty"[..]), (1, &[][..]), (9, &[][..])] {
+            let Entry::Occupied(mut o) = map.entry(&i).unwrap() else {
+                panic!("entry {i} should exist");
+            };
+            o.replace(new).unwrap();
+            assert_eq!(o.get(), new);
+            map = o.to_page();
+            expected[i as usize].1 = new.to_vec();
+        }
+        for i in [2, 9, 8] {
+            let Entry::Occupied(o) = map.entry(&i).unwrap() else {
+                panic!("entry {i} should exist");
+            };
+            map = o.delete().unwrap();
+            expected.retain(|(k, _)| *k != i);
+        }
+        assert_eq!(pairs(&page.0), expected);
+    }
+
+    #[test]
+    fn owned_keys() {
+        use core::borrow::Borrow;
+        use std::collections::BTreeMap;
+
+        let mut page = Page([0; PAGE_4K]);
+        let mut map = PageMapMut::<LayoutVarU64>::new(&mut page.0, 1);
+        for (i, key) in [&b"pear"[..], b"apple", b"fig", b"figs"].into_iter().enumerate() {
+            let Entry::Vacant(v) = map.entry(key).unwrap() else {
+                panic!("entries should start out empty");
+            };
+            map = v.insert(&(i as u64)).map_err(|(_, e)| e).unwrap().to_page();
+        }
+
+        // Owned keys sort the same as the page does, and borrow back for lookups
+        let owned: BTreeMap<Box<[u8]>, u64> = map
+            .as_const()
+            .iter()
+            .map(|res| res.unwrap())
+            .map(|(k, v)| (LayoutVarU64::to_owned_key(k), *v))
+            .collect();
+        let page_keys: Vec<&[u8]> = map.as_const().iter().map(|res| res.unwrap().0).collect();
+        assert!(owned.keys().map(|k| &k[..]).eq(page_keys));
+        for (key, value) in owned.iter() {
+            assert_eq!(map.as_const().get(key.borrow()).unwrap(), Some(value));
+        }
+        assert_eq!(owned.get(&b"fig"[..]), Some(&2));
+
+        // And they outlive the page they came from
+        page.0.fill(0);
+        assert_eq!(owned.keys().next().map(|k| &k[..]), Some(&b"apple"[..]));
+
+        // Integer keys are their own owned keys
+        assert_eq!(LayoutU64U64::to_owned_key(&7), 7);
+        assert_eq!(LayoutU64Var::to_owned_key(&u64::MAX), u64::MAX);
+    }
+
+    /// Drain an iterator, taking from the back on the steps where `back` says to, and check that
+    /// both ends report exhaustion afterwards. Returns the keys in page order.
+    fn drain<'a, V>(
+        mut iter: impl DoubleEndedIterator<Item = Result<(&'a u64, V), Error>>,
+        back: impl Fn(usize) -> bool,
+    ) -> Vec<u64> {
+        let mut front = Vec::new();
+        let mut rear = Vec::new();
+        for step in 0.. {
+            let from_back = back(step);
+            let next = if from_back {
+                iter.next_back()
+            } else {
+                iter.next()
+            };
+            let Some(res) = next else {
+                break;
+            };
+            let key = *res.unwrap().0;
+            if from_back {
+                rear.push(key);
+            } else {
+                front.push(key);
+            }
+        }
+        for _ in 0..2 {
+            assert!(iter.next().is_none());
+            assert!(iter.next_back().is_none());
+        }
+        front.extend(rear.into_iter().rev());
+        front
+    }
+
+    #[test]
+    fn drain_in_any_direction() {
+        let value = |i: u64| vec![i as u8; (i % 3) as usize];
+        for len in [0, 1, 2, 7, 100] {
+            let mut page = Page([0; PAGE_4K]);
+            let mut map = PageMapMut::<LayoutU64Var>::new(&mut page.0, 1);
+            for i in 0..len {
+                let Entry::Vacant(v) = map.entry(&i).unwrap() else {
+                    panic!("entries should start out empty");
+                };
+                map = v.insert(&value(i)).map_err(|(_, e)| e).unwrap().to_page();
+            }
+            let expected: Vec<u64> = (0..len).collect();
+            let patterns: [fn(usize) -> bool; 4] = [
+                |_| false,
+                |_| true,
+                |step| step % 2 == 1,
+                |step| step % 3 != 0,
+            ];
+            for back in patterns {
+                let map = PageMap::<LayoutU64Var>::from_page(&page.0).unwrap();
+                assert_eq!(drain(map.iter(), back), expected);
+                let map = PageMapMut::<LayoutU64Var>::from_page(&mut page.0).unwrap();
+                assert_eq!(drain(map.into_iter(), back), expected);
+                let map = PageMap::<LayoutU64Var>::from_page(&page.0).unwrap();
+                let keys = map.iter().keys().map(|res| res.map(|k| (k, ())));
+                assert_eq!(drain(keys, back), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_pick_up_where_pairs_left_off() {
+        let mut page = Page([0; PAGE_4K]);
+        filled_page(&mut page, 50);
+        let map = PageMap::<LayoutU64Var>::from_page(&page.0).unwrap();
+        let mut iter = map.iter();
+        iter.next().unwrap().unwrap();
+        iter.next_back().unwrap().unwrap();
+        let mut keys = iter.keys();
+        assert_eq!(*keys.next_back().unwrap().unwrap(), 48);
+        let rest: Vec<u64> = keys.map(|res| *res.unwrap()).collect();
+        assert_eq!(rest, (1..48).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn empty_keys() {
+        let mut page = Page([0; PAGE_4K]);
+        let map = PageMapMut::<LayoutVarU64>::new(&mut page.0, 1);
+
+        // Empty keys are refused, but keys of every other length are fine
+        let Entry::Vacant(v) = map.entry(&[]).unwrap() else {
+            panic!("the page should be empty");
+        };
+        let Err((v, err)) = v.insert(&5) else {
+            panic!("an empty key shouldn't be insertable");
+        };
+        assert_eq!(err, Error::EmptyKey);
+        let mut map = v.to_page();
+        let keys: Vec<Vec<u8>> = (1..=20).map(|len| vec![len as u8; len]).collect();
+        for key in keys.iter() {
+            let Entry::Vacant(v) = map.entry(key).unwrap() else {
+                panic!("entries should start out empty");
+            };
+            map = v
+                .insert(&(key.len() as u64))
+                .map_err(|(_, e)| e)
+                .unwrap()
+                .to_page();
+        }
+        map.as_const().verify().unwrap();
+        let found: Vec<(Vec<u8>, u64)> = map
+            .as_const()
+            .iter()
+            .map(|res| res.map(|(k, v)| (k.to_vec(), *v)).unwrap())
+            .collect();
+        let expected: Vec<(Vec<u8>, u64)> =
+            keys.iter().map(|k| (k.clone(), k.len() as u64)).collect();
+        assert_eq!(found, expected);
+
+        // A zero-length key that made it onto a page is treated as corruption
+        let info = CONTENT_SIZE - core::mem::size_of::<LayoutVarU64>();
+        page.0[info..CONTENT_SIZE].fill(0);
+        let map = PageMap::<LayoutVarU64>::from_page(&page.0).unwrap();
+        assert!(map.verify().is_err());
+    }
+
+    #[test]
+    fn empty_value_split_and_balance() {
+        // Runs of empty values, interspersed with a few large ones, exercise
+        // the cutpoint arithmetic with lots of zero-length pairs.
+        let value = |i: u64| {
+            if i % 50 == 49 {
+                vec![i as u8; 200]
+            } else {
+                Vec::new()
+            }
+        };
+        for fill in [0.0, 0.3, 0.5, 0.9, 1.0] {
+            let mut page = Page([0; PAGE_4K]);
+            let mut other = Page([0; PAGE_4K]);
+            let mut map = PageMapMut::<LayoutU64Var>::new(&mut page.0, 1);
+            let mut len = 0;
+            loop {
+                let Entry::Vacant(v) = map.entry(&len).unwrap() else {
+                    panic!("entries should start out empty");
+                };
+                match v.insert(&value(len)) {
+                    Ok(o) => map = o.to_page(),
+                    Err((v, Error::OutofSpace(_))) => {
+                        map = v.to_page();
+                        break;
+                    }
+                    Err((_, e)) => panic!("unexpected error {e:?}"),
+                }
+                len += 1;
+            }
+            let expected: Vec<(u64, Vec<u8>)> = (0..len).map(|i| (i, value(i))).collect();
+
+            map.split_to_with_fill(&mut other.0, fill).unwrap();
+            let (lower_pairs, upper_pairs) = (pairs(&page.0), pairs(&other.0));
+            assert!(!lower_pairs.is_empty() && !upper_pairs.is_empty());
+            assert_eq!([lower_pairs, upper_pairs].concat(), expected);
+
+            // Balancing moves them back, either all at once or partway
+            let map = PageMapMut::<LayoutU64Var>::from_page(&mut page.0).unwrap();
+            let upper = PageMapMut::<LayoutU64Var>::from_page(&mut other.0).unwrap();
+            match unsafe { map.balance(upper) } {
+                Ok(Balance::Merged(_)) => assert_eq!(pairs(&page.0), expected),
+                Ok(Balance::Balanced { .. }) => {
+                    let (lower_pairs, upper_pairs) = (pairs(&page.0), pairs(&other.0));
+                    assert_eq!([lower_pairs, upper_pairs].concat(), expected);
+                }
+                Err(Error::UnexpectedNoOp) => (),
+                Err(e) => panic!("unexpected error {e:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn layout_ids() {
+        let mut page = Page([0; PAGE_4K]);
+        filled_page(&mut page, 10);
+        assert_eq!(page.0[PAGE_4K - 2], LayoutU64Var::LAYOUT_ID);
+
+        // The page only loads as the layout it was written with
+        assert_eq!(keys(&page.0).unwrap(), (0..10).collect::<Vec<_>>());
+        let err = PageMap::<LayoutVarU64>::from_page(&page.0).unwrap_err();
+        assert_eq!(
+            err,
+            Error::LayoutMismatch {
+                expected: LayoutVarU64::LAYOUT_ID,
+                found: LayoutU64Var::LAYOUT_ID,
+            }
+        );
+        assert!(err.to_string().contains("layout id 2"));
+        assert!(err.to_string().contains("layout id 3"));
+        assert!(matches!(
+            PageMapMut::<LayoutU64U64>::from_page(&mut page.0),
+            Err(Error::LayoutMismatch {
+                expected: 1,
+                found: 2
+            })
+        ));
+
+        // Copies keep the layout id
+        let mut copy = Page([0; PAGE_4K]);
+        PageMap::<LayoutU64Var>::from_page(&page.0)
+            .unwrap()
+            .copy_to(&mut copy.0);
+        assert_eq!(keys(&copy.0).unwrap(), (0..10).collect::<Vec<_>>());
+        assert!(PageMap::<LayoutU64U64>::from_page(&copy.0).is_err());
+
+        // Legacy pages load as anything, until they're opened for writing
+        // and get stamped with a real id
+        page.0[PAGE_4K - 2] = LEGACY_LAYOUT_ID;
+        assert_eq!(keys(&page.0).unwrap(), (0..10).collect::<Vec<_>>());
+        assert!(PageMap::<LayoutU64U64>::from_page(&page.0).is_ok());
+        PageMapMut::<LayoutU64Var>::from_page(&mut page.0).unwrap();
+        assert_eq!(page.0[PAGE_4K - 2], LayoutU64Var::LAYOUT_ID);
+        assert!(PageMap::<LayoutU64U64>::from_page(&page.0).is_err());
+    }
+
+    /// Fill a fresh page with `pairs` until it runs out of space, returning
+    /// how many fit.
+    fn fill_count<T, K, V>(pairs: impl Iterator<Item = (K, V)>) -> usize
+    where
+        T: PageLayout,
+        K: core::borrow::Borrow<T::Key>,
+        V: core::borrow::Borrow<T::Value>,
+    {
+        let mut page = Page([0; PAGE_4K]);
+        let mut map = PageMapMut::<T>::new(&mut page.0, 1);
+        for (n, (k, v)) in pairs.enumerate() {
+            let Entry::Vacant(e) = map.entry(k.borrow()).unwrap() else {
+                panic!("keys should be unique");
+            };
+            match e.insert(v.borrow()) {
+                Ok(o) => map = o.to_page(),
+                Err((_, Error::OutofSpace(_))) => return n,
+                Err((_, e)) => panic!("unexpected error {e:?}"),
+            }
+        }
+        panic!("ran out of pairs before the page filled up");
+    }
+
+    #[test]
+    fn capacity_constants() {
+        let big = |i: u64| {
+            let mut key = vec![0; MAX_VAR_SIZE];
+            key[..8].copy_from_slice(&i.to_be_bytes());
+            key
+        };
+
+        // Smallest and largest pairs pack exactly as many as advertised
+        let small = fill_count::<LayoutU64U64, _, _>((0..).map(|i| (i, i)));
+        assert_eq!(small, max_pairs::<LayoutU64U64>());
+        assert_eq!(small, min_pairs::<LayoutU64U64>());
+        assert_eq!(small, crate::btree::branch_fanout::<LayoutU64U64>());
+
+        let small = fill_count::<LayoutU64Var, _, _>((0..).map(|i| (i, Vec::new())));
+        assert_eq!(small, max_pairs::<LayoutU64Var>());
+        let large = fill_count::<LayoutU64Var, _, _>((0..).map(|i| (i, big(i))));
+        assert_eq!(large, min_pairs::<LayoutU64Var>());
+
+        let small = fill_count::<LayoutVarU64, _, _>((0..).map(|i: u64| (i.to_be_bytes(), i)));
+        assert_eq!(small, max_pairs::<LayoutVarU64>());
+        assert_eq!(small, crate::btree::branch_fanout::<LayoutVarU64>());
+        let large = fill_count::<LayoutVarU64, _, _>((0..).map(|i| (big(i), i)));
+        assert_eq!(large, min_pairs::<LayoutVarU64>());
+
+        // And the longest keys and values fit, while anything longer doesn't
+        assert_eq!(LayoutU64Var::MAX_VALUE_LEN, MAX_VAR_SIZE);
+        assert_eq!(LayoutVarU64::MAX_KEY_LEN, MAX_VAR_SIZE);
+        let too_long = vec![1; MAX_VAR_SIZE + 1];
+        assert!(LayoutU64Var::determine_value_len(&too_long[1..]).is_ok());
+        assert_eq!(
+            LayoutU64Var::determine_value_len(&too_long),
+            Err(Error::WriteTooLarge)
+        );
+        assert!(LayoutVarU64::determine_key_len(&too_long[1..]).is_ok());
+        assert_eq!(
+            LayoutVarU64::determine_key_len(&too_long),
+            Err(Error::WriteTooLarge)
+        );
+    }
+
+    /// Look up `key` in copies of `page`, searching from each end, and check
+    /// that both searches find the same entry and leave identical pages after
+    /// writing `value` to it or deleting it.
+    fn check_directions<T: PageLayout>(page: &Page, key: &T::Key, value: &T::Value) {
+        for op in 0..3 {
+            let mut results = Vec::new();
+            for from_front in [false, true] {
+                let mut copy = Page(page.0);
+                let map = PageMapMut::<T>::from_page(&mut copy.0).unwrap();
+                let found = match map.entry_from(key, from_front).unwrap() {
+                    Entry::Occupied(mut o) => {
+                        assert_eq!(o.key(), key);
+                        let first = o.first();
+                        match op {
+                            1 => o.replace(value).unwrap(),
+                            2 => drop(o.delete().unwrap()),
+                            _ => (),
+                        }
+                        (true, first)
+                    }
+                    Entry::Vacant(v) => {
+                        assert_eq!(v.key(), key);
+                        let first = v.first();
+                        if op == 1 {
+                            let o = v.insert(value).map_err(|(_, e)| e).unwrap();
+                            assert_eq!(o.first(), first);
+                        }
+                        (false, first)
+                    }
+                };
+                results.push((found, copy));
+            }
+            let [(back, back_page), (front, front_page)] = &results[..] else {
+                unreachable!();
+            };
+            assert_eq!(back, front, "entries differ for {key:?}");
+            assert!(back_page.0 == front_page.0, "pages differ for {key:?}");
+            let map = PageMap::<T>::from_page(&back_page.0).unwrap();
+            map.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn search_directions_agree() {
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..100 {
+            // Even keys, so odd probes are always vacant
+            let mut page = Page([0; PAGE_4K]);
+            let mut map = PageMapMut::<LayoutU64Var>::new(&mut page.0, 1);
+            let mut keys = Vec::new();
+            for _ in 0..rng.below(80) {
+                let key = rng.below(2048) * 2;
+                let value = vec![key as u8; rng.below(24) as usize];
+                if let Entry::Vacant(v) = map.entry(&key).unwrap() {
+                    map = v.insert(&value).map_err(|(_, e)| e).unwrap().to_page();
+                    keys.push(key);
+                } else {
+                    map = PageMapMut::from_page(&mut page.0).unwrap();
+                }
+            }
+            keys.sort_unstable();
+            let mut probes = vec![0, 1, u64::MAX];
+            probes.extend(keys.first().copied());
+            probes.extend(keys.last().copied());
+            probes.extend((0..16).map(|_| rng.below(4100)));
+            for probe in probes {
+                check_directions::<LayoutU64Var>(&page, &probe, &[7; 5]);
+            }
+        }
+
+        // Byte string keys, sharing prefixes
+        for _ in 0..100 {
+            let rand_key = |rng: &mut Rng| {
+                let len = 1 + rng.below(10) as usize;
+                let byte = |rng: &mut Rng| b"aab"[rng.below(3) as usize];
+                (0..len).map(|_| byte(rng)).collect::<Vec<u8>>()
+            };
+            let mut page = Page([0; PAGE_4K]);
+            let mut map = PageMapMut::<LayoutVarU64>::new(&mut page.0, 1);
+            for _ in 0..rng.below(60) {
+                let key = rand_key(&mut rng);
+                if let Entry::Vacant(v) = map.entry(&key).unwrap() {
+                    map = v.insert(&1).map_err(|(_, e)| e).unwrap().to_page();
+                } else {
+                    map = PageMapMut::from_page(&mut page.0).unwrap();
+                }
+            }
+            for _ in 0..16 {
+                check_directions::<LayoutVarU64>(&page, &rand_key(&mut rng), &2);
+            }
+        }
+    }
+
+    #[test]
+    fn closer_to_first() {
+        assert!(u64_closer_to_first(10, 0, 100));
+        assert!(!u64_closer_to_first(60, 0, 100));
+        assert!(!u64_closer_to_first(u64::MAX - 10, 0, u64::MAX));
+        assert!(bytes_closer_to_first(b"key-b", b"key-a", b"key-z"));
+        assert!(!bytes_closer_to_first(b"key-x", b"key-a", b"key-z"));
+        assert!(bytes_closer_to_first(b"a\x01", b"a", b"az"));
+        assert!(!bytes_closer_to_first(b"ay", b"a", b"az"));
+    }
+}
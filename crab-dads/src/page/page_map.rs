@@ -1,10 +1,10 @@
 use core::{cmp::Ordering, marker::PhantomData, slice};
 
 use crate::{
-    arrays::{KeyValArray, RevSizedArray}, ByteFormatter, Error, TwoArrayTrailer, PAGE_4K
+    arrays::{KeyValArray, RevSizedArray}, fmt::HexDump, Error, TwoArrayTrailer, PAGE_4K
 };
 
-use super::{PageLayout, PageMapMut, CONTENT_SIZE};
+use super::{check_layout, PageLayout, PageMapMut, CONTENT_SIZE, POISONED_PAGE_TYPE};
 
 #[repr(transparent)]
 pub struct PageMap<'a, T: PageLayout> {
@@ -12,6 +12,11 @@ pub struct PageMap<'a, T: PageLayout> {
     page: *const u8,
 }
 
+// Safety: this is a shared borrow of the page, like a `&'a [u8; 4096]`. `T` only describes how the
+// bytes are laid out.
+unsafe impl<T: PageLayout + Sync> Send for PageMap<'_, T> {}
+unsafe impl<T: PageLayout + Sync> Sync for PageMap<'_, T> {}
+
 impl<'a, T: PageLayout> Clone for PageMap<'a, T> {
     fn clone(&self) -> Self {
         Self {
@@ -23,17 +28,19 @@ impl<'a, T: PageLayout> Clone for PageMap<'a, T> {
 
 impl<'a, T: PageLayout> core::fmt::Debug for PageMap<'a, T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        let (lower, upper) = unsafe {
+        let (lower, upper, upper_start) = unsafe {
             let lengths = self.page_trailer().lengths_unchecked();
             let upper_bytes = lengths.upper_bytes::<T>();
+            let upper_start = CONTENT_SIZE - upper_bytes;
             let lower = slice::from_raw_parts(self.page, lengths.lower_bytes::<u8>());
-            let upper = slice::from_raw_parts(self.page.add(CONTENT_SIZE-upper_bytes), upper_bytes);
-            (lower, upper)
+            let upper = slice::from_raw_parts(self.page.add(upper_start), upper_bytes);
+            (lower, upper, upper_start)
         };
+        let upper = HexDump::new(upper).with_base_offset(upper_start as u64);
         f.debug_struct(core::any::type_name::<Self>())
             .field("trailer", self.page_trailer())
-            .field("lower_bytes", &ByteFormatter::new(lower))
-            .field("upper_bytes", &ByteFormatter::new(upper))
+            .field("lower_bytes", &HexDump::new(lower))
+            .field("upper_bytes", &upper)
             .finish()
     }
 }
@@ -46,6 +53,10 @@ impl<'a, T: PageLayout> PageMap<'a, T> {
             layout: PhantomData,
         };
         let trailer = ret.page_trailer();
+        if trailer.page_type == POISONED_PAGE_TYPE {
+            return Err(Error::Poisoned);
+        }
+        check_layout::<T>(trailer)?;
         trailer.lengths::<u8, T>(CONTENT_SIZE)?;
         Ok(ret)
     }
@@ -103,10 +114,12 @@ impl<'a, T: PageLayout> PageMap<'a, T> {
                 upper_bytes,
             );
 
-            PageMapMut {
+            let mut map = PageMapMut {
                 page: dst.as_mut_ptr(),
                 layout: PhantomData,
-            }
+            };
+            map.page_trailer_mut().layout_id = T::LAYOUT_ID;
+            map
         }
     }
 
@@ -141,6 +154,15 @@ impl<'a, T: PageLayout> Clone for PageIter<'a, T> {
 }
 
 impl<'a, T: PageLayout> PageIter<'a, T> {
+    /// Iterate over just the keys left in this iterator, stepping over the values without
+    /// reading them out.
+    pub fn keys(self) -> PageKeys<'a, T> {
+        PageKeys {
+            info: self.info,
+            data: self.data,
+        }
+    }
+
     #[allow(clippy::type_complexity)]
     fn next_internal(&mut self) -> Result<Option<(&'a T::Key, &'a T::Value)>, Error> {
         let Some(info) = self.info.next() else {
@@ -166,6 +188,64 @@ impl<'a, T: PageLayout> PageIter<'a, T> {
     }
 }
 
+/// Iterator over the keys in a page, without reading out any of the values. Made by
+/// [`PageIter::keys`].
+#[derive(Debug)]
+pub struct PageKeys<'a, T: PageLayout> {
+    info: RevSizedArray<'a, T>,
+    data: KeyValArray<'a>,
+}
+
+impl<'a, T: PageLayout> Clone for PageKeys<'a, T> {
+    fn clone(&self) -> Self {
+        Self {
+            info: self.info.clone(),
+            data: self.data.clone(),
+        }
+    }
+}
+
+impl<'a, T: PageLayout> PageKeys<'a, T> {
+    fn next_internal(&mut self) -> Result<Option<&'a T::Key>, Error> {
+        let Some(info) = self.info.next() else {
+            self.data.next_none()?;
+            return Ok(None);
+        };
+        let info = info?;
+        let key = self.data.next_key(info.key_len(), info.value_len())?;
+        // Safety: we constructed our slice using the provided length numbers.
+        unsafe { Ok(Some(info.read_key(key))) }
+    }
+
+    fn next_back_internal(&mut self) -> Result<Option<&'a T::Key>, Error> {
+        let Some(info) = self.info.next_back() else {
+            self.data.next_none()?;
+            return Ok(None);
+        };
+        let info = info?;
+        let key = self.data.next_key_back(info.key_len(), info.value_len())?;
+        // Safety: we constructed our slice using the provided length numbers.
+        unsafe { Ok(Some(info.read_key(key))) }
+    }
+}
+
+impl<'a, T: PageLayout> Iterator for PageKeys<'a, T> {
+    type Item = Result<&'a T::Key, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_internal().transpose()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.info.len() + 1))
+    }
+}
+
+impl<'a, T: PageLayout> DoubleEndedIterator for PageKeys<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_internal().transpose()
+    }
+}
+
 impl<'a, T: PageLayout> Iterator for PageIter<'a, T> {
     type Item = Result<(&'a T::Key, &'a T::Value), Error>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -1,4 +1,6 @@
 
+use core::borrow::Borrow;
+
 use bytemuck::{CheckedBitPattern, NoUninit};
 use crate::Error;
 
@@ -11,10 +13,38 @@ use crate::Error;
 ///   provided source slices.
 /// - `write_key` and `write_value` must work even if the current bit pattern is
 ///   incorrect.
+///
+/// Values may always be zero-length. Keys may not: layouts with variable-length
+/// keys must reject empty ones in `determine_key_len` with
+/// [`Error::EmptyKey`], and treat them as an invalid bit pattern when reading.
 pub unsafe trait PageLayout: NoUninit + CheckedBitPattern + Default {
     type Key: Ord + core::fmt::Debug + ?Sized;
     type Value: ?Sized;
 
+    /// An owned copy of a key, for holding onto one past the life of the page
+    /// it was read from. It must order the same way as the key it borrows as.
+    type OwnedKey: Borrow<Self::Key> + Ord + Clone + core::fmt::Debug;
+
+    /// Stable identifier for this layout, stored in every page written with
+    /// it so that a page can't be read back with the wrong layout. It must be
+    /// unique among layouts, never change once pages have been written with
+    /// it, and never be [`LEGACY_LAYOUT_ID`][super::LEGACY_LAYOUT_ID].
+    const LAYOUT_ID: u8;
+
+    /// Longest key this layout can store, in bytes.
+    const MAX_KEY_LEN: usize;
+
+    /// Longest value this layout can store, in bytes.
+    const MAX_VALUE_LEN: usize;
+
+    /// Fewest bytes a single pair can take up in a page, including its entry
+    /// of this layout struct.
+    const MIN_PAIR_SIZE: usize;
+
+    /// Most bytes a single pair can take up in a page, including its entry of
+    /// this layout struct.
+    const MAX_PAIR_SIZE: usize;
+
     /// The size of the variable-length portion of the current key.
     fn key_len(&self) -> usize;
 
@@ -72,6 +102,17 @@ pub unsafe trait PageLayout: NoUninit + CheckedBitPattern + Default {
     /// exactly equal to what the function returned.
     unsafe fn write_value(&mut self, val: &Self::Value, dst: &mut [u8]);
 
+    /// Copy a key out of a page. Reading and writing pages never needs this;
+    /// it's only for code that has to keep a key around afterwards.
+    fn to_owned_key(key: &Self::Key) -> Self::OwnedKey;
+
+    /// Guess whether `key`, which sorts strictly between `first` and `last`,
+    /// is closer to `first`. Pages are searched for a key starting from
+    /// whichever end this picks, so a good guess halves the average search.
+    /// The default always picks `last`, which is where ascending keys go.
+    fn closer_to_first(_key: &Self::Key, _first: &Self::Key, _last: &Self::Key) -> bool {
+        false
+    }
 }
 
 pub trait PageLayoutVectored: PageLayout {
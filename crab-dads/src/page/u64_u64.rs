@@ -6,15 +6,24 @@ use super::PageLayout;
 
 #[derive(Zeroable, Clone, Copy, Default)]
 pub struct LayoutU64U64 {
-    key: u64,
+    pub(crate) key: u64,
 }
 
 unsafe impl NoUninit for LayoutU64U64 {}
 unsafe impl AnyBitPattern for LayoutU64U64 {}
 
+// Splitting a page has to leave at least one pair on each side.
+const _: () = assert!(super::min_pairs::<LayoutU64U64>() >= 2);
+
 unsafe impl PageLayout for LayoutU64U64 {
     type Key = u64;
     type Value = u64;
+    type OwnedKey = u64;
+    const LAYOUT_ID: u8 = 1;
+    const MAX_KEY_LEN: usize = 8;
+    const MAX_VALUE_LEN: usize = 8;
+    const MIN_PAIR_SIZE: usize = core::mem::size_of::<Self>() + 8;
+    const MAX_PAIR_SIZE: usize = Self::MIN_PAIR_SIZE;
 
     fn key_len(&self) -> usize {
         0
@@ -24,6 +33,10 @@ unsafe impl PageLayout for LayoutU64U64 {
         8
     }
 
+    fn to_owned_key(key: &Self::Key) -> Self::OwnedKey {
+        *key
+    }
+
     unsafe fn read_key<'a>(&'a self, _: &'a [u8]) -> &'a Self::Key {
         &self.key
     }
@@ -56,4 +69,8 @@ unsafe impl PageLayout for LayoutU64U64 {
             (dst.as_mut_ptr() as *mut u64).write(*val);
         }
     }
+
+    fn closer_to_first(key: &Self::Key, first: &Self::Key, last: &Self::Key) -> bool {
+        super::u64_closer_to_first(*key, *first, *last)
+    }
 }
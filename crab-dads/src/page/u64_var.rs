@@ -7,7 +7,7 @@ use super::{PageLayout, PageLayoutVectored, MAX_VAR_SIZE};
 #[repr(C)]
 #[derive(Zeroable, Clone, Copy, Default)]
 pub struct LayoutU64Var {
-    len: u16,
+    pub(crate) len: u16,
 }
 
 unsafe impl NoUninit for LayoutU64Var {}
@@ -19,9 +19,18 @@ unsafe impl CheckedBitPattern for LayoutU64Var {
     }
 }
 
+// Splitting a page has to leave at least one pair on each side.
+const _: () = assert!(super::min_pairs::<LayoutU64Var>() >= 2);
+
 unsafe impl PageLayout for LayoutU64Var {
     type Key = u64;
     type Value = [u8];
+    type OwnedKey = u64;
+    const LAYOUT_ID: u8 = 2;
+    const MAX_KEY_LEN: usize = 8;
+    const MAX_VALUE_LEN: usize = MAX_VAR_SIZE;
+    const MIN_PAIR_SIZE: usize = core::mem::size_of::<Self>() + 8;
+    const MAX_PAIR_SIZE: usize = core::mem::size_of::<Self>() + 8 + MAX_VAR_SIZE;
 
     fn key_len(&self) -> usize {
         8
@@ -31,6 +40,10 @@ unsafe impl PageLayout for LayoutU64Var {
         ((self.len + 7) & !7) as usize
     }
 
+    fn to_owned_key(key: &Self::Key) -> Self::OwnedKey {
+        *key
+    }
+
     unsafe fn read_key<'a>(&'a self, src: &'a [u8]) -> &'a Self::Key {
         unsafe { &*(src.as_ptr() as *const u64) }
     }
@@ -57,6 +70,10 @@ unsafe impl PageLayout for LayoutU64Var {
         }
     }
 
+    fn closer_to_first(key: &Self::Key, first: &Self::Key, last: &Self::Key) -> bool {
+        super::u64_closer_to_first(*key, *first, *last)
+    }
+
     unsafe fn update_value<'a>(&'a self, src: &'a mut [u8]) -> &'a mut Self::Value {
         unsafe { src.get_unchecked_mut(0..(self.len as usize)) }
     }
@@ -74,7 +91,7 @@ impl PageLayoutVectored for LayoutU64Var {
         if len > MAX_VAR_SIZE {
             return Err(Error::WriteTooLarge);
         }
-        Ok((len + 7) / 8)
+        Ok((len + 7) & !7)
     }
 
     unsafe fn write_value_vectored(&mut self, val: &[&Self::Value], dst: &mut [u8]) {
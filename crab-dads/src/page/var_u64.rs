@@ -1,3 +1,5 @@
+use alloc::boxed::Box;
+
 use bytemuck::{CheckedBitPattern, NoUninit, Zeroable};
 
 use crate::Error;
@@ -7,7 +9,7 @@ use super::{PageLayout, MAX_VAR_SIZE};
 #[repr(C)]
 #[derive(Zeroable, Clone, Copy, Default)]
 pub struct LayoutVarU64 {
-    len: u16,
+    pub(crate) len: u16,
 }
 
 unsafe impl NoUninit for LayoutVarU64 {}
@@ -15,22 +17,36 @@ unsafe impl NoUninit for LayoutVarU64 {}
 unsafe impl CheckedBitPattern for LayoutVarU64 {
     type Bits = u16;
     fn is_valid_bit_pattern(bits: &Self::Bits) -> bool {
-        *bits <= (MAX_VAR_SIZE as u16)
+        // Keys are never empty
+        (1..=(MAX_VAR_SIZE as u16)).contains(bits)
     }
 }
 
+// Splitting a page has to leave at least one pair on each side.
+const _: () = assert!(super::min_pairs::<LayoutVarU64>() >= 2);
+
 unsafe impl PageLayout for LayoutVarU64 {
     type Key = [u8];
     type Value = u64;
+    type OwnedKey = Box<[u8]>;
+    const LAYOUT_ID: u8 = 3;
+    const MAX_KEY_LEN: usize = MAX_VAR_SIZE;
+    const MAX_VALUE_LEN: usize = 8;
+    const MIN_PAIR_SIZE: usize = core::mem::size_of::<Self>() + 8 + 8;
+    const MAX_PAIR_SIZE: usize = core::mem::size_of::<Self>() + MAX_VAR_SIZE + 8;
 
     fn key_len(&self) -> usize {
-        ((self.len + 7) / 8) as usize
+        ((self.len + 7) & !7) as usize
     }
 
     fn value_len(&self) -> usize {
         8
     }
 
+    fn to_owned_key(key: &Self::Key) -> Self::OwnedKey {
+        key.into()
+    }
+
     unsafe fn read_key<'a>(&'a self, src: &'a [u8]) -> &'a Self::Key {
         unsafe { src.get_unchecked(0..(self.len as usize)) }
     }
@@ -40,10 +56,13 @@ unsafe impl PageLayout for LayoutVarU64 {
     }
 
     fn determine_key_len(key: &Self::Key) -> Result<usize, Error> {
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
         if key.len() > MAX_VAR_SIZE {
             return Err(Error::WriteTooLarge);
         }
-        Ok((key.len() + 7) / 8)
+        Ok((key.len() + 7) & !7)
     }
 
     fn determine_value_len(_: &Self::Value) -> Result<usize, Error> {
@@ -70,4 +89,8 @@ unsafe impl PageLayout for LayoutVarU64 {
         }
     }
 
+    fn closer_to_first(key: &Self::Key, first: &Self::Key, last: &Self::Key) -> bool {
+        super::bytes_closer_to_first(key, first, last)
+    }
+
 }
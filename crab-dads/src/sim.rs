@@ -0,0 +1,791 @@
+//! A simulated, heap-backed page allocator for testing code built on
+//! [`RawRead`] and [`RawWrite`].
+//!
+//! [`SimAllocator`] is the single writer, and [`SimReader`]s are snapshot
+//! readers of whatever it last committed. Pages freed by the writer are only
+//! reclaimed once no reader could still be looking at them, the same as the
+//! real allocator. Failures can be injected to test error paths: allocations
+//! and writable loads can be made to fail after some number of calls, and
+//! loads of specific pages can be made to fail outright.
+//!
+//! Nothing here is durable. Every page lives in process memory and is gone
+//! once the allocator and all of its readers are dropped, and a "commit" is
+//! just a swap of in-memory state - there's no crash consistency to speak of.
+
+extern crate std;
+
+use core::{cell::UnsafeCell, fmt, ptr::NonNull};
+use std::{
+    alloc::{self, Layout},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    vec::Vec,
+};
+
+use crate::{
+    btree::{BTreeConfig, BTreeRead, BTreeWrite, LoadMut, RawRead, RawWrite},
+    fmt::HexDump,
+    page::{LayoutU64Var, PageLayout, PageMapMut, LEGACY_LAYOUT_ID},
+    Error, StorageError, NULL_PAGE, PAGE_4K,
+};
+
+/// Page number of the root page every simulated database starts out with.
+/// Page numbers start here, as [`NULL_PAGE`] is never handed out.
+pub const SIM_ROOT_PAGE: u64 = NULL_PAGE + 1;
+
+/// A page-aligned, zeroed, heap-allocated run of pages.
+struct PageBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Safety: this is uniquely owned memory, like a `Box<[u8]>`.
+unsafe impl Send for PageBuf {}
+unsafe impl Sync for PageBuf {}
+
+impl PageBuf {
+    fn new(num_pages: usize) -> Result<Self, StorageError> {
+        if num_pages == 0 {
+            return Err(StorageError::Io("can't allocate zero pages"));
+        }
+        let len = num_pages
+            .checked_mul(PAGE_4K)
+            .ok_or(StorageError::Io("allocation size overflowed"))?;
+        let layout = Layout::from_size_align(len, PAGE_4K)
+            .map_err(|_| StorageError::Io("allocation size overflowed"))?;
+        // Safety: the layout is non-zero in size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).ok_or(StorageError::Io("out of memory"))?;
+        Ok(Self { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Get the memory with a lifetime unconnected to this buffer.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure the buffer outlives the returned slice, and
+    /// that it's not aliased mutably.
+    unsafe fn detach<'a>(&self) -> &'a [u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Get the memory mutably, with a lifetime unconnected to this buffer.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure the buffer outlives the returned slice, and
+    /// that it's not aliased at all.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn detach_mut<'a>(&self) -> &'a mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for PageBuf {
+    fn drop(&mut self) {
+        // Safety: this is the same layout we allocated with.
+        unsafe {
+            alloc::dealloc(
+                self.ptr.as_ptr(),
+                Layout::from_size_align_unchecked(self.len, PAGE_4K),
+            )
+        }
+    }
+}
+
+/// State shared between the writer and all readers.
+struct SimShared {
+    /// Root page as of the last commit
+    root: u64,
+    /// Committed pages
+    memory: BTreeMap<u64, PageBuf>,
+    /// Number of readers open on each generation
+    checkouts: BTreeMap<u64, usize>,
+    /// Most recently committed generation
+    commit: u64,
+    /// Pages that fail to load
+    fail_loads: BTreeSet<u64>,
+}
+
+struct MemoryFmt<'a>(&'a BTreeMap<u64, PageBuf>);
+impl fmt::Debug for MemoryFmt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (page, mem) in self.0.iter() {
+            map.entry(page, &HexDump::new(mem.bytes()));
+        }
+        map.finish()
+    }
+}
+
+impl fmt::Debug for SimShared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimShared")
+            .field("root", &self.root)
+            .field("commit", &self.commit)
+            .field("checkouts", &self.checkouts)
+            .field("fail_loads", &self.fail_loads)
+            .field("memory", &MemoryFmt(&self.memory))
+            .finish()
+    }
+}
+
+fn read_shared(shared: &RwLock<SimShared>) -> Result<RwLockReadGuard<'_, SimShared>, StorageError> {
+    shared
+        .read()
+        .map_err(|_| StorageError::Safety("simulated storage lock was poisoned"))
+}
+
+fn write_shared(
+    shared: &RwLock<SimShared>,
+) -> Result<RwLockWriteGuard<'_, SimShared>, StorageError> {
+    shared
+        .write()
+        .map_err(|_| StorageError::Safety("simulated storage lock was poisoned"))
+}
+
+/// Look up a committed region, checking it against the requested size.
+unsafe fn load_committed<'a>(
+    shared: &SimShared,
+    page: u64,
+    num_pages: usize,
+) -> Result<Option<&'a [u8]>, StorageError> {
+    if page == NULL_PAGE {
+        return Err(StorageError::OutOfRange(page));
+    }
+    if shared.fail_loads.contains(&page) {
+        return Err(StorageError::Io("simulated load failure"));
+    }
+    let Some(mem) = shared.memory.get(&page) else {
+        return Ok(None);
+    };
+    if mem.len != num_pages * PAGE_4K {
+        return Err(StorageError::Corruption(
+            "Incorrect size for the requested page",
+        ));
+    }
+    // Safety: committed memory isn't freed until every reader that could see
+    // it has moved on, and is never written to again.
+    unsafe { Ok(Some(mem.detach())) }
+}
+
+/// A snapshot reader of a [`SimAllocator`]'s most recent commit at the time
+/// it was opened.
+///
+/// While the reader is alive, every page reachable from its snapshot is kept
+/// in memory. Cloning a reader gives another view of the same snapshot; use
+/// [`reload`][Self::reload] to move on to the latest commit instead.
+pub struct SimReader {
+    shared: Arc<RwLock<SimShared>>,
+    root: u64,
+    commit: u64,
+}
+
+impl fmt::Debug for SimReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimReader")
+            .field("root", &self.root)
+            .field("commit", &self.commit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SimReader {
+    fn open(shared: &Arc<RwLock<SimShared>>) -> Result<Self, StorageError> {
+        let mut inner = write_shared(shared)?;
+        let commit = inner.commit;
+        *inner.checkouts.entry(commit).or_default() += 1;
+        Ok(Self {
+            shared: shared.clone(),
+            root: inner.root,
+            commit,
+        })
+    }
+
+    /// Drop this snapshot and open a new one on the latest commit.
+    pub fn reload(self) -> Result<Self, StorageError> {
+        Self::open(&self.shared)
+    }
+
+    /// The root page of this snapshot.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Load the tree rooted at this snapshot's root page.
+    pub fn tree<B, L>(&self) -> Result<BTreeRead<'_, B, L, Self>, Error>
+    where
+        B: PageLayout<Value = u64>,
+        L: PageLayout<Key = B::Key>,
+    {
+        unsafe { BTreeRead::load(self, self.root) }
+    }
+}
+
+impl Clone for SimReader {
+    fn clone(&self) -> Self {
+        // Our own checkout keeps this entry alive, so it's fine to keep going
+        // even if some other thread poisoned the lock.
+        let mut inner = self.shared.write().unwrap_or_else(|e| e.into_inner());
+        *inner.checkouts.entry(self.commit).or_default() += 1;
+        Self {
+            shared: self.shared.clone(),
+            root: self.root,
+            commit: self.commit,
+        }
+    }
+}
+
+unsafe impl RawRead for SimReader {
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        let inner = read_shared(&self.shared)?;
+        unsafe { load_committed(&inner, page, num_pages)? }.ok_or(StorageError::OutOfRange(page))
+    }
+
+    fn generation(&self) -> u64 {
+        self.commit
+    }
+}
+
+impl Drop for SimReader {
+    fn drop(&mut self) {
+        let mut inner = self.shared.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = inner.checkouts.get_mut(&self.commit) {
+            *count -= 1;
+            if *count == 0 {
+                inner.checkouts.remove(&self.commit);
+            }
+        }
+    }
+}
+
+/// Writer-only state, touched through `&self` by the [`RawWrite`] methods.
+#[derive(Default)]
+struct SimWriteCell {
+    /// Next unused page number
+    page_num: u64,
+    /// Pages allocated since the last commit
+    dirty: BTreeMap<u64, PageBuf>,
+    /// Committed runs freed since, as `(page, num_pages)`, by the generation that no longer uses
+    /// them
+    to_drop: VecDeque<(u64, Vec<(u64, usize)>)>,
+    root: u64,
+    /// Coalesced runs handed to the most recent `deallocate_batch` call
+    batch_runs: Vec<(u64, usize)>,
+    /// Number of allocations made
+    allocations: usize,
+    /// Fail every allocation once this many have been made
+    fail_allocs_after: Option<usize>,
+    /// Number of `load_mut` calls made since the limit below was set
+    load_muts: usize,
+    /// Fail every `load_mut` once this many have been made
+    fail_load_muts_after: Option<usize>,
+}
+
+impl fmt::Debug for SimWriteCell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimWriteCell")
+            .field("root", &self.root)
+            .field("page_num", &self.page_num)
+            .field("dirty", &MemoryFmt(&self.dirty))
+            .field("to_drop", &self.to_drop)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A simulated database's single writer.
+///
+/// Pages are allocated with strictly increasing page numbers and are never
+/// reused. A new database starts with an empty leaf page at
+/// [`SIM_ROOT_PAGE`], ready to be the root of a tree. It carries
+/// [`LEGACY_LAYOUT_ID`], so a tree of any layout can claim it. Changes become visible
+/// to readers opened after [`commit`][Self::commit].
+#[derive(Debug)]
+pub struct SimAllocator {
+    shared: Arc<RwLock<SimShared>>,
+    cell: UnsafeCell<SimWriteCell>,
+    commit: u64,
+    starting_page_num: u64,
+    starting_root: u64,
+}
+
+impl Default for SimAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimAllocator {
+    /// Create a new, empty database.
+    pub fn new() -> Self {
+        let mut memory = BTreeMap::new();
+        let root = PageBuf::new(1).expect("should be able to allocate a single page");
+        // Safety: we own this page and nobody else has seen it yet.
+        unsafe {
+            let page = &mut *(root.detach_mut().as_mut_ptr() as *mut [u8; PAGE_4K]);
+            PageMapMut::<LayoutU64Var>::new(page, 1)
+                .page_trailer_mut()
+                .layout_id = LEGACY_LAYOUT_ID;
+        }
+        memory.insert(SIM_ROOT_PAGE, root);
+
+        let shared = Arc::new(RwLock::new(SimShared {
+            root: SIM_ROOT_PAGE,
+            memory,
+            checkouts: BTreeMap::new(),
+            commit: 0,
+            fail_loads: BTreeSet::new(),
+        }));
+        Self {
+            shared,
+            cell: UnsafeCell::new(SimWriteCell {
+                page_num: SIM_ROOT_PAGE + 1,
+                root: SIM_ROOT_PAGE,
+                ..Default::default()
+            }),
+            commit: 0,
+            starting_page_num: SIM_ROOT_PAGE + 1,
+            starting_root: SIM_ROOT_PAGE,
+        }
+    }
+
+    /// Open a reader on the most recent commit.
+    pub fn reader(&self) -> Result<SimReader, StorageError> {
+        SimReader::open(&self.shared)
+    }
+
+    /// The root page as of the pending transaction.
+    pub fn root(&self) -> u64 {
+        unsafe { (*self.cell.get()).root }
+    }
+
+    /// Set the root page that will be published by the next commit.
+    pub fn set_root(&mut self, root: u64) {
+        self.cell.get_mut().root = root;
+    }
+
+    /// Load the tree rooted at the current root page, updating the root page
+    /// if it gets copied on write.
+    pub fn tree<B, L>(&mut self) -> Result<BTreeWrite<'_, B, L, Self>, Error>
+    where
+        B: PageLayout<Value = u64>,
+        L: PageLayout<Key = B::Key>,
+    {
+        self.tree_with_config(BTreeConfig::default())
+    }
+
+    /// Same as [`tree`][Self::tree], using the given configuration for all
+    /// modifications.
+    pub fn tree_with_config<B, L>(
+        &mut self,
+        config: BTreeConfig,
+    ) -> Result<BTreeWrite<'_, B, L, Self>, Error>
+    where
+        B: PageLayout<Value = u64>,
+        L: PageLayout<Key = B::Key>,
+    {
+        unsafe {
+            let (tree, root) = BTreeWrite::load_with_config(self, (*self.cell.get()).root, config)?;
+            if let Some(root) = root {
+                (*self.cell.get()).root = root;
+            }
+            Ok(tree)
+        }
+    }
+
+    /// Total pages currently held in memory, committed or not.
+    pub fn page_count(&self) -> usize {
+        let mem_len = read_shared(&self.shared).map_or(0, |inner| inner.memory.len());
+        let dirty_len = unsafe { (*self.cell.get()).dirty.len() };
+        mem_len + dirty_len
+    }
+
+    /// The generation the next commit will publish.
+    pub fn pending_generation(&self) -> u64 {
+        self.commit + 1
+    }
+
+    /// The coalesced runs handed to the most recent `deallocate_batch` call.
+    pub fn last_batch_runs(&self) -> &[(u64, usize)] {
+        unsafe { &(*self.cell.get()).batch_runs }
+    }
+
+    /// Make every allocation fail once `count` more have succeeded. `None`
+    /// lets allocations succeed again.
+    pub fn fail_allocations_after(&mut self, count: Option<usize>) {
+        let cell = self.cell.get_mut();
+        cell.allocations = 0;
+        cell.fail_allocs_after = count;
+    }
+
+    /// Make every `load_mut` fail once `count` more have been made, whether
+    /// the page is dirty or not. `None` lets them succeed again.
+    pub fn fail_load_muts_after(&mut self, count: Option<usize>) {
+        let cell = self.cell.get_mut();
+        cell.load_muts = 0;
+        cell.fail_load_muts_after = count;
+    }
+
+    /// Make every load of the given page fail, for readers and writer alike.
+    pub fn fail_loads_of(&mut self, page: u64) -> Result<(), StorageError> {
+        write_shared(&self.shared)?.fail_loads.insert(page);
+        Ok(())
+    }
+
+    /// Change the stored bytes of a committed page in place, the way damage on
+    /// disk would. Fails if there's no committed page starting there.
+    ///
+    /// # Safety
+    ///
+    /// Nothing loaded from the page may still be in use, by the writer or by
+    /// any reader.
+    pub unsafe fn corrupt_page(
+        &mut self,
+        page: u64,
+        f: impl FnOnce(&mut [u8]),
+    ) -> Result<(), StorageError> {
+        let inner = write_shared(&self.shared)?;
+        let mem = inner
+            .memory
+            .get(&page)
+            .ok_or(StorageError::OutOfRange(page))?;
+        // Safety: the caller guarantees nobody else is looking at the page.
+        f(unsafe { mem.detach_mut() });
+        Ok(())
+    }
+
+    /// Stop injecting any failures.
+    pub fn clear_faults(&mut self) -> Result<(), StorageError> {
+        self.fail_allocations_after(None);
+        self.fail_load_muts_after(None);
+        write_shared(&self.shared)?.fail_loads.clear();
+        Ok(())
+    }
+
+    /// Publish all pending changes to new readers, and reclaim any freed
+    /// pages that no open reader can still see.
+    pub fn commit(&mut self) -> Result<(), StorageError> {
+        let mut inner = write_shared(&self.shared)?;
+        let cell = self.cell.get_mut();
+
+        // Move the dirty pages into the committed set.
+        inner.memory.append(&mut cell.dirty);
+
+        self.starting_page_num = cell.page_num;
+        self.starting_root = cell.root;
+        self.commit += 1;
+        inner.root = cell.root;
+        inner.commit = self.commit;
+
+        // A page freed in some generation is only needed by readers from
+        // before it.
+        let oldest = inner
+            .checkouts
+            .first_key_value()
+            .map_or(u64::MAX, |(c, _)| *c);
+        while let Some((freed_in, runs)) = cell.to_drop.pop_front() {
+            if freed_in > oldest {
+                cell.to_drop.push_front((freed_in, runs));
+                break;
+            }
+            for (page, num_pages) in runs {
+                let end = page + num_pages as u64;
+                let freed: Vec<u64> = inner.memory.range(page..end).map(|(p, _)| *p).collect();
+                for page in freed {
+                    inner.memory.remove(&page);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Throw away all changes made since the last commit.
+    pub fn reset(&mut self) {
+        let cell = self.cell.get_mut();
+        if let Some((freed_in, _)) = cell.to_drop.back() {
+            if *freed_in == (self.commit + 1) {
+                cell.to_drop.pop_back();
+            }
+        }
+        cell.dirty.clear();
+        cell.page_num = self.starting_page_num;
+        cell.root = self.starting_root;
+    }
+
+    /// Free the `num_pages` pages starting at `page`, which may span several regions. Regions
+    /// allocated since the last commit go right away, and the rest once no reader can see them.
+    fn free(&self, page: u64, num_pages: usize) {
+        let cell = unsafe { &mut *self.cell.get() };
+        let end = page + num_pages as u64;
+        let dirty: Vec<u64> = cell.dirty.range(page..end).map(|(p, _)| *p).collect();
+        let mut freed = 0;
+        for p in dirty {
+            freed += cell.dirty.remove(&p).map_or(0, |mem| mem.len / PAGE_4K);
+        }
+        if freed == num_pages {
+            return;
+        }
+        let freed_in = self.commit + 1;
+        match cell.to_drop.back_mut() {
+            Some((c, runs)) if *c == freed_in => runs.push((page, num_pages)),
+            _ => cell
+                .to_drop
+                .push_back((freed_in, std::vec![(page, num_pages)])),
+        }
+    }
+}
+
+unsafe impl RawRead for SimAllocator {
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        let inner = read_shared(&self.shared)?;
+        if let Some(mem) = unsafe { load_committed(&inner, page, num_pages)? } {
+            return Ok(mem);
+        }
+        let cell = unsafe { &*self.cell.get() };
+        let mem = cell
+            .dirty
+            .get(&page)
+            .ok_or(StorageError::OutOfRange(page))?;
+        if mem.len != num_pages * PAGE_4K {
+            return Err(StorageError::Corruption(
+                "Incorrect size for the requested page",
+            ));
+        }
+        // Safety: dirty pages stay allocated until freed by the caller.
+        unsafe { Ok(mem.detach()) }
+    }
+
+    fn generation(&self) -> u64 {
+        self.commit
+    }
+}
+
+unsafe impl RawWrite for SimAllocator {
+    fn allocate(&self, num_pages: usize) -> Result<(&mut [u8], u64), StorageError> {
+        let cell = unsafe { &mut *self.cell.get() };
+        if cell
+            .fail_allocs_after
+            .is_some_and(|limit| cell.allocations >= limit)
+        {
+            return Err(StorageError::Io("simulated allocation failure"));
+        }
+        let mem = PageBuf::new(num_pages)?;
+        cell.allocations += 1;
+        let page_num = cell.page_num;
+        cell.page_num += num_pages as u64;
+        // Safety: the buffer stays in the dirty set until freed or committed,
+        // and nothing else has a view of it yet.
+        let raw = unsafe { mem.detach_mut() };
+        cell.dirty.insert(page_num, mem);
+        Ok((raw, page_num))
+    }
+
+    unsafe fn deallocate(&self, page: u64, num_pages: usize) -> Result<(), StorageError> {
+        self.free(page, num_pages);
+        Ok(())
+    }
+
+    unsafe fn deallocate_batch(
+        &self,
+        pages: &mut dyn Iterator<Item = (u64, usize)>,
+    ) -> Result<(), StorageError> {
+        // Sort and coalesce adjacent regions, then free each run in one go.
+        let mut pages: Vec<(u64, usize)> = pages.collect();
+        pages.sort_unstable();
+        let mut runs: Vec<(u64, usize)> = Vec::new();
+        for (page, num_pages) in pages {
+            match runs.last_mut() {
+                Some(run) if run.0 + run.1 as u64 == page => run.1 += num_pages,
+                _ => runs.push((page, num_pages)),
+            }
+        }
+        for (page, num_pages) in runs.iter().copied() {
+            self.free(page, num_pages);
+        }
+        unsafe { (*self.cell.get()).batch_runs = runs };
+        Ok(())
+    }
+
+    unsafe fn load_mut(&self, page: u64, num_pages: usize) -> Result<LoadMut<'_>, StorageError> {
+        unsafe {
+            let cell = &mut *self.cell.get();
+            if cell
+                .fail_load_muts_after
+                .is_some_and(|limit| cell.load_muts >= limit)
+            {
+                return Err(StorageError::Io("simulated load failure"));
+            }
+            cell.load_muts += 1;
+            if let Some(p) = cell.dirty.get(&page) {
+                if read_shared(&self.shared)?.fail_loads.contains(&page) {
+                    return Err(StorageError::Io("simulated load failure"));
+                }
+                return Ok(LoadMut::Dirty(p.detach_mut()));
+            }
+            let read = self.load(page, num_pages)?;
+            let (write, write_page) = self.allocate(num_pages)?;
+            Ok(LoadMut::Clean {
+                write,
+                write_page,
+                read,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        btree::Entry,
+        page::{LayoutU64U64, LayoutU64Var},
+    };
+
+    type Tree<'a> = BTreeWrite<'a, LayoutU64U64, LayoutU64Var, SimAllocator>;
+
+    fn insert_range(tree: &mut Tree, keys: core::ops::Range<u64>) -> Result<(), Error> {
+        for i in keys {
+            match tree.entry(&i)? {
+                Entry::Occupied(o) => o.replace(i.to_le_bytes().as_slice())?,
+                Entry::Vacant(v) => {
+                    v.insert(i.to_le_bytes().as_slice())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_keys(reader: &SimReader) -> Vec<u64> {
+        let tree = reader.tree::<LayoutU64U64, LayoutU64Var>().unwrap();
+        tree.range(..).unwrap().map(|res| *res.unwrap().0).collect()
+    }
+
+    #[test]
+    fn raw_pages() {
+        let mut writer = SimAllocator::new();
+        let (page, page_num) = writer.allocate(2).unwrap();
+        assert_eq!(page.len(), 2 * PAGE_4K);
+        assert_eq!(page.as_ptr() as usize % PAGE_4K, 0);
+        assert!(page.iter().all(|b| *b == 0));
+        page[..4].copy_from_slice(&[5, 6, 7, 8]);
+
+        // Readers can't see it until it's committed
+        let reader = writer.reader().unwrap();
+        assert_eq!(
+            unsafe { reader.load(page_num, 2) },
+            Err(StorageError::OutOfRange(page_num))
+        );
+        writer.commit().unwrap();
+        let reader = reader.reload().unwrap();
+        assert_eq!(
+            unsafe { &reader.load(page_num, 2).unwrap()[..4] },
+            [5, 6, 7, 8]
+        );
+        assert!(unsafe { reader.load(page_num, 1) }.is_err());
+
+        // Writing to a committed page copies it elsewhere
+        let LoadMut::Clean {
+            write,
+            write_page,
+            read,
+        } = (unsafe { writer.load_mut(page_num, 2).unwrap() })
+        else {
+            panic!("committed pages should never be writable in place");
+        };
+        assert_ne!(write_page, page_num);
+        assert_eq!(read[..4], [5, 6, 7, 8]);
+        write[..4].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(matches!(
+            unsafe { writer.load_mut(write_page, 2).unwrap() },
+            LoadMut::Dirty(_)
+        ));
+    }
+
+    #[test]
+    fn snapshots_and_reclamation() {
+        let mut writer = SimAllocator::new();
+        insert_range(&mut writer.tree().unwrap(), 0..5000).unwrap();
+        writer.commit().unwrap();
+        let old = writer.reader().unwrap();
+        let old_clone = old.clone();
+        assert_eq!(old.generation(), 1);
+        let used = writer.page_count();
+
+        // Rewrite everything, freeing every page the old snapshot uses
+        let mut tree: Tree = writer.tree().unwrap();
+        tree.clear().unwrap();
+        insert_range(&mut tree, 5000..10000).unwrap();
+        writer.commit().unwrap();
+        writer.commit().unwrap();
+
+        // Old readers still see their snapshot, new ones see the new data
+        let new = writer.reader().unwrap();
+        assert_eq!(read_keys(&old), (0..5000).collect::<Vec<_>>());
+        assert_eq!(read_keys(&old_clone), (0..5000).collect::<Vec<_>>());
+        assert_eq!(read_keys(&new), (5000..10000).collect::<Vec<_>>());
+        let both = writer.page_count();
+
+        // Once the old readers are all gone, their pages get reclaimed
+        drop(old);
+        writer.commit().unwrap();
+        assert_eq!(writer.page_count(), both);
+        drop(old_clone);
+        writer.commit().unwrap();
+        assert_eq!(writer.page_count(), both - used);
+        assert_eq!(read_keys(&new), (5000..10000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reset() {
+        let mut writer = SimAllocator::new();
+        insert_range(&mut writer.tree().unwrap(), 0..1000).unwrap();
+        writer.commit().unwrap();
+        let root = writer.root();
+        let pages = writer.page_count();
+
+        insert_range(&mut writer.tree().unwrap(), 1000..2000).unwrap();
+        assert_ne!(writer.root(), root);
+        writer.reset();
+        assert_eq!(writer.root(), root);
+        assert_eq!(writer.page_count(), pages);
+        writer.commit().unwrap();
+        assert_eq!(
+            read_keys(&writer.reader().unwrap()),
+            (0..1000).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn fault_injection() {
+        let mut writer = SimAllocator::new();
+        insert_range(&mut writer.tree().unwrap(), 0..2000).unwrap();
+        writer.commit().unwrap();
+
+        // Allocations fail once the limit's reached
+        writer.fail_allocations_after(Some(3));
+        let res = insert_range(&mut writer.tree().unwrap(), 2000..10000);
+        assert_eq!(
+            res,
+            Err(Error::Storage(StorageError::Io(
+                "simulated allocation failure"
+            )))
+        );
+        assert!(writer.allocate(1).is_err());
+        writer.reset();
+        writer.clear_faults().unwrap();
+        insert_range(&mut writer.tree().unwrap(), 2000..3000).unwrap();
+        writer.commit().unwrap();
+
+        // Loads of a specific page fail for everyone
+        let reader = writer.reader().unwrap();
+        writer.fail_loads_of(reader.root()).unwrap();
+        assert!(reader.tree::<LayoutU64U64, LayoutU64Var>().is_err());
+        assert!(writer.tree::<LayoutU64U64, LayoutU64Var>().is_err());
+        writer.clear_faults().unwrap();
+        assert_eq!(read_keys(&reader), (0..3000).collect::<Vec<_>>());
+    }
+}
@@ -4,11 +4,14 @@ use super::*;
 #[repr(C)]
 pub struct TwoArrayTrailer {
     /// lower array length (grows up from start of the page)
-    lower_len: u16,
+    pub(crate) lower_len: u16,
     /// upper array length (grows down from end, minus this trailer)
-    upper_len: u16,
-    unused0: u16,
-    unused1: u8,
+    pub(crate) upper_len: u16,
+    pub(crate) unused0: u16,
+    /// The [`LAYOUT_ID`][crate::page::PageLayout::LAYOUT_ID] of the layout the
+    /// page was written with, or
+    /// [`LEGACY_LAYOUT_ID`][crate::page::LEGACY_LAYOUT_ID] if it predates them.
+    pub layout_id: u8,
     /// The page type identifier
     pub page_type: u8,
 }
@@ -17,6 +20,7 @@ impl core::fmt::Debug for TwoArrayTrailer {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("TwoArrayTrailer")
             .field("page_type", &self.page_type)
+            .field("layout_id", &self.layout_id)
             .field("lower_len", &self.lower_len)
             .field("upper_len", &self.upper_len)
             .finish()
@@ -48,6 +52,22 @@ impl TwoArrayLengths {
     pub fn upper_bytes<U>(&self) -> usize {
         self.upper * core::mem::size_of::<U>()
     }
+
+    /// Offset both lengths, in elements. Fails if either would go negative or
+    /// past the size of a page, which only a corrupted page can cause.
+    pub fn offset(&self, lower: isize, upper: isize) -> Result<Self, Error> {
+        let offset = |len: usize, delta: isize| {
+            len.checked_add_signed(delta)
+                .filter(|len| *len <= crate::page::CONTENT_SIZE)
+                .ok_or(Error::DataCorruption(
+                    "array length update would overflow the page",
+                ))
+        };
+        Ok(Self {
+            lower: offset(self.lower, lower)?,
+            upper: offset(self.upper, upper)?,
+        })
+    }
 }
 
 impl TwoArrayTrailer {
@@ -80,6 +100,16 @@ impl TwoArrayTrailer {
         }
     }
 
+    /// Set both lengths at once. Both are checked before either is written,
+    /// so the trailer is never left with only one of them updated.
+    #[inline]
+    pub fn set_lengths(&mut self, lower: u16, upper: u16) {
+        debug_assert!(lower <= 4088);
+        debug_assert!(upper <= 4088);
+        self.lower_len = lower;
+        self.upper_len = upper;
+    }
+
     /// Set the upper length
     #[inline]
     pub fn set_upper_len(&mut self, len: u16) {
@@ -87,16 +117,13 @@ impl TwoArrayTrailer {
         self.upper_len = len;
     }
 
-    /// Add to the upper length.
-    ///
-    /// # Safety
-    ///
-    /// The delta must not cause the length to over/underflow a `u16` value.
+    /// Add to the upper length, leaving it untouched and erroring if it would
+    /// go negative or past the size of a page.
     #[inline]
-    pub unsafe fn add_to_upper_len(&mut self, delta: isize) {
-        let len = self.upper_len as isize + delta;
-        debug_assert!(len <= 4088);
-        self.upper_len = len as u16;
+    pub fn add_to_upper_len(&mut self, delta: isize) -> Result<(), Error> {
+        let lengths = unsafe { self.lengths_unchecked() }.offset(0, delta)?;
+        self.upper_len = lengths.upper as u16;
+        Ok(())
     }
 
     /// Set the lower length
@@ -106,15 +133,12 @@ impl TwoArrayTrailer {
         self.lower_len = len;
     }
 
-    /// Add to the lower length.
-    ///
-    /// # Safety
-    ///
-    /// The delta must not cause the length to over/underflow a `u16` value.
+    /// Add to the lower length, leaving it untouched and erroring if it would
+    /// go negative or past the size of a page.
     #[inline]
-    pub unsafe fn add_to_lower_len(&mut self, delta: isize) {
-        let len = self.lower_len as isize + delta;
-        debug_assert!(len <= 4088);
-        self.lower_len = len as u16;
+    pub fn add_to_lower_len(&mut self, delta: isize) -> Result<(), Error> {
+        let lengths = unsafe { self.lengths_unchecked() }.offset(delta, 0)?;
+        self.lower_len = lengths.lower as u16;
+        Ok(())
     }
 }
@@ -0,0 +1,75 @@
+//! Allocator throughput over an anonymous map: a batch of allocations gets requested, written
+//! back, committed, and freed again, with the committer keeping up after every transaction.
+//!
+//! Allocation sizes and the order they're freed in come from a fixed seed, so every run does
+//! exactly the same work.
+
+use crab_dads::rng::Rng;
+use crab_db::{alloc_anon, Alloc, CommitUnit, WriteUnit, BLOCK_SIZE, CLUSTER_SIZE, PAGE_SIZE};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SEED: u64 = 0x5eed;
+
+/// Allocations made and freed per iteration.
+const BATCH: usize = 256;
+
+/// Run one allocate, commit, free cycle, handing back the unit for the next one.
+fn cycle(unit: WriteUnit, commit: &mut CommitUnit, lens: &[u64], order: &[usize]) -> WriteUnit {
+    let mut write = unit.write();
+    for len in lens {
+        write.new_allocation(*len).unwrap();
+    }
+    let (unit, allocs) = write.commit(b"");
+    commit.commit().unwrap();
+
+    let mut write = unit.write();
+    let placed: Vec<Alloc> = allocs.iter().map(|a| a.alloc()).collect();
+    for alloc in allocs {
+        write.use_allocation(alloc).unwrap();
+    }
+    let (unit, _) = write.commit(b"");
+    commit.commit().unwrap();
+
+    let mut write = unit.write();
+    for i in order {
+        let alloc = placed[*i];
+        write.txn_free(alloc.page.get(), alloc.len).unwrap();
+    }
+    let (unit, _) = write.commit(b"");
+    commit.commit().unwrap();
+    unit
+}
+
+fn alloc_cycle(c: &mut Criterion) {
+    // Anything over a cluster takes a whole block, so the mixed sizes stay within one
+    let mut rng = Rng::new(SEED);
+    let mixed: Vec<u64> = (0..BATCH)
+        .map(|_| (1 + rng.below(4)) * PAGE_SIZE as u64)
+        .collect();
+    let mut order: Vec<usize> = (0..BATCH).collect();
+    for i in (1..BATCH).rev() {
+        order.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let cases = [
+        ("page", vec![PAGE_SIZE as u64; BATCH]),
+        ("cluster", vec![CLUSTER_SIZE as u64; BATCH]),
+        ("mixed", mixed),
+    ];
+
+    let mut group = c.benchmark_group("alloc_cycle");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for (case, lens) in cases {
+        let (_read, unit, mut commit) = alloc_anon(64 * BLOCK_SIZE).unwrap();
+        let mut unit = Some(unit);
+        group.bench_function(BenchmarkId::from_parameter(case), |b| {
+            b.iter(|| {
+                let next = cycle(unit.take().unwrap(), &mut commit, &lens, &order);
+                unit = Some(next);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, alloc_cycle);
+criterion_main!(benches);
@@ -0,0 +1,77 @@
+//! Commit latency for a file-backed database where each transaction touches a small slice of it.
+//!
+//! The database is 1 GiB, mostly filled up front, and every iteration writes 1% of it through
+//! block-sized write allocations, commits, then frees them again. Commits only have to flush what
+//! each transaction wrote, so the time shouldn't grow with the size of the file.
+
+use crab_db::{Alloc, CommitUnit, OpenOptions, WriteUnit, BLOCK_SIZE};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+/// Size of the whole database.
+const DB_SIZE: usize = 1 << 30;
+
+/// Blocks written per iteration, about 1% of the database.
+const TOUCHED: usize = DB_SIZE / BLOCK_SIZE / 100;
+
+/// Blocks filled before measuring, leaving room for the ones written each iteration.
+const FILLED: usize = DB_SIZE / BLOCK_SIZE * 3 / 4;
+
+/// Write `count` blocks worth of allocations, each filled with `byte`, and commit them.
+fn write_blocks(
+    unit: WriteUnit,
+    commit: &mut CommitUnit,
+    count: usize,
+    byte: u8,
+) -> (WriteUnit, Vec<Alloc>) {
+    let mut write = unit.write();
+    for _ in 0..count {
+        write.new_allocation(BLOCK_SIZE as u64).unwrap();
+    }
+    let (unit, allocs) = write.commit(b"");
+
+    let mut write = unit.write();
+    let placed: Vec<Alloc> = allocs.iter().map(|a| a.alloc()).collect();
+    for mut alloc in allocs {
+        alloc.fill(byte);
+        alloc.set_written(BLOCK_SIZE);
+        write.use_allocation(alloc).unwrap();
+    }
+    let (unit, _) = write.commit(b"");
+    commit.commit().unwrap();
+    (unit, placed)
+}
+
+fn touch_one_percent(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("crab-db-bench-commit-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (_read, mut unit, mut commit) = OpenOptions::default().size(DB_SIZE).open(&path).unwrap();
+
+    // Fill most of the file in a few big commits, so the maps have plenty resident
+    for _ in 0..(FILLED / TOUCHED) {
+        unit = write_blocks(unit, &mut commit, TOUCHED, 0x11).0;
+    }
+
+    let mut unit = Some(unit);
+    let mut group = c.benchmark_group("commit");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes((TOUCHED * BLOCK_SIZE) as u64));
+    group.bench_function("touch_1_percent", |b| {
+        b.iter(|| {
+            let (next, placed) = write_blocks(unit.take().unwrap(), &mut commit, TOUCHED, 0x22);
+            let mut write = next.write();
+            for alloc in placed {
+                write.txn_free(alloc.page.get(), alloc.len).unwrap();
+            }
+            let (next, _) = write.commit(b"");
+            commit.commit().unwrap();
+            unit = Some(next);
+        })
+    });
+    group.finish();
+
+    drop((unit, commit));
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, touch_one_percent);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Read transaction spawns from several threads at once, while a writer keeps committing a root as
+//! big as a root page holds. Every spawn checks out the newest root, so the readers contend with
+//! each other and with the writer publishing new ones.
+//!
+//! There's nothing to contend with under the `single-threaded` feature, so it's empty then.
+
+#[cfg(not(feature = "single-threaded"))]
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(not(feature = "single-threaded"))]
+use crab_db::{alloc_anon, format, ReadUnit, MIN_DB_SIZE, ROOT_SIZE};
+#[cfg(not(feature = "single-threaded"))]
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// Time `iters` spawns on each of `threads` threads, all started together.
+#[cfg(not(feature = "single-threaded"))]
+fn spawn_readers(read: &ReadUnit, threads: usize, iters: u64) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..iters {
+                    drop(read.reader());
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+#[cfg(not(feature = "single-threaded"))]
+fn spawn_under_commits(c: &mut Criterion) {
+    let max = ROOT_SIZE - format::ROOT_HEADER_SIZE - format::ROOT_HASH_SIZE;
+    let (read, unit, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+
+    // The writer keeps committing until it's hung up on
+    let (hang_up, stop) = mpsc::channel::<()>();
+    let writer = thread::spawn(move || {
+        let root = vec![0x5a; max];
+        let mut unit = unit;
+        while stop.try_recv() == Err(mpsc::TryRecvError::Empty) {
+            unit = unit.write().commit(&root).0;
+            commit.commit().unwrap();
+        }
+    });
+
+    let mut group = c.benchmark_group("reader_spawn");
+    for threads in [1, 4, 8] {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+            b.iter_custom(|iters| spawn_readers(&read, threads, iters))
+        });
+    }
+    group.finish();
+
+    drop(hang_up);
+    writer.join().unwrap();
+}
+
+#[cfg(not(feature = "single-threaded"))]
+criterion_group!(benches, spawn_under_commits);
+#[cfg(not(feature = "single-threaded"))]
+criterion_main!(benches);
+
+#[cfg(feature = "single-threaded")]
+fn main() {}
@@ -0,0 +1,152 @@
+use std::{collections::VecDeque, fmt};
+
+/// What happened to a range of pages in an [`AuditRecord`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOp {
+    /// The range was handed out by the allocator
+    Alloc,
+    /// The range was given back to the allocator
+    Free,
+}
+
+/// A single allocator operation, as kept by the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// The write transaction that made the operation
+    pub txn_id: u64,
+    /// What the operation was
+    pub op: AuditOp,
+    /// The byte offset of the first page in the range
+    pub page: u64,
+    /// The length of the range, in bytes
+    pub len: u64,
+}
+
+impl AuditRecord {
+    /// Check if this record touches any part of the range starting at `page`, `len` bytes long.
+    pub fn overlaps(&self, page: u64, len: u64) -> bool {
+        self.page < page.saturating_add(len) && page < self.page.saturating_add(self.len)
+    }
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            AuditOp::Alloc => "alloc",
+            AuditOp::Free => "free",
+        };
+        write!(
+            f,
+            "txn {}: {op} 0x{:x}+0x{:x}",
+            self.txn_id, self.page, self.len
+        )
+    }
+}
+
+/// Ring buffer of the most recent allocator operations, shared through the database core.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    capacity: usize,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add a record, dropping the oldest one if the log is full.
+    pub fn push(&mut self, record: AuditRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Every record still in the log, oldest first.
+    pub fn snapshot(&self) -> Vec<AuditRecord> {
+        self.records.iter().copied().collect()
+    }
+
+    /// The records still in the log that touch the given range, oldest first.
+    pub fn history(&self, page: u64, len: u64) -> AuditHistory {
+        AuditHistory(
+            self.records
+                .iter()
+                .filter(|r| r.overlaps(page, len))
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+/// The audit records for a range of pages, attached to errors like
+/// [`AllocError::DoubleFree`][crate::AllocError::DoubleFree].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditHistory(pub Vec<AuditRecord>);
+
+impl fmt::Display for AuditHistory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str(" (no audit history for it)");
+        }
+        f.write_str(". Audit history: ")?;
+        for (i, record) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{record}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(txn_id: u64, op: AuditOp, page: u64) -> AuditRecord {
+        AuditRecord {
+            txn_id,
+            op,
+            page,
+            len: 0x1000,
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut log = AuditLog::new(3);
+        for i in 0..5 {
+            log.push(record(i, AuditOp::Alloc, i * 0x1000));
+        }
+        let ids: Vec<u64> = log.snapshot().iter().map(|r| r.txn_id).collect();
+        assert_eq!(ids, [2, 3, 4]);
+
+        // Only records touching the range make it into the history
+        log.push(record(5, AuditOp::Free, 0x3000));
+        let history = log.history(0x3000, 0x1000);
+        assert_eq!(
+            history,
+            AuditHistory(vec![
+                record(3, AuditOp::Alloc, 0x3000),
+                record(5, AuditOp::Free, 0x3000)
+            ])
+        );
+        assert_eq!(
+            history.to_string(),
+            ". Audit history: txn 3: alloc 0x3000+0x1000, txn 5: free 0x3000+0x1000"
+        );
+
+        // A zero-sized log keeps nothing
+        let mut log = AuditLog::new(0);
+        log.push(record(0, AuditOp::Alloc, 0));
+        assert!(log.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    future::poll_fn,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+/// Lets async tasks wait on a commit becoming durable, without blocking a thread to do it.
+///
+/// Get one from [`CommitUnit::notify`][crate::CommitUnit::notify]. The commit unit updates it
+/// every time a commit finishes, waking up any task whose transaction ID is now covered. No
+/// executor is involved; the waiting is plain [`Waker`] bookkeeping, so it works under any
+/// runtime.
+///
+/// Committing itself still blocks, so from async code it belongs on a blocking thread, eg. with
+/// tokio's `spawn_blocking`, and the other tasks wait on [`durable`][Self::durable].
+#[derive(Debug)]
+pub struct CommitNotify {
+    inner: Mutex<NotifyInner>,
+}
+
+#[derive(Debug)]
+struct NotifyInner {
+    /// The newest transaction ID that's been committed
+    last: u64,
+    /// Tasks waiting on a commit, along with the ID each is waiting for
+    waiting: Vec<(u64, Waker)>,
+}
+
+impl CommitNotify {
+    pub(crate) fn new(last: u64) -> Self {
+        Self {
+            inner: Mutex::new(NotifyInner {
+                last,
+                waiting: Vec::new(),
+            }),
+        }
+    }
+
+    /// The newest transaction ID that's been committed.
+    pub fn last_committed(&self) -> u64 {
+        self.inner.lock().unwrap().last
+    }
+
+    /// Wait until the transaction with the given ID, and everything before it, has been
+    /// committed. Resolves immediately if it already has been.
+    pub async fn durable(&self, id: u64) {
+        poll_fn(|cx| self.poll_durable(id, cx)).await
+    }
+
+    fn poll_durable(&self, id: u64, cx: &mut Context<'_>) -> Poll<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.last >= id {
+            return Poll::Ready(());
+        }
+        // Polling again from the same task shouldn't pile up more wakers
+        match inner
+            .waiting
+            .iter_mut()
+            .find(|(want, waker)| *want == id && waker.will_wake(cx.waker()))
+        {
+            Some((_, waker)) => waker.clone_from(cx.waker()),
+            None => inner.waiting.push((id, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+
+    /// Record that everything up to `id` has been committed, and wake up whoever was waiting on
+    /// it.
+    pub(crate) fn committed(&self, id: u64) {
+        let ready: Vec<Waker> = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.last = inner.last.max(id);
+            let last = inner.last;
+            let (ready, waiting) = inner.waiting.drain(..).partition(|(want, _)| *want <= last);
+            inner.waiting = waiting;
+            ready.into_iter().map(|(_, waker)| waker).collect()
+        };
+        // Wake outside the lock, in case a task gets polled right away
+        for waker in ready {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn durable() {
+        let notify = CommitNotify::new(3);
+        assert_eq!(notify.last_committed(), 3);
+
+        // Already-committed IDs don't wait at all
+        block_on(notify.durable(1));
+        block_on(notify.durable(3));
+
+        // Waiting on a later ID resolves once a commit from another thread covers it
+        let notify = Arc::new(notify);
+        let committer = {
+            let notify = notify.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                notify.committed(4);
+                thread::sleep(Duration::from_millis(20));
+                notify.committed(6);
+            })
+        };
+        block_on(notify.durable(5));
+        assert_eq!(notify.last_committed(), 6);
+        committer.join().unwrap();
+        assert!(notify.inner.lock().unwrap().waiting.is_empty());
+
+        // IDs never go backwards
+        notify.committed(2);
+        assert_eq!(notify.last_committed(), 6);
+    }
+}
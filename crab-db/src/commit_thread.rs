@@ -0,0 +1,142 @@
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use crate::{AllocError, CommitUnit};
+
+type CommitResult = Result<u64, AllocError>;
+
+/// Runs a [`CommitUnit`] on its own thread, committing whenever asked to.
+///
+/// Get one from [`CommitUnit::spawn`]. Each call to [`request_commit`][Self::request_commit] hands
+/// back a [`CommitTicket`] to wait on. Requests that pile up while a commit is running are all
+/// covered by the next one, so asking often doesn't mean committing once per request.
+///
+/// Dropping the handle commits one last time and joins the thread. Any error from that final
+/// commit has nowhere to go, so wait on a ticket first if it matters.
+#[derive(Debug)]
+pub struct CommitHandle {
+    requests: Option<Sender<Sender<CommitResult>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CommitHandle {
+    pub(crate) fn new(unit: CommitUnit) -> Self {
+        let (requests, recv) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("crab-db-commit".into())
+            .spawn(move || run(unit, recv))
+            .expect("failed to spawn the committer thread");
+        Self {
+            requests: Some(requests),
+            thread: Some(thread),
+        }
+    }
+
+    /// Ask for everything written so far to be committed. The returned ticket resolves once it's
+    /// on disk, or with the error that kept it from getting there.
+    pub fn request_commit(&self) -> CommitTicket {
+        let (send, recv) = mpsc::channel();
+        if let Some(requests) = &self.requests {
+            // If the thread is gone, the ticket finds out when it waits
+            let _ = requests.send(send);
+        }
+        CommitTicket(recv)
+    }
+}
+
+impl Drop for CommitHandle {
+    fn drop(&mut self) {
+        // Closing the request channel is what tells the thread to wrap up
+        drop(self.requests.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A pending commit requested through [`CommitHandle::request_commit`].
+#[derive(Debug)]
+pub struct CommitTicket(Receiver<CommitResult>);
+
+impl CommitTicket {
+    /// Block until the commit is on disk, returning the ID of the newest durable transaction.
+    ///
+    /// A failed commit's error goes to exactly one ticket. Any other tickets it would have
+    /// covered get a fresh attempt of their own instead of a copy of it. Fails with
+    /// [`AllocError::CommitterGone`] if the committer thread panicked first.
+    pub fn wait(self) -> Result<u64, AllocError> {
+        self.0.recv().unwrap_or(Err(AllocError::CommitterGone))
+    }
+}
+
+/// The committer thread: commit for every batch of requests, then once more when the handle is
+/// dropped.
+fn run(mut unit: CommitUnit, requests: Receiver<Sender<CommitResult>>) {
+    while let Ok(first) = requests.recv() {
+        let mut waiting: VecDeque<_> = [first].into();
+        waiting.extend(requests.try_iter());
+        while let Some(ticket) = waiting.pop_front() {
+            match unit.commit() {
+                Ok(id) => {
+                    // Tickets that were dropped without waiting just miss out
+                    for ticket in [ticket].into_iter().chain(waiting.drain(..)) {
+                        let _ = ticket.send(Ok(id));
+                    }
+                }
+                Err(e) => {
+                    let _ = ticket.send(Err(e));
+                }
+            }
+        }
+    }
+    let _ = unit.commit();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::TempPath, AllocErrorKind, OpenOptions, PAGE_SIZE};
+
+    #[test]
+    fn commit_tickets() {
+        let path = TempPath::new("commit-thread");
+        let (read, unit, commit) = OpenOptions::default().open(&path).unwrap();
+        let core = commit.core.clone();
+        let opened = commit.committed_id();
+        let handle = commit.spawn();
+
+        // Waiting on a ticket means the transaction before it is durable
+        let unit = unit.write().commit(b"one").0;
+        let ticket = handle.request_commit();
+        assert_eq!(ticket.wait().unwrap(), unit.generation());
+        assert_eq!(unit.generation(), opened + 1);
+
+        // A failed flush shows up on the ticket, and a later one can still succeed
+        let unit = unit.write().commit(b"two").0;
+        core.storage.lock().unwrap().fail_range_flushes(true);
+        let ticket = handle.request_commit();
+        assert_eq!(ticket.wait().unwrap_err().kind(), AllocErrorKind::Sync);
+        core.storage.lock().unwrap().fail_range_flushes(false);
+        assert_eq!(handle.request_commit().wait().unwrap(), unit.generation());
+
+        // Several tickets can be outstanding at once
+        let mut write = unit.write();
+        write.0.take_available(PAGE_SIZE as u64).unwrap();
+        let unit = write.commit(b"three").0;
+        let tickets: Vec<_> = (0..4).map(|_| handle.request_commit()).collect();
+        for ticket in tickets {
+            assert_eq!(ticket.wait().unwrap(), unit.generation());
+        }
+
+        // Dropping the handle commits whatever is left
+        let unit = unit.write().commit(b"four").0;
+        let last = unit.generation();
+        drop(handle);
+        drop((read, unit, core));
+        let (read, unit, _commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(&read.reader().root.root[..], b"four");
+        assert_eq!(unit.generation(), last);
+    }
+}
@@ -30,15 +30,207 @@ pub enum AllocError {
         requested: usize,
         source: std::io::Error,
     },
+    /// Growing the database would take it past the limit set by [`OpenOptions::max_size`]
+    ///
+    /// [`OpenOptions::max_size`]: crate::OpenOptions::max_size
+    #[error("Database is limited to 0x{limit:x} bytes, but growing it needed 0x{requested:x}")]
+    DatabaseFull { limit: u64, requested: u64 },
     #[error("Punching a hole in the sparse memory map failed")]
     HolePunch(#[source] std::io::Error),
-    /// Other, miscellaneous errors
-    #[error("Other: {0}")]
-    Other(&'static str),
+    /// Couldn't pass a hint about how a range will be used on to the OS
+    #[error("Advising the OS about a range of the memory map failed")]
+    Advise(#[source] std::io::Error),
+    /// Another writer already exists for this database
+    #[error("A writer is already active on this database")]
+    WriterActive,
+    /// A write allocation was handed to a different database than the one it came from
+    #[error("Write allocation belongs to a different database")]
+    ForeignAllocation,
+    /// A commit that needed every write allocation back found some still outstanding
+    #[error("{count} write allocations handed out by earlier commits haven't come back yet")]
+    OutstandingAllocations { count: usize },
+    /// Tried to abort the first transaction of a brand-new database, which has nothing to go back
+    /// to
+    #[error("Can't abort the very first transaction of the database")]
+    AbortFirstTransaction,
+    /// Asked to commit up to a transaction that's already durable, or that doesn't exist yet
+    #[error(
+        "Can't commit up to transaction {id}, as {committed} is already durable and {newest} is the newest"
+    )]
+    CommitOutOfRange { id: u64, committed: u64, newest: u64 },
+    /// Asked to commit up to a transaction too far behind the newest one to still be known
+    #[error("Transaction {id} is too far behind the newest to be committed on its own")]
+    CommitHistoryGone { id: u64 },
+    /// A commit ticket lost its committer thread before getting an answer
+    #[error("The committer thread panicked before the commit finished")]
+    CommitterGone,
+    /// Two transactions from different databases were compared against each other
+    #[error("Transactions belong to different databases")]
+    ForeignTransaction,
+    /// The same tree root was tracked twice in one write transaction
+    #[error("Tree root \"{0}\" is already being tracked")]
+    DuplicateRoot(String),
+    /// Tracked tree roots were neither updated nor marked unchanged before committing
+    #[error("Tree roots were neither updated nor marked unchanged before committing: {0:?}")]
+    UnresolvedRoots(Vec<String>),
+    /// The root data, with its header and hash, doesn't fit in a root page
+    #[error(
+        "Root data is 0x{len:x} bytes with its header and hash, over the 0x{max:x} bytes a root page can hold"
+    )]
+    RootTooLarge { len: usize, max: usize },
+    /// A tree root's name is too long to store in the root data
+    #[error("Tree root name is 0x{len:x} bytes, longer than the 0xffff bytes allowed")]
+    RootNameTooLong { len: usize },
+    /// A mutex shared between the units was poisoned by a panic while it was held
+    #[error("A mutex shared between the database units was poisoned")]
+    MutexPoisoned,
+    /// The system's page size is one the allocator can't work with
+    #[error(
+        "System page size is 0x{found:x} bytes, but it must be a power of two no larger than 1 MiB"
+    )]
+    UnsupportedPageSize { found: usize },
+    /// The size asked for when opening wasn't a whole number of blocks, or was below the minimum
+    #[error(
+        "Size 0x{size:x} isn't a multiple of the 1 MiB block size, or is below the 4 MiB minimum"
+    )]
+    InvalidSize { size: usize },
+    /// The backing file is too large to memory-map on this architecture
+    #[error("The backing file is 0x{len:x} bytes, too large to memory-map on this architecture")]
+    FileTooLarge { len: u64 },
+    /// An internal invariant didn't hold. This is always a bug in the allocator.
+    #[error("Internal error: {0}")]
+    Internal(&'static str),
+    /// A page offset wasn't aligned to [`ALLOC_ALIGN`][crate::ALLOC_ALIGN]
+    #[error("Tried to access offset 0x{offset:x}, which isn't aligned to a 4 kiB page")]
+    Misaligned { offset: usize },
+    /// A page offset pointed into the root pages, where no data can ever live
+    #[error("Tried to access offset 0x{offset:x}, which is inside the root pages")]
+    RootAccess { offset: usize },
+    /// A range of pages didn't fit in the address space
+    #[error("Range of 0x{len:x} bytes at offset 0x{offset:x} doesn't fit in the address space")]
+    RangeOverflow { offset: u64, len: u64 },
+    /// A byte offset had some of the reserved bits outside of
+    /// [`PAGE_NUM_MASK`][crate::PAGE_NUM_MASK] set
+    #[error("Byte offset 0x{page:x} has reserved upper bits set")]
+    ReservedPageBits { page: u64 },
+    /// Tried to write to pages that weren't allocated or dirtied by the current write transaction
+    #[error("Tried to access 0x{len:x} bytes at offset 0x{offset:x}, which aren't owned by the current write transaction")]
+    NotOwned { offset: usize, len: usize },
+    /// No free space was big enough for an allocation
+    #[error("No free space can hold an allocation of 0x{len:x} bytes")]
+    NoSpace { len: u64 },
+    /// Pages were freed while some of them were already free
+    #[cfg(not(feature = "alloc-audit"))]
+    #[error("Freed 0x{len:x} bytes at 0x{page:x}, but some of them were already free")]
+    DoubleFree { page: u64, len: u64 },
+    /// Pages were freed while some of them were already free. Carries whatever the audit log
+    /// still remembers about them.
+    #[cfg(feature = "alloc-audit")]
+    #[error("Freed 0x{len:x} bytes at 0x{page:x}, but some of them were already free{history}")]
+    DoubleFree {
+        page: u64,
+        len: u64,
+        history: crate::AuditHistory,
+    },
     #[error("Invalid access on the memory map was attempted. Tried to get slice at offset 0x{offset:x} with length 0x{len:x}")]
     InvalidAccess { offset: usize, len: usize },
+    /// A range ran from one memory map into the next. Maps always meet on a block boundary, and
+    /// allocations never cross one, so this is always a bug.
+    #[error("Range of 0x{len:x} bytes at 0x{offset:x} crosses the map boundary at 0x{boundary:x}")]
+    SplitAcrossMaps {
+        offset: usize,
+        len: usize,
+        boundary: usize,
+    },
+}
+
+impl AllocError {
+    /// Get the kind of error this is, which can be compared against an expected one. The
+    /// details carried by the error, including any I/O error underneath, are left behind.
+    pub fn kind(&self) -> AllocErrorKind {
+        match self {
+            Self::Open(_) => AllocErrorKind::Open,
+            Self::DataFormat(e) => AllocErrorKind::DataFormat(e.kind()),
+            Self::Lock(_) => AllocErrorKind::Lock,
+            Self::Sync(_) => AllocErrorKind::Sync,
+            Self::ResizeFailed { .. } => AllocErrorKind::ResizeFailed,
+            Self::AllocFailed { .. } => AllocErrorKind::AllocFailed,
+            Self::DatabaseFull { .. } => AllocErrorKind::DatabaseFull,
+            Self::HolePunch(_) => AllocErrorKind::HolePunch,
+            Self::Advise(_) => AllocErrorKind::Advise,
+            Self::WriterActive => AllocErrorKind::WriterActive,
+            Self::ForeignAllocation => AllocErrorKind::ForeignAllocation,
+            Self::OutstandingAllocations { .. } => AllocErrorKind::OutstandingAllocations,
+            Self::AbortFirstTransaction => AllocErrorKind::AbortFirstTransaction,
+            Self::CommitOutOfRange { .. } => AllocErrorKind::CommitOutOfRange,
+            Self::CommitHistoryGone { .. } => AllocErrorKind::CommitHistoryGone,
+            Self::CommitterGone => AllocErrorKind::CommitterGone,
+            Self::ForeignTransaction => AllocErrorKind::ForeignTransaction,
+            Self::DuplicateRoot(_) => AllocErrorKind::DuplicateRoot,
+            Self::UnresolvedRoots(_) => AllocErrorKind::UnresolvedRoots,
+            Self::RootTooLarge { .. } => AllocErrorKind::RootTooLarge,
+            Self::RootNameTooLong { .. } => AllocErrorKind::RootNameTooLong,
+            Self::MutexPoisoned => AllocErrorKind::MutexPoisoned,
+            Self::UnsupportedPageSize { .. } => AllocErrorKind::UnsupportedPageSize,
+            Self::InvalidSize { .. } => AllocErrorKind::InvalidSize,
+            Self::FileTooLarge { .. } => AllocErrorKind::FileTooLarge,
+            Self::Internal(_) => AllocErrorKind::Internal,
+            Self::Misaligned { .. } => AllocErrorKind::Misaligned,
+            Self::RootAccess { .. } => AllocErrorKind::RootAccess,
+            Self::RangeOverflow { .. } => AllocErrorKind::RangeOverflow,
+            Self::ReservedPageBits { .. } => AllocErrorKind::ReservedPageBits,
+            Self::NotOwned { .. } => AllocErrorKind::NotOwned,
+            Self::NoSpace { .. } => AllocErrorKind::NoSpace,
+            Self::DoubleFree { .. } => AllocErrorKind::DoubleFree,
+            Self::InvalidAccess { .. } => AllocErrorKind::InvalidAccess,
+            Self::SplitAcrossMaps { .. } => AllocErrorKind::SplitAcrossMaps,
+        }
+    }
 }
 
+/// The kind of an [`AllocError`], with one variant for each of its variants. Unlike the error
+/// itself, a kind can be copied and compared, which makes it handy for tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AllocErrorKind {
+    Open,
+    DataFormat(FormatErrorKind),
+    Lock,
+    Sync,
+    ResizeFailed,
+    AllocFailed,
+    DatabaseFull,
+    HolePunch,
+    Advise,
+    WriterActive,
+    ForeignAllocation,
+    OutstandingAllocations,
+    AbortFirstTransaction,
+    CommitOutOfRange,
+    CommitHistoryGone,
+    CommitterGone,
+    ForeignTransaction,
+    DuplicateRoot,
+    UnresolvedRoots,
+    RootTooLarge,
+    RootNameTooLong,
+    MutexPoisoned,
+    UnsupportedPageSize,
+    InvalidSize,
+    FileTooLarge,
+    Internal,
+    Misaligned,
+    RootAccess,
+    RangeOverflow,
+    ReservedPageBits,
+    NotOwned,
+    NoSpace,
+    DoubleFree,
+    InvalidAccess,
+    SplitAcrossMaps,
+}
+
+/// Something in the database file doesn't make sense
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum FormatError {
@@ -48,10 +240,73 @@ pub enum FormatError {
     RootHash,
     #[error("File size is incorrect - too small or not a valid number of 1 MiB blocks")]
     FileSize,
+    #[error(
+        "File is 0x{actual:x} bytes, shorter than the 0x{recorded:x} bytes the root page recorded"
+    )]
+    Truncated { recorded: u64, actual: u64 },
     #[error("Invalid page type {0}")]
     PageType(u8),
     #[error("Invalid Leaf Page")]
     LeafPage,
     #[error("Invalid Branch Page")]
     BranchPage,
+    #[error("Invalid freelist page")]
+    Freelist,
+    #[error("Freelist page at 0x{page:x} doesn't match its hash")]
+    FreelistHash { page: u64 },
+    #[error("Invalid tree root payload")]
+    RootPayload,
+    #[error(
+        "File was created with a {field} of 0x{stored:x} bytes, but this build uses 0x{compiled:x}"
+    )]
+    Geometry {
+        field: &'static str,
+        stored: u64,
+        compiled: u64,
+    },
+    #[error(
+        "File type is \"{}\", but \"{}\" was expected",
+        found.escape_ascii(),
+        expected.escape_ascii()
+    )]
+    FileType { expected: [u8; 8], found: [u8; 8] },
+}
+
+impl FormatError {
+    /// Get the kind of format error this is, which can be compared against an expected one.
+    pub fn kind(&self) -> FormatErrorKind {
+        match self {
+            Self::DuplicateIds => FormatErrorKind::DuplicateIds,
+            Self::RootHash => FormatErrorKind::RootHash,
+            Self::FileSize => FormatErrorKind::FileSize,
+            Self::Truncated { .. } => FormatErrorKind::Truncated,
+            Self::PageType(_) => FormatErrorKind::PageType,
+            Self::LeafPage => FormatErrorKind::LeafPage,
+            Self::BranchPage => FormatErrorKind::BranchPage,
+            Self::Freelist => FormatErrorKind::Freelist,
+            Self::FreelistHash { .. } => FormatErrorKind::FreelistHash,
+            Self::RootPayload => FormatErrorKind::RootPayload,
+            Self::Geometry { .. } => FormatErrorKind::Geometry,
+            Self::FileType { .. } => FormatErrorKind::FileType,
+        }
+    }
+}
+
+/// The kind of a [`FormatError`], with one variant for each of its variants. Also reachable through
+/// [`AllocErrorKind::DataFormat`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FormatErrorKind {
+    DuplicateIds,
+    RootHash,
+    FileSize,
+    Truncated,
+    PageType,
+    LeafPage,
+    BranchPage,
+    Freelist,
+    FreelistHash,
+    RootPayload,
+    Geometry,
+    FileType,
 }
@@ -0,0 +1,207 @@
+//! Where every byte the allocator writes to disk goes, as constants derived from the structs that
+//! actually get written.
+//!
+//! The file starts with two root pages, [`ROOT_SIZE`] bytes each, at [`ROOT_SLOT_0`] and
+//! [`ROOT_SLOT_1`]. Commits alternate between them, so the older one survives a torn write. Each
+//! holds a root header, then a payload as long as the header's [`ROOT_PAYLOAD_LEN`] field says,
+//! then a [`ROOT_HASH_SIZE`]-byte xxh3 hash of the header and payload together. The payload is the list of tree roots described
+//! on [`TxnRoots`][crate::TxnRoots].
+//!
+//! Freelist pages are [`IntPage`][crate::int_page::IntPage]s, which end in a header of their own,
+//! starting with an xxh3 hash of the rest of the page, and pack each pair's key and value lengths
+//! into a single byte. All multi-byte integers are
+//! little-endian. The golden-file tests in this module pin the whole encoding, so changing any of
+//! it means regenerating the fixtures on purpose.
+
+use std::mem::{offset_of, size_of};
+
+use crate::{int_page::h, RootHeader, ROOT_SIZE};
+
+/// Offset of the first root page in the file.
+pub const ROOT_SLOT_0: usize = 0;
+
+/// Offset of the second root page in the file.
+pub const ROOT_SLOT_1: usize = ROOT_SIZE;
+
+/// Size of the root header at the start of each root page.
+pub const ROOT_HEADER_SIZE: usize = size_of::<RootHeader>();
+
+/// Offset of the 8-byte file type tag in a root page.
+pub const ROOT_FILE_TYPE: usize = offset_of!(RootHeader, file_type);
+
+/// Offset of the root payload's length, as a `u16`.
+pub const ROOT_PAYLOAD_LEN: usize = offset_of!(RootHeader, len);
+
+/// Offset of the root format version byte.
+pub const ROOT_VERSION: usize = offset_of!(RootHeader, version);
+
+/// Offset of the geometry record: the log2 of the block, cluster, root, and page sizes the file was
+/// created with, one byte each. All zero means it wasn't recorded, and is taken to match.
+pub const ROOT_GEOMETRY: usize = offset_of!(RootHeader, geometry);
+
+/// Offset of the file length the root page was committed with, as a `u64`.
+pub const ROOT_FILE_LEN: usize = offset_of!(RootHeader, file_len);
+
+/// Offset of the commit id, as a `u64`.
+pub const ROOT_ID: usize = offset_of!(RootHeader, id);
+
+/// Offset of the freelist head page, as a `u64`. Zero means the freelist is empty.
+pub const ROOT_FREELIST: usize = offset_of!(RootHeader, freelist);
+
+/// Size of the hash following the root payload.
+pub const ROOT_HASH_SIZE: usize = 8;
+
+/// The current root format version. Version 2 added the hash to every freelist page, so files
+/// from version 1 can't be opened anymore.
+pub const ROOT_FORMAT_VERSION: u8 = 2;
+
+/// Page type byte for a freelist leaf page, mapping the start of each free run to its length in
+/// bytes.
+pub const FREELIST_LEAF: u8 = 0x11;
+
+/// Page type byte for a freelist branch page, mapping the first key of each child page to that
+/// child's page offset.
+pub const FREELIST_BRANCH: u8 = 0x10;
+
+/// Size of the header at the end of an [`IntPage`][crate::int_page::IntPage].
+pub const INT_PAGE_HEADER_SIZE: usize = size_of::<h::Header>();
+
+/// Offset of the header within an [`IntPage`][crate::int_page::IntPage].
+pub const INT_PAGE_HEADER_OFFSET: usize = crate::PAGE_SIZE - INT_PAGE_HEADER_SIZE;
+
+/// Offset of the page's xxh3 hash, as a `u64`. It covers every byte in the page besides itself.
+pub const INT_PAGE_HASH: usize = INT_PAGE_HEADER_OFFSET + h::HASH;
+
+/// Offset of the number of pairs in the page, as a `u16`.
+pub const INT_PAGE_LEN: usize = INT_PAGE_HEADER_OFFSET + h::LEN;
+
+/// Offset of the end of the data section, as a `u16`.
+pub const INT_PAGE_END: usize = INT_PAGE_HEADER_OFFSET + h::END;
+
+/// Offset of the page type byte.
+pub const INT_PAGE_TYPE: usize = INT_PAGE_HEADER_OFFSET + h::PAGE_TYPE;
+
+/// Bits of the pair count and data end that are used. The rest are ignored.
+pub const INT_PAGE_HEADER_MASK: u16 = 0xFFF;
+
+/// Bits of a length byte holding 8 minus the number of bytes stored for the key.
+pub const INT_KEY_LEN_MASK: u8 = 0x07;
+
+/// Bits of a length byte holding 8 minus the number of bytes stored for the value.
+pub const INT_VALUE_LEN_MASK: u8 = 0x78;
+
+/// Shift of the value's bits within a length byte.
+pub const INT_VALUE_LEN_SHIFT: u32 = 3;
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        int_page::IntPage, tests::at, ByteOffset, RootData, TxnRoots, MIN_DB_SIZE, ROOT_MAP_SIZE,
+    };
+
+    /// Compare against a checked-in fixture, or rewrite it if `UPDATE_FIXTURES` is set.
+    fn check_fixture(name: &str, bytes: &[u8]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name);
+        if std::env::var_os("UPDATE_FIXTURES").is_some() {
+            std::fs::write(&path, bytes).unwrap();
+            return;
+        }
+        let expected = std::fs::read(&path)
+            .unwrap_or_else(|e| panic!("couldn't read fixture {}: {e}", path.display()));
+        if let Some(at) =
+            (0..bytes.len().max(expected.len())).find(|i| bytes.get(*i) != expected.get(*i))
+        {
+            panic!(
+                "{name} no longer matches its fixture, starting at byte {at}. If the format change \
+                 is intentional, rerun with UPDATE_FIXTURES=1 and commit the new fixture."
+            );
+        }
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!((ROOT_SLOT_0, ROOT_SLOT_1), (0, 0x4000));
+        assert_eq!(ROOT_HEADER_SIZE, 40);
+        assert_eq!(ROOT_FILE_TYPE, 0);
+        assert_eq!(ROOT_PAYLOAD_LEN, 8);
+        assert_eq!(ROOT_VERSION, 10);
+        assert_eq!(ROOT_GEOMETRY, 12);
+        assert_eq!(ROOT_FILE_LEN, 16);
+        assert_eq!(ROOT_ID, 24);
+        assert_eq!(ROOT_FREELIST, 32);
+        assert_eq!(INT_PAGE_HEADER_SIZE, 14);
+        assert_eq!(INT_PAGE_HASH, 4082);
+        assert_eq!(INT_PAGE_LEN, 4090);
+        assert_eq!(INT_PAGE_END, 4092);
+        assert_eq!(INT_PAGE_TYPE, 4095);
+    }
+
+    #[test]
+    fn golden_root() {
+        let mut roots = TxnRoots::default();
+        let slot = roots.track("accounts", at(0x12_3000), [1, 1]).unwrap();
+        roots.unchanged(slot);
+        let slot = roots.track("names", at(0x45_6000), [3, 3]).unwrap();
+        roots.unchanged(slot);
+
+        let mut root = RootData::new(
+            b"crab-db\0",
+            ByteOffset::new(ROOT_MAP_SIZE as u64).unwrap(),
+            MIN_DB_SIZE as u64,
+        );
+        root.id_tracker.set_newest(7);
+        root.root = roots.payload().unwrap().into();
+        let mut bytes = Vec::new();
+        root.store(&mut bytes).unwrap();
+
+        let payload_len =
+            u16::from_le_bytes([bytes[ROOT_PAYLOAD_LEN], bytes[ROOT_PAYLOAD_LEN + 1]]);
+        assert_eq!(payload_len as usize, root.root.len());
+        assert_eq!(
+            bytes.len(),
+            ROOT_HEADER_SIZE + root.root.len() + ROOT_HASH_SIZE
+        );
+        assert_eq!(bytes[ROOT_VERSION], ROOT_FORMAT_VERSION);
+        assert_eq!(bytes[ROOT_GEOMETRY..ROOT_GEOMETRY + 4], [20, 14, 14, 12]);
+        assert_eq!(bytes[ROOT_ID..ROOT_ID + 8], 7u64.to_le_bytes());
+        check_fixture("root.bin", &bytes);
+    }
+
+    #[test]
+    fn golden_int_page() {
+        let mut mem = vec![0u8; 2 * crate::PAGE_SIZE];
+        let ptr = mem
+            .as_mut_ptr()
+            .wrapping_add(mem.as_mut_ptr().align_offset(crate::PAGE_SIZE));
+        let mut page = unsafe { IntPage::new(ptr, FREELIST_LEAF) };
+        let pairs = [
+            (0, 0),
+            (1, 0x100),
+            (0x1234, 0x4000),
+            (0x10_0000, 0x00ab_cdef_0123_4567),
+        ];
+        for (k, v) in pairs {
+            assert_eq!(page.insert(k, v), Ok(None));
+        }
+        assert_eq!(page.iter().collect::<Vec<_>>(), pairs);
+        page.seal();
+        assert!(page.hash_matches());
+        let page = unsafe { std::slice::from_raw_parts(ptr, crate::PAGE_SIZE) };
+
+        assert_eq!(
+            u16::from_le_bytes([page[INT_PAGE_LEN], page[INT_PAGE_LEN + 1]]),
+            4
+        );
+        assert_eq!(page[INT_PAGE_TYPE], FREELIST_LEAF);
+        // Key 0 keeps one byte, and value 0 none at all
+        let first = page[INT_PAGE_HEADER_OFFSET - 1];
+        assert_eq!(first & INT_KEY_LEN_MASK, 7);
+        assert_eq!((first & INT_VALUE_LEN_MASK) >> INT_VALUE_LEN_SHIFT, 8);
+        check_fixture("int_page.bin", page);
+    }
+}
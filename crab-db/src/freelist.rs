@@ -0,0 +1,724 @@
+//! The on-disk freelist: a B-tree of [`IntPage`]s mapping the start of each free run to its
+//! length in bytes. Leaf pages hold the runs, and branch pages map the first key of each child to
+//! the child's page offset. Every page is sealed with a hash, so a torn or stray write to one
+//! shows up as [`FormatError::FreelistHash`], unless
+//! [`verify_checksums`][crate::OpenOptions::verify_checksums] turned the check off.
+//!
+//! A commit that changes the free space writes out a whole new freelist on pages taken from the
+//! availability lists, and frees the old one like any other pages, so readers of older snapshots
+//! can keep walking it.
+
+use std::{iter::FusedIterator, marker::PhantomData, sync::Arc};
+
+use crate::{
+    error::FormatError,
+    format::{FREELIST_BRANCH, FREELIST_LEAF, INT_PAGE_HEADER_SIZE},
+    int_page::IntPage,
+    AllocError, BlockRange, DbCore, ByteOffset, RawMemory, ReadTxn, PAGE_SIZE, ROOT_MAP_SIZE,
+};
+
+/// No sane freelist gets anywhere near this deep. Hitting it means there's a cycle.
+const MAX_DEPTH: usize = 16;
+
+/// One loaded level of the freelist, with its remaining entries stored in reverse order.
+struct Level {
+    leaf: bool,
+    entries: Vec<(u64, u64)>,
+}
+
+/// Lazily walks a snapshot's on-disk freelist, yielding each free run as an `(offset, len)` pair
+/// in ascending order. Only one page per level of the freelist is held in memory at a time.
+///
+/// Corruption is reported as an error item, after which the walker is exhausted.
+pub(crate) struct FreelistWalker {
+    storage: RawMemory,
+    core: Arc<DbCore>,
+    stack: Vec<Level>,
+    pending: Option<u64>,
+    /// Every page loaded so far
+    pages: Vec<u64>,
+}
+
+impl FreelistWalker {
+    /// Set up a walker for the freelist with the given head page. A head of 0 is an empty
+    /// freelist.
+    ///
+    /// # Safety
+    ///
+    /// The freelist pages must not be freed or reused for as long as the walker is alive, which
+    /// is normally upheld by holding onto the read transaction the head page came from.
+    pub unsafe fn new(storage: RawMemory, core: Arc<DbCore>, head: ByteOffset) -> Self {
+        let head = head.get();
+        Self {
+            storage,
+            core,
+            stack: Vec::new(),
+            pending: (head != 0).then_some(head),
+            pages: Vec::new(),
+        }
+    }
+
+    fn load(&mut self, page: u64) -> Result<Level, AllocError> {
+        let corrupt = || AllocError::DataFormat(FormatError::Freelist);
+        // Child pages come straight off of disk, so make sure they're really page numbers
+        let range = BlockRange::from_pages(page, 1)?;
+        if !range.is_aligned() {
+            return Err(corrupt());
+        }
+        let mem = unsafe { self.storage.get(&self.core, range) }.map_err(|_| corrupt())?;
+        self.pages.push(page);
+        let int_page = unsafe { IntPage::load(mem.as_mut_ptr()) }.map_err(|_| corrupt())?;
+        if self.core.verify_checksums && !int_page.hash_matches() {
+            return Err(AllocError::DataFormat(FormatError::FreelistHash { page }));
+        }
+        int_page.validate().map_err(|_| corrupt())?;
+        let leaf = match int_page.page_type() {
+            FREELIST_LEAF => true,
+            FREELIST_BRANCH => false,
+            t => return Err(AllocError::DataFormat(FormatError::PageType(t))),
+        };
+        let mut entries: Vec<(u64, u64)> = int_page.iter().collect();
+        entries.reverse();
+        Ok(Level { leaf, entries })
+    }
+}
+
+impl Iterator for FreelistWalker {
+    type Item = Result<(u64, u64), AllocError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(page) = self.pending.take() {
+                if self.stack.len() >= MAX_DEPTH {
+                    self.stack.clear();
+                    return Some(Err(AllocError::DataFormat(FormatError::Freelist)));
+                }
+                match self.load(page) {
+                    Ok(level) => self.stack.push(level),
+                    Err(e) => {
+                        self.stack.clear();
+                        return Some(Err(e));
+                    }
+                }
+            }
+            let level = self.stack.last_mut()?;
+            match level.entries.pop() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(entry) if level.leaf => return Some(Ok(entry)),
+                Some((_, child)) => self.pending = Some(child),
+            }
+        }
+    }
+}
+
+/// Checks the runs coming out of a [`FreelistWalker`] against the snapshot they came from: every
+/// run must be page-aligned, non-empty, sorted, non-overlapping, and start past the root pages.
+///
+/// Runs reaching past the end of the file are clipped to it, and any starting past it are
+/// dropped, as a reader can outlive the end of the file being truncated away.
+///
+/// Runs are yielded as `(start, end)` byte offsets. Corruption is reported as an error item,
+/// after which this is exhausted.
+pub(crate) struct FreeRuns {
+    walker: FreelistWalker,
+    /// End of the last run returned
+    prev_end: u64,
+    file_len: u64,
+    done: bool,
+}
+
+impl FreeRuns {
+    pub(crate) fn new(txn: &ReadTxn) -> Result<Self, AllocError> {
+        let file_len = {
+            let Ok(storage) = txn.core.storage.lock() else {
+                return Err(AllocError::MutexPoisoned);
+            };
+            unsafe { storage.get_maps() }
+                .iter()
+                .map(|m| m.len() as u64)
+                .sum()
+        };
+        // Safety: the freelist pages can't be reused while the read transaction is alive, and
+        // every user of this borrows it for as long as they exist.
+        let walker = unsafe {
+            FreelistWalker::new(txn.storage.clone(), txn.core.clone(), txn.root.freelist)
+        };
+        Ok(Self {
+            walker,
+            prev_end: ROOT_MAP_SIZE as u64,
+            file_len,
+            done: false,
+        })
+    }
+
+    /// Every freelist page walked so far. Once this is exhausted, that's the whole freelist.
+    pub(crate) fn pages(&self) -> &[u64] {
+        &self.walker.pages
+    }
+}
+
+impl Iterator for FreeRuns {
+    type Item = Result<(u64, u64), AllocError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+            let (start, len) = match self.walker.next() {
+                Some(Ok(run)) => run,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            if let Err(e) = ByteOffset::new(start) {
+                self.done = true;
+                return Some(Err(e));
+            }
+            let end = start.checked_add(len);
+            let valid = (start & (PAGE_SIZE as u64 - 1)) == 0
+                && (len & (PAGE_SIZE as u64 - 1)) == 0
+                && len > 0
+                && start >= self.prev_end
+                && end.is_some();
+            if !valid {
+                self.done = true;
+                return Some(Err(AllocError::DataFormat(FormatError::Freelist)));
+            }
+            self.prev_end = start + len;
+            // Runs that were truncated away are still checked, so the whole freelist gets walked
+            if start < self.file_len {
+                return Some(Ok((start, self.prev_end.min(self.file_len))));
+            }
+        }
+    }
+}
+
+/// Most entries a freelist page is sure to hold: keys and values each take up at most 8 bytes,
+/// plus a byte for both of their lengths.
+const PAGE_ENTRIES: usize = (PAGE_SIZE - INT_PAGE_HEADER_SIZE) / 17;
+
+/// How many pages each level of a freelist holding `runs` runs takes, from the leaves up. An empty
+/// freelist has no levels at all.
+fn levels(runs: usize) -> Vec<usize> {
+    let mut levels = Vec::new();
+    let mut entries = runs;
+    while entries > 0 {
+        let pages = entries.div_ceil(PAGE_ENTRIES);
+        levels.push(pages);
+        if pages == 1 {
+            break;
+        }
+        entries = pages;
+    }
+    levels
+}
+
+/// How many pages a freelist holding `runs` runs is written out to.
+pub(crate) fn pages_needed(runs: usize) -> usize {
+    levels(runs).iter().sum()
+}
+
+/// Write the free runs, as `(start, len)` byte pairs, out to a new freelist, returning its head.
+/// The freelist is shaped to hold `capacity` runs, which must be at least as many as there are,
+/// and takes up exactly the [`pages_needed`] for that many. Each level's entries are spread evenly
+/// over its pages, so none of them end up empty unless there's only one.
+///
+/// # Safety
+///
+/// Every page in `pages` must be free, with nothing else reading or writing it.
+pub(crate) unsafe fn write_freelist(
+    storage: &mut RawMemory,
+    core: &Arc<DbCore>,
+    runs: &[(u64, u64)],
+    capacity: usize,
+    pages: &[u64],
+) -> Result<ByteOffset, AllocError> {
+    let levels = levels(capacity);
+    if runs.len() > capacity || pages.len() != levels.iter().sum::<usize>() {
+        return Err(AllocError::Internal(
+            "freelist pages don't fit the free runs",
+        ));
+    }
+    let mut entries = runs.to_vec();
+    let mut pages = pages.iter();
+    let mut head = 0;
+    for (depth, count) in levels.into_iter().enumerate() {
+        let page_type = if depth == 0 {
+            FREELIST_LEAF
+        } else {
+            FREELIST_BRANCH
+        };
+        let mut parents = Vec::with_capacity(count);
+        for i in 0..count {
+            let chunk = &entries[i * entries.len() / count..(i + 1) * entries.len() / count];
+            let page = *pages.next().unwrap();
+            let mem = unsafe { storage.get(core, BlockRange::new(page as usize, PAGE_SIZE))? };
+            let mut int_page = unsafe { IntPage::new(mem.as_mut_ptr(), page_type) };
+            for (k, v) in chunk {
+                int_page
+                    .insert(*k, *v)
+                    .map_err(|_| AllocError::Internal("freelist page overflowed"))?;
+            }
+            int_page.seal();
+            if count > 1 {
+                let Some((first, _)) = chunk.first() else {
+                    return Err(AllocError::Internal("empty freelist page below a branch"));
+                };
+                parents.push((*first, page));
+            }
+            head = page;
+        }
+        entries = parents;
+    }
+    ByteOffset::new(head)
+}
+
+/// Iterator over every allocated range in a read transaction's snapshot, as sorted and coalesced
+/// `(offset, len)` byte pairs. Created with [`ReadTxn::live_ranges`][crate::ReadTxn::live_ranges].
+///
+/// The freelist is walked lazily, so this is safe to use on very large databases. If a corrupt
+/// freelist page is found, it is returned as an error item and iteration stops.
+pub struct LiveRangeIter<'a> {
+    free: FreeRuns,
+    /// Start of the next live range that hasn't been returned yet
+    cursor: u64,
+    done: bool,
+    txn: PhantomData<&'a ReadTxn>,
+}
+
+impl<'a> LiveRangeIter<'a> {
+    pub(crate) fn new(txn: &'a ReadTxn) -> Result<Self, AllocError> {
+        Ok(Self {
+            free: FreeRuns::new(txn)?,
+            cursor: ROOT_MAP_SIZE as u64,
+            done: false,
+            txn: PhantomData,
+        })
+    }
+
+    /// Also return the root pages at the start of the file as live.
+    pub fn include_roots(mut self) -> Self {
+        if self.cursor == ROOT_MAP_SIZE as u64 {
+            self.cursor = 0;
+        }
+        self
+    }
+}
+
+impl<'a> Iterator for LiveRangeIter<'a> {
+    type Item = Result<(u64, u64), AllocError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        for free in self.free.by_ref() {
+            let (start, end) = match free {
+                Ok(run) => run,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            let live = (self.cursor, start - self.cursor);
+            self.cursor = end;
+            if live.1 > 0 {
+                return Some(Ok(live));
+            }
+        }
+        self.done = true;
+        let file_len = self.free.file_len;
+        (self.cursor < file_len).then(|| Ok((self.cursor, file_len - self.cursor)))
+    }
+}
+
+impl<'a> FusedIterator for LiveRangeIter<'a> {}
+
+/// Iterator over every range that was free in an older snapshot but is allocated in a newer one,
+/// as sorted and coalesced `(offset, len)` byte pairs. Created with
+/// [`ReadTxn::changed_since`][crate::ReadTxn::changed_since].
+///
+/// Pages are never modified in place once committed, so these are exactly the pages whose
+/// contents may differ between the two snapshots, which is what an incremental backup needs to
+/// copy. Both freelists are walked lazily and in step. If either freelist is corrupt, the error
+/// is returned as an item and iteration stops.
+pub struct ChangedRangeIter<'a> {
+    old: FreeRuns,
+    new: FreeRuns,
+    /// The part of the current old free run that hasn't been checked yet
+    old_run: Option<(u64, u64)>,
+    /// The current new free run, as `(start, end)`
+    new_run: Option<(u64, u64)>,
+    /// A changed range that may still be extended by the next one
+    pending: Option<(u64, u64)>,
+    done: bool,
+    txn: PhantomData<&'a ReadTxn>,
+}
+
+impl<'a> ChangedRangeIter<'a> {
+    pub(crate) fn new(txn: &'a ReadTxn, old: &'a ReadTxn) -> Result<Self, AllocError> {
+        Ok(Self {
+            old: FreeRuns::new(old)?,
+            new: FreeRuns::new(txn)?,
+            old_run: None,
+            new_run: None,
+            pending: None,
+            done: false,
+            txn: PhantomData,
+        })
+    }
+
+    fn fail(&mut self, e: AllocError) -> Option<Result<(u64, u64), AllocError>> {
+        self.done = true;
+        Some(Err(e))
+    }
+}
+
+impl<'a> Iterator for ChangedRangeIter<'a> {
+    type Item = Result<(u64, u64), AllocError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            let (start, end) = match self.old_run.take() {
+                Some(run) => run,
+                None => match self.old.next() {
+                    Some(Ok(run)) => run,
+                    Some(Err(e)) => return self.fail(e),
+                    None => {
+                        self.done = true;
+                        return self.pending.take().map(|(s, e)| Ok((s, e - s)));
+                    }
+                },
+            };
+
+            // Skip past new free runs that end before this one starts
+            while self.new_run.is_none_or(|(_, new_end)| new_end <= start) {
+                match self.new.next() {
+                    Some(Ok(run)) => self.new_run = Some(run),
+                    Some(Err(e)) => return self.fail(e),
+                    None => {
+                        self.new_run = None;
+                        break;
+                    }
+                }
+            }
+
+            // Split off the part of this run that comes before the next new free run
+            let changed = match self.new_run {
+                Some((new_start, new_end)) if new_start < end => {
+                    if new_end < end {
+                        self.old_run = Some((new_end, end));
+                    }
+                    (new_start > start).then_some((start, new_start))
+                }
+                _ => Some((start, end)),
+            };
+            let Some((start, end)) = changed else {
+                continue;
+            };
+
+            match self.pending {
+                Some((s, e)) if e == start => self.pending = Some((s, end)),
+                Some((s, e)) => {
+                    self.pending = Some((start, end));
+                    return Some(Ok((s, e - s)));
+                }
+                None => self.pending = Some((start, end)),
+            }
+        }
+    }
+}
+
+impl<'a> FusedIterator for ChangedRangeIter<'a> {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        run_set::RunSet,
+        tests::{test_core, test_storage},
+        AllocErrorKind, ReadUnit, RootCheckout, BLOCK_SIZE, CLUSTER_SIZE, MIN_DB_SIZE,
+    };
+
+    /// Write out a freelist page holding the given entries.
+    pub(crate) fn write_freelist_page(
+        storage: &RawMemory,
+        page: u64,
+        page_type: u8,
+        entries: &[(u64, u64)],
+    ) {
+        let mem = unsafe {
+            storage
+                .get_mut_slice(BlockRange::new(page as usize, PAGE_SIZE))
+                .unwrap()
+                .unwrap()
+        };
+        let mut int_page = unsafe { IntPage::new(mem.as_mut_ptr(), page_type) };
+        for (k, v) in entries {
+            int_page.insert(*k, *v).unwrap();
+        }
+        int_page.seal();
+    }
+
+    fn reader_with_freelist(head: u64) -> (Arc<DbCore>, ReadTxn) {
+        let core = test_core();
+        core.root.lock().unwrap().freelist = ByteOffset::new(head).unwrap();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        (core, read.reader())
+    }
+
+    #[test]
+    fn live_ranges() {
+        let p = PAGE_SIZE as u64;
+        let base = ROOT_MAP_SIZE as u64;
+
+        // A two-level freelist: one branch page pointing at two leaves. The freelist pages
+        // themselves sit at base, base + 1 page, and base + 2 pages.
+        let (core, txn) = reader_with_freelist(base);
+        let storage = test_storage(&core);
+        let free = [
+            (base + 4 * p, 2 * p),
+            (base + 6 * p, p),
+            (base + 10 * p, p),
+            (BLOCK_SIZE as u64, BLOCK_SIZE as u64),
+            (3 * BLOCK_SIZE as u64, BLOCK_SIZE as u64),
+        ];
+        write_freelist_page(
+            &storage,
+            base,
+            FREELIST_BRANCH,
+            &[(free[0].0, base + p), (free[3].0, base + 2 * p)],
+        );
+        write_freelist_page(&storage, base + p, FREELIST_LEAF, &free[..3]);
+        write_freelist_page(&storage, base + 2 * p, FREELIST_LEAF, &free[3..]);
+
+        let live: Vec<(u64, u64)> = txn.live_ranges().unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            live,
+            [
+                (base, 4 * p),
+                (base + 7 * p, 3 * p),
+                (base + 11 * p, BLOCK_SIZE as u64 - base - 11 * p),
+                (2 * BLOCK_SIZE as u64, BLOCK_SIZE as u64),
+            ]
+        );
+
+        // Live, free, and root ranges cover the whole file exactly once
+        let live_len: u64 = live.iter().map(|(_, len)| len).sum();
+        let free_len: u64 = free.iter().map(|(_, len)| len).sum();
+        assert_eq!(
+            live_len + free_len + ROOT_MAP_SIZE as u64,
+            MIN_DB_SIZE as u64
+        );
+
+        // Including the roots just extends the first range
+        let with_roots: Vec<(u64, u64)> = txn
+            .live_ranges()
+            .unwrap()
+            .include_roots()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(with_roots[0], (0, base + 4 * p));
+        assert_eq!(with_roots[1..], live[1..]);
+
+        // No freelist at all means everything is live
+        let (_, txn) = reader_with_freelist(0);
+        let live: Vec<(u64, u64)> = txn.live_ranges().unwrap().map(Result::unwrap).collect();
+        assert_eq!(live, [(base, MIN_DB_SIZE as u64 - base)]);
+    }
+
+    #[test]
+    fn corrupt_freelist() {
+        let p = PAGE_SIZE as u64;
+        let base = ROOT_MAP_SIZE as u64;
+        let errors = |txn: &ReadTxn| {
+            let items: Vec<_> = txn.live_ranges().unwrap().collect();
+            assert!(matches!(items.last(), Some(Err(AllocError::DataFormat(_)))));
+            items.iter().filter(|i| i.is_err()).count()
+        };
+
+        // Head page of the wrong type
+        let (core, txn) = reader_with_freelist(base);
+        write_freelist_page(&test_storage(&core), base, 0x42, &[]);
+        assert_eq!(errors(&txn), 1);
+
+        // Misaligned and out-of-range head pages
+        let (_, txn) = reader_with_freelist(base + 8);
+        assert_eq!(errors(&txn), 1);
+        let (_, txn) = reader_with_freelist(1 << 40);
+        assert_eq!(errors(&txn), 1);
+
+        // A branch page pointing at itself
+        let (core, txn) = reader_with_freelist(base);
+        write_freelist_page(&test_storage(&core), base, FREELIST_BRANCH, &[(0, base)]);
+        assert_eq!(errors(&txn), 1);
+
+        // Overlapping runs
+        let (core, txn) = reader_with_freelist(base);
+        write_freelist_page(
+            &test_storage(&core),
+            base,
+            FREELIST_LEAF,
+            &[(base + p, 2 * p), (base + 2 * p, p)],
+        );
+        assert_eq!(errors(&txn), 1);
+
+        // Runs past the end of the file are left over from truncating it, not corruption
+        let (core, txn) = reader_with_freelist(base);
+        write_freelist_page(
+            &test_storage(&core),
+            base,
+            FREELIST_LEAF,
+            &[(base + p, MIN_DB_SIZE as u64), (2 * MIN_DB_SIZE as u64, p)],
+        );
+        let live: Vec<(u64, u64)> = txn.live_ranges().unwrap().map(Result::unwrap).collect();
+        assert_eq!(live, [(base, p)]);
+    }
+
+    #[test]
+    fn write_read_back() {
+        let p = PAGE_SIZE as u64;
+        let base = ROOT_MAP_SIZE as u64;
+
+        // Too many runs for one page, so it takes leaves and a branch above them
+        let runs: Vec<(u64, u64)> = (0..500).map(|i| (base + (8 + 2 * i) * p, p)).collect();
+        assert_eq!(levels(runs.len()), [3, 1]);
+        let pages: Vec<u64> = (0..4).map(|i| base + i * p).collect();
+        let (core, txn) = reader_with_freelist(base + 3 * p);
+        let mut storage = test_storage(&core);
+        let head = unsafe { write_freelist(&mut storage, &core, &runs, runs.len(), &pages) };
+        assert_eq!(head.unwrap(), ByteOffset::new(base + 3 * p).unwrap());
+
+        // Reading it back gives the runs as `(start, end)` pairs
+        let mut read = FreeRuns::new(&txn).unwrap();
+        assert!(read
+            .by_ref()
+            .map(Result::unwrap)
+            .eq(runs.iter().map(|(start, len)| (*start, start + len))));
+        let mut walked = read.pages().to_vec();
+        walked.sort_unstable();
+        assert_eq!(walked, pages);
+
+        // Too few pages for that many runs is refused before anything gets written
+        let err = unsafe { write_freelist(&mut storage, &core, &runs, runs.len(), &pages[..3]) };
+        assert_eq!(err.unwrap_err().kind(), AllocErrorKind::Internal);
+
+        // Changing a leaf after it was written breaks its hash
+        let mem = unsafe { storage.get_mut_slice(BlockRange::new(base as usize, PAGE_SIZE)) };
+        mem.unwrap().unwrap()[INT_PAGE_HEADER_SIZE] ^= 0x01;
+        let err = FreeRuns::new(&txn).unwrap().find_map(Result::err);
+        assert!(matches!(
+            err,
+            Some(AllocError::DataFormat(FormatError::FreelistHash { page })) if page == base
+        ));
+    }
+
+    #[test]
+    fn reserved_page_bits() {
+        let p = PAGE_SIZE as u64;
+        let base = ROOT_MAP_SIZE as u64;
+        let tag = 1 << 48;
+        let reserved = |txn: &ReadTxn| {
+            let items: Vec<_> = txn.live_ranges().unwrap().collect();
+            matches!(
+                items.as_slice(),
+                [Err(AllocError::ReservedPageBits { page })] if page & tag != 0
+            )
+        };
+
+        // A branch page pointing at a tagged copy of a real leaf page
+        let (core, txn) = reader_with_freelist(base);
+        let storage = test_storage(&core);
+        write_freelist_page(&storage, base, FREELIST_BRANCH, &[(0, tag | (base + p))]);
+        write_freelist_page(&storage, base + p, FREELIST_LEAF, &[(base + 2 * p, p)]);
+        assert!(reserved(&txn));
+
+        // A leaf page with a tagged run
+        let (core, txn) = reader_with_freelist(base);
+        write_freelist_page(
+            &test_storage(&core),
+            base,
+            FREELIST_LEAF,
+            &[(tag | (base + p), p)],
+        );
+        assert!(reserved(&txn));
+    }
+
+    #[test]
+    fn changed_since() {
+        let p = PAGE_SIZE as u64;
+        let c = CLUSTER_SIZE as u64;
+        let block = BLOCK_SIZE as u64;
+        let base = ROOT_MAP_SIZE as u64;
+        let core = test_core();
+        let storage = test_storage(&core);
+        let read = ReadUnit::new(storage.clone(), core.clone());
+
+        // Pin an old snapshot
+        write_freelist_page(
+            &storage,
+            base,
+            FREELIST_LEAF,
+            &[(base + p, 7 * p), (block, block), (2 * block, c)],
+        );
+        core.root.lock().unwrap().freelist = ByteOffset::new(base).unwrap();
+        let old = read.reader();
+
+        // Commit a new snapshot that writes its freelist and one more page into the old free
+        // space, allocates a cluster at each end of the old free blocks, and frees an old page.
+        write_freelist_page(
+            &storage,
+            base + 2 * p,
+            FREELIST_LEAF,
+            &[
+                (base + p, p),
+                (base + 4 * p, 4 * p),
+                (base + 20 * p, p),
+                (block + c, block - 2 * c),
+            ],
+        );
+        core.root.lock().unwrap().update(
+            &RootCheckout {
+                id: old.generation() + 1,
+                root: Arc::default(),
+                freelist: ByteOffset::new(base + 2 * p).unwrap(),
+                file_len: MIN_DB_SIZE as u64,
+            },
+            RunSet::new(),
+        );
+        let new = read.reader();
+
+        // Ranges allocated in separate old free runs are coalesced, and the freed page isn't
+        // reported at all.
+        let changed: Vec<(u64, u64)> = new
+            .changed_since(&old)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            changed,
+            [(base + 2 * p, 2 * p), (block, c), (2 * block - c, 2 * c)]
+        );
+
+        // Nothing changes relative to itself
+        assert_eq!(new.changed_since(&new).unwrap().count(), 0);
+
+        // Snapshots from a different database can't be compared
+        let (_, other) = reader_with_freelist(0);
+        assert!(matches!(
+            new.changed_since(&other),
+            Err(AllocError::ForeignTransaction)
+        ));
+    }
+}
@@ -1,6 +1,9 @@
 use std::{cmp::Ordering, fmt, iter::FusedIterator};
 
 use thiserror::Error;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::format::{INT_KEY_LEN_MASK, INT_VALUE_LEN_MASK, INT_VALUE_LEN_SHIFT};
 
 #[derive(Debug, Error, PartialEq, Eq)]
 #[error("No space left in page to insert")]
@@ -21,26 +24,47 @@ pub enum PageError {
 }
 
 /// Put the header in a submodule to stop us accidentally not using the accessor functions
-mod h {
+pub(crate) mod h {
+    use crate::format::INT_PAGE_HEADER_MASK;
+
     #[repr(C)]
     pub struct Header {
+        hash: [u8; 8],
         len: u16,
         end: u16,
         unused: u8,
         pub page_type: u8,
     }
 
+    /// Offsets of each field within the header, for [`crate::format`].
+    pub const HASH: usize = core::mem::offset_of!(Header, hash);
+    pub const LEN: usize = core::mem::offset_of!(Header, len);
+    pub const END: usize = core::mem::offset_of!(Header, end);
+    pub const PAGE_TYPE: usize = core::mem::offset_of!(Header, page_type);
+
     impl Header {
+        /// Get the stored hash
+        #[inline]
+        pub fn hash(&self) -> u64 {
+            u64::from_le_bytes(self.hash)
+        }
+
+        /// Set the stored hash
+        #[inline]
+        pub fn set_hash(&mut self, hash: u64) {
+            self.hash = hash.to_le_bytes();
+        }
+
         /// Get the length, forcing it to be valid
         #[inline]
         pub fn len(&self) -> u16 {
-            self.len.to_le() & 0xFFF
+            self.len.to_le() & INT_PAGE_HEADER_MASK
         }
 
         /// Get the end point, forcing it to be valid
         #[inline]
         pub fn end(&self) -> u16 {
-            self.end.to_le() & 0xFFF
+            self.end.to_le() & INT_PAGE_HEADER_MASK
         }
 
         /// Set the length
@@ -73,7 +97,8 @@ const HEADER_OFFSET: usize = PAGE_SIZE - HEADER_SIZE;
 /// variable-length-encoded `u64` values, with the first one being the key and the second being the
 /// value. Keys are stored in-order.
 ///
-/// The header is a 6-byte structure at the end of the page, consisting of:
+/// The header is a 14-byte structure at the end of the page, consisting of:
+/// - 4082:4089 - xxh3 hash of every other byte in the page, set by [`IntPage::seal`]
 /// - 4090:4091 - the number of items in the page. Only the lower 12 bits are used.
 /// - 4092:4093 - the offset to the end of the data section. Only the lower 12 bits are used.
 /// - 4094 - spare byte
@@ -85,7 +110,8 @@ const HEADER_OFFSET: usize = PAGE_SIZE - HEADER_SIZE;
 /// - 2:0 - 8 minus the number of bytes in the key (1-8)
 ///
 /// The values are just little-endian u64 numbers, but with any upper zero bytes discarded. The keys
-/// are the same, but are always at least one byte long.
+/// are the same, but are always at least one byte long. This means a value of 0 takes up no data
+/// bytes at all, while a key of 0 is still stored as a single zero byte.
 ///
 ///
 pub struct IntPage {
@@ -145,6 +171,34 @@ impl IntPage {
         unsafe { &mut *(self.mem.add(HEADER_OFFSET) as *mut Header) }
     }
 
+    /// Get the page type byte.
+    pub fn page_type(&self) -> u8 {
+        self.header().page_type
+    }
+
+    /// Hash every byte in the page except for the stored hash.
+    fn compute_hash(&self) -> u64 {
+        let hash_at = HEADER_OFFSET + h::HASH;
+        // Safety: the page is 4 kiB in size, as promised on construction.
+        let page = unsafe { std::slice::from_raw_parts(self.mem as *const u8, PAGE_SIZE) };
+        let mut hasher = Xxh3::new();
+        hasher.update(&page[..hash_at]);
+        hasher.update(&page[hash_at + 8..]);
+        hasher.digest()
+    }
+
+    /// Store the hash of the page as it is now. Done once the page has been written out in full;
+    /// any change after this shows up as a mismatch.
+    pub fn seal(&mut self) {
+        let hash = self.compute_hash();
+        self.header_mut().set_hash(hash);
+    }
+
+    /// Check that the page still matches the hash stored by [`seal`][Self::seal].
+    pub fn hash_matches(&self) -> bool {
+        self.header().hash() == self.compute_hash()
+    }
+
     /// Iterate over the key-value pairs.
     pub fn iter(&self) -> IntPageIter {
         let header = self.header();
@@ -299,7 +353,7 @@ impl<'a> Iterator for IntPageIter<'a> {
             self.item_ptr = self.item_ptr.offset(-1);
 
             // Get the key and move the pointer
-            let key_len = len & 0x7;
+            let key_len = len & INT_KEY_LEN_MASK;
             if self.data_ptr >= self.data_end {
                 return None;
             }
@@ -308,7 +362,7 @@ impl<'a> Iterator for IntPageIter<'a> {
             self.data_ptr = self.data_ptr.offset((0x8 - key_len) as isize);
 
             // Get the value and move the pointer
-            let val_len = len & 0x78;
+            let val_len = len & INT_VALUE_LEN_MASK;
             let val = if val_len >= 0x40 {
                 0
             } else {
@@ -342,11 +396,11 @@ impl<'a> DoubleEndedIterator for IntPageIter<'a> {
             let len: u8 = *self.item_end;
 
             // Move the pointer to the value and extract it
-            let val_len = len & 0x78;
+            let val_len = len & INT_VALUE_LEN_MASK;
             let val = if val_len >= 0x40 {
                 0
             } else {
-                self.data_end = self.data_end.offset(((0x40 - val_len) >> 3) as isize);
+                self.data_end = self.data_end.wrapping_sub(((0x40 - val_len) >> 3) as usize);
                 if self.data_end < self.data_ptr {
                     return None;
                 }
@@ -355,8 +409,8 @@ impl<'a> DoubleEndedIterator for IntPageIter<'a> {
             };
 
             // Move the pointer to the key and extract it
-            let key_len = len & 0x7;
-            self.data_end = self.data_end.offset((0x8 - key_len) as isize);
+            let key_len = len & INT_KEY_LEN_MASK;
+            self.data_end = self.data_end.wrapping_sub((0x8 - key_len) as usize);
             if self.data_end < self.data_ptr {
                 return None;
             }
@@ -426,14 +480,14 @@ impl<'a> OccupiedEntry<'a> {
         let data_len = 16 - ((key_len + val_len) as usize);
 
         // Get the old size and check if we have space
-        let old_data_len = unsafe { self.insert_item.offset_from(self.insert_data) };
-        if data_len > (self.page.available() - (old_data_len as usize)) {
+        let old_data_len = unsafe { self.next_data.offset_from(self.insert_data) };
+        if data_len > self.page.available() + old_data_len as usize {
             return Err(OutofSpace);
         }
 
         unsafe {
             // Replace the length number
-            *self.insert_item = (val_len << 3) | key_len;
+            *self.insert_item = (val_len << INT_VALUE_LEN_SHIFT) | key_len;
 
             // Move the existing data as needed
             let copy_len = self.page.header().end() as usize - (self.next_data as usize & 0xFFF);
@@ -443,15 +497,19 @@ impl<'a> OccupiedEntry<'a> {
 
             // Copy in the key
             let mut new_key = (self.insert_data as *const u64).read_unaligned();
-            new_key &= u64::MAX << ((8 - key_len) << 3);
+            new_key &= u64::MAX
+                .checked_shl(((8 - key_len) << 3) as u32)
+                .unwrap_or(0);
             new_key |= self.key;
             (self.insert_data as *mut u64).write_unaligned(new_key);
             self.insert_data = self.insert_data.add((8 - key_len) as usize);
 
             // Copy in the value
-            if val_len < 0x10 {
+            if val_len < 8 {
                 let mut new_val = (self.insert_data as *const u64).read_unaligned();
-                new_val &= u64::MAX << ((8 - val_len) << 3);
+                new_val &= u64::MAX
+                    .checked_shl(((8 - val_len) << 3) as u32)
+                    .unwrap_or(0);
                 new_val |= val;
                 (self.insert_data as *mut u64).write_unaligned(new_val);
             }
@@ -470,7 +528,7 @@ impl<'a> OccupiedEntry<'a> {
             let last_item = self
                 .page
                 .mem
-                .add(HEADER_OFFSET + 1 - (self.page.header().len() as usize));
+                .add(HEADER_OFFSET - (self.page.header().len() as usize));
             let copy_len = self.insert_item.offset_from(last_item);
             last_item.copy_to(last_item.offset(1), copy_len as usize);
 
@@ -532,7 +590,7 @@ impl<'a> VacantEntry<'a> {
                 .add(HEADER_OFFSET - 1 - (self.page.header().len() as usize));
             let copy_len = self.insert_item.offset_from(end);
             end.copy_from(end.offset(1), copy_len as usize);
-            *self.insert_item = (val_len << 3) | key_len;
+            *self.insert_item = (val_len << INT_VALUE_LEN_SHIFT) | key_len;
         }
 
         // Insert the new key and value, shifting everything past them.
@@ -544,15 +602,19 @@ impl<'a> VacantEntry<'a> {
 
             // Copy in the key
             let mut new_key = (self.insert_data as *const u64).read_unaligned();
-            new_key &= u64::MAX << ((8 - key_len) << 3);
+            new_key &= u64::MAX
+                .checked_shl(((8 - key_len) << 3) as u32)
+                .unwrap_or(0);
             new_key |= self.key;
             (self.insert_data as *mut u64).write_unaligned(new_key);
             self.insert_data = self.insert_data.add((8 - key_len) as usize);
 
             // Copy in the value
-            if val_len < 0x10 {
+            if val_len < 8 {
                 let mut new_val = (self.insert_data as *const u64).read_unaligned();
-                new_val &= u64::MAX << ((8 - val_len) << 3);
+                new_val &= u64::MAX
+                    .checked_shl(((8 - val_len) << 3) as u32)
+                    .unwrap_or(0);
                 new_val |= val;
                 (self.insert_data as *mut u64).write_unaligned(new_val);
             }
@@ -595,4 +657,64 @@ mod tests {
 
         println!("{}", mem[4095]);
     }
+
+    #[test]
+    fn zero_key_and_value() {
+        let mut mem = [0u8; 8192];
+        let ptr = mem
+            .as_mut_ptr()
+            .wrapping_add(mem.as_mut_ptr().align_offset(4096));
+
+        // Key 0 takes a byte, value 0 takes nothing
+        let mut page = unsafe { IntPage::new(ptr, 0) };
+        let available = page.available();
+        assert_eq!(page.insert(0, 0), Ok(None));
+        assert_eq!(available - page.available(), 2);
+        assert_eq!(page.insert(1, 0), Ok(None));
+        assert_eq!(page.insert(0x100, 0), Ok(None));
+        assert!(page.validate().is_ok());
+        assert_eq!(page.get(0), Some(0));
+        assert_eq!(page.get(1), Some(0));
+        assert_eq!(page.get(0x100), Some(0));
+        assert_eq!(
+            page.iter().collect::<Vec<_>>(),
+            [(0, 0), (1, 0), (0x100, 0)]
+        );
+    }
+
+    #[test]
+    fn matches_btreemap() {
+        // A page with a guard page on either side, to catch anything written outside of it
+        let mut mem = vec![0xAAu8; 4 * 4096];
+        let start = mem.as_ptr().align_offset(4096) + 4096;
+        let ptr = mem.as_mut_ptr().wrapping_add(start);
+        let mut page = unsafe { IntPage::new(ptr, 0) };
+
+        let mut expected = std::collections::BTreeMap::new();
+        let mut state = 1u64;
+        for _ in 0..20_000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            // Mix in short keys and values, including empty ones, with full-width ones
+            let key = state.rotate_left(17) >> (state % 64);
+            let val = state.rotate_left(29) >> ((state >> 8) % 65).min(63);
+            match (state >> 4) % 3 {
+                0 => assert_eq!(page.remove(key), expected.remove(&key)),
+                _ => {
+                    if let Ok(old) = page.insert(key, val) {
+                        assert_eq!(old, expected.insert(key, val));
+                    }
+                }
+            }
+            assert!(page.validate().is_ok());
+            assert!(page.iter().eq(expected.iter().map(|(k, v)| (*k, *v))));
+            assert!(page
+                .iter()
+                .rev()
+                .eq(expected.iter().rev().map(|(k, v)| (*k, *v))));
+        }
+        assert!(mem[..start].iter().all(|b| *b == 0xAA));
+        assert!(mem[start + 4096..].iter().all(|b| *b == 0xAA));
+    }
 }
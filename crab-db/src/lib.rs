@@ -1,32 +1,66 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-/*
-- 6 bytes for a page number (48 bits)
-- Max size is thus 2^60, or 1 EiB (1024*1024 TiB)
-- 47 sub-blocks are thus needed - the last one can never be filled because we
-  pre-alloc the first 128 kiB for the root page. Also because who the heck puts
-  that much storage at the behest of a single machine???
- */
-
-const NUM_ALLOCS: usize = 47;
-
 use std::{
-    cmp::Ordering, collections::{BTreeMap, BTreeSet}, fmt::{self}, ops::{Deref, DerefMut}, path::Path, sync::{mpsc, Arc, Mutex}
+    cell::Cell, cmp::Ordering, collections::{BTreeMap, BTreeSet, VecDeque}, fmt::{self}, io::{self, Read, Write}, iter::StepBy, marker::PhantomData, ops::{Deref, DerefMut, Range}, path::Path, sync::{atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering}, Arc, Mutex}, time::{Duration, Instant}
 };
 
-use error::FormatError;
+use freelist::{FreeRuns, FreelistWalker};
+use int_page::IntPage;
 use memmap2::{MmapMut, MmapOptions, MmapRaw};
 
+// Raw page views, only public for the workspace's own tools. Not part of the supported API.
+#[doc(hidden)]
 pub mod int_page;
+#[cfg(feature = "alloc-audit")]
+mod audit;
 pub mod block;
 pub mod block_owned;
-pub mod pages;
+#[cfg(feature = "async")]
+mod commit_notify;
+#[cfg(not(feature = "single-threaded"))]
+mod commit_thread;
 mod error;
-pub mod storage;
+pub mod format;
+mod freelist;
+mod metrics;
+pub mod prelude;
+#[cfg(feature = "btree")]
+mod raw;
+#[cfg(feature = "read-cache")]
+mod read_cache;
+#[cfg(feature = "read-stats")]
+mod read_stats;
+pub mod recover;
+mod run_set;
+mod storage;
+mod threading;
+mod txn_roots;
 
-pub use error::AllocError;
-use storage::StorageInner;
+#[cfg(feature = "alloc-audit")]
+pub use audit::{AuditHistory, AuditOp, AuditRecord};
+pub use block::Block;
+pub use block_owned::OwnedBlock;
+#[cfg(feature = "async")]
+pub use commit_notify::CommitNotify;
+#[cfg(not(feature = "single-threaded"))]
+pub use commit_thread::{CommitHandle, CommitTicket};
+pub use error::{AllocError, AllocErrorKind, FormatError, FormatErrorKind};
+pub use freelist::{ChangedRangeIter, LiveRangeIter};
+pub use metrics::MetricsHook;
+#[cfg(feature = "btree")]
+pub use raw::TxnWriter;
+pub use txn_roots::{RootEntry, RootSlot, TxnRoots};
+#[cfg(feature = "read-cache")]
+pub use read_cache::ReadCache;
+#[cfg(feature = "read-stats")]
+pub use read_stats::ReadStats;
+#[cfg(feature = "alloc-audit")]
+use audit::AuditLog;
+use metrics::Metrics;
+use run_set::RunSet;
+use storage::{Advice, StorageInner};
+use threading::{page_queue, queue, PageReceiver, PageSender, QueueReceiver, QueueSender};
 
 /// The maximum allocation size - 1 MiB
 pub const BLOCK_SIZE: usize = 1 << 20;
@@ -37,6 +71,11 @@ pub const MIN_DB_SIZE: usize = 4 << 20;
 /// A single page - should always be 4 kiB
 pub const PAGE_SIZE: usize = 1 << 12;
 
+/// Alignment of every allocation the allocator hands out. All page offsets and all returned memory
+/// are multiples of this, which is what crab-dads' `RawRead` and `RawWrite` traits require. Ranges
+/// that aren't aligned to it are rejected with [`AllocError::Misaligned`].
+pub const ALLOC_ALIGN: usize = PAGE_SIZE;
+
 /// A single page cluster - should be 16 kiB
 pub const CLUSTER_SIZE: usize = 4 * PAGE_SIZE;
 
@@ -46,14 +85,136 @@ pub const ROOT_SIZE: usize = CLUSTER_SIZE;
 /// The size of all root pages in the backing file
 pub const ROOT_MAP_SIZE: usize = ROOT_SIZE * 2;
 
+/// How many of the newest transactions [`CommitUnit::commit_to`] can commit up to. Anything older
+/// can only be made durable along with a newer one.
+pub const COMMIT_HISTORY_LEN: usize = 256;
+
+/// The most runs of written pages a commit flushes one at a time. Past this, flushing every map
+/// in one go is quicker than a call per run.
+const MAX_FLUSH_RUNS: usize = 1024;
+
+/// The bits of a byte offset that may be set. Offsets are 48 bits wide.
+///
+/// The upper 16 bits are reserved, and belong to the allocator. Nothing it loads or hands out may
+/// have them set: not the freelist head in the root header, not the freelist pages, and not any
+/// range a transaction asks for. If a layer above (like crab-dads' branch pages) ever wants to tag
+/// page numbers with them, it must strip its tags before passing a page number back in. Offsets
+/// with reserved bits set are rejected with [`AllocError::ReservedPageBits`], never masked, so a
+/// tagged offset can't silently alias some other page.
+pub const PAGE_NUM_MASK: u64 = (1 << 48) - 1;
+
+/// A byte offset into the backing file. This is how the allocator names pages: every page is
+/// identified by the offset of its first byte, and that's also the `u64` it hands to crab-dads as
+/// a page number. The reserved bits outside of [`PAGE_NUM_MASK`] are always clear.
+///
+/// Don't confuse it with a [`PageNo`], which counts pages instead of bytes. The two only convert
+/// into each other explicitly, so one can't be passed where the other is expected:
+///
+/// ```compile_fail
+/// use crab_db::{PageNo, WriteTxn};
+///
+/// fn write_header(txn: &mut WriteTxn, page: PageNo) {
+///     txn.write_at(page, 0, b"header").unwrap();
+/// }
+/// ```
+///
+/// ```
+/// use crab_db::{PageNo, WriteTxn};
+///
+/// fn write_header(txn: &mut WriteTxn, page: PageNo) {
+///     txn.write_at(page.to_offset(), 0, b"header").unwrap();
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteOffset(u64);
+
+impl ByteOffset {
+    /// Check a raw byte offset, failing with [`AllocError::ReservedPageBits`] if any bits outside
+    /// of [`PAGE_NUM_MASK`] are set.
+    pub fn new(offset: u64) -> Result<Self, AllocError> {
+        if offset & !PAGE_NUM_MASK != 0 {
+            return Err(AllocError::ReservedPageBits { page: offset });
+        }
+        Ok(Self(offset))
+    }
+
+    /// The offset in bytes.
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The page starting at this offset, failing with [`AllocError::Misaligned`] if it isn't on a
+    /// page boundary.
+    pub fn to_page(self) -> Result<PageNo, AllocError> {
+        if self.0 & (PAGE_SIZE as u64 - 1) != 0 {
+            return Err(AllocError::Misaligned {
+                offset: self.0 as usize,
+            });
+        }
+        Ok(PageNo(self.0 / PAGE_SIZE as u64))
+    }
+}
+
+impl From<ByteOffset> for u64 {
+    fn from(offset: ByteOffset) -> Self {
+        offset.0
+    }
+}
+
+impl TryFrom<u64> for ByteOffset {
+    type Error = AllocError;
+
+    fn try_from(offset: u64) -> Result<Self, Self::Error> {
+        Self::new(offset)
+    }
+}
+
+/// The index of a [`PAGE_SIZE`] page in the backing file, so page `n` starts `n * PAGE_SIZE`
+/// bytes in. The allocator itself works in [`ByteOffset`]s; this is for callers that count pages.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PageNo(u64);
+
+impl PageNo {
+    /// Check a raw page index, failing with [`AllocError::ReservedPageBits`] if the page's byte
+    /// offset wouldn't fit in [`PAGE_NUM_MASK`].
+    pub fn new(page: u64) -> Result<Self, AllocError> {
+        ByteOffset::new(page.saturating_mul(PAGE_SIZE as u64))?;
+        Ok(Self(page))
+    }
+
+    /// The page index.
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The byte offset the page starts at.
+    pub fn to_offset(self) -> ByteOffset {
+        ByteOffset(self.0 * PAGE_SIZE as u64)
+    }
+}
+
 /// Struct for pulling memory right off of a memory map
 #[derive(Clone)]
 struct RawMemory {
     maps: Vec<&'static [u8]>,
+    /// The storage generation `maps` was taken at
+    generation: u64,
+    /// The storage's live generation, checked before bothering with the storage lock
+    current: Arc<AtomicU64>,
+    /// How many times `maps` has been refreshed from the storage
+    #[cfg(test)]
+    refreshes: usize,
 }
 
+/// A range of bytes in the backing file. `start` is the byte offset of the first page in the
+/// range.
+///
+/// The crab-dads traits describe the same thing as a `(page, num_pages)` pair, and [`Alloc`] as a
+/// [`ByteOffset`] and byte length; convert between them with [`from_pages`][Self::from_pages],
+/// [`to_pages`][Self::to_pages], and [`Alloc::to_range`] rather than multiplying by hand, so
+/// anything that doesn't fit in a `usize` is caught.
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
-struct BlockRange {
+pub struct BlockRange {
     pub start: usize,
     pub len: usize,
 }
@@ -65,23 +226,114 @@ impl BlockRange {
             len
         }
     }
+
+    /// The range covering `num_pages` pages, starting at `page`. Fails with
+    /// [`AllocError::ReservedPageBits`] if `page` isn't a valid [`ByteOffset`], or
+    /// [`AllocError::RangeOverflow`] if the range doesn't fit in the address space.
+    pub fn from_pages(page: u64, num_pages: usize) -> Result<Self, AllocError> {
+        ByteOffset::new(page)?;
+        let overflow = || AllocError::RangeOverflow {
+            offset: page,
+            len: (num_pages as u64).saturating_mul(PAGE_SIZE as u64),
+        };
+        let start = usize::try_from(page).map_err(|_| overflow())?;
+        let len = num_pages.checked_mul(PAGE_SIZE).ok_or_else(overflow)?;
+        start.checked_add(len).ok_or_else(overflow)?;
+        Ok(Self::new(start, len))
+    }
+
+    /// The range as a `(page, num_pages)` pair, or `None` if it doesn't both start and end on a
+    /// page boundary.
+    ///
+    /// Together with [`from_pages`][Self::from_pages], this is where the crab-dads traits meet the
+    /// allocator. Their `u64` page numbers leave the unit up to the implementation, and crab-db's
+    /// is always the page's byte offset, never a count of pages.
+    pub fn to_pages(&self) -> Option<(u64, usize)> {
+        if !self.is_aligned() || (self.len & (PAGE_SIZE - 1)) != 0 {
+            return None;
+        }
+        Some((self.start as u64, self.len / PAGE_SIZE))
+    }
+
+    /// Check that the range starts on an [`ALLOC_ALIGN`] boundary.
+    pub fn is_aligned(&self) -> bool {
+        (self.start & (ALLOC_ALIGN - 1)) == 0
+    }
+
+    /// Fail with [`AllocError::Misaligned`] if the range doesn't start on an [`ALLOC_ALIGN`]
+    /// boundary.
+    pub fn check_aligned(&self) -> Result<(), AllocError> {
+        if self.is_aligned() {
+            Ok(())
+        } else {
+            Err(AllocError::Misaligned { offset: self.start })
+        }
+    }
+
+    /// The byte offset the range starts at, failing with [`AllocError::ReservedPageBits`] if the
+    /// start doesn't fit in [`PAGE_NUM_MASK`].
+    pub fn page(&self) -> Result<ByteOffset, AllocError> {
+        ByteOffset::new(self.start as u64)
+    }
+
+    /// Check that the range could hold data: it must start on a valid byte offset, be aligned, and
+    /// can't start within the root pages. Fails with [`AllocError::ReservedPageBits`],
+    /// [`AllocError::Misaligned`], or [`AllocError::RootAccess`] respectively.
+    pub fn check_data(&self) -> Result<(), AllocError> {
+        self.page()?;
+        self.check_aligned()?;
+        if self.start < ROOT_MAP_SIZE {
+            return Err(AllocError::RootAccess { offset: self.start });
+        }
+        Ok(())
+    }
 }
 
 impl RawMemory {
+    /// Take the storage's current memory maps.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`StorageInner::get_maps`].
+    unsafe fn new(inner: &StorageInner) -> Self {
+        Self {
+            maps: inner.get_maps(),
+            generation: inner.generation(),
+            current: inner.generation_counter(),
+            #[cfg(test)]
+            refreshes: 0,
+        }
+    }
+
+    /// Check if the storage has grown or shrunk since we last took its maps.
+    fn is_stale(&self) -> bool {
+        self.current.load(AtomicOrdering::Acquire) != self.generation
+    }
+
+    /// Find a range in the maps. `None` if it runs past the end of the last one, as the storage
+    /// may have grown since. Fails with [`AllocError::SplitAcrossMaps`] if it starts in one map
+    /// and ends in another.
     unsafe fn get_mut_slice(
         &self,
         range: BlockRange,
     ) -> Result<Option<&'static mut [u8]>, AllocError> {
+        let range_end = range.start.saturating_add(range.len);
         let mut start = 0;
         for map in self.maps.iter() {
             let end = start + map.len();
             if range.start < end {
-                let lower = range.start - start;
-                let upper = range.start - start + range.len;
-                let m = map.get(lower..upper).ok_or(AllocError::InvalidAccess {
-                    offset: range.start,
-                    len: range.len,
-                })?;
+                if range_end > end {
+                    let mapped: usize = self.maps.iter().map(|m| m.len()).sum();
+                    if range_end > mapped {
+                        return Ok(None);
+                    }
+                    return Err(AllocError::SplitAcrossMaps {
+                        offset: range.start,
+                        len: range.len,
+                        boundary: end,
+                    });
+                }
+                let m = &map[range.start - start..range_end - start];
                 let len = m.len();
                 let ptr = m.as_ptr() as *mut u8;
                 return Ok(Some(std::slice::from_raw_parts_mut(ptr, len)));
@@ -94,25 +346,40 @@ impl RawMemory {
     /// Get a block of memory from the memory maps. Fails if the requested range is outside the
     /// memory map range, it is split across memory maps, or the backing memory map tracker was
     /// poisoned by a separate thread.
+    ///
+    /// The range must already be aligned to [`ALLOC_ALIGN`]; callers are expected to have checked
+    /// this with [`BlockRange::check_aligned`].
     pub unsafe fn get(
         &mut self,
         core: &Arc<DbCore>,
         range: BlockRange,
     ) -> Result<&'static mut [u8], AllocError> {
+        debug_assert!(range.is_aligned(), "unaligned range reached RawMemory::get");
         // Check maps first
         if let Some(s) = self.get_mut_slice(range)? {
+            debug_assert_eq!(s.as_ptr() as usize & (ALLOC_ALIGN - 1), 0);
             return Ok(s);
         }
 
-        // We ran out of maps, check the inner storage to see if we since got more
-        let Ok(inner) = core.storage.lock() else {
-            return Err(AllocError::Other("Backing memory's Mutex was poisoned"));
-        };
-        self.maps = inner.get_maps();
+        // We ran out of maps, check the inner storage to see if we since got more. If it hasn't
+        // grown, there's no point in locking it to look.
+        if self.is_stale() {
+            let Ok(inner) = core.storage.lock() else {
+                return Err(AllocError::MutexPoisoned);
+            };
+            self.maps = inner.get_maps();
+            self.generation = inner.generation();
+            #[cfg(test)]
+            {
+                self.refreshes += 1;
+            }
+            drop(inner);
 
-        // Recheck maps
-        if let Some(s) = self.get_mut_slice(range)? {
-            return Ok(s);
+            // Recheck maps
+            if let Some(s) = self.get_mut_slice(range)? {
+                debug_assert_eq!(s.as_ptr() as usize & (ALLOC_ALIGN - 1), 0);
+                return Ok(s);
+            }
         }
 
         // At this point, give up. We should never actually hit this unless
@@ -123,9 +390,37 @@ impl RawMemory {
             len: range.len,
         })
     }
+
+    /// Get a block of memory from the memory maps without updating our cached list of maps. If
+    /// the range isn't in the cached maps, the storage's current maps are checked instead.
+    pub unsafe fn get_const(
+        &self,
+        core: &Arc<DbCore>,
+        range: BlockRange,
+    ) -> Result<&'static [u8], AllocError> {
+        let invalid = AllocError::InvalidAccess {
+            offset: range.start,
+            len: range.len,
+        };
+        if let Some(s) = self.get_mut_slice(range)? {
+            return Ok(s);
+        }
+        if !self.is_stale() {
+            return Err(invalid);
+        }
+        let Ok(inner) = core.storage.lock() else {
+            return Err(AllocError::MutexPoisoned);
+        };
+        let fresh = RawMemory::new(&inner);
+        fresh
+            .get_mut_slice(range)?
+            .map(|x| x as &'static [u8])
+            .ok_or(invalid)
+    }
 }
 
 /// Tracking of the actual state of a page that's in the "free" table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum FreePageState {
     /// Page is already allocated and cannot be used.
     Allocated,
@@ -138,8 +433,10 @@ struct IdTracker {
     newest: u64,
     /// The oldest ID that's being used somewhere
     oldest: u64,
-    /// Track which IDs are currently checked out
-    tracker: Vec<(u64, usize)>,
+    /// How many times each ID is currently checked out
+    tracker: BTreeMap<u64, usize>,
+    /// Total number of checkouts across every ID
+    outstanding: usize,
 }
 
 impl IdTracker {
@@ -147,14 +444,11 @@ impl IdTracker {
         Self {
             newest: id,
             oldest: id,
-            tracker: Vec::new(),
+            tracker: BTreeMap::new(),
+            outstanding: 0,
         }
     }
 
-    fn find_id(&self, id: u64) -> Option<usize> {
-        self.tracker.iter().position(|(list_id, _)| *list_id == id)
-    }
-
     pub fn newest_id(&self) -> u64 {
         self.newest
     }
@@ -167,41 +461,64 @@ impl IdTracker {
         self.newest = newest;
     }
 
+    /// Number of checkouts that haven't been checked back in yet, counting pins.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    /// Check if anything still has `id` checked out.
+    pub fn is_pinned(&self, id: u64) -> bool {
+        self.tracker.contains_key(&id)
+    }
+
     /// Check a reader out at the current newest ID
     pub fn checkout(&mut self) -> u64 {
-        // Record that we're checking out at a given ID
-        if let Some(pos) = self.find_id(self.newest) {
-            let tracker: &mut (u64, usize) = &mut self.tracker[pos];
-            if tracker.0 == self.newest {
-                tracker.1 += 1;
-            } else {
-                self.tracker.push((self.newest, 1));
-            }
-        } else {
-            self.tracker.push((self.newest, 1));
-        }
-        self.newest
+        self.checkout_at(self.newest)
+    }
+
+    /// Check a reader out at an older ID. It mustn't be older than the oldest ID still checked
+    /// out, as pages that only it could reach may be gone already.
+    pub fn checkout_at(&mut self, id: u64) -> u64 {
+        *self.tracker.entry(id).or_default() += 1;
+        self.outstanding += 1;
+        id
+    }
+
+    /// Add another checkout to an ID that's already checked out, keeping it alive until a matching
+    /// check in.
+    pub fn pin(&mut self, id: u64) {
+        let Some(count) = self.tracker.get_mut(&id) else {
+            panic!("Tried to pin an ID that isn't checked out");
+        };
+        *count += 1;
+        self.outstanding += 1;
     }
 
     /// Check a reader back in
     pub fn checkin(&mut self, id: u64) {
-        // Locate the checkout ID in the list
-        let Some(pos) = self.find_id(id) else {
+        let Some(count) = self.tracker.get_mut(&id) else {
             panic!("Tried to check in an ID that was never checked out");
         };
+        self.outstanding -= 1;
         // Decrement the checkout ID, and if we drop an ID from the list, it's up to us to increment
         // the oldest ID known
-        self.tracker[pos].1 -= 1;
-        if self.tracker[pos].1 == 0 {
-            self.tracker.swap_remove(pos);
+        *count -= 1;
+        if *count == 0 {
+            self.tracker.remove(&id);
             self.oldest = self
                 .tracker
-                .iter()
-                .fold(self.newest, |acc, (id, _)| acc.min(*id));
+                .first_key_value()
+                .map_or(self.newest, |(first, _)| self.newest.min(*first));
         }
     }
 }
 
+/// Pages that readers have checked out through [`ReadBlock`]s, so the writer knows not to reuse
+/// them.
+///
+/// Everything is tracked per page: a block spanning several pages checks out every one of them,
+/// the same way `taken` and the dirty set on the writer count pages. That way freeing any page of
+/// a held block, not just its first one, is caught.
 #[derive(Default, Clone, Debug)]
 struct PageReadTracker {
     read: BTreeMap<u64, usize>,
@@ -210,38 +527,65 @@ struct PageReadTracker {
 }
 
 impl PageReadTracker {
-    /// Register a page for long term read checkout
-    pub fn checkout(&mut self, page: u64) {
-        if let Some(cnt) = self.write.get_mut(&page) {
-            *cnt += 1;
-        } else if let Some(cnt) = self.read.get_mut(&page) {
-            *cnt += 1;
-        } else if self.done.remove(&page) {
-            self.write.insert(page, 1);
-        } else {
-            self.read.insert(page, 1);
-        }
+    /// Every page that a range touches, rounding the end up to a whole page.
+    fn pages(range: BlockRange) -> impl Iterator<Item = u64> {
+        let start = range.start & !(PAGE_SIZE - 1);
+        let end = range.start.saturating_add(range.len.max(1));
+        (start..end).step_by(PAGE_SIZE).map(|page| page as u64)
     }
 
-    /// Check a page back in after concluding the long-term read
-    pub fn checkin(&mut self, page: u64) {
-        if let Some(cnt) = self.read.get_mut(&page) {
-            *cnt -= 1;
-            if *cnt == 0 {
-                self.read.remove(&page);
+    /// Register every page in a range for long term read checkout
+    pub fn checkout(&mut self, range: BlockRange) {
+        for page in Self::pages(range) {
+            if let Some(cnt) = self.write.get_mut(&page) {
+                *cnt += 1;
+            } else if let Some(cnt) = self.read.get_mut(&page) {
+                *cnt += 1;
+            } else if self.done.remove(&page) {
+                self.write.insert(page, 1);
+            } else {
+                self.read.insert(page, 1);
             }
-        } else if let Some(cnt) = self.write.get_mut(&page) {
-            *cnt -= 1;
-            if *cnt == 0 {
-                self.write.remove(&page);
-                self.done.insert(page);
+        }
+    }
+
+    /// Total number of checkouts across every page, whether or not the writer has seen them yet.
+    pub fn outstanding(&self) -> usize {
+        self.read.values().chain(self.write.values()).sum()
+    }
+
+    /// Check if a page is currently checked out by any reader.
+    pub fn contains(&self, page: u64) -> bool {
+        self.read.contains_key(&page) || self.write.contains_key(&page)
+    }
+
+    /// Check every page in a range back in after concluding the long-term read. Fails without
+    /// checking any of them in if some page in the range isn't checked out.
+    pub fn checkin(&mut self, range: BlockRange) -> Result<(), AllocError> {
+        if !Self::pages(range).all(|page| self.contains(page)) {
+            return Err(AllocError::Internal(
+                "read page checkin found a page that wasn't checked out",
+            ));
+        }
+        for page in Self::pages(range) {
+            if let Some(cnt) = self.read.get_mut(&page) {
+                *cnt -= 1;
+                if *cnt == 0 {
+                    self.read.remove(&page);
+                }
+            } else if let Some(cnt) = self.write.get_mut(&page) {
+                *cnt -= 1;
+                if *cnt == 0 {
+                    self.write.remove(&page);
+                    self.done.insert(page);
+                }
             }
-        } else {
-            panic!("Read page checkin failed: the page to be checked in wasn't in either checkout list");
         }
+        Ok(())
     }
 
-    /// Update the writer's list of checked-out pages
+    /// Update the writer's list of checked-out pages, adding newly checked-out pages and dropping
+    /// the ones that every reader has since let go of
     pub fn update_writer(&mut self, map: &mut BTreeSet<u64>) {
         for (page, cnt) in self.read.iter() {
             map.insert(*page);
@@ -268,16 +612,132 @@ impl PageReadTracker {
 // | WriteAlloc |            |           |              | X        | X       |
 // | CommitUnit | X          | X         |              |          | X       |
 
+/// Tracks which writer, if any, currently owns the database. There must only ever be one.
+#[derive(Default, Debug)]
+struct WriterState {
+    /// Generation of the writer currently holding the token, if any
+    active: Option<u64>,
+    /// Generation to hand out to the next writer
+    next: u64,
+}
+
+impl WriterState {
+    /// Claim the writer token, failing if another writer already holds it.
+    pub fn claim(&mut self) -> Result<u64, AllocError> {
+        if self.active.is_some() {
+            return Err(AllocError::WriterActive);
+        }
+        Ok(self.reclaim())
+    }
+
+    /// Forcibly claim the writer token, even if it's currently held. This is only for recovering
+    /// from a leaked writer handle - the old handle's generation goes stale, so dropping it later
+    /// won't release the new claim.
+    pub fn reclaim(&mut self) -> u64 {
+        let generation = self.next;
+        self.next += 1;
+        self.active = Some(generation);
+        generation
+    }
+
+    /// Release the writer token. Does nothing if the given generation is no longer the active one.
+    pub fn release(&mut self, generation: u64) {
+        if self.active == Some(generation) {
+            self.active = None;
+        }
+    }
+
+    /// Check if the given generation still holds the writer token.
+    pub fn is_current(&self, generation: u64) -> bool {
+        self.active == Some(generation)
+    }
+}
+
+/// Proof that a writer holds the token in [`WriterState`]. Releases it when dropped.
+///
+/// The token can move between threads, but is never `Sync`. That carries over to [`WriteUnit`] and
+/// [`WriteTxn`], so the writer is only ever driven from one thread at a time, and nothing behind
+/// its `&self` methods needs to be synchronized.
+struct WriterToken {
+    core: Arc<DbCore>,
+    generation: u64,
+    unsync: PhantomData<Cell<()>>,
+}
+
+impl WriterToken {
+    fn claim(core: &Arc<DbCore>) -> Result<Self, AllocError> {
+        let generation = core.writer.lock().unwrap().claim()?;
+        Ok(Self {
+            core: core.clone(),
+            generation,
+            unsync: PhantomData,
+        })
+    }
+
+    /// Check that this token hasn't been superseded by a recovered writer.
+    fn is_current(&self) -> bool {
+        self.core.writer.lock().unwrap().is_current(self.generation)
+    }
+}
+
+impl Drop for WriterToken {
+    fn drop(&mut self) {
+        if let Ok(mut writer) = self.core.writer.lock() {
+            writer.release(self.generation);
+        }
+    }
+}
+
 struct DbCore {
     root: Mutex<RootData>,
     read_pages: Mutex<PageReadTracker>,
     storage: Mutex<StorageInner>,
+    writer: Mutex<WriterState>,
+    /// Number of read transactions currently open
+    readers: AtomicUsize,
+    /// The first error from checking in a dropped [`ReadBlock`], kept for the writer to pick up
+    checkin_error: Mutex<Option<AllocError>>,
+    /// How the root pages looked on open
+    open_report: OpenReport,
+    /// The largest the file may grow to, if it's limited. Always whole blocks, and never less
+    /// than the file length on open.
+    max_size: Option<u64>,
+    /// Whether freelist pages have their hashes checked when they're loaded
+    verify_checksums: bool,
+    /// The most recent allocations and frees
+    #[cfg(feature = "alloc-audit")]
+    audit: Mutex<AuditLog>,
+}
+
+impl DbCore {
+    /// Everything in [`Stats`] but the space usage, which only the writer knows.
+    fn stats(&self) -> Stats {
+        let root = self.root.lock().unwrap();
+        let (file_len, newest_id, durable_id, oldest_id) = (
+            root.data_len,
+            root.id_tracker.newest_id(),
+            root.durable.0.id,
+            root.id_tracker.oldest_id(),
+        );
+        drop(root);
+        Stats {
+            mapped_bytes: self.storage.lock().unwrap().mapped_len() as u64,
+            file_len,
+            newest_id,
+            durable_id,
+            oldest_id,
+            readers: self.readers.load(AtomicOrdering::Relaxed),
+            space: None,
+        }
+    }
 }
 
 struct RootCheckout {
     id: u64,
-    root: Vec<u8>,
-    freelist: u64,
+    root: Arc<[u8]>,
+    freelist: ByteOffset,
+    /// How far into the file this transaction's allocations can reach
+    file_len: u64,
 }
 
 #[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
@@ -287,43 +747,94 @@ struct RootHeader {
     len: u16,
     version: u8,
     _reserved0: u8,
-    _reserved1: u32,
+    geometry: [u8; 4],
     file_len: u64,
     id: u64,
     freelist: u64,
 }
 
+/// The sizes baked into the file layout, in the order the root header records their log2.
+const GEOMETRY: [(&str, usize); 4] = [
+    ("block size", BLOCK_SIZE),
+    ("cluster size", CLUSTER_SIZE),
+    ("root size", ROOT_SIZE),
+    ("page size", PAGE_SIZE),
+];
+
+impl RootHeader {
+    /// The geometry record for a file created by this build.
+    fn geometry() -> [u8; 4] {
+        GEOMETRY.map(|(_, size)| size.trailing_zeros() as u8)
+    }
+
+    /// Check the recorded geometry against the one this build was compiled with. Files from
+    /// before it was recorded leave it zeroed, and are taken to match.
+    fn check_geometry(&self) -> Result<(), FormatError> {
+        if self.geometry == [0; 4] {
+            return Ok(());
+        }
+        for ((field, size), shift) in GEOMETRY.into_iter().zip(self.geometry) {
+            if u32::from(shift) != size.trailing_zeros() {
+                return Err(FormatError::Geometry {
+                    field,
+                    stored: 1u64.checked_shl(shift.into()).unwrap_or(u64::MAX),
+                    compiled: size as u64,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
 /// The Root data that we track and use to synchronize between readers, the writer, and the committer.
 struct RootData {
     /// ID tracking
     id_tracker: IdTracker,
-    /// The remaining root data from the most recent writer
-    root: Vec<u8>,
+    /// The remaining root data from the most recent writer. Shared with every checkout, so that
+    /// handing it out under the root lock never copies it.
+    root: Arc<[u8]>,
     /// The freelist page
-    freelist: u64,
+    freelist: ByteOffset,
     /// The loaded file type
     file_type: [u8; 8],
     /// The stored file size
     file_len: u64,
+    /// The file size as of the newest transaction. Can run past `file_len` when the file has
+    /// grown further than the freelist accounts for.
+    data_len: u64,
+    /// ID of the newest transaction that shrank `data_len`. Readers from before it may still
+    /// reach past the end of the file.
+    shrunk_at: u64,
+    /// The newest transactions that aren't durable yet, with `data_len` as of each and the pages
+    /// each wrote, oldest first. Holds up to [`COMMIT_HISTORY_LEN`] of them.
+    history: VecDeque<(RootSnapshot, u64, RunSet)>,
+    /// The newest durable transaction, with `data_len` as of it. The committer keeps its ID
+    /// checked out, so readers can be checked out at it too.
+    durable: (RootSnapshot, u64),
 }
 
 
 impl RootData {
     /// Create a brand new root data structure
-    pub fn new(file_type: &[u8; 8], freelist: u64, file_len: u64) -> Self {
+    pub fn new(file_type: &[u8; 8], freelist: ByteOffset, file_len: u64) -> Self {
         Self {
             file_type: file_type.to_owned(),
             id_tracker: IdTracker::new(0),
-            root: Vec::new(),
+            root: Arc::default(),
             freelist,
             file_len,
+            data_len: file_len,
+            shrunk_at: 0,
+            history: VecDeque::new(),
+            durable: Default::default(),
         }
+        .loaded_as_durable()
     }
 
     pub fn load(root: &[u8]) -> Result<Self, AllocError> {
         let (header, rem) = root.split_at(std::mem::size_of::<RootHeader>());
         let header: &RootHeader = bytemuck::from_bytes(header);
-        if header.version != 1 {
+        if header.version != format::ROOT_FORMAT_VERSION {
             return Err(AllocError::Open(std::io::Error::other(
                 "Unrecognized version number in header",
             )));
@@ -354,37 +865,79 @@ impl RootData {
                 "Invalid xxHash of header data",
             )));
         }
+        header.check_geometry().map_err(AllocError::DataFormat)?;
 
         Ok(Self {
             file_type: header.file_type,
             id_tracker: IdTracker::new(header.id),
-            root: root_data.to_vec(),
-            freelist: header.freelist,
+            root: Arc::from(root_data),
+            freelist: ByteOffset::new(header.freelist)?,
             file_len: header.file_len,
-        })
+            data_len: header.file_len,
+            shrunk_at: 0,
+            history: VecDeque::new(),
+            durable: Default::default(),
+        }
+        .loaded_as_durable())
+    }
+
+    /// Whatever was just created or loaded is as durable as it gets until the first commit.
+    fn loaded_as_durable(mut self) -> Self {
+        self.durable = (self.snapshot(), self.data_len);
+        self
+    }
+
+    /// Load whichever of the two root pages is newest and intact, reporting which one that was
+    /// and why the other couldn't be used, if it couldn't.
+    pub fn load_newest(root0: &[u8], root1: &[u8]) -> Result<(Self, OpenReport), AllocError> {
+        let report = |slot, damaged| OpenReport {
+            root_slot: Some(slot),
+            damaged,
+            ..OpenReport::default()
+        };
+        match (Self::load(root0), Self::load(root1)) {
+            (Err(e0), Err(_)) => Err(e0),
+            (Ok(root), Err(e1)) => Ok((root, report(0, Some(e1)))),
+            (Err(e0), Ok(root)) => Ok((root, report(1, Some(e0)))),
+            (Ok(root0), Ok(root1)) => match root0.id_tracker.newest.cmp(&root1.id_tracker.newest) {
+                Ordering::Equal => Err(AllocError::DataFormat(FormatError::DuplicateIds)),
+                Ordering::Greater => Ok((root0, report(0, None))),
+                Ordering::Less => Ok((root1, report(1, None))),
+            },
+        }
+    }
+
+    /// Check the recorded file length against the actual size of the file. A file shorter than
+    /// recorded has lost data, and fails with [`FormatError::Truncated`]. A longer one most
+    /// likely grew right before a crash, without any commit recording it, so the blocks past the
+    /// recorded end are returned to be handed out as free space.
+    pub fn unrecorded_blocks(&self, actual: u64) -> Result<StepBy<Range<u64>>, AllocError> {
+        if actual < self.file_len {
+            return Err(AllocError::DataFormat(FormatError::Truncated {
+                recorded: self.file_len,
+                actual,
+            }));
+        }
+        if self.file_len < MIN_DB_SIZE as u64 || !self.file_len.is_multiple_of(BLOCK_SIZE as u64) {
+            return Err(AllocError::DataFormat(FormatError::FileSize));
+        }
+        Ok((self.file_len..actual).step_by(BLOCK_SIZE))
     }
 
     pub fn store(&self, dst: &mut Vec<u8>) -> Result<(), AllocError> {
-        let len = u16::try_from(self.root.len()).map_err(|_| {
-            AllocError::Other("Tried to write out root page data that's at least 64kiB long")
-        })?;
-        let header = RootHeader {
+        self.snapshot().store(dst)
+    }
+
+    /// Grab everything [`store`][Self::store] writes out, without copying the root data. Cheap
+    /// enough to do while holding the root lock, leaving the actual serializing for after.
+    pub fn snapshot(&self) -> RootSnapshot {
+        RootSnapshot {
             file_type: self.file_type,
-            len,
-            version: 1,
-            _reserved0: 0,
-            _reserved1: 0,
             id: self.id_tracker.newest,
             freelist: self.freelist,
             file_len: self.file_len,
-        };
-
-        dst.clear();
-        dst.extend_from_slice(bytemuck::bytes_of(&header));
-        dst.extend_from_slice(&self.root);
-        let hash = xxhash_rust::xxh3::xxh3_64(dst);
-        dst.extend_from_slice(hash.to_le_bytes().as_slice());
-        Ok(())
+            root: self.root.clone(),
+        }
     }
 
     /// Check out for a reader
@@ -394,6 +947,18 @@ impl RootData {
             freelist: self.freelist,
             id,
             root: self.root.clone(),
+            file_len: self.data_len,
+        }
+    }
+
+    /// Check out for a reader, as of the newest durable transaction instead of the newest one
+    pub fn checkout_durable(&mut self) -> RootCheckout {
+        let (snapshot, data_len) = &self.durable;
+        RootCheckout {
+            freelist: snapshot.freelist,
+            id: self.id_tracker.checkout_at(snapshot.id),
+            root: snapshot.root.clone(),
+            file_len: *data_len,
         }
     }
 
@@ -402,77 +967,431 @@ impl RootData {
         self.id_tracker.checkin(co.id);
     }
 
-    /// Update from a writer
-    pub fn update(&mut self, update: &RootCheckout) {
-        self.root.clear();
-        self.root.extend_from_slice(&update.root);
+    /// Update from a writer. The writer builds the new root data beforehand, so this only swaps
+    /// it in. Its freelist accounts for the whole file, so the stored file size follows the
+    /// writer's. A writer that gave up the end of the file lowers it, so the file can be truncated
+    /// once this is durable.
+    ///
+    /// `written` holds every page the writer wrote to, for the committer to flush. A transaction
+    /// pushed out of the history hands its pages on to the next one, as they still need flushing.
+    pub fn update(&mut self, update: &RootCheckout, mut written: RunSet) {
+        self.root = update.root.clone();
         self.id_tracker.set_newest(update.id);
         self.freelist = update.freelist;
+        if update.file_len < self.data_len {
+            self.shrunk_at = update.id;
+        }
+        self.file_len = update.file_len;
+        self.data_len = update.file_len;
+        if self.history.len() == COMMIT_HISTORY_LEN {
+            if let Some((_, _, dropped)) = self.history.pop_front() {
+                let next = self.history.front_mut().map_or(&mut written, |(_, _, w)| w);
+                for (start, len) in dropped.runs() {
+                    next.insert_range(start, len);
+                }
+            }
+        }
+        self.history.push_back((self.snapshot(), self.data_len, written));
     }
-}
 
-/// A unit for spawning read transactions
-pub struct ReadUnit {
-    storage: RawMemory,
-    core: Arc<DbCore>,
-}
+    /// Grab the root as of transaction `id`, along with the data length then, if it's the newest
+    /// or still in the history.
+    pub fn snapshot_at(&self, id: u64) -> Option<(RootSnapshot, u64)> {
+        if id == self.id_tracker.newest {
+            return Some((self.snapshot(), self.data_len));
+        }
+        self.history
+            .iter()
+            .find(|(snapshot, _, _)| snapshot.id == id)
+            .map(|(snapshot, data_len, _)| (snapshot.clone(), *data_len))
+    }
 
-impl ReadUnit {
-    /// Spawn a read transaction
-    pub fn reader(&self) -> ReadTxn {
-        let core = self.core.clone();
-        ReadTxn {
-            storage: self.storage.clone(),
-            core,
-            root: self.core.root.lock().unwrap().checkout(),
+    /// Every page written by the transactions still in the history, up to and including `id`.
+    pub fn written_through(&self, id: u64) -> RunSet {
+        let mut written = RunSet::new();
+        for (_, _, pages) in self.history.iter().take_while(|(s, _, _)| s.id <= id) {
+            for (start, len) in pages.runs() {
+                written.insert_range(start, len);
+            }
         }
+        written
     }
-}
 
-impl Clone for ReadUnit {
-    fn clone(&self) -> Self {
-        let core = self.core.clone();
-        Self {
-            storage: self.storage.clone(),
-            core,
+    /// Drop the history up to and including transaction `id`, once it's durable.
+    pub fn forget_through(&mut self, id: u64) {
+        while self.history.front().is_some_and(|(snapshot, _, _)| snapshot.id <= id) {
+            self.history.pop_front();
         }
     }
 }
 
-/// An active read transaction. Prevents the allocator from reusing any pages that have been freed
-/// since the start of this transaction.
-pub struct ReadTxn {
-    storage: RawMemory,
-    core: Arc<DbCore>,
-    root: RootCheckout,
+/// What state the root pages were found in when the database was opened. See
+/// [`ReadUnit::open_report`].
+#[derive(Debug, Default)]
+pub struct OpenReport {
+    root_slot: Option<usize>,
+    damaged: Option<AllocError>,
+    advice_disabled: bool,
 }
 
-impl Drop for ReadTxn {
-    fn drop(&mut self) {
-        self.core.root.lock().unwrap().checkin(&self.root);
+impl OpenReport {
+    /// Which root page the database was loaded from, 0 or 1. `None` if this open created the
+    /// database.
+    pub fn root_slot(&self) -> Option<usize> {
+        self.root_slot
+    }
+
+    /// Why the other root page couldn't be used, if it couldn't. This usually means the last
+    /// commit before closing was torn partway through writing its root page, and was lost. A root
+    /// page that loads fine but points at a corrupt freelist is passed over the same way. The
+    /// damaged page is the first one rewritten on the next commit.
+    pub fn damaged_root(&self) -> Option<&AllocError> {
+        self.damaged.as_ref()
+    }
+
+    /// Check if opening had to fall back on a root page because the other was damaged.
+    pub fn recovered(&self) -> bool {
+        self.damaged.is_some()
+    }
+
+    /// Check if the system's pages were too large to advise the OS about our clusters with. If
+    /// so, freed blocks aren't hole punched and newly mapped space isn't prefaulted. Freed blocks
+    /// are still reused, but the file never gives their space back to the file system.
+    pub fn advice_disabled(&self) -> bool {
+        self.advice_disabled
     }
 }
 
-impl ReadTxn {
-    /// Read an arbitrary point of memory in the memory map.
-    unsafe fn read(&mut self, range: BlockRange) -> Result<&'static [u8], AllocError> {
-        self.storage
-            .get(&self.core, range)
-            .map(|x: &'static mut [u8]| x as &'static [u8])
+/// A cheap look at the state of the database, from [`WriteUnit::stats`] or [`ReadUnit::stats`].
+/// Nothing here takes a scan of the file to work out.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes of the file currently memory-mapped. Can run past `file_len`, as maps grow ahead of
+    /// the file and aren't always shrunk along with it.
+    pub mapped_bytes: u64,
+    /// Length of the file as of the newest transaction, in bytes
+    pub file_len: u64,
+    /// ID of the newest committed transaction
+    pub newest_id: u64,
+    /// ID of the newest transaction the [`CommitUnit`] has made durable. Everything between it
+    /// and `newest_id` is lost if the process dies now.
+    pub durable_id: u64,
+    /// ID of the oldest transaction something still has checked out
+    pub oldest_id: u64,
+    /// Number of read transactions currently open
+    pub readers: usize,
+    /// How the file's pages are being used. Only the writer keeps track of this, so it's `None`
+    /// when coming from a [`ReadUnit`].
+    pub space: Option<SpaceStats>,
+}
+
+/// How the pages of the file are being used, as of the writer's last commit. The free counts
+/// don't overlap: a page in a wholly free cluster isn't counted as a free page, and neither is a
+/// cluster in a wholly free block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpaceStats {
+    /// Pages that aren't available to allocate. Besides the ones in use, this counts pages freed
+    /// by transactions that readers might still see, and blocks still waiting to be hole punched.
+    pub allocated_pages: u64,
+    /// Free pages in partly taken clusters
+    pub free_pages: u64,
+    /// Wholly free clusters
+    pub free_clusters: u64,
+    /// Wholly free blocks
+    pub free_blocks: u64,
+}
+
+impl SpaceStats {
+    /// Total bytes available to allocate.
+    pub fn free_bytes(&self) -> u64 {
+        self.free_pages * PAGE_SIZE as u64
+            + self.free_clusters * CLUSTER_SIZE as u64
+            + self.free_blocks * BLOCK_SIZE as u64
     }
+}
 
-    /// Check out a point in memory for long-term reads.
+/// The contents of a root page, as of some point in time.
+#[derive(Clone, Default)]
+struct RootSnapshot {
+    file_type: [u8; 8],
+    id: u64,
+    freelist: ByteOffset,
+    file_len: u64,
+    root: Arc<[u8]>,
+}
+
+impl RootSnapshot {
+    /// How many bytes [`store`][Self::store] writes out.
+    pub fn stored_len(&self) -> usize {
+        format::ROOT_HEADER_SIZE + self.root.len() + format::ROOT_HASH_SIZE
+    }
+
+    /// Serialize into `dst`: the header, the root data, and then a hash of both.
+    pub fn store(&self, dst: &mut Vec<u8>) -> Result<(), AllocError> {
+        let len = u16::try_from(self.root.len()).map_err(|_| AllocError::RootTooLarge {
+            len: self.stored_len(),
+            max: format::ROOT_HEADER_SIZE + u16::MAX as usize + format::ROOT_HASH_SIZE,
+        })?;
+        let header = RootHeader {
+            file_type: self.file_type,
+            len,
+            version: format::ROOT_FORMAT_VERSION,
+            _reserved0: 0,
+            geometry: RootHeader::geometry(),
+            id: self.id,
+            freelist: self.freelist.get(),
+            file_len: self.file_len,
+        };
+
+        dst.clear();
+        dst.extend_from_slice(bytemuck::bytes_of(&header));
+        dst.extend_from_slice(&self.root);
+        let hash = xxhash_rust::xxh3::xxh3_64(dst);
+        dst.extend_from_slice(hash.to_le_bytes().as_slice());
+        Ok(())
+    }
+}
+
+/// A unit for spawning read transactions
+pub struct ReadUnit {
+    storage: RawMemory,
+    core: Arc<DbCore>,
+    #[cfg(feature = "read-cache")]
+    cache: Option<Arc<ReadCache>>,
+}
+
+impl ReadUnit {
+    fn new(storage: RawMemory, core: Arc<DbCore>) -> Self {
+        Self {
+            storage,
+            core,
+            #[cfg(feature = "read-cache")]
+            cache: None,
+        }
+    }
+
+    /// Share a new [`ReadCache`] holding up to `max_pages` pages between every read transaction
+    /// spawned from this unit and its clones.
+    #[cfg(feature = "read-cache")]
+    pub fn with_read_cache(mut self, max_pages: usize) -> Self {
+        self.cache = Some(Arc::new(ReadCache::new(self.core.clone(), max_pages)));
+        self
+    }
+
+    /// What state the root pages were in when the database was opened.
+    pub fn open_report(&self) -> &OpenReport {
+        &self.core.open_report
+    }
+
+    /// The file type the database was created with. See [`OpenOptions::file_type`].
+    pub fn file_type(&self) -> [u8; 8] {
+        self.core.root.lock().unwrap().file_type
+    }
+
+    /// Get the current [`Stats`] for the database. Space usage is only tracked by the writer, so
+    /// [`Stats::space`] is always `None`; use [`WriteUnit::stats`] for that.
+    pub fn stats(&self) -> Stats {
+        self.core.stats()
+    }
+
+    /// The read cache used by this unit's transactions, if there is one.
+    #[cfg(feature = "read-cache")]
+    pub fn read_cache(&self) -> Option<&ReadCache> {
+        self.cache.as_deref()
+    }
+
+    /// Spawn a read transaction
+    pub fn reader(&self) -> ReadTxn {
+        let root = self.core.root.lock().unwrap().checkout();
+        self.reader_at(root)
+    }
+
+    /// Spawn a read transaction that only sees what's durable on disk: the newest transaction
+    /// the [`CommitUnit`] has committed, rather than the newest one the writer has. For
+    /// applications that mustn't serve anything a crash could still take back.
     ///
-    /// This doesn't check to make sure the range is page-aligned - this must be upheld by the
-    /// caller. The page range must also be a region that was previously allocated.
-    unsafe fn get_block(&mut self, range: BlockRange) -> Result<ReadBlock, AllocError> {
+    /// This lags behind [`reader`][Self::reader] until the next commit, and stays behind for as
+    /// long as commits keep failing.
+    pub fn reader_durable(&self) -> ReadTxn {
+        let root = self.core.root.lock().unwrap().checkout_durable();
+        self.reader_at(root)
+    }
+
+    fn reader_at(&self, root: RootCheckout) -> ReadTxn {
+        #[cfg(feature = "read-cache")]
+        if let Some(cache) = &self.cache {
+            cache.advance(root.id);
+        }
+        let core = self.core.clone();
+        core.readers.fetch_add(1, AtomicOrdering::Relaxed);
+        ReadTxn {
+            storage: self.storage.clone(),
+            core,
+            root,
+            #[cfg(feature = "read-cache")]
+            cache: self.cache.clone(),
+            #[cfg(feature = "read-stats")]
+            stats: ReadStats::default(),
+        }
+    }
+}
+
+impl Clone for ReadUnit {
+    fn clone(&self) -> Self {
+        let core = self.core.clone();
+        Self {
+            storage: self.storage.clone(),
+            core,
+            #[cfg(feature = "read-cache")]
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+/// An active read transaction. Prevents the allocator from reusing any pages that have been freed
+/// since the start of this transaction.
+pub struct ReadTxn {
+    storage: RawMemory,
+    core: Arc<DbCore>,
+    root: RootCheckout,
+    #[cfg(feature = "read-cache")]
+    cache: Option<Arc<ReadCache>>,
+    #[cfg(feature = "read-stats")]
+    stats: ReadStats,
+}
+
+impl Drop for ReadTxn {
+    fn drop(&mut self) {
+        self.core.root.lock().unwrap().checkin(&self.root);
+        self.core.readers.fetch_sub(1, AtomicOrdering::Relaxed);
+    }
+}
+
+impl ReadTxn {
+    /// The transaction ID this reader has checked out. This is the generation that tree handles
+    /// opened through this reader belong to; page numbers taken from an older generation may have
+    /// since been freed and reused.
+    pub fn generation(&self) -> u64 {
+        self.root.id
+    }
+
+    /// How many times the database's backing storage has grown. Unrelated to
+    /// [`generation`][Self::generation]: this changes whenever the writer expands the file, even
+    /// mid-transaction, so comparing it across calls is a cheap way to notice the database
+    /// getting bigger.
+    pub fn storage_generation(&self) -> u64 {
+        self.storage.current.load(AtomicOrdering::Acquire)
+    }
+
+    /// Iterate over every allocated range in this snapshot, excluding the root pages. See
+    /// [`LiveRangeIter`] for details.
+    pub fn live_ranges(&self) -> Result<LiveRangeIter<'_>, AllocError> {
+        LiveRangeIter::new(self)
+    }
+
+    /// Iterate over every range that was free in an older snapshot but is allocated in this one.
+    /// See [`ChangedRangeIter`] for details.
+    ///
+    /// Fails with [`AllocError::ForeignTransaction`] if `old` came from a different database.
+    pub fn changed_since<'a>(
+        &'a self,
+        old: &'a ReadTxn,
+    ) -> Result<ChangedRangeIter<'a>, AllocError> {
+        if !Arc::ptr_eq(&old.core, &self.core) {
+            return Err(AllocError::ForeignTransaction);
+        }
+        ChangedRangeIter::new(self, old)
+    }
+
+    /// The transaction ID this reader has checked out. Same as [`generation`][Self::generation].
+    pub fn id(&self) -> u64 {
+        self.root.id
+    }
+
+    /// The root data committed with this transaction. This is where applications keep track of
+    /// their tree root pages, usually as a [`TxnRoots`] payload.
+    pub fn root_data(&self) -> &[u8] {
+        &self.root.root
+    }
+
+    /// Statistics on the reads made so far in this transaction.
+    #[cfg(feature = "read-stats")]
+    pub fn read_stats(&self) -> &ReadStats {
+        &self.stats
+    }
+
+    /// Read `num_pages` pages starting at the byte offset `page`, as of this transaction. The
+    /// slice stays valid for as long as the transaction does, as nothing allocated in it can be
+    /// reused before then. Pages that weren't allocated as of this transaction hold no data, and
+    /// the writer may change them at any point.
+    ///
+    /// Fails with [`AllocError::Misaligned`] if `page` isn't on a page boundary,
+    /// [`AllocError::RootAccess`] if it's within the root pages, or [`AllocError::InvalidAccess`]
+    /// if the range runs past the end of the file as of this transaction.
+    pub fn read_pages(&mut self, page: u64, num_pages: usize) -> Result<&[u8], AllocError> {
+        let range = data_range(page, num_pages, self.root.file_len)?;
+        // Safety: the range is aligned, outside the root pages, and within the file as of our
+        // generation, which stays checked out until we're dropped.
+        unsafe { self.read(range) }
+    }
+
+    /// Tell the OS that `num_pages` pages starting at the byte offset `page` are about to be read
+    /// through in order, so it reads ahead of them more aggressively. This is only a hint, and
+    /// does nothing on Windows.
+    ///
+    /// It's the only hint on the read side. Pages that have been read go back to the OS's usual
+    /// page cache handling, which drops them under memory pressure.
+    ///
+    /// Fails the same way as [`read_pages`][Self::read_pages] does for a bad range, with
+    /// [`AllocError::InvalidAccess`] if the range spans two memory maps, or with
+    /// [`AllocError::Advise`] if the OS rejects the hint.
+    pub fn advise_sequential(&self, page: u64, num_pages: usize) -> Result<(), AllocError> {
+        let range = data_range(page, num_pages, self.root.file_len)?;
+        advise(&self.core, range, Advice::Sequential)
+    }
+
+    /// Read an arbitrary point of memory in the memory map. The range must be aligned to
+    /// [`ALLOC_ALIGN`], and can't be within the root pages.
+    unsafe fn read(&mut self, range: BlockRange) -> Result<&'static [u8], AllocError> {
+        range.check_data()?;
+        let mem = self.load(range)?;
+        #[cfg(feature = "read-stats")]
+        self.stats.record(mem);
+        Ok(mem)
+    }
+
+    /// Look up memory for an aligned range, going through the read cache if there is one.
+    unsafe fn load(&mut self, range: BlockRange) -> Result<&'static [u8], AllocError> {
+        #[cfg(feature = "read-cache")]
+        if let Some(mem) = self.cache.as_ref().and_then(|c| c.get(self.root.id, range)) {
+            return Ok(mem);
+        }
         let mem = self
             .storage
             .get(&self.core, range)
             .map(|x: &'static mut [u8]| x as &'static [u8])?;
+        // Safety: we're reading in our own generation, and we're still alive.
+        #[cfg(feature = "read-cache")]
+        if let Some(cache) = &self.cache {
+            cache.insert(self.root.id, range, mem);
+        }
+        Ok(mem)
+    }
+
+    /// Check out a point in memory for long-term reads.
+    ///
+    /// Fails if the range isn't aligned to [`ALLOC_ALIGN`] or is within the root pages. The page
+    /// range must also be a region that was previously allocated. Every page the range covers
+    /// stays checked out until the block is dropped, so the writer won't reuse any of them.
+    unsafe fn get_block(&mut self, range: BlockRange) -> Result<ReadBlock, AllocError> {
+        range.check_data()?;
+        let mem = self.load(range)?;
+        #[cfg(feature = "read-stats")]
+        self.stats.record(mem);
+        self.core.read_pages.lock().unwrap().checkout(range);
         Ok(ReadBlock {
             mem,
-            page: range.start as u64,
+            range,
             core: self.core.clone(),
         })
     }
@@ -480,13 +1399,46 @@ impl ReadTxn {
 
 struct ReadBlock {
     mem: &'static [u8],
-    page: u64,
+    range: BlockRange,
     core: Arc<DbCore>,
 }
 
+/// The range of `num_pages` pages starting at the byte offset `page`, checked the same way as for
+/// [`ReadTxn::read_pages`] against a file `file_len` bytes long.
+fn data_range(page: u64, num_pages: usize, file_len: u64) -> Result<BlockRange, AllocError> {
+    let range = BlockRange::from_pages(page, num_pages)?;
+    range.check_data()?;
+    if (range.start + range.len) as u64 > file_len {
+        return Err(AllocError::InvalidAccess {
+            offset: range.start,
+            len: range.len,
+        });
+    }
+    Ok(range)
+}
+
+/// Pass `advice` about a range on to the storage, for the hints on allocations and transactions.
+fn advise(core: &DbCore, range: BlockRange, advice: Advice) -> Result<(), AllocError> {
+    let Ok(storage) = core.storage.lock() else {
+        return Err(AllocError::MutexPoisoned);
+    };
+    storage.advise(range, advice)
+}
+
 impl Drop for ReadBlock {
     fn drop(&mut self) {
-        self.core.read_pages.lock().unwrap().checkin(self.page);
+        // Panicking here would poison the read tracker for everyone, so the writer gets the error
+        // instead
+        let Ok(mut read_pages) = self.core.read_pages.lock() else {
+            return;
+        };
+        let res = read_pages.checkin(self.range);
+        drop(read_pages);
+        if let Err(e) = res {
+            if let Ok(mut slot) = self.core.checkin_error.lock() {
+                slot.get_or_insert(e);
+            }
+        }
     }
 }
 
@@ -507,7 +1459,7 @@ impl AsRef<[u8]> for ReadBlock {
 impl fmt::Debug for ReadBlock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ReadBlock")
-            .field("page", &self.page)
+            .field("page", &ByteOffset(self.range.start as u64))
             .field("size", &self.mem.len())
             .finish()
     }
@@ -515,25 +1467,156 @@ impl fmt::Debug for ReadBlock {
 
 /// A long-term allocated block of memory that hasn't yet been committed to the database.
 ///
-/// Write Allocations enable multithreaded bulk writes. Many can be set up at once with [`WriteTxn::write_alloc`]
+/// Write Allocations enable multithreaded bulk writes. Many can be set up at once with [`WriteTxn::new_allocation`]
+///
+/// With the `single-threaded` feature, they can't be sent to other threads.
+///
+/// Hand an allocation back with [`WriteTxn::use_allocation`] to put it in the database, or with
+/// [`finish`][Self::finish] from a thread without the writer. Dropping it instead releases its
+/// pages, which the writer picks up at the start of its next transaction.
+///
+/// Only the first [`written`][Self::written] bytes are kept once the allocation goes back into a
+/// transaction; the rest is zeroed, so whatever the pages held before never shows up to readers.
+/// [`fill_from`][Self::fill_from] and [`writer`][Self::writer] keep track of this as they go.
+/// Anything written through the slice directly needs [`set_written`][Self::set_written].
 pub struct WriteAlloc {
     mem: &'static mut [u8],
     page: u64,
-    chan: mpsc::Sender<u64>,
+    /// How many bytes from the start have been written
+    written: usize,
+    chan: QueueSender<AllocReturn>,
     core: Arc<DbCore>,
 }
 
+/// How a [`WriteAlloc`] came back to the writer without going through
+/// [`WriteTxn::use_allocation`].
+enum AllocReturn {
+    /// Dropped, so the page at this offset is free again
+    Dropped(u64),
+    /// Handed back with [`WriteAlloc::finish`], ready to go into the database
+    Finished(WriteAlloc),
+}
+
 impl WriteAlloc {
     /// Get the page number that was allocated.
     fn page(&self) -> u64 {
         self.page
     }
+
+    /// Where the allocation is, and how long it is. Its length is always a whole number of pages.
+    pub fn alloc(&self) -> Alloc {
+        Alloc {
+            page: ByteOffset(self.page),
+            len: self.mem.len(),
+        }
+    }
+
+    /// Tell the OS the allocation is about to be written, so it can start faulting it in ahead
+    /// of time. This is only a hint, and does nothing on Windows.
+    pub fn advise_willneed(&self) -> Result<(), AllocError> {
+        advise(&self.core, self.range(), Advice::WillNeed)
+    }
+
+    /// Tell the OS the allocation won't be touched again for a while, so its pages can be
+    /// dropped from memory once they're written out. Nothing written so far is lost. This is
+    /// only a hint, and does nothing on Windows or for anonymous maps.
+    pub fn advise_dontneed(&self) -> Result<(), AllocError> {
+        advise(&self.core, self.range(), Advice::DontNeed)
+    }
+
+    /// The range of bytes the allocation covers.
+    fn range(&self) -> BlockRange {
+        BlockRange::new(self.page as usize, self.mem.len())
+    }
+
+    /// How many bytes from the start of the allocation have been written.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Mark the first `len` bytes as written, for data put in place through the slice. Clamped to
+    /// the length of the allocation.
+    pub fn set_written(&mut self, len: usize) {
+        self.written = len.min(self.mem.len());
+    }
+
+    /// Read from `src` straight into the allocation, picking up after what's already been
+    /// written, until it's full or `src` runs dry. Returns the number of bytes read in.
+    ///
+    /// If `src` fails partway through, whatever it had read in before then still counts as
+    /// written.
+    pub fn fill_from(&mut self, src: &mut impl Read) -> io::Result<usize> {
+        let start = self.written;
+        while self.written < self.mem.len() {
+            match src.read(&mut self.mem[self.written..]) {
+                Ok(0) => break,
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.written - start)
+    }
+
+    /// Write into the allocation, picking up after what's already been written. Writes past the
+    /// end fail with [`io::ErrorKind::WriteZero`].
+    pub fn writer(&mut self) -> impl Write + '_ {
+        AllocWriter(self)
+    }
+
+    /// Hand the allocation back to the writer from any thread. It goes into the writer's current
+    /// transaction if that's waiting on it with [`CommitPolicy::WaitForAll`], and otherwise into
+    /// the next one, just like passing it to [`WriteTxn::use_allocation`].
+    pub fn finish(self) {
+        let chan = self.chan.clone();
+        chan.send(AllocReturn::Finished(self));
+    }
+
+    /// Zero everything past what's been written.
+    fn clear_unwritten(&mut self) {
+        self.mem[self.written..].fill(0);
+    }
+
+    /// Let go of a committed allocation. Its page is part of the database now, so unlike
+    /// dropping it, this doesn't hand the page back to the writer.
+    fn commit(self) {
+        let alloc = std::mem::ManuallyDrop::new(self);
+        // Safety: the allocation is never used again, so these are each dropped exactly once
+        unsafe {
+            drop(std::ptr::read(&alloc.chan));
+            drop(std::ptr::read(&alloc.core));
+        }
+    }
+}
+
+/// Sink for [`WriteAlloc::writer`].
+struct AllocWriter<'a>(&'a mut WriteAlloc);
+
+impl Write for AllocWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let alloc = &mut *self.0;
+        let rem = &mut alloc.mem[alloc.written..];
+        if rem.is_empty() && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "write allocation is full",
+            ));
+        }
+        let len = rem.len().min(buf.len());
+        rem[..len].copy_from_slice(&buf[..len]);
+        alloc.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl Drop for WriteAlloc {
     /// Release the allocated page back to the allocator when dropped
     fn drop(&mut self) {
-        let _ = self.chan.send(self.page);
+        self.chan.send(AllocReturn::Dropped(self.page));
     }
 }
 
@@ -563,6 +1646,58 @@ impl AsMut<[u8]> for WriteAlloc {
     }
 }
 
+/// The low bits of an `available_16k` entry, marking which of the cluster's pages are taken.
+const CLUSTER_TAKEN_MASK: u64 = (1 << CLUSTER_PAGES) - 1;
+
+/// Pages in a cluster.
+const CLUSTER_PAGES: u64 = (CLUSTER_SIZE / PAGE_SIZE) as u64;
+
+/// Clusters in a block.
+const BLOCK_CLUSTERS: u64 = (BLOCK_SIZE / CLUSTER_SIZE) as u64;
+
+/// Carve a free run of `len` bytes starting at `start` up into the largest aligned pieces that
+/// fit, as `(offset, len)` pairs.
+fn carve(start: u64, len: u64) -> impl Iterator<Item = (u64, u64)> {
+    let end = start + len;
+    let mut page = start;
+    std::iter::from_fn(move || {
+        let remaining = end.checked_sub(page).filter(|remaining| *remaining > 0)?;
+        let size = if (page as usize & (BLOCK_SIZE - 1)) == 0 && remaining >= BLOCK_SIZE as u64 {
+            BLOCK_SIZE
+        } else if (page as usize & (CLUSTER_SIZE - 1)) == 0 && remaining >= CLUSTER_SIZE as u64 {
+            CLUSTER_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        let at = page;
+        page += size as u64;
+        Some((at, size as u64))
+    })
+}
+
+/// The availability lists and file length as they were when a transaction started, so aborting it
+/// can put them back.
+#[derive(Default)]
+struct TxnStart {
+    available_4k: Vec<u64>,
+    available_16k: Vec<u64>,
+    available_blocks: Vec<u64>,
+    file_len: u64,
+}
+
+/// A piece of the availability lists written out of memory to free pages, in the same format as
+/// the on-disk freelist, to keep a transaction within its memory budget.
+struct Spill {
+    /// Head page of the written-out runs
+    head: ByteOffset,
+    /// Pages the runs were written to, which are free again once they're read back in
+    pages: Vec<u64>,
+    /// Start of the first run
+    start: u64,
+    /// End of the last run
+    end: u64,
+}
+
 pub struct WriteUnitInner {
     /// Track which pages in the free list are not actually free
     taken: BTreeSet<u64>,
@@ -571,452 +1706,5451 @@ pub struct WriteUnitInner {
     /// Root data (only used by the Write Transaction)
     root: RootCheckout,
     /// Track which pages are marked as dirty
-    dirty: BTreeSet<u64>,
+    dirty: RunSet,
     /// Secondary "taken" tracker for use during transactions
-    taken_txn: BTreeSet<u64>,
-    /// List of available 4kiB pages
+    taken_txn: RunSet,
+    /// List of available 4kiB pages, folded into `available_16k` at the start of each transaction
     available_4k: Vec<u64>,
-    /// List of available 16kiB page clusters (with 4-bit tracking info in LSBs)
+    /// List of available 16kiB page clusters (with 4-bit tracking info in LSBs). Bit `i` set means
+    /// the cluster's `i`th page is taken, so a plain cluster offset is a wholly free cluster. A
+    /// cluster with every page taken is dropped from the list instead.
     available_16k: Vec<u64>,
     /// List of available blocks
     available_blocks: Vec<u64>,
+    /// Pieces of the availability lists spilled out of memory during the current transaction, with
+    /// the lowest runs last. They're read back in as the lists run dry, and all of them before the
+    /// transaction commits.
+    spilled: Vec<Spill>,
+    /// What the current transaction started out with, for aborting it
+    txn_start: TxnStart,
+    /// Runs given back by dropped write allocations during the current transaction. They aren't
+    /// in `txn_start`, so an abort has to free them again.
+    txn_returned: Vec<(u64, u64)>,
     /// List of allocations that were requested
     alloc_req: Vec<WriteAlloc>,
     /// List of allocations that will hopefully be committed
     alloc_completions: Vec<WriteAlloc>,
-    /// Sender to hand out to the write allocators (indicating when things become free)
-    alloc_send: mpsc::Sender<u64>,
-    /// Receiver to pick up when a write allocation is dropped
-    alloc_recv: mpsc::Receiver<u64>,
+    /// Length of every write allocation that's been handed out but neither committed nor dropped
+    alloc_lens: BTreeMap<u64, u64>,
+    /// Sender to hand out to the write allocators (indicating when they're finished or dropped)
+    alloc_send: QueueSender<AllocReturn>,
+    /// Receiver to pick up write allocations that were finished or dropped
+    alloc_recv: QueueReceiver<AllocReturn>,
     /// Sender to punch holes in the filesystem when freeing up a block
-    hole_punch_req: mpsc::Sender<u64>,
+    hole_punch_req: PageSender,
     /// Receiver of completed hole punching operations
-    hole_punch_resp: mpsc::Receiver<u64>,
+    hole_punch_resp: PageReceiver,
     /// List of hole punch requests we'll send out on committing a transaction
     hole_punch_future_req: Vec<u64>,
+    /// Freed blocks waiting on the committer to punch them out, after which they're free again
+    punching: BTreeSet<u64>,
+    /// Receiver of the IDs of transactions the committer has made durable
+    durable_recv: QueueReceiver<u64>,
+    /// The newest transaction the committer has made durable
+    durable_id: u64,
+    /// Runs freed by transactions, as `(page, len, state)`, that readers might still be using
+    pending_free: Vec<(u64, u64, FreePageState)>,
+    /// Pages holding the freelist of the newest committed transaction
+    freelist_pages: Vec<u64>,
+    /// The free space that freelist holds
+    freelist_runs: RunSet,
+    /// Soft limit on the memory used by the per-transaction bookkeeping
+    txn_memory_budget: Option<usize>,
+    /// Set once we've warned about going over budget in the current transaction
+    budget_warned: bool,
+    /// User-provided metrics hook
+    metrics: Metrics,
+    /// Our claim on being the database's only writer
+    token: WriterToken,
+    /// Access to the memory maps
+    storage: RawMemory,
+    /// Root pages of the trees touched by the current transaction
+    roots: TxnRoots,
+    /// Root data set for the current transaction, if it's been set
+    root_data: Option<Vec<u8>>,
+    /// Bytes to grow the file by when the availability lists run dry
+    growth_step: u64,
 }
 
-pub struct WriteUnit(WriteUnitInner);
-pub struct WriteTxn(WriteUnitInner);
+impl WriteUnitInner {
+    /// Set up the writer, claiming the database's writer token. Fails if another writer exists.
+    fn new(
+        core: Arc<DbCore>,
+        storage: RawMemory,
+        root: RootCheckout,
+        hole_punch_req: PageSender,
+        hole_punch_resp: PageReceiver,
+        durable_recv: QueueReceiver<u64>,
+        options: &OpenOptions,
+    ) -> Result<Self, AllocError> {
+        let growth_step = options.growth_step;
+        if growth_step == 0 || !growth_step.is_multiple_of(BLOCK_SIZE) {
+            return Err(AllocError::InvalidSize { size: growth_step });
+        }
+        let token = WriterToken::claim(&core)?;
+        let (alloc_send, alloc_recv) = queue();
+        let durable_id = root.id;
+        Ok(Self {
+            token,
+            storage,
+            taken: BTreeSet::new(),
+            core,
+            root,
+            dirty: RunSet::new(),
+            taken_txn: RunSet::new(),
+            available_4k: Vec::new(),
+            available_16k: Vec::new(),
+            available_blocks: Vec::new(),
+            spilled: Vec::new(),
+            txn_start: TxnStart::default(),
+            txn_returned: Vec::new(),
+            alloc_req: Vec::new(),
+            alloc_completions: Vec::new(),
+            alloc_lens: BTreeMap::new(),
+            alloc_send,
+            alloc_recv,
+            hole_punch_req,
+            hole_punch_resp,
+            hole_punch_future_req: Vec::new(),
+            punching: BTreeSet::new(),
+            durable_recv,
+            durable_id,
+            pending_free: Vec::new(),
+            freelist_pages: Vec::new(),
+            freelist_runs: RunSet::new(),
+            txn_memory_budget: options.txn_memory_budget,
+            budget_warned: false,
+            metrics: options.metrics.clone(),
+            roots: TxnRoots::default(),
+            root_data: None,
+            growth_step: growth_step as u64,
+        })
+    }
 
-impl WriteUnit {
-    pub fn write(mut self) -> WriteTxn {
-        // Process any pending operations from readers, write allocations, and the committer
-        while let Ok(page) = self.0.hole_punch_resp.try_recv() {
-            self.0.taken.remove(&page);
+    /// Approximate number of bytes used by the bookkeeping structures that grow over the course
+    /// of a single transaction.
+    fn txn_memory_usage(&self) -> usize {
+        const U64: usize = std::mem::size_of::<u64>();
+        self.dirty.mem_usage()
+            + self.taken_txn.mem_usage()
+            + U64 * self.available_4k.capacity()
+            + U64 * self.available_16k.capacity()
+            + U64 * self.available_blocks.capacity()
+            + U64 * self.hole_punch_future_req.capacity()
+    }
+
+    /// Spill the availability lists out of memory, down to a quarter of the memory budget. The
+    /// front of each list goes, as allocations come off the back, and is written out to pages
+    /// from the rest of the lists in pieces that each fit in that quarter again. They're only free
+    /// pages, so writing them out early is always safe. Returns the number of entries that were
+    /// spilled.
+    fn spill_available(&mut self) -> usize {
+        const U64: usize = std::mem::size_of::<u64>();
+        let Some(budget) = self.txn_memory_budget else {
+            return 0;
+        };
+        let keep = (budget / 4 / U64).max(64);
+        let lists =
+            self.available_4k.len() + self.available_16k.len() + self.available_blocks.len();
+        if lists <= keep {
+            return 0;
         }
-        while let Ok(page) = self.0.alloc_recv.try_recv() {
-            self.0.taken.remove(&page);
+
+        let mut free = RunSet::new();
+        let spill_4k = self.available_4k.len().saturating_sub(keep / 3);
+        for page in self.available_4k.drain(..spill_4k) {
+            free.insert(page);
         }
-        let mut read_pages = self.0.core.read_pages.lock().unwrap();
-        read_pages.update_writer(&mut self.0.taken);
-        drop(read_pages);
+        let spill_16k = self.available_16k.len().saturating_sub(keep / 3);
+        for entry in self.available_16k.drain(..spill_16k) {
+            let cluster = entry & !CLUSTER_TAKEN_MASK;
+            for i in (0..CLUSTER_PAGES).filter(|i| entry & (1 << i) == 0) {
+                free.insert(cluster + i * PAGE_SIZE as u64);
+            }
+        }
+        let spill_blocks = self.available_blocks.len().saturating_sub(keep / 3);
+        for block in self.available_blocks.drain(..spill_blocks) {
+            free.insert_range(block, BLOCK_SIZE as u64);
+        }
+        self.available_4k.shrink_to_fit();
+        self.available_16k.shrink_to_fit();
+        self.available_blocks.shrink_to_fit();
 
-        // Clear out all the transaction working data before starting a new transaction
-        self.0.dirty.clear();
-        self.0.taken_txn.clear();
-        self.0.available_4k.clear();
-        self.0.available_16k.clear();
-        self.0.available_blocks.clear();
-        self.0.alloc_req.clear();
-        self.0.alloc_completions.clear();
-        self.0.hole_punch_future_req.clear();
+        // Split the runs wherever a piece would take more than `keep` entries to read back in
+        let mut pieces: Vec<Vec<(u64, u64)>> = vec![Vec::new()];
+        let mut entries = 0;
+        for (start, len) in free.runs() {
+            for (at, size) in carve(start, len) {
+                if entries == keep {
+                    pieces.push(Vec::new());
+                    entries = 0;
+                }
+                let piece = pieces.last_mut().unwrap();
+                match piece.last_mut() {
+                    Some((run, run_len)) if *run + *run_len == at => *run_len += size,
+                    _ => piece.push((at, size)),
+                }
+                entries += 1;
+            }
+        }
+        drop(free);
 
-        WriteTxn(self.0)
+        let mut spilled = 0;
+        for runs in pieces.into_iter().rev().filter(|runs| !runs.is_empty()) {
+            let needed = freelist::pages_needed(runs.len());
+            let mut pages = Vec::with_capacity(needed);
+            while pages.len() < needed {
+                let Some(page) = self.take_page() else {
+                    break;
+                };
+                pages.push(page);
+            }
+            let written = if pages.len() < needed {
+                Err(AllocError::NoSpace {
+                    len: (needed * PAGE_SIZE) as u64,
+                })
+            } else {
+                // Safety: the pages just came off the availability lists, so nothing else is
+                // using them.
+                unsafe {
+                    freelist::write_freelist(
+                        &mut self.storage,
+                        &self.core,
+                        &runs,
+                        runs.len(),
+                        &pages,
+                    )
+                }
+            };
+            let (start, last) = (runs[0], runs[runs.len() - 1]);
+            match written {
+                Ok(head) => {
+                    spilled += runs
+                        .iter()
+                        .map(|(at, len)| carve(*at, *len).count())
+                        .sum::<usize>();
+                    self.spilled.push(Spill {
+                        head,
+                        pages,
+                        start: start.0,
+                        end: last.0 + last.1,
+                    });
+                }
+                Err(_) => {
+                    for (at, len) in runs {
+                        self.add_free_run(at, len);
+                    }
+                    for page in pages {
+                        self.add_free_run(page, PAGE_SIZE as u64);
+                    }
+                }
+            }
+        }
+        spilled
     }
-}
 
-/// Allocation information
-pub struct Alloc {
-    /// The byte offset to the page
-    pub page: u64,
-    /// The allocated number of bytes (always in increments of 4096)
-    pub len: usize,
-}
+    /// Walk the runs of a spilled piece of the availability lists.
+    fn spill_runs(&self, spill: &Spill) -> FreelistWalker {
+        // Safety: a spill's pages stay off of the availability lists until it's read back in for
+        // good.
+        unsafe { FreelistWalker::new(self.storage.clone(), self.core.clone(), spill.head) }
+    }
 
-impl WriteTxn {
-    /// Allocate a new page
-    pub fn txn_allocate(&mut self, len: u64) -> Result<Alloc, AllocError> {
-        let page = 0;
-        self.0.dirty.insert(page);
-        todo!("Actually write the allocator")
+    /// Read the lowest spilled piece of the availability lists back onto them, along with the
+    /// pages it was written to. Returns `false` if nothing is spilled.
+    fn unspill(&mut self) -> bool {
+        let Some(spill) = self.spilled.pop() else {
+            return false;
+        };
+        for run in self.spill_runs(&spill) {
+            // We wrote these pages ourselves, so only a bug gets us here. The rest of the runs are
+            // lost until the freelist is rebuilt, same as with a corrupt freelist.
+            let Ok((start, len)) = run else {
+                debug_assert!(false, "spilled runs couldn't be read back in");
+                break;
+            };
+            self.add_free_run(start, len);
+        }
+        for page in spill.pages {
+            self.add_free_run(page, PAGE_SIZE as u64);
+        }
+        true
     }
 
-    /// Allocate a page for writing by any thread at any point in time.
-    ///
+    /// Read everything spilled back onto the availability lists.
+    fn unspill_all(&mut self) {
+        while self.unspill() {}
+    }
+
+    /// Pick up every write allocation that's been finished or dropped since we last looked.
+    fn collect_returned_allocs(&mut self) {
+        while let Some(returned) = self.alloc_recv.try_recv() {
+            self.alloc_returned(returned);
+        }
+    }
+
+    /// Deal with a write allocation that came back. Finished ones go into the current transaction,
+    /// same as with [`WriteTxn::use_allocation`].
+    fn alloc_returned(&mut self, returned: AllocReturn) {
+        match returned {
+            AllocReturn::Dropped(page) => {
+                self.taken.remove(&page);
+                // A dropped allocation was never part of the database, so its pages are free
+                // again
+                if let Some(len) = self.alloc_lens.remove(&page) {
+                    let freed = self.free_pages(page, len);
+                    debug_assert!(freed.is_ok(), "dropped write allocation was already free");
+                    self.txn_returned.push((page, len));
+                }
+            }
+            AllocReturn::Finished(mut alloc) => {
+                alloc.clear_unwritten();
+                self.alloc_completions.push(alloc);
+            }
+        }
+    }
+
+    /// Number of write allocations handed out by earlier commits that haven't come back yet.
+    /// Allocations requested in this transaction aren't handed out until it commits, so they
+    /// don't count.
+    fn outstanding_allocs(&self) -> usize {
+        self.alloc_lens.len() - self.alloc_req.len() - self.alloc_completions.len()
+    }
+
+    /// Wait up to `timeout` for every outstanding write allocation to come back, returning how
+    /// many still haven't.
+    fn wait_for_allocs(&mut self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        self.collect_returned_allocs();
+        while self.outstanding_allocs() > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Some(returned) = self.alloc_recv.recv_timeout(left) else {
+                break;
+            };
+            self.alloc_returned(returned);
+        }
+        self.outstanding_allocs()
+    }
+
+    /// Everything that's free as of the transaction being committed: the availability lists, runs
+    /// waiting on readers or hole punching, and write allocations that haven't been used yet,
+    /// which are lost if the database is closed before they are.
+    fn free_space(&self) -> RunSet {
+        let mut free = RunSet::new();
+        for page in self.available_4k.iter() {
+            free.insert(*page);
+        }
+        for entry in self.available_16k.iter() {
+            let cluster = entry & !CLUSTER_TAKEN_MASK;
+            for i in (0..CLUSTER_PAGES).filter(|i| entry & (1 << i) == 0) {
+                free.insert(cluster + i * PAGE_SIZE as u64);
+            }
+        }
+        for block in self.available_blocks.iter().chain(self.punching.iter()) {
+            free.insert_range(*block, BLOCK_SIZE as u64);
+        }
+        for (page, len, _) in self.pending_free.iter() {
+            free.insert_range(*page, *len);
+        }
+        for (page, len) in self.alloc_lens.iter() {
+            free.insert_range(*page, *len);
+        }
+        free
+    }
+
+    /// Write the free space out to a new freelist for transaction `id`, if it's changed since the
+    /// last one was written. The old freelist's pages are freed by the same transaction, and the
+    /// new one's pages come off of the availability lists.
+    ///
+    /// If the lists can't spare enough pages, or they can't be written, the transaction gets an
+    /// empty freelist instead. That leaks the free space if the database is reopened from it, but
+    /// never hands out anything in use, and the next commit tries again.
+    fn write_freelist(&mut self, id: u64) {
+        if self.free_space() == self.freelist_runs {
+            return;
+        }
+        for page in std::mem::take(&mut self.freelist_pages) {
+            let state = FreePageState::FreeAfter(id);
+            self.pending_free.push((page, PAGE_SIZE as u64, state));
+        }
+
+        // Taking pages can split a free run in two, so make room for a run more per page taken
+        let runs = self.free_space().run_count();
+        let mut needed = freelist::pages_needed(runs);
+        while freelist::pages_needed(runs + needed) > needed {
+            needed = freelist::pages_needed(runs + needed);
+        }
+        let capacity = runs + needed;
+        let mut pages = Vec::with_capacity(needed);
+        while pages.len() < needed {
+            let Some(page) = self.take_page() else {
+                break;
+            };
+            pages.push(page);
+        }
+        pages.sort_unstable();
+
+        let free = self.free_space();
+        let written = if pages.len() < needed {
+            Err(AllocError::NoSpace {
+                len: (needed * PAGE_SIZE) as u64,
+            })
+        } else {
+            let runs: Vec<(u64, u64)> = free.runs().collect();
+            // Safety: the pages just came off the availability lists, so nothing else is using
+            // them.
+            unsafe {
+                freelist::write_freelist(
+                    &mut self.storage,
+                    &self.core,
+                    &runs,
+                    capacity,
+                    &pages,
+                )
+            }
+        };
+        match written {
+            Ok(head) => {
+                self.root.freelist = head;
+                self.freelist_pages = pages;
+                self.freelist_runs = free;
+            }
+            Err(_) => {
+                for page in pages {
+                    self.add_free_run(page, PAGE_SIZE as u64);
+                }
+                self.root.freelist = ByteOffset::default();
+                self.freelist_runs = RunSet::new();
+            }
+        }
+    }
+
+    /// Fold loose 4kiB pages back into the clusters they belong to, and clusters back into whole
+    /// blocks, so that churn in small allocations doesn't leave the bigger sizes starved.
+    /// Partially free clusters end up at the back of `available_16k`, where 4kiB allocations look
+    /// first.
+    fn coalesce_available(&mut self) {
+        if self.available_4k.is_empty() && self.available_16k.is_empty() {
+            return;
+        }
+
+        // Work out which pages of each cluster are taken, starting from all of them
+        let mut clusters: BTreeMap<u64, u64> = BTreeMap::new();
+        for entry in self.available_16k.drain(..) {
+            let cluster = entry & !CLUSTER_TAKEN_MASK;
+            *clusters.entry(cluster).or_insert(CLUSTER_TAKEN_MASK) &= entry & CLUSTER_TAKEN_MASK;
+        }
+        for page in self.available_4k.drain(..) {
+            let cluster = page & !(CLUSTER_SIZE as u64 - 1);
+            let bit = 1 << ((page - cluster) / PAGE_SIZE as u64);
+            *clusters.entry(cluster).or_insert(CLUSTER_TAKEN_MASK) &= !bit;
+        }
+
+        // Blocks whose clusters are all wholly free become blocks again
+        let mut whole: BTreeMap<u64, u64> = BTreeMap::new();
+        for (cluster, _) in clusters.iter().filter(|(_, taken)| **taken == 0) {
+            *whole.entry(cluster & !(BLOCK_SIZE as u64 - 1)).or_default() += 1;
+        }
+        whole.retain(|_, count| *count == BLOCK_CLUSTERS);
+        self.available_blocks.extend(whole.keys());
+
+        let (full, partial): (Vec<u64>, Vec<u64>) = clusters
+            .into_iter()
+            .filter(|(cluster, taken)| {
+                *taken != CLUSTER_TAKEN_MASK
+                    && !whole.contains_key(&(cluster & !(BLOCK_SIZE as u64 - 1)))
+            })
+            .map(|(cluster, taken)| cluster | taken)
+            .partition(|entry| entry & CLUSTER_TAKEN_MASK == 0);
+        self.available_16k.extend(full);
+        self.available_16k.extend(partial);
+    }
+
+    /// Take free space for `len` bytes off of the availability lists, splitting a bigger piece if
+    /// there's nothing of the right size. Anything split off but not needed goes back on the
+    /// lists. Returns `None` if there's no piece big enough, or `len` is over [`BLOCK_SIZE`].
+    fn take_available(&mut self, len: u64) -> Option<u64> {
+        let page = self.take_available_inner(len)?;
+        #[cfg(feature = "alloc-audit")]
+        self.audit(AuditOp::Alloc, page, len.next_multiple_of(PAGE_SIZE as u64));
+        Some(page)
+    }
+
+    /// Take free space for `len` bytes like [`take_available`][Self::take_available], reading
+    /// spilled free space back in or growing the file by a growth step first if there isn't any.
+    /// The new blocks come out of the transaction like any other free space, and the longer file
+    /// length is published when it commits. Fails with [`AllocError::NoSpace`] if `len` is over [`BLOCK_SIZE`], or with
+    /// whatever went wrong growing the storage.
+    fn take_or_grow(&mut self, len: u64) -> Result<u64, AllocError> {
+        if len > BLOCK_SIZE as u64 {
+            return Err(AllocError::NoSpace { len });
+        }
+        // Anything spilled gets read back in before the file grows
+        loop {
+            if let Some(page) = self.take_available(len) {
+                return Ok(page);
+            }
+            if !self.unspill() {
+                break;
+            }
+        }
+        self.grow()?;
+        self.take_available(len).ok_or(AllocError::NoSpace { len })
+    }
+
+    /// Grow the file by one growth step, or as much of one as the size limit allows, putting the
+    /// new blocks on the availability lists with the lowest one on top. Fails with
+    /// [`AllocError::DatabaseFull`] if the file is already at the limit.
+    fn grow(&mut self) -> Result<(), AllocError> {
+        let start = self.root.file_len;
+        let mut end = start + self.growth_step;
+        if let Some(limit) = self.core.max_size {
+            if start >= limit {
+                return Err(AllocError::DatabaseFull {
+                    limit,
+                    requested: end,
+                });
+            }
+            end = end.min(limit);
+        }
+        let Ok(len) = usize::try_from(end) else {
+            return Err(AllocError::NoSpace {
+                len: self.growth_step,
+            });
+        };
+        {
+            let Ok(mut storage) = self.core.storage.lock() else {
+                return Err(AllocError::MutexPoisoned);
+            };
+            // Safety: nothing but the writer touches anything past the file length, and the maps
+            // only ever grow in place or gain a new one, so nothing handed out so far moves.
+            unsafe { storage.grow_to(len)? };
+        }
+        let blocks = (end - start) / BLOCK_SIZE as u64;
+        self.available_blocks
+            .extend((0..blocks).rev().map(|i| start + i * BLOCK_SIZE as u64));
+        self.root.file_len = end;
+        Ok(())
+    }
+
+    fn take_available_inner(&mut self, len: u64) -> Option<u64> {
+        if len <= PAGE_SIZE as u64 {
+            self.take_page()
+        } else if len <= CLUSTER_SIZE as u64 {
+            let cluster = self.take_cluster()?;
+            let used = len.next_multiple_of(PAGE_SIZE as u64);
+            self.add_free_run(cluster + used, CLUSTER_SIZE as u64 - used);
+            Some(cluster)
+        } else if len <= BLOCK_SIZE as u64 {
+            let block = self.available_blocks.pop()?;
+            let used = len.next_multiple_of(PAGE_SIZE as u64);
+            self.add_free_run(block + used, BLOCK_SIZE as u64 - used);
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    /// Take a single free page, preferring loose pages, then partially free clusters, then whole
+    /// clusters, and only then splitting a block.
+    fn take_page(&mut self) -> Option<u64> {
+        if let Some(page) = self.available_4k.pop() {
+            return Some(page);
+        }
+        let entry = match self.available_16k.last() {
+            Some(entry) => *entry,
+            None => {
+                self.split_block()?;
+                *self.available_16k.last()?
+            }
+        };
+        let cluster = entry & !CLUSTER_TAKEN_MASK;
+        let taken = entry & CLUSTER_TAKEN_MASK;
+        let index = (!taken & CLUSTER_TAKEN_MASK).trailing_zeros() as u64;
+        let taken = taken | (1 << index);
+        if taken == CLUSTER_TAKEN_MASK {
+            self.available_16k.pop();
+        } else {
+            *self.available_16k.last_mut()? = cluster | taken;
+        }
+        Some(cluster + index * PAGE_SIZE as u64)
+    }
+
+    /// Take a wholly free cluster, splitting a block if there isn't one.
+    fn take_cluster(&mut self) -> Option<u64> {
+        let pos = match self
+            .available_16k
+            .iter()
+            .rposition(|entry| entry & CLUSTER_TAKEN_MASK == 0)
+        {
+            Some(pos) => pos,
+            None => {
+                self.split_block()?;
+                self.available_16k.len() - 1
+            }
+        };
+        Some(self.available_16k.remove(pos))
+    }
+
+    /// Break a free block up into clusters, leaving the block's first cluster at the back of
+    /// `available_16k`.
+    fn split_block(&mut self) -> Option<()> {
+        let block = self.available_blocks.pop()?;
+        let clusters = (0..BLOCK_CLUSTERS).rev();
+        self.available_16k
+            .extend(clusters.map(|i| block + i * CLUSTER_SIZE as u64));
+        Some(())
+    }
+
+    /// Give `len` bytes starting at `page` back to the availability lists. Fails with
+    /// [`AllocError::DoubleFree`] if any of those pages are already on them.
+    fn free_pages(&mut self, page: u64, len: u64) -> Result<(), AllocError> {
+        let len = len.next_multiple_of(PAGE_SIZE as u64);
+        if self.overlaps_available(page, len) {
+            return Err(self.double_free(page, len));
+        }
+        #[cfg(feature = "alloc-audit")]
+        self.audit(AuditOp::Free, page, len);
+        self.add_free_run(page, len);
+        Ok(())
+    }
+
+    /// Free `len` bytes starting at `page` once no reader can see them anymore. Until the
+    /// transaction commits, the pages still count as allocated. Fails with
+    /// [`AllocError::DoubleFree`] if any of them are already free or waiting to be.
+    fn free_later(&mut self, page: u64, len: u64) -> Result<(), AllocError> {
+        if self.overlaps_available(page, len) || self.overlaps_pending(page, len) {
+            return Err(self.double_free(page, len));
+        }
+        #[cfg(feature = "alloc-audit")]
+        self.audit(AuditOp::Free, page, len);
+        self.pending_free.push((page, len, FreePageState::Allocated));
+        Ok(())
+    }
+
+    /// Hand back every pending run that no reader can reach anymore: freed by a transaction the
+    /// oldest reader has caught up to, and with none of its pages checked out. Whole blocks get
+    /// queued for hole punching, and only come back once that's done.
+    fn release_freed(&mut self, oldest: u64) {
+        if self.pending_free.is_empty() {
+            return;
+        }
+        let pinned: BTreeSet<u64> = {
+            let read_pages = self.core.read_pages.lock().unwrap();
+            read_pages
+                .read
+                .keys()
+                .chain(read_pages.write.keys())
+                .copied()
+                .collect()
+        };
+        for (page, len, state) in std::mem::take(&mut self.pending_free) {
+            let end = page + len;
+            let reachable = match state {
+                FreePageState::Allocated => true,
+                FreePageState::FreeAfter(id) => id > oldest,
+            };
+            if reachable || pinned.range(page..end).next().is_some() {
+                self.pending_free.push((page, len, state));
+                continue;
+            }
+            // Committed write allocations leave their pages taken, which no longer applies
+            let stale: Vec<u64> = self.taken.range(page..end).copied().collect();
+            for taken in stale {
+                self.taken.remove(&taken);
+            }
+
+            let first_block = page.next_multiple_of(BLOCK_SIZE as u64);
+            let last_block = end & !(BLOCK_SIZE as u64 - 1);
+            if first_block >= last_block {
+                self.add_free_run(page, len);
+                continue;
+            }
+            self.add_free_run(page, first_block - page);
+            for block in (first_block..last_block).step_by(BLOCK_SIZE) {
+                self.hole_punch_future_req.push(block);
+                self.punching.insert(block);
+            }
+            self.add_free_run(last_block, end - last_block);
+        }
+    }
+
+    /// Check if any page in the `len` bytes starting at `page` has been freed but isn't back on
+    /// the availability lists yet.
+    fn overlaps_pending(&self, page: u64, len: u64) -> bool {
+        let end = page + len;
+        self.pending_free
+            .iter()
+            .any(|(start, run, _)| *start < end && page < start + run)
+            || self
+                .punching
+                .range(..end)
+                .next_back()
+                .is_some_and(|block| page < block + BLOCK_SIZE as u64)
+    }
+
+    /// The error for freeing pages that were already free.
+    fn double_free(&self, page: u64, len: u64) -> AllocError {
+        AllocError::DoubleFree {
+            page,
+            len,
+            #[cfg(feature = "alloc-audit")]
+            history: self.core.audit.lock().unwrap().history(page, len),
+        }
+    }
+
+    /// Check if any page in the `len` bytes starting at `page` is on the availability lists, or
+    /// has been spilled from them.
+    fn overlaps_available(&self, page: u64, len: u64) -> bool {
+        let end = page + len;
+        let overlaps = |start: u64, size: usize| start < end && page < start + size as u64;
+        self.available_4k.iter().any(|p| overlaps(*p, PAGE_SIZE))
+            || self
+                .available_blocks
+                .iter()
+                .any(|b| overlaps(*b, BLOCK_SIZE))
+            || self.available_16k.iter().any(|entry| {
+                let cluster = entry & !CLUSTER_TAKEN_MASK;
+                (0..CLUSTER_PAGES).any(|i| {
+                    entry & (1 << i) == 0 && overlaps(cluster + i * PAGE_SIZE as u64, PAGE_SIZE)
+                })
+            })
+            || self.spilled.iter().any(|spill| {
+                spill.pages.iter().any(|p| overlaps(*p, PAGE_SIZE))
+                    || (spill.start < end
+                        && page < spill.end
+                        && self.spill_runs(spill).any(|run| {
+                            run.is_ok_and(|(start, len)| start < end && page < start + len)
+                        }))
+            })
+    }
+
+    /// Take every page in the `len` bytes starting at `page` off of the availability lists. Blocks
+    /// and clusters that are only partly covered stay on the lists with the rest of their pages.
+    fn withhold_available(&mut self, page: u64, len: u64) {
+        let end = page + len.next_multiple_of(PAGE_SIZE as u64);
+        let overlaps = |start: u64, size: usize| start < end && page < start + size as u64;
+
+        let (split, kept): (Vec<u64>, Vec<u64>) = self
+            .available_blocks
+            .drain(..)
+            .partition(|b| overlaps(*b, BLOCK_SIZE));
+        self.available_blocks = kept;
+        for block in split {
+            let block_end = block + BLOCK_SIZE as u64;
+            if block < page {
+                self.add_free_run(block, page - block);
+            }
+            if end < block_end {
+                self.add_free_run(end, block_end - end);
+            }
+        }
+
+        for entry in self.available_16k.iter_mut() {
+            let cluster = *entry & !CLUSTER_TAKEN_MASK;
+            for i in 0..CLUSTER_PAGES {
+                if overlaps(cluster + i * PAGE_SIZE as u64, PAGE_SIZE) {
+                    *entry |= 1 << i;
+                }
+            }
+        }
+        self.available_16k
+            .retain(|entry| entry & CLUSTER_TAKEN_MASK != CLUSTER_TAKEN_MASK);
+        self.available_4k.retain(|p| !overlaps(*p, PAGE_SIZE));
+    }
+
+    /// Record an allocator operation in the audit log.
+    #[cfg(feature = "alloc-audit")]
+    fn audit(&self, op: AuditOp, page: u64, len: u64) {
+        self.core.audit.lock().unwrap().push(AuditRecord {
+            txn_id: self.root.id + 1,
+            op,
+            page,
+            len,
+        });
+    }
+
+    /// Bytes of free space on the availability lists.
+    fn available_bytes(&self) -> u64 {
+        let partial: u64 = self
+            .available_16k
+            .iter()
+            .map(|entry| CLUSTER_PAGES - (entry & CLUSTER_TAKEN_MASK).count_ones() as u64)
+            .sum();
+        (self.available_4k.len() as u64 + partial) * PAGE_SIZE as u64
+            + self.available_blocks.len() as u64 * BLOCK_SIZE as u64
+    }
+
+    /// Put a free run of `len` bytes starting at `start` on the availability lists, carved up into
+    /// the largest aligned pieces that fit.
+    fn add_free_run(&mut self, start: u64, len: u64) {
+        for (at, size) in carve(start, len) {
+            match size as usize {
+                BLOCK_SIZE => self.available_blocks.push(at),
+                CLUSTER_SIZE => self.available_16k.push(at),
+                _ => self.available_4k.push(at),
+            }
+        }
+    }
+
+    /// Fill the availability lists from the on-disk freelist that `txn` sees, and remember it as
+    /// the freelist the next commit replaces. Runs are clipped to the `recorded` file length, as
+    /// anything past it is found by [`RootData::unrecorded_blocks`] instead. Fails with
+    /// [`AllocError::DataFormat`] if the freelist is corrupt, leaving the lists partially filled.
+    fn load_freelist(&mut self, txn: &ReadTxn, recorded: u64) -> Result<(), AllocError> {
+        let mut runs = FreeRuns::new(txn)?;
+        for run in runs.by_ref() {
+            let (start, end) = run?;
+            let end = end.min(recorded);
+            if start < end {
+                self.add_free_run(start, end - start);
+                self.freelist_runs.insert_range(start, end - start);
+            }
+        }
+        self.freelist_pages = runs.pages().to_vec();
+        Ok(())
+    }
+
+    /// Check the bookkeeping against the memory budget, spilling if we're over it. If we're still
+    /// over afterwards, warn through the metrics hook, once per transaction.
+    fn check_budget(&mut self) {
+        let Some(budget) = self.txn_memory_budget else {
+            return;
+        };
+        if self.budget_warned || self.txn_memory_usage() <= budget {
+            return;
+        }
+        if self.spill_available() > 0 && self.txn_memory_usage() <= budget {
+            return;
+        }
+        self.budget_warned = true;
+        self.metrics
+            .txn_memory_over_budget(self.txn_memory_usage(), budget);
+    }
+
+    /// Mark a page as dirty within the current transaction.
+    fn mark_dirty(&mut self, page: u64) {
+        if self.dirty.insert(page) {
+            self.check_budget();
+        }
+    }
+
+    /// Panic if any dirty page is one a reader might still reach. Only the reader-pinned pages,
+    /// and the pages `taken` says aren't free yet, are candidates, minus the allocations this
+    /// transaction made for itself.
+    #[cfg(any(test, feature = "paranoid"))]
+    fn check_dirty_unreachable(&self) {
+        let read_pages = self.core.read_pages.lock().unwrap();
+        let own: BTreeSet<u64> = self
+            .alloc_req
+            .iter()
+            .chain(self.alloc_completions.iter())
+            .map(WriteAlloc::page)
+            .collect();
+        let overlap: BTreeSet<u64> = self
+            .taken
+            .iter()
+            .chain(read_pages.read.keys())
+            .chain(read_pages.write.keys())
+            .copied()
+            .filter(|page| !own.contains(page) && self.dirty.contains(*page))
+            .collect();
+        drop(read_pages);
+        if !overlap.is_empty() {
+            let oldest = self.core.root.lock().unwrap().id_tracker.oldest_id();
+            let pages: Vec<String> = overlap.iter().map(|p| format!("{p:#x}")).collect();
+            panic!(
+                "dirty pages are still reachable by the reader at id {oldest}: {}",
+                pages.join(", ")
+            );
+        }
+    }
+
+    /// Work out the page-aligned range covering an access at `offset` bytes into the allocation at
+    /// `page`, checking that every page it touches is owned by the current transaction. Returns
+    /// the range along with where the access starts inside of it.
+    fn txn_range(
+        &self,
+        page: ByteOffset,
+        offset: usize,
+        len: usize,
+    ) -> Result<(BlockRange, usize), AllocError> {
+        let start = BlockRange::from_pages(page.get(), 0)?;
+        start.check_data()?;
+        let start = start.start;
+        let not_owned = AllocError::NotOwned {
+            offset: start.saturating_add(offset),
+            len,
+        };
+        let Some(end) = start.checked_add(offset).and_then(|s| s.checked_add(len)) else {
+            return Err(not_owned);
+        };
+        let access_start = start + offset;
+        let first_page = access_start & !(PAGE_SIZE - 1);
+        let Some(last_page) = end.checked_next_multiple_of(PAGE_SIZE) else {
+            return Err(not_owned);
+        };
+        let pages = BlockRange::new(first_page, (last_page - first_page).max(PAGE_SIZE));
+        if !self.dirty.contains_range(pages.start as u64, pages.len as u64) {
+            return Err(not_owned);
+        }
+        Ok((pages, access_start - first_page))
+    }
+}
+
+pub struct WriteUnit(WriteUnitInner);
+pub struct WriteTxn(WriteUnitInner);
+
+impl WriteUnit {
+    /// The ID of the transaction this writer last committed, or the one the database was opened
+    /// at if it hasn't committed any yet. Hand it to [`CommitUnit::commit_to`] to make everything
+    /// up to that commit durable.
+    pub fn generation(&self) -> u64 {
+        self.0.root.id
+    }
+
+    /// Get the current [`Stats`] for the database, including how the file's space is being used.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.0.core.stats();
+        let inner = &self.0;
+        let partial = inner.available_16k.iter().filter(|e| *e & CLUSTER_TAKEN_MASK != 0);
+        let free_pages = inner.available_4k.len() as u64
+            + partial
+                .map(|e| CLUSTER_PAGES - (e & CLUSTER_TAKEN_MASK).count_ones() as u64)
+                .sum::<u64>();
+        let free_clusters = inner
+            .available_16k
+            .iter()
+            .filter(|e| *e & CLUSTER_TAKEN_MASK == 0)
+            .count() as u64;
+        let free_blocks = inner.available_blocks.len() as u64;
+        let free = SpaceStats {
+            allocated_pages: 0,
+            free_pages,
+            free_clusters,
+            free_blocks,
+        };
+        let data_pages = stats.file_len.saturating_sub(ROOT_MAP_SIZE as u64) / PAGE_SIZE as u64;
+        stats.space = Some(SpaceStats {
+            allocated_pages: data_pages.saturating_sub(free.free_bytes() / PAGE_SIZE as u64),
+            ..free
+        });
+        stats
+    }
+
+    /// Take the error from the first read block that failed to check its pages back in, if any
+    /// has since the last call. This only happens if the allocator has a bug.
+    pub fn take_checkin_error(&self) -> Option<AllocError> {
+        self.0.core.checkin_error.lock().unwrap().take()
+    }
+
+    /// Get the allocations and frees still in the audit log, oldest first.
+    #[cfg(feature = "alloc-audit")]
+    pub fn audit_log(&self) -> Vec<AuditRecord> {
+        self.0.core.audit.lock().unwrap().snapshot()
+    }
+
+    pub fn write(mut self) -> WriteTxn {
+        // Process any pending operations from readers, write allocations, and the committer
+        while let Some(page) = self.0.hole_punch_resp.try_recv() {
+            self.0.taken.remove(&page);
+            if self.0.punching.remove(&page) {
+                self.0.add_free_run(page, BLOCK_SIZE as u64);
+            }
+        }
+        self.0.collect_returned_allocs();
+        let mut read_pages = self.0.core.read_pages.lock().unwrap();
+        read_pages.update_writer(&mut self.0.taken);
+        debug_assert!(
+            read_pages
+                .write
+                .keys()
+                .all(|page| self.0.taken.contains(page)),
+            "pages checked out by readers are missing from the writer's taken set"
+        );
+        drop(read_pages);
+
+        // Pages freed by transactions every reader has since moved past are free for good now, as
+        // long as the committer has flushed a root page from after them. Until then, the durable
+        // root page could still reach them. Blocks among them wait on hole punch requests, which
+        // carry over until the next commit sends them out.
+        while let Some(id) = self.0.durable_recv.try_recv() {
+            self.0.durable_id = self.0.durable_id.max(id);
+        }
+        let oldest = self.0.core.root.lock().unwrap().id_tracker.oldest_id();
+        self.0.release_freed(oldest.min(self.0.durable_id));
+
+        // Clear out all the transaction working data before starting a new transaction. The
+        // availability lists stay, as everything on them is still free, but are noted down in case
+        // the transaction is aborted.
+        self.0.dirty.clear();
+        self.0.taken_txn.clear();
+        self.0.coalesce_available();
+        self.0.alloc_req.clear();
+        self.0.budget_warned = false;
+        self.0.roots.clear();
+        self.0.root_data = None;
+        self.0.txn_returned.clear();
+        self.0.txn_start = TxnStart {
+            available_4k: self.0.available_4k.clone(),
+            available_16k: self.0.available_16k.clone(),
+            available_blocks: self.0.available_blocks.clone(),
+            file_len: self.0.root.file_len,
+        };
+
+        WriteTxn(self.0)
+    }
+}
+
+/// What [`WriteTxn::commit_with`] does about write allocations that earlier commits handed out and
+/// that haven't come back yet, through [`WriteTxn::use_allocation`], [`WriteAlloc::finish`], or
+/// being dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitPolicy {
+    /// Wait up to this long for every one of them to come back. Finished ones go into the
+    /// transaction, and if any are still out by the end, it fails with
+    /// [`AllocError::OutstandingAllocations`].
+    ///
+    /// With the `single-threaded` feature, nothing else can hand them back while the writer
+    /// waits, so this only picks up the ones that already came back.
+    WaitForAll(Duration),
+    /// Commit without them, same as [`WriteTxn::commit`]. They can still go into a later
+    /// transaction.
+    CommitWithout,
+    /// Fail with [`AllocError::OutstandingAllocations`] if there are any.
+    Fail,
+}
+
+/// Allocation information
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Alloc {
+    /// The byte offset to the page
+    pub page: ByteOffset,
+    /// The allocated number of bytes (always in increments of 4096)
+    pub len: usize,
+}
+
+impl Alloc {
+    /// The range of bytes this allocation covers. Fails with [`AllocError::RangeOverflow`] if it
+    /// doesn't fit in the address space.
+    pub fn to_range(&self) -> Result<BlockRange, AllocError> {
+        let overflow = AllocError::RangeOverflow {
+            offset: self.page.get(),
+            len: self.len as u64,
+        };
+        let Ok(start) = usize::try_from(self.page.get()) else {
+            return Err(overflow);
+        };
+        if start.checked_add(self.len).is_none() {
+            return Err(overflow);
+        }
+        Ok(BlockRange::new(start, self.len))
+    }
+
+    /// The allocation covering a range of bytes. Fails with [`AllocError::ReservedPageBits`] if
+    /// the range doesn't start on a valid page number, or [`AllocError::Misaligned`] if it
+    /// doesn't start and end on page boundaries.
+    pub fn from_range(range: BlockRange) -> Result<Self, AllocError> {
+        let page = range.page()?;
+        if range.to_pages().is_none() {
+            return Err(AllocError::Misaligned {
+                offset: range.start,
+            });
+        }
+        Ok(Self {
+            page,
+            len: range.len,
+        })
+    }
+}
+
+impl WriteTxn {
+    /// Allocate `len` bytes, rounded up to a whole number of pages, for this transaction to write
+    /// into directly with [`write_at`][Self::write_at]. The pages are dirty until the transaction
+    /// commits, and no reader can see them before then. Aborting puts them back.
+    ///
+    /// Like [`new_allocation`][Self::new_allocation], the allocation can be no bigger than
+    /// [`BLOCK_SIZE`], failing with [`AllocError::NoSpace`] otherwise, and the file grows if
+    /// there's no free space big enough for it.
+    pub fn txn_allocate(&mut self, len: u64) -> Result<Alloc, AllocError> {
+        let len = len.max(1).next_multiple_of(PAGE_SIZE as u64);
+        let page = self.0.take_or_grow(len)?;
+        if self.0.dirty.insert_range(page, len) {
+            self.0.check_budget();
+        }
+        Ok(Alloc {
+            page: ByteOffset(page),
+            len: len as usize,
+        })
+    }
+
+    /// Allocate a page for writing by any thread at any point in time.
+    ///
     /// Requested allocations are provided once the current write transaction is committed.
     ///
     /// The allocated data is not committed until the [`WriteAlloc`] is returned to an active
     /// [`WriteTxn`] and [`WriteTxn::commit`] is called.
+    ///
+    /// The allocation covers `len` bytes rounded up to a whole number of pages, and can be no
+    /// bigger than [`BLOCK_SIZE`], failing with [`AllocError::NoSpace`] otherwise. If there's no
+    /// free space big enough for it, the file grows by [`OpenOptions::growth_step`] first, failing
+    /// with [`AllocError::DatabaseFull`] if it's already as large as [`OpenOptions::max_size`]
+    /// allows.
     pub fn new_allocation(&mut self, len: u64) -> Result<(), AllocError> {
-        todo!("Actually write the allocator")
+        let len = len.max(1).next_multiple_of(PAGE_SIZE as u64);
+        let page = self.0.take_or_grow(len)?;
+        let range = BlockRange::new(page as usize, len as usize);
+        // Safety: the pages just came off the availability lists, so nothing else can be using
+        // them, and the allocation is the only thing handed access to them until it comes back.
+        let mem = match unsafe { self.0.storage.get(&self.0.core, range) } {
+            Ok(mem) => mem,
+            Err(e) => {
+                self.0.add_free_run(page, len);
+                return Err(e);
+            }
+        };
+        self.0.alloc_lens.insert(page, len);
+        self.0.alloc_req.push(WriteAlloc {
+            mem,
+            page,
+            written: 0,
+            chan: self.0.alloc_send.clone(),
+            core: self.0.core.clone(),
+        });
+        Ok(())
+    }
+
+    /// Free `len` bytes of pages starting at the byte offset `page`, rounding up to a whole page.
+    ///
+    /// Readers from before this transaction may still be using the pages, so they only go back to
+    /// the allocator once the oldest reader has caught up to this transaction and nothing has
+    /// them checked out. The [`CommitUnit`] also has to have made this transaction durable, as
+    /// the root page on disk can reach them until then. Whole blocks among them get their holes
+    /// punched by the [`CommitUnit`] first. Aborting the transaction leaves the pages allocated.
+    /// Pages allocated by this transaction with [`txn_allocate`][Self::txn_allocate] were never
+    /// visible to anything else, so they're free again right away instead.
+    ///
+    /// Fails with [`AllocError::Misaligned`] if `page` isn't on a page boundary,
+    /// [`AllocError::RootAccess`] if it's within the root pages, [`AllocError::InvalidAccess`] if
+    /// the range is empty or runs past the end of the file, or [`AllocError::DoubleFree`] if any
+    /// of the pages are already free or waiting to be.
+    pub fn txn_free(&mut self, page: u64, len: usize) -> Result<(), AllocError> {
+        let len = self.free_len(page, len)?;
+        self.free_run(page, len, self.0.dirty.contains_range(page, len))
+    }
+
+    /// Free a batch of ranges, each given as a `(page, len)` pair like for
+    /// [`txn_free`][Self::txn_free]. The ranges are sorted and adjacent ones merged first, so the
+    /// bookkeeping is done once per run of them instead of once per range. Ranges allocated by
+    /// this transaction are only merged with each other, as they're free again right away.
+    ///
+    /// Every range is checked before any of them are freed, failing the same way as
+    /// [`txn_free`][Self::txn_free] would. A [`AllocError::DoubleFree`] is only found while
+    /// freeing, so the runs before it stay freed.
+    pub fn txn_free_batch(
+        &mut self,
+        ranges: impl IntoIterator<Item = (u64, usize)>,
+    ) -> Result<(), AllocError> {
+        let mut ranges = ranges
+            .into_iter()
+            .map(|(page, len)| Ok((page, self.free_len(page, len)?)))
+            .collect::<Result<Vec<_>, AllocError>>()?;
+        ranges.sort_unstable();
+        let mut runs: Vec<(u64, u64, bool)> = Vec::new();
+        for (page, len) in ranges {
+            let dirty = self.0.dirty.contains_range(page, len);
+            match runs.last_mut() {
+                Some((start, run_len, run_dirty))
+                    if *start + *run_len == page && *run_dirty == dirty =>
+                {
+                    *run_len += len
+                }
+                _ => runs.push((page, len, dirty)),
+            }
+        }
+        for (page, len, dirty) in runs {
+            self.free_run(page, len, dirty)?;
+        }
+        Ok(())
+    }
+
+    /// Check a range to be freed, returning its length rounded up to a whole page.
+    fn free_len(&self, page: u64, len: usize) -> Result<u64, AllocError> {
+        let range = BlockRange::from_pages(page, len.div_ceil(PAGE_SIZE))?;
+        range.check_data()?;
+        if range.len == 0 || (range.start + range.len) as u64 > self.0.root.file_len {
+            return Err(AllocError::InvalidAccess {
+                offset: range.start,
+                len,
+            });
+        }
+        Ok(range.len as u64)
+    }
+
+    /// Free a checked run of pages: right away if they're `dirty` from being allocated by this
+    /// transaction, and otherwise once no reader can see them.
+    fn free_run(&mut self, page: u64, len: u64, dirty: bool) -> Result<(), AllocError> {
+        if dirty {
+            for dirty in (page..(page + len)).step_by(PAGE_SIZE) {
+                self.0.dirty.remove(dirty);
+            }
+            return self.0.free_pages(page, len);
+        }
+        self.0.free_later(page, len)
+    }
+
+    /// Give up every wholly free block at the end of the file, returning the new file length. The
+    /// file never shrinks below [`MIN_DB_SIZE`].
+    ///
+    /// The shorter length is published when this transaction commits. The [`CommitUnit`]
+    /// truncates the file once that commit is durable and every reader from before it is gone, so
+    /// nothing can still reach the blocks given up.
+    pub fn compact_tail(&mut self) -> u64 {
+        self.0.unspill_all();
+        let free: BTreeSet<u64> = self.0.available_blocks.iter().copied().collect();
+        let mut len = self.0.root.file_len;
+        while len > MIN_DB_SIZE as u64 && free.contains(&(len - BLOCK_SIZE as u64)) {
+            len -= BLOCK_SIZE as u64;
+        }
+        self.0.available_blocks.retain(|block| *block < len);
+        self.0.root.file_len = len;
+        len
+    }
+
+    /// Put a written-out allocation into this transaction. Everything past its
+    /// [`written`][WriteAlloc::written] length is zeroed.
+    ///
+    /// Fails with [`AllocError::ForeignAllocation`] if the allocation came from a different
+    /// database, as its page number would mean something else entirely in this one.
+    pub fn use_allocation(&mut self, alloc: WriteAlloc) -> Result<(), AllocError> {
+        if !Arc::ptr_eq(&alloc.core, &self.0.core) {
+            return Err(AllocError::ForeignAllocation);
+        }
+        self.0.alloc_returned(AllocReturn::Finished(alloc));
+        Ok(())
+    }
+
+    /// Number of write allocations handed out by earlier commits that haven't come back yet,
+    /// whether through [`use_allocation`][Self::use_allocation], [`WriteAlloc::finish`], or being
+    /// dropped. Any that came back since the last look are picked up first.
+    pub fn outstanding_allocations(&mut self) -> usize {
+        self.0.collect_returned_allocs();
+        self.0.outstanding_allocs()
+    }
+
+    /// Determine if the provided page is marked as dirty or not
+    pub fn is_dirty(&self, page: ByteOffset) -> bool {
+        self.0.dirty.contains(page.get())
+    }
+
+    /// Copy `data` into the allocation at `page`, starting `offset` bytes in. The write may cross
+    /// page boundaries, but every page it touches must have been allocated or dirtied by this
+    /// transaction, otherwise [`AllocError::NotOwned`] is returned and nothing is written.
+    pub fn write_at(
+        &mut self,
+        page: ByteOffset,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), AllocError> {
+        let (range, start) = self.0.txn_range(page, offset, data.len())?;
+        // Safety: the range is entirely within pages owned by this transaction, which no reader
+        // can see, and we hold the only writer.
+        let dst = unsafe { self.0.storage.get(&self.0.core, range)? };
+        dst[start..(start + data.len())].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Read back `len` bytes of the allocation at `page`, starting `offset` bytes in. Like
+    /// [`write_at`][Self::write_at], the range must lie within pages owned by this transaction.
+    pub fn read_back(
+        &self,
+        page: ByteOffset,
+        offset: usize,
+        len: usize,
+    ) -> Result<&[u8], AllocError> {
+        let (range, start) = self.0.txn_range(page, offset, len)?;
+        let src = unsafe { self.0.storage.get_const(&self.0.core, range)? };
+        Ok(&src[start..(start + len)])
+    }
+
+    /// Start tracking the root page of the tree named `name` for this transaction, along with the
+    /// layout ids of its branch and leaf pages. See [`TxnRoots`] for how tracked roots need to be
+    /// resolved before committing.
+    pub fn track_root(
+        &mut self,
+        name: impl Into<String>,
+        page: ByteOffset,
+        layout: [u8; 2],
+    ) -> Result<RootSlot, AllocError> {
+        self.0.roots.track(name, page, layout)
+    }
+
+    /// Record the new root page returned when loading a tracked tree for writing, or `None` if it
+    /// stayed where it was.
+    pub fn update_root(&mut self, slot: RootSlot, new_page: Option<ByteOffset>) {
+        self.0.roots.update(slot, new_page)
+    }
+
+    /// Mark a tracked tree as not having been changed by this transaction.
+    pub fn root_unchanged(&mut self, slot: RootSlot) {
+        self.0.roots.unchanged(slot)
+    }
+
+    /// The tree roots tracked by this transaction.
+    pub fn roots(&self) -> &TxnRoots {
+        &self.0.roots
+    }
+
+    /// The root data this transaction commits with [`commit_root_data`][Self::commit_root_data]:
+    /// whatever [`set_root_data`][Self::set_root_data] last set, or the previous transaction's
+    /// root data if it hasn't been set.
+    pub fn root_data(&self) -> &[u8] {
+        self.0.root_data.as_deref().unwrap_or(&self.0.root.root)
+    }
+
+    /// Set the root data for [`commit_root_data`][Self::commit_root_data] to commit. Fails with
+    /// [`AllocError::RootTooLarge`] if it won't fit in a root page, leaving the root data as it
+    /// was, instead of only failing once the [`CommitUnit`] goes to write it out.
+    pub fn set_root_data(&mut self, root_data: &[u8]) -> Result<(), AllocError> {
+        let len = format::ROOT_HEADER_SIZE + root_data.len() + format::ROOT_HASH_SIZE;
+        if len > ROOT_SIZE {
+            return Err(AllocError::RootTooLarge {
+                len,
+                max: ROOT_SIZE,
+            });
+        }
+        self.0.root_data = Some(root_data.to_vec());
+        Ok(())
+    }
+
+    /// Commit the transaction like [`commit`][Self::commit], using [`root_data`][Self::root_data]
+    /// as the root data.
+    pub fn commit_root_data(mut self) -> (WriteUnit, Vec<WriteAlloc>) {
+        let root_data = self.0.root_data.take();
+        let root_data = root_data.unwrap_or_else(|| self.0.root.root.to_vec());
+        self.commit(&root_data)
+    }
+
+    /// Commit the transaction, using the tracked tree roots as the root payload. If any tracked
+    /// tree wasn't resolved, nothing is committed and the transaction is handed back along with
+    /// an [`AllocError::UnresolvedRoots`] listing them.
+    #[allow(clippy::result_large_err)]
+    pub fn commit_roots(self) -> Result<(WriteUnit, Vec<WriteAlloc>), (Self, AllocError)> {
+        match self.0.roots.payload() {
+            Ok(payload) => Ok(self.commit(&payload)),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Commit the transaction like [`commit`][Self::commit], with `policy` deciding what happens
+    /// to write allocations that earlier commits handed out and that haven't come back yet. On
+    /// success, the number of them left out of the transaction is returned as well, which is only
+    /// ever nonzero with [`CommitPolicy::CommitWithout`].
+    ///
+    /// If the policy won't commit without them, nothing is committed and the transaction is handed
+    /// back along with an [`AllocError::OutstandingAllocations`]. Any allocations that came back
+    /// while waiting stay in it.
+    #[allow(clippy::result_large_err)]
+    pub fn commit_with(
+        mut self,
+        root_data: &[u8],
+        policy: CommitPolicy,
+    ) -> Result<(WriteUnit, Vec<WriteAlloc>, usize), (Self, AllocError)> {
+        let outstanding = match policy {
+            CommitPolicy::WaitForAll(timeout) => self.0.wait_for_allocs(timeout),
+            CommitPolicy::CommitWithout | CommitPolicy::Fail => self.outstanding_allocations(),
+        };
+        if outstanding > 0 && policy != CommitPolicy::CommitWithout {
+            return Err((
+                self,
+                AllocError::OutstandingAllocations { count: outstanding },
+            ));
+        }
+        let (unit, allocs) = self.commit(root_data);
+        Ok((unit, allocs, outstanding))
+    }
+
+    /// Commit the transaction to the database and optionally return the requested long-term allocations.
+    ///
+    /// The transaction becomes visible to new readers right away, and durable once the
+    /// [`CommitUnit`] next commits. The returned [`WriteUnit`] is ready to start the next
+    /// transaction.
+    ///
+    /// Write allocations handed out by earlier commits that haven't come back yet are left out,
+    /// and can still go into a later transaction. Use [`commit_with`][Self::commit_with] to wait
+    /// for them, or to find out how many there were.
+    pub fn commit(mut self, root_data: &[u8]) -> (WriteUnit, Vec<WriteAlloc>) {
+        #[cfg(any(test, feature = "paranoid"))]
+        self.0.check_dirty_unreachable();
+
+        // Requested allocations get handed out, and their pages stay taken until each one is
+        // either used or dropped
+        let requested = std::mem::take(&mut self.0.alloc_req);
+        self.0.taken.extend(requested.iter().map(WriteAlloc::page));
+
+        // Completed allocations are part of the database now, so their pages stay taken for good.
+        // They need flushing along with everything else the transaction wrote.
+        let mut written = std::mem::take(&mut self.0.dirty);
+        for alloc in self.0.alloc_completions.drain(..) {
+            self.0.alloc_lens.remove(&alloc.page());
+            written.insert_range(alloc.page(), alloc.mem.len() as u64);
+            alloc.commit();
+        }
+
+        // Anything freed in this transaction is free once every reader has caught up to it
+        let id = self.0.root.id + 1;
+        for (_, _, state) in self.0.pending_free.iter_mut() {
+            if *state == FreePageState::Allocated {
+                *state = FreePageState::FreeAfter(id);
+            }
+        }
+
+        // Whatever's left is free as of this transaction, on disk as well as in memory. That
+        // includes anything spilled out of the availability lists.
+        self.0.unspill_all();
+        self.0.write_freelist(id);
+        for page in self.0.freelist_pages.iter() {
+            written.insert(*page);
+        }
+
+        // Publish the new root for readers and the committer
+        let checkout = RootCheckout {
+            id: self.0.root.id + 1,
+            root: Arc::from(root_data),
+            freelist: self.0.root.freelist,
+            file_len: self.0.root.file_len,
+        };
+        self.0.core.root.lock().unwrap().update(&checkout, written);
+        // Now that the file length is published, the committer may truncate down to it again
+        if let Ok(len) = usize::try_from(checkout.file_len) {
+            self.0.core.storage.lock().unwrap().set_writer_len(len);
+        }
+        self.0.root = checkout;
+
+        // Hole punch requests
+        for page in self.0.hole_punch_future_req.drain(..) {
+            self.0.hole_punch_req.send(page);
+            self.0.taken.insert(page);
+        }
+
+        (WriteUnit(self.0), requested)
+    }
+
+    /// Abort the current transaction, undoing all transaction operations and returning any
+    /// write allocations it was holding onto.
+    ///
+    /// The availability lists and file length go back to how they were when the transaction
+    /// started, so anything it allocated or gave up with [`compact_tail`][Self::compact_tail] is
+    /// free again right away. Allocations requested with
+    /// [`new_allocation`][Self::new_allocation] are the exception: they come back along with the
+    /// written-out ones, and their pages stay taken until they're dropped or used, same as if the
+    /// transaction had committed. The file keeps whatever growth they need.
+    ///
+    /// Fails with [`AllocError::AbortFirstTransaction`] if this is the first transaction on a
+    /// brand-new database, handing the transaction back.
+    #[allow(clippy::result_large_err)]
+    pub fn abort(mut self) -> Result<(WriteUnit, Vec<WriteAlloc>), (Self, AllocError)> {
+        if self.0.root.id == 0 {
+            return Err((self, AllocError::AbortFirstTransaction));
+        }
+        let start = std::mem::take(&mut self.0.txn_start);
+        // Spilled runs were on the lists when the transaction started, and so were the pages they
+        // were written to
+        self.0.spilled.clear();
+        self.0.available_4k = start.available_4k;
+        self.0.available_16k = start.available_16k;
+        self.0.available_blocks = start.available_blocks;
+        self.0.root.file_len = start.file_len;
+        for (page, len) in std::mem::take(&mut self.0.txn_returned) {
+            self.0.add_free_run(page, len);
+        }
+
+        // Requested allocations get handed back instead of freed, so their pages have to come
+        // off the lists again, along with any growth they were carved out of
+        let requested = std::mem::take(&mut self.0.alloc_req);
+        let grown = requested
+            .iter()
+            .map(|alloc| alloc.page + self.0.alloc_lens[&alloc.page])
+            .max()
+            .map_or(0, |end| end.next_multiple_of(BLOCK_SIZE as u64));
+        if grown > self.0.root.file_len {
+            let file_len = self.0.root.file_len;
+            self.0.add_free_run(file_len, grown - file_len);
+            self.0.root.file_len = grown;
+        }
+        for alloc in requested.iter() {
+            let len = self.0.alloc_lens[&alloc.page];
+            self.0.withhold_available(alloc.page, len);
+            self.0.taken.insert(alloc.page);
+        }
+
+        self.0.dirty.clear();
+        self.0.taken_txn.clear();
+        self.0
+            .pending_free
+            .retain(|(_, _, state)| *state != FreePageState::Allocated);
+        let mut ret = std::mem::take(&mut self.0.alloc_completions);
+        ret.extend(requested);
+        Ok((WriteUnit(self.0), ret))
+    }
+}
+
+/// Handles committing completed write transactions to disk.
+///
+/// This unit is basically another open read transaction, representing any future program that will
+/// open the database. When it commits to disk, it grabs the current completed transaction, flushes
+/// the pages written since the last commit to disk synchronously and advances to that completed
+/// transaction ID. The first commit after opening flushes everything instead.
+///
+/// For anonymous memory maps, no sync to disk occurs, but this does still need to be called.
+///
+/// Committing after every write transaction is generally a good idea, though this should be done in
+/// a separate thread, as this is a blocking operation. Committing also punches out any blocks the
+/// writer freed up, so with the `single-threaded` feature, calling it from the same loop as
+/// everything else is all that's needed.
+pub struct CommitUnit {
+    /// The current "checked-out" ID we're holding onto
+    id: u64,
+    /// The file length as of the last durable commit. The file can be truncated down to it.
+    durable_len: u64,
+    /// The data to commit to the root page
+    commit_data: Vec<u8>,
+    /// Any pending hole punch operations
+    hole_punch_req: PageReceiver,
+    /// Completed hole punch operations
+    hole_punch_resp: PageSender,
+    /// Tells the writer each time a transaction becomes durable, so it can reuse what it freed
+    durable: QueueSender<u64>,
+    /// The two root pages to write to
+    root0: &'static mut [u8],
+    root1: &'static mut [u8],
+    write_root0: bool,
+    /// How much to flush on each commit
+    sync_mode: SyncMode,
+    /// Whether the next commit has to flush everything, instead of only the pages written since
+    /// the last one. Set on open, as whatever the database was opened with might not be on disk.
+    flush_all: bool,
+    /// Access to the core database synchronization primitives
+    core: Arc<DbCore>,
+    /// Async tasks waiting on commits
+    #[cfg(feature = "async")]
+    notify: Arc<CommitNotify>,
+}
+
+impl CommitUnit {
+    /// Commit everything written so far, blocking until it's all on disk. Returns the ID of the
+    /// newest durable transaction, same as [`committed_id`][Self::committed_id] afterwards. With
+    /// a [`sync_mode`][Self::sync_mode] other than [`SyncMode::Full`], less of the blocking is
+    /// done, and "durable" only means as much as the mode promises.
+    ///
+    /// A failed commit changes nothing: the last durable transaction stays as it was, and the
+    /// writer can keep committing on top of what isn't durable yet. Calling this again retries,
+    /// picking up the newest transaction there is by then. Until a retry succeeds, readers from
+    /// [`ReadUnit::reader_durable`] keep seeing the last durable state.
+    ///
+    /// From async code, run this somewhere blocking is allowed, like tokio's `spawn_blocking` or
+    /// `block_in_place`, rather than on an executor thread. With the `async` feature, other tasks
+    /// can wait for the commit through [`notify`][Self::notify] instead of polling.
+    pub fn commit(&mut self) -> Result<u64, AllocError> {
+        let newest = self.core.root.lock().unwrap().id_tracker.newest_id();
+        self.commit_to(newest)
+    }
+
+    /// Commit everything up to and including transaction `id`, blocking until it's all on disk,
+    /// and leave anything newer for a later commit. Returns `id`. Transaction IDs come from
+    /// [`WriteUnit::generation`] after each commit.
+    ///
+    /// Fails with [`AllocError::CommitOutOfRange`] if `id` is older than the newest durable
+    /// transaction or newer than the newest one there is, and with
+    /// [`AllocError::CommitHistoryGone`] if it's more than [`COMMIT_HISTORY_LEN`] transactions
+    /// behind the newest.
+    pub fn commit_to(&mut self, id: u64) -> Result<u64, AllocError> {
+        self.prepare_to(id)?.publish()
+    }
+
+    /// Do the first half of a [`commit`][Self::commit]: flush everything written so far, but
+    /// don't write the root page yet. Nothing about the durable state changes until the returned
+    /// [`PreparedCommit`] is published, and a crash before then leaves the database at the last
+    /// durable transaction.
+    ///
+    /// This is for keeping something else in step with the database, like a log that has to
+    /// hold a record of each transaction before the transaction itself is durable. Write the
+    /// record in between preparing and publishing.
+    pub fn prepare(&mut self) -> Result<PreparedCommit<'_>, AllocError> {
+        let newest = self.core.root.lock().unwrap().id_tracker.newest_id();
+        self.prepare_to(newest)
+    }
+
+    /// Do the first half of a [`commit_to`][Self::commit_to], like [`prepare`][Self::prepare]
+    /// does for [`commit`][Self::commit]. Fails the same way `commit_to` does.
+    pub fn prepare_to(&mut self, id: u64) -> Result<PreparedCommit<'_>, AllocError> {
+        // Check out the transaction's ID now, so nothing it can reach gets reused while we're
+        // committing it. We also need to grab the state of the Root that we want to write out.
+        let (snapshot, data_len, new_id, written) = {
+            let mut mutex = self.core.root.lock().unwrap();
+            let newest = mutex.id_tracker.newest_id();
+            if id < self.id || id > newest {
+                return Err(AllocError::CommitOutOfRange {
+                    id,
+                    committed: self.id,
+                    newest,
+                });
+            }
+            let found = mutex.snapshot_at(id);
+            // Make sure it'll fit before checking out an ID for it
+            if let Some((snapshot, _)) = &found {
+                let len = snapshot.stored_len();
+                if len > ROOT_SIZE {
+                    return Err(AllocError::RootTooLarge {
+                        len,
+                        max: ROOT_SIZE,
+                    });
+                }
+            }
+            // It's durable already. Writing the same ID out to the other root page would leave
+            // both claiming to be the newest, so there's nothing to publish.
+            if id == self.id {
+                drop(mutex);
+                self.commit_data.clear();
+                return Ok(PreparedCommit {
+                    unit: self,
+                    id,
+                    pending: None,
+                });
+            }
+            let Some((snapshot, data_len)) = found else {
+                return Err(AllocError::CommitHistoryGone { id });
+            };
+            let written = (self.sync_mode == SyncMode::Full && !self.flush_all)
+                .then(|| mutex.written_through(id))
+                .filter(|written| written.run_count() <= MAX_FLUSH_RUNS);
+            // The ID we hold now is no newer than this one, so it's safe to check out
+            let new_id = mutex.id_tracker.checkout_at(id);
+            drop(mutex);
+            (snapshot, data_len, new_id, written)
+        };
+
+        // Serialize it without holding up readers
+        if let Err(e) = snapshot.store(&mut self.commit_data) {
+            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
+            return Err(e);
+        }
+
+        // Perform the main flush, of only the pages written since the last commit if there
+        // aren't too many runs of them
+        let res = {
+            let mutex = self.core.storage.lock().unwrap();
+            let res = match (self.sync_mode, written) {
+                (SyncMode::Full, Some(written)) => mutex.flush_runs(written.runs()),
+                (SyncMode::Full, None) => mutex.flush(),
+                (SyncMode::Async, _) => mutex.flush_async(),
+                (SyncMode::None, _) => Ok(()),
+            };
+            drop(mutex);
+            res
+        };
+        if let Err(e) = res {
+            // We failed to sync, so we need to undo our new checkout and retain the old one.
+            // This probably isn't recoverable, but just in case, we should act as correctly as possible.
+            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
+            return Err(e);
+        }
+        self.flush_all = false;
+
+        Ok(PreparedCommit {
+            unit: self,
+            id: new_id,
+            pending: Some((snapshot, data_len)),
+        })
+    }
+
+    /// Write out and flush the root page for a prepared commit of transaction `new_id`, making it
+    /// durable. Fails if the root page can't be written, handing back the checkout of `new_id`.
+    fn publish(
+        &mut self,
+        new_id: u64,
+        snapshot: RootSnapshot,
+        data_len: u64,
+    ) -> Result<u64, AllocError> {
+        // Update the tree root
+        let root_write = if self.write_root0 { &mut self.root0 } else { &mut self.root1 };
+        let len = self.commit_data.len();
+        if len > root_write.len() {
+            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
+            return Err(AllocError::RootTooLarge {
+                len,
+                max: root_write.len(),
+            });
+        }
+        let (root_write, tail) = root_write.split_at_mut(len);
+        root_write.copy_from_slice(&self.commit_data);
+        // Don't leave any of an older, longer root page lying around after this one
+        tail.fill(0);
+
+        // Flush the tree root
+        let root_block = BlockRange::new(if self.write_root0 { 0 } else { ROOT_SIZE }, ROOT_SIZE);
+        let res = if self.sync_mode == SyncMode::None {
+            Ok(())
+        } else {
+            let mutex = self.core.storage.lock().unwrap();
+            let res = mutex.flush_range(root_block);
+            drop(mutex);
+            res
+        };
+        if let Err(e) = res {
+            // We failed to sync, so we need to undo our new checkout and retain the old one.
+            // This probably isn't recoverable, but just in case, we should act as correctly as possible.
+            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
+            return Err(e);
+        }
+
+        // Swap in the new read transaction id, and alternate root pages so this one survives if
+        // the next is torn
+        let mut root = self.core.root.lock().unwrap();
+        root.id_tracker.checkin(self.id);
+        root.forget_through(new_id);
+        root.durable = (snapshot, data_len);
+        drop(root);
+        self.id = new_id;
+        self.durable.send(new_id);
+        self.durable_len = data_len;
+        self.write_root0 = !self.write_root0;
+        #[cfg(feature = "async")]
+        self.notify.committed(new_id);
+        Ok(new_id)
+    }
+
+    /// The ID of the newest durable transaction: the last one committed, or the one the database
+    /// was opened at if nothing has been committed since. Compare it against
+    /// [`WriteUnit::generation`] to see how far behind the disk is.
+    pub fn committed_id(&self) -> u64 {
+        self.id
+    }
+
+    /// How much each commit waits on the disk, as set by [`OpenOptions::sync_mode`].
+    pub fn sync_mode(&self) -> SyncMode {
+        self.sync_mode
+    }
+
+    /// Move this unit onto a thread of its own, which commits whenever the returned handle asks
+    /// it to. Dropping the handle commits one last time and stops the thread.
+    #[cfg(not(feature = "single-threaded"))]
+    pub fn spawn(self) -> CommitHandle {
+        CommitHandle::new(self)
+    }
+
+    /// Get a handle async tasks can use to wait on commits.
+    #[cfg(feature = "async")]
+    pub fn notify(&self) -> Arc<CommitNotify> {
+        self.notify.clone()
+    }
+
+    /// Start simulating a disk under the database that loses power after `budget` more bytes are
+    /// flushed to it. The real file carries on as before, so only [`take_disk`][Self::take_disk]
+    /// shows what a power cut would have left behind.
+    #[cfg(feature = "testing")]
+    #[doc(hidden)]
+    pub fn cut_power_after(&self, budget: usize) {
+        self.core.storage.lock().unwrap().cut_power_after(budget);
+    }
+
+    /// Stop simulating power loss, returning the database as it was left on the simulated disk.
+    /// Writing that out to a file and opening it is the same as rebooting after the power cut.
+    #[cfg(feature = "testing")]
+    #[doc(hidden)]
+    pub fn take_disk(&self) -> Option<Vec<u8>> {
+        self.core.storage.lock().unwrap().take_disk()
+    }
+
+    /// Punch holes for every block the writer has queued up, handing each one back to it once
+    /// done. A failed punch only means the space isn't returned to the OS, so the block is handed
+    /// back either way.
+    fn punch_holes(&mut self) {
+        while let Some(page) = self.hole_punch_req.try_recv() {
+            if let Ok(block) = BlockRange::from_pages(page, BLOCK_SIZE / PAGE_SIZE) {
+                let mut storage = self.core.storage.lock().unwrap();
+                // Safety: the writer only requests blocks that nothing can read or write anymore.
+                let _ = unsafe { storage.hole_punch(block) };
+            }
+            self.hole_punch_resp.send(page);
+        }
+    }
+
+    /// Truncate the file down to the durable file length, once every reader that could still
+    /// reach past it is gone. Until then, this is retried on every commit. Like hole punching, a
+    /// failure only means the space isn't returned to the OS.
+    fn truncate_tail(&mut self) {
+        let root = self.core.root.lock().unwrap();
+        if root.id_tracker.oldest_id() < root.shrunk_at {
+            return;
+        }
+        drop(root);
+        let Ok(len) = usize::try_from(self.durable_len) else {
+            return;
+        };
+        let mut storage = self.core.storage.lock().unwrap();
+        // Safety: the writer gave up everything past the durable length before publishing it, and
+        // every reader left checked out has a length no longer than it. Anything the writer has
+        // grown into since is kept.
+        let _ = unsafe { storage.truncate_tail(len) };
+    }
+}
+
+/// A commit that's been flushed but not published yet, from [`CommitUnit::prepare`].
+///
+/// Everything the transaction wrote is on disk, but the root page that makes it durable isn't.
+/// [`publish`][Self::publish] writes that out. Until then, the database reopens at the last
+/// durable transaction, and readers from [`ReadUnit::reader_durable`] keep seeing it.
+/// [`abandon`][Self::abandon], or just dropping this, leaves things that way.
+pub struct PreparedCommit<'a> {
+    unit: &'a mut CommitUnit,
+    id: u64,
+    /// The root to publish and the data length that goes with it. `None` if `id` is already
+    /// durable, or once this has been published or abandoned.
+    pending: Option<(RootSnapshot, u64)>,
+}
+
+impl PreparedCommit<'_> {
+    /// The ID the transaction will be durable as once published.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The root page that publishing writes out. Empty if the transaction is already durable, in
+    /// which case there's nothing to write.
+    pub fn root_page(&self) -> &[u8] {
+        &self.unit.commit_data
+    }
+
+    /// Write out and flush the root page, making the transaction durable. Returns its ID, same
+    /// as [`CommitUnit::commit_to`].
+    ///
+    /// A failure leaves the last durable transaction as it was, and the commit can be retried by
+    /// preparing it again.
+    pub fn publish(mut self) -> Result<u64, AllocError> {
+        let id = match self.pending.take() {
+            Some((snapshot, data_len)) => self.unit.publish(self.id, snapshot, data_len)?,
+            None => self.id,
+        };
+        // The transactions that freed up any requested blocks, or gave up the end of the file,
+        // are now durable
+        self.unit.punch_holes();
+        self.unit.truncate_tail();
+        Ok(id)
+    }
+
+    /// Give up on the commit without writing the root page. The last durable transaction stays
+    /// as it was. Same as dropping this.
+    pub fn abandon(self) {}
+}
+
+impl Drop for PreparedCommit<'_> {
+    fn drop(&mut self) {
+        if self.pending.take().is_some() {
+            self.unit
+                .core
+                .root
+                .lock()
+                .unwrap()
+                .id_tracker
+                .checkin(self.id);
+        }
+    }
+}
+
+type AllocTuple = (ReadUnit, WriteUnit, CommitUnit);
+
+/// How many records the audit log keeps unless told otherwise.
+#[cfg(feature = "alloc-audit")]
+pub const DEFAULT_AUDIT_LOG_LEN: usize = 4096;
+
+/// Make sure the system page size is one we can map blocks with, returning whether it's small
+/// enough to advise the OS about our clusters with too.
+///
+/// Maps only ever start on block boundaries, so any power of two up to the block size works. Our
+/// 4 kiB pages are the same no matter what the system's are. Larger system pages just mean the
+/// OS sees several of ours as one, so hole punching and prefaulting get turned off.
+fn check_page_size(found: usize) -> Result<bool, AllocError> {
+    if !found.is_power_of_two() || found > BLOCK_SIZE {
+        return Err(AllocError::UnsupportedPageSize { found });
+    }
+    Ok(found <= CLUSTER_SIZE)
+}
+
+/// How much waiting on the disk [`CommitUnit::commit`] does, set with
+/// [`OpenOptions::sync_mode`]. Anonymous maps never touch a disk, whichever mode is picked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Flush everything written, then the root page, blocking until both are on disk. A commit
+    /// that returns is durable. This is the default.
+    #[default]
+    Full,
+    /// Start flushing everything written without waiting on it, then flush the root page and
+    /// wait on that. Commits are much faster, but a crash can leave the newest root page on disk
+    /// ahead of some of the data it points to.
+    ///
+    /// If writing that data out fails later on, the error comes back from the next synchronous
+    /// flush of the file. That's the root page flush of a later commit, so the next call to
+    /// [`commit`][CommitUnit::commit] fails with [`AllocError::Sync`], leaving the last durable
+    /// transaction as it was. Transactions that were published before the failure may be missing
+    /// data on disk, so the database should be reopened from a known good copy.
+    Async,
+    /// Never flush anything, leaving it to the OS to write the maps out whenever it likes.
+    /// Commits still hand out transaction IDs and wake up readers, same as for an anonymous map,
+    /// but a crash can lose any number of them, or leave the file torn between two.
+    None,
+}
+
+/// Options for opening a database, either backed by a file with [`open`][Self::open] or by
+/// anonymous memory with [`open_anon`][Self::open_anon].
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    size: Option<usize>,
+    file_type: [u8; 8],
+    accept_any_file_type: bool,
+    txn_memory_budget: Option<usize>,
+    metrics: Metrics,
+    prefault_on_grow: bool,
+    growth_step: usize,
+    max_size: Option<usize>,
+    sync_mode: SyncMode,
+    verify_checksums: bool,
+    #[cfg(feature = "read-cache")]
+    read_cache: Option<usize>,
+    #[cfg(feature = "alloc-audit")]
+    audit_log_len: usize,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            size: None,
+            file_type: *b"crab-db\0",
+            accept_any_file_type: false,
+            txn_memory_budget: None,
+            metrics: Metrics::default(),
+            prefault_on_grow: false,
+            growth_step: MIN_DB_SIZE,
+            max_size: None,
+            sync_mode: SyncMode::Full,
+            verify_checksums: true,
+            #[cfg(feature = "read-cache")]
+            read_cache: None,
+            #[cfg(feature = "alloc-audit")]
+            audit_log_len: DEFAULT_AUDIT_LOG_LEN,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Set the desired size of the opened file allocator. If one isn't
+    /// provided, this will default to the minimum of 4 MiB, or if the file
+    /// exists, then the file size. If the file exists and has a larger size
+    /// than the one set here, then the file's size is used instead.
+    ///
+    /// The size must be a multiple of [`BLOCK_SIZE`] and at least [`MIN_DB_SIZE`]. It isn't
+    /// rounded to fit; opening fails with [`AllocError::InvalidSize`] instead.
+    pub fn size(&mut self, size: usize) -> &mut Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Work out how large to make the database, given the size of the existing file, or 0 if
+    /// there isn't one yet.
+    fn target_size(&self, file_size: usize) -> Result<usize, AllocError> {
+        let Some(size) = self.size else {
+            return Ok(MIN_DB_SIZE.max(file_size));
+        };
+        if size < MIN_DB_SIZE || !size.is_multiple_of(BLOCK_SIZE) {
+            return Err(AllocError::InvalidSize { size });
+        }
+        Ok(size.max(file_size))
+    }
+
+    /// Set the desired file type header for creating a new database. If one isn't specified, this
+    /// will default to the byte string "crab-db\0".
+    ///
+    /// Opening an existing database with a different file type fails with
+    /// [`FormatError::FileType`], unless [`accept_any_file_type`][Self::accept_any_file_type] is
+    /// set.
+    pub fn file_type(&mut self, file_type: &[u8; 8]) -> &mut Self {
+        self.file_type = *file_type;
+        self
+    }
+
+    /// Open existing databases whatever their file type is, for tools that inspect arbitrary
+    /// databases. The type they were created with can be found with [`ReadUnit::file_type`].
+    pub fn accept_any_file_type(&mut self) -> &mut Self {
+        self.accept_any_file_type = true;
+        self
+    }
+
+    /// Set a soft limit, in bytes, on the memory used to track a single write transaction's
+    /// dirty pages, taken pages, and availability lists. When the limit is hit, most of the
+    /// availability lists are spilled out to free pages in the same format as the on-disk
+    /// freelist, and read back in as the rest run dry, or when the transaction commits. If that
+    /// isn't enough, a warning is raised through the [metrics hook][Self::metrics], but the
+    /// transaction is allowed to continue.
+    ///
+    /// By default, there is no limit.
+    pub fn txn_memory_budget(&mut self, bytes: usize) -> &mut Self {
+        self.txn_memory_budget = Some(bytes);
+        self
+    }
+
+    /// Set a hook to be notified of allocator events.
+    pub fn metrics(&mut self, hook: Arc<dyn MetricsHook>) -> &mut Self {
+        self.metrics = Metrics::new(Some(hook));
+        self
+    }
+
+    /// Prefault newly mapped memory whenever the database grows, so that a bulk load crossing a
+    /// growth boundary doesn't take a page fault on every page it writes into. Prefaulting runs on
+    /// a helper thread and never holds up the writer, except when the database grows again before
+    /// the last prefault finished.
+    ///
+    /// On Linux 5.14 and later, this faults in every page as `MAP_POPULATE` would. Elsewhere on
+    /// Unix, the OS is only told the pages will be needed soon, and on Windows this does nothing.
+    /// With the `single-threaded` feature, prefaulting happens inline as part of growing instead.
+    /// Systems with pages larger than a cluster never prefault; see
+    /// [`OpenReport::advice_disabled`]. Off by default.
+    pub fn prefault_on_grow(&mut self, prefault: bool) -> &mut Self {
+        self.prefault_on_grow = prefault;
+        self
+    }
+
+    /// Set how many bytes to grow the database by whenever the writer runs out of free space.
+    /// Bigger steps mean fewer resizes and remaps, at the cost of more unused space at the end of
+    /// the file. Defaults to [`MIN_DB_SIZE`].
+    ///
+    /// Like [`size`][Self::size], the step must be a nonzero multiple of [`BLOCK_SIZE`], and
+    /// opening fails with [`AllocError::InvalidSize`] otherwise.
+    pub fn growth_step(&mut self, bytes: usize) -> &mut Self {
+        self.growth_step = bytes;
+        self
+    }
+
+    /// Stop the database from growing past `bytes`. Once it's that large, allocations that can't
+    /// be fit into the existing free space fail with [`AllocError::DatabaseFull`]. The last
+    /// growth step is cut short to land on the limit exactly.
+    ///
+    /// The limit is rounded down to a whole number of blocks, and raised to the file's size on
+    /// open if it's below that. By default, there is no limit.
+    pub fn max_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Set how much the [`CommitUnit`] waits on the disk when committing. Defaults to
+    /// [`SyncMode::Full`].
+    pub fn sync_mode(&mut self, mode: SyncMode) -> &mut Self {
+        self.sync_mode = mode;
+        self
+    }
+
+    /// Set whether freelist pages have their hashes checked when they're loaded. A page that
+    /// doesn't match fails with [`FormatError::FreelistHash`], and opening falls back on the older
+    /// root page if it can. Defaults to on; turning it off saves hashing every freelist page on
+    /// open, at the cost of trusting whatever bit rot has done to them.
+    pub fn verify_checksums(&mut self, verify: bool) -> &mut Self {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Give the [`ReadUnit`] a [`ReadCache`] holding up to `max_pages` pages. By default, there
+    /// is no read cache.
+    #[cfg(feature = "read-cache")]
+    pub fn read_cache(&mut self, max_pages: usize) -> &mut Self {
+        self.read_cache = Some(max_pages);
+        self
+    }
+
+    /// Keep the last `records` allocations and frees in the audit log. Defaults to
+    /// [`DEFAULT_AUDIT_LOG_LEN`]. Zero turns the log off, though double frees are still caught.
+    #[cfg(feature = "alloc-audit")]
+    pub fn audit_log_len(&mut self, records: usize) -> &mut Self {
+        self.audit_log_len = records;
+        self
+    }
+    
+    /// Open an anonymous memory map instead of an on-disk file.
+    ///
+    /// The database starts out empty and lives only as long as its units do. Commits still hand
+    /// out new transaction IDs and wake up readers as usual, but there's no file to flush, so
+    /// they never block on the disk. Handy for testing code built on the allocator.
+    pub fn open_anon(&self) -> Result<AllocTuple, AllocError> {
+        let advise = check_page_size(page_size::get())?;
+        let size = self.target_size(0)?;
+        let map = MmapRaw::from(
+            MmapMut::map_anon(size).map_err(|e| AllocError::AllocFailed {
+                requested: size,
+                source: e,
+            })?,
+        );
+        let storage = StorageInner::init(map, None)
+            .with_advice(advise)
+            .with_prefault_on_grow(self.prefault_on_grow);
+        self.assemble(storage, None, size)
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<AllocTuple, AllocError> {
+        use fs4::fs_std::FileExt;
+
+        let advise = check_page_size(page_size::get())?;
+        // Catch a bad size before creating anything
+        self.target_size(0)?;
+
+        // Open and lock the file
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(AllocError::Open)?;
+        if !file.try_lock_exclusive().map_err(AllocError::Lock)? {
+            return Err(AllocError::Lock(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "database file is already open",
+            )));
+        }
+
+        // Figure out the file size and resize as needed.
+        let file_size = file.metadata().map_err(AllocError::Open)?.len();
+        if file_size > (usize::MAX as u64) {
+            return Err(AllocError::FileTooLarge { len: file_size });
+        }
+        let file_size = file_size as usize;
+        let is_new = file_size == 0;
+        if (file_size > 0 && file_size < MIN_DB_SIZE) || ((file_size & (BLOCK_SIZE - 1)) != 0) {
+            return Err(AllocError::DataFormat(error::FormatError::FileSize));
+        }
+        // Make sure it's the kind of database we're after before resizing anything
+        if !is_new {
+            self.check_file_type(&file)?;
+        }
+        let requested_size = self.target_size(file_size)?;
+        if requested_size != file_size {
+            file.set_len(requested_size as u64)
+                .map_err(|e| AllocError::ResizeFailed {
+                    size: file_size,
+                    requested: requested_size,
+                    source: e,
+                })?;
+        }
+
+        let map = MmapOptions::new()
+            .len(requested_size)
+            .map_raw(&file)
+            .map_err(|e| AllocError::AllocFailed {
+                requested: requested_size,
+                source: e,
+            })?;
+        
+
+        let storage = StorageInner::init(map, Some(file))
+            .with_advice(advise)
+            .with_prefault_on_grow(self.prefault_on_grow);
+        self.assemble(storage, (!is_new).then_some(file_size), requested_size)
+    }
+
+    /// Check that the existing database in `file` has the file type we're opening it as, failing
+    /// with [`FormatError::FileType`] if not. Anything goes with
+    /// [`accept_any_file_type`][Self::accept_any_file_type].
+    fn check_file_type(&self, mut file: &std::fs::File) -> Result<(), AllocError> {
+        if self.accept_any_file_type {
+            return Ok(());
+        }
+        let mut roots = vec![0; ROOT_MAP_SIZE];
+        file.read_exact(&mut roots).map_err(AllocError::Open)?;
+        let (root, _) = RootData::load_newest(&roots[..ROOT_SIZE], &roots[ROOT_SIZE..])?;
+        if root.file_type != self.file_type {
+            return Err(AllocError::DataFormat(FormatError::FileType {
+                expected: self.file_type,
+                found: root.file_type,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Set up the units on top of freshly mapped storage of `requested_size` bytes. `file_size` is
+    /// the size of the existing database being opened, or `None` if it's a brand new one.
+    ///
+    /// An existing database is opened from its newest intact root page. If that one's freelist
+    /// turns out to be corrupt, the commit it came from can't have fully made it to disk, so the
+    /// older root page is used instead, provided it's intact too.
+    fn assemble(
+        &self,
+        storage: StorageInner,
+        file_size: Option<usize>,
+        requested_size: usize,
+    ) -> Result<AllocTuple, AllocError> {
+        if file_size.is_none() {
+            return self
+                .assemble_root(storage, file_size, requested_size, None)
+                .map_err(|(e, _)| e);
+        }
+        let read_storage = unsafe { RawMemory::new(&storage) };
+        let roots: [&[u8]; 2] = [0, ROOT_SIZE].map(|start| {
+            let range = BlockRange::new(start, ROOT_SIZE);
+            unsafe { read_storage.get_mut_slice(range).unwrap().unwrap() as &[u8] }
+        });
+        let (root, open_report) = RootData::load_newest(roots[0], roots[1])?;
+        let older = open_report.root_slot.map_or(0, |slot| 1 - slot);
+        let both_intact = !open_report.recovered();
+        let loaded = Some((root, open_report));
+        match self.assemble_root(storage, file_size, requested_size, loaded) {
+            Err((e @ AllocError::DataFormat(_), Some(storage))) if both_intact => {
+                let open_report = OpenReport {
+                    root_slot: Some(older),
+                    damaged: Some(e),
+                    ..OpenReport::default()
+                };
+                let loaded = Some((RootData::load(roots[older])?, open_report));
+                self.assemble_root(storage, file_size, requested_size, loaded)
+                    .map_err(|(e, _)| e)
+            }
+            res => res.map_err(|(e, _)| e),
+        }
+    }
+
+    /// Set up the units for [`assemble`][Self::assemble], using the given root page and how it
+    /// was loaded, or a brand new one if `None`. If the root's freelist can't be loaded, the
+    /// storage is handed back along with the error, so another root page can be tried.
+    #[allow(clippy::result_large_err)]
+    fn assemble_root(
+        &self,
+        storage: StorageInner,
+        file_size: Option<usize>,
+        requested_size: usize,
+        loaded: Option<(RootData, OpenReport)>,
+    ) -> Result<AllocTuple, (AllocError, Option<StorageInner>)> {
+        let is_new = file_size.is_none();
+        let file_size = file_size.unwrap_or(0);
+        let read_storage = unsafe { RawMemory::new(&storage) };
+
+        let commit_root0 = unsafe { read_storage.get_mut_slice(BlockRange::new(0, ROOT_SIZE)).unwrap().unwrap() };
+        let commit_root1 = unsafe { read_storage.get_mut_slice(BlockRange::new(ROOT_SIZE, ROOT_SIZE)).unwrap().unwrap() };
+        let (mut root, mut open_report) = loaded.unwrap_or_else(|| {
+            (
+                RootData::new(
+                    &self.file_type,
+                    ByteOffset(ROOT_MAP_SIZE as u64),
+                    requested_size as u64,
+                ),
+                OpenReport::default(),
+            )
+        });
+        open_report.advice_disabled = !storage.advice_enabled();
+        // Commit to the root page we didn't load first, so a damaged one gets replaced right away
+        let commit_write_root0 = open_report.root_slot != Some(0);
+        // Anything past the recorded length, whether it never got committed or we're growing the
+        // file now, is free space the freelist doesn't know about. The recorded length stays put
+        // until the next commit writes out a freelist that accounts for that space, so it's found
+        // again on every open until then.
+        let recorded = root.file_len;
+        let unrecorded_blocks: Vec<u64> = if is_new {
+            Vec::new()
+        } else {
+            let grown = (file_size as u64..requested_size as u64).step_by(BLOCK_SIZE);
+            root.unrecorded_blocks(file_size as u64)
+                .map_err(|e| (e, None))?
+                .chain(grown)
+                .collect()
+        };
+        root.data_len = recorded.max(file_size as u64).max(requested_size as u64);
+        let durable_len = root.data_len;
+        let commit_id = root.id_tracker.checkout();
+
+        let write_root_checkout = RootCheckout {
+            id: root.id_tracker.newest,
+            root: root.root.clone(),
+            freelist: root.freelist,
+            file_len: root.data_len,
+        };
+
+        let core = Arc::new(DbCore {
+            root: Mutex::new(root),
+            read_pages: Mutex::new(PageReadTracker::default()),
+            storage: Mutex::new(storage),
+            writer: Mutex::new(WriterState::default()),
+            readers: AtomicUsize::new(0),
+            checkin_error: Mutex::new(None),
+            open_report,
+            max_size: self
+                .max_size
+                .map(|max| (max as u64 & !(BLOCK_SIZE as u64 - 1)).max(durable_len)),
+            verify_checksums: self.verify_checksums,
+            #[cfg(feature = "alloc-audit")]
+            audit: Mutex::new(AuditLog::new(self.audit_log_len)),
+        });
+
+        let (write_hole_punch_req, commit_hole_punch_req) = page_queue();
+        let (commit_hole_punch_resp, write_hole_punch_resp) = page_queue();
+        let (commit_durable, write_durable) = queue();
+
+        let mut write = WriteUnitInner::new(
+            core.clone(),
+            read_storage.clone(),
+            write_root_checkout,
+            write_hole_punch_req,
+            write_hole_punch_resp,
+            write_durable,
+            self,
+        )
+        .map_err(|e| (e, None))?;
+
+        let read = ReadUnit::new(read_storage.clone(), core.clone());
+        let internal = |msg| (AllocError::Internal(msg), None);
+        if is_new {
+            // If we're brand new, the first page past the roots holds a freelist with everything
+            // after it in one run
+            let first_free = ROOT_MAP_SIZE + PAGE_SIZE;
+            let run = (first_free as u64, (requested_size - first_free) as u64);
+            let head = BlockRange::new(ROOT_MAP_SIZE, PAGE_SIZE);
+            let mem = unsafe { read_storage.get_mut_slice(head) }
+                .ok()
+                .flatten()
+                .ok_or_else(|| internal("no room for a new database's freelist"))?;
+            let mut page = unsafe { IntPage::new(mem.as_mut_ptr(), format::FREELIST_LEAF) };
+            page.insert(run.0, run.1)
+                .map_err(|_| internal("initial freelist page overflowed"))?;
+            page.seal();
+            write.add_free_run(run.0, run.1);
+            write.freelist_runs.insert_range(run.0, run.1);
+            write.freelist_pages.push(head.start as u64);
+        } else {
+            let loaded = write.load_freelist(&read.reader(), recorded);
+            if let Err(e) = loaded {
+                // Nothing else holds onto the core yet, so the storage can be taken back out
+                drop((read, write));
+                let storage = Arc::try_unwrap(core)
+                    .ok()
+                    .and_then(|core| core.storage.into_inner().ok());
+                return Err((e, storage));
+            }
+            write.available_blocks.extend(unrecorded_blocks);
+        }
+
+        #[cfg(feature = "read-cache")]
+        let read = match self.read_cache {
+            Some(max_pages) => read.with_read_cache(max_pages),
+            None => read,
+        };
+
+        let commit = CommitUnit {
+            id: commit_id,
+            durable_len,
+            commit_data: Vec::new(),
+            hole_punch_req: commit_hole_punch_req,
+            hole_punch_resp: commit_hole_punch_resp,
+            durable: commit_durable,
+            root0: commit_root0,
+            root1: commit_root1,
+            write_root0: commit_write_root0,
+            sync_mode: self.sync_mode,
+            flush_all: true,
+            core,
+            #[cfg(feature = "async")]
+            notify: Arc::new(CommitNotify::new(commit_id)),
+        };
+
+        Ok((read, WriteUnit(write), commit))
+    }
+}
+
+pub fn alloc_anon(size: usize) -> Result<AllocTuple, AllocError> {
+    OpenOptions::default().size(size).open_anon()
+}
+
+pub fn alloc_open<P: AsRef<Path>>(path: P) -> Result<AllocTuple, AllocError> {
+    OpenOptions::default().open(path)
+}
+
+pub struct Allocator {}
+
+pub struct AllocInfo {
+    addr: u64,
+    pages: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// A file in the temp directory for a test to open, deleted again when this is dropped, so it
+    /// doesn't outlive a failing test. Declare it before anything holding the file open.
+    pub(crate) struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        /// A path unique to `name` and this process, with any file left there cleared out.
+        pub(crate) fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("crab-db-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Deref for TempPath {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    pub(crate) fn test_core() -> Arc<DbCore> {
+        let map = MmapRaw::from(MmapMut::map_anon(MIN_DB_SIZE).unwrap());
+        Arc::new(DbCore {
+            root: Mutex::new(RootData::new(
+                b"crab-db\0",
+                ByteOffset::default(),
+                MIN_DB_SIZE as u64,
+            )),
+            read_pages: Mutex::new(PageReadTracker::default()),
+            storage: Mutex::new(StorageInner::init(map, None)),
+            writer: Mutex::new(WriterState::default()),
+            readers: AtomicUsize::new(0),
+            checkin_error: Mutex::new(None),
+            open_report: OpenReport::default(),
+            max_size: None,
+            verify_checksums: true,
+            #[cfg(feature = "alloc-audit")]
+            audit: Mutex::new(AuditLog::new(DEFAULT_AUDIT_LOG_LEN)),
+        })
+    }
+
+    pub(crate) fn test_storage(core: &Arc<DbCore>) -> RawMemory {
+        unsafe { RawMemory::new(&core.storage.lock().unwrap()) }
+    }
+
+    pub(crate) fn test_writer_on(
+        core: &Arc<DbCore>,
+        options: &OpenOptions,
+    ) -> Result<WriteUnitInner, AllocError> {
+        let root = core.root.lock().unwrap().checkout();
+        let (hole_punch_req, _) = page_queue();
+        let (_, hole_punch_resp) = page_queue();
+        let (_, durable) = queue();
+        WriteUnitInner::new(
+            core.clone(),
+            test_storage(core),
+            root,
+            hole_punch_req,
+            hole_punch_resp,
+            durable,
+            options,
+        )
+    }
+
+    pub(crate) fn test_writer(options: &OpenOptions) -> WriteUnitInner {
+        test_writer_on(&test_core(), options).unwrap()
+    }
+
+    pub(crate) fn at(offset: u64) -> ByteOffset {
+        ByteOffset::new(offset).unwrap()
+    }
+
+    #[test]
+    fn misaligned_reads() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let mut txn = read.reader();
+        unsafe {
+            let mem = txn.read(BlockRange::new(ROOT_MAP_SIZE, PAGE_SIZE)).unwrap();
+            assert_eq!(mem.as_ptr() as usize & (ALLOC_ALIGN - 1), 0);
+            assert!(matches!(
+                txn.read(BlockRange::new(ROOT_MAP_SIZE + 8, PAGE_SIZE)),
+                Err(AllocError::Misaligned { offset }) if offset == ROOT_MAP_SIZE + 8
+            ));
+            assert!(matches!(
+                txn.get_block(BlockRange::new(ROOT_MAP_SIZE + 1, PAGE_SIZE)),
+                Err(AllocError::Misaligned { .. })
+            ));
+
+            // The root pages are never handed out as data
+            for offset in [0, ROOT_SIZE, ROOT_MAP_SIZE - PAGE_SIZE] {
+                assert!(matches!(
+                    txn.read(BlockRange::new(offset, PAGE_SIZE)),
+                    Err(AllocError::RootAccess { offset: o }) if o == offset
+                ));
+                assert!(matches!(
+                    txn.get_block(BlockRange::new(offset, PAGE_SIZE)),
+                    Err(AllocError::RootAccess { .. })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_page_bits() {
+        let tagged = (1 << 48) | ROOT_MAP_SIZE as u64;
+        assert_eq!(ByteOffset::new(PAGE_NUM_MASK).unwrap().get(), PAGE_NUM_MASK);
+        assert!(matches!(
+            ByteOffset::try_from(tagged),
+            Err(AllocError::ReservedPageBits { page }) if page == tagged
+        ));
+
+        // Root data with a tagged freelist head is rejected, even with a valid hash
+        let mut root = RootData::new(b"crab-db\0", ByteOffset(tagged), MIN_DB_SIZE as u64);
+        let mut buf = Vec::new();
+        root.store(&mut buf).unwrap();
+        assert!(matches!(
+            RootData::load(&buf),
+            Err(AllocError::ReservedPageBits { page }) if page == tagged
+        ));
+        root.freelist = ByteOffset(ROOT_MAP_SIZE as u64);
+        root.store(&mut buf).unwrap();
+        assert_eq!(
+            RootData::load(&buf).unwrap().freelist.get(),
+            ROOT_MAP_SIZE as u64
+        );
+
+        // Reads never mask the reserved bits off to reach the page underneath
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let mut txn = read.reader();
+        for start in [tagged, !(ALLOC_ALIGN as u64 - 1)] {
+            let range = BlockRange::new(start as usize, PAGE_SIZE);
+            unsafe {
+                assert!(matches!(
+                    txn.read(range),
+                    Err(AllocError::ReservedPageBits { page }) if page == start
+                ));
+                assert!(matches!(
+                    txn.get_block(range),
+                    Err(AllocError::ReservedPageBits { .. })
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn range_conversions() {
+        let page = ROOT_MAP_SIZE as u64;
+        let range = BlockRange::from_pages(page, 3).unwrap();
+        assert_eq!(range, BlockRange::new(ROOT_MAP_SIZE, 3 * PAGE_SIZE));
+        assert_eq!(range.to_pages(), Some((page, 3)));
+        let alloc = Alloc::from_range(range).unwrap();
+        assert_eq!(alloc.page.get(), page);
+        assert_eq!(alloc.len, 3 * PAGE_SIZE);
+        assert_eq!(alloc.to_range().unwrap(), range);
+        assert_eq!(
+            BlockRange::from_pages(page, 0).unwrap().to_pages(),
+            Some((page, 0))
+        );
+
+        // Ranges that don't start and end on a page boundary have no page form
+        assert_eq!(
+            BlockRange::new(ROOT_MAP_SIZE + 8, PAGE_SIZE).to_pages(),
+            None
+        );
+        assert_eq!(
+            BlockRange::new(ROOT_MAP_SIZE, PAGE_SIZE + 1).to_pages(),
+            None
+        );
+        assert!(matches!(
+            Alloc::from_range(BlockRange::new(ROOT_MAP_SIZE, 100)),
+            Err(AllocError::Misaligned { .. })
+        ));
+
+        // Overflowing the page count, or the end of the address space, is caught
+        let overflow = |res: Result<BlockRange, AllocError>| {
+            matches!(res, Err(AllocError::RangeOverflow { .. }))
+        };
+        assert!(overflow(BlockRange::from_pages(page, usize::MAX)));
+        assert!(overflow(BlockRange::from_pages(
+            PAGE_NUM_MASK & !(PAGE_SIZE as u64 - 1),
+            usize::MAX / PAGE_SIZE
+        )));
+        let alloc = Alloc {
+            page: ByteOffset::new(PAGE_NUM_MASK).unwrap(),
+            len: usize::MAX,
+        };
+        assert!(overflow(alloc.to_range()));
+        #[cfg(target_pointer_width = "32")]
+        assert!(overflow(BlockRange::from_pages(1 << 40, 1)));
+
+        // As are page numbers with reserved bits
+        assert!(matches!(
+            BlockRange::from_pages(1 << 48, 1),
+            Err(AllocError::ReservedPageBits { .. })
+        ));
+        assert!(matches!(
+            Alloc::from_range(BlockRange::new(usize::MAX & !(PAGE_SIZE - 1), PAGE_SIZE)),
+            Err(AllocError::ReservedPageBits { .. })
+        ));
+    }
+
+    #[test]
+    fn offset_page_conversions() {
+        let page = PageNo::new(5).unwrap();
+        assert_eq!(page.to_offset(), at(5 * PAGE_SIZE as u64));
+        assert_eq!(page.to_offset().to_page().unwrap(), page);
+        assert_eq!(at(0).to_page().unwrap(), PageNo::default());
+
+        // Offsets that aren't on a page boundary aren't any page's offset
+        assert!(matches!(
+            at(5 * PAGE_SIZE as u64 + 8).to_page(),
+            Err(AllocError::Misaligned { offset }) if offset == 5 * PAGE_SIZE + 8
+        ));
+
+        // The last page is the one whose offset still fits in the mask
+        let last = PAGE_NUM_MASK / PAGE_SIZE as u64;
+        assert_eq!(
+            PageNo::new(last).unwrap().to_offset(),
+            at(PAGE_NUM_MASK & !(PAGE_SIZE as u64 - 1))
+        );
+        for page in [last + 1, u64::MAX] {
+            assert!(matches!(
+                PageNo::new(page),
+                Err(AllocError::ReservedPageBits { .. })
+            ));
+        }
+    }
+
+    #[cfg(feature = "read-stats")]
+    #[test]
+    fn read_stats() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let mut txn = read.reader();
+        assert_eq!(txn.read_stats(), &ReadStats::default());
+        assert_eq!(txn.read_stats().resident_fraction(), None);
+        for i in 0..200 {
+            let page = ROOT_MAP_SIZE + (i % 16) * PAGE_SIZE;
+            unsafe { txn.read(BlockRange::new(page, PAGE_SIZE)).unwrap() };
+        }
+        let stats = txn.read_stats();
+        assert_eq!(stats.reads, 200);
+        assert_eq!(stats.bytes, 200 * PAGE_SIZE as u64);
+        assert!(stats.resident_pages <= stats.sampled_pages);
+        if cfg!(target_os = "linux") {
+            assert!(stats.sampled_pages > 0);
+            let fraction = stats.resident_fraction().unwrap();
+            assert!((0.0..=1.0).contains(&fraction));
+        }
+    }
+
+    #[test]
+    fn reader_generation() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let old = read.reader();
+
+        // A writer publishing a new root moves new readers to the next generation, while the old
+        // reader stays on the snapshot it checked out.
+        let update = RootCheckout {
+            id: old.generation() + 1,
+            root: Arc::default(),
+            freelist: ByteOffset::default(),
+            file_len: MIN_DB_SIZE as u64,
+        };
+        core.root.lock().unwrap().update(&update, RunSet::new());
+        let new = read.reader();
+        assert_eq!(new.generation(), old.generation() + 1);
+    }
+
+    #[test]
+    fn root_data_shared() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let old = read.reader();
+
+        // Readers share the published root data instead of each getting a copy
+        let payload: Arc<[u8]> = Arc::from(&b"some tree roots"[..]);
+        core.root.lock().unwrap().update(
+            &RootCheckout {
+                id: old.generation() + 1,
+                root: payload.clone(),
+                freelist: ByteOffset::default(),
+                file_len: MIN_DB_SIZE as u64,
+            },
+            RunSet::new(),
+        );
+        let new = read.reader();
+        let newer = read.reader();
+        assert!(Arc::ptr_eq(&new.root.root, &payload));
+        assert!(Arc::ptr_eq(&newer.root.root, &payload));
+        assert!(old.root.root.is_empty());
+
+        // And what gets committed is exactly what was published
+        let mut stored = Vec::new();
+        core.root.lock().unwrap().store(&mut stored).unwrap();
+        let loaded = RootData::load(&stored).unwrap();
+        assert_eq!(&loaded.root[..], &payload[..]);
+        assert_eq!(loaded.id_tracker.newest_id(), new.generation());
+    }
+
+    #[test]
+    fn root_data_access() {
+        let (read, write, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let (unit, _) = write.write().commit(b"first");
+        commit.commit().unwrap();
+        let txn = read.reader();
+        assert_eq!(txn.root_data(), b"first");
+        assert_eq!(txn.id(), txn.generation());
+
+        // Root data starts out as the last transaction's, and too much of it fails right away
+        let mut write = unit.write();
+        assert_eq!(write.root_data(), b"first");
+        let max = ROOT_SIZE - format::ROOT_HEADER_SIZE - format::ROOT_HASH_SIZE;
+        assert!(matches!(
+            write.set_root_data(&vec![0; max + 1]),
+            Err(AllocError::RootTooLarge { len, max }) if len == ROOT_SIZE + 1 && max == ROOT_SIZE
+        ));
+        assert_eq!(write.root_data(), b"first");
+        write.set_root_data(&vec![1; max]).unwrap();
+        write.set_root_data(b"second").unwrap();
+        assert_eq!(write.root_data(), b"second");
+        let (unit, _) = write.commit_root_data();
+        commit.commit().unwrap();
+        let txn = read.reader();
+        assert_eq!(txn.root_data(), b"second");
+        assert_eq!(txn.id(), 2);
+
+        // Aborting drops whatever was set, and committing without setting any keeps it the same
+        let mut write = unit.write();
+        write.set_root_data(b"third").unwrap();
+        let Ok((unit, _)) = write.abort() else {
+            panic!("abort failed");
+        };
+        let write = unit.write();
+        assert_eq!(write.root_data(), b"second");
+        let (_unit, _) = write.commit_root_data();
+        assert_eq!(read.reader().root_data(), b"second");
+        assert_eq!(read.reader().id(), 3);
+    }
+
+    /// Spawns readers from several threads while another keeps committing a root payload as big
+    /// as a root page holds. Every reader should see all of it, and only ever the one committed
+    /// with its ID.
+    #[test]
+    #[cfg(not(feature = "single-threaded"))]
+    fn reader_spawn_consistent_root() {
+        use std::sync::mpsc;
+
+        const READERS: usize = 4;
+        const UPDATES: u64 = 500;
+        let max = ROOT_SIZE - format::ROOT_HEADER_SIZE - format::ROOT_HASH_SIZE;
+        let (read, unit, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let base = unit.generation();
+        // The readers stop once the writer hangs up, which it also does by panicking
+        let (hang_ups, stops): (Vec<_>, Vec<_>) =
+            (0..READERS).map(|_| mpsc::channel::<()>()).unzip();
+
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                let _hang_ups = hang_ups;
+                let mut unit = unit;
+                for update in 1..=UPDATES {
+                    let root: Vec<u8> = update.to_le_bytes().repeat(max / 8);
+                    unit = unit.write().commit(&root).0;
+                    commit.commit().unwrap();
+                }
+            });
+            for stop in stops {
+                let read = &read;
+                s.spawn(move || {
+                    let mut last = 0;
+                    while stop.try_recv() != Err(mpsc::TryRecvError::Disconnected) {
+                        let txn = read.reader();
+                        assert!(txn.id() >= last, "reader went back to {}", txn.id());
+                        last = txn.id();
+                        let root = txn.root_data();
+                        if last == base {
+                            continue;
+                        }
+                        assert_eq!(root.len(), max / 8 * 8);
+                        let (update, rest) = root.split_at(8);
+                        assert_eq!(u64::from_le_bytes(update.try_into().unwrap()), last - base);
+                        assert!(rest.chunks(8).all(|chunk| chunk == update));
+                    }
+                });
+            }
+        });
+        assert_eq!(read.reader().id(), base + UPDATES);
+    }
+
+    #[test]
+    fn write_at() {
+        let mut write = WriteTxn(test_writer(&OpenOptions::default()));
+        let page = ROOT_MAP_SIZE as u64;
+        let p = PAGE_SIZE as u64;
+        write.0.mark_dirty(page);
+        write.0.mark_dirty(page + p);
+        write.0.mark_dirty(page + 3 * p);
+
+        // In-bounds, and across a page boundary within the same run
+        write.write_at(at(page), 16, b"hello").unwrap();
+        assert_eq!(write.read_back(at(page), 16, 5).unwrap(), b"hello");
+        write.write_at(at(page), PAGE_SIZE - 3, b"seam!").unwrap();
+        assert_eq!(write.read_back(at(page + p), 0, 2).unwrap(), b"m!");
+        assert_eq!(
+            write.read_back(at(page), PAGE_SIZE - 3, 5).unwrap(),
+            b"seam!"
+        );
+        write
+            .write_at(at(page + 3 * p), 0, &[7; PAGE_SIZE])
+            .unwrap();
+
+        // Pages that aren't owned by this transaction are rejected
+        let not_owned = |res: Result<(), AllocError>| matches!(res, Err(AllocError::NotOwned { .. }));
+        assert!(not_owned(write.write_at(at(page + 2 * p), 0, b"x")));
+        assert!(not_owned(write.write_at(
+            at(page),
+            2 * PAGE_SIZE - 1,
+            b"xx"
+        )));
+        assert!(not_owned(write.write_at(
+            at(page + 3 * p),
+            1,
+            &[0; PAGE_SIZE]
+        )));
+        assert!(not_owned(write.write_at(at(page), usize::MAX, b"x")));
+        assert!(matches!(
+            write.read_back(at(page + 2 * p), 0, 1),
+            Err(AllocError::NotOwned { .. })
+        ));
+        assert!(matches!(
+            write.write_at(at(page + 1), 0, b"x"),
+            Err(AllocError::Misaligned { .. })
+        ));
+        assert!(matches!(
+            write.write_at(at(0), 0, b"root"),
+            Err(AllocError::RootAccess { offset: 0 })
+        ));
+        assert!(matches!(
+            write.read_back(at(ROOT_SIZE as u64), 0, 1),
+            Err(AllocError::RootAccess { .. })
+        ));
+        assert_eq!(
+            write.read_back(at(page + 3 * p), PAGE_SIZE - 1, 1).unwrap(),
+            &[7]
+        );
+    }
+
+    #[test]
+    fn foreign_allocation() {
+        let mut write_a = WriteTxn(test_writer(&OpenOptions::default()));
+        let mut write_b = WriteTxn(test_writer(&OpenOptions::default()));
+        let alloc_for = |write: &WriteTxn| {
+            let mem = unsafe {
+                write
+                    .0
+                    .storage
+                    .get_mut_slice(BlockRange::new(ROOT_MAP_SIZE, PAGE_SIZE))
+                    .unwrap()
+                    .unwrap()
+            };
+            WriteAlloc {
+                mem,
+                page: ROOT_MAP_SIZE as u64,
+                written: 0,
+                chan: write.0.alloc_send.clone(),
+                core: write.0.core.clone(),
+            }
+        };
+
+        // Cross-feeding allocations between databases is rejected
+        let from_b = alloc_for(&write_b);
+        assert!(matches!(
+            write_a.use_allocation(from_b),
+            Err(AllocError::ForeignAllocation)
+        ));
+        assert!(write_a.0.alloc_completions.is_empty());
+
+        // Returning them to their own database works
+        write_a.use_allocation(alloc_for(&write_a)).unwrap();
+        write_b.use_allocation(alloc_for(&write_b)).unwrap();
+        assert_eq!(write_a.0.alloc_completions.len(), 1);
+        assert_eq!(write_b.0.alloc_completions.len(), 1);
+    }
+
+    /// A source of predictable bytes that hands them out a few odd-sized pieces at a time.
+    struct Trickle {
+        pos: usize,
+        len: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.len - self.pos).min(12_345);
+            for (i, b) in buf[..len].iter_mut().enumerate() {
+                *b = trickle_byte(self.pos + i);
+            }
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    fn trickle_byte(pos: usize) -> u8 {
+        (pos % 251) as u8
+    }
+
+    #[test]
+    fn alloc_fill_and_write() {
+        let mut write = WriteTxn(test_writer(&OpenOptions::default()));
+        let len = 2 * BLOCK_SIZE;
+        let alloc_at = |write: &WriteTxn, page: usize| {
+            let mem = unsafe {
+                write
+                    .0
+                    .storage
+                    .get_mut_slice(BlockRange::new(page, len))
+                    .unwrap()
+                    .unwrap()
+            };
+            mem.fill(0xa5);
+            WriteAlloc {
+                mem,
+                page: page as u64,
+                written: 0,
+                chan: write.0.alloc_send.clone(),
+                core: write.0.core.clone(),
+            }
+        };
+
+        // A source longer than the allocation fills it right up, and no further
+        let mut alloc = alloc_at(&write, BLOCK_SIZE);
+        let mut src = Trickle {
+            pos: 0,
+            len: 3 * BLOCK_SIZE,
+        };
+        assert_eq!(alloc.fill_from(&mut src).unwrap(), len);
+        assert_eq!(alloc.fill_from(&mut src).unwrap(), 0);
+        assert_eq!(alloc.written(), len);
+        assert!(alloc.iter().enumerate().all(|(i, b)| *b == trickle_byte(i)));
+        assert_eq!(
+            alloc.writer().write(&[1]).unwrap_err().kind(),
+            io::ErrorKind::WriteZero
+        );
+
+        // A shorter one stops where it runs out, and the writer carries on from there
+        drop(alloc);
+        let mut alloc = alloc_at(&write, BLOCK_SIZE);
+        let short = BLOCK_SIZE + 100;
+        let mut src = Trickle { pos: 0, len: short };
+        assert_eq!(alloc.fill_from(&mut src).unwrap(), short);
+        alloc.writer().write_all(b"tail").unwrap();
+        assert_eq!(alloc.written(), short + 4);
+        assert_eq!(&alloc[short..short + 4], b"tail");
+        let err = alloc.writer().write_all(&vec![0; len]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(alloc.written(), len);
+
+        // Only what was written survives going back into the transaction
+        alloc.set_written(short);
+        let mem = alloc.as_ptr();
+        write.use_allocation(alloc).unwrap();
+        let (kept, cleared) = unsafe { std::slice::from_raw_parts(mem, len) }.split_at(short);
+        assert!(kept.iter().enumerate().all(|(i, b)| *b == trickle_byte(i)));
+        assert!(cleared.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn queued_pages_round_trip() {
+        // Write allocations and punched-out blocks both make their way back to the writer through
+        // page queues, whether those are channels or the `single-threaded` plain queues.
+        let core = test_core();
+        let storage = test_storage(&core);
+        let (hole_punch_req, commit_req) = page_queue();
+        let (commit_resp, hole_punch_resp) = page_queue();
+        let (commit_durable, durable) = queue();
+        let root = core.root.lock().unwrap().checkout();
+        let options = OpenOptions::default();
+        let mut write = WriteUnitInner::new(
+            core.clone(),
+            storage.clone(),
+            root,
+            hole_punch_req,
+            hole_punch_resp,
+            durable,
+            &options,
+        )
+        .unwrap();
+        let mut commit = CommitUnit {
+            id: core.root.lock().unwrap().id_tracker.checkout(),
+            durable_len: MIN_DB_SIZE as u64,
+            commit_data: Vec::new(),
+            hole_punch_req: commit_req,
+            hole_punch_resp: commit_resp,
+            durable: commit_durable,
+            root0: unsafe { storage.get_mut_slice(BlockRange::new(0, ROOT_SIZE)) }
+                .unwrap()
+                .unwrap(),
+            root1: unsafe { storage.get_mut_slice(BlockRange::new(ROOT_SIZE, ROOT_SIZE)) }
+                .unwrap()
+                .unwrap(),
+            write_root0: true,
+            sync_mode: SyncMode::Full,
+            flush_all: true,
+            core: core.clone(),
+            #[cfg(feature = "async")]
+            notify: Arc::new(CommitNotify::new(0)),
+        };
+
+        // A dropped write allocation frees its page up by the next transaction
+        let page = BLOCK_SIZE as u64;
+        let alloc = WriteAlloc {
+            mem: unsafe { storage.get_mut_slice(BlockRange::new(BLOCK_SIZE, PAGE_SIZE)) }
+                .unwrap()
+                .unwrap(),
+            page,
+            written: 0,
+            chan: write.alloc_send.clone(),
+            core: core.clone(),
+        };
+        write.taken.insert(page);
+        drop(alloc);
+        let write = WriteUnit(write).write();
+        assert!(!write.0.taken.contains(&page));
+
+        // A block queued for hole punching stays taken until the committer has punched it out
+        let mut write = write.0;
+        let block = 2 * BLOCK_SIZE as u64;
+        write.taken.insert(block);
+        write.hole_punch_req.send(block);
+        let write = WriteUnit(write).write();
+        assert!(write.0.taken.contains(&block));
+        commit.commit().unwrap();
+        let write = WriteUnit(write.0).write();
+        assert!(!write.0.taken.contains(&block));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn freed_block_hole_punch() {
+        let path = TempPath::new("punch");
+        let (read, unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+
+        // Fill a whole block and commit it
+        let mut write = unit.write();
+        write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        let (unit, mut allocs) = write.commit(b"");
+        let mut alloc = allocs.pop().unwrap();
+        let block = alloc.alloc().page.get();
+        assert!(block.is_multiple_of(BLOCK_SIZE as u64));
+        alloc.writer().write_all(&[0xab; BLOCK_SIZE]).unwrap();
+        let mut write = unit.write();
+        write.use_allocation(alloc).unwrap();
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let on_disk = || {
+            let contents = std::fs::read(&path).unwrap();
+            contents[block as usize..][..BLOCK_SIZE].to_vec()
+        };
+        assert!(on_disk().iter().all(|b| *b == 0xab));
+
+        // Free it. Once that's durable, the writer queues it up for the committer to punch out.
+        let mut write = unit.write();
+        write.txn_free(block, BLOCK_SIZE).unwrap();
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let write = unit.write();
+        assert!(write.0.punching.contains(&block));
+        assert!(!write.0.available_blocks.contains(&block));
+
+        // The request goes out with the next commit, and the block comes back on the write after
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let write = unit.write();
+        assert!(!write.0.punching.contains(&block));
+        assert!(!write.0.taken.contains(&block));
+        assert!(write.0.available_blocks.contains(&block));
+        assert!(on_disk().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn commit_root_page() {
+        let core = test_core();
+        let storage = test_storage(&core);
+        let (_, hole_punch_req) = page_queue();
+        let (hole_punch_resp, _) = page_queue();
+        let (durable, _) = queue();
+        let mut commit = CommitUnit {
+            id: core.root.lock().unwrap().id_tracker.checkout(),
+            durable_len: MIN_DB_SIZE as u64,
+            commit_data: Vec::new(),
+            hole_punch_req,
+            hole_punch_resp,
+            durable,
+            root0: unsafe { storage.get_mut_slice(BlockRange::new(0, ROOT_SIZE)) }
+                .unwrap()
+                .unwrap(),
+            root1: unsafe { storage.get_mut_slice(BlockRange::new(ROOT_SIZE, ROOT_SIZE)) }
+                .unwrap()
+                .unwrap(),
+            write_root0: true,
+            sync_mode: SyncMode::Full,
+            flush_all: true,
+            core: core.clone(),
+            #[cfg(feature = "async")]
+            notify: Arc::new(CommitNotify::new(0)),
+        };
+
+        // Whatever was in the root page past the new root data gets zeroed
+        core.root.lock().unwrap().id_tracker.set_newest(1);
+        commit.root0.fill(0xa5);
+        commit.commit().unwrap();
+        let len = commit.commit_data.len();
+        assert_eq!(commit.root0[..len], commit.commit_data[..]);
+        assert!(commit.root0[len..].iter().all(|b| *b == 0));
+        assert!(RootData::load(commit.root0).is_ok());
+        #[cfg(feature = "async")]
+        assert_eq!(commit.notify().last_committed(), commit.id);
+
+        // Root data too large for the page fails before checking out an ID for it
+        core.root.lock().unwrap().root = vec![0; ROOT_SIZE].into();
+        let tracker = |core: &DbCore| {
+            let root = core.root.lock().unwrap();
+            let ids = &root.id_tracker;
+            (ids.newest_id(), ids.oldest_id(), ids.tracker.clone())
+        };
+        let before = tracker(&core);
+        let id = commit.id;
+        let expected = ROOT_SIZE + format::ROOT_HEADER_SIZE + format::ROOT_HASH_SIZE;
+        assert!(matches!(
+            commit.commit(),
+            Err(AllocError::RootTooLarge { len, max }) if len == expected && max == ROOT_SIZE
+        ));
+        assert_eq!(tracker(&core), before);
+        assert_eq!(commit.id, id);
+        #[cfg(feature = "async")]
+        assert_eq!(commit.notify().last_committed(), id);
+        assert!(commit.root0[len..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn file_len_on_reopen() {
+        let path = TempPath::new("file-len");
+        let recorded = MIN_DB_SIZE as u64 + BLOCK_SIZE as u64;
+
+        // A file whose first root page records its length
+        let mut root = RootData::new(b"crab-db\0", ByteOffset::default(), recorded);
+        root.id_tracker.set_newest(3);
+        let mut bytes = Vec::new();
+        root.store(&mut bytes).unwrap();
+        std::fs::write(&path, &bytes).unwrap();
+
+        // Resize the closed file, then load the root pages back the same way opening does
+        let reopen = |len: u64| {
+            let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(len).unwrap();
+            drop(file);
+            let contents = std::fs::read(&path).unwrap();
+            let (root, report) =
+                RootData::load_newest(&contents[..ROOT_SIZE], &contents[ROOT_SIZE..ROOT_MAP_SIZE])
+                    .unwrap();
+            assert_eq!(report.root_slot(), Some(0));
+            assert_eq!(root.file_len, recorded);
+            root.unrecorded_blocks(contents.len() as u64)
+                .map(|blocks| blocks.collect::<Vec<_>>())
+        };
+        assert_eq!(reopen(recorded).unwrap(), []);
+
+        // Growth that never got committed is free space
+        assert_eq!(
+            reopen(recorded + 2 * BLOCK_SIZE as u64).unwrap(),
+            [recorded, recorded + BLOCK_SIZE as u64]
+        );
+
+        // Losing the end of the file is an error
+        let actual = recorded - BLOCK_SIZE as u64;
+        assert!(matches!(
+            reopen(actual),
+            Err(AllocError::DataFormat(FormatError::Truncated { recorded: r, actual: a }))
+                if (r, a) == (recorded, actual)
+        ));
+    }
+
+    #[test]
+    fn damaged_root_recovery() {
+        let path = TempPath::new("damaged");
+        let mut root = RootData::new(b"crab-db\0", ByteOffset::default(), MIN_DB_SIZE as u64);
+        root.id_tracker.set_newest(3);
+        let mut contents = Vec::new();
+        root.store(&mut contents).unwrap();
+        contents.resize(ROOT_SIZE, 0);
+        // A torn write of the second root page
+        contents.resize(ROOT_SIZE + 100, 0x5a);
+        contents.resize(MIN_DB_SIZE, 0);
+        std::fs::write(&path, &contents).unwrap();
+
+        // Opening falls back on the first root page, and says so
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let report = read.open_report();
+        assert_eq!(report.root_slot(), Some(0));
+        assert!(report.recovered());
+        assert!(matches!(report.damaged_root(), Some(AllocError::Open(_))));
+
+        // Committing with nothing new leaves the root pages alone
+        commit.commit().unwrap();
+        assert!(RootData::load(commit.root1).is_err());
+
+        // The first real commit replaces the damaged page, and the next goes back to the first
+        let (write, _) = write.write().commit(b"fixed");
+        commit.commit().unwrap();
+        let newest = |root: &[u8]| RootData::load(root).unwrap().id_tracker.newest_id();
+        assert_eq!(newest(commit.root1), 4);
+        assert_eq!(&RootData::load(commit.root1).unwrap().root[..], b"fixed");
+        assert_eq!(newest(commit.root0), 3);
+        let (write, _) = write.write().commit(b"again");
+        commit.commit().unwrap();
+        assert_eq!(newest(commit.root0), 5);
+        drop((read, write, commit));
+
+        // Reopening finds both root pages intact
+        let (read, _write, _commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(read.open_report().root_slot(), Some(0));
+        assert!(!read.open_report().recovered());
+        assert_eq!(&read.reader().root.root[..], b"again");
+    }
+
+    #[test]
+    fn root_geometry() {
+        let path = TempPath::new("geometry");
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let (unit, _) = write.write().commit(b"older");
+        commit.commit().unwrap();
+        let (unit, _) = unit.write().commit(b"sized");
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+        let original = std::fs::read(&path).unwrap();
+
+        // Rewrite the geometry record in both root pages, keeping their hashes valid
+        let forge = |geometry: [u8; 4]| {
+            let mut contents = original.clone();
+            for slot in [format::ROOT_SLOT_0, format::ROOT_SLOT_1] {
+                let mut bytes = Vec::new();
+                RootData::load(&contents[slot..slot + ROOT_SIZE])
+                    .unwrap()
+                    .store(&mut bytes)
+                    .unwrap();
+                bytes[format::ROOT_GEOMETRY..][..4].copy_from_slice(&geometry);
+                let hashed = bytes.len() - format::ROOT_HASH_SIZE;
+                let hash = xxhash_rust::xxh3::xxh3_64(&bytes[..hashed]);
+                bytes[hashed..].copy_from_slice(&hash.to_le_bytes());
+                contents[slot..][..bytes.len()].copy_from_slice(&bytes);
+            }
+            std::fs::write(&path, &contents).unwrap();
+            OpenOptions::default().open(&path)
+        };
+
+        // Each size that doesn't match is called out by name
+        let cases = [
+            ([21, 14, 14, 12], "block size", 1 << 21, BLOCK_SIZE),
+            ([20, 13, 14, 12], "cluster size", 1 << 13, CLUSTER_SIZE),
+            ([20, 14, 15, 12], "root size", 1 << 15, ROOT_SIZE),
+            ([20, 14, 14, 14], "page size", 1 << 14, PAGE_SIZE),
+        ];
+        for (geometry, name, size, expected) in cases {
+            assert!(matches!(
+                forge(geometry),
+                Err(AllocError::DataFormat(FormatError::Geometry { field, stored, compiled }))
+                    if field == name && stored == size && compiled == expected as u64
+            ));
+        }
+
+        // Files from before the record existed have it zeroed, and still open
+        let (read, _write, _commit) = forge([0; 4]).unwrap();
+        assert!(!read.open_report().recovered());
+        assert_eq!(&read.reader().root.root[..], b"sized");
+    }
+
+    #[test]
+    fn corrupt_freelist_fallback() {
+        let path = TempPath::new("fallback");
+
+        // Two commits, one in each root page
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let (unit, _) = write.write().commit(b"older");
+        commit.commit().unwrap();
+        let (unit, _) = unit.write().commit(b"newer");
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+
+        // Point the newer one at a freelist page that never got written
+        let mut contents = std::fs::read(&path).unwrap();
+        let (mut root, report) =
+            RootData::load_newest(&contents[..ROOT_SIZE], &contents[ROOT_SIZE..ROOT_MAP_SIZE])
+                .unwrap();
+        assert_eq!(&root.root[..], b"newer");
+        let slot = report.root_slot().unwrap();
+        root.freelist = at(BLOCK_SIZE as u64);
+        let mut bytes = Vec::new();
+        root.store(&mut bytes).unwrap();
+        contents[slot * ROOT_SIZE..][..bytes.len()].copy_from_slice(&bytes);
+        std::fs::write(&path, &contents).unwrap();
+
+        // Opening falls back on the older root page, and says why
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let report = read.open_report();
+        assert_eq!(report.root_slot(), Some(1 - slot));
+        assert!(matches!(
+            report.damaged_root(),
+            Some(AllocError::DataFormat(FormatError::FreelistHash { page }))
+                if *page == BLOCK_SIZE as u64
+        ));
+        assert_eq!(&read.reader().root.root[..], b"older");
+
+        // The next commit replaces the newer root page
+        let (unit, _) = write.write().commit(b"replaced");
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+        let (read, _write, _commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(read.open_report().root_slot(), Some(slot));
+        assert!(!read.open_report().recovered());
+        assert_eq!(&read.reader().root.root[..], b"replaced");
+    }
+
+    #[test]
+    fn crash_recovery() {
+        const CYCLES: usize = 64;
+        let path = TempPath::new("crash");
+
+        // Each transaction writes a pattern unique to it, and records where in the root. They
+        // alternate between two pages, so nothing overwrites what the last durable one wrote.
+        let page = |seq: u64| BLOCK_SIZE as u64 + (seq % 2) * PAGE_SIZE as u64;
+        let pattern = |seq: u64| {
+            let x = seq.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            [x.to_le_bytes(), (!x).to_le_bytes()].concat()
+        };
+        let txn = |unit: WriteUnit, seq: u64| {
+            let mut write = unit.write();
+            write.0.mark_dirty(page(seq));
+            write.write_at(at(page(seq)), 0, &pattern(seq)).unwrap();
+            write.commit(&seq.to_le_bytes()).0
+        };
+        let mut rng = 0x5eed_u64;
+        let mut next = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng
+        };
+
+        // Start from a database whose first transaction is on disk in full
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let unit = txn(write, 0);
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+
+        let (mut durable, mut seq) = (0, 0);
+        let (mut torn, mut lost) = (0, 0);
+        for _ in 0..CYCLES {
+            // Whatever comes back must be the last durable transaction or the one in flight, in
+            // full
+            let (read, mut unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+            let mut reader = read.reader();
+            let recovered = u64::from_le_bytes(reader.root.root[..].try_into().unwrap());
+            assert!(
+                recovered == durable || recovered == seq,
+                "recovered {recovered}, but {durable} was durable and {seq} in flight"
+            );
+            let data = reader.read_pages(page(recovered), 1).unwrap();
+            assert_eq!(data[..16], pattern(recovered));
+            torn += read.open_report().recovered() as usize;
+            lost += (recovered != seq) as usize;
+            drop(reader);
+
+            // Run transactions until the power goes out partway through one of them
+            let budget = 1 + next() as usize % 128;
+            commit.core.storage.lock().unwrap().cut_power_after(budget);
+            seq = recovered;
+            durable = recovered;
+            loop {
+                seq += 1;
+                unit = txn(unit, seq);
+                commit.commit().unwrap();
+                if !commit.core.storage.lock().unwrap().powered() {
+                    break;
+                }
+                durable = seq;
+            }
+
+            // Only what made it to the simulated disk survives
+            let disk = commit.core.storage.lock().unwrap().take_disk().unwrap();
+            drop((read, unit, commit));
+            std::fs::write(&path, disk).unwrap();
+        }
+
+        // Make sure the power went out at all the interesting points along the way
+        assert!(torn > 0);
+        assert!(lost > torn);
+    }
+
+    #[test]
+    fn open_size() {
+        let sized = |size| {
+            let mut options = OpenOptions::default();
+            options.size(size);
+            options
+        };
+        let mib = BLOCK_SIZE;
+
+        // Without a size, it's the minimum, or whatever the file already is
+        let options = OpenOptions::default();
+        assert_eq!(options.target_size(0).unwrap(), MIN_DB_SIZE);
+        assert_eq!(options.target_size(8 * mib).unwrap(), 8 * mib);
+
+        // Whole blocks at or above the minimum are used as-is, unless the file is already larger
+        assert_eq!(sized(5 * mib).target_size(0).unwrap(), 5 * mib);
+        assert_eq!(sized(5 * mib).target_size(4 * mib).unwrap(), 5 * mib);
+        assert_eq!(sized(5 * mib).target_size(8 * mib).unwrap(), 8 * mib);
+
+        // Anything else is rejected rather than rounded, even if the file would've covered it
+        for size in [0, mib, MIN_DB_SIZE - mib, 5 * mib + 1, 5 * mib + mib / 2] {
+            for file_size in [0, 8 * mib] {
+                assert!(matches!(
+                    sized(size).target_size(file_size),
+                    Err(AllocError::InvalidSize { size: s }) if s == size
+                ));
+            }
+        }
+
+        // And opening fails before the file ever gets created
+        let path = TempPath::new("open-size");
+        assert!(matches!(
+            sized(5 * mib + 1).open(&path),
+            Err(AllocError::InvalidSize { .. })
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn open_anon() {
+        let (read, write, mut commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let write = write.write();
+
+        // Everything past the roots and the freelist's first page starts out free, in the
+        // largest aligned pieces that fit. The pages sharing the freelist's cluster end up as a
+        // partly taken cluster.
+        let freelist = ROOT_MAP_SIZE as u64;
+        assert_eq!(write.0.root.freelist.get(), freelist);
+        assert!(write.0.available_4k.is_empty());
+        let first_cluster = ROOT_MAP_SIZE + CLUSTER_SIZE;
+        let mut clusters: Vec<u64> = (first_cluster..BLOCK_SIZE)
+            .step_by(CLUSTER_SIZE)
+            .map(|p| p as u64)
+            .collect();
+        clusters.push(freelist | 0b0001);
+        assert_eq!(write.0.available_16k, clusters);
+        let blocks: Vec<u64> = (1..5).map(|i| (i * BLOCK_SIZE) as u64).collect();
+        assert_eq!(write.0.available_blocks, blocks);
+
+        // Commits go through and advance the transaction ID, with nothing to flush
+        let (unit, _) = write.commit(b"hello");
+        assert_eq!(&read.reader().root.root[..], b"hello");
+        let id = commit.id;
+        commit.commit().unwrap();
+        assert!(commit.id > id);
+
+        // The free pages carry over into the next transaction
+        let write = unit.write();
+        assert_eq!(write.0.available_blocks, blocks);
+
+        assert!(matches!(
+            alloc_anon(BLOCK_SIZE),
+            Err(AllocError::InvalidSize { .. })
+        ));
+    }
+
+    #[test]
+    fn read_pages() {
+        let (read, write, _commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let mut write = write.write();
+        let page = (ROOT_MAP_SIZE + CLUSTER_SIZE) as u64;
+        write.0.mark_dirty(page);
+        write.0.mark_dirty(page + PAGE_SIZE as u64);
+        write
+            .write_at(at(page), PAGE_SIZE - 5, b"committed")
+            .unwrap();
+        let (_unit, _) = write.commit(b"");
+
+        // Committed data reads back, across page boundaries
+        let mut txn = read.reader();
+        let mem = txn.read_pages(page, 2).unwrap();
+        assert_eq!(mem.len(), 2 * PAGE_SIZE);
+        assert_eq!(&mem[PAGE_SIZE - 5..PAGE_SIZE + 4], b"committed");
+
+        // Right up to the end of the file is fine, but not a page further
+        let end = (5 * BLOCK_SIZE) as u64;
+        let last = end - PAGE_SIZE as u64;
+        assert_eq!(txn.read_pages(last, 1).unwrap().len(), PAGE_SIZE);
+        let past_end =
+            |res: Result<&[u8], AllocError>| matches!(res, Err(AllocError::InvalidAccess { .. }));
+        assert!(past_end(txn.read_pages(end, 1)));
+        assert!(past_end(txn.read_pages(last, 2)));
+        assert!(past_end(txn.read_pages(page, 1 << 30)));
+        assert!(matches!(
+            txn.read_pages(page, usize::MAX),
+            Err(AllocError::RangeOverflow { .. })
+        ));
+
+        // The root pages and unaligned pages are never readable
+        assert!(matches!(
+            txn.read_pages(ROOT_SIZE as u64, 1),
+            Err(AllocError::RootAccess { .. })
+        ));
+        assert!(matches!(
+            txn.read_pages(page + 8, 1),
+            Err(AllocError::Misaligned { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "single-threaded"))]
+    fn write_alloc_threads() {
+        let (read, write, _commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let mut write = write.write();
+        let p = PAGE_SIZE as u64;
+        let lens = [1, p, 3 * p - 100, CLUSTER_SIZE as u64, 100_000];
+        for len in lens {
+            write.new_allocation(len).unwrap();
+        }
+        let (unit, mut allocs) = write.commit(b"");
+        assert_eq!(allocs.len(), lens.len());
+
+        // Fill each allocation from its own thread, leaving the back half of every other one
+        // unwritten
+        std::thread::scope(|s| {
+            for (i, alloc) in allocs.iter_mut().enumerate() {
+                s.spawn(move || {
+                    let len = alloc.len() >> (i % 2);
+                    alloc.writer().write_all(&vec![i as u8 + 1; len]).unwrap();
+                });
+            }
+        });
+
+        let mut write = unit.write();
+        let mut placed: Vec<(Alloc, usize)> =
+            allocs.iter().map(|a| (a.alloc(), a.written())).collect();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (_unit, _) = write.commit(b"");
+
+        // Everything written reads back, the rest is zeroed, and no two allocations overlap
+        let mut txn = read.reader();
+        for (i, (alloc, written)) in placed.iter().enumerate() {
+            assert_eq!(alloc.len as u64, lens[i].next_multiple_of(p));
+            let mem = txn
+                .read_pages(alloc.page.get(), alloc.len / PAGE_SIZE)
+                .unwrap();
+            assert!(mem[..*written].iter().all(|b| *b == i as u8 + 1));
+            assert!(mem[*written..].iter().all(|b| *b == 0));
+        }
+        placed.sort_by_key(|(alloc, _)| alloc.page);
+        for pair in placed.windows(2) {
+            assert!(pair[0].0.page.get() + pair[0].0.len as u64 <= pair[1].0.page.get());
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "single-threaded"))]
+    fn straggler_alloc_policies() {
+        use std::{sync::mpsc, thread};
+
+        let (read, write, _commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        // Hand out an allocation to a thread that fills and finishes it a little while after
+        // being told to go
+        let straggler = |mut write: WriteTxn, fill: u8| {
+            write.new_allocation(PAGE_SIZE as u64).unwrap();
+            let (unit, mut allocs) = write.commit(b"");
+            let mut alloc = allocs.pop().unwrap();
+            let placed = alloc.alloc();
+            let (go, wait) = mpsc::channel();
+            let thread = thread::spawn(move || {
+                wait.recv().unwrap();
+                thread::sleep(Duration::from_millis(10));
+                alloc.writer().write_all(&[fill; 64]).unwrap();
+                alloc.finish();
+            });
+            (unit.write(), placed, go, thread)
+        };
+        let committed = |alloc: Alloc, fill: u8| {
+            let mut txn = read.reader();
+            let mem = txn.read_pages(alloc.page.get(), 1).unwrap();
+            mem[..64].iter().all(|b| *b == fill) && mem[64..].iter().all(|b| *b == 0)
+        };
+
+        // Failing leaves the transaction as it was
+        let (write, first, go, thread) = straggler(write.write(), 1);
+        let Err((write, err)) = write.commit_with(b"", CommitPolicy::Fail) else {
+            panic!("committed with an allocation outstanding");
+        };
+        assert!(matches!(err, AllocError::OutstandingAllocations { count: 1 }));
+
+        // Committing without it says it's missing, and it goes into the next transaction instead
+        let Ok((unit, _, left_out)) = write.commit_with(b"", CommitPolicy::CommitWithout) else {
+            panic!("commit failed");
+        };
+        assert_eq!(left_out, 1);
+        assert!(!committed(first, 1));
+        go.send(()).unwrap();
+        thread.join().unwrap();
+        let (write, second, go, thread) = straggler(unit.write(), 2);
+        assert!(committed(first, 1));
+
+        // Waiting picks it up as soon as it's finished, and puts it in the same transaction
+        go.send(()).unwrap();
+        let wait = CommitPolicy::WaitForAll(Duration::from_secs(60));
+        let Ok((unit, _, left_out)) = write.commit_with(b"", wait) else {
+            panic!("commit failed");
+        };
+        assert_eq!(left_out, 0);
+        assert!(committed(second, 2));
+        thread.join().unwrap();
+
+        // Unless it takes too long
+        let (write, third, go, thread) = straggler(unit.write(), 3);
+        let wait = CommitPolicy::WaitForAll(Duration::from_millis(1));
+        let Err((mut write, err)) = write.commit_with(b"", wait) else {
+            panic!("committed with an allocation outstanding");
+        };
+        assert!(matches!(err, AllocError::OutstandingAllocations { count: 1 }));
+        go.send(()).unwrap();
+        thread.join().unwrap();
+        assert_eq!(write.outstanding_allocations(), 0);
+        write.commit(b"");
+        assert!(committed(third, 3));
+    }
+
+    #[test]
+    fn dropped_write_alloc() {
+        let (_read, write, mut commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let mut write = write.write();
+        let free = write.0.available_bytes();
+        let lens = [CLUSTER_SIZE as u64, BLOCK_SIZE as u64];
+        for len in lens {
+            write.new_allocation(len).unwrap();
+        }
+        assert_eq!(write.0.available_bytes(), free - lens.iter().sum::<u64>());
+
+        // Dropped allocations come back on the next transaction
+        let (unit, allocs) = write.commit(b"");
+        drop(allocs);
+        let mut write = unit.write();
+        assert_eq!(write.0.available_bytes(), free);
+        assert!(write.0.alloc_lens.is_empty());
+
+        // Used ones don't, even once their handles are gone
+        write.new_allocation(PAGE_SIZE as u64).unwrap();
+        let (unit, allocs) = write.commit(b"");
+        let mut write = unit.write();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (unit, _) = write.commit(b"");
+        // The freelist gets rewritten, and its old page comes back once the committer moves on
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        assert_eq!(write.0.available_bytes(), free - PAGE_SIZE as u64);
+
+        // And neither can go past the block size
+        assert!(matches!(
+            write.new_allocation(BLOCK_SIZE as u64 + 1),
+            Err(AllocError::NoSpace { .. })
+        ));
+    }
+
+    #[test]
+    fn txn_free() {
+        let (read, write, mut commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let mut write = write.write();
+        let free = write.0.available_bytes();
+        let (p, b) = (PAGE_SIZE as u64, BLOCK_SIZE as u64);
+        write.new_allocation(p).unwrap();
+        write.new_allocation(b).unwrap();
+        let (unit, allocs) = write.commit(b"");
+        let mut write = unit.write();
+        let placed: Vec<u64> = allocs.iter().map(|a| a.alloc().page.get()).collect();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let (page, block) = (placed[0], placed[1]);
+
+        // Aborting a transaction keeps its frees from ever happening
+        let mut write = unit.write();
+        write.txn_free(page, PAGE_SIZE).unwrap();
+        let Ok((unit, _)) = write.abort() else {
+            panic!("abort failed");
+        };
+
+        // Freeing anything twice, or anything that was never allocated, fails
+        let mut write = unit.write();
+        assert!(write.0.pending_free.is_empty());
+        write.txn_free(page, PAGE_SIZE).unwrap();
+        write.txn_free(block, BLOCK_SIZE).unwrap();
+        let double_free =
+            |res: Result<(), AllocError>| matches!(res, Err(AllocError::DoubleFree { .. }));
+        assert!(double_free(write.txn_free(page, 1)));
+        assert!(double_free(write.txn_free(block + 8 * p, PAGE_SIZE)));
+        let never_used = write.0.available_blocks[0];
+        assert!(double_free(write.txn_free(never_used, PAGE_SIZE)));
+        assert!(matches!(
+            write.txn_free(ROOT_SIZE as u64, PAGE_SIZE),
+            Err(AllocError::RootAccess { .. })
+        ));
+        assert!(matches!(
+            write.txn_free(5 * b - p, 2 * PAGE_SIZE),
+            Err(AllocError::InvalidAccess { .. })
+        ));
+        assert!(matches!(
+            write.txn_free(page, 0),
+            Err(AllocError::InvalidAccess { .. })
+        ));
+
+        // Nothing comes back while a reader or the committer could still see the freed pages. That
+        // includes the page of the freelist replaced by this commit, so the new one's page is
+        // missing on top.
+        let reader = read.reader();
+        let (unit, _) = write.commit(b"");
+        let write = unit.write();
+        assert_eq!(write.0.available_bytes(), free - p - b - p);
+        drop(reader);
+        let write = write.commit(b"").0.write();
+        assert_eq!(write.0.available_bytes(), free - p - b - p);
+
+        // Once they've moved on, the page is free right away, and the block once it's punched out
+        commit.commit().unwrap();
+        let write = write.commit(b"").0.write();
+        assert_eq!(write.0.available_bytes(), free - b);
+        assert_eq!(write.0.hole_punch_future_req, [block]);
+        let unit = write.commit(b"").0;
+        commit.commit().unwrap();
+        let write = unit.write();
+        assert_eq!(write.0.available_bytes(), free);
+        assert!(write.0.pending_free.is_empty() && write.0.punching.is_empty());
+    }
+
+    #[test]
+    fn txn_free_batch() {
+        let (_read, unit, mut commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let p = PAGE_SIZE as u64;
+        let mut write = unit.write();
+        let old = write.txn_allocate(8 * p).unwrap().page.get();
+        let unit = write.commit(b"").0;
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        let new = write.txn_allocate(4 * p).unwrap().page.get();
+        let free = write.0.available_bytes();
+
+        // A bad range anywhere in the batch fails it before anything is freed
+        let bad = [(old, PAGE_SIZE), (new, PAGE_SIZE), (old + p + 1, PAGE_SIZE)];
+        assert!(matches!(
+            write.txn_free_batch(bad),
+            Err(AllocError::Misaligned { .. })
+        ));
+        assert!(write.0.pending_free.is_empty());
+        assert_eq!(write.0.available_bytes(), free);
+
+        // Committed pages wait as one run per gap between them, and this transaction's own pages
+        // come straight back
+        let committed = [7, 0, 2, 1, 5, 6].map(|i| (old + i * p, PAGE_SIZE));
+        let own = (0..4).rev().map(|i| (new + i * p, PAGE_SIZE));
+        write
+            .txn_free_batch(committed.into_iter().chain(own))
+            .unwrap();
+        let pending: Vec<(u64, u64)> = write
+            .0
+            .pending_free
+            .iter()
+            .map(|(page, len, _)| (*page, *len))
+            .collect();
+        assert_eq!(pending, [(old, 3 * p), (old + 5 * p, 3 * p)]);
+        assert_eq!(write.0.available_bytes(), free + 4 * p);
+        assert!(!write.0.dirty.contains(new));
+
+        // A run overlapping anything already freed is a double free, and none of it gets freed
+        assert!(matches!(
+            write.txn_free_batch([(old + 5 * p, PAGE_SIZE), (old + 3 * p, 2 * PAGE_SIZE)]),
+            Err(AllocError::DoubleFree { .. })
+        ));
+        assert_eq!(write.0.pending_free.len(), 2);
+    }
+
+    #[test]
+    fn txn_allocate() {
+        let (_read, write, mut commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let mut write = write.write();
+        let free = write.0.available_bytes();
+
+        // Allocations come out dirty, so they can be written to straight away
+        let alloc = write.txn_allocate(PAGE_SIZE as u64 + 1).unwrap();
+        assert_eq!(alloc.len, 2 * PAGE_SIZE);
+        let page = alloc.page.get();
+        assert!(write.is_dirty(at(page)) && write.is_dirty(at(page + PAGE_SIZE as u64)));
+        write.write_at(alloc.page, PAGE_SIZE - 2, b"hey").unwrap();
+        assert!(matches!(
+            write.txn_allocate(BLOCK_SIZE as u64 + 1),
+            Err(AllocError::NoSpace { .. })
+        ));
+
+        // Freeing them in the same transaction gives them back right away
+        write.txn_free(page, alloc.len).unwrap();
+        assert!(!write.is_dirty(at(page)));
+        assert!(write.0.pending_free.is_empty());
+        assert_eq!(write.0.available_bytes(), free);
+
+        // Once committed, they're like any other allocated page
+        let alloc = write.txn_allocate(PAGE_SIZE as u64).unwrap();
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        assert!(!write.is_dirty(alloc.page));
+        write.txn_free(alloc.page.get(), alloc.len).unwrap();
+        assert_eq!(write.0.pending_free.len(), 1);
+    }
+
+    #[test]
+    fn availability_across_transactions() {
+        let (_read, write, mut commit) = alloc_anon(5 * BLOCK_SIZE).unwrap();
+        let p = PAGE_SIZE as u64;
+        let lists = |write: &WriteTxn| {
+            let w = &write.0;
+            let lists = [&w.available_4k, &w.available_16k, &w.available_blocks];
+            lists.map(|list| {
+                let mut list = list.clone();
+                list.sort();
+                list
+            })
+        };
+        let mut write = write.write();
+        write.new_allocation(p).unwrap();
+        let (unit, allocs) = write.commit(b"");
+        let mut write = unit.write();
+        let page = allocs[0].alloc().page.get();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+
+        // A freed page goes on the lists once it's safe, and stays there from then on without the
+        // freelist being read back
+        let mut write = unit.write();
+        write.txn_free(page, PAGE_SIZE).unwrap();
+        let unit = write.commit(b"").0;
+        commit.commit().unwrap();
+        let write = unit.write();
+        assert!(write.0.overlaps_available(page, p));
+        let before = lists(&write);
+        let write = write.commit(b"").0.write();
+        assert_eq!(lists(&write), before);
+
+        // Aborting puts back everything the transaction took, along with the file length
+        let mut write = write;
+        let file_len = write.0.root.file_len;
+        assert!(write.compact_tail() < file_len);
+        write.new_allocation(p).unwrap();
+        write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        assert_ne!(lists(&write), before);
+        let Ok((unit, allocs)) = write.abort() else {
+            panic!("abort failed");
+        };
+        assert_eq!(allocs.len(), 2);
+        drop(allocs);
+        let write = unit.write();
+        assert_eq!(lists(&write), before);
+        assert_eq!(write.0.root.file_len, file_len);
+        assert!(write.0.alloc_lens.is_empty());
+
+        // Allocations from earlier commits that get dropped mid-transaction stay free on abort
+        let mut write = write;
+        write.new_allocation(p).unwrap();
+        let (unit, allocs) = write.commit(b"");
+        let dropped = allocs[0].alloc().page.get();
+        let mut write = unit.write();
+        assert!(!write.0.overlaps_available(dropped, p));
+        drop(allocs);
+        assert_eq!(write.outstanding_allocations(), 0);
+        let Ok((unit, _)) = write.abort() else {
+            panic!("abort failed");
+        };
+        let write = unit.write();
+        assert!(write.0.overlaps_available(dropped, p));
+    }
+
+    #[test]
+    fn repeated_aborts_keep_space() {
+        let (_read, write, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+
+        // The very first transaction has nothing to go back to
+        let Err((write, err)) = write.write().abort() else {
+            panic!("aborted the first transaction");
+        };
+        assert_eq!(err.kind(), AllocErrorKind::AbortFirstTransaction);
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+
+        // Allocating and aborting over and over, dropping what comes back, leaks nothing
+        let Ok((mut unit, _)) = unit.write().abort() else {
+            panic!("abort failed");
+        };
+        let space = unit.stats().space;
+        assert!(space.is_some());
+        for i in 0..1000u64 {
+            let mut write = unit.write();
+            write.new_allocation(PAGE_SIZE as u64).unwrap();
+            write.new_allocation(CLUSTER_SIZE as u64 + i % 8).unwrap();
+            write.new_allocation(BLOCK_SIZE as u64).unwrap();
+            let Ok((next, allocs)) = write.abort() else {
+                panic!("abort failed");
+            };
+            assert_eq!(allocs.len(), 3);
+            drop(allocs);
+
+            // The dropped allocations are picked up by the next transaction
+            let Ok((next, _)) = next.write().abort() else {
+                panic!("abort failed");
+            };
+            assert_eq!(next.stats().space, space);
+            assert!(next.0.taken_txn.is_empty());
+            unit = next;
+        }
+    }
+
+    #[test]
+    fn size_classes() {
+        let p = PAGE_SIZE as u64;
+        let c = CLUSTER_SIZE as u64;
+        let b = BLOCK_SIZE as u64;
+        let mut write = test_writer(&OpenOptions::default());
+        write.available_blocks = vec![b, 2 * b];
+
+        // A page splits a block, leaving the rest of its first cluster partly taken
+        assert_eq!(write.take_available(1), Some(2 * b));
+        assert_eq!(write.available_blocks, [b]);
+        assert_eq!(write.available_16k.len(), BLOCK_CLUSTERS as usize);
+        assert_eq!(write.available_16k.last(), Some(&((2 * b) | 0b0001)));
+        assert_eq!(write.take_available(p), Some(2 * b + p));
+        assert_eq!(write.available_16k.last(), Some(&((2 * b) | 0b0011)));
+
+        // Whole clusters skip over the partly taken one, and leftovers go back on the lists
+        assert_eq!(write.take_available(c), Some(2 * b + c));
+        assert_eq!(write.take_available(2 * p + 1), Some(2 * b + 2 * c));
+        assert_eq!(write.available_4k, [2 * b + 2 * c + 3 * p]);
+        assert_eq!(write.take_available(b + 1), None);
+
+        // Freeing everything and coalescing gets the whole block back
+        let before = write.available_bytes();
+        for page in [2 * b, 2 * b + p] {
+            write.add_free_run(page, p);
+        }
+        write.add_free_run(2 * b + c, c);
+        write.add_free_run(2 * b + 2 * c, 3 * p);
+        assert_eq!(write.available_bytes(), before + 2 * p + c + 3 * p);
+        write.coalesce_available();
+        assert!(write.available_4k.is_empty());
+        assert!(write.available_16k.is_empty());
+        write.available_blocks.sort();
+        assert_eq!(write.available_blocks, [b, 2 * b]);
+    }
+
+    #[test]
+    fn stats() {
+        let p = PAGE_SIZE as u64;
+        let (read, unit, _commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let before = unit.stats();
+        let space = before.space.unwrap();
+        let data_pages = (MIN_DB_SIZE - ROOT_MAP_SIZE) as u64 / p;
+        assert_eq!(before.file_len, MIN_DB_SIZE as u64);
+        assert!(before.mapped_bytes >= before.file_len);
+        assert_eq!(before.newest_id, unit.generation());
+        assert_eq!(before.readers, 0);
+        assert_eq!(space.allocated_pages + space.free_bytes() / p, data_pages);
+
+        // Readers see the same, minus the space usage
+        let txn = read.reader();
+        let expected = Stats {
+            readers: 1,
+            space: None,
+            ..before.clone()
+        };
+        assert_eq!(read.stats(), expected);
+
+        // Taking a page splits a cluster, and the reader holds the oldest ID back
+        let mut write = unit.write();
+        write.0.take_available(p).unwrap();
+        let (unit, _) = write.commit(b"");
+        let after = unit.stats();
+        let after_space = after.space.unwrap();
+        assert_eq!(after.newest_id, before.newest_id + 1);
+        assert!(after.oldest_id <= txn.generation());
+        assert_eq!(after.readers, 1);
+        assert!(after_space.allocated_pages > space.allocated_pages);
+        assert!(after_space.free_pages > 0);
+        assert_eq!(after_space.allocated_pages + after_space.free_bytes() / p, data_pages);
+
+        drop(txn);
+        assert_eq!(read.stats().readers, 0);
+    }
+
+    #[test]
+    fn double_free() {
+        let p = PAGE_SIZE as u64;
+        let (_read, write, _commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let mut write = write.write();
+        let page = write.0.take_available(p).unwrap();
+        let cluster = write.0.take_available(CLUSTER_SIZE as u64).unwrap();
+        // Freed last, so the commit's new freelist goes there instead of `page`
+        let spare = write.0.take_available(p).unwrap();
+        write.0.free_pages(page, p).unwrap();
+        write.0.free_pages(spare, p).unwrap();
+        let (unit, _) = write.commit(b"");
+        let mut write = unit.write();
+
+        // Freeing it again is caught, whether it's the same range or just overlaps a free one
+        let err = write.0.free_pages(page, p).unwrap_err();
+        assert!(
+            matches!(err, AllocError::DoubleFree { page: pg, len, .. } if (pg, len) == (page, p))
+        );
+        assert!(matches!(
+            write.0.free_pages(cluster, 2 * CLUSTER_SIZE as u64),
+            Err(AllocError::DoubleFree { .. })
+        ));
+        write.0.free_pages(cluster, CLUSTER_SIZE as u64).unwrap();
+
+        // With the audit log, the error says what happened to the page before
+        #[cfg(feature = "alloc-audit")]
+        {
+            let msg = err.to_string();
+            for op in ["alloc", "free"] {
+                let record = format!("txn 1: {op} {page:#x}+0x1000");
+                assert!(msg.contains(&record), "{msg}");
+            }
+            let (unit, _) = write.commit(b"");
+            let log = unit.audit_log();
+            assert_eq!(log.len(), 6);
+            assert_eq!(
+                log.last(),
+                Some(&AuditRecord {
+                    txn_id: 2,
+                    op: AuditOp::Free,
+                    page: cluster,
+                    len: CLUSTER_SIZE as u64,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn fragmentation_churn() {
+        let p = PAGE_SIZE as u64;
+        let c = CLUSTER_SIZE as u64;
+        let (_read, write, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let mut write = write.write();
+        // The freelist moves around as it gets rewritten, so count its pages as free too
+        let free = |write: &WriteTxn| {
+            write.0.available_bytes() + write.0.freelist_pages.len() as u64 * p
+        };
+        let total = free(&write);
+
+        // Alternate page and cluster allocations, freeing every other page and every cluster
+        // again, over many transactions. Pages freed in one transaction get folded back into
+        // their clusters by the next, so clusters never run out while there's room for them.
+        let mut held = Vec::new();
+        for round in 0..64 {
+            let mut pages = Vec::new();
+            let mut clusters = Vec::new();
+            for _ in 0..16 {
+                pages.push(write.0.take_available(p).unwrap());
+                clusters.push(write.0.take_available(c).unwrap());
+            }
+            for (i, page) in pages.into_iter().enumerate() {
+                if i % 2 == 0 || round % 2 == 0 {
+                    write.0.add_free_run(page, p);
+                } else {
+                    held.push(page);
+                }
+            }
+            for cluster in clusters {
+                write.0.add_free_run(cluster, c);
+            }
+            let (unit, _) = write.commit(b"");
+            commit.commit().unwrap();
+            write = unit.write();
+            assert_eq!(free(&write) + held.len() as u64 * p, total);
+        }
+
+        // Every cluster without a held or freelist page in it can still be allocated whole
+        let mut touched: Vec<u64> = held
+            .iter()
+            .chain(&write.0.freelist_pages)
+            .map(|page| page & !(c - 1))
+            .collect();
+        touched.sort();
+        touched.dedup();
+        let whole = total / c - touched.len() as u64;
+        let mut clusters = Vec::new();
+        while let Some(cluster) = write.0.take_available(c) {
+            clusters.push(cluster);
+        }
+        assert_eq!(clusters.len() as u64, whole);
+        for cluster in clusters {
+            write.0.add_free_run(cluster, c);
+        }
+        for page in held {
+            write.0.add_free_run(page, p);
+        }
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let write = unit.write();
+        assert_eq!(free(&write), total);
+        assert!(write.0.available_4k.is_empty());
+    }
+
+    #[test]
+    fn reopen_freelist() {
+        let path = TempPath::new("reopen");
+
+        // A brand new file, written to and closed
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let write = write.write();
+        let created = write.0.free_space();
+        let (unit, _) = write.commit(b"first");
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+
+        // Reopening finds the same free space, and the committed root
+        let (read, write, commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(&read.reader().root.root[..], b"first");
+        let write = write.write();
+        assert_eq!(write.0.free_space(), created);
+        drop((read, write, commit));
+
+        // Growing the file on open adds the new space as free blocks, and they stay free
+        let mut options = OpenOptions::default();
+        options.size(MIN_DB_SIZE + BLOCK_SIZE);
+        let (read, write, mut commit) = options.open(&path).unwrap();
+        let write = write.write();
+        let mut grown = created.clone();
+        grown.insert_range(MIN_DB_SIZE as u64, BLOCK_SIZE as u64);
+        assert_eq!(write.0.free_space(), grown);
+
+        // That changes the free space, so the commit writes out a new freelist, and the old one's
+        // page is free again
+        let (unit, _) = write.commit(b"second");
+        commit.commit().unwrap();
+        let write = unit.write();
+        let head = write.0.root.freelist.0;
+        assert_ne!(head, ROOT_MAP_SIZE as u64);
+        let committed = write.0.free_space();
+        assert!(committed.contains(ROOT_MAP_SIZE as u64));
+        drop((read, write, commit));
+        let (read, write, commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(write.write().0.free_space(), committed);
+        drop((read, commit));
+
+        // A corrupt freelist page is caught by its hash. The first root's freelist is still
+        // intact, so opening falls back on that.
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[head as usize + format::INT_PAGE_TYPE] ^= 0x01;
+        std::fs::write(&path, &contents).unwrap();
+        let (read, _write, _commit) = OpenOptions::default().open(&path).unwrap();
+        assert!(matches!(
+            read.open_report().damaged_root(),
+            Some(AllocError::DataFormat(FormatError::FreelistHash { page })) if *page == head
+        ));
+        assert_eq!(&read.reader().root.root[..], b"first");
+    }
+
+    #[test]
+    fn unverified_freelist() {
+        let path = TempPath::new("unverified");
+
+        // Two commits, with only the second using up free space and writing a new freelist
+        let (read, write, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let mut write = write.write();
+        write.new_allocation(PAGE_SIZE as u64).unwrap();
+        let (unit, allocs) = write.commit(b"first");
+        commit.commit().unwrap();
+        let first = unit.0.root.freelist.0;
+        let mut write = unit.write();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (unit, _) = write.commit(b"second");
+        commit.commit().unwrap();
+        let head = unit.0.root.freelist.0;
+        assert_ne!(head, first);
+        drop((read, unit, commit));
+
+        // Flipping a byte nothing else looks at is only caught by the hash
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[head as usize + format::INT_PAGE_TYPE - 1] ^= 0x01;
+        std::fs::write(&path, &contents).unwrap();
+        let (read, write, commit) = OpenOptions::default().open(&path).unwrap();
+        assert!(matches!(
+            read.open_report().damaged_root(),
+            Some(AllocError::DataFormat(FormatError::FreelistHash { page })) if *page == head
+        ));
+        assert_eq!(&read.reader().root.root[..], b"first");
+        drop((read, write, commit));
+
+        // Without checking, the newest root opens as if nothing happened
+        let mut options = OpenOptions::default();
+        options.verify_checksums(false);
+        let (read, _write, _commit) = options.open(&path).unwrap();
+        assert!(read.open_report().damaged_root().is_none());
+        assert_eq!(&read.reader().root.root[..], b"second");
+    }
+
+    #[test]
+    fn file_type_mismatch() {
+        let path = TempPath::new("file-type");
+        let mut options = OpenOptions::default();
+        options.file_type(b"my-app-1");
+        let (read, write, mut commit) = options.open(&path).unwrap();
+        assert_eq!(&read.file_type(), b"my-app-1");
+        let (unit, _) = write.write().commit(b"mine");
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+
+        // Opening it as something else fails, and leaves the file alone
+        let contents = std::fs::read(&path).unwrap();
+        let mut options = OpenOptions::default();
+        options.size(MIN_DB_SIZE + BLOCK_SIZE);
+        let Err(err) = options.open(&path) else {
+            panic!("opened a database of the wrong file type");
+        };
+        assert_eq!(err.kind(), AllocErrorKind::DataFormat(FormatErrorKind::FileType));
+        assert!(matches!(
+            err,
+            AllocError::DataFormat(FormatError::FileType { expected, found })
+                if &expected == b"crab-db\0" && &found == b"my-app-1"
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+        // Unless any file type goes, and then it can be checked after opening
+        let mut options = OpenOptions::default();
+        options.accept_any_file_type();
+        let (read, _write, _commit) = options.open(&path).unwrap();
+        assert_eq!(&read.file_type(), b"my-app-1");
+        assert_eq!(&read.reader().root.root[..], b"mine");
+    }
+
+    #[test]
+    fn failed_root_flush() {
+        let p = PAGE_SIZE as u64;
+        let (_read, write, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let mut write = write.write();
+        let page = write.0.take_available(p).unwrap();
+        let (unit, _) = write.commit(b"");
+        let durable = commit.commit().unwrap();
+
+        // A transaction frees the page, but its root page never makes it to disk
+        let mut write = unit.write();
+        write.txn_free(page, PAGE_SIZE).unwrap();
+        let (unit, _) = write.commit(b"");
+        commit.core.storage.lock().unwrap().fail_range_flushes(true);
+        assert!(matches!(commit.commit(), Err(AllocError::Sync(_))));
+        assert_eq!(commit.committed_id(), durable);
+
+        // The durable root page can still reach it, so it can't be handed out again
+        let write = unit.write();
+        assert_eq!(write.0.durable_id, durable);
+        assert!(!write.0.overlaps_available(page, p));
+        let write = write.commit(b"").0.write();
+        assert!(!write.0.overlaps_available(page, p));
+
+        // Once a root page from after it is flushed, it's free
+        commit.core.storage.lock().unwrap().fail_range_flushes(false);
+        commit.commit().unwrap();
+        let write = write.commit(b"").0.write();
+        assert!(write.0.overlaps_available(page, p));
+    }
+
+    #[test]
+    fn commit_retry_and_durable_readers() {
+        let (read, unit, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let unit = unit.write().commit(b"durable").0;
+        let durable = commit.commit().unwrap();
+        assert_eq!(read.reader_durable().generation(), durable);
+
+        // Commits keep failing while the writer carries on past them
+        commit.core.storage.lock().unwrap().fail_range_flushes(true);
+        let mut unit = unit;
+        for root in [b"one", b"two", b"six"] {
+            unit = unit.write().commit(root).0;
+            assert_eq!(commit.commit().unwrap_err().kind(), AllocErrorKind::Sync);
+            assert_eq!(commit.committed_id(), durable);
+        }
+        assert_eq!(unit.generation(), durable + 3);
+        let stats = read.stats();
+        assert_eq!((stats.newest_id, stats.durable_id), (durable + 3, durable));
+
+        // Plain readers see the newest transaction, durable ones only what's on disk
+        let newest = read.reader();
+        let pinned = read.reader_durable();
+        assert_eq!(&newest.root.root[..], b"six");
+        assert_eq!(pinned.generation(), durable);
+        assert_eq!(&pinned.root.root[..], b"durable");
+        assert_eq!(read.stats().oldest_id, durable);
+
+        // A retry that works picks up the newest transaction, and durable readers move up to it
+        commit
+            .core
+            .storage
+            .lock()
+            .unwrap()
+            .fail_range_flushes(false);
+        assert_eq!(commit.commit().unwrap(), durable + 3);
+        assert_eq!(commit.committed_id(), unit.generation());
+        assert_eq!(read.stats().durable_id, durable + 3);
+        let caught_up = read.reader_durable();
+        assert_eq!(caught_up.generation(), durable + 3);
+        assert_eq!(&caught_up.root.root[..], b"six");
+
+        // The old durable reader still holds its transaction until it's dropped
+        assert_eq!(read.stats().oldest_id, durable);
+        drop((pinned, newest, caught_up));
+        let _unit = unit.write().commit(b"").0;
+        commit.commit().unwrap();
+        assert_eq!(read.stats().oldest_id, durable + 4);
+    }
+
+    #[test]
+    fn sync_modes() {
+        let modes = [SyncMode::Full, SyncMode::Async, SyncMode::None];
+        for (i, mode) in modes.into_iter().enumerate() {
+            let path = TempPath::new(&format!("sync-mode-{i}"));
+            let (read, mut unit, mut commit) =
+                OpenOptions::default().sync_mode(mode).open(&path).unwrap();
+            assert_eq!(commit.sync_mode(), mode);
+            for root in [&b"zero"[..], b"one"] {
+                unit = unit.write().commit(root).0;
+                commit.commit().unwrap();
+            }
+
+            // Lose power right after the next commit, and see what made it onto the disk
+            commit
+                .core
+                .storage
+                .lock()
+                .unwrap()
+                .cut_power_after(usize::MAX);
+            let unit = unit.write().commit(b"two").0;
+            assert_eq!(commit.commit().unwrap(), unit.generation());
+            assert_eq!(&read.reader_durable().root.root[..], b"two");
+            let crashed = commit.core.storage.lock().unwrap().take_disk().unwrap();
+            drop((read, unit, commit));
+
+            // Only a mode that flushes the root page gets it onto the disk
+            std::fs::write(&path, crashed).unwrap();
+            let (read, _unit, _commit) = OpenOptions::default().open(&path).unwrap();
+            let expected: &[u8] = if mode == SyncMode::None { b"one" } else { b"two" };
+            assert_eq!(&read.reader().root.root[..], expected);
+        }
+    }
+
+    #[test]
+    fn commit_flushes_written_pages() {
+        use std::io::Write;
+
+        let path = TempPath::new("written-pages");
+        let (read, unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let mut write = unit.write();
+        write.new_allocation(PAGE_SIZE as u64).unwrap();
+        write.new_allocation(PAGE_SIZE as u64).unwrap();
+        let (unit, mut allocs) = write.commit(b"zero");
+        commit.commit().unwrap();
+
+        // One allocation goes into the next transaction, the other is written to but kept out
+        commit
+            .core
+            .storage
+            .lock()
+            .unwrap()
+            .cut_power_after(usize::MAX);
+        let mut kept = allocs.pop().unwrap();
+        kept.writer().write_all(b"not yet").unwrap();
+        let mut used = allocs.pop().unwrap();
+        used.writer().write_all(b"committed").unwrap();
+        let kept_at = kept.alloc().to_range().unwrap();
+        let used_at = used.alloc().to_range().unwrap();
+        let mut write = unit.write();
+        write.use_allocation(used).unwrap();
+        let (unit, _) = write.commit(b"one");
+        let written = read.core.root.lock().unwrap().written_through(unit.generation());
+        assert!(written.contains(used_at.start as u64));
+        assert!(!written.contains(kept_at.start as u64));
+        commit.commit().unwrap();
+        let disk = commit.core.storage.lock().unwrap().take_disk().unwrap();
+
+        // Only what the transaction wrote made it onto the disk, and that's enough to reopen it
+        assert_eq!(&disk[used_at.start..][..9], b"committed");
+        assert!(disk[kept_at.start..][..kept_at.len].iter().all(|b| *b == 0));
+        drop((read, unit, commit, kept));
+        std::fs::write(&path, disk).unwrap();
+        let (read, _unit, _commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(&read.reader().root.root[..], b"one");
+    }
+
+    #[test]
+    fn written_pages_outlast_history() {
+        // Transactions pushed out of the history hand their written pages on to the next one
+        let mut root = RootData::new(b"crab-db\0", ByteOffset::default(), MIN_DB_SIZE as u64);
+        let page = |i: usize| (ROOT_MAP_SIZE + i * PAGE_SIZE) as u64;
+        for i in 0..(COMMIT_HISTORY_LEN + 2) {
+            let mut written = RunSet::new();
+            written.insert(page(i));
+            let update = RootCheckout {
+                id: i as u64 + 1,
+                root: Arc::default(),
+                freelist: ByteOffset::default(),
+                file_len: MIN_DB_SIZE as u64,
+            };
+            root.update(&update, written);
+        }
+        let newest = root.id_tracker.newest_id();
+        let all = root.written_through(newest);
+        assert_eq!(all.page_count(), COMMIT_HISTORY_LEN as u64 + 2);
+        assert_eq!(all.run_count(), 1);
+        // Committing an older one only takes what it and the ones before it wrote
+        assert_eq!(root.written_through(newest - 1).page_count(), all.page_count() - 1);
+        root.forget_through(newest - 1);
+        let last: Vec<u64> = root.written_through(newest).pages().collect();
+        assert_eq!(last, [page(COMMIT_HISTORY_LEN + 1)]);
+    }
+
+    #[test]
+    fn two_phase_commit() {
+        let path = TempPath::new("two-phase");
+        // Both root pages hold a durable transaction to start with
+        let (read, mut unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+        for root in [&b"zero"[..], b"one"] {
+            unit = unit.write().commit(root).0;
+            commit.commit().unwrap();
+        }
+        let durable = commit.committed_id();
+        let unit = unit.write().commit(b"two").0;
+        let outstanding = || read.core.root.lock().unwrap().id_tracker.outstanding();
+        let before = outstanding();
+
+        // Preparing flushes the data but leaves the root page alone. Crashing here loses power
+        // right after the flush.
+        commit
+            .core
+            .storage
+            .lock()
+            .unwrap()
+            .cut_power_after(usize::MAX);
+        let prepared = commit.prepare().unwrap();
+        assert_eq!(prepared.id(), unit.generation());
+        assert!(!prepared.root_page().is_empty());
+        let crashed = prepared
+            .unit
+            .core
+            .storage
+            .lock()
+            .unwrap()
+            .take_disk()
+            .unwrap();
+        assert_eq!(&read.reader_durable().root.root[..], b"one");
+
+        // Abandoning it changes nothing, and hands back the transaction it had checked out
+        prepared.abandon();
+        assert_eq!(commit.committed_id(), durable);
+        assert_eq!(outstanding(), before);
+
+        // Publishing a fresh one makes the transaction durable, same as a plain commit
+        let prepared = commit.prepare().unwrap();
+        assert_eq!(prepared.publish().unwrap(), unit.generation());
+        assert_eq!(commit.committed_id(), unit.generation());
+        assert_eq!(&read.reader_durable().root.root[..], b"two");
+
+        // With nothing new, there's nothing to write
+        let prepared = commit.prepare().unwrap();
+        assert_eq!(prepared.id(), unit.generation());
+        assert!(prepared.root_page().is_empty());
+        assert_eq!(prepared.publish().unwrap(), unit.generation());
+        drop((read, unit, commit));
+        let (read, unit, commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(&read.reader().root.root[..], b"two");
+        drop((read, unit, commit));
+
+        // The database as it was on disk between the two phases reopens at the old root
+        std::fs::write(&path, crashed).unwrap();
+        let (read, _unit, commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(&read.reader().root.root[..], b"one");
+        assert_eq!(commit.committed_id(), durable);
+        assert!(!read.open_report().recovered());
+    }
+
+    #[test]
+    fn commit_to_id() {
+        let path = TempPath::new("commit-to");
+
+        // Three transactions, none of them durable yet
+        let (read, mut unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let opened = commit.committed_id();
+        let mut ids = Vec::new();
+        for root in [b"one", b"two", b"six"] {
+            unit = unit.write().commit(root).0;
+            ids.push(unit.generation());
+        }
+        assert_eq!(ids, [opened + 1, opened + 2, opened + 3]);
+
+        // Only the first two get committed
+        assert_eq!(commit.commit_to(ids[1]).unwrap(), ids[1]);
+        assert_eq!(commit.committed_id(), ids[1]);
+        assert_eq!(commit.commit_to(ids[1]).unwrap(), ids[1]);
+
+        // It won't go backwards, or past the newest transaction
+        for id in [ids[0], ids[2] + 1] {
+            assert!(matches!(
+                commit.commit_to(id),
+                Err(AllocError::CommitOutOfRange { committed, newest, .. })
+                    if (committed, newest) == (ids[1], ids[2])
+            ));
+        }
+        drop((read, unit, commit));
+
+        // Reopening finds the second one
+        let (read, unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(&read.reader().root.root[..], b"two");
+        assert_eq!(commit.committed_id(), ids[1]);
+        assert_eq!(unit.generation(), ids[1]);
+
+        // Only so many transactions back can be committed on their own, but committing
+        // everything always works
+        let mut unit = unit.write().commit(b"").0;
+        let first = unit.generation();
+        for _ in 0..COMMIT_HISTORY_LEN {
+            unit = unit.write().commit(b"").0;
+        }
+        assert!(matches!(
+            commit.commit_to(first),
+            Err(AllocError::CommitHistoryGone { id }) if id == first
+        ));
+        assert_eq!(commit.commit_to(first + 1).unwrap(), first + 1);
+        assert_eq!(commit.commit().unwrap(), unit.generation());
+    }
+
+    /// Allocate three times the minimum database size in whole blocks, growing the file two
+    /// blocks at a time, and check every block reads back what was written into it. Returns the
+    /// file length that was committed.
+    fn grow_through((read, write, mut commit): AllocTuple) -> u64 {
+        const ALLOCS: usize = 3 * MIN_DB_SIZE / BLOCK_SIZE;
+        let mut write = write.write();
+        for _ in 0..ALLOCS {
+            write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        }
+        assert!(write.0.root.file_len > MIN_DB_SIZE as u64);
+        let (unit, mut allocs) = write.commit(b"");
+        for (i, alloc) in allocs.iter_mut().enumerate() {
+            alloc
+                .writer()
+                .write_all(&[i as u8 + 1; BLOCK_SIZE])
+                .unwrap();
+        }
+        let mut write = unit.write();
+        let placed: Vec<Alloc> = allocs.iter().map(WriteAlloc::alloc).collect();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (_unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+
+        let mut txn = read.reader();
+        let file_len = txn.root.file_len;
+        assert_eq!((file_len - MIN_DB_SIZE as u64) % (2 * BLOCK_SIZE) as u64, 0);
+        for (i, alloc) in placed.iter().enumerate() {
+            assert!(alloc.page.get() + alloc.len as u64 <= file_len);
+            let mem = txn
+                .read_pages(alloc.page.get(), BLOCK_SIZE / PAGE_SIZE)
+                .unwrap();
+            assert!(mem.iter().all(|b| *b == i as u8 + 1));
+        }
+        file_len
+    }
+
+    #[test]
+    fn grow_on_demand() {
+        let mut options = OpenOptions::default();
+        options.growth_step(2 * BLOCK_SIZE);
+
+        // Anonymous storage just maps more memory
+        grow_through(options.open_anon().unwrap());
+
+        // A file grows to match, and reopens at the new length
+        let path = TempPath::new("grow");
+        let file_len = grow_through(options.open(&path).unwrap());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), file_len);
+        let (read, _write, _commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(read.reader().root.file_len, file_len);
+
+        // The step has to be whole blocks
+        for step in [0, PAGE_SIZE, BLOCK_SIZE + PAGE_SIZE] {
+            assert!(matches!(
+                OpenOptions::default().growth_step(step).open_anon(),
+                Err(AllocError::InvalidSize { size }) if size == step
+            ));
+        }
+    }
+
+    #[test]
+    fn max_size() {
+        let limit = 8 * BLOCK_SIZE as u64;
+        let fill_blocks = |write: &mut WriteTxn| loop {
+            if let Err(e) = write.new_allocation(BLOCK_SIZE as u64) {
+                break e;
+            }
+        };
+
+        // The limit rounds down to whole blocks, and the last growth step stops short at it
+        let mut options = OpenOptions::default();
+        options
+            .growth_step(3 * BLOCK_SIZE)
+            .max_size(8 * BLOCK_SIZE + PAGE_SIZE);
+        let (read, write, mut commit) = options.open_anon().unwrap();
+        let mut write = write.write();
+        let err = fill_blocks(&mut write);
+        assert!(matches!(
+            err,
+            AllocError::DatabaseFull { limit: l, requested } if l == limit && requested > limit
+        ));
+        assert_eq!(write.0.root.file_len, limit);
+
+        // Whatever fit before the limit still gets committed
+        let (unit, mut allocs) = write.commit(b"");
+        assert!(!allocs.is_empty());
+        for (i, alloc) in allocs.iter_mut().enumerate() {
+            alloc
+                .writer()
+                .write_all(&[i as u8 + 1; BLOCK_SIZE])
+                .unwrap();
+        }
+        let mut write = unit.write();
+        let placed: Vec<Alloc> = allocs.iter().map(WriteAlloc::alloc).collect();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+
+        // It stays full, and what's there stays readable
+        let mut write = unit.write();
+        assert_eq!(fill_blocks(&mut write).kind(), AllocErrorKind::DatabaseFull);
+        assert_eq!(write.0.root.file_len, limit);
+        let mut txn = read.reader();
+        for (i, alloc) in placed.iter().enumerate() {
+            let mem = txn
+                .read_pages(alloc.page.get(), BLOCK_SIZE / PAGE_SIZE)
+                .unwrap();
+            assert!(mem.iter().all(|b| *b == i as u8 + 1));
+        }
+
+        // A limit below the starting size is raised to it
+        let mut options = OpenOptions::default();
+        options.size(6 * BLOCK_SIZE).max_size(BLOCK_SIZE);
+        let (_read, write, _commit) = options.open_anon().unwrap();
+        let mut write = write.write();
+        assert!(matches!(
+            fill_blocks(&mut write),
+            AllocError::DatabaseFull { limit, .. } if limit == 6 * BLOCK_SIZE as u64
+        ));
+        assert_eq!(write.0.root.file_len, 6 * BLOCK_SIZE as u64);
     }
 
-    /// Put a written-out allocation into this transaction
-    pub fn use_allocation(&mut self, alloc: WriteAlloc) {
-        self.0.alloc_completions.push(alloc);
+    #[test]
+    fn grown_space_survives_truncation() {
+        // The committer truncating down to the durable length mustn't take the blocks the writer
+        // has grown into since
+        let (read, write, mut commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let (unit, _) = write.write().commit(b"");
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        let mut count = 0;
+        while write.0.root.file_len == MIN_DB_SIZE as u64 {
+            write.new_allocation(BLOCK_SIZE as u64).unwrap();
+            count += 1;
+        }
+        let grown = write.0.root.file_len;
+        commit.commit().unwrap();
+        assert_eq!(
+            commit.core.storage.lock().unwrap().mapped_len() as u64,
+            grown
+        );
+
+        // Aborting hands the allocations back along with the growth they sit in, and once they're
+        // dropped, the same allocations fit without growing again
+        let Ok((unit, allocs)) = write.abort() else {
+            panic!("abort failed");
+        };
+        assert_eq!(allocs.len(), count);
+        drop(allocs);
+        let mut write = unit.write();
+        let kept = write.0.root.file_len;
+        assert!(kept > MIN_DB_SIZE as u64 && kept <= grown);
+        for _ in 0..count {
+            write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        }
+        assert_eq!(write.0.root.file_len, kept);
+        let (_unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        assert_eq!(read.reader().root.file_len, kept);
     }
 
-    /// Determine if the provided page is marked as dirty or not
-    pub fn is_dirty(&self, page: u64) -> bool {
-        self.0.dirty.contains(&page)
+    #[test]
+    fn compact_tail() {
+        let path = TempPath::new("compact");
+        let file_len = || std::fs::metadata(&path).unwrap().len();
+        const ALLOCS: usize = 100;
+        let full = (MIN_DB_SIZE + ALLOCS * BLOCK_SIZE) as u64;
+
+        // 100 MiB of allocations fill up the end of the file
+        let mut options = OpenOptions::default();
+        options.size(full as usize);
+        let (read, write, mut commit) = options.open(&path).unwrap();
+        let mut write = write.write();
+        for _ in 0..ALLOCS {
+            write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        }
+        let (unit, allocs) = write.commit(b"");
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        let placed: Vec<Alloc> = allocs.iter().map(WriteAlloc::alloc).collect();
+        for alloc in allocs {
+            write.use_allocation(alloc).unwrap();
+        }
+        assert_eq!(write.compact_tail(), full);
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        assert_eq!(file_len(), full);
+
+        // Once they're all freed and punched out, everything past the minimum size can go
+        let mut write = unit.write();
+        for alloc in &placed {
+            write.txn_free(alloc.page.get(), alloc.len).unwrap();
+        }
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        let (unit, _) = unit.write().commit(b"");
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        assert_eq!(write.compact_tail(), MIN_DB_SIZE as u64);
+
+        // A reader from before the shrink holds off truncating the file, even once it's durable
+        let mut old = read.reader();
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        assert_eq!(file_len(), full);
+        assert!(old.read_pages(full - PAGE_SIZE as u64, 1).is_ok());
+        let mut new = read.reader();
+        assert!(matches!(
+            new.read_pages(MIN_DB_SIZE as u64, 1),
+            Err(AllocError::InvalidAccess { .. })
+        ));
+        drop(old);
+        commit.commit().unwrap();
+        assert_eq!(file_len(), MIN_DB_SIZE as u64);
+
+        // The writer carries on within what's left
+        let mut write = unit.write();
+        write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+        drop((read, new, unit, commit));
+
+        // Reopening only finds free space within the shorter file
+        let (read, write, commit) = OpenOptions::default().open(&path).unwrap();
+        assert_eq!(read.reader().root.file_len, MIN_DB_SIZE as u64);
+        let write = write.write();
+        let blocks = &write.0.available_blocks;
+        assert!(blocks.iter().all(|b| *b < MIN_DB_SIZE as u64));
     }
 
-    /// Commit the transaction to the database and optionally return the requested long-term allocations.
-    pub fn commit(self, root_data: &[u8]) -> (WriteUnit, Vec<WriteAlloc>) {
-        todo!("Push the remaining 4k page allocations into the allocator");
-        /*
-        todo!("Commit the requested allocations into the taken marker");
-        todo!("Commit the completed allocations");
-        todo!("Update the transaction ID number and the new freelist base page");
-        //
-        // Hole punch requests
-        for page in self.0.hole_punch_future_req.iter().copied() {
-            let _ = self.0.hole_punch_req.send(page);
-            self.0.taken.insert(page);
+    #[test]
+    fn single_writer() {
+        let core = test_core();
+        let options = OpenOptions::default();
+
+        // A second writer can't be set up while the first is around, whether it's currently a
+        // WriteTxn or a WriteUnit.
+        let first = WriteTxn(test_writer_on(&core, &options).unwrap());
+        assert!(matches!(
+            test_writer_on(&core, &options),
+            Err(AllocError::WriterActive)
+        ));
+        let first = WriteUnit(first.0);
+        assert!(matches!(
+            test_writer_on(&core, &options),
+            Err(AllocError::WriterActive)
+        ));
+        let first = first.write();
+        assert!(matches!(
+            test_writer_on(&core, &options),
+            Err(AllocError::WriterActive)
+        ));
+
+        // Dropping the first writer frees up the token
+        drop(first);
+        let second = test_writer_on(&core, &options).unwrap();
+        assert!(second.token.is_current());
+        drop(second);
+
+        // A leaked writer holds onto the token until it's forcibly reclaimed. Once that happens,
+        // the stale handle can no longer release the new writer's claim.
+        let leaked = test_writer_on(&core, &options).unwrap();
+        assert!(matches!(
+            test_writer_on(&core, &options),
+            Err(AllocError::WriterActive)
+        ));
+        let recovered = core.writer.lock().unwrap().reclaim();
+        assert!(!leaked.token.is_current());
+        drop(leaked);
+        assert!(core.writer.lock().unwrap().is_current(recovered));
+        assert!(matches!(
+            test_writer_on(&core, &options),
+            Err(AllocError::WriterActive)
+        ));
+    }
+
+    #[derive(Default)]
+    struct BudgetCounter(AtomicUsize);
+
+    impl MetricsHook for BudgetCounter {
+        fn txn_memory_over_budget(&self, used: usize, budget: usize) {
+            assert!(used > budget);
+            self.0.fetch_add(1, AtomicOrdering::Relaxed);
         }
-        todo!()
-        */
     }
 
-    /// Abort the current transaction, undoing all transaction operations and returning any written-out allocation.
-    /// 
-    /// This will panic if this is called on the first transaction on a brand-new database.
-    pub fn abort(mut self) -> (WriteUnit, Vec<WriteAlloc>) {
-        if self.0.root.id == 0 {
-            panic!("Can't abort the very first transaction of the database");
+    #[test]
+    fn txn_memory_budget() {
+        const PAGES: u64 = 100_000;
+
+        // Sequential allocations collapse down to a single run, and stay well within budget.
+        let counter = Arc::new(BudgetCounter::default());
+        let mut options = OpenOptions::default();
+        options.txn_memory_budget(4096).metrics(counter.clone());
+        let mut write = test_writer(&options);
+        for i in 0..PAGES {
+            write.mark_dirty(ROOT_MAP_SIZE as u64 + i * PAGE_SIZE as u64);
         }
-        self.0.dirty.clear();
-        self.0.alloc_req.clear();
-        let ret = std::mem::take(&mut self.0.alloc_completions);
-        (WriteUnit(self.0), ret)
+        assert_eq!(write.dirty.run_count(), 1);
+        assert_eq!(write.dirty.page_count(), PAGES);
+        assert!(write.txn_memory_usage() <= 4096);
+        assert_eq!(counter.0.load(AtomicOrdering::Relaxed), 0);
+
+        // Scattered allocations can't be coalesced, so they blow the budget. We should get warned
+        // exactly once, and keep on going.
+        let mut write = test_writer(&options);
+        for i in 0..PAGES {
+            write.mark_dirty(ROOT_MAP_SIZE as u64 + i * 2 * PAGE_SIZE as u64);
+        }
+        assert_eq!(write.dirty.run_count(), PAGES as usize);
+        assert_eq!(counter.0.load(AtomicOrdering::Relaxed), 1);
+
+        // Without a budget, nothing is reported at all.
+        let mut write = test_writer(&OpenOptions::default());
+        for i in 0..PAGES {
+            write.mark_dirty(ROOT_MAP_SIZE as u64 + i * 2 * PAGE_SIZE as u64);
+        }
+        assert_eq!(write.dirty.run_count(), PAGES as usize);
+        assert_eq!(counter.0.load(AtomicOrdering::Relaxed), 1);
     }
-}
 
-/// Handles committing completed write transactions to disk.
-///
-/// This unit is basically another open read transaction, representing any future program that will
-/// open the database. When it commits to disk, it grabs the current completed transaction, flushes
-/// everything to disk synchronously and advances to that completed transaction ID.
-///
-/// For anonymous memory maps, no sync to disk occurs, but this does still need to be called.
-///
-/// Committing after every write transaction is generally a good idea, though this should be done in
-/// a separate thread, as this is a blocking operation.
-pub struct CommitUnit {
-    /// The current "checked-out" ID we're holding onto
-    id: u64,
-    /// The data to commit to the root page
-    commit_data: Vec<u8>,
-    /// Any pending hole punch operations
-    hole_punch_req: mpsc::Receiver<u64>,
-    /// Completed hole punch operations
-    hole_punch_resp: mpsc::Sender<u64>,
-    /// The two root pages to write to
-    root0: &'static mut [u8],
-    root1: &'static mut [u8],
-    write_root0: bool,
-    /// Access to the core database synchronization primitives
-    core: Arc<DbCore>,
-}
+    #[test]
+    fn spill_available() {
+        const PAGES: u64 = 8000;
+        const BUDGET: usize = 8192;
+        let p = PAGE_SIZE as u64;
+        let path = TempPath::new("spill");
+        let counter = Arc::new(BudgetCounter::default());
+        let mut options = OpenOptions::default();
+        options.txn_memory_budget(BUDGET).metrics(counter.clone());
 
-impl CommitUnit {
-    pub fn commit(&mut self) -> Result<(), AllocError> {
-        // Acquire our next transaction ID now, as we're about to commit everything up to this
-        // point. We also need to grab the current state of the Root that we want to write out.
-        let new_id = {
-            let mut mutex = self.core.root.lock().unwrap();
-            mutex.store(&mut self.commit_data)?;
-            let new_id = mutex.id_tracker.checkout();
-            drop(mutex);
-            new_id
-        };
+        // Free every other page of a big allocation, scattering the free space over thousands of
+        // partly free clusters
+        let (read, unit, mut commit) = options.open(&path).unwrap();
+        let mut write = unit.write();
+        let pages: Vec<u64> = (0..PAGES)
+            .map(|_| write.txn_allocate(p).unwrap().page.get())
+            .collect();
+        let unit = write.commit(b"allocated").0;
+        commit.commit().unwrap();
+        let mut write = unit.write();
+        for page in pages.iter().step_by(2) {
+            write.txn_free(*page, PAGE_SIZE).unwrap();
+        }
+        let unit = write.commit(b"freed").0;
+        commit.commit().unwrap();
+        let unit = unit.write().commit(b"released").0;
+        commit.commit().unwrap();
 
-        // Perform the main flush
-        let res = {
-            let mutex = self.core.storage.lock().unwrap();
-            let res = mutex.flush();
-            drop(mutex);
-            res
-        };
-        if res.is_err() {
-            // We failed to sync, so we need to undo our new checkout and retain the old one.
-            // This probably isn't recoverable, but just in case, we should act as correctly as possible.
-            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
-            return res;
+        // The first page dirtied puts the lists over budget, and spilling brings them back under
+        let mut write = unit.write();
+        let file_len = write.0.root.file_len;
+        let free = write.0.available_bytes();
+        let mut free_runs = write.0.free_space();
+        assert!(write.0.txn_memory_usage() > BUDGET);
+        write.txn_allocate(p).unwrap();
+        assert!(!write.0.spilled.is_empty());
+        assert!(write.0.txn_memory_usage() <= BUDGET);
+        assert_eq!(counter.0.load(AtomicOrdering::Relaxed), 0);
+
+        // Spilled pages still count as free
+        let spilled = write.0.spilled[0].start;
+        let err = write.0.free_pages(spilled, p).unwrap_err();
+        assert_eq!(err.kind(), AllocErrorKind::DoubleFree);
+
+        // Every bit of free space can be allocated before the file has to grow, as the spilled
+        // runs get read back in when the lists run dry
+        for _ in 1..free / p {
+            write.txn_allocate(p).unwrap();
         }
+        assert!(write.0.spilled.is_empty());
+        assert_eq!(write.0.available_bytes(), 0);
+        assert_eq!(write.0.root.file_len, file_len);
 
-        // Update the tree root
-        let root_write = if self.write_root0 { &mut self.root0 } else { &mut self.root1 };
-        let Some(root_write) = root_write.get_mut(0..self.commit_data.len()) else {
-            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
-            return Err(AllocError::Other(
-                "Tried to write root data that was too large for the root page",
-            ));
-        };
-        root_write.copy_from_slice(&self.commit_data);
+        // Aborting puts it all back, and the next transaction spills again
+        let unit = write.abort().map_err(|(_, e)| e).unwrap().0;
+        let mut write = unit.write();
+        assert_eq!(write.0.available_bytes(), free);
+        let page = write.txn_allocate(p).unwrap().page.get();
+        assert!(!write.0.spilled.is_empty());
+        assert!(write.0.txn_memory_usage() <= BUDGET);
+        free_runs.remove(page);
 
-        // Flush the tree root
-        let root_block = BlockRange::new(if self.write_root0 { 0 } else { ROOT_SIZE }, ROOT_SIZE);
-        let res = {
-            let mutex = self.core.storage.lock().unwrap();
-            let res = mutex.flush_range(root_block);
-            drop(mutex);
-            res
+        // Committing reads the spilled runs back in, so they make it into the freelist, and come
+        // back out of it through `load_freelist` on reopening
+        let unit = write.commit(b"spilled").0;
+        assert!(unit.0.spilled.is_empty());
+        let freelist_pages = unit.0.freelist_pages.clone();
+        commit.commit().unwrap();
+        drop((read, unit, commit));
+        let (_read, unit, _commit) = options.open(&path).unwrap();
+        let mut loaded = unit.0.free_space();
+        for page in freelist_pages {
+            loaded.insert(page);
+        }
+        for (start, len) in free_runs.runs() {
+            assert!(loaded.contains_range(start, len));
+        }
+    }
+
+    #[test]
+    fn commit_round_trip() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let before = read.reader();
+        let mut write = WriteTxn(test_writer_on(&core, &OpenOptions::default()).unwrap());
+        let alloc_at = |write: &WriteTxn, page: u64| WriteAlloc {
+            mem: unsafe {
+                write
+                    .0
+                    .storage
+                    .get_mut_slice(BlockRange::new(page as usize, PAGE_SIZE))
+                    .unwrap()
+                    .unwrap()
+            },
+            page,
+            written: 0,
+            chan: write.0.alloc_send.clone(),
+            core: write.0.core.clone(),
         };
-        if res.is_err() {
-            // We failed to sync, so we need to undo our new checkout and retain the old one.
-            // This probably isn't recoverable, but just in case, we should act as correctly as possible.
-            self.core.root.lock().unwrap().id_tracker.checkin(new_id);
-            return res;
+
+        // Write across a pair of pages, finish off one write allocation, and request another
+        let page = ROOT_MAP_SIZE as u64;
+        write.0.mark_dirty(page);
+        write.0.mark_dirty(page + PAGE_SIZE as u64);
+        write.write_at(at(page), PAGE_SIZE - 3, b"crab-db").unwrap();
+        let done = BLOCK_SIZE as u64;
+        let mut alloc = alloc_at(&write, done);
+        write.0.taken.insert(done);
+        alloc.writer().write_all(b"bulk").unwrap();
+        write.use_allocation(alloc).unwrap();
+        let requested = 2 * BLOCK_SIZE as u64;
+        write.0.alloc_req.push(alloc_at(&write, requested));
+
+        let (unit, allocs) = write.commit(b"roots");
+        let pages: Vec<u64> = allocs.iter().map(WriteAlloc::page).collect();
+        assert_eq!(pages, [requested]);
+        assert!(unit.0.taken.contains(&requested));
+        assert_eq!(unit.0.root.id, before.generation() + 1);
+
+        // New readers see the new root and the data, old ones stay where they were
+        let mut after = read.reader();
+        assert_eq!(after.generation(), before.generation() + 1);
+        assert_eq!(&after.root.root[..], b"roots");
+        assert!(before.root.root.is_empty());
+        unsafe {
+            let range = BlockRange::new(page as usize, 2 * PAGE_SIZE);
+            let mem = after.read(range).unwrap();
+            assert_eq!(&mem[PAGE_SIZE - 3..PAGE_SIZE + 4], b"crab-db");
+            let range = BlockRange::new(done as usize, PAGE_SIZE);
+            let mem = after.read(range).unwrap();
+            assert_eq!(&mem[..4], b"bulk");
         }
 
-        // Swap in the new read transaction id
-        self.core.root.lock().unwrap().id_tracker.checkin(self.id);
-        self.id = new_id;
-        Ok(())
+        // The unit goes right into the next transaction. The committed allocation keeps its page,
+        // while dropping the requested one frees it up.
+        drop(allocs);
+        let write = unit.write();
+        assert!(write.0.taken.contains(&done));
+        assert!(!write.0.taken.contains(&requested));
+        assert!(!write.is_dirty(at(page)));
+        let (_unit, allocs) = write.commit(b"again");
+        assert!(allocs.is_empty());
+        assert_eq!(read.reader().generation(), before.generation() + 2);
+        assert_eq!(&read.reader().root.root[..], b"again");
     }
-}
-
-type AllocTuple = (ReadUnit, WriteUnit, CommitUnit);
 
-#[derive(Clone, Debug)]
-struct OpenOptions {
-    size: Option<usize>,
-    file_type: [u8; 8],
-}
+    #[test]
+    fn dirty_pages_unreachable() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
 
-impl Default for OpenOptions {
-    fn default() -> Self {
-        Self {
-            size: None,
-            file_type: *b"crab-db\0",
+        let page = |i: u64| ROOT_MAP_SIZE as u64 + i * PAGE_SIZE as u64;
+        let pin = |i: u64| BlockRange::new(page(i) as usize, PAGE_SIZE);
+        let mut write = test_writer(&OpenOptions::default());
+        for i in 0..4 {
+            write.mark_dirty(page(i));
         }
+        // Pages pinned or taken elsewhere are fine, as long as we aren't writing to them.
+        write.core.read_pages.lock().unwrap().checkout(pin(4));
+        write.taken.insert(page(5));
+        write.check_dirty_unreachable();
+
+        // A dirty page that a reader still holds must trip the check, naming the page.
+        write.core.read_pages.lock().unwrap().checkout(pin(2));
+        let res = catch_unwind(AssertUnwindSafe(|| write.check_dirty_unreachable()));
+        let msg = res.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.contains(&format!("{:#x}", page(2))), "{msg}");
+        write
+            .core
+            .read_pages
+            .lock()
+            .unwrap()
+            .checkin(pin(2))
+            .unwrap();
+
+        // So must one the freelist still thinks is taken.
+        write.taken.insert(page(3));
+        let res = catch_unwind(AssertUnwindSafe(|| write.check_dirty_unreachable()));
+        assert!(res.is_err());
     }
-}
 
-impl OpenOptions {
-    /// Set the desired size of the opened file allocator. If one isn't
-    /// provided, this will default to the minimum of 16 MiB, or if the file
-    /// exists, then the file size. If the file exists and has a larger size
-    /// than the one set here, then the file's size is used instead.
-    pub fn size(&mut self, size: usize) -> &mut Self {
-        self.size = Some(size);
-        self
+    #[test]
+    fn system_page_sizes() {
+        // Pages up to a cluster get full support, and anything larger up to a block gets by
+        // without advice
+        for size in [PAGE_SIZE / 2, PAGE_SIZE, 2 * PAGE_SIZE, CLUSTER_SIZE] {
+            assert!(check_page_size(size).unwrap(), "{size:#x}");
+        }
+        for size in [2 * CLUSTER_SIZE, 64 << 10, BLOCK_SIZE] {
+            assert!(!check_page_size(size).unwrap(), "{size:#x}");
+        }
+        for size in [0, 3 * PAGE_SIZE, 2 * BLOCK_SIZE] {
+            assert!(matches!(
+                check_page_size(size),
+                Err(AllocError::UnsupportedPageSize { found }) if found == size
+            ));
+        }
+
+        // Whatever this system is, the open report says how it went
+        let advise = check_page_size(page_size::get()).unwrap();
+        let (read, _write, _commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        assert_eq!(read.open_report().advice_disabled(), !advise);
     }
 
-    /// Set the desired file type header for creating a new database. If one isn't specified, this
-    /// will default to the byte string "crab-db\0".
-    pub fn file_type(&mut self, file_type: &[u8; 8]) -> &mut Self {
-        self.file_type = *file_type;
-        self
+    #[test]
+    fn ranges_across_maps() {
+        // Two maps meeting on a block boundary, as the storage would leave them
+        let first: &'static [u8] = vec![1u8; BLOCK_SIZE].leak();
+        let second: &'static [u8] = vec![2u8; BLOCK_SIZE].leak();
+        let storage = RawMemory {
+            maps: vec![first, second],
+            generation: 0,
+            current: Arc::new(AtomicU64::new(0)),
+            refreshes: 0,
+        };
+        let get = |start, len| unsafe { storage.get_mut_slice(BlockRange::new(start, len)) };
+
+        // Pages on either side of the boundary come from their own map
+        let last = get(BLOCK_SIZE - PAGE_SIZE, PAGE_SIZE).unwrap().unwrap();
+        assert!(last.len() == PAGE_SIZE && last.iter().all(|b| *b == 1));
+        let next = get(BLOCK_SIZE, PAGE_SIZE).unwrap().unwrap();
+        assert!(next.len() == PAGE_SIZE && next.iter().all(|b| *b == 2));
+        assert_eq!(
+            get(BLOCK_SIZE, BLOCK_SIZE).unwrap().unwrap().len(),
+            BLOCK_SIZE
+        );
+
+        // A range running over the boundary is an error, not a slice of the wrong length
+        assert!(matches!(
+            get(BLOCK_SIZE - PAGE_SIZE, 2 * PAGE_SIZE),
+            Err(AllocError::SplitAcrossMaps { offset, len, boundary })
+                if (offset, len, boundary) == (BLOCK_SIZE - PAGE_SIZE, 2 * PAGE_SIZE, BLOCK_SIZE)
+        ));
+
+        // Running past the end of the last map may just mean the maps are stale
+        assert!(get(2 * BLOCK_SIZE - PAGE_SIZE, 2 * PAGE_SIZE)
+            .unwrap()
+            .is_none());
+        assert!(get(2 * BLOCK_SIZE, PAGE_SIZE).unwrap().is_none());
+        assert!(get(BLOCK_SIZE, usize::MAX).unwrap().is_none());
+
+        // The storage only ever grows by whole blocks, so maps can't end mid-block
+        let core = test_core();
+        let mut inner = core.storage.lock().unwrap();
+        for size in [0, PAGE_SIZE, BLOCK_SIZE + PAGE_SIZE] {
+            assert!(matches!(
+                unsafe { inner.expand(size) },
+                Err(AllocError::InvalidSize { size: s }) if s == size
+            ));
+        }
+        assert!(unsafe { inner.expand(BLOCK_SIZE) }.is_ok());
     }
-    
-    /// Open an anonymous memory map isntead of an on-disk file.
-    pub fn open_anon(&self) -> Result<AllocTuple, AllocError> {
-        let size = self.size.unwrap_or_default().max(MIN_DB_SIZE);
-        let map = MmapRaw::from(
-            MmapMut::map_anon(size).map_err(|e| AllocError::AllocFailed {
-                requested: size,
-                source: e,
-            })?,
+
+    #[test]
+    fn typed_errors() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        // A panic while holding the storage lock shows up as a poisoned mutex, not a panic. The
+        // storage has to have grown for a reader to bother locking it at all.
+        let core = test_core();
+        let storage = test_storage(&core);
+        unsafe { core.storage.lock().unwrap().expand(BLOCK_SIZE) }.unwrap();
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let _storage = core.storage.lock().unwrap();
+            panic!("poisoning the storage lock");
+        }));
+        assert!(res.is_err());
+        assert!(matches!(
+            unsafe { storage.get_const(&core, BlockRange::new(MIN_DB_SIZE, PAGE_SIZE)) },
+            Err(AllocError::MutexPoisoned)
+        ));
+
+        // Root data whose length can't even be recorded in the header
+        let mut root = RootData::new(b"crab-db\0", ByteOffset::default(), MIN_DB_SIZE as u64);
+        root.root = vec![0; u16::MAX as usize + 1].into();
+        let overhead = format::ROOT_HEADER_SIZE + format::ROOT_HASH_SIZE;
+        assert!(matches!(
+            root.store(&mut Vec::new()),
+            Err(AllocError::RootTooLarge { len, max })
+                if len == u16::MAX as usize + 1 + overhead && max == u16::MAX as usize + overhead
+        ));
+
+        // Only reachable on architectures narrower than 64 bits, so just check it reads right
+        assert_eq!(
+            AllocError::FileTooLarge { len: 1 << 40 }.to_string(),
+            "The backing file is 0x10000000000 bytes, too large to memory-map on this architecture"
         );
-        let storage = StorageInner::init(map, None);
-        todo!()
     }
 
-    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<AllocTuple, AllocError> {
-        use fs4::fs_std::FileExt;
+    #[test]
+    fn error_kinds() {
+        // Kinds compare equal whatever details the errors carry, I/O errors included
+        let misaligned = |offset| AllocError::Misaligned { offset }.kind();
+        assert_eq!(misaligned(8), misaligned(16));
+        assert_ne!(misaligned(8), AllocErrorKind::RootAccess);
+        let sync = AllocError::Sync(io::Error::other("disk on fire"));
+        assert_eq!(sync.kind(), AllocErrorKind::Sync);
+        assert_eq!(
+            BlockRange::from_pages(1 << 48, 1).unwrap_err().kind(),
+            AllocErrorKind::ReservedPageBits
+        );
 
-        if (page_size::get() != PAGE_SIZE) && (page_size::get() != CLUSTER_SIZE) {
-            return Err(AllocError::Other("System page size is neither 4kiB nor 16kiB."));
-        }
+        // Format errors are reachable through their own kinds
+        let path = TempPath::new("error-kinds");
+        std::fs::write(&path, [0; PAGE_SIZE]).unwrap();
+        let opened = OpenOptions::default().open(&path).err().map(|e| e.kind());
+        assert_eq!(
+            opened,
+            Some(AllocErrorKind::DataFormat(FormatErrorKind::FileSize))
+        );
 
-        // Open and lock the file
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(path)
-            .map_err(AllocError::Open)?;
-        file.try_lock_exclusive().map_err(AllocError::Lock)?;
+        // The file stays locked while it's open, and only one opener gets it
+        let path = TempPath::new("locked");
+        let (read, unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let unit = unit.write().commit(b"").0;
+        commit.commit().unwrap();
+        let opened = OpenOptions::default().open(&path).err().map(|e| e.kind());
+        assert_eq!(opened, Some(AllocErrorKind::Lock));
+        drop((read, unit, commit));
+        assert!(OpenOptions::default().open(&path).is_ok());
+        let format = FormatError::Truncated {
+            recorded: 2,
+            actual: 1,
+        };
+        assert_eq!(format.kind(), FormatErrorKind::Truncated);
+        assert_eq!(
+            AllocError::DataFormat(format).kind(),
+            AllocErrorKind::DataFormat(FormatErrorKind::Truncated)
+        );
+    }
 
-        // Figure out the file size and resize as needed.
-        let file_size = file.metadata().map_err(AllocError::Open)?.len();
-        if file_size > (usize::MAX as u64) {
-            return Err(AllocError::Other(
-                "The file is larger than can be memory-mapped in this architecture",
+    #[test]
+    fn storage_generation() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let txn = read.reader();
+        let mut storage = test_storage(&core);
+        let grown = BlockRange::new(MIN_DB_SIZE, PAGE_SIZE);
+        let beyond = BlockRange::new(MIN_DB_SIZE + BLOCK_SIZE, PAGE_SIZE);
+        let expand = || unsafe { core.storage.lock().unwrap().expand(BLOCK_SIZE) }.unwrap();
+
+        // Misses against storage that hasn't grown never go looking for new maps
+        assert_eq!(txn.storage_generation(), 0);
+        for _ in 0..10 {
+            assert!(matches!(
+                unsafe { storage.get(&core, grown) },
+                Err(AllocError::InvalidAccess { .. })
             ));
         }
-        let file_size = file_size as usize;
-        let is_new = file_size == 0;
-        if (file_size > 0 && file_size < MIN_DB_SIZE) || ((file_size & (BLOCK_SIZE - 1)) != 0) {
-            return Err(AllocError::DataFormat(error::FormatError::FileSize));
+        assert_eq!(storage.refreshes, 0);
+
+        // Once it grows, the first miss picks up the new maps, and that's it
+        expand();
+        assert_eq!(txn.storage_generation(), 1);
+        for _ in 0..10 {
+            assert!(unsafe { storage.get(&core, grown) }.is_ok());
+            assert!(unsafe { storage.get(&core, beyond) }.is_err());
         }
-        let requested_size = (self.size.unwrap_or(MIN_DB_SIZE) & !(BLOCK_SIZE - 1))
-            .max(MIN_DB_SIZE)
-            .max(file_size);
-        if requested_size != file_size {
-            file.set_len(file_size as u64)
-                .map_err(|e| AllocError::ResizeFailed {
-                    size: file_size,
-                    requested: requested_size,
-                    source: e,
-                })?;
+        assert_eq!(storage.refreshes, 1);
+
+        expand();
+        assert_eq!(txn.storage_generation(), 2);
+        for _ in 0..10 {
+            assert!(unsafe { storage.get(&core, beyond) }.is_ok());
         }
+        assert_eq!(storage.refreshes, 2);
+    }
 
-        let map = MmapOptions::new()
-            .len(requested_size)
-            .map_raw(&file)
-            .map_err(|e| AllocError::AllocFailed {
-                requested: requested_size,
-                source: e,
-            })?;
-        
+    #[test]
+    fn read_block_pins_every_page() {
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let mut txn = read.reader();
+        let write = WriteUnit(test_writer_on(&core, &OpenOptions::default()).unwrap());
 
-        let storage = StorageInner::init(map, Some(file));
-        let read_storage = RawMemory {
-            maps: unsafe { storage.get_maps() },
-        };
+        // A three-page block pins all three pages, not just the first
+        let start = ROOT_MAP_SIZE as u64;
+        let tail = start + 2 * PAGE_SIZE as u64;
+        let block = unsafe { txn.get_block(BlockRange::new(start as usize, 3 * PAGE_SIZE)) };
+        let block = block.unwrap();
+        assert_eq!(block.len(), 3 * PAGE_SIZE);
+        let mut write = write.write();
+        assert!(write.0.taken.contains(&start));
+        assert!(write.0.taken.contains(&tail));
 
-        let commit_root0 = unsafe { read_storage.get_mut_slice(BlockRange::new(0, ROOT_SIZE)).unwrap().unwrap() };
-        let commit_root1 = unsafe { read_storage.get_mut_slice(BlockRange::new(ROOT_SIZE, ROOT_SIZE)).unwrap().unwrap() };
-        let (mut root, commit_write_root0) = if is_new {
-            (RootData::new(&self.file_type, 0, 0), true)
-        } else {
-            let root0 = RootData::load(commit_root0);
-            let root1 = RootData::load(commit_root1);
-            match (root0, root1) {
-                (Err(e0), Err(e1)) => return Err(e0),
-                (Ok(root), Err(_)) => (root, false),
-                (Err(_), Ok(root)) => (root, true),
-                (Ok(root0), Ok(root1)) => {
-                    match root0.id_tracker.newest.cmp(&root1.id_tracker.newest) {
-                        Ordering::Equal => return Err(AllocError::DataFormat(FormatError::DuplicateIds)),
-                        Ordering::Greater => (root0, false),
-                        Ordering::Less => (root1, true),
-                    }
-                }
-            }
-        };
-        let commit_id = root.id_tracker.checkout();
+        // Freeing the tail page while the block is held is deferred, so the writer can't reuse it
+        write.0.mark_dirty(tail);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            write.0.check_dirty_unreachable()
+        }));
+        assert!(res.is_err());
+        write.0.dirty.clear();
 
-        let write_root_checkout = if is_new {
-            RootCheckout {
-                id: root.id_tracker.newest,
-                root: Vec::new(),
-                freelist: ROOT_MAP_SIZE as u64,
-            }
-        }
-        else {
-            RootCheckout {
-                id: root.id_tracker.newest,
-                root: root.root.clone(),
-                freelist: root.freelist,
-            }
-        };
+        // Still pinned across transactions until the block drops, then released by the next one
+        let (unit, _) = write.commit(b"");
+        let write = unit.write();
+        assert!(write.0.taken.contains(&tail));
+        drop(block);
+        let (unit, _) = write.commit(b"");
+        let write = unit.write();
+        assert!(!write.0.taken.contains(&start));
+        assert!(!write.0.taken.contains(&tail));
+    }
 
-        let core = Arc::new(DbCore {
-            root: Mutex::new(root),
-            read_pages: Mutex::new(PageReadTracker::default()),
-            storage: Mutex::new(storage),
-        });
+    #[test]
+    fn read_checkin_errors() {
+        let page = |i: usize| ROOT_MAP_SIZE + i * PAGE_SIZE;
+        let mut tracker = PageReadTracker::default();
+        let both = BlockRange::new(page(0), 2 * PAGE_SIZE);
+        tracker.checkout(both);
+        tracker.checkout(BlockRange::new(page(1), PAGE_SIZE));
+        assert_eq!(tracker.outstanding(), 3);
+        assert!(tracker.contains(page(0) as u64) && tracker.contains(page(1) as u64));
 
-        let (alloc_send, alloc_recv) = mpsc::channel();
-        let (write_hole_punch_req, commit_hole_punch_req) = mpsc::channel();
-        let (commit_hole_punch_resp, write_hole_punch_resp) = mpsc::channel();
+        // Checking in twice fails, and leaves the pages that are still out alone
+        tracker.checkin(both).unwrap();
+        let err = tracker.checkin(both).unwrap_err();
+        assert_eq!(err.kind(), AllocErrorKind::Internal);
+        assert!(!tracker.contains(page(0) as u64));
+        assert!(tracker.contains(page(1) as u64));
+        assert_eq!(tracker.outstanding(), 1);
 
-        let write = WriteTxn(WriteUnitInner {
-            taken: BTreeSet::new(),
-            core: core.clone(),
-            root: write_root_checkout,
-            dirty: BTreeSet::new(),
-            taken_txn: BTreeSet::new(),
-            available_4k: Vec::new(),
-            available_16k: Vec::new(),
-            available_blocks: Vec::new(),
-            alloc_req: Vec::new(),
-            alloc_completions: Vec::new(),
-            alloc_send,
-            alloc_recv,
-            hole_punch_req: write_hole_punch_req,
-            hole_punch_resp: write_hole_punch_resp,
-            hole_punch_future_req: Vec::new(),
-        });
+        // As does checking in a page that was never checked out
+        let never = BlockRange::new(page(5), PAGE_SIZE);
+        assert_eq!(
+            tracker.checkin(never).unwrap_err().kind(),
+            AllocErrorKind::Internal
+        );
 
-        if is_new {
-            // If we're brand new, forcibly set up our freelist and then populate in our initial pages
-            todo!("Set up the freelist");
-            /*
-            if page_size::get() == PAGE_SIZE {
-                for page in ((ROOT_MAP_SIZE as u64)..(BLOCK_SIZE as u64)).step_by(PAGE_SIZE) {
-                    write.0.available_4k.push(page);
-                }
-            }
-            else {
-                for page in ((ROOT_MAP_SIZE as u64)..(BLOCK_SIZE as u64)).step_by(CLUSTER_SIZE) {
-                    write.0.available_16k.push(page);
-                }
-            }
-            for page in ((BLOCK_SIZE as u64)..(requested_size as u64)).step_by(BLOCK_SIZE) {
-                write.0.available_blocks.push(page);
-            }
-            */
-        }
-        else if requested_size != file_size {
-            // If we're not brand new, and our 
+        // A block whose pages were already checked in hands its error to the writer on drop,
+        // instead of panicking while holding the tracker
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone());
+        let write = WriteUnit(test_writer_on(&core, &OpenOptions::default()).unwrap());
+        let mut txn = read.reader();
+        let range = BlockRange::new(page(0), PAGE_SIZE);
+        let block = unsafe { txn.get_block(range) }.unwrap();
+        core.read_pages.lock().unwrap().checkin(range).unwrap();
+        drop(block);
+        assert!(!core.read_pages.is_poisoned());
+        let err = write.take_checkin_error().unwrap();
+        assert_eq!(err.kind(), AllocErrorKind::Internal);
+        assert!(write.take_checkin_error().is_none());
+    }
 
-        }
-        // If the actual file size
+    #[test]
+    fn advice_hints() {
+        use std::io::Write;
 
-        let read = ReadUnit {
-            storage: read_storage,
-            core: core.clone(),
-        };
+        let path = TempPath::new("advice");
+        let (read, unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+        let mut write = unit.write();
+        write.new_allocation(BLOCK_SIZE as u64).unwrap();
+        let (unit, mut allocs) = write.commit(b"");
+        commit.commit().unwrap();
 
-        let commit = CommitUnit {
-            id: commit_id,
-            commit_data: Vec::new(),
-            hole_punch_req: commit_hole_punch_req,
-            hole_punch_resp: commit_hole_punch_resp,
-            root0: commit_root0,
-            root1: commit_root1,
-            write_root0: commit_write_root0,
-            core,
-        };
+        // Dropping a written allocation's pages from memory leaves what was written in place
+        let mut alloc = allocs.pop().unwrap();
+        alloc.advise_willneed().unwrap();
+        alloc.writer().write_all(b"hinted").unwrap();
+        alloc.advise_dontneed().unwrap();
+        assert_eq!(&alloc[..6], b"hinted");
+        let placed = alloc.alloc();
+        let mut write = unit.write();
+        write.use_allocation(alloc).unwrap();
+        let (unit, _) = write.commit(b"");
+        commit.commit().unwrap();
+
+        // Reading it back through with the read-ahead hint finds it there too
+        let mut txn = read.reader();
+        let (page, num_pages) = (placed.page.get(), placed.len / PAGE_SIZE);
+        txn.advise_sequential(page, num_pages).unwrap();
+        assert_eq!(&txn.read_pages(page, num_pages).unwrap()[..6], b"hinted");
+
+        // Hints are checked like reads are
+        assert_eq!(
+            txn.advise_sequential(0, 1).unwrap_err().kind(),
+            AllocErrorKind::RootAccess
+        );
+        let end = txn.root.file_len;
+        assert_eq!(
+            txn.advise_sequential(end - PAGE_SIZE as u64, 2)
+                .unwrap_err()
+                .kind(),
+            AllocErrorKind::InvalidAccess
+        );
+
+        // Anonymous maps keep their contents, as there's no file to read them back from
+        let (_read, unit, _commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let mut write = unit.write();
+        write.new_allocation(PAGE_SIZE as u64).unwrap();
+        let (_unit, mut allocs) = write.commit(b"");
+        let mut alloc = allocs.pop().unwrap();
+        alloc.writer().write_all(b"kept").unwrap();
+        alloc.advise_dontneed().unwrap();
+        assert_eq!(&alloc[..4], b"kept");
+    }
 
-        // Determine if we have
+    #[test]
+    fn id_tracker_interleaved() {
+        let mut ids = IdTracker::new(3);
+        assert_eq!(ids.checkout(), 3);
+        ids.set_newest(4);
+        assert_eq!(ids.checkout(), 4);
+        assert_eq!(ids.checkout(), 4);
+        ids.set_newest(5);
+        assert_eq!(ids.checkout(), 5);
+        ids.pin(3);
+        assert_eq!(ids.outstanding(), 5);
+        assert!(ids.is_pinned(3) && ids.is_pinned(4) && ids.is_pinned(5));
+        assert!(!ids.is_pinned(2));
 
-        //let root = RootData::load()
-        todo!()
+        // Checking in doesn't move the oldest ID until the last checkout at it goes
+        ids.checkin(4);
+        ids.checkin(3);
+        assert_eq!(ids.oldest_id(), 3);
+        ids.checkin(3);
+        assert!(!ids.is_pinned(3));
+        assert_eq!(ids.oldest_id(), 4);
+
+        // Dropping an ID that isn't the oldest leaves the oldest where it was
+        ids.set_newest(6);
+        ids.checkin(5);
+        assert_eq!(ids.oldest_id(), 4);
+        assert_eq!(ids.checkout_at(4), 4);
+        ids.checkin(4);
+        ids.checkin(4);
+        assert_eq!(ids.outstanding(), 0);
+        assert_eq!(ids.oldest_id(), 6);
+        assert_eq!(ids.newest_id(), 6);
     }
-}
 
-pub fn alloc_anon(size: usize) -> Result<AllocTuple, AllocError> {
-    OpenOptions::default().size(size).open_anon()
+    #[test]
+    #[should_panic(expected = "never checked out")]
+    fn id_tracker_unknown_checkin() {
+        let mut ids = IdTracker::new(1);
+        ids.checkout();
+        ids.checkin(2);
+    }
 }
 
-pub fn alloc_open<P: AsRef<Path>>(path: P) -> Result<AllocTuple, AllocError> {
-    OpenOptions::default().open(path)
-}
+/// Which handles can be sent to or shared between other threads. Everything on the read side can
+/// go anywhere. The writer and committer can each move to a thread of their own but are never
+/// shared, and allocations can be filled from many threads at once. With `single-threaded`, the
+/// write side stays on the thread that opened the database.
+#[cfg(test)]
+mod trait_assertions {
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
 
-// Page numbers are up to 6 bytes - the upper 2 bytes are for other shit.
-// For the root page, the entry format is:
-// 6 bytes pointing to the sub-page
-// 2 bytes indicating # of entries in page, but uppermost bit indicates if leaf of branch.
-// 8 bytes of xxhash
+    use super::*;
 
-// The root page of the allocator is:
-// - 16-byte entries for each possible sub-page - there are 47 total (6 bytes of page number, highest can never exist)
-// - 16-byte header for the "to-free" list
-// - 16-byte entries pointing to each "to-free" list
+    assert_impl_all!(ReadUnit: Send, Sync);
+    assert_impl_all!(ReadTxn: Send, Sync);
+    assert_impl_all!(ReadBlock: Send, Sync);
+    assert_impl_all!(Block: Send, Sync);
+    assert_impl_all!(OwnedBlock: Send, Sync);
 
-// Each freelist is actually a btreemap, potentially terminating
+    #[cfg(not(feature = "single-threaded"))]
+    assert_impl_all!(WriteUnit: Send);
+    #[cfg(not(feature = "single-threaded"))]
+    assert_impl_all!(WriteTxn: Send);
+    #[cfg(not(feature = "single-threaded"))]
+    assert_impl_all!(CommitUnit: Send);
+    #[cfg(not(feature = "single-threaded"))]
+    assert_impl_all!(WriteAlloc: Send, Sync);
+    #[cfg(not(feature = "single-threaded"))]
+    assert_impl_all!(CommitHandle: Send, Sync);
+    assert_not_impl_any!(WriteUnit: Sync);
+    assert_not_impl_any!(WriteTxn: Sync);
+    assert_not_impl_any!(CommitUnit: Sync);
 
-pub struct Allocator {}
+    #[cfg(feature = "single-threaded")]
+    assert_not_impl_any!(WriteUnit: Send);
+    #[cfg(feature = "single-threaded")]
+    assert_not_impl_any!(WriteTxn: Send);
+    #[cfg(feature = "single-threaded")]
+    assert_not_impl_any!(CommitUnit: Send);
+    #[cfg(feature = "single-threaded")]
+    assert_not_impl_any!(WriteAlloc: Send, Sync);
 
-pub struct AllocInfo {
-    addr: u64,
-    pages: usize,
+    // Raw page views have no lifetime tying them to the map, so they stay where they were made
+    assert_not_impl_any!(IntPage: Send, Sync);
+    assert_not_impl_any!(int_page::OccupiedEntry<'static>: Send, Sync);
+    assert_not_impl_any!(int_page::VacantEntry<'static>: Send, Sync);
 }
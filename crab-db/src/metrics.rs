@@ -0,0 +1,41 @@
+use std::{fmt, sync::Arc};
+
+/// Hooks for observing the allocator without pulling in a logging or metrics dependency.
+///
+/// Every method has a no-op default, so implementors only need to override the events they care
+/// about. Hooks are called synchronously from whichever thread triggered the event, so they should
+/// return quickly.
+pub trait MetricsHook: Send + Sync {
+    /// A write transaction's bookkeeping structures are using more memory than the configured
+    /// budget (see [`OpenOptions::txn_memory_budget`][crate::OpenOptions::txn_memory_budget]),
+    /// even after spilling what could be spilled. The transaction keeps going regardless.
+    fn txn_memory_over_budget(&self, used: usize, budget: usize) {
+        let _ = (used, budget);
+    }
+}
+
+/// A cloneable handle to a user-provided [`MetricsHook`].
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Option<Arc<dyn MetricsHook>>);
+
+impl Metrics {
+    pub fn new(hook: Option<Arc<dyn MetricsHook>>) -> Self {
+        Self(hook)
+    }
+
+    pub fn txn_memory_over_budget(&self, used: usize, budget: usize) {
+        if let Some(hook) = &self.0 {
+            hook.txn_memory_over_budget(used, budget);
+        }
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "Metrics(Some(..))"
+        } else {
+            "Metrics(None)"
+        })
+    }
+}
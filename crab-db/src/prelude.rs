@@ -0,0 +1,117 @@
+//! The supported API in one import.
+//!
+//! Each version of the prelude is frozen once released: [`v1`] only ever gains items in a
+//! compatible way, and anything that would break it goes in a new version instead. Glob-importing
+//! `crab_db::prelude::*` always gets the newest one.
+//!
+//! With the `btree` feature, the prelude also brings in the [`crab_dads`] B-tree and page layout
+//! types needed to build trees on top of the allocator.
+
+/// The first version of the prelude.
+pub mod v1 {
+    #[cfg(feature = "btree")]
+    pub use crab_dads::{
+        btree::{BTreeConfig, BTreeRead, BTreeWrite, RawRead, RawWrite},
+        page::{LayoutU64U64, LayoutU64Var, LayoutVarU64, PageLayout},
+    };
+    #[cfg(feature = "btree")]
+    pub use crate::TxnWriter;
+
+    pub use crate::{
+        Alloc, AllocError, AllocErrorKind, Block, CommitPolicy, CommitUnit, FormatError,
+        FormatErrorKind, OpenOptions, OwnedBlock, PreparedCommit, ReadTxn, ReadUnit, SyncMode,
+        WriteAlloc, WriteTxn, WriteUnit,
+    };
+    #[cfg(not(feature = "single-threaded"))]
+    pub use crate::{CommitHandle, CommitTicket};
+}
+
+pub use v1::*;
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    /// Every public item declared at the top of `src`, one per line, with the `cfg` attribute
+    /// gating it if there is one. Only the crate root and the prelude are looked at: the modules
+    /// they expose are listed by name, so adding one shows up here.
+    fn public_items(src: &str, top_level_only: bool) -> Vec<String> {
+        let mut items = Vec::new();
+        let mut cfg = None;
+        let mut lines = src.lines();
+        while let Some(line) = lines.next() {
+            let trimmed = line.trim_start();
+            if top_level_only && trimmed.len() != line.len() {
+                continue;
+            }
+            if trimmed.starts_with("#[cfg(") {
+                cfg = Some(trimmed.to_string());
+                continue;
+            }
+            if !trimmed.starts_with("pub ") {
+                if !trimmed.starts_with("#[") && !trimmed.starts_with("//") {
+                    cfg = None;
+                }
+                continue;
+            }
+            let mut item = trimmed.to_string();
+            if item.starts_with("pub use") {
+                // Grouped imports can span several lines
+                while !item.ends_with(';') {
+                    let Some(next) = lines.next() else {
+                        break;
+                    };
+                    item.push(' ');
+                    item.push_str(next.trim());
+                }
+                item = item
+                    .replace("{ ", "{")
+                    .replace(", }", "}")
+                    .replace(",}", "}");
+            } else {
+                let end = item
+                    .find(['(', '<', ':', '=', '{', ';'])
+                    .unwrap_or(item.len());
+                item.truncate(end);
+            }
+            let item = item.trim_end_matches(';').trim_end().to_string();
+            items.push(match cfg.take() {
+                Some(cfg) => format!("{cfg} {item}"),
+                None => item,
+            });
+        }
+        items
+    }
+
+    /// The public API is pinned by a fixture, so anything exposed or removed by accident fails
+    /// here. Rerun with `UPDATE_FIXTURES` set to accept a deliberate change.
+    #[test]
+    fn public_api_snapshot() {
+        let mut api = public_items(include_str!("lib.rs"), true);
+        api.extend(public_items(include_str!("prelude.rs"), false));
+        let api = api.join("\n") + "\n";
+
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join("public-api.txt");
+        if std::env::var_os("UPDATE_FIXTURES").is_some() {
+            std::fs::write(&path, &api).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("couldn't read fixture {}: {e}", path.display()));
+        let added: Vec<&str> = api
+            .lines()
+            .filter(|l| !expected.lines().any(|e| e == *l))
+            .collect();
+        let removed: Vec<&str> = expected
+            .lines()
+            .filter(|e| !api.lines().any(|l| l == *e))
+            .collect();
+        assert!(
+            added.is_empty() && removed.is_empty(),
+            "the public API changed.\nadded: {added:#?}\nremoved: {removed:#?}\nIf this is \
+             intentional, rerun with UPDATE_FIXTURES=1 and commit the new fixture."
+        );
+    }
+}
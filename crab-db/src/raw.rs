@@ -0,0 +1,235 @@
+//! Glue between the transactions and crab-dads' [`RawRead`] and [`RawWrite`] traits, so B-trees
+//! can be built straight on top of the allocator. Page numbers are byte offsets into the file, as
+//! everywhere else in this crate.
+
+use std::cell::RefCell;
+
+use crab_dads::{
+    btree::{LoadMut, RawRead, RawWrite},
+    StorageError,
+};
+
+use crate::{data_range, AllocError, AllocErrorKind, ReadTxn, WriteTxn, PAGE_SIZE};
+
+/// The closest [`StorageError`] to an allocator error from a request at `page`. Storage errors
+/// can't carry any details, so those are lost.
+fn storage_error(page: u64, e: AllocError) -> StorageError {
+    match e.kind() {
+        // Page numbers all come off of pages the tree wrote, so a misaligned one means damaged data
+        AllocErrorKind::Misaligned => StorageError::Corruption("page number isn't page-aligned"),
+        AllocErrorKind::RootAccess
+        | AllocErrorKind::RangeOverflow
+        | AllocErrorKind::ReservedPageBits
+        | AllocErrorKind::InvalidAccess => StorageError::OutOfRange(page),
+        AllocErrorKind::DataFormat(_) => StorageError::Corruption("allocator metadata is corrupt"),
+        AllocErrorKind::MutexPoisoned => StorageError::Safety("allocator lock was poisoned"),
+        AllocErrorKind::NotOwned | AllocErrorKind::DoubleFree => {
+            StorageError::Safety("pages aren't allocated to this transaction")
+        }
+        AllocErrorKind::NoSpace | AllocErrorKind::DatabaseFull => {
+            StorageError::Io("database is out of space")
+        }
+        _ => StorageError::Io("allocator request failed"),
+    }
+}
+
+// Safety: a reader only gets at pages within the file as of the transaction it checked out, which
+// the writer won't reuse until the reader is gone. Every range starts on a page boundary, and the
+// maps themselves are page-aligned.
+unsafe impl RawRead for ReadTxn {
+    /// Load pages like [`read_pages`][ReadTxn::read_pages] does, though without counting towards
+    /// the read statistics, as only shared access is available.
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        let range =
+            data_range(page, num_pages, self.root.file_len).map_err(|e| storage_error(page, e))?;
+        #[cfg(feature = "read-cache")]
+        if let Some(mem) = self.cache.as_ref().and_then(|c| c.get(self.root.id, range)) {
+            return Ok(mem);
+        }
+        let mem = unsafe { self.storage.get_const(&self.core, range) }
+            .map_err(|e| storage_error(page, e))?;
+        // Safety: we're reading in our own generation, and we're still alive.
+        #[cfg(feature = "read-cache")]
+        if let Some(cache) = &self.cache {
+            unsafe { cache.insert(self.root.id, range, mem) };
+        }
+        Ok(mem)
+    }
+
+    fn generation(&self) -> u64 {
+        self.root.id
+    }
+}
+
+/// Lets crab-dads B-trees write through a [`WriteTxn`].
+///
+/// New pages come from [`WriteTxn::txn_allocate`], and are the only ones ever loaded as
+/// [`LoadMut::Dirty`]. Loading any other page for writing copies it to a freshly allocated one
+/// instead, leaving the original for readers, so nothing a reader can see is ever written to.
+/// Freed pages go through [`WriteTxn::txn_free`]: ones this transaction allocated are free again
+/// right away, and the rest once every reader that could see them is gone.
+///
+/// The tree's root page and anything else needed to find it again go in the root data when the
+/// transaction commits, see [`WriteTxn::set_root_data`].
+///
+/// ```
+/// use crab_db::prelude::*;
+/// use crab_dads::{btree::Entry, format::PAGE_TYPE_LEAF, page::PageMapMut};
+///
+/// let (read, write, mut commit) = OpenOptions::default().open_anon().unwrap();
+/// let mut txn = write.write();
+/// let writer = TxnWriter::new(&mut txn);
+///
+/// // Start the tree off with an empty leaf as its root
+/// let (page, root) = writer.allocate_page().unwrap();
+/// PageMapMut::<LayoutU64Var>::new(page, PAGE_TYPE_LEAF);
+/// let (mut tree, moved) = unsafe {
+///     BTreeWrite::<LayoutU64U64, LayoutU64Var, _>::load(&writer, root).unwrap()
+/// };
+/// assert!(moved.is_none());
+/// for i in 0..10_000u64 {
+///     let Entry::Vacant(entry) = tree.entry(&i).unwrap() else {
+///         panic!("key {i} was already in the tree");
+///     };
+///     entry.insert(&i.to_le_bytes()).unwrap();
+/// }
+/// drop(tree);
+/// drop(writer);
+/// txn.set_root_data(&root.to_le_bytes()).unwrap();
+/// let (_write, _) = txn.commit_root_data();
+/// commit.commit().unwrap();
+///
+/// // Readers find the tree through the root data
+/// let reader = read.reader();
+/// let root = u64::from_le_bytes(reader.root_data().try_into().unwrap());
+/// let tree = unsafe { BTreeRead::<LayoutU64U64, LayoutU64Var, _>::load(&reader, root).unwrap() };
+/// for i in 0..10_000u64 {
+///     assert_eq!(tree.get(&i).unwrap(), Some(&i.to_le_bytes()[..]));
+/// }
+/// ```
+pub struct TxnWriter<'a> {
+    txn: RefCell<&'a mut WriteTxn>,
+}
+
+impl<'a> TxnWriter<'a> {
+    /// Write through `txn` until this is dropped.
+    pub fn new(txn: &'a mut WriteTxn) -> Self {
+        Self {
+            txn: RefCell::new(txn),
+        }
+    }
+}
+
+// Safety: same as for the reader, with pages checked against the file length as it is in this
+// transaction, which may have grown.
+unsafe impl RawRead for TxnWriter<'_> {
+    unsafe fn load(&self, page: u64, num_pages: usize) -> Result<&[u8], StorageError> {
+        let txn = self.txn.borrow();
+        let w = &txn.0;
+        let range =
+            data_range(page, num_pages, w.root.file_len).map_err(|e| storage_error(page, e))?;
+        unsafe { w.storage.get_const(&w.core, range) }.map_err(|e| storage_error(page, e))
+    }
+
+    fn generation(&self) -> u64 {
+        self.txn.borrow().0.root.id
+    }
+}
+
+// Safety: only pages allocated by this transaction are handed out for writing, and those came off
+// the availability lists, so no reader can reach them. Everything else is copied first.
+unsafe impl RawWrite for TxnWriter<'_> {
+    unsafe fn load_mut(&self, page: u64, num_pages: usize) -> Result<LoadMut<'_>, StorageError> {
+        {
+            let mut txn = self.txn.borrow_mut();
+            let w = &mut txn.0;
+            let range =
+                data_range(page, num_pages, w.root.file_len).map_err(|e| storage_error(page, e))?;
+            if w.dirty.contains_range(page, range.len as u64) {
+                let write =
+                    unsafe { w.storage.get(&w.core, range) }.map_err(|e| storage_error(page, e))?;
+                return Ok(LoadMut::Dirty(write));
+            }
+        }
+        let read = unsafe { self.load(page, num_pages)? };
+        let (write, write_page) = self.allocate(num_pages)?;
+        Ok(LoadMut::Clean {
+            write,
+            write_page,
+            read,
+        })
+    }
+
+    fn allocate(&self, num_pages: usize) -> Result<(&mut [u8], u64), StorageError> {
+        let mut txn = self.txn.borrow_mut();
+        let alloc = txn
+            .txn_allocate((num_pages * PAGE_SIZE) as u64)
+            .map_err(|e| storage_error(0, e))?;
+        let page = alloc.page.get();
+        let range = alloc.to_range().map_err(|e| storage_error(page, e))?;
+        let w = &mut txn.0;
+        match unsafe { w.storage.get(&w.core, range) } {
+            Ok(write) => Ok((write, page)),
+            Err(e) => {
+                // Never handed out, so it can go straight back
+                let _ = txn.txn_free(page, alloc.len);
+                Err(storage_error(page, e))
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, page: u64, num_pages: usize) -> Result<(), StorageError> {
+        self.txn
+            .borrow_mut()
+            .txn_free(page, num_pages * PAGE_SIZE)
+            .map_err(|e| storage_error(page, e))
+    }
+
+    /// Frees the whole batch with [`WriteTxn::txn_free_batch`], so adjacent regions are freed as
+    /// one run.
+    unsafe fn deallocate_batch(
+        &self,
+        pages: &mut dyn Iterator<Item = (u64, usize)>,
+    ) -> Result<(), StorageError> {
+        let pages: Vec<(u64, usize)> = pages.collect();
+        let first = pages.iter().map(|(page, _)| *page).min().unwrap_or(0);
+        self.txn
+            .borrow_mut()
+            .txn_free_batch(pages.into_iter().map(|(page, n)| (page, n * PAGE_SIZE)))
+            .map_err(|e| storage_error(first, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crab_dads::{
+        btree::{RawRead, RawWrite},
+        StorageError,
+    };
+
+    use crate::{alloc_anon, TxnWriter, MIN_DB_SIZE, ROOT_MAP_SIZE};
+
+    fn corrupt<T>(res: Result<T, StorageError>) -> bool {
+        matches!(res, Err(StorageError::Corruption(_)))
+    }
+
+    #[test]
+    fn misaligned_pages() {
+        let (read, unit, _commit) = alloc_anon(MIN_DB_SIZE).unwrap();
+        let reader = read.reader();
+        let mut txn = unit.write();
+        let writer = TxnWriter::new(&mut txn);
+        let (_, page) = writer.allocate(1).unwrap();
+
+        // A page number off of a page boundary is refused as damaged data, whichever way it's
+        // used, instead of handing out misaligned memory
+        unsafe {
+            assert!(writer.load(page, 1).is_ok());
+            assert!(corrupt(writer.load(page + 8, 1)));
+            assert!(corrupt(writer.load_mut(page + 8, 1)));
+            assert!(corrupt(writer.deallocate(page + 8, 1)));
+            assert!(reader.load(ROOT_MAP_SIZE as u64, 1).is_ok());
+            assert!(corrupt(reader.load(ROOT_MAP_SIZE as u64 + 8, 1)));
+        }
+    }
+}
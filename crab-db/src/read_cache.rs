@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::{BlockRange, DbCore, PAGE_SIZE};
+
+/// A read cache shared by every [`ReadTxn`][crate::ReadTxn] spawned from a
+/// [`ReadUnit`][crate::ReadUnit], remembering where in the memory maps each `(generation, range)`
+/// lookup landed.
+///
+/// Only that lookup is saved: finding the map holding a range, which means locking the storage if
+/// it has grown since the transaction started. The pages are still read straight out of the
+/// maps, and a B-tree descending through a transaction still loads every page on its way down,
+/// cached or not. When the storage hasn't grown, finding the map is cheaper than the cache's own
+/// lock, so the cache only pays off for readers that keep running into new maps. The
+/// `point_lookup` benchmark in the workspace tests compares the two.
+///
+/// Entries are keyed by generation, so a transaction is never handed memory from a different
+/// snapshot. Only the newest generation read through the cache is kept, bounded by page count
+/// and evicting the least recently used ranges first. That generation stays checked out while
+/// it's cached, just like an open read transaction, so the pages it points to can't be freed and
+/// reused. Spawning a reader on a newer generation releases it, so the cache never holds back
+/// more than one generation. Until then an idle cache keeps it, unless [`clear`][Self::clear]ed.
+pub struct ReadCache {
+    core: Arc<DbCore>,
+    max_pages: usize,
+    inner: Mutex<CacheInner>,
+}
+
+/// A cached range of memory.
+struct CacheEntry {
+    mem: &'static [u8],
+    /// Most recent use, as a key into the LRU list
+    tick: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// The generation everything cached belongs to, which we hold a checkout on
+    generation: Option<u64>,
+    /// Cached memory, keyed by range start and length
+    entries: HashMap<(usize, usize), CacheEntry>,
+    /// Cache keys in order of most recent use
+    lru: BTreeMap<u64, (usize, usize)>,
+    tick: u64,
+    /// Total pages currently cached
+    pages: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl ReadCache {
+    pub(crate) fn new(core: Arc<DbCore>, max_pages: usize) -> Self {
+        Self {
+            core,
+            max_pages,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Maximum number of pages this cache will hold.
+    pub fn max_pages(&self) -> usize {
+        self.max_pages
+    }
+
+    /// Number of pages currently cached.
+    pub fn pages(&self) -> usize {
+        self.inner.lock().unwrap().pages
+    }
+
+    /// Number of lookups that were served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.inner.lock().unwrap().hits
+    }
+
+    /// Number of lookups that had to go to the memory maps.
+    pub fn misses(&self) -> u64 {
+        self.inner.lock().unwrap().misses
+    }
+
+    /// Drop every cached range and release the generation the cache was holding onto.
+    pub fn clear(&self) {
+        self.release(&mut self.inner.lock().unwrap());
+    }
+
+    /// Release the cached generation if `generation` is newer, as a reader has moved on to it.
+    pub(crate) fn advance(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation.is_some_and(|cached| cached < generation) {
+            self.release(&mut inner);
+        }
+    }
+
+    /// Look up a range previously read in the given generation.
+    pub(crate) fn get(&self, generation: u64, range: BlockRange) -> Option<&'static [u8]> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let key = (range.start, range.len);
+        let entry = match inner.generation {
+            Some(cached) if cached == generation => inner.entries.get_mut(&key),
+            _ => None,
+        };
+        let Some(entry) = entry else {
+            inner.misses += 1;
+            return None;
+        };
+        inner.hits += 1;
+        inner.lru.remove(&entry.tick);
+        inner.tick += 1;
+        entry.tick = inner.tick;
+        inner.lru.insert(inner.tick, key);
+        Some(entry.mem)
+    }
+
+    /// Remember a range read in the given generation. Ranges from a generation older than the
+    /// cached one are left out, and ones from a newer generation replace everything cached.
+    ///
+    /// # Safety
+    ///
+    /// `mem` must be the memory for `range` as seen by a read transaction on `generation`, and
+    /// that read transaction must still be alive, so the generation is still checked out.
+    pub(crate) unsafe fn insert(&self, generation: u64, range: BlockRange, mem: &'static [u8]) {
+        let pages = range.len.div_ceil(PAGE_SIZE);
+        if pages > self.max_pages {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match inner.generation {
+            Some(cached) if cached > generation => return,
+            Some(cached) if cached < generation => self.release(&mut inner),
+            _ => (),
+        }
+        // Hold onto the generation so its pages can't be reused while cached
+        if inner.generation.is_none() {
+            self.core.root.lock().unwrap().id_tracker.pin(generation);
+            inner.generation = Some(generation);
+        }
+        let key = (range.start, range.len);
+        if inner.entries.contains_key(&key) {
+            return;
+        }
+
+        // Make room
+        while inner.pages + pages > self.max_pages {
+            let Some((_, old)) = inner.lru.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&old);
+            inner.pages -= old.1.div_ceil(PAGE_SIZE);
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, key);
+        inner.entries.insert(key, CacheEntry { mem, tick });
+        inner.pages += pages;
+    }
+
+    /// Drop everything cached and check the generation back in.
+    fn release(&self, inner: &mut CacheInner) {
+        inner.entries.clear();
+        inner.lru.clear();
+        inner.pages = 0;
+        if let Some(generation) = inner.generation.take() {
+            self.core
+                .root
+                .lock()
+                .unwrap()
+                .id_tracker
+                .checkin(generation);
+        }
+    }
+}
+
+impl Drop for ReadCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        run_set::RunSet,
+        tests::{test_core, test_storage},
+        ByteOffset, ReadUnit, RootCheckout, MIN_DB_SIZE, ROOT_MAP_SIZE,
+    };
+
+    #[test]
+    fn generation_invalidation() {
+        fn assert_shareable<T: Send + Sync>() {}
+        assert_shareable::<ReadCache>();
+
+        let core = test_core();
+        let read = ReadUnit::new(test_storage(&core), core.clone()).with_read_cache(4);
+        let cache = read.read_cache().unwrap();
+        let ranges: Vec<BlockRange> = (0..6)
+            .map(|i| BlockRange::new(ROOT_MAP_SIZE + i * PAGE_SIZE, PAGE_SIZE))
+            .collect();
+
+        // The first transaction fills the cache, and the next one on the same generation reuses it
+        let mut txn = read.reader();
+        let generation = txn.generation();
+        let mem = unsafe { txn.read(ranges[0]).unwrap() };
+        unsafe { txn.read(ranges[1]).unwrap() };
+        drop(txn);
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        let mut txn = read.reader();
+        assert_eq!(
+            unsafe { txn.read(ranges[0]).unwrap() }.as_ptr(),
+            mem.as_ptr()
+        );
+        unsafe { txn.read(ranges[1]).unwrap() };
+        drop(txn);
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+        assert_eq!(cache.pages(), 2);
+
+        // The cache keeps the generation checked out after its readers are gone
+        let mut stale = read.reader();
+        let update = RootCheckout {
+            id: generation + 1,
+            root: Arc::default(),
+            freelist: ByteOffset::default(),
+            file_len: MIN_DB_SIZE as u64,
+        };
+        core.root.lock().unwrap().update(&update, RunSet::new());
+        drop(stale);
+        assert_eq!(core.root.lock().unwrap().id_tracker.oldest_id(), generation);
+        stale = read.reader_durable();
+        assert_eq!(stale.generation(), generation);
+
+        // A reader on a newer generation releases it, and never sees the old entries
+        let mut txn = read.reader();
+        assert_eq!(txn.generation(), generation + 1);
+        assert_eq!(cache.pages(), 0);
+        unsafe { txn.read(ranges[0]).unwrap() };
+        assert_eq!((cache.hits(), cache.misses()), (2, 3));
+
+        // Nor does a reader still on the old one get it cached again
+        unsafe { stale.read(ranges[1]).unwrap() };
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+        assert_eq!(cache.pages(), 1);
+        drop(stale);
+        assert_eq!(
+            core.root.lock().unwrap().id_tracker.oldest_id(),
+            generation + 1
+        );
+
+        // Filling up the cache evicts the least recently used range
+        for range in &ranges[1..5] {
+            unsafe { txn.read(*range).unwrap() };
+        }
+        assert_eq!(cache.pages(), 4);
+        unsafe { txn.read(ranges[4]).unwrap() };
+        unsafe { txn.read(ranges[0]).unwrap() };
+        assert_eq!((cache.hits(), cache.misses()), (3, 9));
+        drop(txn);
+        assert_eq!(
+            core.root.lock().unwrap().id_tracker.oldest_id(),
+            generation + 1
+        );
+
+        // Clearing releases everything
+        cache.clear();
+        assert_eq!(cache.pages(), 0);
+        assert_eq!(core.root.lock().unwrap().id_tracker.outstanding(), 0);
+    }
+}
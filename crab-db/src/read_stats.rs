@@ -0,0 +1,60 @@
+/// Only every `SAMPLE_INTERVAL`th read (must be a power of two) gets its residency checked, so the `mincore` calls don't
+/// dominate the cost of reading.
+const SAMPLE_INTERVAL: u64 = 64;
+
+/// At most this many bytes of a sampled read are checked for residency.
+const SAMPLE_MAX_LEN: usize = 64 * crate::PAGE_SIZE;
+
+/// Statistics on the reads made by a single [`ReadTxn`][crate::ReadTxn].
+///
+/// Residency is estimated by sampling a subset of reads with `mincore`. This is only done on
+/// Linux; elsewhere, the sample counts stay at zero.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// Number of reads from the memory maps
+    pub reads: u64,
+    /// Total number of bytes requested by those reads
+    pub bytes: u64,
+    /// Number of system pages checked for residency
+    pub sampled_pages: u64,
+    /// Number of the checked system pages that were already resident in memory
+    pub resident_pages: u64,
+}
+
+impl ReadStats {
+    /// Estimated fraction of the read working set that was already resident in memory, or `None`
+    /// if nothing has been sampled.
+    pub fn resident_fraction(&self) -> Option<f64> {
+        (self.sampled_pages > 0).then(|| self.resident_pages as f64 / self.sampled_pages as f64)
+    }
+
+    /// Record a read of the given memory.
+    pub(crate) fn record(&mut self, mem: &[u8]) {
+        self.reads += 1;
+        self.bytes += mem.len() as u64;
+        if ((self.reads - 1) & (SAMPLE_INTERVAL - 1)) == 0 {
+            self.sample(&mem[..mem.len().min(SAMPLE_MAX_LEN)]);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sample(&mut self, mem: &[u8]) {
+        if mem.is_empty() {
+            return;
+        }
+        // mincore wants an address aligned to the system page size, which may be larger than ours
+        let page_size = page_size::get();
+        let start = mem.as_ptr() as usize & !(page_size - 1);
+        let len = mem.as_ptr() as usize + mem.len() - start;
+        let mut vec = vec![0u8; len.div_ceil(page_size)];
+        let res = unsafe { libc::mincore(start as *mut libc::c_void, len, vec.as_mut_ptr()) };
+        if res != 0 {
+            return;
+        }
+        self.sampled_pages += vec.len() as u64;
+        self.resident_pages += vec.iter().filter(|v| (**v & 1) != 0).count() as u64;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sample(&mut self, _mem: &[u8]) {}
+}
@@ -0,0 +1,157 @@
+//! Recovery tools for databases whose allocator metadata has been damaged, but whose actual data
+//! is still intact.
+
+use crate::{run_set::RunSet, AllocError, WriteTxn, PAGE_SIZE, ROOT_MAP_SIZE};
+
+/// Summary of a freelist rebuild.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RebuildReport {
+    /// Bytes recorded as free after the rebuild
+    pub reclaimed: u64,
+    /// Bytes the old free lists claimed were free
+    pub previously_recorded: u64,
+}
+
+/// Throw out the writer's free lists and rebuild them from scratch, treating everything that isn't
+/// reachable as free.
+///
+/// `reachable` must cover every live range in the database as `(page, len)` byte pairs, typically
+/// gathered by walking every tree with `BTreeRead::verify`. Anything left out will be handed out
+/// again by the allocator and overwritten, so this must only be run when the caller's trees are
+/// known to be intact. Pages currently checked out by readers or pending write allocations are
+/// never treated as free.
+///
+/// The old freelist's pages are thrown in with the rest of the free space, and the next commit
+/// writes out a new one.
+pub fn rebuild_freelist(
+    txn: &mut WriteTxn,
+    reachable: impl Iterator<Item = (u64, usize)>,
+) -> Result<RebuildReport, AllocError> {
+    let w = &mut txn.0;
+    w.unspill_all();
+
+    let previously_recorded = w.available_bytes();
+
+    // Everything that must not be handed out: the root pages, the caller's live data, anything
+    // we already wrote in this transaction, and anything checked out elsewhere.
+    let mut used = RunSet::new();
+    used.insert_range(0, ROOT_MAP_SIZE as u64);
+    for (page, len) in reachable {
+        if (page as usize & (PAGE_SIZE - 1)) != 0 {
+            return Err(AllocError::Misaligned {
+                offset: page as usize,
+            });
+        }
+        let len = (len as u64).next_multiple_of(PAGE_SIZE as u64);
+        used.insert_range(page, len);
+    }
+    for (page, len) in w.dirty.runs().chain(w.taken_txn.runs()) {
+        used.insert_range(page, len);
+    }
+    for page in w.taken.iter() {
+        used.insert(*page);
+    }
+
+    // Work out the complement within the current file size.
+    let file_len = {
+        let Ok(storage) = w.core.storage.lock() else {
+            return Err(AllocError::MutexPoisoned);
+        };
+        unsafe { storage.get_maps() }
+            .iter()
+            .map(|m| m.len() as u64)
+            .sum::<u64>()
+    };
+    let mut free = Vec::new();
+    let mut cursor = 0;
+    for (start, len) in used.runs() {
+        if start >= file_len {
+            break;
+        }
+        if start > cursor {
+            free.push((cursor, start - cursor));
+        }
+        cursor = cursor.max(start + len);
+    }
+    if cursor < file_len {
+        free.push((cursor, file_len - cursor));
+    }
+
+    // Carve the free space up into the largest aligned pieces we can.
+    w.available_4k.clear();
+    w.available_16k.clear();
+    w.available_blocks.clear();
+    let mut reclaimed = 0;
+    for (start, len) in free {
+        w.add_free_run(start, len);
+        reclaimed += len;
+    }
+
+    w.freelist_pages.clear();
+
+    Ok(RebuildReport {
+        reclaimed,
+        previously_recorded,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{at, test_writer},
+        BlockRange, OpenOptions, BLOCK_SIZE, CLUSTER_SIZE, MIN_DB_SIZE,
+    };
+
+    #[test]
+    fn rebuild_after_corruption() {
+        let mut txn = WriteTxn(test_writer(&OpenOptions::default()));
+        let p = PAGE_SIZE as u64;
+
+        // Live data scattered through the first block, plus a whole block further out
+        let live = [
+            (ROOT_MAP_SIZE as u64, PAGE_SIZE),
+            (ROOT_MAP_SIZE as u64 + 5 * p, 3 * PAGE_SIZE),
+            (BLOCK_SIZE as u64, BLOCK_SIZE),
+        ];
+
+        // Corrupt free lists that claim live pages (and a bogus count of space) are free
+        txn.0.available_4k = vec![ROOT_MAP_SIZE as u64, ROOT_MAP_SIZE as u64 + 6 * p];
+        txn.0.available_blocks = vec![BLOCK_SIZE as u64; 4];
+        let report = rebuild_freelist(&mut txn, live.iter().copied()).unwrap();
+        assert_eq!(report.previously_recorded, 2 * p + 4 * BLOCK_SIZE as u64);
+
+        // Everything except the root pages and the live data is free
+        let live_bytes: u64 = live.iter().map(|(_, len)| *len as u64).sum();
+        assert_eq!(
+            report.reclaimed,
+            MIN_DB_SIZE as u64 - ROOT_MAP_SIZE as u64 - live_bytes
+        );
+
+        // Nothing handed back out overlaps with live data
+        let mut free = RunSet::new();
+        let lists = [
+            (&txn.0.available_4k, PAGE_SIZE),
+            (&txn.0.available_16k, CLUSTER_SIZE),
+            (&txn.0.available_blocks, BLOCK_SIZE),
+        ];
+        for (list, len) in lists {
+            for page in list.iter() {
+                let range = BlockRange::new(*page as usize, len);
+                assert!(range.is_aligned());
+                assert!((*page as usize & (len - 1)) == 0);
+                for (live, live_len) in live.iter() {
+                    assert!(*page + len as u64 <= *live || *live + *live_len as u64 <= *page);
+                }
+                assert!(free.insert_range(*page, len as u64));
+            }
+        }
+        assert_eq!(free.page_count() * p, report.reclaimed);
+
+        // The freshly rebuilt lists are usable for writing again
+        let page = txn.0.available_4k[0];
+        txn.0.mark_dirty(page);
+        txn.write_at(at(page), 0, b"alive").unwrap();
+        assert_eq!(txn.read_back(at(page), 0, 5).unwrap(), b"alive");
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use crate::PAGE_SIZE;
+
+/// A set of 4 kiB pages, stored as coalesced runs of contiguous pages.
+///
+/// Each entry maps the byte offset of the first page in a run to the byte offset just past the
+/// last page in the run. Sequential allocations thus collapse down to a single entry, no matter
+/// how many pages they cover.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RunSet {
+    runs: BTreeMap<u64, u64>,
+}
+
+impl RunSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a single page. Returns true if the page wasn't already present.
+    pub fn insert(&mut self, page: u64) -> bool {
+        self.insert_range(page, PAGE_SIZE as u64)
+    }
+
+    /// Insert a range of pages, given the starting byte offset and the length in bytes. Returns
+    /// true if any page in the range wasn't already present.
+    pub fn insert_range(&mut self, start: u64, len: u64) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let mut start = start;
+        let mut end = start + len;
+
+        // Merge with a run that ends at or after our start
+        if let Some((&s, &e)) = self.runs.range(..=start).next_back() {
+            if e >= end {
+                return false;
+            }
+            if e >= start {
+                start = s;
+                self.runs.remove(&s);
+            }
+        }
+
+        // Swallow any runs that begin inside of (or right at the end of) our new run
+        while let Some((&s, &e)) = self.runs.range(start..=end).next() {
+            self.runs.remove(&s);
+            end = end.max(e);
+        }
+
+        self.runs.insert(start, end);
+        true
+    }
+
+    /// Remove a single page. Returns true if the page was present.
+    pub fn remove(&mut self, page: u64) -> bool {
+        let Some((&s, &e)) = self.runs.range(..=page).next_back() else {
+            return false;
+        };
+        if page >= e {
+            return false;
+        }
+        self.runs.remove(&s);
+        if s < page {
+            self.runs.insert(s, page);
+        }
+        let next = page + PAGE_SIZE as u64;
+        if next < e {
+            self.runs.insert(next, e);
+        }
+        true
+    }
+
+    /// Check if a page is in the set.
+    pub fn contains(&self, page: u64) -> bool {
+        self.runs
+            .range(..=page)
+            .next_back()
+            .is_some_and(|(_, &e)| page < e)
+    }
+
+    /// Check if an entire range of pages is in the set.
+    pub fn contains_range(&self, start: u64, len: u64) -> bool {
+        self.runs
+            .range(..=start)
+            .next_back()
+            .is_some_and(|(_, &e)| start + len <= e)
+    }
+
+    /// Empty out the set.
+    pub fn clear(&mut self) {
+        self.runs.clear();
+    }
+
+    /// Check if there are no pages in the set.
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// The number of coalesced runs being tracked. This is what determines the memory footprint.
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// The number of pages in the set.
+    pub fn page_count(&self) -> u64 {
+        self.runs.iter().map(|(s, e)| (e - s) / PAGE_SIZE as u64).sum()
+    }
+
+    /// Iterate over the runs as `(start, len)` byte pairs, in ascending order.
+    pub fn runs(&self) -> impl DoubleEndedIterator<Item = (u64, u64)> + '_ {
+        self.runs.iter().map(|(&s, &e)| (s, e - s))
+    }
+
+    /// Iterate over every page in the set, in ascending order.
+    pub fn pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|(&s, &e)| (s..e).step_by(PAGE_SIZE))
+    }
+
+    /// Approximate number of bytes used to hold this set in memory.
+    pub fn mem_usage(&self) -> usize {
+        // A B-Tree node holds roughly 11 entries, but nodes are rarely full. Assume one 64-byte
+        // cache line of overhead per entry on top of the key-value pair itself.
+        self.runs.len() * (2 * std::mem::size_of::<u64>() + 64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P: u64 = PAGE_SIZE as u64;
+
+    #[test]
+    fn coalesce() {
+        let mut set = RunSet::new();
+        for i in 0..1000 {
+            assert!(set.insert(i * P));
+        }
+        assert_eq!(set.run_count(), 1);
+        assert_eq!(set.page_count(), 1000);
+        assert!(!set.insert(500 * P));
+
+        // Punch a hole and fill it back in
+        assert!(set.remove(500 * P));
+        assert!(!set.contains(500 * P));
+        assert_eq!(set.run_count(), 2);
+        assert!(set.insert(500 * P));
+        assert_eq!(set.run_count(), 1);
+
+        // Bridge two separate runs with a range
+        set.insert_range(2000 * P, 10 * P);
+        assert_eq!(set.run_count(), 2);
+        set.insert_range(990 * P, 1020 * P);
+        assert_eq!(set.run_count(), 1);
+        assert!(set.contains_range(0, 2010 * P));
+        assert!(!set.contains(2010 * P));
+    }
+
+    #[test]
+    fn scattered() {
+        let mut set = RunSet::new();
+        for i in 0..1000 {
+            set.insert(i * 2 * P);
+        }
+        assert_eq!(set.run_count(), 1000);
+        assert_eq!(set.pages().count(), 1000);
+        for i in 0..1000 {
+            assert!(set.contains(i * 2 * P));
+            assert!(!set.contains(i * 2 * P + P));
+        }
+    }
+}
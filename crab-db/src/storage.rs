@@ -1,19 +1,130 @@
-use std::fs::File;
+use std::{
+    fs::File,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use memmap2::{MmapMut, MmapOptions, MmapRaw, RemapOptions};
 
-use crate::{AllocError, BlockRange};
+use crate::{threading::Task, AllocError, BlockRange, BLOCK_SIZE};
 
 pub(crate) enum ExpandStorage {
     ReplaceLastMap(&'static mut [u8]),
     NewMap(&'static mut [u8]),
 }
 
+/// How a newly mapped region ended up being prefaulted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PrefaultMethod {
+    /// Every page was faulted in writable, the same as mapping it with `MAP_POPULATE` would.
+    Populate,
+    /// Populating isn't supported by this kernel, so the OS was only told the pages will be
+    /// needed soon.
+    WillNeed,
+    /// Nothing could be done on this platform.
+    Unsupported,
+}
+
+/// Prefault `len` bytes of a memory map starting at `offset`.
+fn prefault(map: &MmapRaw, offset: usize, len: usize) -> PrefaultMethod {
+    #[cfg(target_os = "linux")]
+    if map
+        .advise_range(memmap2::Advice::PopulateWrite, offset, len)
+        .is_ok()
+    {
+        return PrefaultMethod::Populate;
+    }
+    #[cfg(unix)]
+    if map
+        .advise_range(memmap2::Advice::WillNeed, offset, len)
+        .is_ok()
+    {
+        return PrefaultMethod::WillNeed;
+    }
+    PrefaultMethod::Unsupported
+}
+
+/// What [`StorageInner::advise`] can tell the OS about a range of the maps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Advice {
+    /// The range is about to be read through in order, so read ahead of it aggressively.
+    Sequential,
+    /// The range will be needed soon, so start reading it in now.
+    WillNeed,
+    /// The range won't be needed for a while, so its pages can be dropped. They're read back in
+    /// from the file if they're touched again.
+    DontNeed,
+}
+
+/// A stand-in for the disk underneath the memory maps, for simulating power loss in tests.
+///
+/// Every flush copies the bytes that changed since the last one into `disk`, in order, until
+/// `budget` of them have gone through. After that the power is out, and nothing else persists. A
+/// write cut off partway through is left torn, half new and half old.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) struct PowerCut {
+    pub disk: Vec<u8>,
+    pub budget: usize,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl PowerCut {
+    /// Persist whatever changed within `range` of the maps, as far as the budget allows.
+    fn persist(&mut self, maps: &[Arc<MmapRaw>], range: BlockRange) {
+        let mut start = 0;
+        for map in maps {
+            let map = unsafe { std::slice::from_raw_parts(map.as_ptr(), map.len()) };
+            let end = start + map.len();
+            let lower = range.start.max(start);
+            let upper = (range.start + range.len).min(end);
+            for page in (lower..upper).step_by(crate::PAGE_SIZE) {
+                let len = crate::PAGE_SIZE.min(upper - page);
+                let src = &map[(page - start)..(page - start + len)];
+                let dst = &mut self.disk[page..(page + len)];
+                if src == dst {
+                    continue;
+                }
+                for (dst, src) in dst.iter_mut().zip(src).filter(|(d, s)| d != s) {
+                    if self.budget == 0 {
+                        return;
+                    }
+                    *dst = *src;
+                    self.budget -= 1;
+                }
+            }
+            start = end;
+        }
+    }
+}
+
 /// This tracks all allocated memory maps and holds onto the optional backing file. Readers,
 /// writers, and committers each should wrap this struct.
+///
+/// Maps are reference counted so that a background prefault can hold onto the one it's working
+/// on. Nothing else ever clones them.
 pub(crate) struct StorageInner {
-    maps: Vec<MmapRaw>,
+    maps: Vec<Arc<MmapRaw>>,
+    /// Bumped every time the maps change, so holders of old map slices can tell they're stale
+    /// without taking the storage lock
+    generation: Arc<AtomicU64>,
     file: Option<File>,
+    /// Whether to advise the OS about ranges of the maps at all. Without it, hole punching and
+    /// prefaulting are skipped.
+    advise: bool,
+    prefault_on_grow: bool,
+    prefault: Option<Task<PrefaultMethod>>,
+    /// Bytes the writer may be using, grown into but not necessarily committed yet. Truncating
+    /// the tail never goes below it.
+    writer_len: usize,
+    /// Simulated disk that flushes go to, if we're testing power loss
+    #[cfg(any(test, feature = "testing"))]
+    power_cut: Option<std::cell::RefCell<PowerCut>>,
+    /// Make every [`flush_range`][Self::flush_range] fail, for testing what a commit does when its
+    /// root page can't be flushed
+    #[cfg(test)]
+    fail_range_flushes: bool,
 }
 
 impl StorageInner {
@@ -21,9 +132,72 @@ impl StorageInner {
     /// Initialize with a memory map and an optional backing file.
     pub fn init(map: MmapRaw, file: Option<File>) -> Self {
         Self {
-            maps: vec![map],
+            maps: vec![Arc::new(map)],
+            generation: Arc::new(AtomicU64::new(0)),
             file,
+            advise: true,
+            prefault_on_grow: false,
+            prefault: None,
+            writer_len: 0,
+            #[cfg(any(test, feature = "testing"))]
+            power_cut: None,
+            #[cfg(test)]
+            fail_range_flushes: false,
+        }
+    }
+
+    /// Turn hole punching and prefaulting on or off. They're on by default, but system pages
+    /// larger than a cluster are too coarse to advise the OS about our ranges with.
+    pub fn with_advice(mut self, advise: bool) -> Self {
+        self.advise = advise;
+        self
+    }
+
+    /// Check if hole punching, prefaulting, and other advice are turned on.
+    pub fn advice_enabled(&self) -> bool {
+        self.advise
+    }
+
+    /// Prefault each newly mapped region on a helper thread after every [`expand`][Self::expand],
+    /// so the first writes into it don't each take a page fault.
+    pub fn with_prefault_on_grow(mut self, prefault_on_grow: bool) -> Self {
+        self.prefault_on_grow = prefault_on_grow;
+        self
+    }
+
+    /// Check if the last prefault started by [`expand`][Self::expand] has finished. Also true if
+    /// none was ever started.
+    pub fn prefault_done(&self) -> bool {
+        self.prefault.as_ref().is_none_or(Task::is_finished)
+    }
+
+    /// Wait for the last prefault started by [`expand`][Self::expand] to finish, returning how it
+    /// went. `None` if there wasn't one pending.
+    pub fn wait_prefault(&mut self) -> Option<PrefaultMethod> {
+        let prefault = self.prefault.take()?;
+        // The prefault only ever makes a system call, so it can't panic
+        Some(prefault.join().unwrap_or(PrefaultMethod::Unsupported))
+    }
+
+    /// Start prefaulting `len` bytes at the end of the last memory map, if enabled.
+    fn start_prefault(&mut self, len: usize) {
+        if !self.prefault_on_grow || !self.advise {
+            return;
         }
+        let map = unsafe { self.maps.last().unwrap_unchecked().clone() };
+        let offset = map.len() - len;
+        self.prefault = Some(Task::spawn(move || prefault(&map, offset, len)));
+    }
+
+    /// How many times the storage has been expanded or truncated.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// The counter behind [`generation`][Self::generation], for checking it without holding the
+    /// storage lock.
+    pub fn generation_counter(&self) -> Arc<AtomicU64> {
+        self.generation.clone()
     }
 
     /// Extract raw slices pointing to the the memory maps with unbounded
@@ -50,25 +224,46 @@ impl StorageInner {
     /// Expand the backing storage, either by expanding the file and then memory
     /// mapping it if this is file-backed, or by creating a new anonymous memory
     /// map if there is no backing file.
+    ///
+    /// `new_alloc` must be a whole number of blocks, failing with [`AllocError::InvalidSize`]
+    /// otherwise. That way every map starts and ends on a block boundary, whether the last map
+    /// could be grown in place or a new one had to be added, and no allocation ever straddles two
+    /// maps.
+    ///
+    /// If prefaulting on growth is enabled, a successful expansion starts prefaulting the new
+    /// region in the background. Any previous prefault is waited on first, as the map it's working
+    /// on may be about to be remapped.
     pub unsafe fn expand(&mut self, new_alloc: usize) -> Result<ExpandStorage, AllocError> {
+        if new_alloc == 0 || !new_alloc.is_multiple_of(BLOCK_SIZE) {
+            return Err(AllocError::InvalidSize { size: new_alloc });
+        }
+        debug_assert!(self.mapped_len().is_multiple_of(BLOCK_SIZE));
+        self.wait_prefault();
+        let ret = self.expand_maps(new_alloc)?;
+        self.generation.fetch_add(1, Ordering::Release);
+        self.start_prefault(new_alloc);
+        Ok(ret)
+    }
+
+    unsafe fn expand_maps(&mut self, new_alloc: usize) -> Result<ExpandStorage, AllocError> {
         // Is this file-backed?
         if let Some(file) = self.file.as_ref() {
-            // Resize the file first
+            // Resize the file first. The new map goes right after the old ones, which can run past
+            // the end of the file if a truncate couldn't shrink them.
             let current_size = file.metadata().map_err(AllocError::Open)?.len();
-            file.set_len(new_alloc as u64 + current_size).map_err(|e| {
-                AllocError::ResizeFailed {
+            let mapped = self.mapped_len() as u64;
+            file.set_len(mapped + new_alloc as u64)
+                .map_err(|e| AllocError::ResizeFailed {
                     size: current_size as usize,
-                    requested: current_size as usize + new_alloc,
+                    requested: mapped as usize + new_alloc,
                     source: e,
-                }
-            })?;
+                })?;
             // Update the metadata in order to get the new file size stored
             file.sync_all().map_err(AllocError::Sync)?;
 
             // On Linux, we might be able to just expand the last memory map
             #[cfg(target_os = "linux")]
-            {
-                let map = self.maps.last_mut().unwrap_unchecked();
+            if let Some(map) = Arc::get_mut(self.maps.last_mut().unwrap_unchecked()) {
                 let new_size = map.len() + new_alloc;
                 if map
                     .remap(new_size, RemapOptions::new().may_move(false))
@@ -80,7 +275,7 @@ impl StorageInner {
             }
 
             let map = MmapOptions::new()
-                .offset(current_size)
+                .offset(mapped)
                 .len(new_alloc)
                 .map_raw(file)
                 .map_err(|e| AllocError::AllocFailed {
@@ -88,14 +283,13 @@ impl StorageInner {
                     source: e,
                 })?;
             let ret = std::slice::from_raw_parts_mut(map.as_mut_ptr(), new_alloc);
-            self.maps.push(map);
+            self.maps.push(Arc::new(map));
             Ok(ExpandStorage::NewMap(ret))
         } else {
             // We're an anonymous memory map, expand that or create a new anonymous map
             // On Linux, we might be able to just expand the last memory map
             #[cfg(target_os = "linux")]
-            {
-                let map = self.maps.last_mut().unwrap_unchecked();
+            if let Some(map) = Arc::get_mut(self.maps.last_mut().unwrap_unchecked()) {
                 let new_size = map.len() + new_alloc;
                 if map
                     .remap(new_size, RemapOptions::new().may_move(false))
@@ -113,11 +307,112 @@ impl StorageInner {
                 }
             })?);
             let ret = std::slice::from_raw_parts_mut(map.as_mut_ptr(), new_alloc);
-            self.maps.push(map);
+            self.maps.push(Arc::new(map));
             Ok(ExpandStorage::NewMap(ret))
         }
     }
 
+    /// Total length of every memory map, in bytes.
+    pub fn mapped_len(&self) -> usize {
+        self.maps.iter().map(|m| m.len()).sum()
+    }
+
+    /// Make sure the first `len` bytes are mapped and, if this is file-backed, in the file,
+    /// expanding the storage if need be. From then on, [`truncate_tail`][Self::truncate_tail]
+    /// leaves them alone. `len` must be a whole number of blocks.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`expand`][Self::expand].
+    pub unsafe fn grow_to(&mut self, len: usize) -> Result<(), AllocError> {
+        let mapped = self.mapped_len();
+        if len > mapped {
+            self.expand(len - mapped)?;
+        } else if let Some(file) = self.file.as_ref() {
+            // A truncate may have left the maps running past the end of the file
+            let current_size = file.metadata().map_err(AllocError::Open)?.len();
+            if current_size < len as u64 {
+                file.set_len(len as u64)
+                    .map_err(|e| AllocError::ResizeFailed {
+                        size: current_size as usize,
+                        requested: len,
+                        source: e,
+                    })?;
+                file.sync_all().map_err(AllocError::Sync)?;
+            }
+        }
+        self.writer_len = self.writer_len.max(len);
+        Ok(())
+    }
+
+    /// Set how many bytes the writer is using, once it has published its file length. Anything
+    /// past that can be truncated again once the shorter length is durable.
+    pub fn set_writer_len(&mut self, len: usize) {
+        self.writer_len = len;
+    }
+
+    /// Shrink the backing storage down to `len` bytes, like [`truncate`][Self::truncate], but
+    /// never past what the writer may be using. Returns whether there was anything to give up.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`truncate`][Self::truncate].
+    pub unsafe fn truncate_tail(&mut self, len: usize) -> Result<bool, AllocError> {
+        self.truncate(len.max(self.writer_len))
+    }
+
+    /// Shrink the backing storage down to `len` bytes, dropping or shrinking every memory map
+    /// past that point and, if this is file-backed, truncating the file to match. Returns whether
+    /// there was anything past `len` to give up.
+    ///
+    /// A map that can't be shrunk in place is left as it is, with its tail past the end of the
+    /// file. Nothing may touch it there.
+    ///
+    /// # Safety
+    ///
+    /// Every slice taken from the maps past `len` is dangling afterwards. It is up to the caller
+    /// to ensure that no reader or writer can still reach that far.
+    pub unsafe fn truncate(&mut self, len: usize) -> Result<bool, AllocError> {
+        if len >= self.mapped_len() {
+            return Ok(false);
+        }
+        // The prefault may be holding onto one of the maps we're about to drop
+        self.wait_prefault();
+        let mut end = 0;
+        let keep = self
+            .maps
+            .iter()
+            .take_while(|m| {
+                let start = end;
+                end += m.len();
+                start < len
+            })
+            .count();
+        self.maps.truncate(keep);
+        end = self.maps.iter().map(|m| m.len()).sum();
+
+        // On Linux, we might be able to shrink the map that now runs past the end
+        #[cfg(target_os = "linux")]
+        if end > len {
+            if let Some(map) = Arc::get_mut(self.maps.last_mut().unwrap_unchecked()) {
+                let _ = map.remap(map.len() - (end - len), RemapOptions::new().may_move(false));
+            }
+        }
+
+        if let Some(file) = self.file.as_ref() {
+            let current_size = file.metadata().map_err(AllocError::Open)?.len();
+            file.set_len(len as u64)
+                .map_err(|e| AllocError::ResizeFailed {
+                    size: current_size as usize,
+                    requested: len,
+                    source: e,
+                })?;
+            file.sync_all().map_err(AllocError::Sync)?;
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(true)
+    }
+
     /// Punch a hole in a memory map.
     ///
     /// For a file-backed map, this should tell the file system to remove the selected range from
@@ -125,6 +420,8 @@ impl StorageInner {
     ///
     /// For an anonymous memory map, this frees a section of memory back to the system.
     ///
+    /// Does nothing if advice is turned off; see [`with_advice`][Self::with_advice].
+    ///
     /// In both of the above cases, this often results in a complete TLB flush. Because of this, and
     /// the tracking information needed for sparse maps, it's recommended that a block range be on
     /// the order of 1 MiB or larger.
@@ -135,6 +432,9 @@ impl StorageInner {
     /// generally zero out the memory). As such, it is up to the caller to ensure that there no
     /// other writers or readers that have borrowed this chunk of the maps.
     pub unsafe fn hole_punch(&mut self, mut hole: BlockRange) -> Result<(), AllocError> {
+        if !self.advise {
+            return Ok(());
+        }
         let mut idx = 0;
         for map in self.maps.iter_mut() {
             if hole.start >= (idx + map.len()) {
@@ -161,9 +461,47 @@ impl StorageInner {
         Ok(())
     }
 
+    /// Start simulating a disk that loses power after `budget` more bytes are flushed to it.
+    /// Everything in the maps so far is taken to already be on it.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn cut_power_after(&mut self, budget: usize) {
+        let disk = unsafe { self.get_maps() }.concat();
+        self.power_cut = Some(std::cell::RefCell::new(PowerCut { disk, budget }));
+    }
+
+    /// Check if the simulated disk still has power. Always true if there isn't one.
+    #[cfg(test)]
+    pub fn powered(&self) -> bool {
+        self.power_cut
+            .as_ref()
+            .is_none_or(|cut| cut.borrow().budget > 0)
+    }
+
+    /// Stop simulating power loss, returning everything that made it onto the simulated disk.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn take_disk(&mut self) -> Option<Vec<u8>> {
+        self.power_cut.take().map(|cut| cut.into_inner().disk)
+    }
+
+    /// Make every [`flush_range`][Self::flush_range] from now on fail, or stop doing so.
+    #[cfg(test)]
+    pub fn fail_range_flushes(&mut self, fail: bool) {
+        self.fail_range_flushes = fail;
+    }
+
+    /// Hand a flush of `range` to the simulated disk, if there is one.
+    #[cfg_attr(not(any(test, feature = "testing")), allow(unused_variables))]
+    fn persist(&self, range: BlockRange) {
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(cut) = self.power_cut.as_ref() {
+            cut.borrow_mut().persist(&self.maps, range);
+        }
+    }
+
     /// Flush all memory maps.
     #[cfg(not(windows))]
     pub fn flush(&self) -> Result<(), AllocError> {
+        self.persist(BlockRange::new(0, usize::MAX));
         if self.file.is_none() {
             return Ok(());
         }
@@ -176,6 +514,7 @@ impl StorageInner {
     /// Flush all memory maps.
     #[cfg(windows)]
     pub fn flush(&self) -> Result<(), AllocError> {
+        self.persist(BlockRange::new(0, usize::MAX));
         if self.file.is_none() {
             return Ok(());
         }
@@ -191,25 +530,97 @@ impl StorageInner {
         Ok(())
     }
 
+    /// Start flushing all memory maps without waiting for the writes to finish. Any error from the
+    /// writes themselves comes back from whichever synchronous flush of the file comes next.
+    pub fn flush_async(&self) -> Result<(), AllocError> {
+        self.persist(BlockRange::new(0, usize::MAX));
+        if self.file.is_none() {
+            return Ok(());
+        }
+        for map in self.maps.iter() {
+            map.flush_async().map_err(AllocError::Sync)?;
+        }
+        Ok(())
+    }
+
+    /// Flush only the given `(start, len)` runs of bytes, splitting any that cross from one
+    /// memory map into the next. Anything past the end of the maps has nothing to flush.
+    pub fn flush_runs(&self, runs: impl Iterator<Item = (u64, u64)>) -> Result<(), AllocError> {
+        let mapped = self.mapped_len();
+        for (start, len) in runs {
+            let end = start.saturating_add(len).min(mapped as u64) as usize;
+            let mut start = start.min(mapped as u64) as usize;
+            let mut map_end = 0;
+            for map in self.maps.iter() {
+                map_end += map.len();
+                if start >= end {
+                    break;
+                }
+                if start < map_end {
+                    let piece_end = end.min(map_end);
+                    self.flush_range(BlockRange::new(start, piece_end - start))?;
+                    start = piece_end;
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Flush a range within a single memory map. Errors if the range crosses memory maps.
     pub fn flush_range(&self, range: BlockRange) -> Result<(), AllocError> {
+        #[cfg(test)]
+        if self.fail_range_flushes {
+            return Err(AllocError::Sync(std::io::Error::other(
+                "injected flush failure",
+            )));
+        }
+        self.persist(range);
         if self.file.is_none() {
             return Ok(());
         }
+        let (map, offset) = self.map_for(range)?;
+        map.flush_range(offset, range.len)
+            .map_err(AllocError::Sync)
+    }
+
+    /// Tell the OS how a range within a single memory map is about to be used. Errors if the
+    /// range crosses memory maps, same as [`flush_range`][Self::flush_range].
+    ///
+    /// Does nothing on Windows, or if advice is turned off; see [`with_advice`][Self::with_advice].
+    /// [`Advice::DontNeed`] also does nothing for anonymous maps, as dropping their pages would
+    /// drop what's in them too.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub fn advise(&self, range: BlockRange, advice: Advice) -> Result<(), AllocError> {
+        let (map, offset) = self.map_for(range)?;
+        if !self.advise {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        match advice {
+            Advice::Sequential => map.advise_range(memmap2::Advice::Sequential, offset, range.len),
+            Advice::WillNeed => map.advise_range(memmap2::Advice::WillNeed, offset, range.len),
+            Advice::DontNeed if self.file.is_none() => Ok(()),
+            // Safety: the map is shared and backed by the file, so the pages dropped are read
+            // back in from it unchanged.
+            Advice::DontNeed => unsafe {
+                map.unchecked_advise_range(memmap2::UncheckedAdvice::DontNeed, offset, range.len)
+            },
+        }
+        .map_err(AllocError::Advise)?;
+        Ok(())
+    }
 
+    /// Find the memory map holding all of `range`, along with where the range starts within it.
+    /// Fails with [`AllocError::InvalidAccess`] if the range isn't within a single map.
+    fn map_for(&self, range: BlockRange) -> Result<(&MmapRaw, usize), AllocError> {
         let mut start = 0;
         for map in self.maps.iter() {
             let end = start + map.len();
             if range.start < end {
                 if (range.start + range.len) > end {
-                    return Err(AllocError::InvalidAccess {
-                        offset: range.start,
-                        len: range.len,
-                    });
+                    break;
                 }
-                map.flush_range(range.start - start, range.len)
-                    .map_err(AllocError::Sync)?;
-                return Ok(());
+                return Ok((map, range.start - start));
             }
             start = end;
         }
@@ -219,3 +630,97 @@ impl StorageInner {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempPath;
+
+    /// Grow the storage a few times, filling each new region with a different byte, and return
+    /// how each growth got prefaulted.
+    fn grow(storage: &mut StorageInner) -> Vec<Option<PrefaultMethod>> {
+        let mut methods = Vec::new();
+        for fill in 1..=3u8 {
+            let new_alloc = fill as usize * BLOCK_SIZE;
+            let region = match unsafe { storage.expand(new_alloc).unwrap() } {
+                ExpandStorage::ReplaceLastMap(map) => {
+                    let len = map.len();
+                    &mut map[(len - new_alloc)..]
+                }
+                ExpandStorage::NewMap(map) => map,
+            };
+            assert_eq!(region.len(), new_alloc);
+            region.fill(fill);
+            methods.push(storage.wait_prefault());
+            assert!(storage.prefault_done());
+        }
+        methods
+    }
+
+    /// Everything in the storage, in order.
+    fn contents(storage: &StorageInner) -> Vec<u8> {
+        unsafe { storage.get_maps() }.concat()
+    }
+
+    fn expected_prefault() -> PrefaultMethod {
+        if cfg!(target_os = "linux") {
+            PrefaultMethod::Populate
+        } else if cfg!(unix) {
+            PrefaultMethod::WillNeed
+        } else {
+            PrefaultMethod::Unsupported
+        }
+    }
+
+    #[test]
+    fn prefault_on_grow_anon() {
+        let anon = |prefault| {
+            let map = MmapRaw::from(MmapMut::map_anon(BLOCK_SIZE).unwrap());
+            StorageInner::init(map, None).with_prefault_on_grow(prefault)
+        };
+
+        let mut plain = anon(false);
+        assert_eq!(plain.generation(), 0);
+        assert_eq!(grow(&mut plain), [None; 3]);
+        assert_eq!(plain.generation(), 3);
+        let mut prefaulted = anon(true);
+        assert_eq!(grow(&mut prefaulted), [Some(expected_prefault()); 3]);
+
+        // Prefaulting never changes what ends up in memory
+        let contents = contents(&prefaulted);
+        assert_eq!(contents.len(), 7 * BLOCK_SIZE);
+        assert_eq!(contents, self::contents(&plain));
+        assert!(contents[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert!(contents[(4 * BLOCK_SIZE)..].iter().all(|b| *b == 3));
+    }
+
+    #[test]
+    fn prefault_on_grow_file() {
+        let path = TempPath::new("prefault");
+        let file = |prefault| {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            file.set_len(BLOCK_SIZE as u64).unwrap();
+            let map = MmapOptions::new().len(BLOCK_SIZE).map_raw(&file).unwrap();
+            StorageInner::init(map, Some(file)).with_prefault_on_grow(prefault)
+        };
+
+        let plain = {
+            let mut plain = file(false);
+            assert_eq!(grow(&mut plain), [None; 3]);
+            contents(&plain)
+        };
+        let mut prefaulted = file(true);
+        assert_eq!(grow(&mut prefaulted), [Some(expected_prefault()); 3]);
+        assert_eq!(contents(&prefaulted), plain);
+        drop(prefaulted);
+
+        // And it all made it into the file
+        assert_eq!(std::fs::read(&path).unwrap(), plain);
+    }
+}
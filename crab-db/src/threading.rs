@@ -0,0 +1,219 @@
+//! The parts of the allocator that change with the `single-threaded` feature.
+//!
+//! By default, the units of the allocator hand page numbers and write allocations to each other
+//! over channels, so each unit can live on its own thread, and background work like prefaulting
+//! runs on helper threads. With `single-threaded`, the queues are plain shared
+//! [`VecDeque`][std::collections::VecDeque]s and background work runs to completion right where
+//! it's started, so no threads are ever spawned. The types keep the same names and methods either
+//! way, only dropping the `Send` bounds they no longer need.
+
+#[cfg(not(feature = "single-threaded"))]
+mod imp {
+    use std::{fmt, sync::mpsc, thread::JoinHandle, time::Duration};
+
+    /// Sending half of a queue.
+    pub(crate) struct QueueSender<T>(mpsc::Sender<T>);
+
+    impl<T> QueueSender<T> {
+        /// Queue up an item. Items sent after the receiver is gone are dropped.
+        pub fn send(&self, item: T) {
+            let _ = self.0.send(item);
+        }
+    }
+
+    impl<T> Clone for QueueSender<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+
+    impl<T> fmt::Debug for QueueSender<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("QueueSender").finish_non_exhaustive()
+        }
+    }
+
+    /// Receiving half of a queue.
+    pub(crate) struct QueueReceiver<T>(mpsc::Receiver<T>);
+
+    impl<T> QueueReceiver<T> {
+        /// Take the next queued item, if there is one.
+        pub fn try_recv(&self) -> Option<T> {
+            self.0.try_recv().ok()
+        }
+
+        /// Take the next queued item, waiting up to `timeout` for one to be sent.
+        pub fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+            self.0.recv_timeout(timeout).ok()
+        }
+    }
+
+    impl<T> fmt::Debug for QueueReceiver<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("QueueReceiver").finish_non_exhaustive()
+        }
+    }
+
+    /// Create a new, empty queue.
+    pub(crate) fn queue<T>() -> (QueueSender<T>, QueueReceiver<T>) {
+        let (send, recv) = mpsc::channel();
+        (QueueSender(send), QueueReceiver(recv))
+    }
+
+    /// Work running in the background, on its own thread.
+    #[derive(Debug)]
+    pub(crate) struct Task<T>(JoinHandle<T>);
+
+    impl<T: Send + 'static> Task<T> {
+        /// Start running `f` on a new thread.
+        pub fn spawn(f: impl FnOnce() -> T + Send + 'static) -> Self {
+            Self(std::thread::spawn(f))
+        }
+
+        /// Check if the work has finished.
+        pub fn is_finished(&self) -> bool {
+            self.0.is_finished()
+        }
+
+        /// Wait for the work to finish, returning its result. `None` if it panicked.
+        pub fn join(self) -> Option<T> {
+            self.0.join().ok()
+        }
+    }
+}
+
+#[cfg(feature = "single-threaded")]
+mod imp {
+    use std::{cell::RefCell, collections::VecDeque, fmt, rc::Rc, time::Duration};
+
+    /// Sending half of a queue.
+    pub(crate) struct QueueSender<T>(Rc<RefCell<VecDeque<T>>>);
+
+    impl<T> QueueSender<T> {
+        /// Queue up an item. Items sent after the receiver is gone are dropped.
+        pub fn send(&self, item: T) {
+            // With only this side left, nobody will ever read the queue again
+            if Rc::strong_count(&self.0) > 1 {
+                self.0.borrow_mut().push_back(item);
+            }
+        }
+    }
+
+    impl<T> Clone for QueueSender<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+
+    impl<T> fmt::Debug for QueueSender<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("QueueSender").finish_non_exhaustive()
+        }
+    }
+
+    /// Receiving half of a queue.
+    pub(crate) struct QueueReceiver<T>(Rc<RefCell<VecDeque<T>>>);
+
+    impl<T> QueueReceiver<T> {
+        /// Take the next queued item, if there is one.
+        pub fn try_recv(&self) -> Option<T> {
+            self.0.borrow_mut().pop_front()
+        }
+
+        /// Take the next queued item. Nothing else can send while this waits, so it's the same as
+        /// [`try_recv`][Self::try_recv].
+        pub fn recv_timeout(&self, _timeout: Duration) -> Option<T> {
+            self.try_recv()
+        }
+    }
+
+    impl<T> fmt::Debug for QueueReceiver<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("QueueReceiver").finish_non_exhaustive()
+        }
+    }
+
+    impl<T> Drop for QueueReceiver<T> {
+        /// Drop whatever's still queued, as it can hold senders of its own that would otherwise
+        /// keep the queue alive forever.
+        fn drop(&mut self) {
+            let queued = std::mem::take(&mut *self.0.borrow_mut());
+            drop(queued);
+        }
+    }
+
+    /// Create a new, empty queue.
+    pub(crate) fn queue<T>() -> (QueueSender<T>, QueueReceiver<T>) {
+        let queue = Rc::new(RefCell::new(VecDeque::new()));
+        (QueueSender(queue.clone()), QueueReceiver(queue))
+    }
+
+    /// Work that would run in the background, already run to completion.
+    #[derive(Debug)]
+    pub(crate) struct Task<T>(T);
+
+    impl<T> Task<T> {
+        /// Run `f` right away, on the calling thread.
+        pub fn spawn(f: impl FnOnce() -> T) -> Self {
+            Self(f())
+        }
+
+        /// Check if the work has finished, which it always has.
+        pub fn is_finished(&self) -> bool {
+            true
+        }
+
+        /// Get the result of the work.
+        pub fn join(self) -> Option<T> {
+            Some(self.0)
+        }
+    }
+}
+
+pub(crate) use imp::*;
+
+/// Sending half of a queue of page numbers.
+pub(crate) type PageSender = QueueSender<u64>;
+
+/// Receiving half of a queue of page numbers.
+pub(crate) type PageReceiver = QueueReceiver<u64>;
+
+/// Create a new, empty queue of page numbers.
+pub(crate) fn page_queue() -> (PageSender, PageReceiver) {
+    queue()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn page_queue_order() {
+        let (send, recv) = page_queue();
+        let other = send.clone();
+        send.send(3);
+        other.send(1);
+        send.send(2);
+        assert_eq!(recv.try_recv(), Some(3));
+        assert_eq!(recv.try_recv(), Some(1));
+        assert_eq!(recv.try_recv(), Some(2));
+        assert_eq!(recv.try_recv(), None);
+
+        // Sending into a queue nobody reads anymore is harmless
+        drop(recv);
+        send.send(4);
+    }
+
+    #[test]
+    fn task_thread() {
+        let task = Task::spawn(|| thread::current().id());
+        let ran_on = task.join().unwrap();
+        if cfg!(feature = "single-threaded") {
+            assert_eq!(ran_on, thread::current().id());
+        } else {
+            assert_ne!(ran_on, thread::current().id());
+        }
+    }
+}
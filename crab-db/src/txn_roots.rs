@@ -0,0 +1,238 @@
+//! Tracking of the tree root pages that make up a write transaction's root payload.
+
+use crate::{error::FormatError, AllocError, ByteOffset};
+
+/// Handle to a root page tracked by [`TxnRoots`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RootSlot(usize);
+
+#[derive(Debug)]
+struct TrackedRoot {
+    name: String,
+    page: ByteOffset,
+    layout: [u8; 2],
+    resolved: bool,
+}
+
+/// One tree's entry in a parsed root payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootEntry {
+    /// Name the tree was tracked under
+    pub name: String,
+    /// The tree's root page
+    pub page: ByteOffset,
+    /// Layout ids of the tree's branch and leaf pages, as given by crab-dads'
+    /// `PageLayout::LAYOUT_ID`. Zero means the layout wasn't recorded.
+    pub layout: [u8; 2],
+}
+
+/// The root pages of every tree a write transaction touches, gathered up into the root payload
+/// that gets committed along with it.
+///
+/// Each tree is registered when it's loaded, and must be resolved before committing: either by
+/// handing over whatever new root page loading it for writing returned, or by explicitly marking
+/// it unchanged. Forgetting a tree would otherwise commit its old root page, silently reverting
+/// it while its old pages get freed out from under it, so building the payload fails instead.
+///
+/// The payload is a sequence of entries, each a little-endian `u16` name length, the name, the
+/// branch and leaf layout ids as one byte each, and the root page as a little-endian `u64`. It's
+/// stored alongside the freelist head in the database's root pages. The layout ids let tools
+/// tell what kind of tree each root is without knowing the schema that made it.
+#[derive(Debug, Default)]
+pub struct TxnRoots {
+    roots: Vec<TrackedRoot>,
+}
+
+impl TxnRoots {
+    /// Start tracking the tree named `name`, whose root page is currently `page` and whose branch
+    /// and leaf layout ids are `layout`. Fails with [`AllocError::DuplicateRoot`] if the name is
+    /// already tracked.
+    pub fn track(
+        &mut self,
+        name: impl Into<String>,
+        page: ByteOffset,
+        layout: [u8; 2],
+    ) -> Result<RootSlot, AllocError> {
+        let name = name.into();
+        if name.len() > u16::MAX as usize {
+            return Err(AllocError::RootNameTooLong { len: name.len() });
+        }
+        if self.roots.iter().any(|r| r.name == name) {
+            return Err(AllocError::DuplicateRoot(name));
+        }
+        self.roots.push(TrackedRoot {
+            name,
+            page,
+            layout,
+            resolved: false,
+        });
+        Ok(RootSlot(self.roots.len() - 1))
+    }
+
+    /// Resolve a tree with the new root page returned when loading it for writing. `None` means
+    /// the root page stayed where it was.
+    pub fn update(&mut self, slot: RootSlot, new_page: Option<ByteOffset>) {
+        let root = &mut self.roots[slot.0];
+        if let Some(page) = new_page {
+            root.page = page;
+        }
+        root.resolved = true;
+    }
+
+    /// Resolve a tree as not having been changed by this transaction.
+    pub fn unchanged(&mut self, slot: RootSlot) {
+        self.roots[slot.0].resolved = true;
+    }
+
+    /// The current root page of a tracked tree.
+    pub fn page(&self, slot: RootSlot) -> ByteOffset {
+        self.roots[slot.0].page
+    }
+
+    /// The branch and leaf layout ids of a tracked tree.
+    pub fn layout(&self, slot: RootSlot) -> [u8; 2] {
+        self.roots[slot.0].layout
+    }
+
+    /// Names of the trees that haven't been resolved yet.
+    pub fn unresolved(&self) -> impl Iterator<Item = &str> {
+        self.roots
+            .iter()
+            .filter(|r| !r.resolved)
+            .map(|r| r.name.as_str())
+    }
+
+    /// Build the root payload. Fails with [`AllocError::UnresolvedRoots`], listing every tree
+    /// that wasn't resolved, rather than commit a possibly stale root page.
+    pub fn payload(&self) -> Result<Vec<u8>, AllocError> {
+        let unresolved: Vec<String> = self.unresolved().map(String::from).collect();
+        if !unresolved.is_empty() {
+            return Err(AllocError::UnresolvedRoots(unresolved));
+        }
+        let mut payload = Vec::new();
+        for root in self.roots.iter() {
+            payload.extend_from_slice(&(root.name.len() as u16).to_le_bytes());
+            payload.extend_from_slice(root.name.as_bytes());
+            payload.extend_from_slice(&root.layout);
+            payload.extend_from_slice(&root.page.get().to_le_bytes());
+        }
+        Ok(payload)
+    }
+
+    /// Parse a root payload back into its entries. Root pages with reserved bits set are rejected
+    /// with [`AllocError::ReservedPageBits`].
+    pub fn parse(mut payload: &[u8]) -> Result<Vec<RootEntry>, AllocError> {
+        let invalid = || AllocError::DataFormat(FormatError::RootPayload);
+        let mut roots = Vec::new();
+        while !payload.is_empty() {
+            let (len, rem) = payload.split_first_chunk::<2>().ok_or_else(invalid)?;
+            let len = u16::from_le_bytes(*len) as usize;
+            let name = rem.get(..len).ok_or_else(invalid)?;
+            let name = std::str::from_utf8(name).map_err(|_| invalid())?;
+            let (layout, rem) = rem[len..].split_first_chunk::<2>().ok_or_else(invalid)?;
+            let (page, rem) = rem.split_first_chunk::<8>().ok_or_else(invalid)?;
+            let page = ByteOffset::new(u64::from_le_bytes(*page))?;
+            roots.push(RootEntry {
+                name: name.to_owned(),
+                page,
+                layout: *layout,
+            });
+            payload = rem;
+        }
+        Ok(roots)
+    }
+
+    /// Stop tracking everything, ready for the next transaction.
+    pub(crate) fn clear(&mut self) {
+        self.roots.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{at, test_writer},
+        OpenOptions, WriteTxn,
+    };
+
+    #[test]
+    fn forgotten_root() {
+        let mut txn = WriteTxn(test_writer(&OpenOptions::default()));
+        let users = txn.track_root("users", at(0x10000), [1, 2]).unwrap();
+        let posts = txn.track_root("posts", at(0x20000), [3, 3]).unwrap();
+        let tags = txn.track_root("tags", at(0x30000), [0, 0]).unwrap();
+        assert!(matches!(
+            txn.track_root("users", at(0x40000), [1, 2]),
+            Err(AllocError::DuplicateRoot(name)) if name == "users"
+        ));
+
+        // Two trees were rewritten, but only one of them gets recorded
+        txn.update_root(users, Some(at(0x50000)));
+        txn.root_unchanged(tags);
+        let Err((mut txn, err)) = txn.commit_roots() else {
+            panic!("committing with a forgotten root should fail");
+        };
+        assert!(matches!(&err, AllocError::UnresolvedRoots(names) if names == &["posts"]));
+        assert!(err.to_string().contains("posts"));
+
+        // Once it's recorded, the payload has every tree's latest root and its layouts, with
+        // unrecorded layouts kept as-is
+        txn.update_root(posts, Some(at(0x60000)));
+        assert_eq!(txn.roots().page(posts), at(0x60000));
+        assert_eq!(txn.roots().layout(posts), [3, 3]);
+        let payload = txn.roots().payload().unwrap();
+        let entry = |name: &str, page, layout| RootEntry {
+            name: name.to_owned(),
+            page,
+            layout,
+        };
+        assert_eq!(
+            TxnRoots::parse(&payload).unwrap(),
+            [
+                entry("users", at(0x50000), [1, 2]),
+                entry("posts", at(0x60000), [3, 3]),
+                entry("tags", at(0x30000), [0, 0]),
+            ]
+        );
+
+        // A root that didn't move still counts as resolved
+        let mut roots = TxnRoots::default();
+        let slot = roots.track("idx", at(0x70000), [1, 1]).unwrap();
+        roots.update(slot, None);
+        assert_eq!(roots.unresolved().count(), 0);
+        assert_eq!(roots.page(slot), at(0x70000));
+
+        // Truncated payloads are rejected
+        for bad in [
+            &payload[..payload.len() - 1],
+            &[9, 0, b'x'],
+            &[1, 0, b'x', 1, 2],
+        ] {
+            assert!(matches!(
+                TxnRoots::parse(bad),
+                Err(AllocError::DataFormat(FormatError::RootPayload))
+            ));
+        }
+
+        // Names have to fit their 2-byte length
+        let mut roots = TxnRoots::default();
+        let name = "x".repeat(u16::MAX as usize + 1);
+        assert!(matches!(
+            roots.track(name, at(0x70000), [1, 1]),
+            Err(AllocError::RootNameTooLong { len }) if len == u16::MAX as usize + 1
+        ));
+        let name = "x".repeat(u16::MAX as usize);
+        assert!(roots.track(name, at(0x70000), [1, 1]).is_ok());
+
+        // So are root pages with reserved bits set
+        let tagged = (1 << 48) | 0x10000;
+        let mut payload = payload;
+        let len = payload.len();
+        payload[len - 8..].copy_from_slice(&u64::to_le_bytes(tagged));
+        assert!(matches!(
+            TxnRoots::parse(&payload),
+            Err(AllocError::ReservedPageBits { page }) if page == tagged
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+//! B-tree throughput over crab-dads' simulated allocator: inserting into a fresh tree, appending to
+//! one through a cursor, rewriting and draining a committed one, point lookups, and range scans.
+//!
+//! Every dataset comes from a fixed seed, so numbers from different runs and different machines
+//! are measuring the same trees.
+
+use std::{borrow::Borrow, hint::black_box, ops::Bound};
+
+use crab_dads::{
+    btree::{BTreeConfig, Entry},
+    page::PageLayout,
+    sim::SimAllocator,
+};
+use crab_tests::{dataset, insert_all, BytesU64, Rng, Shape, U64Bytes, U64U64};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+const SEED: u64 = 0x5eed;
+
+/// Records loaded into a `u64 -> u64` tree, and into the trees with variable-length keys or
+/// values. Each is enough for at least three levels.
+const U64_LEN: u64 = 1 << 17;
+const BYTES_LEN: u64 = 20_000;
+
+/// Number of operations timed per iteration of the lookup and rewrite benchmarks.
+const OPS: usize = 1000;
+
+/// Load a whole dataset into a new simulated database and commit it.
+fn load<S: Shape>(records: &[(S::Key, S::Value)]) -> SimAllocator {
+    let mut writer = SimAllocator::new();
+    insert_all::<S, _>(&mut writer.tree().unwrap(), records).unwrap();
+    writer.commit().unwrap();
+    writer
+}
+
+/// `OPS` keys picked at random from the records.
+fn pick<S: Shape>(records: &[(S::Key, S::Value)], rng: &mut Rng) -> Vec<S::Key> {
+    (0..OPS)
+        .map(|_| records[rng.below(records.len() as u64) as usize].0.clone())
+        .collect()
+}
+
+fn insert_shape<S: Shape>(c: &mut Criterion, name: &str, len: u64) {
+    let random = dataset::<S>(SEED, 0..len);
+    let mut sequential = random.clone();
+    sequential.sort_by(|a, b| a.0.cmp(&b.0));
+    let appending = BTreeConfig {
+        split_fill: 0.9,
+        append_optimized: true,
+        scratch_pages: 0,
+    };
+
+    let mut group = c.benchmark_group(format!("insert/{name}"));
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(random.len() as u64));
+    let cases = [
+        ("random", &random, BTreeConfig::default()),
+        ("sequential", &sequential, BTreeConfig::default()),
+        ("sequential_append", &sequential, appending),
+    ];
+    for (case, records, config) in cases {
+        group.bench_function(case, |b| {
+            b.iter_batched(
+                SimAllocator::new,
+                |mut writer| {
+                    let mut tree = writer.tree_with_config(config).unwrap();
+                    insert_all::<S, _>(&mut tree, records).unwrap();
+                    drop(tree);
+                    writer
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Bulk load sorted records into a fresh tree, through an entry per record and through a cursor,
+/// which holds onto the leaf being appended to instead of descending to it for every record.
+fn append_shape<S: Shape>(c: &mut Criterion, name: &str, len: u64)
+where
+    <S::Leaf as PageLayout>::Key: ToOwned,
+{
+    let mut records = dataset::<S>(SEED, 0..len);
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut group = c.benchmark_group(format!("append/{name}"));
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(records.len() as u64));
+    group.bench_function("entry", |b| {
+        b.iter_batched(
+            SimAllocator::new,
+            |mut writer| {
+                let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+                insert_all::<S, _>(&mut tree, &records).unwrap();
+                drop(tree);
+                writer
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("cursor", |b| {
+        b.iter_batched(
+            SimAllocator::new,
+            |mut writer| {
+                let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+                let mut cursor = tree.cursor();
+                for (key, value) in &records {
+                    cursor.insert_before(key.borrow(), value.borrow()).unwrap();
+                }
+                drop(cursor);
+                drop(tree);
+                writer
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Overwrite records in a committed tree, so every leaf touched gets copied on write along with
+/// the branches above it. The transaction is thrown away after each iteration, so they all start
+/// from the same tree.
+fn rewrite_shape<S: Shape>(c: &mut Criterion, name: &str, len: u64) {
+    let records = dataset::<S>(SEED, 0..len);
+    let mut writer = load::<S>(&records);
+    let mut rng = Rng::new(!SEED);
+    let keys = pick::<S>(&records, &mut rng);
+    let values: Vec<S::Value> = (0..OPS).map(|_| S::value(&mut rng)).collect();
+
+    let mut group = c.benchmark_group(format!("rewrite/{name}"));
+    group.throughput(Throughput::Elements(OPS as u64));
+    group.bench_function("random", |b| {
+        b.iter(|| {
+            let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+            for (key, value) in keys.iter().zip(&values) {
+                let Entry::Occupied(o) = tree.entry(key.borrow()).unwrap() else {
+                    panic!("key should be in the tree");
+                };
+                o.replace(value.borrow()).unwrap();
+            }
+            drop(tree);
+            writer.reset();
+        })
+    });
+    group.finish();
+}
+
+/// Drain a committed tree from the front, by finding each first key with a range and removing it
+/// through an entry, and with `pop_first`. The transaction is thrown away after each iteration,
+/// so they all start from the same tree.
+fn drain_shape<S: Shape>(c: &mut Criterion, name: &str, len: u64)
+where
+    <S::Leaf as PageLayout>::Key: ToOwned,
+    <S::Leaf as PageLayout>::Value: ToOwned,
+{
+    let records = dataset::<S>(SEED, 0..len);
+    let mut writer = load::<S>(&records);
+
+    let mut group = c.benchmark_group(format!("drain/{name}"));
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(len));
+    group.bench_function("range_entry", |b| {
+        b.iter(|| {
+            let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+            loop {
+                let first = tree
+                    .as_read()
+                    .range::<<S::Leaf as PageLayout>::Key, _>(..)
+                    .unwrap()
+                    .next();
+                let Some(key) = first.map(|res| res.unwrap().0.to_owned()) else {
+                    break;
+                };
+                let Entry::Occupied(o) = tree.entry(key.borrow()).unwrap() else {
+                    panic!("key was just found in the tree");
+                };
+                o.remove().unwrap();
+            }
+            drop(tree);
+            writer.reset();
+        })
+    });
+    group.bench_function("pop_first", |b| {
+        b.iter(|| {
+            let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+            while tree.pop_first().unwrap().is_some() {}
+            drop(tree);
+            writer.reset();
+        })
+    });
+    group.finish();
+}
+
+fn lookup_shape<S: Shape>(c: &mut Criterion, name: &str, len: u64) {
+    let records = dataset::<S>(SEED, 0..len);
+    let writer = load::<S>(&records);
+    let reader = writer.reader().unwrap();
+    let tree = reader.tree::<S::Branch, S::Leaf>().unwrap();
+    let mut rng = Rng::new(!SEED);
+    let hits = pick::<S>(&records, &mut rng);
+    let misses: Vec<S::Key> = dataset::<S>(!SEED, len..len + OPS as u64)
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+
+    let mut group = c.benchmark_group(format!("lookup/{name}"));
+    group.throughput(Throughput::Elements(OPS as u64));
+    for (case, keys) in [("hit", &hits), ("miss", &misses)] {
+        group.bench_function(case, |b| {
+            b.iter(|| {
+                for key in keys {
+                    black_box(tree.get(key.borrow()).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn scan_shape<S: Shape>(c: &mut Criterion, name: &str, len: u64) {
+    let records = dataset::<S>(SEED, 0..len);
+    let writer = load::<S>(&records);
+    let reader = writer.reader().unwrap();
+    let tree = reader.tree::<S::Branch, S::Leaf>().unwrap();
+    let mut rng = Rng::new(!SEED);
+    let starts = pick::<S>(&records, &mut rng);
+
+    let mut group = c.benchmark_group(format!("scan/{name}"));
+    group.throughput(Throughput::Elements(len));
+    group.bench_function("forward", |b| {
+        b.iter(|| {
+            for entry in tree.range::<<S::Leaf as PageLayout>::Key, _>(..).unwrap() {
+                black_box(entry.unwrap());
+            }
+        })
+    });
+    group.bench_function("reverse", |b| {
+        b.iter(|| {
+            for entry in tree
+                .range::<<S::Leaf as PageLayout>::Key, _>(..)
+                .unwrap()
+                .rev()
+            {
+                black_box(entry.unwrap());
+            }
+        })
+    });
+
+    // Short scans are dominated by the descent to the first key instead
+    const SHORT: usize = 64;
+    group.throughput(Throughput::Elements((OPS * SHORT) as u64));
+    group.bench_function(BenchmarkId::new("short", SHORT), |b| {
+        b.iter(|| {
+            for start in &starts {
+                let range = (Bound::Included(start.borrow()), Bound::Unbounded);
+                let iter = tree.range::<<S::Leaf as PageLayout>::Key, _>(range);
+                for entry in iter.unwrap().take(SHORT) {
+                    black_box(entry.unwrap());
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+fn insert(c: &mut Criterion) {
+    insert_shape::<U64U64>(c, "u64_u64", U64_LEN);
+    insert_shape::<U64Bytes>(c, "u64_bytes", BYTES_LEN);
+    insert_shape::<BytesU64>(c, "bytes_u64", BYTES_LEN);
+}
+
+fn append(c: &mut Criterion) {
+    append_shape::<U64U64>(c, "u64_u64", U64_LEN);
+    append_shape::<U64Bytes>(c, "u64_bytes", BYTES_LEN);
+    append_shape::<BytesU64>(c, "bytes_u64", BYTES_LEN);
+}
+
+fn rewrite(c: &mut Criterion) {
+    rewrite_shape::<U64U64>(c, "u64_u64", U64_LEN);
+    rewrite_shape::<U64Bytes>(c, "u64_bytes", BYTES_LEN);
+    rewrite_shape::<BytesU64>(c, "bytes_u64", BYTES_LEN);
+}
+
+fn drain(c: &mut Criterion) {
+    drain_shape::<U64U64>(c, "u64_u64", U64_LEN);
+    drain_shape::<U64Bytes>(c, "u64_bytes", BYTES_LEN);
+    drain_shape::<BytesU64>(c, "bytes_u64", BYTES_LEN);
+}
+
+fn lookup(c: &mut Criterion) {
+    lookup_shape::<U64U64>(c, "u64_u64", U64_LEN);
+    lookup_shape::<U64Bytes>(c, "u64_bytes", BYTES_LEN);
+    lookup_shape::<BytesU64>(c, "bytes_u64", BYTES_LEN);
+}
+
+fn scan(c: &mut Criterion) {
+    scan_shape::<U64U64>(c, "u64_u64", U64_LEN);
+    scan_shape::<U64Bytes>(c, "u64_bytes", BYTES_LEN);
+    scan_shape::<BytesU64>(c, "bytes_u64", BYTES_LEN);
+}
+
+criterion_group!(benches, insert, append, rewrite, drain, lookup, scan);
+criterion_main!(benches);
@@ -0,0 +1,189 @@
+//! Page iteration, search, split and balance microbenchmarks, on pages built directly from the same seeded
+//! datasets the B-tree benchmarks use.
+//!
+//! Each iteration works on fresh copies of the source pages, so the copies are part of what gets
+//! timed. They're a small fraction of it.
+
+use std::{borrow::Borrow, hint::black_box};
+
+use crab_dads::{
+    page::{Balance, Entry, PageMap, PageMapMut},
+    Error,
+};
+use crab_tests::{dataset, BytesU64, Shape, U64Bytes, U64U64};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+const SEED: u64 = 0x5eed;
+
+#[derive(Clone)]
+#[repr(align(4096))]
+struct Page([u8; 4096]);
+
+impl Page {
+    fn new() -> Self {
+        Self([0; 4096])
+    }
+}
+
+/// Fill a fresh leaf page with records until the next one doesn't fit, or `max` of them are in.
+/// Returns the page and how many records went in.
+fn fill<S: Shape>(records: &[(S::Key, S::Value)], max: usize) -> (Page, usize) {
+    let mut page = Page::new();
+    let mut map = PageMapMut::<S::Leaf>::new(&mut page.0, 1);
+    let mut count = 0;
+    for (key, value) in records.iter().take(max) {
+        let Entry::Vacant(v) = map.entry(key.borrow()).unwrap() else {
+            panic!("keys should be unique");
+        };
+        match v.insert(value.borrow()) {
+            Ok(o) => map = o.to_page(),
+            Err((_, Error::OutofSpace(_))) => break,
+            Err((_, e)) => panic!("unexpected error {e:?}"),
+        }
+        count += 1;
+    }
+    (page, count)
+}
+
+/// Read through a full page, pair by pair and then just the keys, which steps over the values.
+fn iter_shape<S: Shape>(c: &mut Criterion, name: &str) {
+    let mut records = dataset::<S>(SEED, 0..4096);
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    let (full, per_page) = fill::<S>(&records, usize::MAX);
+    let map = PageMap::<S::Leaf>::from_page(&full.0).unwrap();
+
+    let mut group = c.benchmark_group(format!("iter/{name}"));
+    group.throughput(Throughput::Elements(per_page as u64));
+    group.bench_function("pairs", |b| {
+        b.iter(|| {
+            for res in black_box(&map).iter() {
+                black_box(res.unwrap().0);
+            }
+        })
+    });
+    group.bench_function("keys", |b| {
+        b.iter(|| {
+            for res in black_box(&map).iter().keys() {
+                black_box(res.unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Fill a fresh page with records in random order, finding each one's slot with `entry`, which
+/// searches from whichever end of the page the key looks closer to, and with `entry_from_back`.
+fn search_shape<S: Shape>(c: &mut Criterion, name: &str) {
+    let records = dataset::<S>(SEED, 0..4096);
+    let (_, per_page) = fill::<S>(&records, usize::MAX);
+    let records = &records[..per_page];
+
+    let mut group = c.benchmark_group(format!("search/{name}"));
+    group.throughput(Throughput::Elements(per_page as u64));
+    for (case, from_back) in [("closer_end", false), ("back", true)] {
+        group.bench_function(case, |b| {
+            b.iter_batched_ref(
+                Page::new,
+                |page| {
+                    let mut map = PageMapMut::<S::Leaf>::new(&mut page.0, 1);
+                    for (key, value) in records {
+                        let entry = if from_back {
+                            map.entry_from_back(key.borrow())
+                        } else {
+                            map.entry(key.borrow())
+                        };
+                        let Entry::Vacant(v) = entry.unwrap() else {
+                            panic!("keys should be unique");
+                        };
+                        let Ok(o) = v.insert(value.borrow()) else {
+                            panic!("the same records fit on one page before");
+                        };
+                        map = o.to_page();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn split_shape<S: Shape>(c: &mut Criterion, name: &str) {
+    let mut records = dataset::<S>(SEED, 0..4096);
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    let (full, _) = fill::<S>(&records, usize::MAX);
+
+    let mut group = c.benchmark_group(format!("split/{name}"));
+    for fill in [0.5, 0.9] {
+        group.bench_function(BenchmarkId::from_parameter(fill), |b| {
+            b.iter_batched_ref(
+                || (full.clone(), Page::new()),
+                |(src, dst)| {
+                    let mut map = PageMapMut::<S::Leaf>::from_page(&mut src.0).unwrap();
+                    black_box(map.split_to_with_fill(&mut dst.0, fill).unwrap());
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn balance_shape<S: Shape>(c: &mut Criterion, name: &str) {
+    let mut records = dataset::<S>(SEED, 0..4096);
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    let (_, per_page) = fill::<S>(&records, usize::MAX);
+    let quarter = (per_page / 4).max(1);
+
+    // A full page next to a quarter-full one has to be evened out, while two quarter-full pages
+    // merge into one
+    let cases = [("even", per_page), ("merge", quarter)];
+    let mut group = c.benchmark_group(format!("balance/{name}"));
+    for (case, lower_len) in cases {
+        let (lower, lower_len) = fill::<S>(&records, lower_len);
+        let (higher, _) = fill::<S>(&records[lower_len..], quarter);
+        group.bench_function(case, |b| {
+            b.iter_batched_ref(
+                || (lower.clone(), higher.clone()),
+                |(lower, higher)| {
+                    let lower = PageMapMut::<S::Leaf>::from_page(&mut lower.0).unwrap();
+                    let higher = PageMapMut::<S::Leaf>::from_page(&mut higher.0).unwrap();
+                    // Safety: every key in the higher page comes after every key in the lower one
+                    match unsafe { lower.balance(higher) }.unwrap() {
+                        Balance::Merged(map) => black_box(map.data_len()),
+                        Balance::Balanced { lower, .. } => black_box(lower.data_len()),
+                    };
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn iter(c: &mut Criterion) {
+    iter_shape::<U64U64>(c, "u64_u64");
+    iter_shape::<U64Bytes>(c, "u64_bytes");
+    iter_shape::<BytesU64>(c, "bytes_u64");
+}
+
+fn search(c: &mut Criterion) {
+    search_shape::<U64U64>(c, "u64_u64");
+    search_shape::<U64Bytes>(c, "u64_bytes");
+    search_shape::<BytesU64>(c, "bytes_u64");
+}
+
+fn split(c: &mut Criterion) {
+    split_shape::<U64U64>(c, "u64_u64");
+    split_shape::<U64Bytes>(c, "u64_bytes");
+    split_shape::<BytesU64>(c, "bytes_u64");
+}
+
+fn balance(c: &mut Criterion) {
+    balance_shape::<U64U64>(c, "u64_u64");
+    balance_shape::<U64Bytes>(c, "u64_bytes");
+    balance_shape::<BytesU64>(c, "bytes_u64");
+}
+
+criterion_group!(benches, iter, search, split, balance);
+criterion_main!(benches);
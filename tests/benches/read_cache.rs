@@ -0,0 +1,85 @@
+//! Point lookups through a B-tree stored in crab-db, a batch of them per read transaction, the way a
+//! server spawning a transaction per request would do them. With a read cache on the read unit,
+//! transactions after the first on a generation find every page they load already looked up in the
+//! memory maps; without one, each load goes to the maps.
+//!
+//! The tree is loaded and committed before anything is timed, so every transaction reads the same
+//! generation.
+
+use std::hint::black_box;
+
+use crab_dads::{
+    btree::{BTreeRead, BTreeWrite, RawWrite},
+    format::PAGE_TYPE_LEAF,
+    page::PageMapMut,
+};
+use crab_db::{OpenOptions, ReadUnit, TxnWriter};
+use crab_tests::{dataset, insert_all, Rng, Shape, U64U64};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+type Branch = <U64U64 as Shape>::Branch;
+type Leaf = <U64U64 as Shape>::Leaf;
+
+const SEED: u64 = 0x5eed;
+
+/// Records in the tree, enough for three levels.
+const LEN: u64 = 1 << 17;
+
+/// Lookups made in each read transaction.
+const LOOKUPS: usize = 16;
+
+/// Pages the read cache holds, enough for the whole tree.
+const CACHE_PAGES: usize = 1 << 12;
+
+/// Load `records` into a tree in a new anonymous database, with a read cache holding up to
+/// `cache_pages` pages if there is one, and commit it. Hands back the unit to read it through and
+/// the tree's root page.
+fn load(records: &[(u64, u64)], cache_pages: Option<usize>) -> (ReadUnit, u64) {
+    let mut options = OpenOptions::default();
+    if let Some(max_pages) = cache_pages {
+        options.read_cache(max_pages);
+    }
+    let (read, unit, mut commit) = options.open_anon().unwrap();
+    let mut txn = unit.write();
+    let root = {
+        let writer = TxnWriter::new(&mut txn);
+        let (page, root) = writer.allocate_page().unwrap();
+        PageMapMut::<Leaf>::new(page, PAGE_TYPE_LEAF);
+        let (mut tree, moved) =
+            unsafe { BTreeWrite::<Branch, Leaf, _>::load(&writer, root) }.unwrap();
+        insert_all::<U64U64, _>(&mut tree, records).unwrap();
+        moved.unwrap_or(root)
+    };
+    txn.set_root_data(&root.to_le_bytes()).unwrap();
+    txn.commit_root_data();
+    commit.commit().unwrap();
+    (read, root)
+}
+
+fn point_lookup(c: &mut Criterion) {
+    let records = dataset::<U64U64>(SEED, 0..LEN);
+    let mut rng = Rng::new(SEED);
+    let keys: Vec<u64> = (0..(LOOKUPS * 64))
+        .map(|_| records[rng.below(LEN) as usize].0)
+        .collect();
+
+    let mut group = c.benchmark_group("point_lookup");
+    group.throughput(Throughput::Elements(LOOKUPS as u64));
+    for (name, cache_pages) in [("plain", None), ("read_cache", Some(CACHE_PAGES))] {
+        let (read, root) = load(&records, cache_pages);
+        let mut batches = keys.chunks(LOOKUPS).cycle();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let txn = read.reader();
+                let tree = unsafe { BTreeRead::<Branch, Leaf, _>::load(&txn, root) }.unwrap();
+                for key in batches.next().unwrap() {
+                    black_box(tree.get(key).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, point_lookup);
+criterion_main!(benches);
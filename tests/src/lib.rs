@@ -0,0 +1,226 @@
+//! Shared pieces for the workspace-level tests: reproducible datasets for each tree shape, and
+//! helpers to load them into a [`BTreeWrite`] and check them back out of a [`BTreeRead`].
+//!
+//! Everything here is generic over [`RawRead`] and [`RawWrite`], so the same datasets and checks
+//! run against any allocator: crab-dads' simulated allocator in `full_stack`, and a real crab-db
+//! file in `crab_db`, which [`TempPath`] cleans up after.
+
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    fmt::Debug,
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+};
+
+use crab_dads::{
+    btree::{BTreeRead, BTreeWrite, Entry, RawRead, RawWrite},
+    page::{LayoutU64U64, LayoutU64Var, LayoutVarU64, PageLayout, MAX_VAR_SIZE},
+    rng::mix,
+    Error,
+};
+
+pub use crab_dads::rng::Rng;
+
+/// A tree shape - its branch and leaf layouts - along with how to generate records for it.
+pub trait Shape {
+    type Branch: PageLayout<Value = u64>;
+    type Leaf: PageLayout<Key = <Self::Branch as PageLayout>::Key, Value: PartialEq + Debug>;
+    type Key: Borrow<<Self::Leaf as PageLayout>::Key> + Ord + Clone + Debug;
+    type Value: Borrow<<Self::Leaf as PageLayout>::Value> + PartialEq + Clone + Debug;
+
+    /// The `i`th record of a dataset. Keys are unique for every `i`.
+    fn record(rng: &mut Rng, i: u64) -> (Self::Key, Self::Value);
+
+    /// A fresh value to overwrite an existing record with.
+    fn value(rng: &mut Rng) -> Self::Value;
+}
+
+/// `u64 -> u64`
+pub struct U64U64;
+
+impl Shape for U64U64 {
+    type Branch = LayoutU64U64;
+    type Leaf = LayoutU64U64;
+    type Key = u64;
+    type Value = u64;
+
+    fn record(rng: &mut Rng, i: u64) -> (u64, u64) {
+        (mix(i), rng.next_u64())
+    }
+
+    fn value(rng: &mut Rng) -> u64 {
+        rng.next_u64()
+    }
+}
+
+/// `u64 -> bytes`, with values anywhere from empty up to the largest a page can hold.
+pub struct U64Bytes;
+
+impl Shape for U64Bytes {
+    type Branch = LayoutU64U64;
+    type Leaf = LayoutU64Var;
+    type Key = u64;
+    type Value = Vec<u8>;
+
+    fn record(rng: &mut Rng, i: u64) -> (u64, Vec<u8>) {
+        (mix(i), Self::value(rng))
+    }
+
+    fn value(rng: &mut Rng) -> Vec<u8> {
+        let len = rng.below(MAX_VAR_SIZE as u64 + 1) as usize;
+        rng.bytes(len)
+    }
+}
+
+/// `bytes -> u64`, with keys from 8 bytes up to the largest a page can hold.
+pub struct BytesU64;
+
+impl Shape for BytesU64 {
+    type Branch = LayoutVarU64;
+    type Leaf = LayoutVarU64;
+    type Key = Vec<u8>;
+    type Value = u64;
+
+    fn record(rng: &mut Rng, i: u64) -> (Vec<u8>, u64) {
+        // Unique leading bytes keep the keys unique, and the tail makes them vary in length
+        let tail = rng.below(MAX_VAR_SIZE as u64 - 7) as usize;
+        let mut key = mix(i).to_be_bytes().to_vec();
+        key.extend_from_slice(&rng.bytes(tail));
+        (key, rng.next_u64())
+    }
+
+    fn value(rng: &mut Rng) -> u64 {
+        rng.next_u64()
+    }
+}
+
+/// A reproducible dataset of the records in `range`, in the order they should be inserted.
+/// Datasets with non-overlapping ranges never share a key.
+pub fn dataset<S: Shape>(seed: u64, range: Range<u64>) -> Vec<(S::Key, S::Value)> {
+    let mut rng = Rng::new(seed);
+    range.map(|i| S::record(&mut rng, i)).collect()
+}
+
+/// Insert every record into the tree. Fails if any key is already present.
+pub fn insert_all<S, W>(
+    tree: &mut BTreeWrite<'_, S::Branch, S::Leaf, W>,
+    records: &[(S::Key, S::Value)],
+) -> Result<(), Error>
+where
+    S: Shape,
+    W: RawWrite,
+{
+    for (key, value) in records {
+        match tree.entry(key.borrow())? {
+            Entry::Occupied(_) => return Err(Error::InvalidState("Key was already in the tree")),
+            Entry::Vacant(v) => {
+                v.insert(value.borrow())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Overwrite or delete a random selection of the expected records in the tree, keeping `expected`
+/// in sync with it. Roughly one in `every` records is touched.
+pub fn churn<S, W>(
+    tree: &mut BTreeWrite<'_, S::Branch, S::Leaf, W>,
+    expected: &mut BTreeMap<S::Key, S::Value>,
+    rng: &mut Rng,
+    every: u64,
+) -> Result<(), Error>
+where
+    S: Shape,
+    W: RawWrite,
+{
+    let picked: Vec<S::Key> = expected
+        .keys()
+        .filter(|_| rng.below(every) == 0)
+        .cloned()
+        .collect();
+    for key in picked {
+        let Entry::Occupied(o) = tree.entry(key.borrow())? else {
+            return Err(Error::InvalidState(
+                "Expected key was missing from the tree",
+            ));
+        };
+        if rng.below(2) == 0 {
+            o.delete()?;
+            expected.remove::<S::Key>(&key);
+        } else {
+            let value = S::value(rng);
+            o.replace(value.borrow())?;
+            expected.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Check that the tree holds exactly the expected records: every one can be looked up, and
+/// iterating in either direction gives them all back in order, with nothing else.
+pub fn verify<S, R>(
+    tree: &BTreeRead<'_, S::Branch, S::Leaf, R>,
+    expected: &BTreeMap<S::Key, S::Value>,
+) where
+    S: Shape,
+    R: RawRead,
+{
+    for (key, value) in expected {
+        let found = tree.get(key.borrow()).unwrap();
+        assert_eq!(found, Some(value.borrow()), "lookup of {key:?}");
+    }
+
+    let mut iter = tree.range::<<S::Leaf as PageLayout>::Key, _>(..).unwrap();
+    for (key, value) in expected {
+        let (k, v) = iter.next().expect("iteration ended early").unwrap();
+        assert_eq!(k, key.borrow());
+        assert_eq!(v, value.borrow());
+    }
+    assert!(iter.next().is_none(), "iteration found extra entries");
+
+    let mut iter = tree
+        .range::<<S::Leaf as PageLayout>::Key, _>(..)
+        .unwrap()
+        .rev();
+    for key in expected.keys().rev() {
+        let (k, _) = iter.next().expect("reverse iteration ended early").unwrap();
+        assert_eq!(k, key.borrow());
+    }
+    assert!(
+        iter.next().is_none(),
+        "reverse iteration found extra entries"
+    );
+}
+
+/// A path in the temp directory for a test's database file, which is deleted again once this is
+/// dropped, however the test ended up going.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// A path unique to `name` and this process, with any file left there cleared out.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("crab-tests-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,143 @@
+//! The same round trips as `full_stack`, but through crab-db on a real file: the tree lives in the
+//! database's pages, its root page goes in the root data, and "opening it back up from scratch"
+//! means closing the file and opening it again.
+
+use std::{collections::BTreeMap, path::Path};
+
+use crab_dads::{
+    btree::{BTreeRead, BTreeWrite, RawWrite},
+    format::PAGE_TYPE_LEAF,
+    page::PageMapMut,
+};
+use crab_db::{OpenOptions, ReadTxn, TxnWriter, WriteTxn};
+use crab_tests::{
+    churn, dataset, insert_all, verify, BytesU64, Rng, Shape, TempPath, U64Bytes, U64U64,
+};
+
+/// Smallest file a dataset should end up in, so that the trees get several levels deep and the
+/// database has to keep growing.
+const MIN_BYTES: u64 = 24 << 20;
+
+/// The tree's root page, as stored in the root data. `None` before the tree exists.
+fn root_page(root_data: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(root_data.try_into().ok()?))
+}
+
+/// Run `f` on the transaction's tree, starting a new one if there isn't one yet, and stage the
+/// tree's new root page to be committed.
+fn with_tree<S: Shape>(
+    txn: &mut WriteTxn,
+    f: impl FnOnce(&mut BTreeWrite<'_, S::Branch, S::Leaf, TxnWriter<'_>>),
+) {
+    let existing = root_page(txn.root_data());
+    let root = {
+        let writer = TxnWriter::new(txn);
+        let root = match existing {
+            Some(root) => root,
+            None => {
+                let (page, root) = writer.allocate_page().unwrap();
+                PageMapMut::<S::Leaf>::new(page, PAGE_TYPE_LEAF);
+                root
+            }
+        };
+        let (mut tree, moved) =
+            unsafe { BTreeWrite::<S::Branch, S::Leaf, _>::load(&writer, root) }.unwrap();
+        f(&mut tree);
+        moved.unwrap_or(root)
+    };
+    txn.set_root_data(&root.to_le_bytes()).unwrap();
+}
+
+fn check<S: Shape>(reader: &ReadTxn, expected: &BTreeMap<S::Key, S::Value>) {
+    let root = root_page(reader.root_data()).expect("no tree was committed");
+    let tree = unsafe { BTreeRead::<S::Branch, S::Leaf, _>::load(reader, root) }.unwrap();
+    verify::<S, _>(&tree, expected);
+}
+
+fn reopen<S: Shape>(path: &Path, expected: &BTreeMap<S::Key, S::Value>) {
+    let (read, _, _) = OpenOptions::default().open(path).unwrap();
+    check::<S>(&read.reader(), expected);
+}
+
+fn round_trip<S: Shape>(seed: u64, len: u64, batches: u64) {
+    let path = TempPath::new(&format!("round-trip-{seed}"));
+    let records = dataset::<S>(seed, 0..len);
+    let mut expected = BTreeMap::new();
+
+    // Load the dataset over several transactions
+    let (read, mut unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+    for batch in records.chunks(len.div_ceil(batches) as usize) {
+        let mut txn = unit.write();
+        with_tree::<S>(&mut txn, |tree| insert_all::<S, _>(tree, batch).unwrap());
+        unit = txn.commit_root_data().0;
+        commit.commit().unwrap();
+        expected.extend(batch.iter().cloned());
+    }
+    let file_len = std::fs::metadata(&path).unwrap().len();
+    assert!(file_len >= MIN_BYTES, "dataset only took {file_len} bytes");
+
+    // Then rewrite and delete some of it
+    let mut rng = Rng::new(!seed);
+    let mut txn = unit.write();
+    with_tree::<S>(&mut txn, |tree| {
+        churn::<S, _>(tree, &mut expected, &mut rng, 8).unwrap()
+    });
+    unit = txn.commit_root_data().0;
+    commit.commit().unwrap();
+    check::<S>(&read.reader(), &expected);
+
+    // Throw away a transaction partway through: none of it should ever become visible
+    let more = dataset::<S>(!seed, len..(len + len / 8));
+    let mut txn = unit.write();
+    with_tree::<S>(&mut txn, |tree| {
+        churn::<S, _>(tree, &mut expected.clone(), &mut rng, 4).unwrap();
+        insert_all::<S, _>(tree, &more).unwrap();
+    });
+    let Ok((next, _)) = txn.abort() else {
+        panic!("couldn't abort the transaction");
+    };
+    unit = next;
+    check::<S>(&read.reader(), &expected);
+
+    // Lose power partway through committing one instead, after its pages are flushed but before
+    // the root page pointing at them is. The database should come back at the previous root.
+    let durable = read.reader().root_data().to_vec();
+    let mut txn = unit.write();
+    with_tree::<S>(&mut txn, |tree| insert_all::<S, _>(tree, &more).unwrap());
+    let (unit, _) = txn.commit_root_data();
+    commit.cut_power_after(usize::MAX);
+    commit.prepare().unwrap().abandon();
+    let crashed = commit.take_disk().unwrap();
+    drop((read, unit, commit));
+    std::fs::write(&path, crashed).unwrap();
+    let (read, _, _) = OpenOptions::default().open(&path).unwrap();
+    let reader = read.reader();
+    assert_eq!(reader.root_data(), &durable[..]);
+    check::<S>(&reader, &expected);
+    drop((reader, read));
+
+    // And the database is still perfectly usable afterwards
+    let (_, unit, mut commit) = OpenOptions::default().open(&path).unwrap();
+    let mut txn = unit.write();
+    with_tree::<S>(&mut txn, |tree| insert_all::<S, _>(tree, &more).unwrap());
+    let (unit, _) = txn.commit_root_data();
+    commit.commit().unwrap();
+    expected.extend(more);
+    drop((unit, commit));
+    reopen::<S>(&path, &expected);
+}
+
+#[test]
+fn u64_u64() {
+    round_trip::<U64U64>(1, 3 << 18, 4);
+}
+
+#[test]
+fn u64_bytes() {
+    round_trip::<U64Bytes>(2, 40_000, 4);
+}
+
+#[test]
+fn bytes_u64() {
+    round_trip::<BytesU64>(3, 40_000, 4);
+}
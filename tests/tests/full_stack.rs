@@ -0,0 +1,121 @@
+//! Load realistic datasets into a B-tree, commit them, open them back up from scratch, and check
+//! every record - then do it again after throwing away a transaction partway through, as a crash
+//! before the root gets published would.
+//!
+//! Only 4 kiB pages exist so far, so the tests are parameterized over tree shapes alone.
+
+use std::{borrow::Borrow, collections::BTreeMap};
+
+use crab_dads::{btree::BTreeRead, counting::CountingReader, sim::SimAllocator};
+use crab_tests::{churn, dataset, insert_all, verify, BytesU64, Rng, Shape, U64Bytes, U64U64};
+
+/// Smallest amount of page memory a dataset should take up, so that the trees get several levels
+/// deep and the allocator has to keep growing.
+const MIN_BYTES: usize = 4 << 20;
+
+fn full_stack<S: Shape>(seed: u64, len: u64, batches: u64) {
+    let mut writer = SimAllocator::new();
+    let records = dataset::<S>(seed, 0..len);
+    let mut expected = BTreeMap::new();
+
+    // Load the dataset over several transactions
+    for batch in records.chunks(len.div_ceil(batches) as usize) {
+        let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+        insert_all::<S, _>(&mut tree, batch).unwrap();
+        writer.commit().unwrap();
+        expected.extend(batch.iter().cloned());
+    }
+    assert!(
+        writer.page_count() * 4096 >= MIN_BYTES,
+        "dataset only took {} pages",
+        writer.page_count()
+    );
+
+    // Then rewrite and delete some of it
+    let mut rng = Rng::new(!seed);
+    let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+    churn::<S, _>(&mut tree, &mut expected, &mut rng, 8).unwrap();
+    writer.commit().unwrap();
+
+    // A reader opened from nothing but the published root sees all of it
+    let reader = writer.reader().unwrap();
+    verify::<S, _>(&reader.tree().unwrap(), &expected);
+
+    // Crash partway through a transaction: none of it should ever become visible
+    let more = dataset::<S>(!seed, len..(len + len / 8));
+    let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+    churn::<S, _>(&mut tree, &mut expected.clone(), &mut rng, 4).unwrap();
+    insert_all::<S, _>(&mut tree, &more).unwrap();
+    writer.reset();
+    let reader = reader.reload().unwrap();
+    verify::<S, _>(&reader.tree().unwrap(), &expected);
+
+    // Crash because the allocator ran dry partway through a transaction
+    writer.fail_allocations_after(Some(64));
+    let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+    assert!(insert_all::<S, _>(&mut tree, &more).is_err());
+    writer.reset();
+    writer.clear_faults().unwrap();
+    let reader = reader.reload().unwrap();
+    verify::<S, _>(&reader.tree().unwrap(), &expected);
+
+    // And the database is still perfectly usable afterwards
+    let mut tree = writer.tree::<S::Branch, S::Leaf>().unwrap();
+    insert_all::<S, _>(&mut tree, &more).unwrap();
+    writer.commit().unwrap();
+    expected.extend(more);
+    let reader = reader.reload().unwrap();
+    verify::<S, _>(&reader.tree().unwrap(), &expected);
+}
+
+#[test]
+fn u64_u64() {
+    full_stack::<U64U64>(1, 1 << 18, 4);
+}
+
+#[test]
+fn u64_bytes() {
+    full_stack::<U64Bytes>(2, 40_000, 4);
+}
+
+#[test]
+fn bytes_u64() {
+    full_stack::<BytesU64>(3, 40_000, 4);
+}
+
+/// Every lookup in a committed tree loads one page per level below the root and nothing else, so
+/// the lookup benchmarks are timing `levels - 1` page loads per key, whichever key it is.
+fn lookup_loads<S: Shape>(seed: u64, len: u64, levels: u64) {
+    let mut writer = SimAllocator::new();
+    let records = dataset::<S>(seed, 0..len);
+    insert_all::<S, _>(&mut writer.tree::<S::Branch, S::Leaf>().unwrap(), &records).unwrap();
+    writer.commit().unwrap();
+
+    let reader = writer.reader().unwrap();
+    let counting = CountingReader::new(&reader);
+    let tree =
+        unsafe { BTreeRead::<S::Branch, S::Leaf, _>::load(&counting, reader.root()) }.unwrap();
+    let mut rng = Rng::new(!seed);
+    for _ in 0..1000 {
+        let (key, value) = &records[rng.below(len) as usize];
+        counting.reset();
+        assert_eq!(tree.get(key.borrow()).unwrap(), Some(value.borrow()));
+        let loads = counting.snapshot().loads.total();
+        assert_eq!(loads, levels - 1, "lookup of {key:?}");
+    }
+}
+
+#[test]
+fn u64_u64_lookup_loads() {
+    lookup_loads::<U64U64>(4, 1 << 17, 3);
+}
+
+#[test]
+fn u64_bytes_lookup_loads() {
+    lookup_loads::<U64Bytes>(5, 20_000, 3);
+}
+
+#[test]
+fn bytes_u64_lookup_loads() {
+    lookup_loads::<BytesU64>(6, 20_000, 7);
+}